@@ -0,0 +1,260 @@
+//! Benchmarks for the parts of the edit pipeline the demo leans on hardest.
+//! They drive automerge the same way the app does: one list element per
+//! character, a change request per keystroke, and the backend thread copying
+//! the history from one backend to the other. Routing patches and importing
+//! a file go through the core crate's `DocumentEngine`, which the backend
+//! thread applies everything with, and its `TextDocument` and `TextImport`,
+//! so they time the demo's own code rather than a copy of it.
+//!
+//! Run with `cargo bench`.
+
+use automerge_backend::{Backend, Change};
+use automerge_demo_core::document::{TextDocument, Update};
+use automerge_demo_core::engine::DocumentEngine;
+use automerge_demo_core::ids::{DocumentId, PeerId};
+use automerge_demo_core::import::TextImport;
+use automerge_frontend::{Frontend, LocalChange, Path, Value};
+use automerge_protocol as amp;
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Counts allocations so the patch routing benchmark can show how many the
+/// engine makes, criterion only measures time
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+/// How many allocations `f` makes
+fn allocations<T>(f: impl FnOnce() -> T) -> usize {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    let result = f();
+    let after = ALLOCATIONS.load(Ordering::Relaxed);
+    drop(result);
+    after - before
+}
+
+/// A frontend and the backend it is in sync with
+struct Pair {
+    frontend: Frontend,
+    backend: Backend,
+}
+
+impl Pair {
+    fn new() -> Pair {
+        let mut pair = Pair {
+            frontend: Frontend::new(),
+            backend: Backend::init(),
+        };
+        pair.change(vec![LocalChange::set(
+            Path::root().key("text"),
+            Value::Sequence(Vec::new(), amp::SequenceType::Text),
+        )]);
+        pair
+    }
+
+    /// Make a change and apply the resulting patch, like a window typing
+    fn change(&mut self, changes: Vec<LocalChange>) {
+        let cr = self
+            .frontend
+            .change(None, |doc| {
+                for change in &changes {
+                    doc.add_change(change.clone())?;
+                }
+                Ok(())
+            })
+            .unwrap()
+            .unwrap();
+        let patch = self.backend.apply_local_change(cr).unwrap();
+        self.frontend.apply_patch(patch).unwrap();
+    }
+
+    /// Type `len` characters, `per_change` characters per change
+    fn type_text(&mut self, len: usize, per_change: usize) {
+        let mut typed = 0;
+        while typed < len {
+            let n = per_change.min(len - typed);
+            let changes = (0..n).map(|i| insert_char(typed + i, 'a')).collect();
+            self.change(changes);
+            typed += n;
+        }
+    }
+}
+
+/// A new document's engine, and a tab served by it which has nothing yet
+fn engine_and_tab() -> (DocumentEngine<Backend>, TextDocument) {
+    let (sx, rx) = crossbeam::channel::unbounded();
+    let mut engine = DocumentEngine::new(Backend::init(), PeerId(0), DocumentId::random());
+    engine.join(PeerId(0), rx);
+    (engine, TextDocument::new(sx))
+}
+
+/// A tab which has made a new document, and the document's engine
+struct Tab {
+    engine: DocumentEngine<Backend>,
+    doc: TextDocument,
+}
+
+impl Tab {
+    fn new() -> Tab {
+        let (engine, doc) = engine_and_tab();
+        let mut tab = Tab { engine, doc };
+        tab.doc.create().unwrap();
+        tab.answer();
+        tab
+    }
+
+    /// The engine answering every change request the tab has made
+    fn answer(&mut self) {
+        while let Some((_, request)) = self.engine.next_request() {
+            let (fanout, _) = self.engine.apply_request(request).unwrap();
+            self.doc.update(Update::Patch(fanout.patch)).unwrap();
+        }
+    }
+}
+
+fn insert_char(index: usize, c: char) -> LocalChange {
+    LocalChange::insert(
+        Path::root().key("text").index(index),
+        Value::Primitive(amp::Value::Str(c.to_string())),
+    )
+}
+
+fn change_request(c: &mut Criterion) {
+    c.bench_function("change_request/single_char", |b| {
+        b.iter_batched(
+            || {
+                let mut pair = Pair::new();
+                pair.type_text(100, 100);
+                pair.frontend
+            },
+            |mut frontend| {
+                frontend
+                    .change(None, |doc| {
+                        doc.add_change(insert_char(50, 'x'))?;
+                        Ok(())
+                    })
+                    .unwrap()
+            },
+            BatchSize::SmallInput,
+        )
+    });
+
+    // Keep a single pair in sync so the document grows a little with each
+    // iteration, as it would while typing
+    let mut pair = Pair::new();
+    pair.type_text(1000, 1000);
+    c.bench_function("change_request/single_char_round_trip", |b| {
+        b.iter(|| pair.change(vec![insert_char(0, 'x')]))
+    });
+}
+
+/// The patch a fresh window opening a 100KB document receives
+fn load_100kb_patch() -> amp::Patch {
+    let mut source = Pair::new();
+    source.type_text(100 * 1024, 1024);
+    let mut backend = Backend::init();
+    let changes = source.backend.get_changes(&[]).into_iter().cloned().collect();
+    backend.apply_changes(changes).unwrap()
+}
+
+fn patch_application(c: &mut Criterion) {
+    let patch = load_100kb_patch();
+    c.bench_function("apply_patch/load_100kb", |b| {
+        b.iter_batched(
+            || (Frontend::new(), patch.clone()),
+            |(mut frontend, patch)| frontend.apply_patch(patch).unwrap(),
+            BatchSize::LargeInput,
+        )
+    });
+}
+
+/// A document from elsewhere arriving, as the history of a shared document
+/// does: it goes into the document's engine and the engine's patch is moved
+/// into the tab's frontend. Any allocation the engine makes beyond what the
+/// backend does is a copy of the changes or the patch on the way.
+fn patch_routing(c: &mut Criterion) {
+    let mut source = Pair::new();
+    source.type_text(100 * 1024, 1024);
+    let changes: Vec<Change> = source.backend.get_changes(&[]).into_iter().cloned().collect();
+    let by_backend = {
+        let (changes, mut backend) = (changes.clone(), Backend::init());
+        allocations(move || backend.apply_changes(changes).unwrap())
+    };
+    let by_engine = {
+        let (changes, (mut engine, _doc)) = (changes.clone(), engine_and_tab());
+        allocations(move || engine.apply_changes(changes, None).unwrap())
+    };
+    println!(
+        "route_patch/load_100kb: {} allocations applying the changes to a backend, {} through the engine",
+        by_backend, by_engine
+    );
+
+    c.bench_function("route_patch/load_100kb", |b| {
+        b.iter_batched(
+            || (engine_and_tab(), changes.clone()),
+            |((mut engine, mut doc), changes)| {
+                let fanout = engine.apply_changes(changes, None).unwrap();
+                doc.update(Update::Patch(fanout.patch)).unwrap()
+            },
+            BatchSize::LargeInput,
+        )
+    });
+}
+
+/// File > Import Text with a 1MB file: the import's single change inserting
+/// a million characters, made by a tab, applied by its engine and the patch
+/// applied back to the tab
+fn text_import(c: &mut Criterion) {
+    let text = "a".repeat(1024 * 1024);
+    let mut group = c.benchmark_group("import");
+    // Each iteration takes seconds
+    group.sample_size(10);
+    group.bench_function("text_1mb", |b| {
+        b.iter_batched(
+            Tab::new,
+            |mut tab| {
+                let mut import = TextImport::new(&text, 0);
+                while import.step() {}
+                tab.doc.edit(import.take_changes(), Some("Import".to_string())).unwrap();
+                tab.answer();
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+fn history_transfer(c: &mut Criterion) {
+    // One change per character, which is what typing produces
+    let mut pair = Pair::new();
+    pair.type_text(10_000, 1);
+    c.bench_function("get_changes/full_history_10k_changes", |b| {
+        b.iter(|| pair.backend.get_changes(&[]).into_iter().cloned().collect::<Vec<_>>())
+    });
+    let changes: Vec<_> = pair.backend.get_changes(&[]).into_iter().cloned().collect();
+    c.bench_function("apply_changes/full_history_10k_changes", |b| {
+        b.iter_batched(
+            || (Backend::init(), changes.clone()),
+            |(mut backend, changes)| backend.apply_changes(changes).unwrap(),
+            BatchSize::LargeInput,
+        )
+    });
+}
+
+criterion_group!(benches, change_request, patch_application, patch_routing, text_import, history_transfer);
+criterion_main!(benches);
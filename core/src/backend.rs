@@ -0,0 +1,51 @@
+//! A backend thread for one frontend, for a front end with nothing else for
+//! the backend to do.
+//!
+//! The vgtk app's backend thread, `src/backend.rs`, keeps replicas in sync
+//! and talks to other instances, and is tied to the vgtk app's messages.
+//! This one only applies change requests as they arrive and sends back the
+//! patch for each, which is all a window on its own needs.
+
+use automerge_backend::Backend;
+use automerge_protocol as amp;
+use std::thread::{self, JoinHandle};
+
+use crate::document::Update;
+
+/// Start a thread applying the change requests on `requests` to `backend`
+/// and sending back a patch for each on `updates`, until the frontend goes
+/// away. The first patch is the whole document if `backend` isn't empty.
+pub fn spawn(
+    backend: Backend,
+    requests: crossbeam::Receiver<amp::Request>,
+    updates: crossbeam::Sender<Update>,
+) -> JoinHandle<()> {
+    thread::spawn(move || run(backend, requests, updates))
+}
+
+fn run(mut backend: Backend, requests: crossbeam::Receiver<amp::Request>, updates: crossbeam::Sender<Update>) {
+    if !backend.get_heads().is_empty() {
+        if let Ok(patch) = backend.get_patch() {
+            let _ = updates.send(Update::Patch(patch));
+        }
+    }
+    for request in requests {
+        let _span = tracing::info_span!("backend_apply", seq = request.seq).entered();
+        let update = match backend.apply_local_change(request) {
+            Ok(patch) => Update::Patch(patch),
+            Err(e) => {
+                tracing::error!("Could not apply a change request, resyncing the frontend: {}", e);
+                match backend.get_patch() {
+                    Ok(patch) => Update::Resync(patch),
+                    Err(e) => {
+                        tracing::error!("Could not get the state to resync from: {}", e);
+                        return;
+                    }
+                }
+            }
+        };
+        if updates.send(update).is_err() {
+            return;
+        }
+    }
+}
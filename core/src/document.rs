@@ -0,0 +1,177 @@
+//! One window's frontend on a document, and what it's waiting on the backend
+//! for.
+//!
+//! An edit goes into the frontend straight away, which makes a change
+//! request for it, and the request goes to the backend on a channel. The
+//! backend answers each request with a patch, which is how the frontend
+//! learns the request is done, and sends patches for everybody else's
+//! changes too. While any of our requests are unanswered the old frontend
+//! shows our edits on top of the backend's state and holds other people's
+//! patches back, so what the window shows never jumps under the cursor. The
+//! window only has to catch up once everything is answered.
+//!
+//! A `TextDocument` doesn't know what the window is. After each `update` it
+//! says whether the text may have changed, and the window compares what it
+//! shows with `text()`, see `text::changed`.
+
+use automerge_frontend::{Frontend, LocalChange, Path};
+use automerge_protocol as amp;
+
+use crate::text;
+
+/// What the backend sends a frontend
+#[derive(Debug)]
+pub enum Update {
+    /// The answer to one of our change requests, or someone else's changes
+    Patch(amp::Patch),
+    /// The whole state, after the backend couldn't make sense of one of our
+    /// requests, for the frontend to start again from
+    Resync(amp::Patch),
+}
+
+pub struct TextDocument {
+    frontend: Frontend,
+    requests: crossbeam::Sender<amp::Request>,
+    /// How many of our requests the backend hasn't answered yet
+    pending: usize,
+    /// Whether someone else's patch arrived while some were
+    behind: bool,
+}
+
+impl TextDocument {
+    /// A frontend sending its change requests on `requests`, which has
+    /// nothing until the backend sends it the document or it makes one
+    pub fn new(requests: crossbeam::Sender<amp::Request>) -> TextDocument {
+        TextDocument {
+            frontend: Frontend::new(),
+            requests,
+            pending: 0,
+            behind: false,
+        }
+    }
+
+    /// Make a new document, with `text::initial_changes`
+    pub fn create(&mut self) -> Result<(), String> {
+        self.edit(text::initial_changes(), Some("Create the document".to_string()))
+    }
+
+    /// Make `changes` as one change request and send it
+    pub fn edit(&mut self, changes: Vec<LocalChange>, message: Option<String>) -> Result<(), String> {
+        let request = self.frontend
+            .change(message, |doc| {
+                for change in changes {
+                    doc.add_change(change)?;
+                }
+                Ok(())
+            })
+            .map_err(|e| format!("the frontend rejected the edit: {:?}", e))?;
+        if let Some(request) = request {
+            self.requests.send(request).map_err(|_| "the backend has gone away".to_string())?;
+            self.pending += 1;
+        }
+        Ok(())
+    }
+
+    /// `text` went into the window at char offset `pos`
+    pub fn insert(&mut self, pos: usize, text: &str) -> Result<(), String> {
+        self.edit(text::inserted(pos, text), None)
+    }
+
+    /// The window lost the chars from `start` to `end`
+    pub fn delete(&mut self, start: usize, end: usize) -> Result<(), String> {
+        self.edit(text::deleted(start, end), None)
+    }
+
+    pub fn increment(&mut self) -> Result<(), String> {
+        self.edit(vec![LocalChange::increment(Path::root().key(text::COUNTER_FIELD))], None)
+    }
+
+    /// Apply what the backend sent, returning whether the window has to
+    /// compare what it shows with the text. It doesn't for the answer to
+    /// one of our own edits, which it made itself.
+    pub fn update(&mut self, update: Update) -> Result<bool, String> {
+        let patch = match update {
+            Update::Patch(patch) => patch,
+            Update::Resync(patch) => {
+                self.resync(patch)?;
+                return Ok(true);
+            }
+        };
+        let own = patch.actor.as_deref() == Some(self.actor_id().as_str());
+        self.frontend.apply_patch(patch).map_err(|e| format!("the frontend rejected a patch: {:?}", e))?;
+        if own && self.pending > 0 {
+            self.pending -= 1;
+            Ok(self.pending == 0 && std::mem::replace(&mut self.behind, false))
+        } else {
+            self.behind |= self.pending > 0;
+            Ok(true)
+        }
+    }
+
+    /// Start again from `patch`, the whole state of the backend, with a new
+    /// frontend under the same actor. Edits the backend hadn't answered are
+    /// lost.
+    fn resync(&mut self, patch: amp::Patch) -> Result<(), String> {
+        tracing::warn!("resyncing from the backend");
+        let mut frontend = Frontend::new();
+        frontend.actor_id = self.frontend.actor_id.clone();
+        frontend.apply_patch(patch).map_err(|e| format!("could not resync: {:?}", e))?;
+        self.frontend = frontend;
+        self.pending = 0;
+        self.behind = false;
+        Ok(())
+    }
+
+    pub fn actor_id(&self) -> String {
+        self.frontend.actor_id.to_string()
+    }
+
+    /// How many of our change requests the backend hasn't answered yet
+    pub fn pending(&self) -> usize {
+        self.pending
+    }
+
+    pub fn text(&self) -> String {
+        text::text_value(&self.frontend)
+    }
+
+    pub fn counter(&self) -> i64 {
+        text::counter_value(&self.frontend)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use automerge_backend::Backend;
+    use std::time::Duration;
+
+    use crate::backend;
+
+    /// Apply updates until every request has been answered
+    fn settle(document: &mut TextDocument, updates: &crossbeam::Receiver<Update>) {
+        while document.pending() > 0 {
+            let update = updates.recv_timeout(Duration::from_secs(10)).expect("the backend stopped answering");
+            document.update(update).unwrap();
+        }
+    }
+
+    #[test]
+    fn edits_are_answered_by_the_backend() {
+        let (requests, requests_rx) = crossbeam::unbounded();
+        let (updates_tx, updates) = crossbeam::unbounded();
+        let thread = backend::spawn(Backend::init(), requests_rx, updates_tx);
+        let mut document = TextDocument::new(requests);
+        document.create().unwrap();
+        document.insert(0, "Hello, w\u{f6}rld").unwrap();
+        document.delete(5, 12).unwrap();
+        document.insert(5, "!").unwrap();
+        document.increment().unwrap();
+        assert_eq!(document.text(), "Hello!");
+        settle(&mut document, &updates);
+        assert_eq!(document.text(), "Hello!");
+        assert_eq!(document.counter(), 1);
+        drop(document);
+        thread.join().unwrap();
+    }
+}
@@ -0,0 +1,205 @@
+//! One backend for every tab with the same document, as automerge means
+//! there to be one backend per device.
+//!
+//! The demo used to give each tab a backend of its own, and the vgtk app's
+//! backend thread, `src/backend.rs`, applied each backend's new changes to
+//! the others, the way two devices would sync. That's still there with
+//! `--backend-per-tab`, and `--chaos` needs it, but it does all the work
+//! twice for what is one document on one machine. A `DocumentEngine` is one
+//! backend with any number of frontends: a change request from any of them is
+//! applied once, and the patch goes to all of them. The one which made the
+//! request recognises it as the answer to its own, the others apply it as
+//! someone else's change, and since every frontend gets every patch in the
+//! order the backend made them, they all agree on what the backend has.
+//!
+//! A frontend which is suggesting changes, see `src/suggestion.rs`, follows a
+//! fork of the backend instead, which gets everyone else's changes too.
+//!
+//! The engine doesn't send anything itself. What it returns says which
+//! patch goes to which frontend, and the backend thread sends them on with
+//! whatever else has to hear about new changes. Changes from elsewhere can be
+//! anything another peer sent, so a batch the backend won't take is logged
+//! and dropped, with nothing to send.
+
+use automerge_backend::{Backend, Change};
+use automerge_protocol as amp;
+
+use crate::handle::BackendHandle;
+use crate::ids::{DocumentId, PeerId};
+
+/// One tab's frontend, as far as the engine is concerned
+struct EngineFrontend {
+    peer_id: PeerId,
+    requests: crossbeam::Receiver<amp::Request>,
+    /// The fork its requests go to while it's suggesting changes
+    branch: Option<Backend>,
+}
+
+/// Where the patches from one change to the backend go
+pub struct Fanout {
+    /// The backend's patch, for every frontend which isn't suggesting
+    pub patch: amp::Patch,
+    pub to: Vec<PeerId>,
+    /// The frontends which are suggesting, with their forks' patches
+    pub forks: Vec<(PeerId, amp::Patch)>,
+}
+
+pub struct DocumentEngine<B> {
+    pub backend: B,
+    frontends: Vec<EngineFrontend>,
+    /// The engine whose document this is a replica of, with
+    /// `--backend-per-tab`, engines with the same `document` are kept in
+    /// sync. Otherwise there's one engine with each document and this is
+    /// its key.
+    pub document: PeerId,
+    /// The document's id, the same in every instance syncing it
+    pub id: DocumentId,
+}
+
+impl<B: BackendHandle> DocumentEngine<B> {
+    pub fn new(backend: B, document: PeerId, id: DocumentId) -> DocumentEngine<B> {
+        DocumentEngine { backend, frontends: Vec::new(), document, id }
+    }
+
+    /// Start serving the frontend of `peer_id`, which sends its change
+    /// requests on `requests`
+    pub fn join(&mut self, peer_id: PeerId, requests: crossbeam::Receiver<amp::Request>) {
+        self.frontends.push(EngineFrontend { peer_id, requests, branch: None });
+    }
+
+    /// Stop serving the frontend of `peer_id`, returning whether any are left
+    pub fn leave(&mut self, peer_id: PeerId) -> bool {
+        self.frontends.retain(|frontend| frontend.peer_id != peer_id);
+        !self.frontends.is_empty()
+    }
+
+    pub fn serves(&self, peer_id: PeerId) -> bool {
+        self.frontends.iter().any(|frontend| frontend.peer_id == peer_id)
+    }
+
+    /// Each frontend, and the channel it sends its change requests on
+    pub fn frontends(&self) -> impl Iterator<Item = (PeerId, &crossbeam::Receiver<amp::Request>)> {
+        self.frontends.iter().map(|frontend| (frontend.peer_id, &frontend.requests))
+    }
+
+    fn frontend(&mut self, peer_id: PeerId) -> Option<&mut EngineFrontend> {
+        self.frontends.iter_mut().find(|frontend| frontend.peer_id == peer_id)
+    }
+
+    /// A change request waiting from one of the frontends which isn't
+    /// suggesting, for applying the last of them on the way out
+    pub fn next_request(&self) -> Option<(PeerId, amp::Request)> {
+        self.frontends.iter()
+            .filter(|frontend| frontend.branch.is_none())
+            .find_map(|frontend| frontend.requests.try_recv().ok().map(|request| (frontend.peer_id, request)))
+    }
+
+    /// How many change requests are waiting for the engine
+    pub fn waiting(&self) -> usize {
+        self.frontends.iter().map(|frontend| frontend.requests.len()).sum()
+    }
+
+    /// Apply a change request from one of the frontends which isn't
+    /// suggesting, returning where the patch goes and the change it made
+    pub fn apply_request(&mut self, request: amp::Request) -> Result<(Fanout, Vec<Change>), String> {
+        let (patch, changes) = self.backend.apply_local_change_and_get(request)?;
+        Ok((self.fanout(patch, &changes, None), changes))
+    }
+
+    /// Apply changes from elsewhere, returning where the patch goes, which
+    /// is every frontend but `except`, or nothing if the backend won't take
+    /// them
+    pub fn apply_changes(&mut self, changes: Vec<Change>, except: Option<PeerId>) -> Option<Fanout> {
+        // Only copied if there's a fork which needs them too
+        let copy = if self.frontends.iter().any(|frontend| frontend.branch.is_some()) { changes.clone() } else { Vec::new() };
+        match self.backend.apply_changes(changes) {
+            Ok(patch) => Some(self.fanout(patch, &copy, except)),
+            Err(e) => {
+                tracing::error!("Dropping changes to {} which don't apply: {}", self.id, e);
+                None
+            }
+        }
+    }
+
+    /// Where `patch`, for `changes` going into the backend, goes
+    fn fanout(&mut self, patch: amp::Patch, changes: &[Change], except: Option<PeerId>) -> Fanout {
+        let mut fanout = Fanout { patch, to: Vec::new(), forks: Vec::new() };
+        for frontend in self.frontends.iter_mut().filter(|frontend| Some(frontend.peer_id) != except) {
+            match &mut frontend.branch {
+                Some(branch) => match BackendHandle::apply_changes(branch, changes.to_vec()) {
+                    Ok(patch) => fanout.forks.push((frontend.peer_id, patch)),
+                    Err(e) => tracing::error!("Dropping changes to {}'s fork which don't apply: {}", frontend.peer_id, e),
+                },
+                None => fanout.to.push(frontend.peer_id),
+            }
+        }
+        fanout
+    }
+
+    /// Whether the frontend of `peer_id` is suggesting changes, in which
+    /// case its requests go to `apply_suggestion`
+    pub fn suggesting(&self, peer_id: PeerId) -> bool {
+        self.frontends.iter().any(|frontend| frontend.peer_id == peer_id && frontend.branch.is_some())
+    }
+
+    /// Apply a change request from a frontend which is suggesting to its
+    /// fork, returning the fork's patch and the change
+    pub fn apply_suggestion(&mut self, peer_id: PeerId, request: amp::Request) -> Result<(amp::Patch, Vec<Change>), String> {
+        match self.frontend(peer_id).and_then(|frontend| frontend.branch.as_mut()) {
+            Some(branch) => branch.apply_local_change_and_get(request),
+            None => Err(format!("{} isn't suggesting", peer_id)),
+        }
+    }
+
+    /// Fork the backend for the frontend of `peer_id`, returning whether it
+    /// wasn't suggesting already
+    pub fn suggest(&mut self, peer_id: PeerId) -> bool {
+        if !self.serves(peer_id) || self.suggesting(peer_id) {
+            return false;
+        }
+        let mut branch = Backend::init();
+        if let Err(e) = BackendHandle::apply_changes(&mut branch, self.backend.get_changes()) {
+            tracing::error!("Could not fork {} for {} to suggest in: {}", self.id, peer_id, e);
+            return false;
+        }
+        if let Some(frontend) = self.frontend(peer_id) {
+            frontend.branch = Some(branch);
+        }
+        true
+    }
+
+    /// Stop the frontend of `peer_id` suggesting, returning its fork with
+    /// every request it sent while it was applied, and the patches and
+    /// changes they made
+    pub fn stop_suggesting(&mut self, peer_id: PeerId) -> Option<(Backend, Vec<(amp::Patch, Vec<Change>)>)> {
+        let frontend = self.frontend(peer_id)?;
+        let mut branch = frontend.branch.take()?;
+        let mut applied = Vec::new();
+        // The doc has stopped suggesting, but requests it sent before it did
+        // are still suggestions
+        while let Ok(request) = frontend.requests.try_recv() {
+            match branch.apply_local_change_and_get(request) {
+                Ok(patch_and_changes) => applied.push(patch_and_changes),
+                Err(e) => tracing::warn!("Dropping a suggestion from {}: {}", peer_id, e),
+            }
+        }
+        Some((branch, applied))
+    }
+
+    /// The whole state for the frontend of `peer_id` to resync from, its
+    /// fork's if it's suggesting
+    pub fn patch_for(&mut self, peer_id: PeerId) -> Option<amp::Patch> {
+        let id = self.id;
+        let patch = match self.frontend(peer_id).and_then(|frontend| frontend.branch.as_mut()) {
+            Some(branch) => BackendHandle::get_patch(branch),
+            None => self.backend.get_patch(),
+        };
+        patch.map_err(|e| tracing::error!("Could not get the state of {} for {}: {}", id, peer_id, e)).ok()
+    }
+
+    /// The whole state of the document, for a frontend to start from
+    pub fn patch(&mut self) -> Option<amp::Patch> {
+        let id = self.id;
+        self.backend.get_patch().map_err(|e| tracing::error!("Could not get the state of {}: {}", id, e)).ok()
+    }
+}
@@ -0,0 +1,58 @@
+//! What a backend thread needs from a backend, so that it can be one in
+//! this process or one in another.
+
+use automerge_backend::{Backend, Change};
+use automerge_protocol as amp;
+
+/// The operations the backend thread needs from a backend, implemented both
+/// by an in process `Backend` and by the vgtk app's `BackendProcess`, which
+/// proxies to a backend running in a child process, see `src/ipc.rs`
+pub trait BackendHandle {
+    /// An error if the request doesn't make sense to the backend, which
+    /// means the frontend which made it has got out of step with it
+    fn apply_local_change(&mut self, request: amp::Request) -> Result<amp::Patch, String>;
+    /// An error if the changes, which came from elsewhere, are malformed or
+    /// don't make sense with what the backend has
+    fn apply_changes(&mut self, changes: Vec<Change>) -> Result<amp::Patch, String>;
+    /// The hashes of the changes which no other change depends on yet
+    fn get_heads(&mut self) -> Vec<amp::ChangeHash>;
+    /// Every change which isn't an ancestor of one of `heads`
+    fn get_changes_since(&mut self, heads: &[amp::ChangeHash]) -> Vec<Change>;
+    /// A patch for the whole state, for a frontend to start again from
+    fn get_patch(&mut self) -> Result<amp::Patch, String>;
+
+    /// The whole history
+    fn get_changes(&mut self) -> Vec<Change> {
+        self.get_changes_since(&[])
+    }
+
+    /// Apply a local change, returning the patch along with the change it
+    /// produced so that it can be forwarded to peers
+    fn apply_local_change_and_get(&mut self, request: amp::Request) -> Result<(amp::Patch, Vec<Change>), String> {
+        let heads = self.get_heads();
+        let patch = self.apply_local_change(request)?;
+        Ok((patch, self.get_changes_since(&heads)))
+    }
+}
+
+impl BackendHandle for Backend {
+    fn apply_local_change(&mut self, request: amp::Request) -> Result<amp::Patch, String> {
+        Backend::apply_local_change(self, request).map_err(|e| e.to_string())
+    }
+
+    fn apply_changes(&mut self, changes: Vec<Change>) -> Result<amp::Patch, String> {
+        Backend::apply_changes(self, changes).map_err(|e| e.to_string())
+    }
+
+    fn get_heads(&mut self) -> Vec<amp::ChangeHash> {
+        Backend::get_heads(self)
+    }
+
+    fn get_changes_since(&mut self, heads: &[amp::ChangeHash]) -> Vec<Change> {
+        Backend::get_changes(self, heads).iter().copied().cloned().collect()
+    }
+
+    fn get_patch(&mut self) -> Result<amp::Patch, String> {
+        Backend::get_patch(self).map_err(|e| e.to_string())
+    }
+}
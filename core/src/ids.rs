@@ -0,0 +1,37 @@
+//! What the backends and documents are called.
+//!
+//! A peer id only means something inside one instance, it's one backend and
+//! whatever shows its document, which the vgtk app calls a peer because
+//! from automerge's point of view that is what it is. A `DocumentId` is a
+//! UUID made when the document is created or opened, and the same in every
+//! instance syncing it.
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use uuid::Uuid;
+
+/// Identifies one backend and the document in the UI that it backs
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PeerId(pub usize);
+
+impl fmt::Display for PeerId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "peer {}", self.0)
+    }
+}
+
+/// Identifies a document in every instance which syncs it
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct DocumentId(pub Uuid);
+
+impl DocumentId {
+    pub fn random() -> DocumentId {
+        DocumentId(Uuid::new_v4())
+    }
+}
+
+impl fmt::Display for DocumentId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
@@ -0,0 +1,59 @@
+//! Importing a plain text file into the text, without the progress dialog.
+//!
+//! The whole file goes in as a single change rather than the change per
+//! keystroke typing it in would make. That is still one insert op per
+//! character, so the ops are built a chunk at a time, for a front end to
+//! do between redraws of whatever says how it's going, and then taken
+//! together to make the change with. The vgtk app's File > Import Text,
+//! `src/import.rs`, builds them in idle callbacks, and `cargo bench` times
+//! a 1MB import.
+
+use automerge_frontend::LocalChange;
+
+use crate::text;
+
+/// How many characters' worth of ops are built per step
+pub const CHUNK_CHARS: usize = 64 * 1024;
+
+pub struct TextImport {
+    chars: Vec<char>,
+    /// Where in the text it goes
+    offset: usize,
+    /// The ops built so far
+    changes: Vec<LocalChange>,
+}
+
+impl TextImport {
+    /// An import of `text` at char offset `offset`
+    pub fn new(text: &str, offset: usize) -> TextImport {
+        let chars: Vec<char> = text.chars().collect();
+        TextImport { changes: Vec::with_capacity(chars.len()), chars, offset }
+    }
+
+    /// Build the next chunk's ops, returning whether there's more to build
+    pub fn step(&mut self) -> bool {
+        let done = self.changes.len();
+        let end = (done + CHUNK_CHARS).min(self.chars.len());
+        let chunk: String = self.chars[done..end].iter().collect();
+        self.changes.extend(text::inserted(self.offset + done, &chunk));
+        end < self.chars.len()
+    }
+
+    /// How many chars' ops have been built
+    pub fn built(&self) -> usize {
+        self.changes.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.chars.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chars.is_empty()
+    }
+
+    /// The ops for the whole text, once `step` has built them all
+    pub fn take_changes(&mut self) -> Vec<LocalChange> {
+        std::mem::take(&mut self.changes)
+    }
+}
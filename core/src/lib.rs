@@ -0,0 +1,38 @@
+//! The part of the demo which doesn't know what it's drawn with.
+//!
+//! The demo started as a vgtk app, and everything in it was written against
+//! vgtk and GTK 3, but vgtk isn't maintained any more and the point of the
+//! demo is the automerge side: a frontend per window making change requests,
+//! a backend applying them, and patches coming back. So that part lives
+//! here, in a crate with no GUI in its dependencies, for each front end to
+//! build its window on:
+//!
+//! - `text`, where the text and counter are in a document and the local
+//!   changes for editing them
+//! - `document`, one window's frontend and the change requests it's waiting
+//!   on the backend for
+//! - `backend`, a backend thread for a front end which isn't the vgtk app,
+//!   which has one of its own
+//! - `handle`, what a backend thread needs from a backend, in this process
+//!   or another
+//! - `ids`, what backends and documents are called
+//! - `engine`, one backend serving every frontend on a document, which is
+//!   how the vgtk app's backend thread applies changes
+//! - `import`, building the change importing a text file
+//!
+//! The vgtk app, `src/main.rs`, uses `text`, `handle`, `ids`, `engine` and
+//! `import`.
+//! The rest of its backend thread is still its own, tied to its messages:
+//! the replicas, storage, and the transports, whose `Transport` trait is in
+//! terms of the websocket events, presence and signatures, so that part of
+//! moving the pipeline here isn't done. The GTK 4 front end, `gtk4/`, and
+//! the egui one, `src/egui/main.rs`, are built on `text`, `document` and
+//! `backend`.
+
+pub mod backend;
+pub mod document;
+pub mod engine;
+pub mod handle;
+pub mod ids;
+pub mod import;
+pub mod text;
@@ -0,0 +1,93 @@
+//! Where the text and counter are in a document, and the local changes
+//! which edit them.
+//!
+//! The text is a text sequence at `root.text` with one element per
+//! character, so indexes into it are char offsets, as they are in a GTK
+//! text buffer. The counter is a counter at `root.counts`.
+
+use automerge_frontend::{Frontend, LocalChange, Path, Value};
+use automerge_protocol as amp;
+use std::ops::Range;
+
+/// The field the main editor is bound to, and the counter on the Text tab
+pub const TEXT_FIELD: &str = "text";
+pub const COUNTER_FIELD: &str = "counts";
+
+/// The changes making a counter at zero and an empty text, the fields of
+/// the vgtk app's default schema
+pub fn initial_changes() -> Vec<LocalChange> {
+    vec![
+        LocalChange::set(Path::root().key(COUNTER_FIELD), Value::Primitive(amp::Value::Counter(0))),
+        LocalChange::set(Path::root().key(TEXT_FIELD), Value::Sequence(Vec::new(), amp::SequenceType::Text)),
+    ]
+}
+
+/// Get the value of the counter at `root.counts`
+pub fn counter_value(frontend: &Frontend) -> i64 {
+    match frontend.get_value(&Path::root().key(COUNTER_FIELD)) {
+        Some(Value::Primitive(amp::Value::Counter(i))) => i,
+        _ => 0,
+    }
+}
+
+/// The changes for `text` going into the buffer at char offset `pos`. One
+/// element per character so that indexes into the text match offsets into
+/// the buffer.
+pub fn inserted(pos: usize, text: &str) -> Vec<LocalChange> {
+    text.chars().enumerate().map(|(n, c)| {
+        LocalChange::insert(
+            Path::root().key(TEXT_FIELD).index(pos + n),
+            Value::Primitive(amp::Value::Str(c.to_string()))
+        )
+    }).collect()
+}
+
+/// The changes for the buffer losing the chars from `start` to `end`. Each
+/// deletion shifts the rest of the range down, so every deleted character is
+/// at the start of the range.
+pub fn deleted(start: usize, end: usize) -> Vec<LocalChange> {
+    (start..end).map(|_| LocalChange::delete(Path::root().key(TEXT_FIELD).index(start))).collect()
+}
+
+/// Get the contents of the "text" sequence as a string
+pub fn text_value(frontend: &Frontend) -> String {
+    match frontend.get_value(&Path::root().key(TEXT_FIELD)) {
+        Some(Value::Sequence(vals, amp::SequenceType::Text)) => {
+            vals.iter().map(|v| match v {
+                Value::Primitive(amp::Value::Str(s)) => s.to_string(),
+                _ => "".to_string(),
+            })
+            .collect::<Vec<String>>()
+            .join("")
+        },
+        _ => "".to_string()
+    }
+}
+
+/// What has to be replaced to turn `old` into `new`: the chars of `old`
+/// between the start and the end they have in common, and what goes in
+/// their place in `new`. For someone typing that's a word or so, however
+/// long the text is.
+pub fn changed(old: &[char], new: &[char]) -> (Range<usize>, Range<usize>) {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let max_suffix = old.len().min(new.len()) - prefix;
+    let suffix = old.iter().rev().zip(new.iter().rev()).take(max_suffix).take_while(|(a, b)| a == b).count();
+    (prefix..old.len() - suffix, prefix..new.len() - suffix)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::changed;
+
+    fn chars(s: &str) -> Vec<char> {
+        s.chars().collect()
+    }
+
+    #[test]
+    fn changed_is_the_middle() {
+        assert_eq!(changed(&chars("abcdef"), &chars("abXYef")), (2..4, 2..4));
+        assert_eq!(changed(&chars("aaa"), &chars("aaaa")), (3..3, 3..4));
+        assert_eq!(changed(&chars("same"), &chars("same")), (4..4, 4..4));
+        assert_eq!(changed(&chars("abc"), &chars("")), (0..3, 0..0));
+    }
+}
@@ -0,0 +1,184 @@
+//! Two peers, each a frontend and a backend as a tab is, typing into one
+//! text while their changes take whatever route through the network the
+//! fuzzer likes: change requests left unanswered by the backend while the
+//! other peer's changes arrive, batches of changes overtaking each other or
+//! arriving twice. The edits are made by the core crate's `text` functions,
+//! the ones `doc.rs` makes them with, with positions in what the frontend
+//! shows at the time. Nothing
+//! may panic or be rejected along the way, and once everything has arrived
+//! both peers have to have the same text and the same heads.
+//!
+//! Run with `cargo +nightly fuzz run interleavings` from this directory.
+
+#![no_main]
+
+use arbitrary::Arbitrary;
+use automerge_backend::{Backend, Change};
+use automerge_demo_core::text::{deleted, initial_changes, inserted, text_value};
+use automerge_frontend::{Frontend, LocalChange};
+use automerge_protocol as amp;
+use libfuzzer_sys::fuzz_target;
+use std::collections::VecDeque;
+
+/// At most this many characters typed or deleted at once
+const MAX_EDIT: usize = 8;
+
+#[derive(Arbitrary, Debug)]
+enum Op {
+    /// Typing at a position in what the peer's frontend shows, taken modulo
+    /// its length
+    Type { peer: bool, at: u16, text: String },
+    Delete { peer: bool, at: u16, len: u8 },
+    /// The peer's backend answering its oldest change request
+    Acknowledge { peer: bool },
+    /// One of the batches on its way to the peer arriving, not necessarily
+    /// the oldest
+    Deliver { to: bool, which: u8 },
+    /// A batch which has already arrived arriving again, as it does when
+    /// a peer reconnects and is sent the history
+    Redeliver { to: bool, which: u8 },
+}
+
+struct Peer {
+    frontend: Frontend,
+    backend: Backend,
+    /// Change requests the backend has yet to answer, oldest first
+    requests: VecDeque<amp::Request>,
+    /// Batches of the other peer's changes on their way to this one
+    incoming: Vec<Vec<Change>>,
+    /// And those which have arrived
+    arrived: Vec<Vec<Change>>,
+}
+
+impl Peer {
+    fn new() -> Peer {
+        Peer {
+            frontend: Frontend::new(),
+            backend: Backend::init(),
+            requests: VecDeque::new(),
+            incoming: Vec::new(),
+            arrived: Vec::new(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.text().chars().count()
+    }
+
+    fn text(&self) -> String {
+        text_value(&self.frontend)
+    }
+
+    fn edit(&mut self, changes: Vec<LocalChange>) {
+        let request = self
+            .frontend
+            .change(None, |doc| {
+                for change in changes {
+                    doc.add_change(change)?;
+                }
+                Ok(())
+            })
+            .expect("the frontend rejected an edit made from what it shows");
+        self.requests.extend(request);
+    }
+
+    fn type_text(&mut self, at: usize, text: &str) {
+        let at = at % (self.len() + 1);
+        let text: String = text.chars().take(MAX_EDIT).collect();
+        self.edit(inserted(at, &text));
+    }
+
+    fn delete(&mut self, at: usize, len: usize) {
+        let text_len = self.len();
+        if text_len == 0 {
+            return;
+        }
+        let start = at % text_len;
+        let end = (start + len % MAX_EDIT + 1).min(text_len);
+        self.edit(deleted(start, end));
+    }
+
+    /// Answer the oldest change request, returning the changes it made
+    fn acknowledge(&mut self) -> Option<Vec<Change>> {
+        let request = self.requests.pop_front()?;
+        let heads = self.backend.get_heads();
+        let patch = self.backend.apply_local_change(request).expect("the backend rejected a change request");
+        self.frontend.apply_patch(patch).expect("the frontend rejected its own patch");
+        Some(self.backend.get_changes(&heads).into_iter().cloned().collect())
+    }
+
+    fn receive(&mut self, changes: Vec<Change>) {
+        let patch = self.backend.apply_changes(changes).expect("the backend rejected the other peer's changes");
+        self.frontend.apply_patch(patch).expect("the frontend rejected a patch");
+    }
+
+    fn deliver(&mut self, which: usize) {
+        if self.incoming.is_empty() {
+            return;
+        }
+        let changes = self.incoming.remove(which % self.incoming.len());
+        self.arrived.push(changes.clone());
+        self.receive(changes);
+    }
+
+    fn redeliver(&mut self, which: usize) {
+        if self.arrived.is_empty() {
+            return;
+        }
+        let changes = self.arrived[which % self.arrived.len()].clone();
+        self.receive(changes);
+    }
+}
+
+/// The two peers, the one at `i` first
+fn pair(peers: &mut [Peer; 2], i: bool) -> (&mut Peer, &mut Peer) {
+    let (first, second) = peers.split_at_mut(1);
+    if i {
+        (&mut second[0], &mut first[0])
+    } else {
+        (&mut first[0], &mut second[0])
+    }
+}
+
+fuzz_target!(|ops: Vec<Op>| {
+    let mut peers = [Peer::new(), Peer::new()];
+    // The first peer makes the document and the second is sent it before
+    // anything else happens, like a new tab
+    peers[0].edit(initial_changes());
+    let history = peers[0].acknowledge().unwrap();
+    peers[1].receive(history);
+
+    for op in ops {
+        match op {
+            Op::Type { peer, at, text } => pair(&mut peers, peer).0.type_text(at as usize, &text),
+            Op::Delete { peer, at, len } => pair(&mut peers, peer).0.delete(at as usize, len as usize),
+            Op::Acknowledge { peer } => {
+                let (from, to) = pair(&mut peers, peer);
+                if let Some(changes) = from.acknowledge() {
+                    to.incoming.push(changes);
+                }
+            }
+            Op::Deliver { to, which } => pair(&mut peers, to).0.deliver(which as usize),
+            Op::Redeliver { to, which } => pair(&mut peers, to).0.redeliver(which as usize),
+        }
+    }
+
+    for &peer in &[false, true] {
+        let (from, to) = pair(&mut peers, peer);
+        while let Some(changes) = from.acknowledge() {
+            to.incoming.push(changes);
+        }
+    }
+    for peer in &mut peers {
+        while !peer.incoming.is_empty() {
+            peer.deliver(0);
+        }
+    }
+    let [a, b] = &mut peers;
+    assert_eq!(a.text(), b.text(), "the peers ended up with different text");
+    let (a_heads, b_heads) = (a.backend.get_heads(), b.backend.get_heads());
+    assert!(
+        a_heads.len() == b_heads.len() && a_heads.iter().all(|head| b_heads.contains(head)),
+        "the peers ended up with different heads"
+    );
+});
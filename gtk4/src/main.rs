@@ -0,0 +1,157 @@
+//! The demo on GTK 4, built with relm4, as a second front end on the core
+//! crate alongside the vgtk one. `cargo run` from `gtk4/`, it's a crate of
+//! its own, see its manifest.
+//!
+//! It's one window with one document, the text and the counter, and a
+//! backend thread of its own from `automerge_demo_core::backend`, so none of
+//! the vgtk app's tabs, sync or tools yet. What it shows is that the
+//! automerge side doesn't depend on vgtk: the window is the same
+//! `TextDocument` as a tab would be, with a `TextView` in front of it.
+//!
+//! The buffer's signal handlers make each edit in the document as it
+//! happens, before anything else can move the text, so they share it with
+//! the component, as `Doc` shares its frontend with its handlers. What the
+//! backend sends comes in as messages, from a thread forwarding them, and
+//! after each one the buffer is brought up to date by replacing what
+//! differs from the document's text with the handlers blocked.
+
+use automerge_backend::Backend;
+use automerge_demo_core::backend;
+use automerge_demo_core::document::{TextDocument, Update};
+use automerge_demo_core::text;
+use relm4::gtk::glib::SignalHandlerId;
+use relm4::gtk::prelude::*;
+use relm4::{gtk, ComponentParts, ComponentSender, RelmApp, SimpleComponent};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+struct App {
+    document: Rc<RefCell<TextDocument>>,
+    /// Whether the text may differ from the buffer's
+    stale: bool,
+}
+
+#[derive(Debug)]
+enum Msg {
+    Backend(Update),
+    Increment,
+}
+
+struct Widgets {
+    buffer: gtk::TextBuffer,
+    counter: gtk::Label,
+    /// Blocked while the buffer is brought up to date
+    handlers: Vec<SignalHandlerId>,
+}
+
+impl SimpleComponent for App {
+    type Input = Msg;
+    type Output = ();
+    type Init = ();
+    type Root = gtk::Window;
+    type Widgets = Widgets;
+
+    fn init_root() -> gtk::Window {
+        gtk::Window::builder()
+            .title("Automerge Demo (GTK 4)")
+            .default_width(600)
+            .default_height(400)
+            .build()
+    }
+
+    fn init(_: (), window: &gtk::Window, sender: ComponentSender<App>) -> ComponentParts<App> {
+        let (requests, requests_rx) = crossbeam::unbounded();
+        let (updates, updates_rx) = crossbeam::unbounded();
+        backend::spawn(Backend::init(), requests_rx, updates);
+        let forward = sender.clone();
+        std::thread::spawn(move || {
+            for update in updates_rx {
+                forward.input(Msg::Backend(update));
+            }
+        });
+        let mut document = TextDocument::new(requests);
+        if let Err(e) = document.create() {
+            tracing::error!("Could not create the document: {}", e);
+        }
+        let document = Rc::new(RefCell::new(document));
+
+        let view = gtk::TextView::new();
+        view.set_monospace(true);
+        let buffer = view.buffer();
+        let inserting = document.clone();
+        let insert_id = buffer.connect_insert_text(move |_, iter, text| {
+            if let Err(e) = inserting.borrow_mut().insert(iter.offset() as usize, text) {
+                tracing::error!("Could not insert {:?}: {}", text, e);
+            }
+        });
+        let deleting = document.clone();
+        let delete_id = buffer.connect_delete_range(move |_, start, end| {
+            if let Err(e) = deleting.borrow_mut().delete(start.offset() as usize, end.offset() as usize) {
+                tracing::error!("Could not delete: {}", e);
+            }
+        });
+        let scrolled = gtk::ScrolledWindow::builder().vexpand(true).child(&view).build();
+
+        let counter = gtk::Label::new(Some("0"));
+        let increment = gtk::Button::with_label("Increment");
+        let clicked = sender.clone();
+        increment.connect_clicked(move |_| clicked.input(Msg::Increment));
+        let bar = gtk::Box::new(gtk::Orientation::Horizontal, 6);
+        bar.append(&counter);
+        bar.append(&increment);
+
+        let content = gtk::Box::new(gtk::Orientation::Vertical, 6);
+        content.append(&scrolled);
+        content.append(&bar);
+        window.set_child(Some(&content));
+
+        let model = App { document, stale: false };
+        let widgets = Widgets { buffer, counter, handlers: vec![insert_id, delete_id] };
+        ComponentParts { model, widgets }
+    }
+
+    fn update(&mut self, message: Msg, _sender: ComponentSender<App>) {
+        let mut document = self.document.borrow_mut();
+        let result = match message {
+            Msg::Backend(update) => document.update(update),
+            Msg::Increment => document.increment().map(|_| false),
+        };
+        match result {
+            Ok(stale) => self.stale = stale,
+            Err(e) => tracing::error!("{}", e),
+        }
+    }
+
+    fn update_view(&self, widgets: &mut Widgets, _sender: ComponentSender<App>) {
+        let document = self.document.borrow();
+        widgets.counter.set_label(&document.counter().to_string());
+        if !self.stale {
+            return;
+        }
+        let (start, end) = widgets.buffer.bounds();
+        let old: Vec<char> = widgets.buffer.text(&start, &end, true).chars().collect();
+        let new: Vec<char> = document.text().chars().collect();
+        let (removed, put) = text::changed(&old, &new);
+        for id in &widgets.handlers {
+            widgets.buffer.block_signal(id);
+        }
+        if !removed.is_empty() {
+            let mut start = widgets.buffer.iter_at_offset(removed.start as i32);
+            let mut end = widgets.buffer.iter_at_offset(removed.end as i32);
+            widgets.buffer.delete(&mut start, &mut end);
+        }
+        if !put.is_empty() {
+            let inserted: String = new[put.clone()].iter().collect();
+            widgets.buffer.insert(&mut widgets.buffer.iter_at_offset(put.start as i32), &inserted);
+        }
+        for id in &widgets.handlers {
+            widgets.buffer.unblock_signal(id);
+        }
+    }
+}
+
+fn main() {
+    tracing_subscriber::fmt::init();
+    let app = RelmApp::new("org.automerge.Demo.Gtk4");
+    app.run::<App>(());
+}
@@ -0,0 +1,92 @@
+// The JS side of the interop checks, see src/interop.rs. Run with the
+// directory the Rust side saved its changes to:
+//
+//     node interop/interop.js <dir>
+//
+// Loads <dir>/rust.changes and checks they make <dir>/document.json, then
+// builds document.json itself and saves the changes to <dir>/js.changes.
+// Exits with a non-zero status if the check fails.
+
+const fs = require('fs')
+const path = require('path')
+const Automerge = require('automerge')
+
+// Changes are saved as the binary encoding of each one, prefixed with its
+// length as a big endian u32
+function readChanges(file) {
+  const buf = fs.readFileSync(file)
+  const changes = []
+  let offset = 0
+  while (offset < buf.length) {
+    const len = buf.readUInt32BE(offset)
+    offset += 4
+    changes.push(new Uint8Array(buf.subarray(offset, offset + len)))
+    offset += len
+  }
+  return changes
+}
+
+function writeChanges(file, changes) {
+  const parts = []
+  for (const change of changes) {
+    const len = Buffer.alloc(4)
+    len.writeUInt32BE(change.length)
+    parts.push(len, Buffer.from(change))
+  }
+  fs.writeFileSync(file, Buffer.concat(parts))
+}
+
+// Convert between automerge values and the snapshot JSON format, which
+// writes text, counters and timestamps as single key objects
+function toSnapshot(value) {
+  if (value instanceof Automerge.Text) return { $text: value.toString() }
+  if (value instanceof Automerge.Counter) return { $counter: value.value }
+  if (value instanceof Date) return { $timestamp: value.getTime() }
+  if (Array.isArray(value)) return value.map(toSnapshot)
+  if (value !== null && typeof value === 'object') {
+    const out = {}
+    for (const key of Object.keys(value)) out[key] = toSnapshot(value[key])
+    return out
+  }
+  return value
+}
+
+function fromSnapshot(value) {
+  if (Array.isArray(value)) return value.map(fromSnapshot)
+  if (value !== null && typeof value === 'object') {
+    const keys = Object.keys(value)
+    if (keys.length === 1 && keys[0] === '$text') return new Automerge.Text(value.$text)
+    if (keys.length === 1 && keys[0] === '$counter') return new Automerge.Counter(value.$counter)
+    if (keys.length === 1 && keys[0] === '$timestamp') return new Date(value.$timestamp)
+    const out = {}
+    for (const key of keys) out[key] = fromSnapshot(value[key])
+    return out
+  }
+  return value
+}
+
+// JSON with the keys of every object sorted, so two values can be compared
+// as strings
+function canonical(value) {
+  return JSON.stringify(value, (_, v) => {
+    if (v === null || typeof v !== 'object' || Array.isArray(v)) return v
+    const sorted = {}
+    for (const key of Object.keys(v).sort()) sorted[key] = v[key]
+    return sorted
+  })
+}
+
+const dir = process.argv[2]
+const fixture = JSON.parse(fs.readFileSync(path.join(dir, 'document.json'), 'utf8'))
+
+const [fromRust] = Automerge.applyChanges(Automerge.init(), readChanges(path.join(dir, 'rust.changes')))
+if (canonical(toSnapshot(fromRust)) !== canonical(fixture)) {
+  console.error('The Rust changes don\'t make the fixture, they make')
+  console.error(JSON.stringify(toSnapshot(fromRust), null, 2))
+  process.exit(1)
+}
+
+const fromJs = Automerge.change(Automerge.init(), 'Load the fixture', doc => {
+  for (const key of Object.keys(fixture)) doc[key] = fromSnapshot(fixture[key])
+})
+writeChanges(path.join(dir, 'js.changes'), Automerge.getAllChanges(fromJs))
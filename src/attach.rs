@@ -0,0 +1,207 @@
+//! `--attach`, opening another window on the backend of an instance which
+//! is already running.
+//!
+//! Everywhere else in the demo each frontend has a backend of its own. But
+//! the split between them exists so that one backend can serve several
+//! frontends, the way one automerge document in a service worker might
+//! serve every browser tab which has it open. Every instance listens on a
+//! control socket, and an instance started with `--attach` doesn't start any
+//! backends. It opens a single window whose frontend sends its change
+//! requests over the socket to the backend of the running instance's first
+//! document, and applies the patches which come back.
+//!
+//! On the running instance's side each attached frontend is sent the whole
+//! state of the backend as its first patch, and then every patch the
+//! backend produces, whichever frontend's change request or whichever
+//! remote change it came from. A frontend recognises the patches for its
+//! own requests by their actor and sequence number, the rest it applies as
+//! if they were remote. The changes an attached frontend makes are synced to
+//! the running instance's other tabs and websocket peers as if they'd been
+//! made there.
+//!
+//! Frames are length prefixed JSON as in `ipc.rs`. The socket is at
+//! `automerge-demo.sock` in `$XDG_RUNTIME_DIR`, or the temporary directory
+//! without one, unless `--control-socket` says otherwise. Only the first
+//! instance started gets it.
+
+use automerge_protocol as amp;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::backend::{BackendCommand, Ui};
+use crate::bridge::UiSender;
+use crate::ipc::{read_frame, write_frame};
+use crate::peer::PatchEnvelope;
+use crate::Message;
+
+/// Sent by an attached frontend
+#[derive(Serialize, Deserialize, Debug)]
+pub enum ToHost {
+    Request(amp::Request),
+}
+
+/// Sent to an attached frontend
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub enum FromHost {
+    Patch(amp::Patch),
+    /// The frontend's request didn't make sense to the backend, this is the
+    /// whole state to start again from, see `Doc::resync`
+    Resync(amp::Patch),
+}
+
+/// Set by `main` before the application is built, which is before the model
+/// hears about anything, because an attached instance has to run as a GTK
+/// application of its own rather than handing over to the one it attaches to
+static ATTACHED: AtomicBool = AtomicBool::new(false);
+
+pub fn set_attached() {
+    ATTACHED.store(true, Ordering::SeqCst);
+}
+
+/// Whether this instance is a window on another's backend
+pub fn attached() -> bool {
+    ATTACHED.load(Ordering::SeqCst)
+}
+
+pub fn default_socket_path() -> PathBuf {
+    std::env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(std::env::temp_dir)
+        .join("automerge-demo.sock")
+}
+
+/// Listen for frontends attaching, each is handed to the backend thread with
+/// `BackendCommand::Attach`
+pub fn listen(path: &Path, commands: crossbeam::Sender<BackendCommand>) -> io::Result<()> {
+    if UnixStream::connect(path).is_ok() {
+        return Err(io::Error::new(io::ErrorKind::AddrInUse, "another instance is already listening"));
+    }
+    // Left behind by an instance which didn't exit cleanly
+    let _ = std::fs::remove_file(path);
+    let listener = UnixListener::bind(path)?;
+    tracing::info!("frontends can attach at {}", path.display());
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if !serve(stream, &commands) {
+                        return;
+                    }
+                }
+                Err(e) => tracing::warn!("accepting an attached frontend failed: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Pump an attached frontend's requests to the backend thread and patches
+/// back, returning false if the backend thread has gone
+fn serve(stream: UnixStream, commands: &crossbeam::Sender<BackendCommand>) -> bool {
+    let (requests_sx, requests) = crossbeam::channel::unbounded();
+    let (patches, patches_rx) = crossbeam::channel::unbounded();
+    if commands.send(BackendCommand::Attach{requests, patches}).is_err() {
+        return false;
+    }
+    tracing::info!("a frontend attached");
+    let mut reader = match stream.try_clone() {
+        Ok(reader) => reader,
+        Err(e) => {
+            tracing::warn!("could not read from the attached frontend: {}", e);
+            return true;
+        }
+    };
+    // Dropping the sender when the frontend hangs up is what tells the
+    // backend thread it's gone
+    std::thread::spawn(move || loop {
+        match read_frame(&mut reader) {
+            Ok(ToHost::Request(request)) => {
+                if requests_sx.send(request).is_err() {
+                    return;
+                }
+            }
+            Err(_) => {
+                tracing::info!("an attached frontend went away");
+                return;
+            }
+        }
+    });
+    let mut writer = stream;
+    std::thread::spawn(move || {
+        for message in patches_rx {
+            if write_frame(&mut writer, &message).is_err() {
+                return;
+            }
+        }
+    });
+    true
+}
+
+/// Connect to a running instance's control socket
+pub fn connect(path: &Path) -> io::Result<UnixStream> {
+    UnixStream::connect(path)
+}
+
+/// What the backend thread of an attached instance does instead of running
+/// backends: forward the change requests of its one doc over the socket and
+/// send the patches which come back to the UI
+pub fn run_client(
+    stream: UnixStream,
+    scope: UiSender,
+    commands: crossbeam::Receiver<BackendCommand>,
+    closerx: crossbeam::Receiver<()>,
+) {
+    let mut requests = None;
+    let mut writer = stream.try_clone().unwrap();
+    loop {
+        let mut select = crossbeam::channel::Select::new();
+        let commands_index = select.recv(&commands);
+        let close_index = select.recv(&closerx);
+        let requests_index = requests.as_ref().map(|r| select.recv(r));
+        let op = select.select();
+        match op.index() {
+            i if i == commands_index => match op.recv(&commands) {
+                Ok(BackendCommand::AddPeer{peer_id, requests: r, ..}) if requests.is_none() => {
+                    requests = Some(r);
+                    let mut reader = stream.try_clone().unwrap();
+                    let scope = scope.clone();
+                    std::thread::spawn(move || loop {
+                        let message = match read_frame(&mut reader) {
+                            Ok(FromHost::Patch(patch)) => Message::Patch(PatchEnvelope{peer_id, patch, changes: Vec::new()}),
+                            Ok(FromHost::Resync(patch)) => Message::Resync(PatchEnvelope{peer_id, patch, changes: Vec::new()}),
+                            Err(e) => {
+                                tracing::error!("lost the attached instance: {}", e);
+                                return;
+                            }
+                        };
+                        if scope.try_send(message).is_err() {
+                            return;
+                        }
+                    });
+                }
+                // Requests for the other side's backend are taken care of
+                // there, we just need to let go of our end
+                Ok(BackendCommand::Shutdown{done, ..}) => {
+                    let _ = done.send(());
+                    return;
+                }
+                Ok(_) => tracing::warn!("an attached window only has the attached document"),
+                Err(_) => return,
+            },
+            i if i == close_index => return,
+            i if Some(i) == requests_index => match op.recv(requests.as_ref().unwrap()) {
+                Ok(request) => {
+                    if let Err(e) = write_frame(&mut writer, &ToHost::Request(request)) {
+                        tracing::error!("lost the attached instance: {}", e);
+                        return;
+                    }
+                }
+                Err(_) => return,
+            },
+            _ => unreachable!(),
+        }
+    }
+}
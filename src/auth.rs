@@ -0,0 +1,237 @@
+//! Authenticating websocket peers before they can see or change the
+//! document.
+//!
+//! A server started with `--passphrase` or `--authorized-keys` starts each
+//! connection with a challenge, a text message holding a random nonce,
+//! rather than the history. The client proves who it is by replying with
+//! either an HMAC-SHA256 of the nonce keyed with the passphrase, or the
+//! Ed25519 signature of the nonce with a key listed in the authorized keys
+//! file, along with its public key. Only once the server has checked that
+//! and replied `accepted` is the client sent the history and its changes
+//! applied. Anything else gets `rejected` with the reason and the connection
+//! is closed, and the peers popover lists it. The passphrase itself is
+//! never sent, and a fresh nonce each time means an answer overheard on one
+//! connection is no use on another.
+//!
+//! This only says who the client is. It doesn't hide anything from someone
+//! watching the connection or prove who the server is, that's what TLS is
+//! for, see `tls.rs`.
+//!
+//! The handshake messages are JSON:
+//!
+//! ```json
+//! {"type": "challenge", "nonce": "<hex>"}
+//! {"type": "passphrase", "proof": "<hex>"}
+//! {"type": "signature", "public_key": "<hex>", "signature": "<hex>"}
+//! {"type": "accepted"}
+//! {"type": "rejected", "reason": "..."}
+//! ```
+//!
+//! A server which doesn't ask for authentication sends the history straight
+//! away as before, so a client which gets a change as its first message
+//! knows there's nothing to do, and JS clients which only speak changes can
+//! still connect to it.
+
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::time::Duration;
+use tungstenite::WebSocket;
+
+/// How long either end waits for the other's next handshake message
+pub const TIMEOUT: Duration = Duration::from_secs(10);
+
+const NONCE_LEN: usize = 32;
+
+/// What we need from peers connecting to us, and what we present when we
+/// connect to them. One passphrase does for both.
+#[derive(Default)]
+pub struct Auth {
+    pub passphrase: Option<String>,
+    /// Our own key, from `--auth-key`
+    pub key: Option<Ed25519KeyPair>,
+    /// The public keys allowed to connect, from `--authorized-keys`
+    pub authorized: Vec<Vec<u8>>,
+}
+
+// Not derived, so the passphrase doesn't end up in a log
+impl fmt::Debug for Auth {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Auth")
+            .field("passphrase", &self.passphrase.as_ref().map(|_| "..."))
+            .field("key", &self.key.as_ref().map(|k| hex(k.public_key().as_ref())))
+            .field("authorized", &self.authorized.len())
+            .finish()
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum AuthMessage {
+    Challenge{nonce: String},
+    Passphrase{proof: String},
+    Signature{public_key: String, signature: String},
+    Accepted,
+    Rejected{reason: String},
+}
+
+/// How the client side of the handshake went
+pub enum Outcome {
+    /// The server accepted us, or didn't ask. In the second case its first
+    /// message was already a change, which mustn't be lost.
+    Accepted(Option<Vec<u8>>),
+    Rejected(String),
+    /// The connection failed, which is worth trying again
+    Failed(String),
+}
+
+impl Auth {
+    /// Whether clients connecting to us have to authenticate
+    pub fn required(&self) -> bool {
+        self.passphrase.is_some() || !self.authorized.is_empty()
+    }
+
+    /// The server side of the handshake. `Err` is the reason the client was
+    /// rejected, which it has been told.
+    pub fn challenge<S: Read + Write>(&self, socket: &mut WebSocket<S>) -> Result<(), String> {
+        let mut nonce = [0u8; NONCE_LEN];
+        SystemRandom::new().fill(&mut nonce).map_err(|_| "could not make a nonce".to_string())?;
+        send(socket, &AuthMessage::Challenge{nonce: hex(&nonce)}).map_err(|e| e.to_string())?;
+        let result = match receive(socket) {
+            Ok(Some(AuthMessage::Passphrase{proof})) => match &self.passphrase {
+                Some(passphrase) => {
+                    let key = hmac::Key::new(hmac::HMAC_SHA256, passphrase.as_bytes());
+                    match unhex(&proof) {
+                        Some(proof) if hmac::verify(&key, &nonce, &proof).is_ok() => Ok(()),
+                        _ => Err("wrong passphrase".to_string()),
+                    }
+                }
+                None => Err("this server only accepts keys".to_string()),
+            },
+            Ok(Some(AuthMessage::Signature{public_key, signature})) => match (unhex(&public_key), unhex(&signature)) {
+                (Some(public_key), Some(signature)) => {
+                    if !self.authorized.contains(&public_key) {
+                        Err(format!("the key {} isn't authorized", hex(&public_key)))
+                    } else if UnparsedPublicKey::new(&ED25519, &public_key).verify(&nonce, &signature).is_err() {
+                        Err("bad signature".to_string())
+                    } else {
+                        Ok(())
+                    }
+                }
+                _ => Err("invalid signature message".to_string()),
+            },
+            Ok(_) => Err("expected a passphrase or signature".to_string()),
+            Err(e) => Err(e),
+        };
+        let reply = match &result {
+            Ok(()) => AuthMessage::Accepted,
+            Err(reason) => AuthMessage::Rejected{reason: reason.clone()},
+        };
+        // If this fails the client has gone anyway
+        let _ = send(socket, &reply);
+        if result.is_err() {
+            let _ = socket.close(None);
+            let _ = socket.write_pending();
+        }
+        result
+    }
+
+    /// The client side of the handshake
+    pub fn respond<S: Read + Write>(&self, socket: &mut WebSocket<S>) -> Outcome {
+        let nonce = match socket.read_message() {
+            Ok(tungstenite::Message::Binary(change)) => return Outcome::Accepted(Some(change)),
+            Ok(tungstenite::Message::Text(text)) => match serde_json::from_str(&text) {
+                Ok(AuthMessage::Challenge{nonce}) => match unhex(&nonce) {
+                    Some(nonce) => nonce,
+                    None => return Outcome::Failed("invalid challenge".to_string()),
+                },
+                _ => return Outcome::Failed(format!("unexpected message {}", text)),
+            },
+            Ok(other) => return Outcome::Failed(format!("unexpected message {:?}", other)),
+            Err(e) => return Outcome::Failed(e.to_string()),
+        };
+        // A key says more about who we are than a passphrase anyone might
+        // have, so use it if we have one
+        let answer = match (&self.key, &self.passphrase) {
+            (Some(key), _) => AuthMessage::Signature{
+                public_key: hex(key.public_key().as_ref()),
+                signature: hex(key.sign(&nonce).as_ref()),
+            },
+            (None, Some(passphrase)) => {
+                let key = hmac::Key::new(hmac::HMAC_SHA256, passphrase.as_bytes());
+                AuthMessage::Passphrase{proof: hex(hmac::sign(&key, &nonce).as_ref())}
+            }
+            (None, None) => {
+                let _ = socket.close(None);
+                return Outcome::Rejected("the server needs a passphrase or key".to_string());
+            }
+        };
+        if let Err(e) = send(socket, &answer) {
+            return Outcome::Failed(e.to_string());
+        }
+        match receive(socket) {
+            Ok(Some(AuthMessage::Accepted)) => Outcome::Accepted(None),
+            Ok(Some(AuthMessage::Rejected{reason})) => Outcome::Rejected(reason),
+            Ok(other) => Outcome::Failed(format!("unexpected reply {:?}", other)),
+            Err(e) => Outcome::Failed(e),
+        }
+    }
+}
+
+fn send<S: Read + Write>(socket: &mut WebSocket<S>, message: &AuthMessage) -> tungstenite::Result<()> {
+    socket.write_message(tungstenite::Message::Text(serde_json::to_string(message).unwrap()))
+}
+
+/// Read the next handshake message, `None` if it isn't one
+fn receive<S: Read + Write>(socket: &mut WebSocket<S>) -> Result<Option<AuthMessage>, String> {
+    match socket.read_message() {
+        Ok(tungstenite::Message::Text(text)) => Ok(serde_json::from_str(&text).ok()),
+        Ok(_) => Ok(None),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Read our key from a PKCS#8 file made with `generate-key`
+pub fn load_key(path: &Path) -> io::Result<Ed25519KeyPair> {
+    Ed25519KeyPair::from_pkcs8(&std::fs::read(path)?)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+}
+
+/// Make a new key, write it to `path` and return its public key to go in
+/// servers' authorized keys files
+pub fn generate_key(path: &Path) -> io::Result<String> {
+    let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+        .map_err(|_| io::Error::new(io::ErrorKind::Other, "could not generate a key"))?;
+    std::fs::write(path, pkcs8.as_ref())?;
+    Ok(hex(load_key(path)?.public_key().as_ref()))
+}
+
+/// Read an authorized keys file, a hex public key per line. Blank lines and
+/// lines starting with `#` are skipped.
+pub fn load_authorized(path: &Path) -> io::Result<Vec<Vec<u8>>> {
+    std::fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            unhex(line)
+                .filter(|key| key.len() == 32)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("{} isn't an Ed25519 public key", line)))
+        })
+        .collect()
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn unhex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok()).collect()
+}
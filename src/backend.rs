@@ -0,0 +1,1169 @@
+//! The backend thread, which owns every backend and does everything which
+//! touches more than one of them.
+//!
+//! The UI thread only ever has frontends. Each doc sends its change
+//! requests down a channel of its own, and everything else the UI wants
+//! from the backends, adding a peer, saving, merging a file, comes as a
+//! `BackendCommand`. This thread selects on all of those and on the
+//! transports (see `transport.rs`), applies what arrives to the document's
+//! engine (see `core/src/engine.rs`), copies the changes to whatever is
+//! persisting it, and to the other replicas of the document with
+//! `--backend-per-tab`, and sends the patches back to the UI as messages.
+//!
+//! `main` starts the thread with `run_backends` once the UI is up, with a
+//! `BackendConfig` made from the command line.
+
+use automerge_backend::Change;
+use automerge_protocol as amp;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Instant;
+
+pub use automerge_demo_core::handle::BackendHandle;
+use automerge_demo_core::engine::{DocumentEngine, Fanout};
+
+use crate::change_log::ChangeMeta;
+use crate::chaos::Chaos;
+use crate::peer::{DocumentId, PatchEnvelope, PeerId};
+use crate::storage::Storage;
+use crate::transport::Transport;
+use crate::{attach, crypt, event_log, file, journal, metrics, presence, signing, ws, Message};
+
+/// Where the backend thread sends its messages for the UI: the channel to
+/// the UI thread, see `bridge.rs`, or in the tests a channel with fake
+/// frontends on the other end
+pub trait Ui {
+    /// Waits while the UI is behind, an error if it has gone away
+    fn try_send(&self, message: Message) -> Result<(), String>;
+}
+
+/// How a new peer's backend starts out
+#[derive(Clone, Debug)]
+pub enum PeerStart {
+    /// A new document
+    Empty,
+    /// A document loaded from a file
+    Open(Vec<Change>),
+    /// Another replica of the same document as this peer, which it is then
+    /// kept in sync with
+    ReplicaOf(PeerId),
+    /// A document another instance is syncing with us, starting with the
+    /// changes to it the backend thread has been holding on to
+    Remote(DocumentId),
+}
+
+/// Requests the UI makes of the backend thread other than change requests
+#[derive(Clone, Debug)]
+pub enum BackendCommand {
+    /// Start a backend for a new doc, which will send its change requests on
+    /// `requests`
+    AddPeer{peer_id: PeerId, id: DocumentId, requests: crossbeam::Receiver<amp::Request>, start: PeerStart},
+    /// Save the history of the backend of `peer_id` to `path`, encrypted
+    /// if there's a key
+    Save{peer_id: PeerId, path: PathBuf, key: Option<crypt::Key>},
+    /// Send the whole history of the backend of `peer_id` back to the UI
+    GetHistory{peer_id: PeerId, purpose: HistoryFor},
+    /// Write the history of the backend of `peer_id` to `path` as a JSON
+    /// event log
+    ExportHistory{peer_id: PeerId, path: PathBuf, key: Option<crypt::Key>},
+    /// Send the whole state of the backend of `peer_id` back to the UI for
+    /// its frontend to resync from
+    Resync{peer_id: PeerId},
+    /// Apply every change request still queued, save each peer in `save`
+    /// and then stop, replying on `done` once everything is written
+    Shutdown{save: Vec<(PeerId, PathBuf, Option<crypt::Key>)>, done: crossbeam::Sender<()>},
+    /// Send the whole state of a backend of `document` on `reply`
+    GetState{document: PeerId, reply: crossbeam::Sender<amp::Patch>},
+    /// Send the changes in a backend of `document` which came after
+    /// `heads`, and its own heads, on `reply`
+    GetChangesSince{document: PeerId, heads: Vec<amp::ChangeHash>, reply: crossbeam::Sender<(Vec<Change>, Vec<amp::ChangeHash>)>},
+    /// Apply the changes in the file at `path` which the document of
+    /// `peer_id` doesn't have yet, to every replica of it
+    Merge{peer_id: PeerId, path: PathBuf, changes: Vec<Change>},
+    /// Rewrite the file of the document of `peer_id`, if it has one, as a
+    /// snapshot, and snapshot it in the store if there is one, telling the
+    /// UI how much smaller they got
+    Compact{peer_id: PeerId, path: Option<PathBuf>, key: Option<crypt::Key>},
+    /// A frontend in another instance attached to the shared document's
+    /// backend, see `attach.rs`
+    Attach{requests: crossbeam::Receiver<amp::Request>, patches: crossbeam::Sender<attach::FromHost>},
+    /// Tell the other instances what one of our tabs is doing
+    Presence(presence::PresenceState),
+    /// Send the document and heads of every backend back to the UI
+    GetHeads,
+    /// Fork the backend of `peer_id` and apply its change requests to the
+    /// fork from now on, see `suggestion.rs`
+    Suggest{peer_id: PeerId},
+    /// Merge the fork's changes into the backend of `peer_id` if `accept`,
+    /// otherwise drop them, and go back to applying its requests directly
+    ResolveSuggestions{peer_id: PeerId, accept: bool},
+}
+
+/// Which window wants a document's history
+#[derive(Clone, Copy, Debug)]
+pub enum HistoryFor {
+    Compare,
+    Statistics,
+}
+
+/// Batches of remote changes at least this big are announced, smaller ones
+/// are someone typing
+const NOTIFY_MERGED: usize = 10;
+
+/// Websocket peers sync the document the instance started with, which is
+/// the one the first peer belongs to
+pub const SHARED_DOCUMENT: PeerId = PeerId(0);
+
+/// A frontend in another instance sharing the backend of the shared
+/// document
+struct AttachedFrontend {
+    requests: crossbeam::Receiver<amp::Request>,
+    patches: crossbeam::Sender<attach::FromHost>,
+}
+
+/// What the backend thread keeps and checks, from the command line
+pub struct BackendConfig {
+    pub storage: Option<Box<dyn Storage>>,
+    pub journal: Option<journal::Journal>,
+    /// With `--sign-key`
+    pub signatures: Option<Arc<signing::Signatures>>,
+    /// What changes are sent to and received from other instances over
+    pub transports: Vec<Box<dyn Transport>>,
+    /// Where the backend's queue depth and handling times go
+    pub metrics: Arc<metrics::Metrics>,
+    /// With `--chaos`, what stands between the replicas of a document
+    pub chaos: Option<Chaos>,
+    /// With `--backend-per-tab`, every tab gets an engine of its own, and
+    /// those of one document are replicas kept in sync, rather than the tabs
+    /// of a document sharing one
+    pub backend_per_tab: bool,
+}
+
+/// What woke up the backend thread
+enum BackendEvent {
+    /// A change request from a doc, `None` if the doc has gone away
+    Request(PeerId, Option<amp::Request>),
+    /// A change request from the attached frontend at this index, `None` if
+    /// it has gone away
+    Attached(usize, Option<amp::Request>),
+    /// Something happened on the transport at this index
+    Ws(usize, ws::WsEvent),
+    Command(BackendCommand),
+    /// A batch `Chaos` was holding back is due
+    ChaosDue,
+    Close,
+}
+
+/// Pull change requests off the channel from each doc, apply them to the
+/// engine of its document, copy the changes over to the other replicas of
+/// the same document if there are any and send the resulting patches back to
+/// every doc they're for. New changes go out over every transport, see
+/// `transport.rs`, and changes from peers on them are applied to every
+/// replica.
+///
+/// Engines and their backends are created with `new_backend` when the UI
+/// adds a peer for a document which doesn't have one yet, or for every peer
+/// with `backend_per_tab`, and are dropped once the last doc they serve goes
+/// away.
+///
+/// Only the change produced by each change request is forwarded, the full
+/// history is only sent once, to new replicas and to websocket peers when
+/// they connect. Changes are handed to the last backend which needs them
+/// rather than copied, and patches are moved all the way to the frontends,
+/// so the only copy of a change is the one each backend has to own.
+pub fn run_backends<B: BackendHandle>(
+    mut new_backend: impl FnMut(PeerId, DocumentId) -> B,
+    closerx: crossbeam::Receiver<()>,
+    scope: impl Ui,
+    commands: crossbeam::Receiver<BackendCommand>,
+    config: BackendConfig,
+) {
+    let BackendConfig{mut storage, mut journal, signatures, mut transports, metrics, mut chaos, backend_per_tab} = config;
+    // Keyed by the peer they were made for, which is what the journal and
+    // chaos know them by
+    let mut engines: BTreeMap<PeerId, DocumentEngine<B>> = BTreeMap::new();
+    // Changes other instances have sent us for documents which nobody has
+    // opened a tab for yet
+    let mut pending: HashMap<DocumentId, Vec<Change>> = HashMap::new();
+    // Frontends attached from other instances, and the peer whose backend
+    // they share, which is the first peer with the shared document. Every
+    // patch that backend produces goes to them as well as to its own doc.
+    let attached: RefCell<Vec<AttachedFrontend>> = RefCell::new(Vec::new());
+    let attach_host: Cell<Option<PeerId>> = Cell::new(None);
+    let send = |peer_id, patch: amp::Patch, changes| {
+        tell(&scope, Message::Patch(PatchEnvelope{peer_id, patch, changes}))
+    };
+    // Each patch an engine makes goes to its docs, and to the attached
+    // frontends if it's the one they share
+    let deliver = |engine: PeerId, fanout: Fanout, changes: Vec<ChangeMeta>| {
+        let Fanout{patch, to, forks} = fanout;
+        if attach_host.get() == Some(engine) {
+            attached.borrow_mut().retain(|frontend| frontend.patches.send(attach::FromHost::Patch(patch.clone())).is_ok());
+        }
+        for (peer_id, patch) in forks {
+            send(peer_id, patch, changes.clone());
+        }
+        if let Some((last, rest)) = to.split_last() {
+            for peer_id in rest {
+                send(*peer_id, patch.clone(), changes.clone());
+            }
+            send(*last, patch, changes);
+        }
+    };
+    loop {
+        if let Some(journal) = journal.as_mut().filter(|j| j.needs_rotation()) {
+            let checkpoint = engines.iter_mut().map(|(key, engine)| (*key, engine.id, engine.backend.get_changes())).collect();
+            journal.rotate(checkpoint);
+        }
+        if let Some(chaos) = &mut chaos {
+            deliver_held(chaos, &mut engines, Some(Instant::now()), &deliver, &mut journal);
+        }
+        let event = {
+            let tabs: Vec<(PeerId, &crossbeam::Receiver<amp::Request>)> = engines.values().flat_map(DocumentEngine::frontends).collect();
+            let frontends = attached.borrow();
+            let mut select = crossbeam::channel::Select::new();
+            for (_, requests) in &tabs {
+                select.recv(*requests);
+            }
+            for frontend in frontends.iter() {
+                select.recv(&frontend.requests);
+            }
+            let attached_end = tabs.len() + frontends.len();
+            for transport in &transports {
+                select.recv(transport.peer_events());
+            }
+            let transports_end = attached_end + transports.len();
+            let commands_index = select.recv(&commands);
+            select.recv(&closerx);
+            let selected = match chaos.as_ref().and_then(Chaos::next_due) {
+                Some(due) => select.select_timeout(due.saturating_duration_since(Instant::now())).ok(),
+                None => Some(select.select()),
+            };
+            match selected {
+                // Nothing else happened before the next held back batch
+                // was due, which is delivered next time round
+                None => BackendEvent::ChaosDue,
+                Some(op) => match op.index() {
+                    i if i < tabs.len() => BackendEvent::Request(tabs[i].0, op.recv(tabs[i].1).ok()),
+                    i if i < attached_end => {
+                        let index = i - tabs.len();
+                        BackendEvent::Attached(index, op.recv(&frontends[index].requests).ok())
+                    }
+                    i if i < transports_end => {
+                        let index = i - attached_end;
+                        BackendEvent::Ws(index, op.recv(transports[index].peer_events()).unwrap())
+                    }
+                    i if i == commands_index => BackendEvent::Command(op.recv(&commands).unwrap()),
+                    _ => {
+                        let _ = op.recv(&closerx);
+                        BackendEvent::Close
+                    }
+                },
+            }
+        };
+        let waiting = engines.values().map(DocumentEngine::waiting).sum::<usize>()
+            + attached.borrow().iter().map(|frontend| frontend.requests.len()).sum::<usize>()
+            + transports.iter().map(|transport| transport.peer_events().len()).sum::<usize>()
+            + commands.len();
+        // Times everything up to the next time round, however it gets there
+        let _timer = metrics.backend_event(waiting);
+        match event {
+            BackendEvent::Request(peer_id, Some(request)) => {
+                let _span = tracing::info_span!("backend_apply", peer = peer_id.0).entered();
+                let (key, engine) = match engine_of(&mut engines, peer_id) {
+                    Some(found) => found,
+                    None => continue,
+                };
+                // Suggestions only go to the fork, they aren't part of the
+                // document until they're accepted
+                if engine.suggesting(peer_id) {
+                    match engine.apply_suggestion(peer_id, request) {
+                        Ok((patch, new_changes)) => send(peer_id, patch, metas(&new_changes)),
+                        Err(e) => {
+                            tracing::error!("Could not apply a suggestion from {}, resyncing it: {}", peer_id, e);
+                            if let Some(patch) = engine.patch_for(peer_id) {
+                                tell(&scope, Message::Resync(PatchEnvelope{peer_id, patch, changes: Vec::new()}));
+                            }
+                        }
+                    }
+                    continue;
+                }
+                if let Some(journal) = &mut journal {
+                    journal.request(key, &request);
+                }
+                let (fanout, new_changes) = match engine.apply_request(request) {
+                    Ok(applied) => applied,
+                    Err(e) => {
+                        tracing::error!("Could not apply a change request from {}, resyncing it: {}", peer_id, e);
+                        if let Some(patch) = engine.patch() {
+                            tell(&scope, Message::Resync(PatchEnvelope{peer_id, patch, changes: Vec::new()}));
+                        }
+                        continue;
+                    }
+                };
+                let (document, id) = (engine.document, engine.id);
+                // Signed before anything else, so the signatures go out ahead
+                // of the changes
+                if let Some(signatures) = &signatures {
+                    share_signatures(&mut transports, &signatures.sign(&new_changes));
+                }
+                deliver(key, fanout, metas(&new_changes));
+                persist(&mut storage, id, &new_changes);
+                send_changes(&mut transports, document, id, &new_changes);
+                forward(&mut engines, document, Some(key), new_changes, &deliver, &mut journal, chaos.as_mut());
+            }
+            BackendEvent::Request(peer_id, None) => {
+                let (key, engine) = match engine_of(&mut engines, peer_id) {
+                    Some(found) => found,
+                    None => continue,
+                };
+                if engine.leave(peer_id) {
+                    tracing::info!("{} went away, its document's other tabs keep the backend", peer_id);
+                    continue;
+                }
+                tracing::info!("dropping the backend for {}", peer_id);
+                engines.remove(&key);
+                if let Some(journal) = &mut journal {
+                    journal.closed(key);
+                }
+                if attach_host.get() == Some(key) {
+                    // The other replicas have the same changes, so attached
+                    // frontends can carry on with one of them
+                    rehost(&engines, &attach_host, &attached);
+                }
+            }
+            BackendEvent::Attached(index, Some(request)) => {
+                let host = match attach_host.get() {
+                    Some(host) => host,
+                    None => continue,
+                };
+                let _span = tracing::info_span!("backend_apply", attached = index).entered();
+                if let Some(journal) = &mut journal {
+                    journal.request(host, &request);
+                }
+                let engine = engines.get_mut(&host).unwrap();
+                let (fanout, new_changes) = match engine.apply_request(request) {
+                    Ok(applied) => applied,
+                    Err(e) => {
+                        tracing::error!("Could not apply a change request from an attached frontend, resyncing it: {}", e);
+                        if let Some(patch) = engine.patch() {
+                            let _ = attached.borrow()[index].patches.send(attach::FromHost::Resync(patch));
+                        }
+                        continue;
+                    }
+                };
+                let id = engine.id;
+                if let Some(signatures) = &signatures {
+                    share_signatures(&mut transports, &signatures.sign(&new_changes));
+                }
+                deliver(host, fanout, metas(&new_changes));
+                persist(&mut storage, id, &new_changes);
+                send_changes(&mut transports, SHARED_DOCUMENT, id, &new_changes);
+                forward(&mut engines, SHARED_DOCUMENT, Some(host), new_changes, &deliver, &mut journal, chaos.as_mut());
+            }
+            BackendEvent::Attached(index, None) => {
+                tracing::info!("an attached frontend went away");
+                attached.borrow_mut().remove(index);
+            }
+            BackendEvent::Command(BackendCommand::Attach{requests, patches}) => {
+                let engine = match attach_host.get().and_then(|host| engines.get_mut(&host)) {
+                    Some(engine) => engine,
+                    None => {
+                        tracing::warn!("a frontend tried to attach but there's no shared document");
+                        continue;
+                    }
+                };
+                // Its frontend starts empty, so the whole state comes first
+                let patch = match engine.patch() {
+                    Some(patch) => patch,
+                    None => continue,
+                };
+                if patches.send(attach::FromHost::Patch(patch)).is_ok() {
+                    attached.borrow_mut().push(AttachedFrontend{requests, patches});
+                }
+            }
+            BackendEvent::Ws(transport, ws::WsEvent::Connected{client, server}) => {
+                let transport = &mut transports[transport];
+                let shared = engines.values_mut().find(|e| e.document == SHARED_DOCUMENT);
+                if let (Some(storage), Some(server), Some(shared)) = (&mut storage, server, &shared) {
+                    if !transport.knows(server) {
+                        match storage.load_sync_state(shared.id, &server.to_string()) {
+                            Ok(Some(have)) => transport.restore_seen(server, have),
+                            Ok(None) => {}
+                            Err(e) => tracing::warn!("Could not load what {} has from the store: {}", server, e),
+                        }
+                    }
+                }
+                let history = shared.map(|e| e.backend.get_changes()).unwrap_or_default();
+                transport.add_peer(client, server, &history);
+            }
+            BackendEvent::Ws(_, ws::WsEvent::Status(server, status)) => {
+                let _ = scope.try_send(Message::ConnectionStatus(server, status));
+            }
+            BackendEvent::Ws(_, ws::WsEvent::Verify{server, fingerprint, reply}) => {
+                let _ = scope.try_send(Message::VerifyPeer{server, fingerprint, reply});
+            }
+            BackendEvent::Ws(_, ws::WsEvent::Rejected{client, reason}) => {
+                let _ = scope.try_send(Message::PeerRejected(client, reason));
+            }
+            BackendEvent::Ws(_, ws::WsEvent::Signal{description, reply}) => {
+                let _ = scope.try_send(Message::CallSignal{description, reply});
+            }
+            BackendEvent::Ws(_, ws::WsEvent::Announced{document}) => {
+                let _ = scope.try_send(Message::P2pAnnounced(document));
+            }
+            BackendEvent::Ws(_, ws::WsEvent::NetworkPeer{id, present}) => {
+                let _ = scope.try_send(Message::NetworkPeer{id, present});
+            }
+            BackendEvent::Ws(_, ws::WsEvent::Presence(state)) => {
+                // Passed on to everyone else first, if it's new
+                if share_presence(&mut transports, &state) {
+                    let _ = scope.try_send(Message::RemotePresence(state));
+                }
+            }
+            BackendEvent::Command(BackendCommand::Presence(state)) => {
+                share_presence(&mut transports, &state);
+            }
+            BackendEvent::Ws(_, ws::WsEvent::Signature(hash, signature)) => {
+                // Passed on to everyone else, if it's new and checks out
+                if let Some(signatures) = &signatures {
+                    if signatures.receive(hash, signature.clone()) {
+                        share_signatures(&mut transports, &[(hash, signature)]);
+                    }
+                }
+            }
+            BackendEvent::Command(BackendCommand::Suggest{peer_id}) => {
+                // Attached frontends follow the engine's own patches, so they
+                // carry on as they were
+                if let Some((_, engine)) = engine_of(&mut engines, peer_id) {
+                    if engine.suggest(peer_id) {
+                        tracing::info!("{} is suggesting changes", peer_id);
+                    }
+                }
+            }
+            BackendEvent::Command(BackendCommand::ResolveSuggestions{peer_id, accept}) => {
+                let (key, engine) = match engine_of(&mut engines, peer_id) {
+                    Some(found) => found,
+                    None => continue,
+                };
+                let (mut branch, applied) = match engine.stop_suggesting(peer_id) {
+                    Some(stopped) => stopped,
+                    None => continue,
+                };
+                for (patch, new_changes) in applied {
+                    send(peer_id, patch, metas(&new_changes));
+                }
+                let (document, id) = (engine.document, engine.id);
+                if accept {
+                    let heads = engine.backend.get_heads();
+                    let new = BackendHandle::get_changes_since(&mut branch, &heads);
+                    tracing::info!("{} accepted {} suggested changes", peer_id, new.len());
+                    if let Some(journal) = &mut journal {
+                        journal.changes(key, &new);
+                    }
+                    // The doc has them already, from the fork, but the
+                    // document's other tabs don't
+                    if let Some(fanout) = engine.apply_changes(new.clone(), Some(peer_id)) {
+                        deliver(key, fanout, metas(&new));
+                    }
+                    if let Some(signatures) = &signatures {
+                        share_signatures(&mut transports, &signatures.sign(&new));
+                    }
+                    persist(&mut storage, id, &new);
+                    send_changes(&mut transports, document, id, &new);
+                    let count = new.len();
+                    forward(&mut engines, document, Some(key), new, &deliver, &mut journal, chaos.as_mut());
+                    let _ = scope.try_send(Message::SuggestionsAccepted(count));
+                } else {
+                    tracing::info!("{} rejected its suggestions", peer_id);
+                    if let Some(patch) = engine.patch() {
+                        let _ = scope.try_send(Message::SuggestionsRejected(PatchEnvelope{peer_id, patch, changes: Vec::new()}));
+                    }
+                }
+            }
+            BackendEvent::Command(BackendCommand::GetHeads) => {
+                // Per tab, so tabs sharing an engine agree with each other
+                let mut heads = Vec::new();
+                for engine in engines.values_mut() {
+                    let have = engine.backend.get_heads();
+                    let document = engine.document;
+                    heads.extend(engine.frontends().map(|(peer_id, _)| (peer_id, document, have.clone())));
+                }
+                let _ = scope.try_send(Message::Heads(heads));
+            }
+            BackendEvent::Ws(transport, ws::WsEvent::Changes{changes, server, document: None}) => {
+                let _span = tracing::info_span!("backend_apply", remote = changes.len()).entered();
+                let changes = transports[transport].recv_changes(server, changes);
+                if changes.is_empty() {
+                    continue;
+                }
+                if changes.len() >= NOTIFY_MERGED {
+                    let _ = scope.try_send(Message::RemoteChangesMerged{server, count: changes.len()});
+                }
+                for transport in transports.iter_mut() {
+                    transport.send_changes(None, &changes);
+                }
+                if let Some(id) = engines.values().find(|e| e.document == SHARED_DOCUMENT).map(|e| e.id) {
+                    persist(&mut storage, id, &changes);
+                }
+                forward(&mut engines, SHARED_DOCUMENT, None, changes, &deliver, &mut journal, chaos.as_mut());
+            }
+            BackendEvent::Ws(transport, ws::WsEvent::Changes{changes, server, document: Some(id)}) => {
+                let _span = tracing::info_span!("backend_apply", remote = changes.len()).entered();
+                let changes = transports[transport].recv_changes(server, changes);
+                if changes.is_empty() {
+                    continue;
+                }
+                if changes.len() >= NOTIFY_MERGED {
+                    let _ = scope.try_send(Message::RemoteChangesMerged{server, count: changes.len()});
+                }
+                match engines.values().find(|e| e.id == id).map(|e| e.document) {
+                    Some(document) => {
+                        persist(&mut storage, id, &changes);
+                        send_changes(&mut transports, document, id, &changes);
+                        forward(&mut engines, document, None, changes, &deliver, &mut journal, chaos.as_mut());
+                    }
+                    // A document we don't have yet, the UI opens a tab for
+                    // it, which picks these up
+                    None => {
+                        let held = pending.entry(id).or_default();
+                        if held.is_empty() {
+                            tracing::info!("{:?} is sharing document {} with us", server, id);
+                            let _ = scope.try_send(Message::RemoteDocument(id));
+                        }
+                        held.extend(changes);
+                    }
+                }
+            }
+            BackendEvent::Ws(transport, ws::WsEvent::Documents{client, server}) => {
+                let mut histories: Vec<(DocumentId, Vec<Change>)> = Vec::new();
+                for engine in engines.values_mut() {
+                    if engine.document != SHARED_DOCUMENT && !histories.iter().any(|(id, _)| *id == engine.id) {
+                        histories.push((engine.id, engine.backend.get_changes()));
+                    }
+                }
+                // Every signature we know, including those for the shared
+                // document's history, which it was sent before it said hello
+                if let Some(signatures) = &signatures {
+                    for (hash, signature) in signatures.all() {
+                        let _ = client.send(ws::signature_frame(&hash, &signature));
+                    }
+                }
+                transports[transport].add_document_peer(client, server, &histories);
+            }
+            BackendEvent::Command(BackendCommand::AddPeer{peer_id, id, requests, start}) => {
+                // Another tab on a document which has an engine shares it,
+                // starting from everything it has
+                if let PeerStart::ReplicaOf(other_id) = &start {
+                    if let (false, Some((_, engine))) = (backend_per_tab, engine_of(&mut engines, *other_id)) {
+                        engine.join(peer_id, requests);
+                        let history = engine.backend.get_changes();
+                        if !history.is_empty() {
+                            if let Some(patch) = engine.patch() {
+                                send(peer_id, patch, metas(&history));
+                            }
+                        }
+                        continue;
+                    }
+                }
+                let backend = new_backend(peer_id, id);
+                if let Some(journal) = &mut journal {
+                    journal.start(peer_id, id);
+                }
+                let (document, history) = match start {
+                    PeerStart::Empty => (peer_id, Vec::new()),
+                    PeerStart::Open(changes) => (peer_id, changes),
+                    PeerStart::Remote(id) => (peer_id, pending.remove(&id).unwrap_or_default()),
+                    PeerStart::ReplicaOf(other_id) => match engine_of(&mut engines, other_id) {
+                        Some((other_key, other)) => {
+                            // What's on its way to the other replica is on
+                            // its way to this one too
+                            if let Some(chaos) = &mut chaos {
+                                chaos.copy_held(other_key, peer_id);
+                            }
+                            (other.document, other.backend.get_changes())
+                        }
+                        None => (peer_id, Vec::new()),
+                    },
+                };
+                let mut engine = DocumentEngine::new(backend, document, id);
+                engine.join(peer_id, requests);
+                if !history.is_empty() {
+                    // A document new to the store, or one it's loaded from,
+                    // which is a good time to compact it
+                    if document == peer_id {
+                        if let Some(storage) = &mut storage {
+                            if let Err(e) = storage.save_snapshot(id, &history) {
+                                tracing::error!("Could not store document {}: {}", id, e);
+                            }
+                        }
+                    }
+                    if let Some(journal) = &mut journal {
+                        journal.changes(peer_id, &history);
+                    }
+                    let changes = metas(&history);
+                    if let Some(fanout) = engine.apply_changes(history, None) {
+                        deliver(peer_id, fanout, changes);
+                    }
+                }
+                engines.insert(peer_id, engine);
+                if document == SHARED_DOCUMENT && attach_host.get().is_none() {
+                    attach_host.set(Some(peer_id));
+                }
+            }
+            BackendEvent::Command(BackendCommand::Save{peer_id, path, key}) => save(&mut engines, peer_id, &path, key.as_ref()),
+            BackendEvent::Command(BackendCommand::ExportHistory{peer_id, path, key}) => {
+                let changes = match engine_of(&mut engines, peer_id) {
+                    Some((_, engine)) => engine.backend.get_changes(),
+                    None => continue,
+                };
+                match event_log::export(&path, &changes, key.as_ref()) {
+                    Ok(()) => tracing::info!("Exported {} changes to {}", changes.len(), path.display()),
+                    Err(e) => tracing::error!("Could not export to {}: {}", path.display(), e),
+                }
+            }
+            BackendEvent::Command(BackendCommand::Resync{peer_id}) => {
+                if let Some((_, engine)) = engine_of(&mut engines, peer_id) {
+                    if let Some(patch) = engine.patch_for(peer_id) {
+                        tell(&scope, Message::Resync(PatchEnvelope{peer_id, patch, changes: Vec::new()}));
+                    }
+                }
+            }
+            BackendEvent::Command(BackendCommand::GetState{document, reply}) => {
+                // Dropping the reply tells the asker there's no answer
+                if let Some(patch) = engines.values_mut().find(|e| e.document == document).and_then(|e| e.patch()) {
+                    let _ = reply.send(patch);
+                }
+            }
+            BackendEvent::Command(BackendCommand::GetChangesSince{document, heads, reply}) => {
+                if let Some(engine) = engines.values_mut().find(|e| e.document == document) {
+                    let _ = reply.send((engine.backend.get_changes_since(&heads), engine.backend.get_heads()));
+                }
+            }
+            BackendEvent::Command(BackendCommand::Merge{peer_id, path, changes}) => {
+                let engine = match engine_of(&mut engines, peer_id) {
+                    Some((_, engine)) => engine,
+                    None => continue,
+                };
+                let have: HashSet<amp::ChangeHash> = engine.backend.get_changes().iter().map(|c| c.hash).collect();
+                let new: Vec<Change> = changes.into_iter().filter(|c| !have.contains(&c.hash)).collect();
+                if new.is_empty() {
+                    continue;
+                }
+                let _span = tracing::info_span!("backend_apply", file = new.len()).entered();
+                let (document, id) = (engine.document, engine.id);
+                persist(&mut storage, id, &new);
+                send_changes(&mut transports, document, id, &new);
+                let count = new.len();
+                forward(&mut engines, document, None, new, &deliver, &mut journal, chaos.as_mut());
+                let _ = scope.try_send(Message::FileMerged{path, count});
+            }
+            BackendEvent::Command(BackendCommand::Compact{peer_id, path, key}) => {
+                let (id, changes) = match engine_of(&mut engines, peer_id) {
+                    Some((_, engine)) => (engine.id, engine.backend.get_changes()),
+                    None => continue,
+                };
+                let _ = scope.try_send(Message::Compacted(peer_id, compact(id, &changes, path, key, &mut storage)));
+            }
+            BackendEvent::Command(BackendCommand::GetHistory{peer_id, purpose}) => {
+                if let Some((_, engine)) = engine_of(&mut engines, peer_id) {
+                    tell(&scope, Message::History(purpose, engine.backend.get_changes()));
+                }
+            }
+            BackendEvent::Command(BackendCommand::Shutdown{save: to_save, done}) => {
+                finish_chaos(&mut chaos, &mut engines, &mut journal);
+                drain(&mut engines, &mut transports, &mut storage, &mut journal);
+                for (peer_id, path, key) in to_save {
+                    save(&mut engines, peer_id, &path, key.as_ref());
+                }
+                if let Some(storage) = &mut storage {
+                    store(&mut engines, &transports, storage.as_mut());
+                }
+                if let Some(journal) = journal.take() {
+                    journal.finish();
+                }
+                let _ = done.send(());
+                return;
+            }
+            BackendEvent::ChaosDue => {}
+            BackendEvent::Close => {
+                finish_chaos(&mut chaos, &mut engines, &mut journal);
+                drain(&mut engines, &mut transports, &mut storage, &mut journal);
+                if let Some(storage) = &mut storage {
+                    store(&mut engines, &transports, storage.as_mut());
+                }
+                if let Some(journal) = journal.take() {
+                    journal.finish();
+                }
+                return;
+            }
+        }
+    }
+}
+
+/// Send changes made to the document `document` replicates, whose id is
+/// `id`, to the peers on every transport
+fn send_changes(transports: &mut [Box<dyn Transport>], document: PeerId, id: DocumentId, changes: &[Change]) {
+    let document = if document == SHARED_DOCUMENT { None } else { Some(id) };
+    for transport in transports.iter_mut() {
+        transport.send_changes(document, changes);
+    }
+}
+
+fn share_signatures(transports: &mut [Box<dyn Transport>], signatures: &[(amp::ChangeHash, signing::Signature)]) {
+    for transport in transports.iter_mut() {
+        transport.share_signatures(signatures);
+    }
+}
+
+/// Pass a presence state on over every transport, returning whether it was
+/// news to any of them
+fn share_presence(transports: &mut [Box<dyn Transport>], state: &presence::PresenceState) -> bool {
+    transports.iter_mut().fold(false, |new, transport| transport.share_presence(state) || new)
+}
+
+/// Apply every change request still waiting in the docs' channels. The UI
+/// is going away so no patches are sent back, but the changes still go to
+/// the other replicas and websocket peers.
+fn drain<B: BackendHandle>(
+    engines: &mut BTreeMap<PeerId, DocumentEngine<B>>,
+    transports: &mut [Box<dyn Transport>],
+    storage: &mut Option<Box<dyn Storage>>,
+    journal: &mut Option<journal::Journal>,
+) {
+    let mut drained = 0;
+    let keys: Vec<PeerId> = engines.keys().copied().collect();
+    for key in keys {
+        // Suggestions nobody accepted are dropped
+        while let Some((peer_id, request)) = engines[&key].next_request() {
+            if let Some(journal) = journal {
+                journal.request(key, &request);
+            }
+            let engine = engines.get_mut(&key).unwrap();
+            let new_changes = match engine.apply_request(request) {
+                Ok((_, new_changes)) => new_changes,
+                Err(e) => {
+                    tracing::warn!("Dropping a change request from {} on the way out: {}", peer_id, e);
+                    continue;
+                }
+            };
+            let (document, id) = (engine.document, engine.id);
+            persist(storage, id, &new_changes);
+            send_changes(transports, document, id, &new_changes);
+            forward(engines, document, Some(key), new_changes, &|_, _, _| {}, journal, None);
+            drained += 1;
+        }
+    }
+    tracing::info!(drained, "Applied the last change requests before shutting down");
+}
+
+/// Deliver everything chaos is still holding back, without telling the UI,
+/// which is going away
+fn finish_chaos<B: BackendHandle>(chaos: &mut Option<Chaos>, engines: &mut BTreeMap<PeerId, DocumentEngine<B>>, journal: &mut Option<journal::Journal>) {
+    if let Some(chaos) = chaos {
+        deliver_held(chaos, engines, None, &|_, _, _| {}, journal);
+        chaos.report();
+    }
+}
+
+/// The engine serving the frontend of `peer_id`, with its key
+fn engine_of<B: BackendHandle>(engines: &mut BTreeMap<PeerId, DocumentEngine<B>>, peer_id: PeerId) -> Option<(PeerId, &mut DocumentEngine<B>)> {
+    engines.iter_mut().find(|(_, engine)| engine.serves(peer_id)).map(|(key, engine)| (*key, engine))
+}
+
+/// Save the history of the backend of `peer_id` to `path`, encrypted with
+/// `key` if there is one
+fn save<B: BackendHandle>(engines: &mut BTreeMap<PeerId, DocumentEngine<B>>, peer_id: PeerId, path: &std::path::Path, key: Option<&crypt::Key>) {
+    let changes = match engine_of(engines, peer_id) {
+        Some((_, engine)) => engine.backend.get_changes(),
+        None => return,
+    };
+    match file::save_with(path, &changes, key) {
+        Ok(()) => tracing::info!("Saved to {}", path.display()),
+        Err(e) => tracing::error!("Could not save to {}: {}", path.display(), e),
+    }
+}
+
+/// Compact the document's file and what's in the store for it, returning a
+/// report for the UI
+fn compact(
+    id: DocumentId,
+    changes: &[Change],
+    path: Option<PathBuf>,
+    key: Option<crypt::Key>,
+    storage: &mut Option<Box<dyn Storage>>,
+) -> String {
+    let mut report = Vec::new();
+    if let Some(path) = path {
+        match file::compact(&path, changes, key.as_ref()) {
+            Ok((before, after)) => {
+                tracing::info!(before, after, "Compacted {}", path.display());
+                report.push(format!("Compacted {} from {} to {}.", path.display(), file::human_size(before), file::human_size(after)));
+            }
+            Err(e) => report.push(format!("Could not compact {}: {}.", path.display(), e)),
+        }
+    }
+    if let Some(storage) = storage {
+        let sizes = storage.size(id)
+            .and_then(|before| storage.save_snapshot(id, changes).map(|()| before))
+            .and_then(|before| Ok((before, storage.size(id)?)));
+        match sizes {
+            Ok((before, after)) => report.push(format!("The store went from {} to {} for it.", file::human_size(before), file::human_size(after))),
+            Err(e) => report.push(format!("Could not compact the store: {}.", e)),
+        }
+    }
+    if report.is_empty() {
+        report.push("There's nothing to compact until the document is saved.".to_string());
+    }
+    report.join(" ")
+}
+
+/// Append `changes` to document `id` in the store, if there is one
+fn persist(storage: &mut Option<Box<dyn Storage>>, id: DocumentId, changes: &[Change]) {
+    if let Some(storage) = storage {
+        if let Err(e) = storage.append_changes(id, changes) {
+            tracing::error!("Could not store changes to {}: {}", id, e);
+        }
+    }
+}
+
+/// Replace everything in the store with a snapshot of each document, and
+/// keep what each server has for next time
+fn store<B: BackendHandle>(engines: &mut BTreeMap<PeerId, DocumentEngine<B>>, transports: &[Box<dyn Transport>], storage: &mut dyn Storage) {
+    let mut stored: Vec<DocumentId> = Vec::new();
+    for engine in engines.values_mut() {
+        if stored.contains(&engine.id) {
+            continue;
+        }
+        stored.push(engine.id);
+        if let Err(e) = storage.save_snapshot(engine.id, &engine.backend.get_changes()) {
+            tracing::error!("Could not store document {}: {}", engine.id, e);
+        }
+        if engine.document == SHARED_DOCUMENT {
+            for (server, seen) in transports.iter().flat_map(|transport| transport.seen()) {
+                let have: Vec<amp::ChangeHash> = seen.iter().copied().collect();
+                if let Err(e) = storage.save_sync_state(engine.id, &server.to_string(), &have) {
+                    tracing::warn!("Could not store what {} has: {}", server, e);
+                }
+            }
+        }
+    }
+    tracing::info!(documents = stored.len(), "Stored every document");
+}
+
+/// Move attached frontends on to another engine with the shared document,
+/// or disconnect them if there isn't one
+fn rehost<B>(engines: &BTreeMap<PeerId, DocumentEngine<B>>, attach_host: &Cell<Option<PeerId>>, attached: &RefCell<Vec<AttachedFrontend>>) {
+    attach_host.set(engines.iter().find(|(_, e)| e.document == SHARED_DOCUMENT).map(|(key, _)| *key));
+    if attach_host.get().is_none() {
+        attached.borrow_mut().clear();
+    }
+}
+
+/// Apply `changes` to every engine with `document` other than `except`,
+/// which with one engine a document is none of them
+fn forward<B: BackendHandle>(
+    engines: &mut BTreeMap<PeerId, DocumentEngine<B>>,
+    document: PeerId,
+    except: Option<PeerId>,
+    changes: Vec<Change>,
+    deliver: &impl Fn(PeerId, Fanout, Vec<ChangeMeta>),
+    journal: &mut Option<journal::Journal>,
+    chaos: Option<&mut Chaos>,
+) {
+    let mut replicas: Vec<(PeerId, &mut DocumentEngine<B>)> = engines.iter_mut()
+        .filter(|(key, engine)| engine.document == document && Some(**key) != except)
+        .map(|(key, engine)| (*key, engine))
+        .collect();
+    // They're journaled when they're delivered
+    if let Some(chaos) = chaos {
+        for (key, _) in &replicas {
+            chaos.hold(*key, changes.clone());
+        }
+        return;
+    }
+    if let Some(journal) = journal {
+        for (key, _) in &replicas {
+            journal.changes(*key, &changes);
+        }
+    }
+    if let Some(((last_key, last), rest)) = replicas.split_last_mut() {
+        let meta = metas(&changes);
+        for (key, engine) in rest {
+            if let Some(fanout) = engine.apply_changes(changes.clone(), None) {
+                deliver(*key, fanout, meta.clone());
+            }
+        }
+        if let Some(fanout) = last.apply_changes(changes, None) {
+            deliver(*last_key, fanout, meta);
+        }
+    }
+}
+
+/// Deliver the batches `chaos` was holding back which are due by `now`, or
+/// all of them with no `now`, and check the replicas agree once nothing is
+/// held back
+fn deliver_held<B: BackendHandle>(
+    chaos: &mut Chaos,
+    engines: &mut BTreeMap<PeerId, DocumentEngine<B>>,
+    now: Option<Instant>,
+    deliver: &impl Fn(PeerId, Fanout, Vec<ChangeMeta>),
+    journal: &mut Option<journal::Journal>,
+) {
+    for (to, changes) in chaos.due(now) {
+        // The doc may have gone away while they were held
+        if let Some(engine) = engines.get_mut(&to) {
+            if let Some(journal) = journal {
+                journal.changes(to, &changes);
+            }
+            let meta = metas(&changes);
+            if let Some(fanout) = engine.apply_changes(changes, None) {
+                deliver(to, fanout, meta);
+            }
+        }
+    }
+    if chaos.wants_check() {
+        let replicas: Vec<(PeerId, PeerId, Vec<amp::ChangeHash>)> = engines.iter_mut()
+            .map(|(key, engine)| (*key, engine.document, engine.backend.get_heads()))
+            .collect();
+        chaos.check(&replicas);
+    }
+}
+
+/// Send the UI a message it has to have, a patch, a resync or a history it
+/// asked for. The window can close before the backend thread has finished,
+/// which carries on regardless, to save the last changes.
+fn tell(scope: &impl Ui, message: Message) {
+    if let Err(e) = scope.try_send(message) {
+        tracing::warn!("Could not send the UI a message: {}", e);
+    }
+}
+
+/// What the UI needs to know about `changes`, to send along with the patch
+/// applying them
+fn metas(changes: &[Change]) -> Vec<ChangeMeta> {
+    changes.iter().map(ChangeMeta::new).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    //! The backend thread as the app runs it, with fake frontends on the
+    //! test's thread in place of the docs and GTK: two tabs on one document
+    //! typing into their own channels at once, with the patches coming back
+    //! over a channel, until everything has settled and they have to have the
+    //! same text as each other and as the backend. The tabs either share the
+    //! document's engine or, with `backend_per_tab`, are replicas with one
+    //! each. With latency every change request and every patch is held back
+    //! for a random few milliseconds on its way, in order, as a busy UI
+    //! thread would hold them, so requests pile up in the channels and
+    //! patches for the other replica's changes arrive while our own are in
+    //! flight. With chaos, see `chaos.rs`, the backend thread holds back what
+    //! goes from one replica's backend to the other's as well.
+
+    use automerge_backend::Backend;
+    use automerge_frontend::{Frontend, LocalChange, Path};
+    use std::collections::VecDeque;
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use crate::chaos::ChaosConfig;
+    use crate::doc::{deleted, inserted, text_value};
+    use crate::schema::Schema;
+    use crate::stress::{Rng, PHRASE};
+
+    impl Ui for crossbeam::Sender<Message> {
+        fn try_send(&self, message: Message) -> Result<(), String> {
+            crossbeam::Sender::try_send(self, message).map_err(|e| e.to_string())
+        }
+    }
+
+    /// A doc without the text buffer
+    struct FakeFrontend {
+        frontend: Frontend,
+        requests: crossbeam::Sender<amp::Request>,
+        /// Requests made but not yet sent, and when they go
+        outgoing: VecDeque<(Instant, amp::Request)>,
+        /// Patches received but not yet applied, and when they are
+        incoming: VecDeque<(Instant, amp::Patch)>,
+        sent: usize,
+        acknowledged: usize,
+    }
+
+    impl FakeFrontend {
+        fn new(requests: crossbeam::Sender<amp::Request>) -> FakeFrontend {
+            FakeFrontend {
+                frontend: Frontend::new(),
+                requests,
+                outgoing: VecDeque::new(),
+                incoming: VecDeque::new(),
+                sent: 0,
+                acknowledged: 0,
+            }
+        }
+
+        /// When something held back now goes, never before what was held
+        /// back before it
+        fn due(queue_last: Option<Instant>, rng: &Rng, latency_ms: u64) -> Instant {
+            let due = Instant::now() + Duration::from_millis(rng.next(latency_ms as usize + 1) as u64);
+            queue_last.map_or(due, |last| last.max(due))
+        }
+
+        fn edit(&mut self, changes: Vec<LocalChange>, rng: &Rng, latency_ms: u64) {
+            let request = self.frontend.change(None, |doc| {
+                for change in changes {
+                    doc.add_change(change)?;
+                }
+                Ok(())
+            }).unwrap();
+            if let Some(request) = request {
+                let due = FakeFrontend::due(self.outgoing.back().map(|(due, _)| *due), rng, latency_ms);
+                self.outgoing.push_back((due, request));
+                self.sent += 1;
+            }
+        }
+
+        fn receive(&mut self, patch: amp::Patch, rng: &Rng, latency_ms: u64) {
+            let due = FakeFrontend::due(self.incoming.back().map(|(due, _)| *due), rng, latency_ms);
+            self.incoming.push_back((due, patch));
+        }
+
+        /// Send and apply whatever is due, or everything with no `now`
+        fn flush(&mut self, now: Option<Instant>) {
+            let is_due = |due: &Instant| now.map_or(true, |now| *due <= now);
+            while self.outgoing.front().map_or(false, |(due, _)| is_due(due)) {
+                let (_, request) = self.outgoing.pop_front().unwrap();
+                self.requests.send(request).unwrap();
+            }
+            while self.incoming.front().map_or(false, |(due, _)| is_due(due)) {
+                let (_, patch) = self.incoming.pop_front().unwrap();
+                if patch.actor == Some(self.frontend.actor_id.to_string()) {
+                    self.acknowledged += 1;
+                }
+                self.frontend.apply_patch(patch).unwrap();
+            }
+        }
+
+        fn len(&self) -> usize {
+            text_value(&self.frontend).chars().count()
+        }
+    }
+
+    fn converge(latency_ms: u64, edits: usize, chaos: Option<ChaosConfig>, per_tab: bool) {
+        // How long after the last change request chaos can still be holding
+        // back what it led to
+        let settle = chaos.as_ref().map_or(Duration::from_secs(0), |chaos| chaos.max_pause + chaos.max_delay);
+        let (closesx, closerx) = crossbeam::channel::unbounded();
+        let (ui_sx, ui_rx) = crossbeam::channel::unbounded::<Message>();
+        let (commands_sx, commands_rx) = crossbeam::channel::unbounded();
+        let config = BackendConfig {
+            storage: None,
+            journal: None,
+            signatures: None,
+            transports: Vec::new(),
+            metrics: Arc::new(metrics::Metrics::default()),
+            chaos: chaos.map(Chaos::new),
+            backend_per_tab: per_tab,
+        };
+        let thread = std::thread::spawn(move || run_backends(|_, _| Backend::init(), closerx, ui_sx, commands_rx, config));
+
+        let id = DocumentId::random();
+        let mut frontends = Vec::new();
+        for (n, start) in [PeerStart::Empty, PeerStart::ReplicaOf(PeerId(0))].iter().enumerate() {
+            let (requests_sx, requests) = crossbeam::channel::unbounded();
+            commands_sx.send(BackendCommand::AddPeer { peer_id: PeerId(n), id, requests, start: start.clone() }).unwrap();
+            frontends.push(FakeFrontend::new(requests_sx));
+        }
+        let rng = Rng::new(latency_ms + edits as u64);
+        // The first makes the document, as a new tab does, and the other
+        // waits until it has the text before typing into it
+        frontends[0].edit(Schema::default().initial_changes(), &rng, latency_ms);
+        let phrase: Vec<char> = PHRASE.chars().collect();
+        let mut made = [0, 0];
+        while made.iter().any(|made| *made < edits) || frontends.iter().any(|f| f.acknowledged < f.sent) {
+            while let Ok(message) = ui_rx.try_recv() {
+                match message {
+                    Message::Patch(envelope) => frontends[envelope.peer_id.0].receive(envelope.patch, &rng, latency_ms),
+                    Message::Resync(envelope) => panic!("{} had to resync", envelope.peer_id),
+                    _ => {}
+                }
+            }
+            let now = Instant::now();
+            for frontend in &mut frontends {
+                frontend.flush(Some(now));
+            }
+            let who = rng.next(2);
+            let has_text = frontends[who].frontend.get_value(&Path::root().key("text")).is_some();
+            if made[who] < edits && has_text {
+                let len = frontends[who].len();
+                let changes = if len > 0 && rng.next(4) == 0 {
+                    let at = rng.next(len);
+                    deleted(at, (at + 1 + rng.next(3)).min(len))
+                } else {
+                    inserted(rng.next(len + 1), &phrase[made[who] % phrase.len()].to_string())
+                };
+                frontends[who].edit(changes, &rng, latency_ms);
+                made[who] += 1;
+            }
+            std::thread::sleep(Duration::from_micros(200));
+        }
+
+        let settled = Instant::now() + settle;
+        while Instant::now() < settled {
+            for message in ui_rx.try_iter() {
+                if let Message::Patch(envelope) = message {
+                    frontends[envelope.peer_id.0].receive(envelope.patch, &rng, latency_ms);
+                }
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        // Everything each request led to was sent before the backend
+        // answered this, and before it stopped
+        let (reply, state) = crossbeam::channel::bounded(1);
+        commands_sx.send(BackendCommand::GetState { document: SHARED_DOCUMENT, reply }).unwrap();
+        let state = state.recv().unwrap();
+        closesx.send(()).unwrap();
+        thread.join().unwrap();
+        for message in ui_rx.try_iter() {
+            if let Message::Patch(envelope) = message {
+                frontends[envelope.peer_id.0].receive(envelope.patch, &rng, latency_ms);
+            }
+        }
+        for frontend in &mut frontends {
+            frontend.flush(None);
+        }
+        let mut backend = Frontend::new();
+        backend.apply_patch(state).unwrap();
+        let texts: Vec<String> = frontends.iter().map(|f| text_value(&f.frontend)).collect();
+        assert_eq!(texts[0], texts[1]);
+        assert_eq!(texts[0], text_value(&backend));
+        assert!(!texts[0].is_empty());
+    }
+
+    #[test]
+    fn tabs_sharing_an_engine_converge() {
+        converge(0, 300, None, false);
+    }
+
+    #[test]
+    fn tabs_sharing_an_engine_converge_with_latency() {
+        converge(5, 200, None, false);
+    }
+
+    #[test]
+    fn replicas_converge() {
+        converge(0, 300, None, true);
+    }
+
+    #[test]
+    fn replicas_converge_with_latency() {
+        converge(5, 200, None, true);
+    }
+
+    #[test]
+    fn replicas_converge_under_chaos() {
+        let chaos = ChaosConfig { seed: 7, max_delay: Duration::from_millis(20), max_pause: Duration::from_millis(100) };
+        converge(2, 200, Some(chaos), true);
+    }
+}
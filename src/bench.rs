@@ -0,0 +1,127 @@
+//! `automerge-demo bench`, a quick look at what typing costs without a
+//! window in the way.
+//!
+//! One peer types, see `simulate.rs`, and every change it makes is applied
+//! to a second peer straight away, which is the path a keystroke takes
+//! between two tabs: the change request, the typist's backend and the patch
+//! back to its frontend, then the other backend and the patch to the other
+//! frontend. Each stage is timed on its own. `cargo bench` measures the same
+//! things more carefully, this is for a number to compare while changing
+//! something, or on a machine without criterion.
+//!
+//! With `--unified` the same typing is done on the unified automerge crate,
+//! see `unified.rs`, where there's no request or patch: each change is one
+//! transaction, then applied to the other peer. On top of that it times a
+//! third peer with nothing catching up over the sync protocol.
+
+use std::time::{Duration, Instant};
+
+use crate::cli::BenchArgs;
+use crate::simulate::{insert_char, Peer};
+use crate::stress::PHRASE;
+#[cfg(feature = "unified")]
+use crate::unified::UnifiedPeer;
+
+/// How long one stage took over the whole run, and at most for one change
+#[derive(Default)]
+struct Stage {
+    total: Duration,
+    max: Duration,
+}
+
+impl Stage {
+    fn time<T>(&mut self, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        let took = start.elapsed();
+        self.total += took;
+        self.max = self.max.max(took);
+        result
+    }
+
+    fn report(&self, name: &str, changes: usize) {
+        println!(
+            "{:<16} {:>10.3}ms total {:>8.1}\u{b5}s per change {:>8.1}\u{b5}s at most",
+            name,
+            self.total.as_secs_f64() * 1000.0,
+            self.total.as_secs_f64() * 1e6 / changes.max(1) as f64,
+            self.max.as_secs_f64() * 1e6,
+        );
+    }
+}
+
+pub fn run(args: &BenchArgs) -> Result<(), String> {
+    #[cfg(feature = "unified")]
+    {
+        if args.unified {
+            return run_unified(args);
+        }
+    }
+    let per_change = args.per_change.max(1);
+    let (mut typist, history) = Peer::creating()?;
+    let mut other = Peer::default();
+    other.receive(history)?;
+    let phrase: Vec<char> = PHRASE.chars().collect();
+    let (mut request, mut local, mut remote) = (Stage::default(), Stage::default(), Stage::default());
+    let mut changes = 0;
+    let start = Instant::now();
+    let mut typed = 0;
+    while typed < args.chars {
+        let n = per_change.min(args.chars - typed);
+        let edits = (typed..typed + n).map(|i| insert_char(i, phrase[i % phrase.len()])).collect();
+        let cr = match request.time(|| typist.request(edits))? {
+            Some(cr) => cr,
+            None => break,
+        };
+        let made = local.time(|| typist.apply_request(cr))?;
+        remote.time(|| other.receive(made))?;
+        typed += n;
+        changes += 1;
+    }
+    let elapsed = start.elapsed();
+    println!("typed {} characters in {} changes in {:.3}s, {:.0} characters a second",
+        typed, changes, elapsed.as_secs_f64(), typed as f64 / elapsed.as_secs_f64());
+    request.report("change request", changes);
+    local.report("local apply", changes);
+    remote.report("remote apply", changes);
+    if typist.text() != other.text() {
+        return Err("the two peers ended up with different text".to_string());
+    }
+    Ok(())
+}
+
+#[cfg(feature = "unified")]
+fn run_unified(args: &BenchArgs) -> Result<(), String> {
+    use crate::simulate::Editor;
+
+    let per_change = args.per_change.max(1);
+    let mut typist = UnifiedPeer::creating()?;
+    let mut other = typist.fork();
+    let phrase: Vec<char> = PHRASE.chars().collect();
+    let (mut local, mut remote) = (Stage::default(), Stage::default());
+    let mut changes = 0;
+    let start = Instant::now();
+    let mut typed = 0;
+    while typed < args.chars {
+        let n = per_change.min(args.chars - typed);
+        let edit: String = (typed..typed + n).map(|i| phrase[i % phrase.len()]).collect();
+        let made = local.time(|| typist.splice(typed, 0, &edit))?;
+        remote.time(|| other.receive(made))?;
+        typed += n;
+        changes += 1;
+    }
+    let elapsed = start.elapsed();
+    println!("typed {} characters in {} changes in {:.3}s, {:.0} characters a second",
+        typed, changes, elapsed.as_secs_f64(), typed as f64 / elapsed.as_secs_f64());
+    local.report("transaction", changes);
+    remote.report("remote apply", changes);
+    let mut newcomer = UnifiedPeer::empty();
+    let mut sync = Stage::default();
+    let messages = sync.time(|| newcomer.sync_with(&mut typist))?;
+    sync.report("sync a new peer", 1);
+    println!("which took {} messages", messages);
+    if typist.text() != other.text() || typist.text() != newcomer.text() {
+        return Err("the peers ended up with different text".to_string());
+    }
+    Ok(())
+}
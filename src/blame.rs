@@ -0,0 +1,52 @@
+//! The blame gutter, View > Blame. Next to each line of the text it shows who
+//! made the most recent change still visible in that line and when.
+//!
+//! Only insertions leave anything behind in the text, so a line's blame is
+//! the newest change which inserted one of its characters, including the
+//! newline ending it. Deleting part of a line doesn't show up.
+
+use crate::change_log::{ChangeLog, ChangeMeta};
+use crate::offset_index::OffsetIndex;
+
+/// The most recent change behind each line of `text`, `None` for empty lines
+/// and lines whose changes we don't know about
+pub fn line_blame<'a>(text: &str, offsets: &OffsetIndex, log: &'a ChangeLog) -> Vec<Option<&'a ChangeMeta>> {
+    let mut lines = vec![None];
+    let mut ops = offsets.ops();
+    for c in text.chars() {
+        let change = ops.next().flatten().and_then(|op| log.change_for_op(op));
+        let line = lines.last_mut().unwrap();
+        if let Some(change) = change {
+            if line.map_or(true, |l: &ChangeMeta| (change.time, change.seq) > (l.time, l.seq)) {
+                *line = Some(change);
+            }
+        }
+        if c == '\n' {
+            lines.push(None);
+        }
+    }
+    lines
+}
+
+/// The gutter text, one line per line of `text`. Our own changes are shown
+/// as "you".
+pub fn gutter_text(text: &str, offsets: &OffsetIndex, log: &ChangeLog, own_actor: &str) -> String {
+    line_blame(text, offsets, log)
+        .into_iter()
+        .map(|change| match change {
+            Some(change) => {
+                let actor = if change.actor == own_actor {
+                    "you".to_string()
+                } else {
+                    change.short_actor()
+                };
+                match change.time_label() {
+                    Some(time) => format!("{:<8} {}", actor, time),
+                    None => actor,
+                }
+            }
+            None => String::new(),
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
@@ -0,0 +1,235 @@
+//! How the backend thread gets its messages to the UI thread.
+//!
+//! It used to push them straight into the vgtk scope, whose channel never
+//! fills, so when the UI fell behind, under `--stress` say, patches piled up
+//! there without the backend thread knowing, and every `try_send` that
+//! failed, once the window had gone, was an unwrap panicking the thread. Now
+//! they go through a bounded channel, and the UI thread only takes the next
+//! message off it once the model has processed the last one it was given:
+//! each goes to the scope as a `Message::Bridged`, which says when it's been
+//! handled. So there's never more than one of the backend thread's messages
+//! in the scope, and once `CAPACITY` are waiting in the channel the backend
+//! thread waits too, rather than getting further ahead of what's on the
+//! screen. When the UI has gone away sending is an error, which the backend
+//! thread logs and carries on, so it still saves the last changes.
+//!
+//! Someone typing fast in another instance is a patch a keystroke, and each
+//! one used to be a message of its own, so a render, a search and a blame
+//! update of its own. So a patch arriving with the model idle is held back
+//! for up to a frame, `FRAME_MS`, and the patches waiting in the channel go
+//! to the model together, and the docs apply each run of them for the same
+//! doc in one go. A run stops at anything else the backend sends, a resync
+//! say, which goes next, so nothing arrives out of order.
+
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::rc::Rc;
+use std::sync::mpsc::{self, TrySendError};
+use std::time::Duration;
+use vgtk::lib::glib;
+
+use crate::backend::Ui;
+use crate::peer::PatchEnvelope;
+use crate::{Message, Model};
+
+/// How many messages can be waiting for the UI thread before the backend
+/// thread waits for it
+pub const CAPACITY: usize = 1024;
+
+/// How long a patch waits for more to go to the UI with, about a frame
+pub const FRAME_MS: u32 = 16;
+
+/// How long the backend thread sleeps before trying again with the channel
+/// full
+const RETRY: Duration = Duration::from_millis(1);
+
+/// What wakes the UI thread's end up
+enum Wake {
+    /// The backend thread has sent a message
+    Sent,
+    /// The model has processed the message it was given
+    Processed,
+}
+
+/// The backend thread's end
+#[derive(Clone)]
+pub struct UiSender {
+    sender: mpsc::SyncSender<Message>,
+    wake: glib::Sender<Wake>,
+}
+
+/// Goes to the model with each message, for it to say when it's done with
+/// it
+#[derive(Clone)]
+pub struct Processed(glib::Sender<Wake>);
+
+impl Processed {
+    pub fn done(&self) {
+        // Nobody to tell once the bridge is closed
+        let _ = self.0.send(Wake::Processed);
+    }
+}
+
+impl fmt::Debug for Processed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Processed")
+    }
+}
+
+/// The UI thread's end, which has to be closed once the UI has stopped, so
+/// a backend thread waiting for room finds out it's gone
+pub struct Bridge {
+    source: glib::SourceId,
+    /// Whether the source removed itself, with the UI gone first
+    removed: Rc<Cell<bool>>,
+    forward: Rc<RefCell<Forward>>,
+}
+
+/// Taking messages off the channel for the model, one at a time
+struct Forward {
+    receiver: mpsc::Receiver<Message>,
+    scope: vgtk::Scope<Model>,
+    processed: glib::Sender<Wake>,
+    /// Whether the model has a message from us it hasn't processed yet
+    busy: bool,
+    /// A message taken off the channel which hasn't gone yet, held back for
+    /// the frame or stopping a run of patches
+    next: Option<Message>,
+    /// The timer holding back a patch for the frame
+    timer: Option<glib::SourceId>,
+}
+
+/// Make a channel to `scope` on the main context, from the UI thread
+pub fn channel(scope: vgtk::Scope<Model>) -> (UiSender, Bridge) {
+    let (sender, receiver) = mpsc::sync_channel(CAPACITY);
+    let (wake, woken) = glib::MainContext::channel(glib::PRIORITY_DEFAULT);
+    let forward = Rc::new(RefCell::new(Forward {
+        receiver,
+        scope,
+        processed: wake.clone(),
+        busy: false,
+        next: None,
+        timer: None,
+    }));
+    let removed = Rc::new(Cell::new(false));
+    let removing = removed.clone();
+    let forwarding = forward.clone();
+    let source = woken.attach(None, move |wake| {
+        let forwarded = match wake {
+            Wake::Sent => forward_from(&forwarding, true),
+            Wake::Processed => {
+                forwarding.borrow_mut().busy = false;
+                forward_from(&forwarding, false)
+            }
+        };
+        match forwarded {
+            Ok(()) => glib::Continue(true),
+            Err(e) => {
+                tracing::warn!("The UI has gone, dropping what the backend sends it: {}", e);
+                removing.set(true);
+                forwarding.borrow_mut().stop();
+                glib::Continue(false)
+            }
+        }
+    });
+    (UiSender{sender, wake}, Bridge{source, removed, forward})
+}
+
+/// Give the model the next message if it's ready for one. With `hold` a
+/// patch is held back for the frame first, as the model has been idle and
+/// there won't be any others waiting with it yet.
+fn forward_from(forward: &Rc<RefCell<Forward>>, hold: bool) -> Result<(), String> {
+    let mut this = forward.borrow_mut();
+    if this.busy || this.timer.is_some() {
+        return Ok(());
+    }
+    let message = match this.next.take().or_else(|| this.receiver.try_recv().ok()) {
+        Some(message) => message,
+        None => return Ok(()),
+    };
+    match message {
+        Message::Patch(envelope) if hold => {
+            this.next = Some(Message::Patch(envelope));
+            let forward_later = forward.clone();
+            this.timer = Some(glib::timeout_add_local(FRAME_MS, move || {
+                forward_later.borrow_mut().timer.take();
+                if let Err(e) = forward_from(&forward_later, false) {
+                    tracing::warn!("The UI has gone, dropping the patches held for it: {}", e);
+                }
+                glib::Continue(false)
+            }));
+            Ok(())
+        }
+        Message::Patch(envelope) => {
+            let message = this.run_from(envelope);
+            this.send(message)
+        }
+        message => this.send(message),
+    }
+}
+
+impl Forward {
+    /// The patches waiting after `first`, up to the next message which
+    /// isn't one, as one message
+    fn run_from(&mut self, first: PatchEnvelope) -> Message {
+        let mut patches = vec![first];
+        while patches.len() < CAPACITY {
+            match self.receiver.try_recv() {
+                Ok(Message::Patch(envelope)) => patches.push(envelope),
+                Ok(message) => {
+                    self.next = Some(message);
+                    break;
+                }
+                Err(_) => break,
+            }
+        }
+        match patches.len() {
+            1 => Message::Patch(patches.remove(0)),
+            _ => Message::Patches(patches),
+        }
+    }
+
+    fn send(&mut self, message: Message) -> Result<(), String> {
+        let processed = Processed(self.processed.clone());
+        self.scope.try_send(Message::Bridged(Box::new(message), processed)).map_err(|e| e.to_string())?;
+        self.busy = true;
+        Ok(())
+    }
+
+    fn stop(&mut self) {
+        if let Some(timer) = self.timer.take() {
+            glib::source_remove(timer);
+        }
+    }
+}
+
+impl Bridge {
+    pub fn close(self) {
+        self.forward.borrow_mut().stop();
+        if !self.removed.get() {
+            glib::source_remove(self.source);
+        }
+    }
+}
+
+impl Ui for UiSender {
+    fn try_send(&self, message: Message) -> Result<(), String> {
+        let mut message = message;
+        let mut waited = false;
+        loop {
+            match self.sender.try_send(message) {
+                Ok(()) => break,
+                Err(TrySendError::Full(back)) => {
+                    if !waited {
+                        tracing::debug!("The UI is {} messages behind, waiting for it", CAPACITY);
+                        waited = true;
+                    }
+                    message = back;
+                    std::thread::sleep(RETRY);
+                }
+                Err(TrySendError::Disconnected(_)) => return Err("the UI has gone away".to_string()),
+            }
+        }
+        self.wake.send(Wake::Sent).map_err(|_| "the UI has gone away".to_string())
+    }
+}
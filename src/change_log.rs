@@ -0,0 +1,110 @@
+//! What we know about the changes behind the text.
+//!
+//! Patches say what changed but not which change did it, or when. The backend
+//! thread has the changes themselves though, so it sends a `ChangeMeta` for
+//! each one along with the patch which applies it (see `PatchEnvelope`) and
+//! each `Doc` keeps them in a `ChangeLog`. Together with the provenance index,
+//! which knows the op that inserted each character, that is enough to go from
+//! a character to the change it came from: an actor's ops are numbered
+//! consecutively across its changes, so the op belongs to the actor's last
+//! change starting at or before it.
+
+use automerge_backend::Change;
+use automerge_protocol as amp;
+use std::collections::HashMap;
+use vgtk::lib::glib;
+
+/// How much of an actor ID to show, they're long and random
+pub const ACTOR_CHARS: usize = 8;
+
+/// The parts of a change we need in the UI, without its ops
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChangeMeta {
+    pub hash: amp::ChangeHash,
+    pub actor: String,
+    pub seq: u64,
+    /// The counter of the first op in the change
+    pub start_op: u64,
+    /// Seconds since the epoch, as recorded by whoever made the change. Zero
+    /// if they didn't record one.
+    pub time: i64,
+    pub message: Option<String>,
+    /// How many ops it has, and its size encoded
+    pub ops: usize,
+    pub bytes: usize,
+}
+
+impl ChangeMeta {
+    pub fn new(change: &Change) -> ChangeMeta {
+        ChangeMeta {
+            hash: change.hash,
+            actor: change.actor_id().to_string(),
+            seq: change.seq,
+            start_op: change.start_op,
+            time: change.time,
+            message: change.message(),
+            ops: change.len(),
+            bytes: change.raw_bytes().len(),
+        }
+    }
+
+    /// The start of the actor ID, enough to tell actors apart at a glance
+    pub fn short_actor(&self) -> String {
+        self.actor.chars().take(ACTOR_CHARS).collect()
+    }
+
+    /// When the change was made, in local time, or `None` if we don't know
+    pub fn time_label(&self) -> Option<String> {
+        if self.time == 0 {
+            return None;
+        }
+        glib::DateTime::new_from_unix_local(self.time)
+            .format("%Y-%m-%d %H:%M")
+            .map(|s| s.to_string())
+    }
+}
+
+/// Every change a doc has seen, by actor
+#[derive(Default)]
+pub struct ChangeLog {
+    /// Each actor's changes in seq order
+    by_actor: HashMap<String, Vec<ChangeMeta>>,
+}
+
+impl ChangeLog {
+    pub fn record(&mut self, meta: ChangeMeta) {
+        let changes = self.by_actor.entry(meta.actor.clone()).or_insert_with(Vec::new);
+        match changes.binary_search_by_key(&meta.seq, |c| c.seq) {
+            // Replicas can be sent the same change twice, keep the first
+            Ok(_) => {}
+            Err(pos) => changes.insert(pos, meta),
+        }
+    }
+
+    /// The seq of the actor's latest change, zero if it hasn't made any
+    pub fn latest_seq(&self, actor: &str) -> u64 {
+        self.by_actor.get(actor).and_then(|c| c.last()).map_or(0, |c| c.seq)
+    }
+
+    /// Forget the actor's changes after `seq`, which were never made after
+    /// all
+    pub fn forget_after(&mut self, actor: &str, seq: u64) {
+        if let Some(changes) = self.by_actor.get_mut(actor) {
+            changes.retain(|c| c.seq <= seq);
+        }
+    }
+
+    /// The change containing the op with ID `op_id`, which looks like
+    /// `counter@actor`
+    pub fn change_for_op(&self, op_id: &str) -> Option<&ChangeMeta> {
+        let mut parts = op_id.splitn(2, '@');
+        let counter: u64 = parts.next()?.parse().ok()?;
+        let changes = self.by_actor.get(parts.next()?)?;
+        // Start ops increase with seq, so this is the last change which
+        // starts at or before the op
+        match changes.binary_search_by_key(&counter, |c| c.start_op) {
+            Ok(pos) => changes.get(pos),
+            Err(pos) => changes.get(pos.checked_sub(1)?),
+        }
+    }
+}
@@ -0,0 +1,165 @@
+//! `--chaos`, making the backend thread a bad network between the backends
+//! of one document's replicas, to see that its routing copes and not just
+//! automerge.
+//!
+//! Normally the changes one replica makes are applied to the others in the
+//! same pass of the backend thread's loop, in order and exactly once. With
+//! chaos every batch on its way to another replica is held back for a random
+//! time up to `--chaos-delay-ms`, so batches overtake each other and a
+//! backend gets changes before the ones they depend on. Every so often a
+//! replica stops receiving anything for up to `--chaos-pause-ms`, as if it
+//! were cut off, and gets all it missed at once afterwards, and every so
+//! often a batch is delivered twice. Only the deliveries between replicas
+//! are affected: each tab's own patches come straight back, so typing feels
+//! the same.
+//!
+//! Whenever nothing is held back any more every replica of a document has to
+//! have the same heads, which the backend thread checks and logs an error
+//! for if not, see `check`. That's the same promise Tools > Verify
+//! Convergence checks, but checked right when it has to hold rather than
+//! whenever someone thinks to look.
+
+use automerge_backend::Change;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::peer::PeerId;
+use crate::stress::Rng;
+
+/// One in this many batches starts a pause for the replica it's going to
+const PAUSE_ONE_IN: usize = 50;
+
+/// One in this many batches is delivered a second time
+const DUPLICATE_ONE_IN: usize = 10;
+
+/// The bounds chaos stays within, from the command line
+#[derive(Clone, Debug)]
+pub struct ChaosConfig {
+    pub seed: u64,
+    pub max_delay: Duration,
+    pub max_pause: Duration,
+}
+
+/// A batch of changes on its way to a replica
+struct Held {
+    to: PeerId,
+    due: Instant,
+    changes: Vec<Change>,
+}
+
+pub struct Chaos {
+    config: ChaosConfig,
+    rng: Rng,
+    held: Vec<Held>,
+    /// Replicas which are cut off, until when
+    paused: HashMap<PeerId, Instant>,
+    /// Whether something has been delivered since the replicas were last
+    /// checked
+    unchecked: bool,
+    delivered: usize,
+    duplicated: usize,
+    pauses: usize,
+    violations: usize,
+}
+
+impl Chaos {
+    pub fn new(config: ChaosConfig) -> Chaos {
+        tracing::info!(?config, "Chaos between the backends");
+        Chaos {
+            rng: Rng::new(config.seed),
+            config,
+            held: Vec::new(),
+            paused: HashMap::new(),
+            unchecked: false,
+            delivered: 0,
+            duplicated: 0,
+            pauses: 0,
+            violations: 0,
+        }
+    }
+
+    /// How long up to `max`, at random
+    fn up_to(&self, max: Duration) -> Duration {
+        Duration::from_millis(self.rng.next(max.as_millis() as usize + 1) as u64)
+    }
+
+    /// Hold `changes` back on their way to `to`
+    pub fn hold(&mut self, to: PeerId, changes: Vec<Change>) {
+        let now = Instant::now();
+        if self.rng.next(PAUSE_ONE_IN) == 0 && !self.paused.contains_key(&to) {
+            let until = now + self.up_to(self.config.max_pause);
+            tracing::info!(replica = to.0, ms = (until - now).as_millis() as u64, "Chaos cut a replica off");
+            self.paused.insert(to, until);
+            self.pauses += 1;
+        }
+        let earliest = self.paused.get(&to).copied().unwrap_or(now).max(now);
+        if self.rng.next(DUPLICATE_ONE_IN) == 0 {
+            let due = earliest + self.up_to(self.config.max_delay);
+            self.held.push(Held{to, due, changes: changes.clone()});
+            self.duplicated += 1;
+        }
+        let due = earliest + self.up_to(self.config.max_delay);
+        self.held.push(Held{to, due, changes});
+    }
+
+    /// Hold back for `to` copies of everything held back for `from`, for a
+    /// new replica starting from the history `from` has so far
+    pub fn copy_held(&mut self, from: PeerId, to: PeerId) {
+        let copies: Vec<Held> = self.held.iter()
+            .filter(|held| held.to == from)
+            .map(|held| Held{to, due: held.due, changes: held.changes.clone()})
+            .collect();
+        self.held.extend(copies);
+    }
+
+    /// When the next batch is due, if any are held
+    pub fn next_due(&self) -> Option<Instant> {
+        self.held.iter().map(|held| held.due).min()
+    }
+
+    /// The batches due by `now`, in the order they're due, or every batch
+    /// with no `now`, for shutting down
+    pub fn due(&mut self, now: Option<Instant>) -> Vec<(PeerId, Vec<Change>)> {
+        let (mut due, held): (Vec<Held>, Vec<Held>) = self.held.drain(..).partition(|held| now.map_or(true, |now| held.due <= now));
+        self.held = held;
+        if let Some(now) = now {
+            self.paused.retain(|_, until| *until > now);
+        } else {
+            self.paused.clear();
+        }
+        due.sort_by_key(|held| held.due);
+        self.delivered += due.len();
+        self.unchecked |= !due.is_empty();
+        due.into_iter().map(|held| (held.to, held.changes)).collect()
+    }
+
+    /// Whether the replicas' heads are to be compared now: something has
+    /// been delivered since they last were, and nothing's still held back
+    pub fn wants_check(&self) -> bool {
+        self.unchecked && self.held.is_empty()
+    }
+
+    /// Check that the replicas of each document, with their heads, have
+    /// the same heads
+    pub fn check<H: PartialEq + std::fmt::Debug>(&mut self, replicas: &[(PeerId, PeerId, Vec<H>)]) {
+        self.unchecked = false;
+        for (peer, document, heads) in replicas {
+            let first = replicas.iter().find(|(_, d, _)| d == document).unwrap();
+            let same = heads.len() == first.2.len() && heads.iter().all(|head| first.2.contains(head));
+            if !same {
+                self.violations += 1;
+                tracing::error!(replica = peer.0, other = first.0.0, "Chaos left replicas of one document with different heads: {:?} and {:?}", heads, first.2);
+            }
+        }
+    }
+
+    pub fn report(&self) {
+        tracing::info!(
+            delivered = self.delivered,
+            duplicated = self.duplicated,
+            pauses = self.pauses,
+            violations = self.violations,
+            "Chaos is over"
+        );
+    }
+}
@@ -0,0 +1,155 @@
+//! A chat stored in the document at `root.chat`, a list of maps
+//!
+//! ```text
+//! [{ "author": "Doc 1", "time": Timestamp(1700000000000), "body": "Hi" }, ...]
+//! ```
+//!
+//! Keeping the chat in the document means it syncs, saves and replays along
+//! with everything else, and collaborators see what was said about the text
+//! next to the text. Messages are only ever appended, never edited, so two
+//! people sending at once both get their message in, in the same order in
+//! every window.
+//!
+//! A long running document can collect a lot of chat, so rather than read
+//! the whole list out of the frontend after every patch like the checklist
+//! does, `ChatLog` keeps its own copy and applies each patch's sequence diff
+//! for `chat` to it: the edits say where messages were inserted and removed,
+//! and the diff of each inserted map has all of its fields. The pane then
+//! only has widgets to add for the new messages.
+//!
+//! Documents made before the chat existed don't have `root.chat`, the first
+//! message sent creates it. Two instances doing that at the same time
+//! conflict, and the messages in the list which loses disappear.
+
+use automerge_frontend::{Frontend, LocalChange, Path, Value};
+use automerge_protocol as amp;
+use maplit::hashmap;
+use std::time::{SystemTime, UNIX_EPOCH};
+use vgtk::lib::glib;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ChatMessage {
+    pub author: String,
+    /// Milliseconds since the epoch
+    pub time: i64,
+    pub body: String,
+}
+
+impl ChatMessage {
+    /// When the message was sent, in local time
+    pub fn time_label(&self) -> String {
+        glib::DateTime::new_from_unix_local(self.time / 1000)
+            .format("%H:%M")
+            .map(|s| s.to_string())
+            .unwrap_or_default()
+    }
+}
+
+/// The value to initialize `root.chat` with
+pub fn initial_value() -> Value {
+    Value::Sequence(Vec::new(), amp::SequenceType::List)
+}
+
+fn path() -> Path {
+    Path::root().key("chat")
+}
+
+fn message_value(message: &ChatMessage) -> Value {
+    Value::Map(
+        hashmap! {
+            "author".to_string() => Value::Primitive(amp::Value::Str(message.author.clone())),
+            "time".to_string() => Value::Primitive(amp::Value::Timestamp(message.time)),
+            "body".to_string() => Value::Primitive(amp::Value::Str(message.body.clone())),
+        },
+        amp::MapType::Map,
+    )
+}
+
+/// Append a message from `author` to the chat, returning the change request
+/// to send to the backend
+pub fn send(frontend: &mut Frontend, author: &str, body: &str) -> Option<amp::Request> {
+    let body = body.trim();
+    if body.is_empty() {
+        return None;
+    }
+    let message = ChatMessage {
+        author: author.to_string(),
+        time: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|t| t.as_millis() as i64)
+            .unwrap_or(0),
+        body: body.to_string(),
+    };
+    let len = match frontend.get_value(&path()) {
+        Some(Value::Sequence(messages, _)) => Some(messages.len()),
+        _ => None,
+    };
+    frontend
+        .change(Some(format!("Chat: {}", message.body)), |doc| {
+            if len.is_none() {
+                doc.add_change(LocalChange::set(path(), initial_value()))?;
+            }
+            doc.add_change(LocalChange::insert(path().index(len.unwrap_or(0)), message_value(&message)))?;
+            Ok(())
+        })
+        .unwrap()
+}
+
+/// The chat as of the last patch, kept up to date from the patches
+/// themselves
+#[derive(Default)]
+pub struct ChatLog {
+    messages: Vec<ChatMessage>,
+}
+
+impl ChatLog {
+    pub fn messages(&self) -> &[ChatMessage] {
+        &self.messages
+    }
+
+    /// Apply the patch's changes to the chat, returning whether there were
+    /// any
+    pub fn apply_patch(&mut self, patch: &amp::Patch) -> bool {
+        let values = match &patch.diffs {
+            Some(amp::Diff::Map(root)) => match root.props.get("chat") {
+                Some(values) => values,
+                None => return false,
+            },
+            _ => return false,
+        };
+        let seq = match values.values().next() {
+            Some(amp::Diff::Seq(seq)) => seq,
+            // The list was deleted, or replaced with something else
+            _ => {
+                self.messages.clear();
+                return true;
+            }
+        };
+        for edit in &seq.edits {
+            match edit {
+                amp::DiffEdit::Insert { index } => {
+                    let index = (*index).min(self.messages.len());
+                    self.messages.insert(index, ChatMessage::default());
+                }
+                amp::DiffEdit::Remove { index } => {
+                    if *index < self.messages.len() {
+                        self.messages.remove(*index);
+                    }
+                }
+            }
+        }
+        for (index, values) in &seq.props {
+            if let (Some(message), Some(amp::Diff::Map(map))) = (self.messages.get_mut(*index), values.values().next()) {
+                for (key, values) in &map.props {
+                    match (key.as_str(), values.values().next()) {
+                        ("author", Some(amp::Diff::Value(amp::Value::Str(s)))) => message.author = s.clone(),
+                        ("body", Some(amp::Diff::Value(amp::Value::Str(s)))) => message.body = s.clone(),
+                        ("time", Some(amp::Diff::Value(amp::Value::Timestamp(t)))) => message.time = *t,
+                        _ => {}
+                    }
+                }
+            }
+        }
+        true
+    }
+}
@@ -0,0 +1,146 @@
+//! A checklist stored in the document at `root.todos`, a list of maps
+//!
+//! ```text
+//! [{ "title": "Buy milk", "done": false }, ...]
+//! ```
+//!
+//! This is here to show off list CRDT semantics next to the text. Some things
+//! worth trying with two windows: add an item in each window at the same
+//! time (both survive, in an order which is the same in both windows), rename
+//! an item in one window while deleting it in the other (the delete wins, the
+//! rename is lost along with the item), and move an item while renaming it.
+//! There is no move operation in automerge so a move is a delete followed
+//! by an insert of a copy, which means a concurrent rename of the moved item
+//! is applied to the deleted original and disappears.
+
+use automerge_frontend::{Frontend, LocalChange, Path, Value};
+use automerge_protocol as amp;
+use maplit::hashmap;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Todo {
+    pub title: String,
+    pub done: bool,
+}
+
+#[derive(Clone, Debug)]
+pub enum TodoAction {
+    Add(String),
+    Toggle(usize),
+    Rename(usize, String),
+    Move { from: usize, to: usize },
+    Delete(usize),
+}
+
+impl TodoAction {
+    /// The message for the change which makes this action
+    fn message(&self) -> String {
+        match self {
+            TodoAction::Add(title) => format!("Add to-do \"{}\"", title),
+            TodoAction::Toggle(i) => format!("Toggle to-do {}", i + 1),
+            TodoAction::Rename(i, title) => format!("Rename to-do {} to \"{}\"", i + 1, title),
+            TodoAction::Move { from, to } => format!("Move to-do {} to {}", from + 1, to + 1),
+            TodoAction::Delete(i) => format!("Delete to-do {}", i + 1),
+        }
+    }
+}
+
+/// The value to initialize `root.todos` with
+pub fn initial_value() -> Value {
+    Value::Sequence(Vec::new(), amp::SequenceType::List)
+}
+
+fn path() -> Path {
+    Path::root().key("todos")
+}
+
+/// Read the checklist out of the frontend
+pub fn todos(frontend: &Frontend) -> Vec<Todo> {
+    match frontend.get_value(&path()) {
+        Some(Value::Sequence(items, _)) => items.iter().map(parse_todo).collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn parse_todo(value: &Value) -> Todo {
+    match value {
+        Value::Map(props, _) => Todo {
+            title: match props.get("title") {
+                Some(Value::Primitive(amp::Value::Str(s))) => s.clone(),
+                _ => String::new(),
+            },
+            done: match props.get("done") {
+                Some(Value::Primitive(amp::Value::Boolean(b))) => *b,
+                _ => false,
+            },
+        },
+        _ => Todo {
+            title: String::new(),
+            done: false,
+        },
+    }
+}
+
+fn todo_value(todo: &Todo) -> Value {
+    Value::Map(
+        hashmap! {
+            "title".to_string() => Value::Primitive(amp::Value::Str(todo.title.clone())),
+            "done".to_string() => Value::Primitive(amp::Value::Boolean(todo.done)),
+        },
+        amp::MapType::Map,
+    )
+}
+
+/// Apply a checklist action to the frontend, returning the change request
+/// to send to the backend
+pub fn apply(frontend: &mut Frontend, action: TodoAction) -> Option<amp::Request> {
+    if let TodoAction::Add(title) = &action {
+        if title.trim().is_empty() {
+            return None;
+        }
+    }
+    let todos = todos(frontend);
+    frontend
+        .change(Some(action.message()), |doc| {
+            match &action {
+                TodoAction::Add(title) => {
+                    let todo = Todo {
+                        title: title.clone(),
+                        done: false,
+                    };
+                    doc.add_change(LocalChange::insert(path().index(todos.len()), todo_value(&todo)))?;
+                }
+                TodoAction::Toggle(i) => {
+                    if let Some(todo) = todos.get(*i) {
+                        doc.add_change(LocalChange::set(
+                            path().index(*i).key("done"),
+                            Value::Primitive(amp::Value::Boolean(!todo.done)),
+                        ))?;
+                    }
+                }
+                TodoAction::Rename(i, title) => {
+                    if *i < todos.len() {
+                        doc.add_change(LocalChange::set(
+                            path().index(*i).key("title"),
+                            Value::Primitive(amp::Value::Str(title.clone())),
+                        ))?;
+                    }
+                }
+                TodoAction::Move { from, to } => {
+                    if let Some(todo) = todos.get(*from) {
+                        if *to < todos.len() && from != to {
+                            doc.add_change(LocalChange::delete(path().index(*from)))?;
+                            doc.add_change(LocalChange::insert(path().index(*to), todo_value(todo)))?;
+                        }
+                    }
+                }
+                TodoAction::Delete(i) => {
+                    if *i < todos.len() {
+                        doc.add_change(LocalChange::delete(path().index(*i)))?;
+                    }
+                }
+            }
+            Ok(())
+        })
+        .unwrap()
+}
@@ -0,0 +1,286 @@
+//! The command line. Without a subcommand, or with `gui`, the demo opens its
+//! window; the other subcommands are the modes which don't need one, each
+//! with its own flags, so `automerge-demo <subcommand> --help` lists only
+//! what applies.
+//!
+//! The flags the servers have in common, TLS, authentication and sync
+//! filters, are the same `Args` structs flattened into each subcommand
+//! which takes them, so they're spelled and checked the same everywhere.
+//! What clap can't check, a flag needing a feature the demo wasn't built
+//! with, or a file which doesn't load, is checked in `main` as before.
+
+use clap::{Args, Parser, Subcommand};
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
+
+use crate::journal;
+
+#[derive(Debug, Parser)]
+#[command(name = "automerge-demo", about = "Collaborative editing with automerge and vgtk", args_conflicts_with_subcommands = true)]
+pub struct Cli {
+    /// Also write every span to <FILE> in the Chrome trace format, see
+    /// `telemetry.rs`
+    #[arg(long, global = true, value_name = "FILE")]
+    pub trace_file: Option<String>,
+    /// Host a backend for another instance of the demo, which started us
+    /// with `--backend-process`
+    #[arg(long, hide = true, value_name = "SOCKET")]
+    pub backend_worker: Option<String>,
+    /// The Rust side of the interop check, see `tests/interop.rs`
+    #[arg(long, hide = true, value_name = "DIR")]
+    pub interop_save: Option<PathBuf>,
+    #[arg(long, hide = true, value_name = "DIR")]
+    pub interop_load: Option<PathBuf>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// The window's flags, for when there's no subcommand
+    #[command(flatten)]
+    pub gui: GuiArgs,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Open the window, the same as no subcommand
+    Gui(GuiArgs),
+    /// Serve a saved document to websocket and HTTP clients without a
+    /// window, keeping the file up to date
+    Serve(ServeArgs),
+    /// Forward changes between everyone connected, without a window
+    Relay(RelayArgs),
+    /// Print what's in a saved document
+    Inspect(InspectArgs),
+    /// Have peers edit one document over a simulated network, and check
+    /// they end up with the same text
+    Simulate(SimulateArgs),
+    /// Time typing through a frontend and backend into another backend
+    Bench(BenchArgs),
+    /// Rewrite a saved document as a snapshot
+    Compact {
+        file: PathBuf,
+    },
+    /// Write a new Ed25519 key to <FILE> and print its public key
+    GenerateKey {
+        file: PathBuf,
+    },
+}
+
+/// The certificate for `--listen-tls`, or for the relay's server
+#[derive(Debug, Args)]
+pub struct TlsArgs {
+    #[arg(long, value_name = "CERT.PEM")]
+    pub tls_cert: Option<PathBuf>,
+    #[arg(long, value_name = "KEY.PEM")]
+    pub tls_key: Option<PathBuf>,
+}
+
+/// Who our server lets in, and how we prove who we are to others
+#[derive(Debug, Args)]
+pub struct AuthArgs {
+    /// Only let in clients which know <SECRET>, also used when connecting
+    #[arg(long, value_name = "SECRET")]
+    pub passphrase: Option<String>,
+    /// Authenticate with the key in <FILE> when connecting
+    #[arg(long, value_name = "FILE")]
+    pub auth_key: Option<PathBuf>,
+    /// Only let in clients with one of the public keys in <FILE>, one hex
+    /// key per line
+    #[arg(long, value_name = "FILE")]
+    pub authorized_keys: Option<PathBuf>,
+}
+
+#[derive(Debug, Args)]
+pub struct GuiArgs {
+    /// Run each backend in a child process
+    #[arg(long)]
+    pub backend_process: bool,
+    /// Give each tab a backend of its own, kept in sync by the backend
+    /// thread, rather than one per document
+    #[arg(long)]
+    pub backend_per_tab: bool,
+    /// Serve the document to websocket peers on <PORT>
+    #[arg(long, value_name = "PORT", conflicts_with = "listen_tls")]
+    pub serve_ws: Option<u16>,
+    /// Serve the document over TLS on <PORT>
+    #[arg(long, value_name = "PORT", requires_all = ["tls_cert", "tls_key"])]
+    pub listen_tls: Option<u16>,
+    /// The address the server listens on, only this machine unless another
+    /// is given, 0.0.0.0 for every interface
+    #[arg(long, value_name = "ADDR", default_value_t = IpAddr::V4(Ipv4Addr::LOCALHOST))]
+    pub listen: IpAddr,
+    #[command(flatten)]
+    pub tls: TlsArgs,
+    #[command(flatten)]
+    pub auth: AuthArgs,
+    /// Sign every change we make with the key in <FILE>
+    #[arg(long, value_name = "FILE")]
+    pub sign_key: Option<PathBuf>,
+    /// Limit what a peer is sent, see `sync_filter.rs`
+    #[arg(long, value_name = "FILTER")]
+    pub sync_filter: Vec<String>,
+    /// Where windows attach to a running instance
+    #[arg(long, value_name = "PATH")]
+    pub control_socket: Option<PathBuf>,
+    /// Open a window on the backend of the instance already running
+    #[arg(long)]
+    pub attach: bool,
+    /// Connect to the server at ws://<ADDRESS> or wss://<ADDRESS> on startup
+    #[arg(long, value_name = "URL")]
+    pub connect: Option<String>,
+    /// Only trust the certificate with this SHA-256 fingerprint
+    #[arg(long, value_name = "FINGERPRINT", requires = "connect")]
+    pub pin: Option<String>,
+    /// Also sync over libp2p, needs `--features libp2p`
+    #[arg(long)]
+    pub libp2p: bool,
+    #[cfg(feature = "libp2p")]
+    #[arg(long, value_name = "PORT")]
+    pub libp2p_port: Option<u16>,
+    #[cfg(feature = "libp2p")]
+    #[arg(long, value_name = "NAME")]
+    pub libp2p_document: Option<String>,
+    #[cfg(feature = "libp2p")]
+    #[arg(long, value_name = "MULTIADDR")]
+    pub libp2p_peer: Vec<String>,
+    /// Serve the pipeline metrics to Prometheus on <PORT>
+    #[arg(long, value_name = "PORT")]
+    pub metrics_port: Option<u16>,
+    /// Serve the document read only over HTTP on <PORT>
+    #[arg(long, value_name = "PORT")]
+    pub http_port: Option<u16>,
+    /// Open a saved document
+    #[arg(long, value_name = "FILE")]
+    pub open: Option<PathBuf>,
+    /// Feed an exported event log back through a fresh backend
+    #[arg(long, value_name = "FILE")]
+    pub replay: Option<PathBuf>,
+    /// Record every keystroke to <FILE>
+    #[arg(long, value_name = "FILE")]
+    pub record_session: Option<PathBuf>,
+    /// Type a recorded session back in
+    #[arg(long, value_name = "FILE")]
+    pub play_session: Option<PathBuf>,
+    /// How much faster than recorded to play the session
+    #[arg(long, value_name = "SPEED", default_value_t = 1.0)]
+    pub play_speed: f64,
+    /// Type into every tab at <CHARS> characters per second
+    #[arg(long, value_name = "CHARS")]
+    pub stress: Option<f64>,
+    /// Hold back, reorder, duplicate and pause what goes between the
+    /// replicas' backends, see `chaos.rs`
+    #[arg(long)]
+    pub chaos: bool,
+    /// The most a batch of changes is held back for with `--chaos`
+    #[arg(long, value_name = "MS", default_value_t = 500, requires = "chaos")]
+    pub chaos_delay_ms: u64,
+    /// The longest a replica is cut off for with `--chaos`
+    #[arg(long, value_name = "MS", default_value_t = 3000, requires = "chaos")]
+    pub chaos_pause_ms: u64,
+    #[arg(long, value_name = "SEED", default_value_t = 1, requires = "chaos")]
+    pub chaos_seed: u64,
+    /// Save every tab with a file when quitting
+    #[arg(long)]
+    pub save_on_exit: bool,
+    /// Batch keystrokes into one change every <MS> milliseconds
+    #[arg(long, value_name = "MS")]
+    pub coalesce_ms: Option<u64>,
+    /// Serve the documents on the session bus
+    #[arg(long)]
+    pub dbus: bool,
+    /// Don't restore the last session's tabs, or keep this one's
+    #[arg(long)]
+    pub no_workspace: bool,
+    /// Don't keep a journal to recover from a crash with
+    #[arg(long)]
+    pub no_journal: bool,
+    /// How often to flush the journal: always, never or a number of
+    /// milliseconds
+    #[arg(long, value_name = "WHEN")]
+    pub journal_fsync: Option<journal::Fsync>,
+    /// Keep the documents in sled:<DIR> or sqlite:<FILE>
+    #[arg(long, value_name = "STORE")]
+    pub storage: Option<String>,
+    /// Add the fields in the schema at <FILE> to new documents
+    #[arg(long, value_name = "FILE")]
+    pub schema: Option<PathBuf>,
+    /// Passed on to GTK
+    #[arg(last = true, value_name = "GTK_ARGS")]
+    pub gtk_args: Vec<String>,
+}
+
+#[derive(Debug, Args)]
+pub struct ServeArgs {
+    /// The document, in the format File > Save writes
+    pub file: PathBuf,
+    /// Serve it to websocket peers on <PORT>
+    #[arg(long, value_name = "PORT")]
+    pub port: u16,
+    /// Serve it read only over HTTP on <PORT>
+    #[arg(long, value_name = "PORT")]
+    pub http_port: Option<u16>,
+    #[command(flatten)]
+    pub tls: TlsArgs,
+    #[command(flatten)]
+    pub auth: AuthArgs,
+    #[arg(long, value_name = "FILTER")]
+    pub sync_filter: Vec<String>,
+}
+
+#[derive(Debug, Args)]
+pub struct RelayArgs {
+    pub port: u16,
+    /// Keep the document in <FILE> between runs
+    #[arg(long, value_name = "FILE")]
+    pub store: Option<PathBuf>,
+    #[command(flatten)]
+    pub tls: TlsArgs,
+    #[command(flatten)]
+    pub auth: AuthArgs,
+    #[arg(long, value_name = "FILTER")]
+    pub sync_filter: Vec<String>,
+}
+
+#[derive(Debug, Args)]
+pub struct InspectArgs {
+    /// A document saved with File > Save
+    pub file: PathBuf,
+    /// List every change as well
+    #[arg(long)]
+    pub changes: bool,
+    /// The passphrase the file was encrypted with
+    #[arg(long, value_name = "SECRET")]
+    pub passphrase: Option<String>,
+}
+
+#[derive(Debug, Args)]
+pub struct SimulateArgs {
+    #[arg(long, default_value_t = 2)]
+    pub peers: usize,
+    /// How many edits each peer makes
+    #[arg(long, default_value_t = 500)]
+    pub edits: usize,
+    /// The most a change can take to reach another peer, in milliseconds
+    /// of simulated time, each edit taking one
+    #[arg(long, value_name = "MS", default_value_t = 50)]
+    pub latency_ms: u64,
+    #[arg(long, default_value_t = 1)]
+    pub seed: u64,
+    /// Run on the unified automerge crate rather than the split ones, needs
+    /// `--features unified`
+    #[arg(long)]
+    pub unified: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct BenchArgs {
+    /// How many characters to type
+    #[arg(long, default_value_t = 10_000)]
+    pub chars: usize,
+    /// How many characters go in each change, like `--coalesce-ms` batching
+    /// them
+    #[arg(long, default_value_t = 1)]
+    pub per_change: usize,
+    /// Run on the unified automerge crate rather than the split ones, needs
+    /// `--features unified`
+    #[arg(long)]
+    pub unified: bool,
+}
@@ -0,0 +1,292 @@
+//! Copying and pasting text with its marks.
+//!
+//! The text views' own copy puts plain text on the clipboard, and their
+//! paste inserts plain text, so bold text pasted came out as plain text, even
+//! pasted back into the tab it was copied from. Marks aren't in the text,
+//! they're anchored to the ops which inserted it, see `marks.rs`, and pasted
+//! text is inserted by new ops, so there is nothing for the old marks to
+//! stay attached to anyway.
+//!
+//! So the views leave copying, cutting and pasting to the doc. A copy is a
+//! `Clip`, the text selected and the marks over it as char ranges into that
+//! text, offered to other programs as plain text and to ourselves as JSON
+//! under `TARGET`. A paste which finds a clip inserts its text as one
+//! change, and when the backend answers with the ops that inserted it the
+//! `Pastes` anchor the clip's marks to them, to be added in a second change.
+//! A paste of plain text, from anywhere else, has no marks to wait for.
+
+use automerge_protocol as amp;
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::ops::Range;
+use tokio::sync::oneshot;
+use vgtk::lib::gdk;
+use vgtk::lib::gtk::prelude::*;
+use vgtk::lib::gtk::{Clipboard, TargetEntry, TargetFlags};
+
+use crate::marks::MarkType;
+use crate::offset_index::text_diff;
+
+/// The clipboard target a clip goes by, for pasting into another tab or
+/// instance of the demo
+pub const TARGET: &str = "application/x-automerge-demo-clip";
+
+/// The targets other programs can have the text as
+const TEXT_TARGETS: [&str; 5] = ["UTF8_STRING", "text/plain;charset=utf-8", "text/plain", "STRING", "TEXT"];
+
+/// What a view was asked to do with the clipboard
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    Copy,
+    Cut,
+    Paste,
+}
+
+impl Action {
+    /// The view's signal for it, whose own handler the doc's replaces
+    pub fn signal(self) -> &'static str {
+        match self {
+            Action::Copy => "copy-clipboard",
+            Action::Cut => "cut-clipboard",
+            Action::Paste => "paste-clipboard",
+        }
+    }
+}
+
+/// A mark over the chars `start..end` of a clip's text
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ClipMark {
+    #[serde(rename = "type")]
+    pub mark_type: String,
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Text copied, with its marks
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Clip {
+    pub text: String,
+    pub marks: Vec<ClipMark>,
+}
+
+impl Clip {
+    /// A clip of the chars `range` of the text, which are `text`, with the
+    /// parts of `marks`, resolved to char ranges of the whole text, inside it
+    pub fn new(text: String, range: Range<usize>, marks: impl Iterator<Item = (MarkType, Range<usize>)>) -> Clip {
+        let marks = marks
+            .filter_map(|(mark_type, span)| {
+                let (start, end) = (span.start.max(range.start), span.end.min(range.end));
+                if start >= end {
+                    return None;
+                }
+                Some(ClipMark { mark_type: mark_type.name().to_string(), start: start - range.start, end: end - range.start })
+            })
+            .collect();
+        Clip { text, marks }
+    }
+
+    /// Text from somewhere else, with no marks
+    pub fn plain(text: &str) -> Clip {
+        Clip { text: text.to_string(), marks: Vec::new() }
+    }
+}
+
+fn clipboard() -> Clipboard {
+    Clipboard::get(&gdk::Atom::intern("CLIPBOARD"))
+}
+
+/// Put `clip` on the clipboard, as text and as itself
+pub fn put(clip: &Clip) {
+    let mut targets: Vec<TargetEntry> =
+        TEXT_TARGETS.iter().map(|target| TargetEntry::new(target, TargetFlags::empty(), 0)).collect();
+    targets.push(TargetEntry::new(TARGET, TargetFlags::empty(), 1));
+    let json = serde_json::to_string(clip).unwrap();
+    let text = clip.text.clone();
+    let offered = clipboard().set_with_data(&targets, move |_, data, info| {
+        if info == 1 {
+            data.set(&gdk::Atom::intern(TARGET), 8, json.as_bytes());
+        } else {
+            data.set_text(&text);
+        }
+    });
+    if !offered {
+        tracing::warn!("Could not put the copy on the clipboard");
+    }
+}
+
+/// What's on the clipboard, a clip if it is one or plain text if that's all
+/// there is. It's asked for straight away and arrives once the main loop's
+/// had the answer, as the program with the clipboard may be this one.
+pub fn take() -> impl Future<Output = Option<Clip>> {
+    let (sx, rx) = oneshot::channel();
+    clipboard().request_contents(&gdk::Atom::intern(TARGET), move |clipboard, data| {
+        match serde_json::from_slice::<Clip>(&data.get_data()) {
+            Ok(clip) => {
+                let _ = sx.send(Some(clip));
+            }
+            Err(_) => clipboard.request_text(move |_, text| {
+                let _ = sx.send(text.map(Clip::plain));
+            }),
+        }
+    });
+    async move { rx.await.ok().flatten() }
+}
+
+/// A paste whose marks are waiting for its change to be acknowledged
+struct Paste {
+    /// How many more of our requests have to be acknowledged, including
+    /// the paste's own
+    waiting: usize,
+    marks: Vec<ClipMark>,
+}
+
+/// The pastes with marks still to anchor, and the marks anchored
+#[derive(Default)]
+pub struct Pastes {
+    waiting: Vec<Paste>,
+    anchored: Vec<(MarkType, String, String)>,
+}
+
+impl Pastes {
+    /// A paste with `marks` has just been sent, with `pending` of our
+    /// requests unacknowledged now, its own the last of them
+    pub fn sent(&mut self, pending: usize, marks: Vec<ClipMark>) {
+        if !marks.is_empty() {
+            self.waiting.push(Paste { waiting: pending, marks });
+        }
+    }
+
+    /// `patch` acknowledges our oldest request, from `actor`. If it's a
+    /// paste's, the text it inserted is the clip's, and the marks are
+    /// anchored to the ops which inserted it.
+    pub fn acknowledged(&mut self, patch: &amp::Patch, actor: &str) {
+        for paste in &mut self.waiting {
+            paste.waiting -= 1;
+        }
+        let (done, waiting): (Vec<Paste>, Vec<Paste>) = self.waiting.drain(..).partition(|paste| paste.waiting == 0);
+        self.waiting = waiting;
+        for paste in done {
+            let ops = inserted_ops(patch, actor);
+            for mark in paste.marks {
+                let mark_type = match MarkType::from_name(&mark.mark_type) {
+                    Some(mark_type) => mark_type,
+                    None => continue,
+                };
+                match (ops.get(mark.start), mark.end.checked_sub(1).and_then(|last| ops.get(last))) {
+                    (Some(start), Some(end)) => self.anchored.push((mark_type, start.clone(), end.clone())),
+                    _ => tracing::warn!("A pasted {} mark is past the text the paste inserted", mark.mark_type),
+                }
+            }
+        }
+    }
+
+    /// The marks anchored since this was last asked, as mark type and the
+    /// ops inserting their first and last chars
+    pub fn take_anchored(&mut self) -> Vec<(MarkType, String, String)> {
+        std::mem::take(&mut self.anchored)
+    }
+
+    /// Forget every paste, after a resync has forgotten their requests
+    pub fn clear(&mut self) {
+        self.waiting.clear();
+        self.anchored.clear();
+    }
+}
+
+/// The ops from `actor` which inserted elements of the text in `patch`, in
+/// the order they were made, which is the order of the chars
+fn inserted_ops(patch: &amp::Patch, actor: &str) -> Vec<String> {
+    let seq = match text_diff(patch) {
+        Some(amp::Diff::Seq(seq)) => seq,
+        _ => return Vec::new(),
+    };
+    let mut ops: Vec<(u64, String)> = seq
+        .props
+        .values()
+        .flat_map(|values| values.keys())
+        .filter_map(|op| {
+            let mut parts = op.splitn(2, '@');
+            let counter = parts.next()?.parse().ok()?;
+            if parts.next()? == actor {
+                Some((counter, op.clone()))
+            } else {
+                None
+            }
+        })
+        .collect();
+    ops.sort();
+    ops.dedup();
+    ops.into_iter().map(|(_, op)| op).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use automerge_backend::Backend;
+    use automerge_demo_core::text::inserted;
+    use automerge_frontend::{Frontend, LocalChange, Path, Value};
+    use automerge_protocol as amp;
+
+    use super::{Clip, ClipMark, Pastes};
+    use crate::marks::MarkType;
+    use crate::offset_index::OffsetIndex;
+
+    #[test]
+    fn a_clip_keeps_the_marks_inside_the_selection() {
+        let clip = Clip::new(
+            "lo wor".to_string(),
+            3..9,
+            vec![(MarkType::Bold, 0..4), (MarkType::Italic, 5..7), (MarkType::Underline, 9..11)].into_iter(),
+        );
+        assert_eq!(
+            clip.marks,
+            vec![
+                ClipMark { mark_type: "bold".to_string(), start: 0, end: 1 },
+                ClipMark { mark_type: "italic".to_string(), start: 2, end: 4 },
+            ]
+        );
+        let json = serde_json::to_string(&clip).unwrap();
+        assert!(json.contains("\"type\":\"bold\""));
+        assert_eq!(serde_json::from_str::<Clip>(&json).unwrap(), clip);
+    }
+
+    /// Make `changes` in `frontend` and have `backend` answer them
+    fn answered(backend: &mut Backend, frontend: &mut Frontend, changes: Vec<LocalChange>) -> amp::Patch {
+        let request = frontend
+            .change(None, |doc| {
+                for change in changes {
+                    doc.add_change(change)?;
+                }
+                Ok(())
+            })
+            .unwrap()
+            .unwrap();
+        backend.apply_local_change_and_get(request).unwrap().0
+    }
+
+    #[test]
+    fn pasted_marks_are_anchored_to_the_pasted_text() {
+        let mut backend = Backend::init();
+        let mut frontend = Frontend::new();
+        let actor = frontend.actor_id.to_string();
+        let mut offsets = OffsetIndex::default();
+        let mut pastes = Pastes::default();
+        let text = Value::Sequence(Vec::new(), amp::SequenceType::Text);
+        let created = answered(&mut backend, &mut frontend, vec![LocalChange::set(Path::root().key("text"), text)]);
+        offsets.apply_patch(&created);
+        // The paste, and something typed after it before either is answered
+        let pasted = answered(&mut backend, &mut frontend, inserted(0, "bold"));
+        pastes.sent(1, vec![ClipMark { mark_type: "bold".to_string(), start: 1, end: 3 }]);
+        let typed = answered(&mut backend, &mut frontend, inserted(4, "!"));
+        pastes.acknowledged(&pasted, &actor);
+        offsets.apply_patch(&pasted);
+        pastes.acknowledged(&typed, &actor);
+        offsets.apply_patch(&typed);
+        let anchored = pastes.take_anchored();
+        assert_eq!(anchored.len(), 1);
+        let (mark_type, start, end) = &anchored[0];
+        assert_eq!(*mark_type, MarkType::Bold);
+        assert_eq!(offsets.span_of(start), Some(1..2));
+        assert_eq!(offsets.span_of(end), Some(2..3));
+        assert!(pastes.take_anchored().is_empty());
+    }
+}
@@ -0,0 +1,143 @@
+//! The compare window, opened from View > Compare. Two sliders pick points in
+//! the document's history and the text between them is shown as an inline
+//! diff, see `diff.rs`. Under each slider is the message and time of the
+//! last change it includes.
+
+use vgtk::ext::*;
+use vgtk::lib::gtk::*;
+use vgtk::{gtk, Callback, Component, UpdateAction, VNode};
+use automerge_backend::Change;
+use std::rc::Rc;
+
+use crate::change_log::ChangeMeta;
+use crate::diff::{self, Version};
+
+#[derive(Default)]
+pub struct CompareView {
+    history: Rc<Vec<Change>>,
+    on_close: Callback<()>,
+    /// How many changes into the history each side of the comparison is
+    from: usize,
+    to: usize,
+    from_version: Option<Version>,
+    to_version: Option<Version>,
+}
+
+#[derive(Debug, Clone)]
+pub enum CompareMessage {
+    SetFrom(usize),
+    SetTo(usize),
+    Close,
+}
+
+#[derive(Clone, Default)]
+pub struct CompareViewProperties {
+    /// The document's changes in causal order
+    history: Rc<Vec<Change>>,
+    on_close: Callback<()>,
+}
+
+impl CompareView {
+    fn version(&self, n: usize) -> Version {
+        Version::materialize(&self.history[..n.min(self.history.len())])
+    }
+
+    /// What the `n`th change in the history was, for the label under a slider
+    fn change_label(&self, n: usize) -> String {
+        let change = match n.checked_sub(1).and_then(|i| self.history.get(i)) {
+            Some(change) => ChangeMeta::new(change),
+            None => return "The empty document".to_string(),
+        };
+        let actor = change.short_actor();
+        let message = change.message.as_deref().unwrap_or("No message");
+        match change.time_label() {
+            Some(time) => format!("{} \u{2014} {} #{}, {}", message, actor, change.seq, time),
+            None => format!("{} \u{2014} {} #{}", message, actor, change.seq),
+        }
+    }
+
+    /// A slider picking one side of the comparison
+    fn point_view(
+        &self,
+        label: &str,
+        n: usize,
+        version: &Option<Version>,
+        on_change: fn(usize) -> CompareMessage,
+    ) -> VNode<CompareView> {
+        let max = self.history.len().max(1) as f64;
+        let heads = version.as_ref().map(|v| v.heads.join(", ")).unwrap_or_default();
+        gtk!{
+            <Box orientation=Orientation::Vertical spacing=2>
+                <Box orientation=Orientation::Horizontal spacing=12>
+                    <Label label=label.to_string() width_chars=5 xalign=0.0 />
+                    <Scale::with_range(Orientation::Horizontal, 0.0, max, 1.0) digits=0 value=n as f64 Box::expand=true
+                        on value_changed=|scale| on_change(scale.get_value().round() as usize) />
+                    <Label label=format!("heads {}", heads) width_chars=24 xalign=0.0 ellipsize=pango::EllipsizeMode::End />
+                </Box>
+                <Label label=self.change_label(n) xalign=0.0 ellipsize=pango::EllipsizeMode::End />
+            </Box>
+        }
+    }
+}
+
+impl Component for CompareView {
+    type Message = CompareMessage;
+    type Properties = CompareViewProperties;
+
+    fn create(properties: Self::Properties) -> Self {
+        let mut view = CompareView {
+            history: properties.history,
+            on_close: properties.on_close,
+            ..CompareView::default()
+        };
+        // Start off comparing the whole history with the current text
+        view.to = view.history.len();
+        view.from_version = Some(view.version(view.from));
+        view.to_version = Some(view.version(view.to));
+        view
+    }
+
+    fn change(&mut self, properties: Self::Properties) -> UpdateAction<Self> {
+        self.on_close = properties.on_close;
+        UpdateAction::None
+    }
+
+    fn update(&mut self, msg: Self::Message) -> UpdateAction<Self> {
+        match msg {
+            CompareMessage::SetFrom(n) if n != self.from => {
+                self.from = n;
+                self.from_version = Some(self.version(n));
+                UpdateAction::Render
+            }
+            CompareMessage::SetTo(n) if n != self.to => {
+                self.to = n;
+                self.to_version = Some(self.version(n));
+                UpdateAction::Render
+            }
+            CompareMessage::SetFrom(_) | CompareMessage::SetTo(_) => UpdateAction::None,
+            CompareMessage::Close => {
+                self.on_close.send(());
+                UpdateAction::None
+            }
+        }
+    }
+
+    fn view(&self) -> VNode<Self> {
+        let markup = match (&self.from_version, &self.to_version) {
+            (Some(from), Some(to)) => diff::markup(&diff::diff(from, to)),
+            _ => String::new(),
+        };
+        gtk!{
+            <Window title="Compare" default_width=800 default_height=500 border_width=12 on destroy=|_| CompareMessage::Close>
+                <Box orientation=Orientation::Vertical spacing=6>
+                    {self.point_view("From", self.from, &self.from_version, CompareMessage::SetFrom)}
+                    {self.point_view("To", self.to, &self.to_version, CompareMessage::SetTo)}
+                    <Label label=format!("{} changes in the history", self.history.len()) xalign=0.0 />
+                    <ScrolledWindow Box::expand=true>
+                        <Label label=markup use_markup=true wrap=true selectable=true xalign=0.0 yalign=0.0 />
+                    </ScrolledWindow>
+                </Box>
+            </Window>
+        }
+    }
+}
@@ -0,0 +1,308 @@
+//! Text typed with an input method, which only goes into the document once
+//! it's been committed.
+//!
+//! A CJK input method, or dead keys, build up a character over several
+//! keystrokes, and the text view shows it as preedit text until it's
+//! committed. Most input methods keep that out of the buffer, but some edit
+//! the buffer as they go, committing each stage of a Hangul syllable and
+//! deleting it again to commit the next, say, and the buffer's handlers used
+//! to turn every one of those edits into changes: three inserts and two
+//! deletes of elements no one meant to type, for everyone else to see, for
+//! the one character which was left.
+//!
+//! So the views tell the `Composer` whenever their preedit text changes, and
+//! while there is some, the edits the buffer makes are held back and folded
+//! into one replacement of the chars around them. When the preedit goes,
+//! because the character has been committed or the composition cancelled,
+//! what's left of the replacement, once it's been trimmed of anything it
+//! put back just as it was, is what gets typed. An edit somewhere else in the
+//! meantime, or the doc needing the frontend to catch up with the buffer, a
+//! remote patch say, types what's been held so far rather than waiting.
+//!
+//! Without an input method composing there's nothing to hold back, and
+//! edits are typed as they're made.
+
+use automerge_demo_core::text::changed;
+use std::cell::RefCell;
+use std::rc::Rc;
+use vgtk::lib::gtk::prelude::*;
+use vgtk::lib::gtk::TextView;
+
+/// An edit to the text, once it's been typed rather than composed
+#[derive(Clone, Debug, PartialEq)]
+pub enum Typed {
+    /// `text` was inserted at char `at`
+    Insert { at: usize, text: String },
+    /// `text` was deleted from char `start`
+    Delete { start: usize, text: String },
+}
+
+/// What the held edits have done to the text so far
+struct Composition {
+    /// Where it is in the text
+    start: usize,
+    /// The chars it replaced
+    old: Vec<char>,
+    /// What's there now
+    new: Vec<char>,
+}
+
+impl Composition {
+    fn end(&self) -> usize {
+        self.start + self.new.len()
+    }
+
+    /// Fold in inserting `text` at `at`, unless it isn't next to or in the
+    /// middle of the composition
+    fn insert(&mut self, at: usize, text: &str) -> bool {
+        if at < self.start || at > self.end() {
+            return false;
+        }
+        let at = at - self.start;
+        self.new.splice(at..at, text.chars());
+        true
+    }
+
+    /// Fold in deleting `text` from `start`, unless it doesn't touch the
+    /// composition
+    fn delete(&mut self, start: usize, text: &str) -> bool {
+        let deleted: Vec<char> = text.chars().collect();
+        let end = start + deleted.len();
+        if end < self.start || start > self.end() {
+            return false;
+        }
+        // Any chars from outside it were in the text before, and are part of
+        // what it replaces
+        let before = self.start.saturating_sub(start);
+        let after = end.saturating_sub(self.end());
+        let inside = start.max(self.start) - self.start..end.min(self.end()) - self.start;
+        self.new.drain(inside);
+        self.old.splice(0..0, deleted[..before].iter().copied());
+        self.old.extend(&deleted[deleted.len() - after..]);
+        self.start = self.start.min(start);
+        true
+    }
+
+    /// The edits it comes to
+    fn typed(&self) -> Vec<Typed> {
+        let (removed, put) = changed(&self.old, &self.new);
+        let at = self.start + removed.start;
+        let mut typed = Vec::new();
+        if !removed.is_empty() {
+            typed.push(Typed::Delete { start: at, text: self.old[removed].iter().collect() });
+        }
+        if !put.is_empty() {
+            typed.push(Typed::Insert { at, text: self.new[put].iter().collect() });
+        }
+        typed
+    }
+}
+
+struct Inner {
+    /// Whether a view's input method is composing
+    composing: bool,
+    held: Option<Composition>,
+    typed: Box<dyn Fn(Typed)>,
+}
+
+/// Shared by the doc, whose buffer's edits go through it, and its views,
+/// which say when they're composing
+#[derive(Clone)]
+pub struct Composer {
+    inner: Rc<RefCell<Inner>>,
+}
+
+impl Composer {
+    /// A composer handing every edit typed to `typed`
+    pub fn new(typed: impl Fn(Typed) + 'static) -> Composer {
+        Composer { inner: Rc::new(RefCell::new(Inner { composing: false, held: None, typed: Box::new(typed) })) }
+    }
+
+    /// Follow the composing of the input method of `view`
+    pub fn watch(&self, view: &impl IsA<TextView>) {
+        let composer = self.clone();
+        view.connect_preedit_changed(move |_, preedit| composer.preedit_changed(preedit));
+    }
+
+    /// A view's preedit text is now `preedit`, which is empty once it's
+    /// done composing
+    pub fn preedit_changed(&self, preedit: &str) {
+        self.inner.borrow_mut().composing = !preedit.is_empty();
+        if preedit.is_empty() {
+            self.finish();
+        }
+    }
+
+    /// The buffer has had `text` inserted at `at`
+    pub fn inserted(&self, at: usize, text: &str) {
+        self.edited(Typed::Insert { at, text: text.to_string() });
+    }
+
+    /// The buffer has had `text` deleted from `start`
+    pub fn deleted(&self, start: usize, text: &str) {
+        self.edited(Typed::Delete { start, text: text.to_string() });
+    }
+
+    fn edited(&self, edit: Typed) {
+        let mut inner = self.inner.borrow_mut();
+        if !inner.composing {
+            (inner.typed)(edit);
+            return;
+        }
+        let folded = match (&mut inner.held, &edit) {
+            (Some(held), Typed::Insert { at, text }) => held.insert(*at, text),
+            (Some(held), Typed::Delete { start, text }) => held.delete(*start, text),
+            (None, _) => false,
+        };
+        if folded {
+            return;
+        }
+        // Somewhere else, so what's been held so far goes first
+        let held = inner.held.take();
+        inner.held = Some(match edit {
+            Typed::Insert { at, text } => Composition { start: at, old: Vec::new(), new: text.chars().collect() },
+            Typed::Delete { start, text } => Composition { start, old: text.chars().collect(), new: Vec::new() },
+        });
+        drop(inner);
+        self.type_out(held);
+    }
+
+    /// Type whatever's being held back now, as the doc needs the frontend
+    /// to have everything in the buffer
+    pub fn finish(&self) {
+        let held = self.inner.borrow_mut().held.take();
+        self.type_out(held);
+    }
+
+    /// Forget whatever's being held back, along with the rest of what the
+    /// buffer had
+    pub fn discard(&self) {
+        self.inner.borrow_mut().held = None;
+    }
+
+    fn type_out(&self, held: Option<Composition>) {
+        if let Some(held) = held {
+            let inner = self.inner.borrow();
+            for edit in held.typed() {
+                (inner.typed)(edit);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::{Composer, Typed};
+
+    /// A composer and what it's typed, and the buffer's text as the edits
+    /// leave it
+    struct Ime {
+        composer: Composer,
+        typed: Rc<RefCell<Vec<Typed>>>,
+        buffer: Vec<char>,
+    }
+
+    impl Ime {
+        fn new(text: &str) -> Ime {
+            let typed = Rc::new(RefCell::new(Vec::new()));
+            let typing = typed.clone();
+            let composer = Composer::new(move |edit| typing.borrow_mut().push(edit));
+            Ime { composer, typed, buffer: text.chars().collect() }
+        }
+
+        fn insert(&mut self, at: usize, text: &str) {
+            self.buffer.splice(at..at, text.chars());
+            self.composer.inserted(at, text);
+        }
+
+        fn delete(&mut self, start: usize, end: usize) {
+            let text: String = self.buffer.drain(start..end).collect();
+            self.composer.deleted(start, &text);
+        }
+
+        /// What typing everything typed so far into `text` makes, the way
+        /// the frontend would have it
+        fn typed_into(&self, text: &str) -> String {
+            let mut chars: Vec<char> = text.chars().collect();
+            for edit in self.typed.borrow().iter() {
+                match edit {
+                    Typed::Insert { at, text } => {
+                        chars.splice(*at..*at, text.chars());
+                    }
+                    Typed::Delete { start, text } => {
+                        let deleted: String = chars.drain(*start..start + text.chars().count()).collect();
+                        assert_eq!(&deleted, text);
+                    }
+                }
+            }
+            chars.into_iter().collect()
+        }
+    }
+
+    #[test]
+    fn a_hangul_syllable_is_typed_once_it_is_committed() {
+        let mut ime = Ime::new("ab");
+        // An input method which edits the buffer as the syllable builds up
+        ime.composer.preedit_changed("ㅎ");
+        ime.insert(1, "ㅎ");
+        ime.delete(1, 2);
+        ime.composer.preedit_changed("하");
+        ime.insert(1, "하");
+        ime.delete(1, 2);
+        ime.composer.preedit_changed("한");
+        ime.insert(1, "한");
+        assert!(ime.typed.borrow().is_empty());
+        ime.composer.preedit_changed("");
+        assert_eq!(*ime.typed.borrow(), vec![Typed::Insert { at: 1, text: "한".to_string() }]);
+        assert_eq!(ime.typed_into("ab"), ime.buffer.iter().collect::<String>());
+    }
+
+    #[test]
+    fn dead_keys_commit_after_the_preedit_goes() {
+        let mut ime = Ime::new("caf");
+        ime.composer.preedit_changed("´");
+        ime.composer.preedit_changed("");
+        ime.insert(3, "é");
+        assert_eq!(*ime.typed.borrow(), vec![Typed::Insert { at: 3, text: "é".to_string() }]);
+    }
+
+    #[test]
+    fn a_cancelled_composition_types_nothing() {
+        let mut ime = Ime::new("abc");
+        ime.composer.preedit_changed("k");
+        ime.insert(2, "k");
+        ime.delete(2, 3);
+        ime.composer.preedit_changed("");
+        assert!(ime.typed.borrow().is_empty());
+    }
+
+    #[test]
+    fn composing_over_text_replaces_it() {
+        let mut ime = Ime::new("abcd");
+        ime.composer.preedit_changed("ㅋ");
+        ime.delete(1, 3);
+        ime.insert(1, "b");
+        ime.insert(2, "ㅋ");
+        ime.composer.preedit_changed("");
+        assert_eq!(
+            *ime.typed.borrow(),
+            vec![Typed::Delete { start: 2, text: "c".to_string() }, Typed::Insert { at: 2, text: "ㅋ".to_string() }]
+        );
+        assert_eq!(ime.typed_into("abcd"), "abㅋd");
+    }
+
+    #[test]
+    fn an_edit_elsewhere_types_what_was_held() {
+        let mut ime = Ime::new("hello world");
+        ime.composer.preedit_changed("x");
+        ime.insert(5, "x");
+        ime.insert(0, "y");
+        assert_eq!(*ime.typed.borrow(), vec![Typed::Insert { at: 5, text: "x".to_string() }]);
+        ime.composer.finish();
+        assert_eq!(ime.typed_into("hello world"), "yhellox world");
+        assert_eq!(ime.typed_into("hello world"), ime.buffer.iter().collect::<String>());
+    }
+}
@@ -0,0 +1,81 @@
+//! Tools > Verify Convergence, checking that every replica of each document
+//! has ended up in the same place.
+//!
+//! Two replicas with the same heads have applied exactly the same set of
+//! changes, and automerge promises that they then have the same state,
+//! whatever order the changes arrived in. So comparing heads is enough. For
+//! each tab I compare the heads of its backend with those of every other
+//! backend of the same document, and with the heads of the tab's own
+//! frontend as of its last patch, which would differ if a patch was lost on
+//! the way. If changes are still flowing a mismatch is expected and goes
+//! away once they've all arrived, so it's worth trying again when things
+//! are quiet before worrying.
+
+use automerge_protocol as amp;
+
+use crate::peer::PeerId;
+
+/// How much of a hash to show
+const HASH_CHARS: usize = 8;
+
+/// What one tab knows about where its document has got to
+pub struct Replica {
+    pub tab: String,
+    /// The peer whose document this is a replica of
+    pub document: PeerId,
+    /// The heads of the tab's backend
+    pub backend: Vec<amp::ChangeHash>,
+    /// The heads of the tab's frontend, as `Doc::heads` has them
+    pub frontend: Vec<String>,
+}
+
+/// Heads in the form `Doc::heads` has them, sorted
+pub fn format_heads(heads: &[amp::ChangeHash]) -> Vec<String> {
+    let mut heads: Vec<String> = heads.iter().map(|h| format!("{:?}", h)).collect();
+    heads.sort();
+    heads
+}
+
+/// Heads short enough to read
+pub fn short_heads(heads: &[String]) -> String {
+    if heads.is_empty() {
+        return "none".to_string();
+    }
+    heads.iter().map(|h| h.chars().take(HASH_CHARS).collect::<String>()).collect::<Vec<_>>().join(", ")
+}
+
+/// Whether every replica agrees, and a line saying so for each document,
+/// or where they differ
+pub fn verify(replicas: &[Replica]) -> (bool, String) {
+    let mut documents: Vec<PeerId> = replicas.iter().map(|r| r.document).collect();
+    documents.sort();
+    documents.dedup();
+    let mut converged = true;
+    let mut lines = Vec::new();
+    for document in documents {
+        let group: Vec<&Replica> = replicas.iter().filter(|r| r.document == document).collect();
+        let expected = format_heads(&group[0].backend);
+        let mut mismatches = Vec::new();
+        for replica in &group {
+            let backend = format_heads(&replica.backend);
+            if backend != expected {
+                mismatches.push(format!("{}'s backend has heads {}", replica.tab, short_heads(&backend)));
+            }
+            if replica.frontend != backend {
+                mismatches.push(format!("{}'s frontend has heads {}", replica.tab, short_heads(&replica.frontend)));
+            }
+        }
+        if mismatches.is_empty() {
+            lines.push(format!("\u{2713} {}: {} replicas agree on heads {}.", group[0].tab, group.len(), short_heads(&expected)));
+        } else {
+            converged = false;
+            lines.push(format!(
+                "\u{2717} {}: expected heads {}, but {}.",
+                group[0].tab,
+                short_heads(&expected),
+                mismatches.join(", ")
+            ));
+        }
+    }
+    (converged, lines.join("\n"))
+}
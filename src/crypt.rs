@@ -0,0 +1,160 @@
+//! Encrypting saved documents and exported change logs with a passphrase.
+//!
+//! File > Encrypt with Passphrase asks for a passphrase and from then on
+//! every save of the tab's document, and File > Export History, writes the
+//! file encrypted. An encrypted file is `MAGIC`, a random salt and nonce,
+//! and then the contents as they'd otherwise have been written, sealed with
+//! ChaCha20-Poly1305 under a key derived from the passphrase with Argon2.
+//! The header goes in as associated data, so it can't be changed without the
+//! file failing to decrypt either.
+//!
+//! Opening an encrypted file, with File > Open, `--open`, `--replay` or when
+//! the workspace is restored, asks for its passphrase. Argon2 is slow on
+//! purpose, so the key is kept for the rest of the run once it's derived:
+//! saving again, Save As and `--save-on-exit` reuse it, with the file's salt
+//! and a fresh nonce each time, rather than asking again.
+
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::convert::TryFrom;
+use std::fmt;
+use std::io;
+use std::path::Path;
+use vgtk::lib::gtk::prelude::*;
+use vgtk::lib::gtk::{Dialog, DialogFlags, Entry, Label, ResponseType, Window};
+
+/// What an encrypted file starts with. Neither a saved document, which
+/// starts with a change's length, nor a JSON log can start with this.
+const MAGIC: &[u8] = b"AMDEMOE1";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const HEADER_LEN: usize = 8 + SALT_LEN + NONCE_LEN;
+
+/// A key derived from a passphrase, along with the salt it was derived with
+#[derive(Clone)]
+pub struct Key {
+    salt: [u8; SALT_LEN],
+    key: [u8; 32],
+}
+
+// Keys go into `BackendCommand`s, which are logged, so never show the key
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Key(..)")
+    }
+}
+
+impl Key {
+    /// A key for a newly encrypted file, with a new salt
+    pub fn new(passphrase: &str) -> Key {
+        let mut salt = [0u8; SALT_LEN];
+        random(&mut salt);
+        Key::derive(passphrase, salt)
+    }
+
+    /// The key for the encrypted file `bytes`, if `passphrase` is the right
+    /// one
+    pub fn for_file(passphrase: &str, bytes: &[u8]) -> io::Result<Key> {
+        if !is_encrypted(bytes) || bytes.len() < HEADER_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "the file isn't encrypted"));
+        }
+        let salt = <[u8; SALT_LEN]>::try_from(&bytes[MAGIC.len()..MAGIC.len() + SALT_LEN]).unwrap();
+        Ok(Key::derive(passphrase, salt))
+    }
+
+    fn derive(passphrase: &str, salt: [u8; SALT_LEN]) -> Key {
+        let mut key = [0u8; 32];
+        argon2::Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), &salt, &mut key)
+            .expect("Argon2 takes any passphrase with a 16 byte salt");
+        Key{salt, key}
+    }
+}
+
+fn random(bytes: &mut [u8]) {
+    SystemRandom::new().fill(bytes).expect("the system random number generator failed");
+}
+
+pub fn is_encrypted(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+pub fn encrypt(key: &Key, plain: &[u8]) -> Vec<u8> {
+    let mut nonce = [0u8; NONCE_LEN];
+    random(&mut nonce);
+    let mut bytes = Vec::with_capacity(HEADER_LEN + plain.len() + 16);
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&key.salt);
+    bytes.extend_from_slice(&nonce);
+    let sealed = ChaCha20Poly1305::new(chacha20poly1305::Key::from_slice(&key.key))
+        .encrypt(Nonce::from_slice(&nonce), Payload{msg: plain, aad: &bytes})
+        .expect("ChaCha20-Poly1305 can encrypt anything this size");
+    bytes.extend_from_slice(&sealed);
+    bytes
+}
+
+/// Encrypt `plain` if there's a key, otherwise leave it as it is
+pub fn seal(key: Option<&Key>, plain: Vec<u8>) -> Vec<u8> {
+    match key {
+        Some(key) => encrypt(key, &plain),
+        None => plain,
+    }
+}
+
+pub fn decrypt(key: &Key, bytes: &[u8]) -> io::Result<Vec<u8>> {
+    if !is_encrypted(bytes) || bytes.len() < HEADER_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "the file isn't encrypted"));
+    }
+    let (header, sealed) = bytes.split_at(HEADER_LEN);
+    ChaCha20Poly1305::new(chacha20poly1305::Key::from_slice(&key.key))
+        .decrypt(Nonce::from_slice(&header[MAGIC.len() + SALT_LEN..]), Payload{msg: sealed, aad: header})
+        .map_err(|_| io::Error::new(io::ErrorKind::PermissionDenied, "wrong passphrase, or the file is damaged"))
+}
+
+/// Ask for the passphrase to `path`, saying the last one was wrong if
+/// `retry`. With `confirm` it's a new passphrase, asked for twice.
+pub fn ask_passphrase(path: &Path, retry: bool, confirm: bool) -> Option<String> {
+    let title = if confirm { "Encrypt with Passphrase" } else { "Encrypted Document" };
+    let dialog = Dialog::with_buttons(
+        Some(title),
+        None::<&Window>,
+        DialogFlags::MODAL,
+        &[("_Cancel", ResponseType::Cancel), ("_OK", ResponseType::Ok)],
+    );
+    dialog.set_default_response(ResponseType::Ok);
+    let content = dialog.get_content_area();
+    content.set_spacing(6);
+    content.set_border_width(10);
+    let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    let message = match (retry, confirm) {
+        (true, true) => "The passphrases didn't match, try again.".to_string(),
+        (true, false) => format!("That passphrase didn't open {}, try again.", name),
+        (false, true) => format!("Choose a passphrase to encrypt {} with. It can't be opened without it.", name),
+        (false, false) => format!("{} is encrypted, enter its passphrase.", name),
+    };
+    let label = Label::new(Some(&message));
+    label.set_line_wrap(true);
+    content.pack_start(&label, false, false, 0);
+    let entries: Vec<Entry> = (0..if confirm { 2 } else { 1 }).map(|_| {
+        let entry = Entry::new();
+        entry.set_visibility(false);
+        entry.set_activates_default(true);
+        content.pack_start(&entry, false, false, 0);
+        entry
+    }).collect();
+    if confirm {
+        entries[1].set_placeholder_text(Some("Again"));
+    }
+    dialog.show_all();
+    let response = dialog.run();
+    let texts: Vec<String> = entries.iter().map(|e| e.get_text().map(|t| t.to_string()).unwrap_or_default()).collect();
+    dialog.destroy();
+    if response != ResponseType::Ok || texts[0].is_empty() {
+        return None;
+    }
+    if confirm && texts[0] != texts[1] {
+        return ask_passphrase(path, true, true);
+    }
+    Some(texts[0].clone())
+}
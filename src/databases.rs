@@ -0,0 +1,238 @@
+//! The two `Storage` implementations, see `storage.rs`. They're the only
+//! things which need sled and SQLite, so they're here on their own, behind
+//! the `storage` feature.
+
+use automerge_backend::Change;
+use automerge_protocol as amp;
+use rusqlite::{params, OptionalExtension};
+use std::convert::TryFrom;
+use std::io;
+use std::path::Path;
+use uuid::Uuid;
+
+use crate::peer::DocumentId;
+use crate::storage::Storage;
+
+fn other(e: impl std::error::Error + Send + Sync + 'static) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e)
+}
+
+fn decode(bytes: Vec<u8>) -> io::Result<Change> {
+    Change::from_bytes(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e)))
+}
+
+fn encode_hashes(hashes: &[amp::ChangeHash]) -> Vec<u8> {
+    hashes.iter().flat_map(|h| h.0.iter().copied()).collect()
+}
+
+fn decode_hashes(bytes: &[u8]) -> Vec<amp::ChangeHash> {
+    bytes.chunks_exact(32)
+        .map(|chunk| amp::ChangeHash(<[u8; 32]>::try_from(chunk).unwrap()))
+        .collect()
+}
+
+fn document_id(bytes: &[u8]) -> io::Result<DocumentId> {
+    Uuid::from_slice(bytes)
+        .map(DocumentId)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Changes are keyed by the document's id followed by an id from
+/// `Db::generate_id`, which only ever goes up, so a prefix scan gives a
+/// document's changes in the order they were stored
+pub struct SledStorage {
+    db: sled::Db,
+    changes: sled::Tree,
+    /// The documents in the order they were first stored, keyed by that
+    /// order
+    documents: sled::Tree,
+    sync_states: sled::Tree,
+}
+
+impl SledStorage {
+    pub fn open(path: &Path) -> io::Result<SledStorage> {
+        let db = sled::open(path).map_err(other)?;
+        Ok(SledStorage {
+            changes: db.open_tree("changes").map_err(other)?,
+            documents: db.open_tree("documents").map_err(other)?,
+            sync_states: db.open_tree("sync_states").map_err(other)?,
+            db,
+        })
+    }
+
+    fn change_batch(&self, document: DocumentId, changes: &[Change], batch: &mut sled::Batch) -> io::Result<()> {
+        for change in changes {
+            let mut key = document.0.as_bytes().to_vec();
+            key.extend_from_slice(&self.db.generate_id().map_err(other)?.to_be_bytes());
+            batch.insert(key, change.raw_bytes());
+        }
+        Ok(())
+    }
+
+    fn remember(&self, document: DocumentId) -> io::Result<()> {
+        let known = self.documents.iter().values().any(|id| id.map(|id| &id[..] == &document.0.as_bytes()[..]).unwrap_or(false));
+        if !known {
+            let order = self.db.generate_id().map_err(other)?;
+            self.documents.insert(&order.to_be_bytes()[..], &document.0.as_bytes()[..]).map_err(other)?;
+        }
+        Ok(())
+    }
+}
+
+impl Storage for SledStorage {
+    fn append_changes(&mut self, document: DocumentId, changes: &[Change]) -> io::Result<()> {
+        self.remember(document)?;
+        let mut batch = sled::Batch::default();
+        self.change_batch(document, changes, &mut batch)?;
+        self.changes.apply_batch(batch).map_err(other)?;
+        self.db.flush().map_err(other)?;
+        Ok(())
+    }
+
+    fn load_document(&mut self, document: DocumentId) -> io::Result<Vec<Change>> {
+        self.changes.scan_prefix(&document.0.as_bytes()[..])
+            .values()
+            .map(|bytes| decode(bytes.map_err(other)?.to_vec()))
+            .collect()
+    }
+
+    fn save_snapshot(&mut self, document: DocumentId, changes: &[Change]) -> io::Result<()> {
+        self.remember(document)?;
+        let mut batch = sled::Batch::default();
+        for key in self.changes.scan_prefix(&document.0.as_bytes()[..]).keys() {
+            batch.remove(key.map_err(other)?);
+        }
+        self.change_batch(document, changes, &mut batch)?;
+        self.changes.apply_batch(batch).map_err(other)?;
+        self.db.flush().map_err(other)?;
+        Ok(())
+    }
+
+    fn load_sync_state(&mut self, document: DocumentId, peer: &str) -> io::Result<Option<Vec<amp::ChangeHash>>> {
+        let have = self.sync_states.get(sync_key(document, peer)).map_err(other)?;
+        Ok(have.map(|have| decode_hashes(&have)))
+    }
+
+    fn save_sync_state(&mut self, document: DocumentId, peer: &str, have: &[amp::ChangeHash]) -> io::Result<()> {
+        self.sync_states.insert(sync_key(document, peer), encode_hashes(have)).map_err(other)?;
+        self.db.flush().map_err(other)?;
+        Ok(())
+    }
+
+    fn documents(&mut self) -> io::Result<Vec<DocumentId>> {
+        self.documents.iter()
+            .values()
+            .map(|id| document_id(&id.map_err(other)?))
+            .collect()
+    }
+
+    fn size(&mut self, document: DocumentId) -> io::Result<u64> {
+        self.changes.scan_prefix(&document.0.as_bytes()[..])
+            .values()
+            .map(|bytes| Ok(bytes.map_err(other)?.len() as u64))
+            .sum()
+    }
+}
+
+fn sync_key(document: DocumentId, peer: &str) -> Vec<u8> {
+    let mut key = document.0.as_bytes().to_vec();
+    key.extend_from_slice(peer.as_bytes());
+    key
+}
+
+/// Changes go in one table in the order they're inserted, which `rowid`
+/// keeps, and sync states in another keyed by document and peer
+pub struct SqliteStorage {
+    connection: rusqlite::Connection,
+}
+
+impl SqliteStorage {
+    pub fn open(path: &Path) -> io::Result<SqliteStorage> {
+        let connection = rusqlite::Connection::open(path).map_err(other)?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS changes (document BLOB NOT NULL, change BLOB NOT NULL);
+             CREATE INDEX IF NOT EXISTS changes_by_document ON changes (document);
+             CREATE TABLE IF NOT EXISTS sync_states (
+                 document BLOB NOT NULL,
+                 peer TEXT NOT NULL,
+                 have BLOB NOT NULL,
+                 PRIMARY KEY (document, peer)
+             );",
+        ).map_err(other)?;
+        Ok(SqliteStorage{connection})
+    }
+}
+
+fn insert_changes(transaction: &rusqlite::Transaction, document: DocumentId, changes: &[Change]) -> rusqlite::Result<()> {
+    let mut insert = transaction.prepare_cached("INSERT INTO changes (document, change) VALUES (?1, ?2)")?;
+    for change in changes {
+        insert.execute(params![&document.0.as_bytes()[..], change.raw_bytes()])?;
+    }
+    Ok(())
+}
+
+impl Storage for SqliteStorage {
+    fn append_changes(&mut self, document: DocumentId, changes: &[Change]) -> io::Result<()> {
+        let transaction = self.connection.transaction().map_err(other)?;
+        insert_changes(&transaction, document, changes).map_err(other)?;
+        transaction.commit().map_err(other)
+    }
+
+    fn load_document(&mut self, document: DocumentId) -> io::Result<Vec<Change>> {
+        let mut select = self.connection
+            .prepare_cached("SELECT change FROM changes WHERE document = ?1 ORDER BY rowid")
+            .map_err(other)?;
+        let rows = select.query_map(params![&document.0.as_bytes()[..]], |row| row.get::<_, Vec<u8>>(0))
+            .map_err(other)?;
+        rows.map(|bytes| decode(bytes.map_err(other)?)).collect()
+    }
+
+    fn save_snapshot(&mut self, document: DocumentId, changes: &[Change]) -> io::Result<()> {
+        let transaction = self.connection.transaction().map_err(other)?;
+        transaction.execute("DELETE FROM changes WHERE document = ?1", params![&document.0.as_bytes()[..]])
+            .map_err(other)?;
+        insert_changes(&transaction, document, changes).map_err(other)?;
+        transaction.commit().map_err(other)
+    }
+
+    fn load_sync_state(&mut self, document: DocumentId, peer: &str) -> io::Result<Option<Vec<amp::ChangeHash>>> {
+        let have: Option<Vec<u8>> = self.connection
+            .query_row(
+                "SELECT have FROM sync_states WHERE document = ?1 AND peer = ?2",
+                params![&document.0.as_bytes()[..], peer],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(other)?;
+        Ok(have.map(|have| decode_hashes(&have)))
+    }
+
+    fn save_sync_state(&mut self, document: DocumentId, peer: &str, have: &[amp::ChangeHash]) -> io::Result<()> {
+        self.connection
+            .execute(
+                "INSERT OR REPLACE INTO sync_states (document, peer, have) VALUES (?1, ?2, ?3)",
+                params![&document.0.as_bytes()[..], peer, encode_hashes(have)],
+            )
+            .map(|_| ())
+            .map_err(other)
+    }
+
+    fn documents(&mut self) -> io::Result<Vec<DocumentId>> {
+        let mut select = self.connection
+            .prepare_cached("SELECT document FROM changes GROUP BY document ORDER BY min(rowid)")
+            .map_err(other)?;
+        let rows = select.query_map([], |row| row.get::<_, Vec<u8>>(0)).map_err(other)?;
+        rows.map(|id| document_id(&id.map_err(other)?)).collect()
+    }
+
+    fn size(&mut self, document: DocumentId) -> io::Result<u64> {
+        self.connection
+            .query_row(
+                "SELECT coalesce(sum(length(change)), 0) FROM changes WHERE document = ?1",
+                params![&document.0.as_bytes()[..]],
+                |row| row.get::<_, i64>(0),
+            )
+            .map(|size| size as u64)
+            .map_err(other)
+    }
+}
@@ -0,0 +1,124 @@
+//! `--dbus`, controlling the demo from outside over D-Bus.
+//!
+//! Scripts and tests which want to drive the demo shouldn't have to
+//! synthesize GTK input events to do it. With `--dbus` the demo takes the
+//! name `org.example.AutomergeDemo` on the session bus and serves an object
+//! at `/org/example/AutomergeDemo` with methods to edit and read each tab,
+//! and a `PatchApplied` signal for every patch a tab applies, so they can
+//! watch edits propagate between tabs and instances:
+//!
+//! ```text
+//! busctl --user call org.example.AutomergeDemo /org/example/AutomergeDemo \
+//!     org.example.AutomergeDemo InsertText uus 0 0 "hello"
+//! ```
+//!
+//! Tabs are numbered from 0 in the order they're shown. Text goes in through
+//! the tab's buffer, the same as `--play-session`, so it takes the same path
+//! as typing.
+//!
+//! zbus runs the object on a thread of its own. Each method call is sent to
+//! the UI as a `Message::Control` with a channel to answer on, which the
+//! method waits on without holding up the rest of the connection.
+
+use std::sync::Mutex;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use zbus::{dbus_interface, fdo, SignalContext};
+
+use crate::{Message, Model};
+
+pub const NAME: &str = "org.example.AutomergeDemo";
+pub const PATH: &str = "/org/example/AutomergeDemo";
+
+type Reply<T> = UnboundedSender<Result<T, String>>;
+
+/// A method call for the UI to carry out, in the tab at index `tab`
+#[derive(Clone, Debug)]
+pub enum Call {
+    InsertText{tab: u32, offset: u32, text: String, reply: Reply<()>},
+    DeleteText{tab: u32, start: u32, end: u32, reply: Reply<()>},
+    GetText{tab: u32, reply: Reply<String>},
+    /// Replies with the new value
+    IncrementCounter{tab: u32, reply: Reply<i64>},
+    GetCounter{tab: u32, reply: Reply<i64>},
+    /// Replies with `Doc::heads_hash`, which two tabs agree on once they've
+    /// seen the same changes
+    GetHeads{tab: u32, reply: Reply<String>},
+}
+
+struct Control {
+    // Only ever used to send, the lock is just to make it `Sync`
+    scope: Mutex<vgtk::Scope<Model>>,
+}
+
+impl Control {
+    async fn call<T>(&self, call: impl FnOnce(Reply<T>) -> Call) -> fdo::Result<T> {
+        let (reply, mut answer): (_, UnboundedReceiver<Result<T, String>>) = unbounded_channel();
+        self.scope.lock().unwrap()
+            .try_send(Message::Control(call(reply)))
+            .map_err(|_| fdo::Error::Failed("the demo is shutting down".to_string()))?;
+        answer.recv().await
+            .ok_or_else(|| fdo::Error::Failed("the demo is shutting down".to_string()))?
+            .map_err(fdo::Error::Failed)
+    }
+}
+
+#[dbus_interface(name = "org.example.AutomergeDemo")]
+impl Control {
+    /// Insert `text` at char `offset` of the tab's text
+    async fn insert_text(&self, tab: u32, offset: u32, text: String) -> fdo::Result<()> {
+        self.call(|reply| Call::InsertText{tab, offset, text, reply}).await
+    }
+
+    /// Delete the chars from `start` up to `end`
+    async fn delete_text(&self, tab: u32, start: u32, end: u32) -> fdo::Result<()> {
+        self.call(|reply| Call::DeleteText{tab, start, end, reply}).await
+    }
+
+    async fn get_text(&self, tab: u32) -> fdo::Result<String> {
+        self.call(|reply| Call::GetText{tab, reply}).await
+    }
+
+    async fn increment_counter(&self, tab: u32) -> fdo::Result<i64> {
+        self.call(|reply| Call::IncrementCounter{tab, reply}).await
+    }
+
+    async fn get_counter(&self, tab: u32) -> fdo::Result<i64> {
+        self.call(|reply| Call::GetCounter{tab, reply}).await
+    }
+
+    async fn get_heads(&self, tab: u32) -> fdo::Result<String> {
+        self.call(|reply| Call::GetHeads{tab, reply}).await
+    }
+
+    /// The tab at index `tab` applied a patch from `actor`, empty if it
+    /// isn't from any one actor, after which its heads are `heads`
+    #[dbus_interface(signal)]
+    async fn patch_applied(ctxt: &SignalContext<'_>, tab: u32, actor: String, heads: String) -> zbus::Result<()>;
+}
+
+/// The connection to the session bus, which keeps the object served while
+/// it's around
+#[derive(Clone, Debug)]
+pub struct Handle {
+    connection: zbus::blocking::Connection,
+}
+
+/// Take our name on the session bus and serve the control object
+pub fn start(scope: vgtk::Scope<Model>) -> zbus::Result<Handle> {
+    let connection = zbus::blocking::ConnectionBuilder::session()?
+        .name(NAME)?
+        .serve_at(PATH, Control{scope: Mutex::new(scope)})?
+        .build()?;
+    tracing::info!("serving {} at {} on the session bus", NAME, PATH);
+    Ok(Handle{connection})
+}
+
+impl Handle {
+    pub fn patch_applied(&self, tab: usize, actor: Option<String>, heads: String) {
+        let result = SignalContext::new(self.connection.inner(), PATH)
+            .and_then(|ctxt| zbus::block_on(Control::patch_applied(&ctxt, tab as u32, actor.unwrap_or_default(), heads)));
+        if let Err(e) = result {
+            tracing::warn!("could not send PatchApplied: {}", e);
+        }
+    }
+}
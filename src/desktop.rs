@@ -0,0 +1,133 @@
+//! Desktop notifications about other people's edits, for while the window
+//! isn't focused.
+//!
+//! Someone typing sends a patch for every few keystrokes, so rather than a
+//! notification per patch, the edits are tallied up, who made them and how
+//! many characters they inserted or deleted, and a notification about the
+//! tally is sent at most every `MIN_INTERVAL`. It always has the same id, so
+//! each one replaces the last rather than piling up. Clicking it brings the
+//! window back, and once the window's focused again the notification is
+//! withdrawn, there's nothing left to tell.
+//!
+//! Every tab of a document gets a patch for the same remote change, so
+//! changes are counted by hash, once each.
+
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+use automerge_protocol as amp;
+use vgtk::lib::gio::{self, prelude::*};
+use vgtk::lib::glib::Cast;
+use vgtk::lib::gtk::prelude::*;
+use vgtk::lib::gtk::{Application, ApplicationWindow};
+
+/// The id of our one notification
+const ID: &str = "remote-edits";
+
+/// The app action which clicking the notification activates
+const FOCUS_ACTION: &str = "focus-window";
+
+/// How often a notification is sent, at most
+const MIN_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Default)]
+pub struct DesktopNotifier {
+    /// Everyone who has made remote edits since the last notification, in
+    /// the order they first did
+    authors: Vec<String>,
+    /// How many characters they inserted or deleted between them
+    chars: usize,
+    /// The changes counted since the window was last focused
+    seen: HashSet<amp::ChangeHash>,
+    last_sent: Option<Instant>,
+    shown: bool,
+}
+
+/// Let clicking a notification bring the window to the front
+pub fn install(window: &ApplicationWindow) {
+    let app = match window.get_application() {
+        Some(app) => app,
+        None => return,
+    };
+    let action = gio::SimpleAction::new(FOCUS_ACTION, None);
+    let window = window.downgrade();
+    action.connect_activate(move |_, _| {
+        if let Some(window) = window.upgrade() {
+            window.present();
+        }
+    });
+    app.add_action(&action);
+}
+
+fn application() -> Option<Application> {
+    gio::Application::get_default()?.downcast::<Application>().ok()
+}
+
+/// Whether the window has the keyboard focus
+pub fn window_active() -> bool {
+    application()
+        .and_then(|app| app.get_active_window())
+        .map_or(false, |window| window.is_active())
+}
+
+impl DesktopNotifier {
+    /// Count the remote changes `hashes`, made by `authors`, which insert or
+    /// delete `chars` characters between them
+    pub fn edited(&mut self, hashes: &[amp::ChangeHash], authors: Vec<String>, chars: usize) {
+        let mut new = false;
+        for hash in hashes {
+            new |= self.seen.insert(*hash);
+        }
+        if !new {
+            return;
+        }
+        for author in authors {
+            if !self.authors.contains(&author) {
+                self.authors.push(author);
+            }
+        }
+        self.chars += chars;
+    }
+
+    /// Send the notification if there's something new to say and it's been
+    /// long enough since the last one, or withdraw it if the window has been
+    /// focused again. Called every metrics tick.
+    pub fn tick(&mut self) {
+        let app = match application() {
+            Some(app) => app,
+            None => return,
+        };
+        if window_active() {
+            if self.shown {
+                app.withdraw_notification(ID);
+                self.shown = false;
+            }
+            *self = DesktopNotifier::default();
+            return;
+        }
+        if self.authors.is_empty() || self.last_sent.map_or(false, |sent| sent.elapsed() < MIN_INTERVAL) {
+            return;
+        }
+        let notification = gio::Notification::new("Remote edits");
+        notification.set_body(Some(&self.body()));
+        notification.set_default_action(&format!("app.{}", FOCUS_ACTION));
+        app.send_notification(Some(ID), &notification);
+        self.shown = true;
+        self.last_sent = Some(Instant::now());
+        self.authors.clear();
+        self.chars = 0;
+    }
+
+    /// "Alice and Bob changed 42 characters", say
+    fn body(&self) -> String {
+        let who = match self.authors.split_last() {
+            Some((last, [])) => last.clone(),
+            Some((last, rest)) => format!("{} and {}", rest.join(", "), last),
+            None => String::new(),
+        };
+        match self.chars {
+            0 => format!("{} made changes", who),
+            1 => format!("{} changed 1 character", who),
+            n => format!("{} changed {} characters", who, n),
+        }
+    }
+}
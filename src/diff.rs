@@ -0,0 +1,121 @@
+//! Comparing the text at two points in its history.
+//!
+//! A point in history is a prefix of the change log the backend gives us,
+//! which is in causal order. To see the text at that point I replay the
+//! prefix into a fresh backend and apply the resulting patch to a fresh
+//! `OffsetIndex`, see `offset_index.rs`, which makes the text from it much as
+//! a frontend would.
+//!
+//! Rather than diffing the two strings, which would have to guess what moved
+//! where, I diff the characters themselves. Every character in an automerge
+//! text is an element with the ID of the op which inserted it (the offset
+//! index keeps track of these too) and elements never move
+//! relative to each other. So walking both versions together, anything only
+//! in the older version was deleted, anything only in the newer one was
+//! inserted and the rest lines up.
+
+use automerge_backend::{Backend, Change};
+use std::collections::HashSet;
+use vgtk::lib::glib;
+
+use crate::offset_index::OffsetIndex;
+
+/// The text as of some point in the history
+pub struct Version {
+    /// The heads at this point, shortened for display
+    pub heads: Vec<String>,
+    chars: Vec<char>,
+    /// The op which inserted each of `chars`
+    elems: Vec<Option<String>>,
+}
+
+impl Version {
+    /// Replay `changes` to find the text they produce
+    pub fn materialize(changes: &[Change]) -> Version {
+        let mut backend = Backend::init();
+        let mut offsets = OffsetIndex::default();
+        if !changes.is_empty() {
+            let patch = backend.apply_changes(changes.to_vec()).unwrap();
+            offsets.apply_patch(&patch);
+        }
+        let chars: Vec<char> = offsets.chars().collect();
+        let elems = offsets.ops().map(|op| op.map(|s| s.to_string())).collect();
+        let mut heads: Vec<String> = backend
+            .get_heads()
+            .iter()
+            .map(|h| format!("{:?}", h).chars().take(8).collect())
+            .collect();
+        heads.sort();
+        Version { heads, chars, elems }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Span {
+    Same(String),
+    Inserted(String),
+    Deleted(String),
+}
+
+/// What changed in the text between `from` and `to`
+pub fn diff(from: &Version, to: &Version) -> Vec<Span> {
+    let in_from: HashSet<&Option<String>> = from.elems.iter().collect();
+    let in_to: HashSet<&Option<String>> = to.elems.iter().collect();
+    let mut spans = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < from.chars.len() || j < to.chars.len() {
+        if i < from.chars.len() && !in_to.contains(&from.elems[i]) {
+            push(&mut spans, Span::Deleted, from.chars[i]);
+            i += 1;
+        } else if j < to.chars.len() && !in_from.contains(&to.elems[j]) {
+            push(&mut spans, Span::Inserted, to.chars[j]);
+            j += 1;
+        } else if j < to.chars.len() {
+            // In both, so the same element is next in each
+            push(&mut spans, Span::Same, to.chars[j]);
+            i += 1;
+            j += 1;
+        } else {
+            // Only possible if the elements aren't in the same order in both
+            // versions, which automerge guarantees they are
+            push(&mut spans, Span::Deleted, from.chars[i]);
+            i += 1;
+        }
+    }
+    spans
+}
+
+/// Add `c` to the last span if it's the same kind, otherwise start a new one
+fn push(spans: &mut Vec<Span>, kind: fn(String) -> Span, c: char) {
+    let extended = match (spans.last_mut(), kind(String::new())) {
+        (Some(Span::Same(s)), Span::Same(_))
+        | (Some(Span::Inserted(s)), Span::Inserted(_))
+        | (Some(Span::Deleted(s)), Span::Deleted(_)) => {
+            s.push(c);
+            true
+        }
+        _ => false,
+    };
+    if !extended {
+        spans.push(kind(c.to_string()));
+    }
+}
+
+/// Pango markup for an inline diff, insertions in green and deletions struck
+/// through in red
+pub fn markup(spans: &[Span]) -> String {
+    spans
+        .iter()
+        .map(|span| match span {
+            Span::Same(s) => glib::markup_escape_text(s).to_string(),
+            Span::Inserted(s) => format!(
+                "<span background=\"#b8e994\">{}</span>",
+                glib::markup_escape_text(s)
+            ),
+            Span::Deleted(s) => format!(
+                "<span background=\"#f8b4b4\" strikethrough=\"true\">{}</span>",
+                glib::markup_escape_text(s)
+            ),
+        })
+        .collect()
+}
@@ -0,0 +1,86 @@
+//! Finding other instances of the demo on the local network.
+//!
+//! When the websocket server is running we advertise it over mDNS and browse
+//! for other instances doing the same. Discovered instances are pushed into
+//! the application scope so they can be listed in the peers popover, from
+//! where the user can connect to them with `ws::connect`. A server which
+//! needs TLS says so in its TXT record.
+//!
+//! mDNS is part of the `net` feature. Built without it the server isn't
+//! advertised and nothing is ever discovered, but peers can still be
+//! connected to by address.
+
+#[cfg(feature = "net")]
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+#[cfg(feature = "net")]
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+#[cfg(feature = "net")]
+use crate::Message;
+use crate::Model;
+
+#[cfg(feature = "net")]
+pub const SERVICE_TYPE: &str = "_automerge-demo._tcp.local.";
+
+/// Another instance of the demo which we could sync with
+#[derive(Clone, Debug, PartialEq)]
+pub struct Peer {
+    /// The full mDNS service name, unique per instance
+    pub name: String,
+    pub addr: SocketAddr,
+    /// Whether its server has to be connected to with TLS
+    pub tls: bool,
+}
+
+/// Advertise our websocket server on `port` and start browsing for peers.
+/// Discovery stops when the returned daemon is dropped.
+#[cfg(feature = "net")]
+pub fn start(port: u16, tls: bool, scope: vgtk::Scope<Model>) -> Result<ServiceDaemon, mdns_sd::Error> {
+    let daemon = ServiceDaemon::new()?;
+    let instance = format!("automerge-demo-{}", std::process::id());
+    let host = format!("{}.local.", instance);
+    let mut properties = HashMap::new();
+    properties.insert("tls".to_string(), if tls { "1" } else { "0" }.to_string());
+    let info = ServiceInfo::new(SERVICE_TYPE, &instance, &host, "", port, properties)?
+        .enable_addr_auto();
+    let own_name = info.get_fullname().to_string();
+    daemon.register(info)?;
+
+    let events = daemon.browse(SERVICE_TYPE)?;
+    std::thread::spawn(move || {
+        while let Ok(event) = events.recv() {
+            match event {
+                ServiceEvent::ServiceResolved(info) => {
+                    if info.get_fullname() == own_name {
+                        continue;
+                    }
+                    if let Some(ip) = info.get_addresses().iter().next() {
+                        let peer = Peer {
+                            name: info.get_fullname().to_string(),
+                            addr: SocketAddr::new((*ip).into(), info.get_port()),
+                            tls: info.get_property_val_str("tls") == Some("1"),
+                        };
+                        tracing::info!("discovered peer {:?}", peer);
+                        if scope.try_send(Message::PeerDiscovered(peer)).is_err() {
+                            return;
+                        }
+                    }
+                }
+                ServiceEvent::ServiceRemoved(_, name) => {
+                    if scope.try_send(Message::PeerLost(name)).is_err() {
+                        return;
+                    }
+                }
+                _ => {}
+            }
+        }
+    });
+    Ok(daemon)
+}
+
+#[cfg(not(feature = "net"))]
+pub fn start(_port: u16, _tls: bool, _scope: vgtk::Scope<Model>) -> Result<(), std::convert::Infallible> {
+    tracing::info!("not advertising the server, mDNS needs the demo to be built with `--features net`");
+    Ok(())
+}
@@ -0,0 +1,1503 @@
+//! The state of one tab's frontend and the text buffer it is bound to.
+
+use vgtk::lib::gtk::*;
+use vgtk::lib::glib::{self, Cast, SignalHandlerId, ObjectExt};
+use automerge_frontend::{Frontend, LocalChange, Path, Value};
+use automerge_protocol as amp;
+use std::cell::{Cell, RefCell};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::ops::Range;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub use automerge_demo_core::text::{counter_value, deleted, inserted, text_value};
+use automerge_demo_core::text::changed;
+
+use crate::change_log::{ChangeLog, ChangeMeta};
+use crate::clipboard::{self, Clip, Pastes};
+use crate::compose::{Composer, Typed};
+use crate::growth::Growth;
+use crate::history_index::{HistoryIndex, Provenance};
+use crate::markdown::MarkdownPreview;
+use crate::metrics::Metrics;
+use crate::patch_log::PatchLog;
+use crate::peer::DocumentId;
+use crate::pipeline::{ChangeSender, Coalescer, Edit};
+use crate::schema::{self, FieldAction, FieldValue, Schema};
+use crate::session::{self, Recorder};
+use crate::spellcheck::{self, Spellcheck};
+use crate::splice::{self, Splice};
+use crate::subscriptions::{self, Subscriptions};
+use crate::state::{self, DocState};
+use crate::text_cache::TextCache;
+use crate::text_patch::{self, BufferSync, TextEdits};
+use crate::undo::{self, UndoStack};
+
+use crate::{blame, chat, checklist, export, find, snapshot, kanban, marks, presence, size, suggestion, syntax, table, theme, title};
+
+/// The parts of the document which are only shown through the text buffer.
+/// Patches update the buffer directly, so a patch which only touches these
+/// doesn't need the window to be rendered again. The status bar catches up
+/// on the next metrics tick.
+const BUFFER_KEYS: &[&str] = &["text", "marks"];
+
+/// How long the document has to be left alone before we start bringing the
+/// token index up to date, so a burst of typing is only indexed once
+const INDEX_SETTLE_MS: u32 = 500;
+
+/// Counts of what's in the text, for the status bar
+#[derive(Clone, Copy, Default)]
+pub struct TextStats {
+    pub chars: usize,
+    pub words: usize,
+}
+
+/// A wrapper around the state of the frontend, this is passed to DocView as a
+/// property.
+pub struct Doc {
+    frontend: Rc<RefCell<Frontend>>,
+    /// A source buffer when there's syntax highlighting, see `syntax.rs`
+    pub buffer: TextBuffer,
+    /// What the buffer shows, without asking the frontend, see
+    /// `text_cache.rs`
+    display: TextCache,
+    /// The id of the language the text is highlighted as
+    language: Option<String>,
+    /// Whether the colors are for the dark theme, see `theme.rs`
+    dark: bool,
+    /// We need these two signal handlers to block the signals when updating
+    /// the text based on diffs received from the backend
+    insert_text_sigid: SignalHandlerId,
+    del_sig_id: SignalHandlerId,
+    /// This is what we use to send new changes to the backend
+    sender: ChangeSender,
+    /// Batches up the changes from keystrokes before they're sent
+    coalescer: Coalescer,
+    /// Where the buffer's edits go before the coalescer, holding back any
+    /// an input method is composing
+    composer: Composer,
+    spellcheck: Spellcheck,
+    /// Provenance and search indexes over the text
+    index: Rc<RefCell<HistoryIndex>>,
+    /// The pending settle timer or idle indexing, if any
+    index_source: Rc<RefCell<Option<glib::SourceId>>>,
+    /// The fields new documents are created with, shown in the Fields tab
+    schema: Arc<Schema>,
+    /// The title values which lost to the current title in a conflict
+    pub title_conflicts: Vec<String>,
+    undo: Rc<RefCell<UndoStack>>,
+    pub patch_log: PatchLog,
+    /// Where the document was last saved to or opened from
+    pub path: Option<PathBuf>,
+    /// Which document this is in every instance syncing it
+    pub id: DocumentId,
+    /// Whether the window is showing the find bar and patch log in this
+    /// document's tab
+    pub show_find: bool,
+    pub show_patch_log: bool,
+    pub show_undo_history: bool,
+    /// While suggesting, the seq of the last change we made before we
+    /// started, see `suggestion.rs`
+    suggesting_since: Option<u64>,
+    /// Whether the chat pane is open, and the chat it shows
+    pub show_chat: bool,
+    chat: chat::ChatLog,
+    /// Whether the text is shown in two views, both bound to `buffer`. The
+    /// views share the buffer's cursor and selection as well as its text.
+    pub split: bool,
+    /// Whether the views show line numbers and highlight the line the
+    /// cursor is on
+    pub show_line_numbers: bool,
+    /// Whether the blame gutter is showing, and its text
+    pub show_blame: bool,
+    pub blame_buffer: TextBuffer,
+    /// The changes behind the text, for blame. Shared with the views'
+    /// tooltips.
+    change_log: Rc<RefCell<ChangeLog>>,
+    /// How big the history has got over time, see `growth.rs`. Shared with
+    /// the chart's draw handler.
+    growth: Rc<RefCell<Growth>>,
+    /// Whether the Markdown preview is showing, and what it shows
+    pub show_preview: bool,
+    preview: MarkdownPreview,
+    /// A read only doc follows along with everyone else's edits but doesn't
+    /// make any of its own. Shared with the buffer's signal handlers.
+    read_only: Rc<Cell<bool>>,
+    /// Where to record this tab's input, if we're recording the session.
+    /// Shared with the buffer's signal handlers.
+    recorder: Rc<RefCell<Option<Recorder>>>,
+    /// What the find bar is searching for, empty if it isn't
+    search_query: String,
+    /// The char offsets of every match of `search_query`
+    search_matches: Vec<usize>,
+    /// How long it took for our most recent change to be acknowledged
+    last_latency: Option<Duration>,
+    /// The dependencies of the most recent patch, i.e. the heads of the
+    /// document in the backend
+    heads: Vec<String>,
+    /// Who to tell when a patch touches the part of the document they're
+    /// interested in
+    subscriptions: Subscriptions,
+    /// Set when the frontend couldn't apply a patch or make a change, which
+    /// means it no longer agrees with the backend. Shared with the
+    /// coalescer.
+    desynced: Rc<Cell<bool>>,
+    /// Whether the buffer has a patch to catch up with
+    buffer_sync: BufferSync,
+    /// Whether we've asked the backend for the state to resync from
+    resync_requested: bool,
+    /// Where to put the cursor once the text arrives, see `restore_cursor`
+    restore_cursor: Option<usize>,
+    /// Pasted marks waiting for the pasted text's ops, see `clipboard.rs`
+    pastes: Pastes,
+    /// The first and last lines of the text showing, as the view last said
+    viewport: Option<(usize, usize)>,
+    /// Counts of what's in the text as of the last patch to touch it
+    stats: Rc<Cell<TextStats>>,
+    /// The value of the counter, kept up to date so that rendering it
+    /// doesn't have to look it up in the frontend
+    counter: Rc<Cell<i64>>,
+}
+
+
+impl Doc {
+    /// Create a new frontend. If `initialize` is false the initial state of
+    /// the document is coming from the backend (because we're opening a saved
+    /// document) so we don't create it ourselves, otherwise it's created
+    /// with the fields in `schema`. With `coalesce` set keystrokes are
+    /// batched up until typing pauses for that long.
+    pub fn new(
+        sx: crossbeam::Sender<amp::Request>,
+        presence_sx: crossbeam::Sender<presence::PresenceEvent>,
+        metrics: Arc<Metrics>,
+        initialize: bool,
+        coalesce: Option<Duration>,
+        schema: Arc<Schema>,
+    ) -> Doc {
+        let mut frontend = Frontend::new();
+        let sender = ChangeSender::new(
+            sx,
+            presence::Heartbeat::new(frontend.actor_id.to_string(), presence_sx),
+            metrics,
+        );
+        if initialize {
+            // Initialize the state of the frontend to the fields in the
+            // schema, by default {"counts": Counter(0), "text": ""}, and
+            // what the rest of the demo needs
+            // {
+            //     "title": "Untitled",
+            //     "marks": [],
+            //     "todos": [],
+            //     "chat": [],
+            //     "table": {"0": {}, "1": {}, "2": {}},
+            //     "table_rows": Counter(3),
+            //     "table_cols": Counter(3),
+            //     "kanban": [{"title": "To do", "cards": []}, ...]
+            // }
+            let cr = frontend.change(Some("Create the document".to_string()), |doc| {
+                doc.add_change(LocalChange::set(
+                    Path::root().key("title"),
+                    title::initial_value(),
+                ))?;
+                for change in schema.initial_changes() {
+                    doc.add_change(change)?;
+                }
+                doc.add_change(LocalChange::set(
+                    Path::root().key("marks"),
+                    marks::initial_value(),
+                ))?;
+                doc.add_change(LocalChange::set(
+                    Path::root().key("todos"),
+                    checklist::initial_value(),
+                ))?;
+                doc.add_change(LocalChange::set(
+                    Path::root().key("chat"),
+                    chat::initial_value(),
+                ))?;
+                for change in table::initial_changes() {
+                    doc.add_change(change)?;
+                }
+                doc.add_change(LocalChange::set(
+                    Path::root().key("kanban"),
+                    kanban::initial_value(),
+                ))?;
+                Ok(())
+            }).unwrap().unwrap();
+            // Send the initialization change request to the backend
+            sender.send(cr);
+        }
+        let frontend_rf = Rc::new(RefCell::new(frontend));
+        let desynced = Rc::new(Cell::new(false));
+        let coalescer = Coalescer::new(frontend_rf.clone(), sender.clone(), coalesce, desynced.clone());
+        let coalescer_clone = coalescer.clone();
+        let buffer = syntax::buffer();
+        marks::create_tags(&buffer);
+        find::create_tag(&buffer);
+        suggestion::create_tag(&buffer);
+        spellcheck::create_tag(&buffer);
+        theme::apply(&buffer, false);
+        let spellcheck = Spellcheck::new();
+        let spellcheck_clone = spellcheck.clone();
+        let spellcheck_clone_2 = spellcheck.clone();
+        let undo_rf = Rc::new(RefCell::new(UndoStack::default()));
+        let undo_clone = undo_rf.clone();
+        let read_only = Rc::new(Cell::new(false));
+        let read_only_clone = read_only.clone();
+        let read_only_clone_2 = read_only.clone();
+        let recorder: Rc<RefCell<Option<Recorder>>> = Rc::new(RefCell::new(None));
+        let recorder_clone = recorder.clone();
+
+        // What's typed, once any composing is done, see `compose.rs`
+        let composer = Composer::new(move |edit| match edit {
+            Typed::Insert { at, text } => {
+                if let Some(recorder) = recorder_clone.borrow().as_ref() {
+                    recorder.record(session::Input::Insert{offset: at, text: text.clone()});
+                }
+                coalescer_clone.push(Edit::Insert, inserted(at, &text));
+                undo_clone.borrow_mut().record(undo::text_inserted(at, &text));
+            }
+            Typed::Delete { start, text } => {
+                let end = start + text.chars().count();
+                if let Some(recorder) = recorder_clone.borrow().as_ref() {
+                    recorder.record(session::Input::Delete{start, end});
+                }
+                undo_clone.borrow_mut().record(undo::text_deleted(start, &text));
+                coalescer_clone.push(Edit::Delete, deleted(start, end));
+            }
+        });
+        let composer_clone = composer.clone();
+        let composer_clone_2 = composer.clone();
+
+        // Wire up the insert text signal handler
+        let sig_id = buffer.connect_insert_text(move |buffer, iter, i| {
+            // Stopping the signal stops the text going into the buffer,
+            // whether it comes from a view or from code
+            if read_only_clone.get() {
+                buffer.stop_signal_emission("insert-text");
+                return;
+            }
+            let _span = tracing::info_span!("keystroke", kind = "insert", len = i.len()).entered();
+            composer_clone.inserted(iter.get_offset() as usize, i);
+            spellcheck_clone.edited(buffer, iter);
+        });
+
+        // Wire up the delete text handler
+        let del_sig_id = buffer.connect_delete_range(move |buffer, start, end| {
+            if read_only_clone_2.get() {
+                buffer.stop_signal_emission("delete-range");
+                return;
+            }
+            let _span = tracing::info_span!("keystroke", kind = "delete", len = end.get_offset() - start.get_offset()).entered();
+            let deleted = buffer.get_text(start, end, true).map(|t| t.to_string()).unwrap_or_default();
+            composer_clone_2.deleted(start.get_offset() as usize, &deleted);
+            spellcheck_clone_2.edited(buffer, start);
+        });
+        let display = TextCache::attach(&buffer);
+        let display_clone = display.clone();
+
+        let stats = Rc::new(Cell::new(TextStats::default()));
+        let stats_clone = stats.clone();
+        let counter = Rc::new(Cell::new(0));
+        let counter_clone = counter.clone();
+
+        let mut doc = Doc{
+            frontend: frontend_rf,
+            buffer,
+            display,
+            language: None,
+            dark: false,
+            insert_text_sigid: sig_id,
+            del_sig_id,
+            sender,
+            coalescer,
+            composer,
+            spellcheck,
+            index: Rc::new(RefCell::new(HistoryIndex::default())),
+            index_source: Rc::new(RefCell::new(None)),
+            schema,
+            title_conflicts: Vec::new(),
+            undo: undo_rf,
+            patch_log: PatchLog::default(),
+            path: None,
+            id: DocumentId::random(),
+            show_find: false,
+            show_patch_log: false,
+            show_undo_history: false,
+            suggesting_since: None,
+            show_chat: false,
+            chat: chat::ChatLog::default(),
+            split: false,
+            show_line_numbers: true,
+            show_blame: false,
+            blame_buffer: TextBuffer::new::<TextTagTable>(None),
+            change_log: Rc::new(RefCell::new(ChangeLog::default())),
+            growth: Rc::new(RefCell::new(Growth::default())),
+            show_preview: false,
+            preview: MarkdownPreview::default(),
+            read_only,
+            recorder,
+            search_query: String::new(),
+            search_matches: Vec::new(),
+            last_latency: None,
+            heads: Vec::new(),
+            subscriptions: Subscriptions::default(),
+            desynced,
+            buffer_sync: BufferSync::default(),
+            resync_requested: false,
+            restore_cursor: None,
+            pastes: Pastes::default(),
+            viewport: None,
+            stats,
+            counter,
+        };
+        // By the time subscribers hear about a patch the buffer, and so the
+        // cache, has it
+        doc.subscribe(&["text"], Box::new(move |_| {
+            stats_clone.set(TextStats {
+                chars: display_clone.len_chars(),
+                words: display_clone.words(),
+            });
+        }));
+        doc.subscribe(&[schema::COUNTER_FIELD], Box::new(move |frontend| counter_clone.set(counter_value(frontend))));
+        doc
+    }
+
+    /// Apply patches which arrived together, in order, and update the text
+    /// buffer if necessary. Each patch comes with the changes it applies.
+    /// Everything which only depends on where they left the document, the
+    /// marks, search, blame and so on, is brought up to date once at the
+    /// end. Returns whether the window has to be rendered again to show the
+    /// patches, which it doesn't if they only changed what's shown through
+    /// the text buffer.
+    pub fn apply_patches(&mut self, patches: Vec<(amp::Patch, Vec<ChangeMeta>)>) -> bool {
+        // Until the resync arrives the frontend is broken, and the resync
+        // will include whatever these patches do anyway
+        if self.desynced.get() || patches.is_empty() {
+            return false;
+        }
+        // The frontend has to have caught up with the buffer before we
+        // change it underneath
+        self.flush();
+        let mut touched = Vec::new();
+        for (patch, changes) in patches {
+            match self.apply_one(patch, changes) {
+                Some(paths) => touched.extend(paths),
+                None => return false,
+            }
+        }
+        self.subscriptions.notify(&touched, &self.frontend.borrow());
+        self.schedule_indexing();
+        if let Some(offset) = self.restore_cursor.take() {
+            // The text may have changed since, and not be split where it was
+            let offset = self.display.snap_to_grapheme(offset);
+            self.buffer.place_cursor(&self.buffer.get_iter_at_offset(offset as i32));
+        }
+        // A paste's marks can go on once its text has ops
+        let anchored = self.pastes.take_anchored();
+        let cr = marks::add(&mut self.frontend.borrow_mut(), &anchored);
+        self.send_change(cr);
+        // Marks can move when characters are acknowledged or text changes
+        // under them, whoever the patch is from
+        let (marks, _) = marks::marks(&self.frontend.borrow());
+        marks::render(&self.buffer, &marks, self.index.borrow().offsets());
+        self.update_search();
+        self.update_suggestions();
+        self.update_blame();
+        self.update_preview();
+        let text_touched = touched.iter().any(|path| path[0] == "text");
+        self.show_patch_log
+            || self.show_undo_history
+            || (self.show_preview && text_touched)
+            || touched.iter().any(|path| !BUFFER_KEYS.contains(&path[0].as_str()))
+    }
+
+    /// Apply one patch to the frontend and the text buffer, returning the
+    /// paths it touched, or nothing if the frontend couldn't apply it and
+    /// has to be resynced
+    fn apply_one(&mut self, patch: amp::Patch, changes: Vec<ChangeMeta>) -> Option<Vec<Vec<String>>> {
+        let _span = tracing::info_span!("apply_patch", actor = ?patch.actor, seq = ?patch.seq).entered();
+        // Everything that only needs to look at the patch goes first so
+        // that it can then be moved into the frontend rather than copied.
+        // Patches for a whole document can be large.
+        self.index.borrow_mut().apply_patch(&patch);
+        self.patch_log.push(&patch, &changes);
+        self.chat.apply_patch(&patch);
+        self.growth.borrow_mut().record(&changes);
+        for change in changes {
+            self.change_log.borrow_mut().record(change);
+        }
+        self.sender.metrics.patch_applied();
+        self.heads = patch.deps.iter().map(|h| format!("{:?}", h)).collect();
+        self.heads.sort();
+        let title_values = title::values_in_patch(&patch);
+        let text_edits = text_patch::edits(&patch);
+        let touched = subscriptions::touched_paths(&patch);
+        let actor = self.frontend.borrow().actor_id.to_string();
+        let own = patch.actor.as_ref() == Some(&actor);
+        // Only our requests since the last resync are acknowledged
+        if own && self.sender.pending() > 0 {
+            self.pastes.acknowledged(&patch, &actor);
+        }
+        if let Err(e) = self.frontend.borrow_mut().apply_patch(patch) {
+            tracing::error!("The frontend couldn't apply a patch: {:?}", e);
+            self.desynced.set(true);
+            return None;
+        }
+        if let Some(values) = title_values {
+            let current = self.title();
+            self.title_conflicts = values.into_iter().filter(|v| *v != current).collect();
+        }
+        // We don't need to update the text buffer if it's a patch for a
+        // request we made. After a resync there can be patches for
+        // requests made before it, which the buffer doesn't have.
+        let acknowledged = if own { self.sender.acknowledged() } else { None };
+        if let Some(latency) = acknowledged {
+            tracing::debug!(latency_us = latency.as_micros() as u64, "change_acknowledged");
+            self.last_latency = Some(latency);
+        }
+        self.update_text(text_edits, acknowledged.is_some());
+        Some(touched)
+    }
+
+    /// Whether we need the whole state from the backend to resync from.
+    /// Only true once, the first time it's asked after things go wrong.
+    pub fn wants_resync(&mut self) -> bool {
+        if self.desynced.get() && !self.resync_requested {
+            self.resync_requested = true;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Throw away the frontend and start again from `patch`, the whole
+    /// state of the backend, after the frontend and backend have stopped
+    /// agreeing. The new frontend keeps our actor ID, and picks up where
+    /// our changes had got to from the patch's clock, so everything else
+    /// carries on as before. Anything typed but not yet sent, and the undo
+    /// history, is lost.
+    pub fn resync(&mut self, patch: amp::Patch) {
+        let _span = tracing::info_span!("resync").entered();
+        self.composer.discard();
+        self.coalescer.discard();
+        let mut frontend = Frontend::new();
+        frontend.actor_id = self.frontend.borrow().actor_id.clone();
+        let mut index = HistoryIndex::default();
+        index.apply_patch(&patch);
+        let mut chat = chat::ChatLog::default();
+        chat.apply_patch(&patch);
+        let touched = subscriptions::touched_paths(&patch);
+        if let Err(e) = frontend.apply_patch(patch) {
+            // Nothing more we can do, the tab stays as it is
+            tracing::error!("Could not resync from the backend: {:?}", e);
+            return;
+        }
+        *self.frontend.borrow_mut() = frontend;
+        *self.index.borrow_mut() = index;
+        self.chat = chat;
+        *self.undo.borrow_mut() = UndoStack::default();
+        self.sender.reset();
+        self.pastes.clear();
+        self.buffer_sync.reset();
+        self.desynced.set(false);
+        self.resync_requested = false;
+        self.title_conflicts.clear();
+        self.subscriptions.notify(&touched, &self.frontend.borrow());
+        self.schedule_indexing();
+        self.refresh_text();
+        let (marks, _) = marks::marks(&self.frontend.borrow());
+        marks::render(&self.buffer, &marks, self.index.borrow().offsets());
+        self.update_search();
+        self.update_suggestions();
+        self.update_blame();
+        self.update_preview();
+    }
+
+    /// What the local user is doing in this tab, for the presence state
+    /// other instances are sent
+    pub fn activity(&self) -> presence::Activity {
+        let selection = self.buffer.get_selection_bounds()
+            .map(|(start, end)| (start.get_offset() as usize, end.get_offset() as usize));
+        presence::Activity {
+            cursor: self.buffer.get_property_cursor_position() as usize,
+            selection,
+            typing: self.sender.typing(),
+            viewport: self.viewport,
+        }
+    }
+
+    pub fn set_viewport(&mut self, first: usize, last: usize) {
+        self.viewport = Some((first, last));
+    }
+
+    /// Show or hide the blame gutter
+    pub fn toggle_blame(&mut self) {
+        self.show_blame = !self.show_blame;
+        self.update_blame();
+    }
+
+    /// Recompute the blame gutter, if it's showing
+    fn update_blame(&self) {
+        if !self.show_blame {
+            return;
+        }
+        let text = blame::gutter_text(
+            &self.display.text(),
+            self.index.borrow().offsets(),
+            &self.change_log.borrow(),
+            &self.actor_id(),
+        );
+        self.blame_buffer.set_text(&text);
+    }
+
+    /// Show or hide the Markdown preview
+    pub fn toggle_preview(&mut self) {
+        self.show_preview = !self.show_preview;
+        self.update_preview();
+    }
+
+    /// The text rendered as Markdown, in Pango markup
+    pub fn preview_markup(&self) -> &str {
+        self.preview.markup()
+    }
+
+    /// Render the Markdown preview again, if it's showing
+    fn update_preview(&mut self) {
+        if self.show_preview {
+            self.preview.update(&self.display.text());
+        }
+    }
+
+    /// Replace the contents of the text buffer with the text in the frontend
+    fn refresh_text(&self) {
+        // We have to block these signals otherwise the handlers will fire
+        // as we update the text, which will cause a loop
+        self.buffer.block_signal(&self.insert_text_sigid);
+        self.buffer.block_signal(&self.del_sig_id);
+        let text: Vec<char> = text_value(&self.frontend.borrow()).chars().collect();
+        // Not `set_text`, which would have the buffer highlight and
+        // spellcheck everything again
+        let changed = text_patch::replace_changed(&self.buffer, &text);
+        self.buffer.unblock_signal(&self.insert_text_sigid);
+        self.buffer.unblock_signal(&self.del_sig_id);
+        self.spellcheck.check_ranges(&self.buffer, &[changed]);
+    }
+
+    /// Bring the text buffer up to date with a patch, `acknowledged` if
+    /// it's one of ours, by making the same edits to it rather than
+    /// replacing the whole text, see `text_patch.rs`. No `edits` if the
+    /// patch didn't change the text.
+    fn update_text(&self, edits: Option<TextEdits>, acknowledged: bool) {
+        self.buffer.block_signal(&self.insert_text_sigid);
+        self.buffer.block_signal(&self.del_sig_id);
+        let frontend = &self.frontend;
+        let text = || text_value(&frontend.borrow()).chars().collect();
+        let touched = self.buffer_sync.patched(&self.buffer, edits, acknowledged, self.sender.pending(), text);
+        self.buffer.unblock_signal(&self.insert_text_sigid);
+        self.buffer.unblock_signal(&self.del_sig_id);
+        self.spellcheck.check_ranges(&self.buffer, &touched);
+    }
+
+    pub fn language(&self) -> Option<&str> {
+        self.language.as_deref()
+    }
+
+    /// Highlight the text as the language with id `id`, or as plain text
+    pub fn set_language(&mut self, id: Option<String>) {
+        syntax::set_language(&self.buffer, id.as_deref());
+        self.language = id;
+    }
+
+    pub fn dark(&self) -> bool {
+        self.dark
+    }
+
+    /// Switch the text's colors to the dark theme's or the light one's
+    pub fn set_dark(&mut self, dark: bool) {
+        self.dark = dark;
+        theme::apply(&self.buffer, dark);
+    }
+
+    /// Batch up keystrokes for `window` from now on, or stop batching them.
+    /// Anything already held back is made into a change first.
+    pub fn set_coalesce(&self, window: Option<Duration>) {
+        self.coalescer.set_window(window);
+    }
+
+    pub fn spellcheck_available(&self) -> bool {
+        self.spellcheck.available()
+    }
+
+    pub fn spellcheck_enabled(&self) -> bool {
+        self.spellcheck.enabled()
+    }
+
+    /// Turn spellchecking on or off
+    pub fn toggle_spellcheck(&mut self) {
+        let enabled = !self.spellcheck.enabled();
+        self.spellcheck.set_enabled(&self.buffer, enabled);
+    }
+
+    /// Toggle a formatting mark over the current selection
+    pub fn toggle_mark(&mut self, mark_type: marks::MarkType) {
+        if self.read_only() {
+            return;
+        }
+        self.flush();
+        let (start, end) = match self.buffer.get_selection_bounds() {
+            Some(bounds) => bounds,
+            None => return,
+        };
+        let cr = marks::toggle(
+            &mut self.frontend.borrow_mut(),
+            self.index.borrow().offsets(),
+            mark_type,
+            start.get_offset() as usize,
+            end.get_offset() as usize,
+        );
+        self.send_change(cr);
+    }
+
+    /// The document title
+    pub fn title(&self) -> String {
+        title::title(&self.frontend.borrow())
+    }
+
+    pub fn set_title(&mut self, new_title: String) {
+        if self.read_only() {
+            return;
+        }
+        let old_title = self.title();
+        if new_title == old_title && self.title_conflicts.is_empty() {
+            return;
+        }
+        let cr = title::set(&mut self.frontend.borrow_mut(), new_title.clone());
+        self.title_conflicts.clear();
+        if cr.is_some() {
+            self.undo.borrow_mut().record(undo::title_changed(&old_title, &new_title));
+        }
+        self.send_change(cr);
+    }
+
+    /// The whole document in `format`, for File > Export
+    pub fn export(&self, format: export::Format) -> String {
+        export::export(&self.frontend.borrow(), format)
+    }
+
+    /// Write the whole state of the document to `path` as JSON
+    pub fn save_snapshot(&self, path: &std::path::Path) -> std::io::Result<()> {
+        snapshot::save(path, &mut self.frontend.borrow_mut())
+    }
+
+    /// Set each key of the root to the values from a snapshot, as a single
+    /// change. Used on an empty document, so there aren't any other keys.
+    pub fn load_snapshot(&mut self, root: std::collections::HashMap<String, Value>) {
+        let changes = root.into_iter().map(|(key, value)| LocalChange::set(Path::root().key(key), value)).collect();
+        self.apply_local_changes(changes, "Load a snapshot".to_string());
+    }
+
+    /// The current state of the checklist
+    pub fn todos(&self) -> Vec<checklist::Todo> {
+        checklist::todos(&self.frontend.borrow())
+    }
+
+    /// Apply a checklist action locally and send it to the backend
+    pub fn todo_action(&mut self, action: checklist::TodoAction) {
+        if self.read_only() {
+            return;
+        }
+        let cr = checklist::apply(&mut self.frontend.borrow_mut(), action);
+        self.send_change(cr);
+    }
+
+    /// The chat, oldest message first
+    pub fn chat_messages(&self) -> &[chat::ChatMessage] {
+        self.chat.messages()
+    }
+
+    /// Send a chat message from `author`
+    pub fn send_chat(&mut self, author: &str, body: &str) {
+        if self.read_only() {
+            return;
+        }
+        let cr = chat::send(&mut self.frontend.borrow_mut(), author, body);
+        self.send_change(cr);
+    }
+
+    /// Whether the Text tab has a counter, which it does unless the schema
+    /// leaves it out
+    pub fn has_counter(&self) -> bool {
+        self.schema.has_counter()
+    }
+
+    /// The fields from the schema shown in the Fields tab, and their values
+    pub fn fields(&self) -> Vec<(String, FieldValue)> {
+        self.schema.values(&self.frontend.borrow())
+    }
+
+    /// Apply a Fields tab action locally and send it to the backend
+    pub fn field_action(&mut self, action: FieldAction) {
+        if self.read_only() {
+            return;
+        }
+        let cr = self.schema.apply(&mut self.frontend.borrow_mut(), action);
+        self.send_change(cr);
+    }
+
+    /// The current state of the table
+    pub fn table(&self) -> table::Table {
+        table::table(&self.frontend.borrow())
+    }
+
+    /// Apply a table action locally and send it to the backend
+    pub fn table_action(&mut self, action: table::TableAction) {
+        if self.read_only() {
+            return;
+        }
+        let cr = table::apply(&mut self.frontend.borrow_mut(), action);
+        self.send_change(cr);
+    }
+
+    /// The current state of the kanban board
+    pub fn kanban(&self) -> Vec<kanban::Column> {
+        kanban::columns(&self.frontend.borrow())
+    }
+
+    /// Apply a kanban action locally and send it to the backend
+    pub fn kanban_action(&mut self, action: kanban::KanbanAction) {
+        if self.read_only() {
+            return;
+        }
+        let cr = kanban::apply(&mut self.frontend.borrow_mut(), action);
+        self.send_change(cr);
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.read_only() && self.undo.borrow().can_undo()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.read_only() && self.undo.borrow().can_redo()
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.read_only.get()
+    }
+
+    /// Stop or start making local changes. Remote patches are applied
+    /// either way.
+    pub fn set_read_only(&mut self, read_only: bool) {
+        // Anything typed before we stopped still gets sent
+        self.flush();
+        self.read_only.set(read_only);
+    }
+
+    /// Record this tab's input from now on
+    pub fn set_recorder(&mut self, recorder: Recorder) {
+        *self.recorder.borrow_mut() = Some(recorder);
+    }
+
+    /// Feed recorded input back in as if the user had just done it. Text
+    /// goes in through the buffer, like typing.
+    pub fn play(&mut self, input: &session::Input) {
+        match input {
+            session::Input::Insert{offset, text} => {
+                let mut iter = self.buffer.get_iter_at_offset(*offset as i32);
+                self.buffer.insert(&mut iter, text);
+            }
+            session::Input::Delete{start, end} => {
+                let mut start = self.buffer.get_iter_at_offset(*start as i32);
+                let mut end = self.buffer.get_iter_at_offset(*end as i32);
+                self.buffer.delete(&mut start, &mut end);
+            }
+            session::Input::IncrementCounter => self.inc_counter(),
+            session::Input::DecrementCounter => self.dec_counter(),
+        }
+    }
+
+    /// Undo our most recent local edit
+    pub fn undo(&mut self) {
+        self.undo_steps(1);
+    }
+
+    pub fn redo(&mut self) {
+        self.redo_steps(1);
+    }
+
+    /// Undo our `steps` most recent local edits as a single change
+    pub fn undo_steps(&mut self, steps: usize) {
+        if self.read_only() {
+            return;
+        }
+        let changes = self.undo.borrow_mut().undo_steps(steps);
+        if let Some(changes) = changes {
+            self.apply_local_changes(changes, steps_message("Undo", steps));
+        }
+    }
+
+    pub fn redo_steps(&mut self, steps: usize) {
+        if self.read_only() {
+            return;
+        }
+        let changes = self.undo.borrow_mut().redo_steps(steps);
+        if let Some(changes) = changes {
+            self.apply_local_changes(changes, steps_message("Redo", steps));
+        }
+    }
+
+    /// What undo and redo would do, with the remote changes from the patch
+    /// log in between, for View > Undo History
+    pub fn undo_history(&self) -> Vec<undo::HistoryRow> {
+        let own_actor = self.actor_id();
+        let remote = self.patch_log.entries()
+            .filter(|e| e.actor.as_ref() != Some(&own_actor))
+            .filter_map(|e| {
+                let change = e.change.as_ref()?;
+                let what = change.message.clone().unwrap_or_else(|| e.keys.join(", "));
+                Some((format!("{}: {}", change.short_actor(), what), change.time))
+            })
+            .collect();
+        undo::history(&self.undo.borrow(), remote)
+    }
+
+    /// Make `changes` as a single change with `message` and update the text
+    /// buffer to match. Used for changes which don't come from editing the
+    /// buffer. Returns whether the changes could be made.
+    fn apply_local_changes(&mut self, changes: Vec<LocalChange>, message: String) -> bool {
+        self.flush();
+        let result = self.frontend.borrow_mut().change(Some(message), |doc| {
+            for change in &changes {
+                doc.add_change(change.clone())?;
+            }
+            Ok(())
+        });
+        let applied = match result {
+            Ok(cr) => {
+                self.send_change(cr);
+                true
+            }
+            // This happens if a remote edit has removed what we were undoing
+            Err(e) => {
+                tracing::warn!("Could not apply local changes: {:?}", e);
+                false
+            }
+        };
+        self.refresh_text();
+        self.update_search();
+        self.update_suggestions();
+        self.update_blame();
+        self.update_preview();
+        applied
+    }
+
+    /// Make the changes inserting an imported file as a single change.
+    /// Imports aren't recorded for undo, like opening a document they're
+    /// too big to be worth keeping the reverse of.
+    pub fn import(&mut self, changes: Vec<LocalChange>, message: String) -> bool {
+        if self.read_only() {
+            return false;
+        }
+        self.apply_local_changes(changes, message)
+    }
+
+    /// Send anything typed which is still being batched up
+    pub fn flush(&self) {
+        self.composer.finish();
+        self.coalescer.flush();
+    }
+
+    /// For the views to say when their input method is composing
+    pub fn composer(&self) -> &Composer {
+        &self.composer
+    }
+
+    /// Copy the selection, with its marks, see `clipboard.rs`
+    pub fn copy(&self) {
+        if let Some(clip) = self.selection_clip() {
+            clipboard::put(&clip);
+        }
+    }
+
+    /// Copy the selection and delete it, unless the tab is read only,
+    /// where it's only copied
+    pub fn cut(&mut self) {
+        let clip = match self.selection_clip() {
+            Some(clip) => clip,
+            None => return,
+        };
+        clipboard::put(&clip);
+        if self.read_only() {
+            return;
+        }
+        let (start, end) = self.selection();
+        match self.splice(vec![Splice::new(start..end, "")], "Cut".to_string()) {
+            Ok(carets) => self.buffer.place_cursor(&self.buffer.get_iter_at_offset(carets[0] as i32)),
+            Err(e) => tracing::warn!("Could not cut: {}", e),
+        }
+    }
+
+    /// Replace the selection with `clip`, or insert it at the cursor, and
+    /// add its marks once the backend has said which ops inserted its text
+    pub fn paste(&mut self, clip: Clip) {
+        if clip.text.is_empty() || self.read_only() {
+            return;
+        }
+        let (start, end) = self.selection();
+        match self.splice(vec![Splice::new(start..end, &clip.text)], "Paste".to_string()) {
+            Ok(carets) => {
+                self.pastes.sent(self.sender.pending(), clip.marks);
+                self.buffer.place_cursor(&self.buffer.get_iter_at_offset(carets[0] as i32));
+            }
+            Err(e) => tracing::warn!("Could not paste: {}", e),
+        }
+    }
+
+    /// The chars selected, or where the cursor is if there's no selection
+    fn selection(&self) -> (usize, usize) {
+        match self.buffer.get_selection_bounds() {
+            Some((start, end)) => (start.get_offset() as usize, end.get_offset() as usize),
+            None => (self.cursor_offset(), self.cursor_offset()),
+        }
+    }
+
+    /// The selection as a clip, with the marks which resolve over it. The
+    /// selection is in the buffer, which has everything typed, while the
+    /// marks resolve in the index, which only has what the backend has
+    /// answered, so what's been typed is sent and the marks are moved past
+    /// whatever of it is still unanswered.
+    fn selection_clip(&self) -> Option<Clip> {
+        self.flush();
+        let (start, end) = self.selection();
+        if start == end {
+            return None;
+        }
+        let shown: Vec<char> = self.display.text().chars().collect();
+        let text = shown.iter().skip(start).take(end - start).collect();
+        let (marks, _) = marks::marks(&self.frontend.borrow());
+        let index = self.index.borrow();
+        let answered: Vec<char> = index.offsets().chars().collect();
+        let (replaced, with) = changed(&answered, &shown);
+        let resolved = marks.iter().filter_map(|mark| {
+            let (from, to) = mark.resolve(index.offsets())?;
+            Some((mark.mark_type, moved(from, &replaced, &with)..moved(to, &replaced, &with)))
+        });
+        Some(Clip::new(text, start..end, resolved))
+    }
+
+    /// The char offset of the cursor
+    pub fn cursor_offset(&self) -> usize {
+        self.buffer.get_iter_at_mark(&self.buffer.get_insert().unwrap()).get_offset() as usize
+    }
+
+    /// Put the cursor at char `offset` when the first patch has filled in
+    /// the text, for a tab restored from the workspace
+    pub fn restore_cursor(&mut self, offset: usize) {
+        self.restore_cursor = Some(offset);
+    }
+
+    pub fn suggesting(&self) -> bool {
+        self.suggesting_since.is_some()
+    }
+
+    /// Start suggesting, once what's been typed so far has gone into the
+    /// document
+    pub fn start_suggesting(&mut self) {
+        self.flush();
+        self.suggesting_since = Some(self.change_log.borrow().latest_seq(&self.actor_id()));
+        self.update_suggestions();
+    }
+
+    /// Stop suggesting. What's been typed but not sent yet goes with the
+    /// suggestions if they're accepted, and is thrown away with them if
+    /// not, along with what we knew about the rejected changes.
+    pub fn stop_suggesting(&mut self, accepted: bool) {
+        if let Some(since) = self.suggesting_since.take() {
+            if accepted {
+                self.flush();
+            } else {
+                self.composer.discard();
+                self.coalescer.discard();
+                self.change_log.borrow_mut().forget_after(&self.actor_id(), since);
+            }
+        }
+        self.update_suggestions();
+    }
+
+    /// How many changes we've suggested
+    pub fn suggestion_count(&self) -> u64 {
+        match self.suggesting_since {
+            Some(since) => self.change_log.borrow().latest_seq(&self.actor_id()).saturating_sub(since),
+            None => 0,
+        }
+    }
+
+    fn update_suggestions(&self) {
+        suggestion::highlight(&self.buffer, self.index.borrow().offsets(), &self.change_log.borrow(), &self.actor_id(), self.suggesting_since);
+    }
+
+    /// Show or hide the find bar, clearing the highlights when hiding it
+    pub fn toggle_find(&mut self) {
+        self.show_find = !self.show_find;
+        if !self.show_find {
+            self.search(String::new());
+        }
+    }
+
+    /// Search for `query` and highlight every match
+    pub fn search(&mut self, query: String) {
+        self.search_query = query;
+        self.update_search();
+    }
+
+    /// How many matches there are for the current search
+    pub fn search_match_count(&self) -> usize {
+        self.search_matches.len()
+    }
+
+    /// Find and highlight the matches again after the text has changed
+    fn update_search(&mut self) {
+        self.search_matches = self.display.find(&self.search_query);
+        find::highlight(&self.buffer, &self.search_matches, self.search_query.chars().count());
+    }
+
+    /// The offset of the match which is currently selected, if any
+    fn selected_match(&self) -> Option<usize> {
+        let (start, end) = self.buffer.get_selection_bounds()?;
+        let start = start.get_offset() as usize;
+        let len = (end.get_offset() as usize) - start;
+        if len == self.search_query.chars().count() && self.search_matches.contains(&start) {
+            Some(start)
+        } else {
+            None
+        }
+    }
+
+    /// Replace the selected match with `replacement` and select the next one.
+    /// If no match is selected this just selects the next one.
+    pub fn replace(&mut self, replacement: &str) {
+        if let Some(start) = self.selected_match() {
+            if let Some(carets) = self.replace_at(&[start], replacement) {
+                // Carry on searching from just after the replacement
+                self.buffer.place_cursor(&self.buffer.get_iter_at_offset(carets[0] as i32));
+            }
+        }
+        self.find_next();
+    }
+
+    /// Replace every match with `replacement`, as a single change
+    pub fn replace_all(&mut self, replacement: &str) {
+        let starts = self.search_matches.clone();
+        self.replace_at(&starts, replacement);
+    }
+
+    fn replace_at(&mut self, starts: &[usize], replacement: &str) -> Option<Vec<usize>> {
+        if starts.is_empty() || self.read_only() {
+            return None;
+        }
+        let query = self.search_query.clone();
+        let len = query.chars().count();
+        let message = match starts.len() {
+            1 => format!("Replace \"{}\" with \"{}\"", query, replacement),
+            n => format!("Replace {} matches of \"{}\" with \"{}\"", n, query, replacement),
+        };
+        let splices = starts.iter().map(|start| Splice::new(*start..start + len, replacement)).collect();
+        match self.splice(splices, message) {
+            Ok(carets) => Some(carets),
+            Err(e) => {
+                tracing::warn!("Could not replace the matches: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Make `splices`, each in terms of the text as it is now, as one change
+    /// and one step to undo, see `splice.rs`. Returns where each one's caret
+    /// ends up, in the order they go in the text.
+    pub fn splice(&mut self, splices: Vec<Splice>, message: String) -> Result<Vec<usize>, String> {
+        if self.read_only() {
+            return Err("the tab is read only".to_string());
+        }
+        // What's shown is what the offsets are in, once the coalescer has
+        // sent it
+        self.flush();
+        let old: Vec<char> = self.display.text().chars().collect();
+        let splices = splice::arrange(splices, old.len())?;
+        if splices.is_empty() {
+            return Ok(Vec::new());
+        }
+        let entry = undo::text_spliced(&splices, &old, message.clone());
+        if !self.apply_local_changes(splice::changes(&splices), message) {
+            return Err("the changes didn't apply".to_string());
+        }
+        self.undo.borrow_mut().record(entry);
+        Ok(splice::carets(&splices))
+    }
+
+    /// Select the next match after the cursor, wrapping around to the start
+    /// of the text. Returns whether there was a match.
+    pub fn find_next(&self) -> bool {
+        // Start just after the current match, if there is one, so searching
+        // again moves on to the next match
+        let from = match self.buffer.get_selection_bounds() {
+            Some((start, _)) => start.get_offset() as usize + 1,
+            None => self.cursor_offset(),
+        };
+        let found = self.search_matches.iter()
+            .find(|m| **m >= from)
+            .or_else(|| self.search_matches.first());
+        match found {
+            Some(start) => {
+                let start_iter = self.buffer.get_iter_at_offset(*start as i32);
+                let end_iter = self.buffer.get_iter_at_offset((start + self.search_query.chars().count()) as i32);
+                self.buffer.select_range(&start_iter, &end_iter);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Who wrote each place the find bar's query appears, if it's a single
+    /// word, see `find::history_matches`. Whatever hasn't been indexed
+    /// since it was edited is indexed first, so the list is never behind
+    /// the text.
+    pub fn history_matches(&self) -> Vec<find::HistoryMatch> {
+        let word = self.search_query.trim();
+        if word.is_empty() || !word.chars().all(char::is_alphanumeric) {
+            return Vec::new();
+        }
+        let mut index = self.index.borrow_mut();
+        index.index_all();
+        find::history_matches(&index, &self.change_log.borrow(), word, &self.actor_id())
+    }
+
+    /// Select the match of the find bar's query at `start`, from the Who
+    /// Wrote It list
+    pub fn select_match(&self, start: usize) {
+        let end = start + self.search_query.trim().chars().count();
+        self.buffer.select_range(&self.buffer.get_iter_at_offset(start as i32), &self.buffer.get_iter_at_offset(end as i32));
+    }
+
+    /// What the views' tooltips say who wrote the char under the pointer
+    /// with
+    pub fn provenance(&self) -> Provenance {
+        Provenance::new(self.index.clone(), self.change_log.clone(), self.actor_id())
+    }
+
+    pub fn text_stats(&self) -> TextStats {
+        self.stats.get()
+    }
+
+    /// Call `callback` with the frontend after every patch which touches
+    /// `path`, a path of map keys from the root. See `subscriptions.rs`.
+    pub fn subscribe(&mut self, path: &[&str], callback: subscriptions::Callback) {
+        self.subscriptions.subscribe(path, callback);
+    }
+
+    /// How many of our change requests the backend hasn't acknowledged yet
+    pub fn pending_changes(&self) -> usize {
+        self.sender.pending()
+    }
+
+    /// How many change requests are waiting in the channel to the backend
+    pub fn queue_depth(&self) -> usize {
+        self.sender.queue_depth()
+    }
+
+    /// How long the backend took to acknowledge our most recent change
+    pub fn last_latency(&self) -> Option<Duration> {
+        self.last_latency
+    }
+
+    /// The heads of the document as of the last patch, sorted
+    pub fn heads(&self) -> &[String] {
+        &self.heads
+    }
+
+    /// A short hash of the heads of the document. Two tabs showing the
+    /// same hash have seen exactly the same changes.
+    pub fn heads_hash(&self) -> String {
+        let mut hasher = DefaultHasher::new();
+        self.heads.hash(&mut hasher);
+        format!("{:08x}", hasher.finish() as u32)
+    }
+
+    /// The actor ID of our frontend
+    pub fn actor_id(&self) -> String {
+        self.frontend.borrow().actor_id.to_string()
+    }
+
+    /// Send a change request produced by the frontend to the backend
+    fn send_change(&self, cr: Option<amp::Request>) {
+        if let Some(cr) = cr {
+            self.sender.send(cr);
+        }
+    }
+
+    /// (Re)start the timer which brings the token index up to date once
+    /// edits have settled, so that a burst of typing is only indexed once.
+    /// It's done a chunk at a time while the main loop is idle, so a resync
+    /// of a long document doesn't hold up the window either.
+    fn schedule_indexing(&self) {
+        if let Some(source) = self.index_source.borrow_mut().take() {
+            glib::source_remove(source);
+        }
+        if !self.index.borrow().needs_indexing() {
+            return;
+        }
+        let index = self.index.clone();
+        let index_source = self.index_source.clone();
+        let source = glib::timeout_add_local(INDEX_SETTLE_MS, move || {
+            let index = index.clone();
+            let indexing = index_source.clone();
+            let idle = glib::idle_add_local(move || {
+                let more = index.borrow_mut().index_step();
+                if !more {
+                    indexing.borrow_mut().take();
+                }
+                glib::Continue(more)
+            });
+            *index_source.borrow_mut() = Some(idle);
+            glib::Continue(false)
+        });
+        *self.index_source.borrow_mut() = Some(source);
+    }
+
+    /// The text as the frontend has it
+    pub fn text(&self) -> String {
+        text_value(&self.frontend.borrow())
+    }
+
+    pub fn growth(&self) -> Rc<RefCell<Growth>> {
+        self.growth.clone()
+    }
+
+    /// Roughly how many bytes the frontend's copy of the document takes up,
+    /// see `size.rs`
+    pub fn frontend_size(&self) -> usize {
+        self.frontend.borrow().get_value(&Path::root()).map_or(0, |value| size::estimate(&value))
+    }
+
+    /// Get the value of the counter
+    pub fn counter_value(&self) -> i64 {
+        self.counter.get()
+    }
+
+    /// Increment the counter value locally and send the corresponding
+    /// change to the backend
+    pub fn inc_counter(&mut self) -> () {
+        self.add_to_counter(1);
+    }
+
+    /// Decrement the counter, as `inc_counter`
+    pub fn dec_counter(&mut self) {
+        self.add_to_counter(-1);
+    }
+
+    fn add_to_counter(&mut self, by: i64) {
+        if self.read_only() || !self.has_counter() {
+            return;
+        }
+        if let Some(recorder) = self.recorder.borrow().as_ref() {
+            recorder.record(if by > 0 { session::Input::IncrementCounter } else { session::Input::DecrementCounter });
+        }
+        let description = if by > 0 { "Increment the counter" } else { "Decrement the counter" };
+        let cr = state::update(&mut self.frontend.borrow_mut(), description, |s: &mut DocState| s.counts.0 += by);
+        // The frontend has already applied the increment, the patch for it
+        // will arrive later
+        self.counter.set(counter_value(&self.frontend.borrow()));
+        self.send_change(cr);
+    }
+}
+
+/// The message for undoing or redoing `steps` edits at once
+fn steps_message(action: &str, steps: usize) -> String {
+    if steps == 1 {
+        action.to_string()
+    } else {
+        format!("{} {} edits", action, steps)
+    }
+}
+
+/// Where char `offset` of a text is once the chars `replaced` have been
+/// replaced by the chars `with`, see `changed`. An offset in what was
+/// replaced stays as far into what replaced it as it can.
+fn moved(offset: usize, replaced: &Range<usize>, with: &Range<usize>) -> usize {
+    if offset <= replaced.start {
+        offset
+    } else if offset >= replaced.end {
+        offset - replaced.end + with.end
+    } else {
+        (with.start + offset - replaced.start).min(with.end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    //! The property everything else rests on: whatever order our typing,
+    //! the backend's answers and someone else's changes come in, the buffer
+    //! says what the frontend says. The tab here is a whole `Doc`, typed
+    //! into through its buffer, so the keystrokes go through its handlers,
+    //! composer and coalescer, and it's sent patches the way the backend
+    //! thread sends them, by a backend the test answers its requests with.
+
+    use automerge_backend::{Backend, Change};
+    use automerge_protocol as amp;
+    use proptest::prelude::*;
+    use proptest::test_runner::{Config, TestCaseError, TestRunner};
+    use std::sync::Arc;
+    use vgtk::lib::gtk::{self, prelude::*};
+
+    use super::{deleted, inserted, text_value, Doc};
+    use crate::backend::BackendHandle;
+    use crate::metrics::Metrics;
+    use crate::presence::PresenceEvent;
+    use crate::schema::Schema;
+    use crate::simulate::Peer;
+
+    /// Positions are taken modulo the length of the text when the op runs
+    #[derive(Clone, Debug)]
+    enum Op {
+        Type(usize, String),
+        Delete(usize, usize),
+        /// The backend answering our oldest change request
+        Acknowledge,
+        OtherTypes(usize, String),
+        OtherDeletes(usize),
+        /// Everything the other peer has made arriving
+        Receive,
+        /// Everything we've made arriving at the other peer
+        Send,
+    }
+
+    fn text() -> impl Strategy<Value = String> {
+        // Multibyte characters, one outside the BMP and a combining accent,
+        // so byte offsets and char offsets are never the same for long
+        let chars = prop::sample::select(vec!['a', 'b', ' ', '\n', 'é', '中', '😀', '\u{301}']);
+        prop::collection::vec(chars, 1..4).prop_map(|chars| chars.into_iter().collect())
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        prop_oneof![
+            3 => (any::<usize>(), text()).prop_map(|(at, text)| Op::Type(at, text)),
+            2 => (any::<usize>(), 1..4usize).prop_map(|(at, len)| Op::Delete(at, len)),
+            2 => Just(Op::Acknowledge),
+            2 => (any::<usize>(), text()).prop_map(|(at, text)| Op::OtherTypes(at, text)),
+            1 => any::<usize>().prop_map(Op::OtherDeletes),
+            2 => Just(Op::Receive),
+            1 => Just(Op::Send),
+        ]
+    }
+
+    struct Tab {
+        doc: Doc,
+        backend: Backend,
+        /// The doc's change requests the backend has yet to answer, oldest
+        /// first
+        requests: crossbeam::Receiver<amp::Request>,
+        _presence: crossbeam::Receiver<PresenceEvent>,
+    }
+
+    impl Tab {
+        fn new() -> Tab {
+            let (sx, requests) = crossbeam::channel::unbounded();
+            let (presence_sx, presence) = crossbeam::channel::unbounded();
+            let doc = Doc::new(sx, presence_sx, Arc::new(Metrics::default()), false, None, Arc::new(Schema::default()));
+            Tab { doc, backend: Backend::init(), requests, _presence: presence }
+        }
+
+        fn buffer_text(&self) -> String {
+            let (start, end) = self.doc.buffer.get_bounds();
+            self.doc.buffer.get_text(&start, &end, true).map(|t| t.to_string()).unwrap_or_default()
+        }
+
+        fn frontend_text(&self) -> String {
+            text_value(&self.doc.frontend.borrow())
+        }
+
+        fn len(&self) -> usize {
+            self.doc.buffer.get_char_count().max(0) as usize
+        }
+
+        fn type_text(&mut self, at: usize, text: &str) {
+            let at = at % (self.len() + 1);
+            self.doc.buffer.insert(&mut self.doc.buffer.get_iter_at_offset(at as i32), text);
+        }
+
+        fn delete(&mut self, at: usize, len: usize) {
+            if self.len() == 0 {
+                return;
+            }
+            let start = at % self.len();
+            let end = (start + len).min(self.len());
+            let buffer = &self.doc.buffer;
+            buffer.delete(&mut buffer.get_iter_at_offset(start as i32), &mut buffer.get_iter_at_offset(end as i32));
+        }
+
+        /// Answer the oldest request, returning the changes it made
+        fn acknowledge(&mut self) -> Vec<Change> {
+            let request = match self.requests.try_recv() {
+                Ok(request) => request,
+                Err(_) => return Vec::new(),
+            };
+            let (patch, changes) = self.backend.apply_local_change_and_get(request).unwrap();
+            self.doc.apply_patches(vec![(patch, Vec::new())]);
+            changes
+        }
+
+        fn receive(&mut self, changes: Vec<Change>) {
+            let patch = BackendHandle::apply_changes(&mut self.backend, changes).unwrap();
+            self.doc.apply_patches(vec![(patch, Vec::new())]);
+        }
+    }
+
+    fn check(ops: Vec<Op>) -> Result<(), TestCaseError> {
+        let (mut other, history) = Peer::creating().unwrap();
+        let mut tab = Tab::new();
+        tab.receive(history);
+        // What each side has made that the other has yet to see
+        let (mut ours, mut theirs) = (Vec::new(), Vec::new());
+        for op in ops {
+            match op.clone() {
+                Op::Type(at, text) => tab.type_text(at, &text),
+                Op::Delete(at, len) => tab.delete(at, len),
+                Op::Acknowledge => ours.extend(tab.acknowledge()),
+                Op::OtherTypes(at, text) => {
+                    let at = at % (other.text().chars().count() + 1);
+                    theirs.extend(other.edit(inserted(at, &text)).unwrap());
+                }
+                Op::OtherDeletes(at) => {
+                    let len = other.text().chars().count();
+                    if len > 0 {
+                        theirs.extend(other.edit(deleted(at % len, at % len + 1)).unwrap());
+                    }
+                }
+                Op::Receive => tab.receive(std::mem::take(&mut theirs)),
+                Op::Send => other.receive(std::mem::take(&mut ours)).unwrap(),
+            }
+            prop_assert!(!tab.doc.wants_resync(), "desynced after {:?}", op);
+            prop_assert_eq!(tab.buffer_text(), tab.frontend_text(), "after {:?}", op);
+        }
+        // And once everything has been delivered everyone agrees
+        while tab.doc.pending_changes() > 0 {
+            ours.extend(tab.acknowledge());
+        }
+        other.receive(ours).unwrap();
+        tab.receive(theirs);
+        prop_assert_eq!(tab.buffer_text(), tab.frontend_text());
+        prop_assert_eq!(tab.buffer_text(), other.text());
+        Ok(())
+    }
+
+    #[test]
+    #[ignore = "needs a display, run with `xvfb-run cargo test -- --ignored`"]
+    fn buffer_follows_the_frontend() {
+        // GTK has to be initialized on the thread which uses the buffer,
+        // which is why this is one test running the cases itself
+        gtk::init().expect("GTK needs a display");
+        let mut runner = TestRunner::new(Config { cases: 128, ..Config::default() });
+        runner.run(&prop::collection::vec(op(), 1..80), check).unwrap();
+    }
+}
@@ -0,0 +1,1076 @@
+//! The tab for one document: a bar with who is editing and the document
+//! title, and a notebook with a tab for each part of the document. The window
+//! around the tabs, with the menus, belongs to the model in `main.rs`.
+
+use vgtk::ext::*;
+use vgtk::lib::gtk::*;
+use vgtk::lib::gdk;
+use vgtk::lib::glib::{self, Cast, IsA, ObjectExt, StaticType};
+use vgtk::{gtk, Component, UpdateAction, VNode, Callback};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::sync::Arc;
+#[cfg(feature = "sourceview")]
+use sourceview::View as SourceView;
+#[cfg(feature = "sourceview")]
+use sourceview::prelude::*;
+
+use crate::doc::Doc;
+use crate::metrics::{self, Metrics};
+use crate::schema::{FieldAction, FieldValue};
+use crate::signing::Signatures;
+use crate::{checklist, clipboard, convergence, discovery, kanban, marks, presence, syntax, table, undo, ws};
+
+/// The height of the history growth chart
+const GROWTH_CHART_HEIGHT: i32 = 80;
+
+#[derive(Default)]
+pub struct DocView {
+    doc: Option<Rc<RefCell<Doc>>>,
+    peers: Vec<discovery::Peer>,
+    connections: BTreeMap<SocketAddr, ws::ConnectionStatus>,
+    rejected: Vec<(SocketAddr, String)>,
+    p2p_document: Option<String>,
+    presence: presence::PresenceMap,
+    metrics: Option<Arc<Metrics>>,
+    signatures: Option<Arc<Signatures>>,
+    on_connect: Callback<SocketAddr>,
+    on_identity: Callback<presence::Identity>,
+    on_resolve: Callback<bool>,
+    /// The contents of the find bar's replace entry
+    replacement: String,
+}
+
+#[derive(Debug, Clone)]
+pub enum DocMessage {
+    Inc,
+    ToggleMark(marks::MarkType),
+    Todo(checklist::TodoAction),
+    Table(table::TableAction),
+    Kanban(kanban::KanbanAction),
+    Field(FieldAction),
+    /// For signal handlers which don't need to tell the component anything
+    Noop,
+    SetName(String),
+    SetColor(String),
+    SetTitle(String),
+    Connect(SocketAddr),
+    SetReadOnly(bool),
+    /// Show or hide the find bar
+    Find,
+    Search(String),
+    FindNext,
+    /// Select the match at this offset, from the Who Wrote It list
+    SelectMatch(usize),
+    SetReplacement(String),
+    Replace,
+    ReplaceAll,
+    /// Open or close the chat pane
+    SetShowChat(bool),
+    SendChat(String),
+    /// The first and last lines of the text which are showing
+    Viewport(usize, usize),
+    /// Undo or redo this many edits at once, from the undo history
+    UndoSteps(usize),
+    RedoSteps(usize),
+    /// Accept or reject the changes we've suggested
+    ResolveSuggestions(bool),
+    /// Highlight the text as the language with this id, or as plain text
+    SetLanguage(Option<String>),
+    /// A view's copy, cut or paste, which the doc does instead
+    Clipboard(clipboard::Action),
+    /// What was on the clipboard to paste, once it's arrived
+    Pasted(Option<clipboard::Clip>),
+}
+
+#[derive(Clone, Default)]
+pub struct DocViewProperties {
+    doc: Option<Rc<RefCell<Doc>>>,
+    /// Other instances discovered on the local network
+    peers: Vec<discovery::Peer>,
+    /// The state of the connections we've made to them
+    connections: BTreeMap<SocketAddr, ws::ConnectionStatus>,
+    /// Clients which failed to authenticate with our server, and why
+    rejected: Vec<(SocketAddr, String)>,
+    /// The id of the document we're syncing over libp2p, if we are
+    p2p_document: Option<String>,
+    /// The identities of everyone editing, including ourselves
+    presence: presence::PresenceMap,
+    /// Pipeline metrics shared by every window
+    metrics: Option<Arc<Metrics>>,
+    /// The signatures of changes, if we're checking them
+    signatures: Option<Arc<Signatures>>,
+    on_connect: Callback<SocketAddr>,
+    on_identity: Callback<presence::Identity>,
+    /// Accept, or reject, the suggestions
+    on_resolve: Callback<bool>,
+}
+
+impl DocView {
+    /// Our own identity, if the model has given us one
+    fn identity(&self) -> Option<presence::Identity> {
+        let actor_id = self.doc.as_ref()?.borrow().actor_id();
+        self.presence.get(&actor_id).map(|p| p.identity.clone())
+    }
+
+    /// An entry for the document title, with a warning button listing the
+    /// losing values if there's a conflict
+    fn doc_title_view(&self, doc: &Doc) -> VNode<DocView> {
+        let conflicts = doc.title_conflicts.clone();
+        gtk!{
+            <Box orientation=Orientation::Horizontal spacing=4>
+                <Entry text=doc.title() tooltip_text="Document title" on activate=|entry| {
+                    DocMessage::SetTitle(entry.get_text().map(|t| t.to_string()).unwrap_or_default())
+                } />
+                <MenuButton image="dialog-warning-symbolic" visible={!conflicts.is_empty()} tooltip_text="Conflicting titles">
+                    <Popover>
+                        <Box orientation=Orientation::Vertical spacing=6 border_width=10>
+                            <Label label="This title was also set concurrently to:" />
+                            {
+                                conflicts.into_iter().map(|value| {
+                                    let chosen = value.clone();
+                                    gtk!{
+                                        <Button label=value on clicked=|_| DocMessage::SetTitle(chosen.clone()) />
+                                    }
+                                })
+                            }
+                        </Box>
+                    </Popover>
+                </MenuButton>
+            </Box>
+        }
+    }
+
+    /// A popover for editing our own name and color
+    fn identity_view(&self) -> VNode<DocView> {
+        let identity = self.identity();
+        let name = identity.as_ref().map(|i| i.name.clone()).unwrap_or_default();
+        let rgba = identity.as_ref()
+            .and_then(|i| i.color.parse::<gdk::RGBA>().ok())
+            .unwrap_or(gdk::RGBA::black());
+        gtk!{
+            <Box orientation=Orientation::Vertical spacing=6 border_width=10>
+                <Label label="Name" halign=Align::Start />
+                <Entry text=name on activate=|entry| {
+                    DocMessage::SetName(entry.get_text().map(|t| t.to_string()).unwrap_or_default())
+                } />
+                <Label label="Color" halign=Align::Start />
+                <ColorButton rgba=&rgba on color_set=|button| DocMessage::SetColor(button.get_rgba().to_string()) />
+            </Box>
+        }
+    }
+
+    /// Everyone else who is editing
+    fn presence_view(&self) -> VNode<DocView> {
+        let own = self.doc.as_ref().map(|d| d.borrow().actor_id());
+        let dark = self.doc.as_ref().map_or(false, |d| d.borrow().dark());
+        gtk!{
+            <Box orientation=Orientation::Horizontal spacing=12>
+                {
+                    self.presence.values()
+                        .filter(|p| Some(&p.identity.actor_id) != own.as_ref())
+                        .map(|p| gtk!{
+                            <Label label=p.markup(dark) use_markup=true tooltip_text=p.identity.actor_id.clone() />
+                        })
+                        .collect::<Vec<_>>()
+                        .into_iter()
+                }
+            </Box>
+        }
+    }
+
+    /// The chat pane, which keeps scrolled to the newest message
+    fn chat_view(&self, doc: &Doc) -> VNode<DocView> {
+        let messages = doc.chat_messages().to_vec();
+        gtk!{
+            <Box orientation=Orientation::Vertical spacing=6 width_request=260>
+                <Label label="Chat" />
+                <ScrolledWindow hscrollbar_policy=PolicyType::Never Box::expand=true>
+                    <Box orientation=Orientation::Vertical spacing=6 on size_allocate=|widget, _| {
+                        let window = widget.get_ancestor(ScrolledWindow::static_type())
+                            .and_then(|w| w.downcast::<ScrolledWindow>().ok());
+                        if let Some(adjustment) = window.and_then(|w| w.get_vadjustment()) {
+                            adjustment.set_value(adjustment.get_upper() - adjustment.get_page_size());
+                        }
+                        DocMessage::Noop
+                    }>
+                        {
+                            messages.into_iter().map(|message| gtk!{
+                                <Box orientation=Orientation::Vertical>
+                                    <Label label=format!("{} · {}", message.author, message.time_label()) halign=Align::Start />
+                                    <Label label=message.body.clone() halign=Align::Start line_wrap=true selectable=true xalign=0.0 />
+                                </Box>
+                            })
+                        }
+                    </Box>
+                </ScrolledWindow>
+                <Entry placeholder_text="Send a message" on activate=|entry| {
+                    let body = entry.get_text().map(|t| t.to_string()).unwrap_or_default();
+                    entry.set_text("");
+                    DocMessage::SendChat(body)
+                } />
+            </Box>
+        }
+    }
+
+    fn checklist_view(&self, doc: &Doc) -> VNode<DocView> {
+        use checklist::TodoAction;
+        let todos = doc.todos();
+        let last = todos.len().saturating_sub(1);
+        gtk!{
+            <Box orientation=Orientation::Vertical spacing=6>
+                <Label label="Checklist" />
+                {
+                    todos.into_iter().enumerate().map(move |(i, todo)| gtk!{
+                        <Box orientation=Orientation::Horizontal spacing=6>
+                            <CheckButton active=todo.done on toggled=|_| DocMessage::Todo(TodoAction::Toggle(i)) />
+                            <Entry text=todo.title.clone() Box::expand=true on activate=|entry| {
+                                let title = entry.get_text().map(|t| t.to_string()).unwrap_or_default();
+                                DocMessage::Todo(TodoAction::Rename(i, title))
+                            } />
+                            <Button image="go-up-symbolic" sensitive={i > 0} on clicked=|_| DocMessage::Todo(TodoAction::Move{from: i, to: i - 1}) />
+                            <Button image="go-down-symbolic" sensitive={i < last} on clicked=|_| DocMessage::Todo(TodoAction::Move{from: i, to: i + 1}) />
+                            <Button image="edit-delete-symbolic" on clicked=|_| DocMessage::Todo(TodoAction::Delete(i)) />
+                        </Box>
+                    })
+                }
+                <Entry placeholder_text="Add an item" on activate=|entry| {
+                    let title = entry.get_text().map(|t| t.to_string()).unwrap_or_default();
+                    entry.set_text("");
+                    DocMessage::Todo(TodoAction::Add(title))
+                } />
+            </Box>
+        }
+    }
+
+    fn table_view(&self, doc: &Doc) -> VNode<DocView> {
+        use table::TableAction;
+        let table = doc.table();
+        let cells: Vec<(usize, usize, String)> = (0..table.rows)
+            .flat_map(|row| (0..table.cols).map(move |col| (row, col)))
+            .map(|(row, col)| (row, col, table.cell(row, col).to_string()))
+            .collect();
+        gtk!{
+            <Box orientation=Orientation::Vertical spacing=6>
+                <Grid row_spacing=4 column_spacing=4>
+                    {
+                        cells.into_iter().map(|(row, col, value)| gtk!{
+                            <Entry text=value Grid::left_attach=col as i32 Grid::top_attach=row as i32 on activate=|entry| {
+                                let value = entry.get_text().map(|t| t.to_string()).unwrap_or_default();
+                                DocMessage::Table(TableAction::SetCell{row, col, value})
+                            } />
+                        })
+                    }
+                </Grid>
+                <Box orientation=Orientation::Horizontal spacing=6>
+                    <Button label="Add row" on clicked=|_| DocMessage::Table(TableAction::AddRow) />
+                    <Button label="Add column" on clicked=|_| DocMessage::Table(TableAction::AddColumn) />
+                </Box>
+            </Box>
+        }
+    }
+
+    fn kanban_view(&self, doc: &Doc) -> VNode<DocView> {
+        use kanban::KanbanAction;
+        let columns = doc.kanban();
+        gtk!{
+            <Box orientation=Orientation::Horizontal spacing=12 homogeneous=true>
+                {
+                    columns.into_iter().enumerate().map(|(c, column)| gtk!{
+                        // Each column is a drop target for cards
+                        <Box orientation=Orientation::Vertical spacing=6
+                            on realize=|w| {
+                                w.drag_dest_set(DestDefaults::ALL, &kanban::drag_targets(), gdk::DragAction::MOVE);
+                                DocMessage::Noop
+                            }
+                            on drag_data_received=|_, _, _, _, data, _, _| {
+                                match data.get_text().and_then(|t| kanban::decode_drag(&t)) {
+                                    Some((from_column, index)) => DocMessage::Kanban(KanbanAction::MoveCard{from_column, index, to_column: c}),
+                                    None => DocMessage::Noop,
+                                }
+                            }>
+                            <Label label=column.title.clone() />
+                            {
+                                column.cards.into_iter().enumerate().map(move |(i, card)| gtk!{
+                                    <Box orientation=Orientation::Horizontal spacing=4>
+                                        <Button label=card Box::expand=true
+                                            on realize=|w| {
+                                                w.drag_source_set(gdk::ModifierType::BUTTON1_MASK, &kanban::drag_targets(), gdk::DragAction::MOVE);
+                                                DocMessage::Noop
+                                            }
+                                            on drag_data_get=|_, _, data, _, _| {
+                                                data.set_text(&kanban::encode_drag(c, i));
+                                                DocMessage::Noop
+                                            } />
+                                        <Button image="edit-delete-symbolic" on clicked=|_| DocMessage::Kanban(KanbanAction::DeleteCard{column: c, index: i}) />
+                                    </Box>
+                                })
+                            }
+                            <Entry placeholder_text="Add a card" on activate=|entry| {
+                                let title = entry.get_text().map(|t| t.to_string()).unwrap_or_default();
+                                entry.set_text("");
+                                DocMessage::Kanban(KanbanAction::AddCard{column: c, title})
+                            } />
+                        </Box>
+                    })
+                }
+            </Box>
+        }
+    }
+
+    /// A widget for each field of the schema which isn't on the Text tab
+    fn fields_view(&self, fields: Vec<(String, FieldValue)>) -> VNode<DocView> {
+        gtk!{
+            <Grid row_spacing=6 column_spacing=12 border_width=10>
+                {
+                    fields.into_iter().enumerate().map(|(row, (name, value))| {
+                        let label = name.clone();
+                        let widget = match value {
+                            FieldValue::Counter(count) => {
+                                let field = name.clone();
+                                gtk!{
+                                    <Box orientation=Orientation::Horizontal spacing=6>
+                                        <Label label=count.to_string() />
+                                        <Button image="list-add" on clicked=|_| DocMessage::Field(FieldAction::Increment(field.clone())) />
+                                    </Box>
+                                }
+                            }
+                            FieldValue::Text(text) | FieldValue::String(text) => {
+                                let field = name.clone();
+                                gtk!{
+                                    <Entry text=text hexpand=true on activate=|entry| {
+                                        let value = entry.get_text().map(|t| t.to_string()).unwrap_or_default();
+                                        DocMessage::Field(FieldAction::Set(field.clone(), value))
+                                    } />
+                                }
+                            }
+                            FieldValue::List(items) => {
+                                let field = name.clone();
+                                gtk!{
+                                    <Box orientation=Orientation::Vertical spacing=4>
+                                        {
+                                            items.into_iter().enumerate().map(move |(i, item)| {
+                                                let field = field.clone();
+                                                gtk!{
+                                                    <Box orientation=Orientation::Horizontal spacing=4>
+                                                        <Label label=item Box::expand=true halign=Align::Start />
+                                                        <Button image="edit-delete-symbolic" on clicked=|_| DocMessage::Field(FieldAction::RemoveItem(field.clone(), i)) />
+                                                    </Box>
+                                                }
+                                            })
+                                        }
+                                        <Entry placeholder_text="Add an item" on activate=|entry| {
+                                            let item = entry.get_text().map(|t| t.to_string()).unwrap_or_default();
+                                            entry.set_text("");
+                                            DocMessage::Field(FieldAction::AddItem(name.clone(), item))
+                                        } />
+                                    </Box>
+                                }
+                            }
+                        };
+                        vec![
+                            gtk!{ <Label label=label halign=Align::Start valign=Align::Start Grid::left_attach=0 Grid::top_attach=row as i32 /> },
+                            gtk!{ <Box Grid::left_attach=1 Grid::top_attach=row as i32>{widget}</Box> },
+                        ]
+                    }).flatten()
+                }
+            </Grid>
+        }
+    }
+
+    fn peers_view(&self) -> VNode<DocView> {
+        gtk!{
+            <Box orientation=Orientation::Vertical spacing=6 border_width=10>
+                <Label label="Peers" />
+                {
+                    if self.peers.is_empty() {
+                        vec![gtk!{ <Label label="No peers found" /> }].into_iter()
+                    } else {
+                        self.peers.iter().map(|peer| {
+                            let addr = peer.addr;
+                            let status = self.connections.get(&addr);
+                            let active = status.map(|s| s.is_active()).unwrap_or(false);
+                            gtk!{
+                                <Box orientation=Orientation::Horizontal spacing=10>
+                                    <Label label=peer.name.clone() halign=Align::Start Box::expand=true />
+                                    <Label label=status.map(|s| s.label()).unwrap_or_default() />
+                                    <Button label="Connect" sensitive=!active on clicked=|_| DocMessage::Connect(addr) />
+                                </Box>
+                            }
+                        }).collect::<Vec<_>>().into_iter()
+                    }
+                }
+                {
+                    self.p2p_document.iter().map(|document| gtk!{
+                        <Label label=format!("libp2p document {}", document) selectable=true line_wrap=true halign=Align::Start />
+                    }).collect::<Vec<_>>().into_iter()
+                }
+                {
+                    if self.rejected.is_empty() {
+                        Vec::new().into_iter()
+                    } else {
+                        let mut rows = vec![gtk!{ <Label label="Rejected" /> }];
+                        rows.extend(self.rejected.iter().map(|(client, reason)| gtk!{
+                            <Label label=format!("{}: {}", client.ip(), reason) halign=Align::Start line_wrap=true />
+                        }));
+                        rows.into_iter()
+                    }
+                }
+            </Box>
+        }
+    }
+
+    /// The text, or two views of it one above the other when the view is
+    /// split. Both views are bound to the same buffer, so the signal handlers
+    /// and patch application in `Doc` don't know or care how many there are.
+    /// With blame on the blame gutter goes to the left, it has a line for
+    /// each line of the text so they line up as long as the text doesn't
+    /// wrap. The Markdown preview goes to the right.
+    fn text_view(&self, doc: &Doc) -> VNode<DocView> {
+        let text = self.editor_view(doc);
+        let text = if doc.show_blame {
+            gtk!{
+                <Box orientation=Orientation::Horizontal spacing=6 Box::expand=true>
+                    <TextView buffer=Some(doc.blame_buffer.clone()) editable=false cursor_visible=false monospace=true />
+                    {text}
+                </Box>
+            }
+        } else {
+            text
+        };
+        if doc.show_preview {
+            gtk!{
+                <Paned orientation=Orientation::Horizontal Box::expand=true>
+                    {text}
+                    <ScrolledWindow hscrollbar_policy=PolicyType::Never>
+                        <Label label=doc.preview_markup().to_string() use_markup=true wrap=true selectable=true
+                            xalign=0.0 yalign=0.0 margin=6 />
+                    </ScrolledWindow>
+                </Paned>
+            }
+        } else {
+            text
+        }
+    }
+
+    /// The text itself, or two views of it one above the other when the tab
+    /// is split
+    #[cfg(feature = "sourceview")]
+    fn editor_view(&self, doc: &Doc) -> VNode<DocView> {
+        let buffer = doc.buffer.clone();
+        let editable = !doc.read_only();
+        // The gutter and the highlight follow the buffer's lines, which patches
+        // change by editing the buffer, so they're never out of date
+        let numbers = doc.show_line_numbers;
+        // Each view's input method, see `compose.rs`
+        let (top, bottom) = (doc.composer().clone(), doc.composer().clone());
+        // And each view's tooltip saying who wrote what's under the pointer
+        let (top_provenance, bottom_provenance) = (doc.provenance(), doc.provenance());
+        if doc.split {
+            gtk!{
+                <Paned orientation=Orientation::Vertical Box::expand=true>
+                    <SourceView buffer=Some(buffer.clone()) editable=editable monospace=true auto_indent=true
+                        show_line_numbers=numbers highlight_current_line=numbers
+                        on realize=|view| { top.watch(view); top_provenance.watch(view); DocMessage::Noop }
+                        on copy_clipboard=|view| clipboard_action(view, clipboard::Action::Copy)
+                        on cut_clipboard=|view| clipboard_action(view, clipboard::Action::Cut)
+                        on paste_clipboard=|view| clipboard_action(view, clipboard::Action::Paste) />
+                    <SourceView buffer=Some(buffer) editable=editable monospace=true auto_indent=true
+                        show_line_numbers=numbers highlight_current_line=numbers
+                        on realize=|view| { bottom.watch(view); bottom_provenance.watch(view); DocMessage::Noop }
+                        on copy_clipboard=|view| clipboard_action(view, clipboard::Action::Copy)
+                        on cut_clipboard=|view| clipboard_action(view, clipboard::Action::Cut)
+                        on paste_clipboard=|view| clipboard_action(view, clipboard::Action::Paste) />
+                </Paned>
+            }
+        } else {
+            gtk!{
+                <SourceView buffer=Some(buffer) editable=editable monospace=true auto_indent=true
+                    show_line_numbers=numbers highlight_current_line=numbers Box::expand=true
+                    on realize=|view| { top.watch(view); top_provenance.watch(view); DocMessage::Noop }
+                    on copy_clipboard=|view| clipboard_action(view, clipboard::Action::Copy)
+                    on cut_clipboard=|view| clipboard_action(view, clipboard::Action::Cut)
+                    on paste_clipboard=|view| clipboard_action(view, clipboard::Action::Paste)
+                    on size_allocate=|view, _| {
+                    let rect = view.get_visible_rect();
+                    let (first, _) = view.get_line_at_y(rect.y);
+                    let (last, _) = view.get_line_at_y(rect.y + rect.height);
+                    DocMessage::Viewport(first.get_line() as usize, last.get_line() as usize)
+                } />
+            }
+        }
+    }
+
+    /// The same without GtkSourceView, which has no line numbers
+    #[cfg(not(feature = "sourceview"))]
+    fn editor_view(&self, doc: &Doc) -> VNode<DocView> {
+        let buffer = doc.buffer.clone();
+        let editable = !doc.read_only();
+        let (top, bottom) = (doc.composer().clone(), doc.composer().clone());
+        // And each view's tooltip saying who wrote what's under the pointer
+        let (top_provenance, bottom_provenance) = (doc.provenance(), doc.provenance());
+        if doc.split {
+            gtk!{
+                <Paned orientation=Orientation::Vertical Box::expand=true>
+                    <TextView buffer=Some(buffer.clone()) editable=editable monospace=true
+                        on realize=|view| { top.watch(view); top_provenance.watch(view); DocMessage::Noop }
+                        on copy_clipboard=|view| clipboard_action(view, clipboard::Action::Copy)
+                        on cut_clipboard=|view| clipboard_action(view, clipboard::Action::Cut)
+                        on paste_clipboard=|view| clipboard_action(view, clipboard::Action::Paste) />
+                    <TextView buffer=Some(buffer) editable=editable monospace=true
+                        on realize=|view| { bottom.watch(view); bottom_provenance.watch(view); DocMessage::Noop }
+                        on copy_clipboard=|view| clipboard_action(view, clipboard::Action::Copy)
+                        on cut_clipboard=|view| clipboard_action(view, clipboard::Action::Cut)
+                        on paste_clipboard=|view| clipboard_action(view, clipboard::Action::Paste) />
+                </Paned>
+            }
+        } else {
+            gtk!{
+                <TextView buffer=Some(buffer) editable=editable monospace=true Box::expand=true
+                    on realize=|view| { top.watch(view); top_provenance.watch(view); DocMessage::Noop }
+                    on copy_clipboard=|view| clipboard_action(view, clipboard::Action::Copy)
+                    on cut_clipboard=|view| clipboard_action(view, clipboard::Action::Cut)
+                    on paste_clipboard=|view| clipboard_action(view, clipboard::Action::Paste)
+                    on size_allocate=|view, _| {
+                    let rect = view.get_visible_rect();
+                    let (first, _) = view.get_line_at_y(rect.y);
+                    let (last, _) = view.get_line_at_y(rect.y + rect.height);
+                    DocMessage::Viewport(first.get_line() as usize, last.get_line() as usize)
+                } />
+            }
+        }
+    }
+
+    /// A button showing the language the text is highlighted as, which
+    /// picks another. There's only plain text without GtkSourceView, so
+    /// it's hidden.
+    fn language_view(&self, doc: &Doc) -> VNode<DocView> {
+        let current = doc.language().and_then(syntax::language_name).unwrap_or_else(|| "Plain Text".to_string());
+        gtk!{
+            <MenuButton label=current tooltip_text="Highlight as" visible=cfg!(feature = "sourceview")>
+                <Popover>
+                    <ScrolledWindow hscrollbar_policy=PolicyType::Never min_content_height=300>
+                        <Box orientation=Orientation::Vertical spacing=2 border_width=6>
+                            <Button label="Plain Text" relief=ReliefStyle::None on clicked=|_| DocMessage::SetLanguage(None) />
+                            {
+                                syntax::languages().into_iter().map(|(id, name)| gtk!{
+                                    <Button label=name relief=ReliefStyle::None on clicked=|_| DocMessage::SetLanguage(Some(id.clone())) />
+                                })
+                            }
+                        </Box>
+                    </ScrolledWindow>
+                </Popover>
+            </MenuButton>
+        }
+    }
+
+    fn find_bar_view(&self, doc: &Doc) -> VNode<DocView> {
+        let matches = match doc.search_match_count() {
+            1 => "1 match".to_string(),
+            n => format!("{} matches", n),
+        };
+        gtk!{
+            <Box orientation=Orientation::Horizontal spacing=6>
+                <SearchEntry placeholder_text="Find" Box::expand=true
+                    on search_changed=|entry| DocMessage::Search(entry.get_text().map(|t| t.to_string()).unwrap_or_default())
+                    on activate=|_| DocMessage::FindNext
+                    on stop_search=|_| DocMessage::Find />
+                <Label label=matches />
+                {self.history_matches_view(doc)}
+                <Entry placeholder_text="Replace with" on changed=|entry| {
+                    DocMessage::SetReplacement(entry.get_text().map(|t| t.to_string()).unwrap_or_default())
+                } />
+                <Button label="Replace" on clicked=|_| DocMessage::Replace />
+                <Button label="Replace All" on clicked=|_| DocMessage::ReplaceAll />
+                <Button image="window-close-symbolic" tooltip_text="Close" on clicked=|_| DocMessage::Find />
+            </Box>
+        }
+    }
+
+    /// Who wrote each place a word being searched for appears, see
+    /// `find::history_matches`
+    fn history_matches_view(&self, doc: &Doc) -> VNode<DocView> {
+        let matches = doc.history_matches();
+        gtk!{
+            <MenuButton label="Who Wrote It" sensitive=!matches.is_empty()
+                tooltip_text="Who wrote each place a word appears">
+                <Popover>
+                    <Box orientation=Orientation::Vertical spacing=2 border_width=10>
+                        {
+                            matches.into_iter().map(|m| {
+                                let start = m.start;
+                                let label = match &m.when {
+                                    Some(when) => format!("{}, {}", m.who, when),
+                                    None => m.who.clone(),
+                                };
+                                gtk!{
+                                    <Button relief=ReliefStyle::None tooltip_text=m.context.clone() on clicked=|_| DocMessage::SelectMatch(start)>
+                                        <Box orientation=Orientation::Horizontal spacing=12>
+                                            <Label label=m.context.clone() halign=Align::Start Box::expand=true ellipsize=pango::EllipsizeMode::End />
+                                            <Label label=label halign=Align::End />
+                                        </Box>
+                                    </Button>
+                                }
+                            })
+                        }
+                    </Box>
+                </Popover>
+            </MenuButton>
+        }
+    }
+
+    /// Live pipeline metrics with a sparkline of the last minute of each
+    fn metrics_view(&self) -> VNode<DocView> {
+        let samples = self.metrics.as_ref().map(|m| m.samples()).unwrap_or_default();
+        let latest = samples.last().cloned().unwrap_or_default();
+        let rows = vec![
+            (
+                "Change requests",
+                format!("{:.0}/s", latest.requests_per_sec),
+                metrics::sparkline(&samples.iter().map(|s| s.requests_per_sec).collect::<Vec<_>>()),
+            ),
+            (
+                "Round trip",
+                format!("{:.1} ms", latest.round_trip_ms),
+                metrics::sparkline(&samples.iter().map(|s| s.round_trip_ms).collect::<Vec<_>>()),
+            ),
+            (
+                "Queue depth",
+                latest.queue_depth.to_string(),
+                metrics::sparkline(&samples.iter().map(|s| s.queue_depth as f64).collect::<Vec<_>>()),
+            ),
+        ];
+        // The backend thread's load, each with a gauge of how close to
+        // saturated it is, see `metrics.rs`
+        let gauges = vec![
+            (
+                "Backend busy",
+                format!("{:.0}%", latest.backend_busy * 100.0),
+                latest.backend_busy,
+                "The share of the last second the backend spent handling messages".to_string(),
+            ),
+            (
+                "Backend queue",
+                latest.backend_queue.to_string(),
+                latest.backend_queue as f64 / metrics::BACKEND_QUEUE_GAUGE_MAX as f64,
+                "The most messages waiting for the backend at once over the last second".to_string(),
+            ),
+            (
+                "Per message",
+                format!("{:.2} ms", latest.backend_ms),
+                latest.backend_ms / metrics::BACKEND_MS_GAUGE_MAX,
+                format!("The mean time the backend took to handle a message over the last second, {:.2} ms at most", latest.backend_max_ms),
+            ),
+        ];
+        let first_gauge = rows.len() as i32;
+        gtk!{
+            <Expander label="Metrics">
+                <Grid row_spacing=4 column_spacing=12>
+                    {
+                        rows.into_iter().enumerate().flat_map(|(row, (name, value, sparkline))| {
+                            let row = row as i32;
+                            vec![
+                                gtk!{ <Label label=name halign=Align::Start Grid::left_attach=0 Grid::top_attach=row /> },
+                                gtk!{ <Label label=value halign=Align::End Grid::left_attach=1 Grid::top_attach=row /> },
+                                gtk!{ <Label label=sparkline Grid::left_attach=2 Grid::top_attach=row /> },
+                            ]
+                        })
+                    }
+                    {
+                        gauges.into_iter().enumerate().flat_map(|(row, (name, value, level, tooltip))| {
+                            let row = first_gauge + row as i32;
+                            vec![
+                                gtk!{ <Label label=name halign=Align::Start tooltip_text=tooltip.clone() Grid::left_attach=0 Grid::top_attach=row /> },
+                                gtk!{ <Label label=value halign=Align::End Grid::left_attach=1 Grid::top_attach=row /> },
+                                gtk!{ <LevelBar value=level.min(1.0) max_value=1.0 valign=Align::Center tooltip_text=tooltip Grid::left_attach=2 Grid::top_attach=row /> },
+                            ]
+                        })
+                    }
+                </Grid>
+            </Expander>
+        }
+    }
+
+    /// The history growth chart, see `growth.rs`. It's drawn from the doc's
+    /// growth as it is at the time, and redrawn every second to keep up.
+    fn growth_view(&self, doc: &Doc) -> VNode<DocView> {
+        let growth = doc.growth();
+        let label = growth.borrow().label();
+        gtk!{
+            <Expander label="History growth">
+                <Box orientation=Orientation::Vertical spacing=4>
+                    <Label label=format!("{}, bytes in blue and ops in orange", label) halign=Align::Start />
+                    <DrawingArea height_request=GROWTH_CHART_HEIGHT on realize=|area| {
+                        let growth = growth.clone();
+                        area.connect_draw(move |area, cr| {
+                            let (width, height) = (area.get_allocated_width() as f64, area.get_allocated_height() as f64);
+                            growth.borrow().draw(cr, width, height);
+                            Inhibit(false)
+                        });
+                        let area = area.downgrade();
+                        glib::timeout_add_seconds_local(1, move || match area.upgrade() {
+                            Some(area) => {
+                                area.queue_draw();
+                                glib::Continue(true)
+                            }
+                            None => glib::Continue(false),
+                        });
+                        DocMessage::Noop
+                    } />
+                </Box>
+            </Expander>
+        }
+    }
+
+    fn status_view(&self, doc: &Doc) -> VNode<DocView> {
+        let stats = doc.text_stats();
+        let latency = match doc.last_latency() {
+            Some(latency) => format!("last patch {} ms", latency.as_millis()),
+            None => "no patches yet".to_string(),
+        };
+        gtk!{
+            <Statusbar spacing=18>
+                <Label label=format!("{} words, {} characters", stats.words, stats.chars) />
+                <Label label=format!("{} pending", doc.pending_changes()) tooltip_text="Local changes the backend hasn't acknowledged yet" />
+                <Label label=latency tooltip_text="Time from sending our latest change to receiving its patch" />
+                <Label label=format!("heads {}", convergence::short_heads(doc.heads())) selectable=true
+                    tooltip_text=format!("Windows with the same heads have seen the same changes:\n{}", doc.heads().join("\n")) />
+            </Statusbar>
+        }
+    }
+
+    /// The patches this window has received, newest first
+    fn patch_log_view(&self, doc: &Doc) -> VNode<DocView> {
+        let own_actor = doc.actor_id();
+        let entries: Vec<String> = doc.patch_log.entries().map(|e| e.describe(&own_actor, self.signatures.as_deref())).collect();
+        gtk!{
+            <Box orientation=Orientation::Vertical spacing=6 width_request=320>
+                <Label label="Patch log" />
+                <ScrolledWindow hscrollbar_policy=PolicyType::Never Box::expand=true>
+                    <Box orientation=Orientation::Vertical spacing=2>
+                        {
+                            entries.into_iter().map(|entry| gtk!{
+                                <Label label=entry halign=Align::Start ellipsize=pango::EllipsizeMode::End />
+                            })
+                        }
+                    </Box>
+                </ScrolledWindow>
+            </Box>
+        }
+    }
+
+    /// The bar above the text while we're suggesting changes
+    fn suggestion_view(&self, doc: &Doc) -> VNode<DocView> {
+        let count = doc.suggestion_count();
+        gtk!{
+            <InfoBar message_type=MessageType::Info visible=doc.suggesting()>
+                <Label label=format!("Suggesting: {} change{} pending", count, if count == 1 { "" } else { "s" }) Box::expand=true halign=Align::Start />
+                <Button label="Accept" on clicked=|_| DocMessage::ResolveSuggestions(true) />
+                <Button label="Reject" on clicked=|_| DocMessage::ResolveSuggestions(false) />
+            </InfoBar>
+        }
+    }
+
+    /// Our own edits which undo and redo would undo and redo, with other
+    /// people's changes in between, newest first
+    fn undo_history_view(&self, doc: &Doc) -> VNode<DocView> {
+        let rows = doc.undo_history();
+        let read_only = doc.read_only();
+        gtk!{
+            <Box orientation=Orientation::Vertical spacing=6 width_request=320>
+                <Label label="Undo history" />
+                <Label label="Nothing to undo" visible=rows.is_empty() />
+                <ScrolledWindow hscrollbar_policy=PolicyType::Never Box::expand=true>
+                    <Box orientation=Orientation::Vertical spacing=2>
+                        {
+                            rows.into_iter().map(move |row| {
+                                let time = row.time_label();
+                                match row {
+                                    undo::HistoryRow::Undoable{label, steps, ..} => gtk!{
+                                        <Box orientation=Orientation::Horizontal spacing=6>
+                                            <Label label=time />
+                                            <Label label=label halign=Align::Start ellipsize=pango::EllipsizeMode::End Box::expand=true />
+                                            <Button label="Undo to here" sensitive=!read_only relief=ReliefStyle::None
+                                                tooltip_text=format!("Undo {} edit{}", steps, if steps == 1 { "" } else { "s" })
+                                                on clicked=|_| DocMessage::UndoSteps(steps) />
+                                        </Box>
+                                    },
+                                    undo::HistoryRow::Undone{label, steps, ..} => gtk!{
+                                        <Box orientation=Orientation::Horizontal spacing=6>
+                                            <Label label=time sensitive=false />
+                                            <Label label=label halign=Align::Start ellipsize=pango::EllipsizeMode::End Box::expand=true sensitive=false />
+                                            <Button label="Redo to here" sensitive=!read_only relief=ReliefStyle::None
+                                                tooltip_text=format!("Redo {} edit{}", steps, if steps == 1 { "" } else { "s" })
+                                                on clicked=|_| DocMessage::RedoSteps(steps) />
+                                        </Box>
+                                    },
+                                    undo::HistoryRow::Remote{description, ..} => gtk!{
+                                        <Box orientation=Orientation::Horizontal spacing=6
+                                            tooltip_text="Someone else's change, which undo leaves alone">
+                                            <Label label=time />
+                                            <Label label=format!("<i>{}</i>", glib::markup_escape_text(&description)) use_markup=true
+                                                halign=Align::Start ellipsize=pango::EllipsizeMode::End Box::expand=true />
+                                            <Label label="remote" sensitive=false />
+                                        </Box>
+                                    },
+                                }
+                            })
+                        }
+                    </Box>
+                </ScrolledWindow>
+            </Box>
+        }
+    }
+}
+
+/// Stop a view copying, cutting or pasting itself, so that the doc does it
+/// with the marks, see `clipboard.rs`
+fn clipboard_action(view: &impl IsA<TextView>, action: clipboard::Action) -> DocMessage {
+    view.stop_signal_emission(action.signal());
+    DocMessage::Clipboard(action)
+}
+
+impl Component for DocView {
+    type Message = DocMessage;
+    type Properties = DocViewProperties;
+    fn view(&self) -> VNode<Self> {
+        match &self.doc {
+            // We're waiting for the outer component to give us a doc
+            None => gtk!{
+                <Box orientation=Orientation::Vertical valign=Align::Center halign=Align::Center Box::expand=true>
+                    <Label label="Initializing" />
+                </Box>
+            },
+            Some(doc) => gtk!{
+                <Box orientation=Orientation::Vertical spacing=6 border_width=12 Box::expand=true>
+                    <Box orientation=Orientation::Horizontal spacing=6>
+                        <MenuButton image="avatar-default-symbolic" tooltip_text="Identity">
+                            <Popover>
+                                {self.identity_view()}
+                            </Popover>
+                        </MenuButton>
+                        {self.doc_title_view(&doc.borrow())}
+                        <ToggleButton image="changes-prevent-symbolic" tooltip_text="Read only: follow other edits without making any"
+                            active=doc.borrow().read_only() on toggled=|button| DocMessage::SetReadOnly(button.get_active()) />
+                        {self.presence_view()}
+                        <ToggleButton image="user-available-symbolic" tooltip_text="Chat"
+                            active=doc.borrow().show_chat on toggled=|button| DocMessage::SetShowChat(button.get_active()) />
+                        <MenuButton Box::expand=true halign=Align::End image="network-workgroup-symbolic" tooltip_text="Peers">
+                            <Popover>
+                                {self.peers_view()}
+                            </Popover>
+                        </MenuButton>
+                    </Box>
+                    {
+                        if doc.borrow().show_find { vec![self.find_bar_view(&doc.borrow())].into_iter() } else { vec![].into_iter() }
+                    }
+                    {self.suggestion_view(&doc.borrow())}
+                    <Box orientation=Orientation::Horizontal spacing=12 Box::expand=true>
+                        <Notebook Box::expand=true>
+                            <Box Notebook::tab_label="Text" orientation=Orientation::Vertical valign=Align::Center halign=Align::Center vexpand=true>
+                                <Label label="Counter" visible=doc.borrow().has_counter() />
+                                <Box spacing=30 halign=Align::Center valign=Align::Center orientation=Orientation::Horizontal Box::expand=false visible=doc.borrow().has_counter()>
+                                    <Label label=doc.borrow().counter_value().to_string() />
+                                    <Button label="inc!" image="list-add" Box::expand=false always_show_image=true on clicked=|_| DocMessage::Inc />
+                                </Box>
+                                <Label label="Text" />
+                                <Box spacing=6 halign=Align::Center orientation=Orientation::Horizontal Box::expand=false>
+                                    <Button image="format-text-bold-symbolic" tooltip_text="Bold" on clicked=|_| DocMessage::ToggleMark(marks::MarkType::Bold) />
+                                    <Button image="format-text-italic-symbolic" tooltip_text="Italic" on clicked=|_| DocMessage::ToggleMark(marks::MarkType::Italic) />
+                                    <Button image="format-text-underline-symbolic" tooltip_text="Underline" on clicked=|_| DocMessage::ToggleMark(marks::MarkType::Underline) />
+                                    {self.language_view(&doc.borrow())}
+                                </Box>
+                                {self.text_view(&doc.borrow())}
+                            </Box>
+                            <Box Notebook::tab_label="Checklist" orientation=Orientation::Vertical>
+                                {self.checklist_view(&doc.borrow())}
+                            </Box>
+                            <Box Notebook::tab_label="Table" orientation=Orientation::Vertical>
+                                {self.table_view(&doc.borrow())}
+                            </Box>
+                            <Box Notebook::tab_label="Kanban" orientation=Orientation::Vertical>
+                                {self.kanban_view(&doc.borrow())}
+                            </Box>
+                            {
+                                let fields = doc.borrow().fields();
+                                if fields.is_empty() {
+                                    vec![].into_iter()
+                                } else {
+                                    vec![gtk!{
+                                        <Box Notebook::tab_label="Fields" orientation=Orientation::Vertical>
+                                            {self.fields_view(fields)}
+                                        </Box>
+                                    }].into_iter()
+                                }
+                            }
+                        </Notebook>
+                        {
+                            if doc.borrow().show_patch_log { vec![self.patch_log_view(&doc.borrow())].into_iter() } else { vec![].into_iter() }
+                        }
+                        {
+                            if doc.borrow().show_undo_history { vec![self.undo_history_view(&doc.borrow())].into_iter() } else { vec![].into_iter() }
+                        }
+                        <Revealer transition_type=RevealerTransitionType::SlideLeft reveal_child=doc.borrow().show_chat>
+                            {self.chat_view(&doc.borrow())}
+                        </Revealer>
+                    </Box>
+                    {self.metrics_view()}
+                    {self.growth_view(&doc.borrow())}
+                    {self.status_view(&doc.borrow())}
+                </Box>
+            }
+        }
+    }
+
+    fn change(&mut self, properties: Self::Properties) -> UpdateAction<Self> {
+        self.doc = properties.doc;
+        self.peers = properties.peers;
+        self.connections = properties.connections;
+        self.rejected = properties.rejected;
+        self.p2p_document = properties.p2p_document;
+        self.presence = properties.presence;
+        self.metrics = properties.metrics;
+        self.signatures = properties.signatures;
+        self.on_connect = properties.on_connect;
+        self.on_identity = properties.on_identity;
+        self.on_resolve = properties.on_resolve;
+        UpdateAction::Render
+    }
+
+    fn update(&mut self, msg: Self::Message) -> UpdateAction<Self> {
+        match msg {
+            DocMessage::Inc => {
+                self.doc.as_mut().map(|d| d.borrow_mut().inc_counter());
+                UpdateAction::Render
+            },
+            DocMessage::ToggleMark(mark_type) => {
+                self.doc.as_mut().map(|d| d.borrow_mut().toggle_mark(mark_type));
+                UpdateAction::None
+            },
+            DocMessage::Todo(action) => {
+                self.doc.as_mut().map(|d| d.borrow_mut().todo_action(action));
+                UpdateAction::Render
+            },
+            DocMessage::Table(action) => {
+                self.doc.as_mut().map(|d| d.borrow_mut().table_action(action));
+                UpdateAction::Render
+            },
+            DocMessage::Kanban(action) => {
+                self.doc.as_mut().map(|d| d.borrow_mut().kanban_action(action));
+                UpdateAction::Render
+            },
+            DocMessage::Field(action) => {
+                self.doc.as_mut().map(|d| d.borrow_mut().field_action(action));
+                UpdateAction::Render
+            },
+            DocMessage::Noop => UpdateAction::None,
+            DocMessage::SetName(name) => {
+                if let Some(identity) = self.identity() {
+                    self.on_identity.send(presence::Identity{name, ..identity});
+                }
+                UpdateAction::None
+            },
+            DocMessage::SetTitle(new_title) => {
+                self.doc.as_mut().map(|d| d.borrow_mut().set_title(new_title));
+                UpdateAction::Render
+            },
+            DocMessage::SetColor(color) => {
+                if let Some(identity) = self.identity() {
+                    self.on_identity.send(presence::Identity{color, ..identity});
+                }
+                UpdateAction::None
+            },
+            DocMessage::Connect(addr) => {
+                self.on_connect.send(addr);
+                UpdateAction::None
+            }
+            DocMessage::SetReadOnly(read_only) => {
+                self.doc.as_mut().map(|d| d.borrow_mut().set_read_only(read_only));
+                UpdateAction::Render
+            }
+            DocMessage::Find => {
+                self.doc.as_mut().map(|d| d.borrow_mut().toggle_find());
+                UpdateAction::Render
+            }
+            DocMessage::Search(query) => {
+                self.doc.as_mut().map(|d| d.borrow_mut().search(query));
+                UpdateAction::Render
+            }
+            DocMessage::FindNext => {
+                self.doc.as_ref().map(|d| d.borrow().find_next());
+                UpdateAction::None
+            }
+            DocMessage::SelectMatch(start) => {
+                self.doc.as_ref().map(|d| d.borrow().select_match(start));
+                UpdateAction::None
+            }
+            DocMessage::SetReplacement(replacement) => {
+                self.replacement = replacement;
+                UpdateAction::None
+            }
+            DocMessage::Replace => {
+                let replacement = self.replacement.clone();
+                self.doc.as_mut().map(|d| d.borrow_mut().replace(&replacement));
+                UpdateAction::Render
+            }
+            DocMessage::ReplaceAll => {
+                let replacement = self.replacement.clone();
+                self.doc.as_mut().map(|d| d.borrow_mut().replace_all(&replacement));
+                UpdateAction::Render
+            }
+            DocMessage::Clipboard(clipboard::Action::Copy) => {
+                self.doc.as_ref().map(|d| d.borrow().copy());
+                UpdateAction::None
+            }
+            DocMessage::Clipboard(clipboard::Action::Cut) => {
+                self.doc.as_mut().map(|d| d.borrow_mut().cut());
+                UpdateAction::Render
+            }
+            DocMessage::Clipboard(clipboard::Action::Paste) => {
+                // Asked for now, it arrives from the main loop
+                let clip = clipboard::take();
+                UpdateAction::defer(async move { DocMessage::Pasted(clip.await) })
+            }
+            DocMessage::Pasted(clip) => {
+                if let Some(clip) = clip {
+                    self.doc.as_mut().map(|d| d.borrow_mut().paste(clip));
+                }
+                UpdateAction::Render
+            }
+            DocMessage::Viewport(first, last) => {
+                self.doc.as_mut().map(|d| d.borrow_mut().set_viewport(first, last));
+                UpdateAction::None
+            }
+            DocMessage::UndoSteps(steps) => {
+                self.doc.as_mut().map(|d| d.borrow_mut().undo_steps(steps));
+                UpdateAction::Render
+            }
+            DocMessage::RedoSteps(steps) => {
+                self.doc.as_mut().map(|d| d.borrow_mut().redo_steps(steps));
+                UpdateAction::Render
+            }
+            DocMessage::SetLanguage(id) => {
+                self.doc.as_mut().map(|d| d.borrow_mut().set_language(id));
+                UpdateAction::Render
+            }
+            DocMessage::ResolveSuggestions(accept) => {
+                self.on_resolve.send(accept);
+                UpdateAction::None
+            }
+            DocMessage::SetShowChat(show) => {
+                self.doc.as_mut().map(|d| d.borrow_mut().show_chat = show);
+                UpdateAction::Render
+            }
+            DocMessage::SendChat(body) => {
+                let author = self.identity()
+                    .map(|i| i.name)
+                    .or_else(|| self.doc.as_ref().map(|d| d.borrow().actor_id().chars().take(8).collect()))
+                    .unwrap_or_default();
+                self.doc.as_mut().map(|d| d.borrow_mut().send_chat(&author, &body));
+                UpdateAction::None
+            }
+        }
+    }
+}
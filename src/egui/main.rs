@@ -0,0 +1,168 @@
+//! The demo on egui, a third front end on the core crate and one with no
+//! GTK at all. `cargo run --features egui --bin automerge-demo-egui`.
+//!
+//! Like the GTK 4 one it's one window on one document with a backend thread
+//! of its own, but egui has no text buffer to connect to. A `TextEdit` edits
+//! a `String` in place, once a frame, so the edits are worked out afterwards
+//! by comparing the string with what it was before the frame, see
+//! `text::changed`, and made in the document as one delete and one insert.
+//! What the backend sends goes the other way the same way: the string is
+//! replaced with the document's text, and the cursor is moved along by
+//! however much changed before it.
+//!
+//! egui only draws when something happens in the window, so the thread
+//! passing on what the backend sends asks for a repaint each time.
+
+use automerge_backend::Backend;
+use automerge_demo_core::backend;
+use automerge_demo_core::document::{TextDocument, Update};
+use automerge_demo_core::text;
+use eframe::egui;
+use eframe::egui::text::CCursor;
+use eframe::egui::text_edit::{CCursorRange, TextEditState};
+use std::ops::Range;
+
+struct App {
+    document: TextDocument,
+    updates: crossbeam::Receiver<Update>,
+    /// What the text edit shows, which is the document's text but for
+    /// this frame's typing
+    text: String,
+}
+
+impl App {
+    fn new(cc: &eframe::CreationContext) -> App {
+        let (requests, requests_rx) = crossbeam::unbounded();
+        let (backend_tx, backend_rx) = crossbeam::unbounded();
+        let (updates_tx, updates) = crossbeam::unbounded();
+        backend::spawn(Backend::init(), requests_rx, backend_tx);
+        let ctx = cc.egui_ctx.clone();
+        std::thread::spawn(move || {
+            for update in backend_rx {
+                if updates_tx.send(update).is_err() {
+                    return;
+                }
+                ctx.request_repaint();
+            }
+        });
+        let mut document = TextDocument::new(requests);
+        if let Err(e) = document.create() {
+            tracing::error!("Could not create the document: {}", e);
+        }
+        App { document, updates, text: String::new() }
+    }
+
+    fn text_id() -> egui::Id {
+        egui::Id::new("text")
+    }
+
+    /// Make what this frame's typing did to `before` in the document
+    fn edited(&mut self, before: &str) {
+        let old: Vec<char> = before.chars().collect();
+        let new: Vec<char> = self.text.chars().collect();
+        let (removed, put) = text::changed(&old, &new);
+        if !removed.is_empty() {
+            if let Err(e) = self.document.delete(removed.start, removed.end) {
+                tracing::error!("Could not delete: {}", e);
+            }
+        }
+        if !put.is_empty() {
+            let inserted: String = new[put.clone()].iter().collect();
+            if let Err(e) = self.document.insert(put.start, &inserted) {
+                tracing::error!("Could not insert {:?}: {}", inserted, e);
+            }
+        }
+    }
+
+    /// Show the document's text, keeping the cursor where it was in it
+    fn refresh(&mut self, ctx: &egui::Context) {
+        let old: Vec<char> = self.text.chars().collect();
+        let new = self.document.text();
+        let (removed, put) = text::changed(&old, &new.chars().collect::<Vec<char>>());
+        self.text = new;
+        if let Some(mut state) = TextEditState::load(ctx, App::text_id()) {
+            if let Some(range) = state.ccursor_range() {
+                let primary = CCursor::new(moved(range.primary.index, &removed, &put));
+                let secondary = CCursor::new(moved(range.secondary.index, &removed, &put));
+                state.set_ccursor_range(Some(CCursorRange::two(secondary, primary)));
+                state.store(ctx, App::text_id());
+            }
+        }
+    }
+}
+
+/// Where the cursor at `index` goes when the chars in `removed` are
+/// replaced with those in `put`
+fn moved(index: usize, removed: &Range<usize>, put: &Range<usize>) -> usize {
+    if index <= removed.start {
+        index
+    } else if index >= removed.end {
+        index - removed.len() + put.len()
+    } else {
+        put.end
+    }
+}
+
+impl eframe::App for App {
+    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        let mut stale = false;
+        for update in self.updates.try_iter().collect::<Vec<Update>>() {
+            match self.document.update(update) {
+                Ok(changed) => stale |= changed,
+                Err(e) => tracing::error!("{}", e),
+            }
+        }
+        if stale {
+            self.refresh(ctx);
+        }
+        egui::TopBottomPanel::bottom("counter").show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                ui.label(self.document.counter().to_string());
+                if ui.button("Increment").clicked() {
+                    if let Err(e) = self.document.increment() {
+                        tracing::error!("{}", e);
+                    }
+                }
+                if self.document.pending() > 0 {
+                    ui.label(format!("{} waiting for the backend", self.document.pending()));
+                }
+            });
+        });
+        egui::CentralPanel::default().show(ctx, |ui| {
+            egui::ScrollArea::vertical().show(ui, |ui| {
+                let before = self.text.clone();
+                let edit = egui::TextEdit::multiline(&mut self.text)
+                    .id(App::text_id())
+                    .code_editor()
+                    .desired_width(f32::INFINITY);
+                if ui.add_sized(ui.available_size(), edit).changed() {
+                    self.edited(&before);
+                }
+            });
+        });
+    }
+}
+
+fn main() {
+    tracing_subscriber::fmt::init();
+    let options = eframe::NativeOptions::default();
+    if let Err(e) = eframe::run_native("Automerge Demo (egui)", options, Box::new(|cc| Box::new(App::new(cc)))) {
+        eprintln!("could not open the window: {}", e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::moved;
+
+    #[test]
+    fn the_cursor_moves_with_the_text_before_it() {
+        // "abc|def" with "b" replaced by "XY"
+        assert_eq!(moved(3, &(1..2), &(1..3)), 4);
+        // the change is after the cursor
+        assert_eq!(moved(1, &(2..3), &(2..2)), 1);
+        // the cursor was among the characters which went
+        assert_eq!(moved(2, &(1..3), &(1..1)), 1);
+    }
+}
@@ -0,0 +1,94 @@
+//! Exporting a document's history as a JSON event log, File > Export History.
+//!
+//! A saved document (see `file.rs`) is in automerge's binary encoding, which
+//! is compact but opaque. The event log is for looking at the history with
+//! other tools: a JSON array with an entry for each change, in causal order,
+//! giving its hash, actor, seq, time, message, dependencies and ops.
+//!
+//! Each entry also carries the change's binary encoding, hex encoded, and
+//! that is what `--replay <file>` feeds back through a fresh backend to
+//! reconstruct the document. The decoded fields are only there to be read,
+//! but replaying does check that every change still hashes to what the log
+//! says it did, so a log which has been edited by hand is rejected rather
+//! than quietly producing a different document.
+
+use automerge_backend::Change;
+use automerge_protocol as amp;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::Path;
+
+use crate::crypt;
+
+#[derive(Serialize, Deserialize)]
+struct LoggedChange {
+    hash: amp::ChangeHash,
+    actor: String,
+    seq: u64,
+    start_op: u64,
+    /// Seconds since the epoch, zero if the change wasn't timestamped
+    time: i64,
+    message: Option<String>,
+    deps: Vec<amp::ChangeHash>,
+    ops: Vec<amp::Op>,
+    /// The change in the binary encoding, as hex
+    bytes: String,
+}
+
+impl LoggedChange {
+    fn new(change: &Change) -> LoggedChange {
+        LoggedChange {
+            hash: change.hash,
+            actor: change.actor_id().to_string(),
+            seq: change.seq,
+            start_op: change.start_op,
+            time: change.time,
+            message: change.message(),
+            deps: change.deps.clone(),
+            ops: change.decode().operations,
+            bytes: to_hex(change.raw_bytes()),
+        }
+    }
+}
+
+/// Write the log to `path`, encrypted with `key` if there is one
+pub fn export(path: &Path, changes: &[Change], key: Option<&crypt::Key>) -> io::Result<()> {
+    let log: Vec<LoggedChange> = changes.iter().map(LoggedChange::new).collect();
+    let json = serde_json::to_vec_pretty(&log)?;
+    std::fs::write(path, crypt::seal(key, json))
+}
+
+/// Read an exported log, decrypted if it was encrypted, back into the
+/// changes it was made from
+pub fn decode(bytes: &[u8]) -> io::Result<Vec<Change>> {
+    let log: Vec<LoggedChange> = serde_json::from_slice(bytes)?;
+    log.into_iter()
+        .enumerate()
+        .map(|(i, entry)| {
+            let bytes = from_hex(&entry.bytes).ok_or_else(|| invalid(format!("change {} isn't valid hex", i)))?;
+            let change = Change::from_bytes(bytes).map_err(|e| invalid(format!("change {}: {:?}", i, e)))?;
+            if change.hash != entry.hash {
+                return Err(invalid(format!("change {} doesn't match its hash", i)));
+            }
+            Ok(change)
+        })
+        .collect()
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
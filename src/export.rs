@@ -0,0 +1,152 @@
+//! File > Export, writing the document out for use outside the demo.
+//!
+//! Everything is read from the frontend's value tree through the same
+//! functions the views use, so the export has whatever the current tab
+//! shows: the title, the text, and the checklist, table and kanban board if
+//! they have anything in them. The text is taken to be Markdown, so the
+//! Markdown export is the text as it is with the other parts added as
+//! Markdown too, the HTML export is that rendered by pulldown-cmark and the
+//! plain text export leaves the text alone and lays the rest out simply.
+
+use automerge_frontend::Frontend;
+use pulldown_cmark::{html, Options, Parser};
+
+use crate::checklist::{self, Todo};
+use crate::doc::text_value;
+use crate::kanban::{self, Column};
+use crate::table::{self, Table};
+use crate::title;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Markdown,
+    Html,
+    PlainText,
+}
+
+impl Format {
+    /// The usual file extension for the format
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Markdown => "md",
+            Format::Html => "html",
+            Format::PlainText => "txt",
+        }
+    }
+}
+
+/// What gets exported, read out of the frontend
+struct Snapshot {
+    title: String,
+    text: String,
+    todos: Vec<Todo>,
+    /// `None` if every cell is empty
+    table: Option<Table>,
+    /// Empty if there are no cards in any column
+    kanban: Vec<Column>,
+}
+
+impl Snapshot {
+    fn new(frontend: &Frontend) -> Snapshot {
+        let table = table::table(frontend);
+        let table_used = (0..table.rows).any(|r| (0..table.cols).any(|c| !table.cell(r, c).is_empty()));
+        let mut kanban = kanban::columns(frontend);
+        if kanban.iter().all(|c| c.cards.is_empty()) {
+            kanban.clear();
+        }
+        Snapshot {
+            title: title::title(frontend),
+            text: text_value(frontend),
+            todos: checklist::todos(frontend),
+            table: if table_used { Some(table) } else { None },
+            kanban,
+        }
+    }
+}
+
+/// The document in `format`
+pub fn export(frontend: &Frontend, format: Format) -> String {
+    let snapshot = Snapshot::new(frontend);
+    match format {
+        Format::Markdown => markdown(&snapshot),
+        Format::Html => {
+            let source = markdown(&snapshot);
+            let mut body = String::new();
+            html::push_html(&mut body, Parser::new_ext(&source, Options::all()));
+            format!(
+                "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n</head>\n<body>\n{}</body>\n</html>\n",
+                escape_html(&snapshot.title),
+                body
+            )
+        }
+        Format::PlainText => plain_text(&snapshot),
+    }
+}
+
+fn markdown(snapshot: &Snapshot) -> String {
+    let mut out = format!("# {}\n\n{}\n", snapshot.title, snapshot.text.trim_end());
+    if !snapshot.todos.is_empty() {
+        out.push_str("\n## Checklist\n\n");
+        for todo in &snapshot.todos {
+            out.push_str(&format!("- [{}] {}\n", if todo.done { "x" } else { " " }, todo.title));
+        }
+    }
+    if let Some(table) = &snapshot.table {
+        out.push_str("\n## Table\n\n");
+        // Markdown tables need a header, the demo's table doesn't have one so
+        // the columns are just numbered
+        let header: Vec<String> = (1..=table.cols).map(|c| c.to_string()).collect();
+        out.push_str(&format!("| {} |\n", header.join(" | ")));
+        out.push_str(&format!("|{}\n", "---|".repeat(table.cols)));
+        for row in 0..table.rows {
+            let cells: Vec<String> = (0..table.cols).map(|col| table.cell(row, col).replace('|', "\\|")).collect();
+            out.push_str(&format!("| {} |\n", cells.join(" | ")));
+        }
+    }
+    if !snapshot.kanban.is_empty() {
+        out.push_str("\n## Kanban\n");
+        for column in &snapshot.kanban {
+            out.push_str(&format!("\n### {}\n\n", column.title));
+            for card in &column.cards {
+                out.push_str(&format!("- {}\n", card));
+            }
+        }
+    }
+    out
+}
+
+fn plain_text(snapshot: &Snapshot) -> String {
+    let mut out = format!(
+        "{}\n{}\n\n{}\n",
+        snapshot.title,
+        "=".repeat(snapshot.title.chars().count()),
+        snapshot.text.trim_end()
+    );
+    if !snapshot.todos.is_empty() {
+        out.push_str("\nChecklist\n\n");
+        for todo in &snapshot.todos {
+            out.push_str(&format!("[{}] {}\n", if todo.done { "x" } else { " " }, todo.title));
+        }
+    }
+    if let Some(table) = &snapshot.table {
+        out.push_str("\nTable\n\n");
+        for row in 0..table.rows {
+            let cells: Vec<&str> = (0..table.cols).map(|col| table.cell(row, col)).collect();
+            out.push_str(&format!("{}\n", cells.join("\t")));
+        }
+    }
+    if !snapshot.kanban.is_empty() {
+        out.push_str("\nKanban\n");
+        for column in &snapshot.kanban {
+            out.push_str(&format!("\n{}\n", column.title));
+            for card in &column.cards {
+                out.push_str(&format!("  - {}\n", card));
+            }
+        }
+    }
+    out
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
@@ -0,0 +1,201 @@
+//! Saving documents to disk and opening them again.
+//!
+//! A saved document is just its change history: each change in the automerge
+//! binary encoding, prefixed with its length as a big endian u32. Opening a
+//! file replays those changes into fresh backends, so the opened document has
+//! the same history as the one which was saved and the two can still be
+//! merged.
+//!
+//! File > Open opens the document in a new tab rather than replacing the
+//! document in the current one.
+//!
+//! A file can also be encrypted, see `crypt.rs`, in which case it's the
+//! encrypted form of the same bytes.
+//!
+//! Every change carries its own actor, dependencies and so on, so a long
+//! history saved that way gets big. Tools > Compact, or `cargo run --
+//! compact <file>`, rewrites the file as `SNAPSHOT_TAG` followed by a
+//! snapshot of the whole document in automerge's compressed document
+//! encoding, with an empty log of changes after it. Saving a compacted file
+//! again keeps the snapshot and only appends the changes which aren't in it
+//! yet, the way an app would save incrementally between compactions.
+
+use automerge_backend::{Backend, Change};
+use automerge_protocol as amp;
+use std::collections::HashSet;
+use std::io;
+use std::path::{Path, PathBuf};
+use vgtk::lib::gtk::prelude::*;
+use vgtk::lib::gtk::{FileChooserAction, FileChooserNative, ResponseType, Window};
+
+use crate::crypt;
+
+pub fn save(path: &Path, changes: &[Change]) -> io::Result<()> {
+    save_with(path, changes, None)
+}
+
+/// `save`, encrypted with `key` if there is one
+pub fn save_with(path: &Path, changes: &[Change], key: Option<&crypt::Key>) -> io::Result<()> {
+    let plain = match compacted(path, key, changes) {
+        Some((mut existing, have)) => {
+            let new: Vec<Change> = changes.iter().filter(|c| !have.contains(&c.hash)).cloned().collect();
+            existing.extend(encode(&new));
+            existing
+        }
+        None => encode(changes),
+    };
+    write(path, crypt::seal(key, plain))
+}
+
+/// The contents of the compacted file at `path` and the hashes of the
+/// changes in it, if it's a compacted file of the document with `changes`,
+/// so it can be appended to
+fn compacted(path: &Path, key: Option<&crypt::Key>, changes: &[Change]) -> Option<(Vec<u8>, HashSet<amp::ChangeHash>)> {
+    let bytes = std::fs::read(path).ok()?;
+    let plain = if crypt::is_encrypted(&bytes) {
+        crypt::decrypt(key?, &bytes).ok()?
+    } else {
+        bytes
+    };
+    if !plain.starts_with(SNAPSHOT_TAG) {
+        return None;
+    }
+    let have: HashSet<amp::ChangeHash> = decode(&plain).ok()?.iter().map(|c| c.hash).collect();
+    // Saving over some other document's file replaces it as usual
+    let ours: HashSet<amp::ChangeHash> = changes.iter().map(|c| c.hash).collect();
+    if !have.is_subset(&ours) {
+        return None;
+    }
+    Some((plain, have))
+}
+
+/// Rewrite `path` as a snapshot of the document with `changes`, returning
+/// its size before and after
+pub fn compact(path: &Path, changes: &[Change], key: Option<&crypt::Key>) -> io::Result<(u64, u64)> {
+    let before = std::fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+    let invalid = |e: automerge_backend::AutomergeError| io::Error::new(io::ErrorKind::InvalidData, e.to_string());
+    let mut backend = Backend::init();
+    backend.apply_changes(changes.to_vec()).map_err(invalid)?;
+    let snapshot = backend.save().map_err(invalid)?;
+    let mut plain = SNAPSHOT_TAG.to_vec();
+    plain.extend_from_slice(&(snapshot.len() as u32).to_be_bytes());
+    plain.extend_from_slice(&snapshot);
+    let bytes = crypt::seal(key, plain);
+    let after = bytes.len() as u64;
+    write(path, bytes)?;
+    Ok((before, after))
+}
+
+fn write(path: &Path, bytes: Vec<u8>) -> io::Result<()> {
+    // Write to a temporary file first so a failed save doesn't destroy the
+    // previous one
+    let tmp = temp_path(path);
+    std::fs::write(&tmp, bytes)?;
+    std::fs::rename(tmp, path)
+}
+
+/// Where to write `path` before renaming it into place, next to it with
+/// ".tmp" after the whole name, so that saving `notes.txt` doesn't write over
+/// a `notes.tmp`
+pub fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+/// A size in bytes for people to read
+pub fn human_size(bytes: u64) -> String {
+    match bytes {
+        b if b < 1024 => format!("{} B", b),
+        b if b < 1024 * 1024 => format!("{:.1} KB", b as f64 / 1024.0),
+        b => format!("{:.1} MB", b as f64 / (1024.0 * 1024.0)),
+    }
+}
+
+/// The changes as they're saved, which is also how `--http-port` serves
+/// them
+pub fn encode(changes: &[Change]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for change in changes {
+        let raw = change.raw_bytes();
+        bytes.extend_from_slice(&(raw.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(raw);
+    }
+    bytes
+}
+
+/// Where the demo keeps `name` in the user's config directory
+pub fn config_path(name: &str) -> Option<PathBuf> {
+    let config = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(config.join("automerge-demo").join(name))
+}
+
+/// Where the demo keeps `name` in the user's state directory, for things
+/// which aren't settings but should outlive a run
+pub fn state_path(name: &str) -> Option<PathBuf> {
+    let state = std::env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local").join("state")))?;
+    Some(state.join("automerge-demo").join(name))
+}
+
+/// What a compacted file starts with
+const SNAPSHOT_TAG: &[u8] = b"AMDEMOS1";
+
+pub fn load(path: &Path) -> io::Result<Vec<Change>> {
+    decode(&std::fs::read(path)?)
+}
+
+/// The changes in a saved file's contents, once decrypted if it was
+/// encrypted
+pub fn decode(mut bytes: &[u8]) -> io::Result<Vec<Change>> {
+    if crypt::is_encrypted(bytes) {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, "the file is encrypted"));
+    }
+    let mut changes = Vec::new();
+    if let Some(rest) = bytes.strip_prefix(SNAPSHOT_TAG) {
+        let (snapshot, rest) = take(rest)?;
+        let backend = Backend::load(snapshot.to_vec())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        changes.extend(backend.get_changes(&[]).into_iter().cloned());
+        bytes = rest;
+    }
+    while !bytes.is_empty() {
+        let (change, rest) = take(bytes)?;
+        let change = Change::from_bytes(change.to_vec())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e)))?;
+        changes.push(change);
+        bytes = rest;
+    }
+    Ok(changes)
+}
+
+/// Split a length prefixed chunk off the front of `bytes`
+fn take(bytes: &[u8]) -> io::Result<(&[u8], &[u8])> {
+    let truncated = || io::Error::new(io::ErrorKind::UnexpectedEof, "the file is truncated");
+    if bytes.len() < 4 {
+        return Err(truncated());
+    }
+    let (len, rest) = bytes.split_at(4);
+    let len = u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize;
+    if rest.len() < len {
+        return Err(truncated());
+    }
+    Ok(rest.split_at(len))
+}
+
+/// Ask the user for a file to open or save to
+pub fn choose(action: FileChooserAction) -> Option<PathBuf> {
+    let title = match action {
+        FileChooserAction::Save => "Save Document",
+        _ => "Open Document",
+    };
+    let dialog = FileChooserNative::new(Some(title), None::<&Window>, action, None, None);
+    dialog.set_do_overwrite_confirmation(true);
+    match dialog.run() {
+        ResponseType::Accept => dialog.get_filename(),
+        _ => None,
+    }
+}
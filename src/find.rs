@@ -0,0 +1,70 @@
+//! Finding and replacing in the text.
+//!
+//! Searching works on the text the tab shows, see `TextCache::find`.
+//! Replacing never touches the buffer directly: every replacement is a
+//! delete of the match followed by an insert of the replacement, all in one
+//! change, which is then rendered like any other change. Replacements are
+//! made from the last match backwards so that the indexes of the earlier
+//! matches aren't moved by the later ones.
+//!
+//! The Who Wrote It list searches the history instead: each place a whole
+//! word appears, from the token index, with the change which inserted it,
+//! from the provenance index and the change log, see `history_index.rs`.
+
+use vgtk::lib::gtk::prelude::*;
+use vgtk::lib::gtk::{TextBuffer, TextTag};
+
+use crate::change_log::{self, ChangeLog};
+use crate::history_index::HistoryIndex;
+
+/// How many chars either side of a word the Who Wrote It list shows
+const CONTEXT_CHARS: usize = 16;
+
+/// The tag used to highlight matches in the buffer
+pub const MATCH_TAG: &str = "search-match";
+
+/// Create the match highlight tag in the buffer's tag table, which
+/// `theme::apply` colors
+pub fn create_tag(buffer: &TextBuffer) {
+    let tag = TextTag::new(Some(MATCH_TAG));
+    buffer.get_tag_table().unwrap().add(&tag);
+}
+
+/// Highlight `len` chars at each of `starts`, replacing any previous
+/// highlights
+pub fn highlight(buffer: &TextBuffer, starts: &[usize], len: usize) {
+    let (start, end) = buffer.get_bounds();
+    buffer.remove_tag_by_name(MATCH_TAG, &start, &end);
+    for offset in starts {
+        let start = buffer.get_iter_at_offset(*offset as i32);
+        let end = buffer.get_iter_at_offset((offset + len) as i32);
+        buffer.apply_tag_by_name(MATCH_TAG, &start, &end);
+    }
+}
+
+/// A place a word appears, and who wrote it there
+#[derive(Clone, Debug)]
+pub struct HistoryMatch {
+    pub start: usize,
+    /// The word with a little of the text either side
+    pub context: String,
+    pub who: String,
+    pub when: Option<String>,
+}
+
+/// Every place `word` appears in the text the index has, and the change
+/// which inserted its first char. Our own changes are "you".
+pub fn history_matches(index: &HistoryIndex, log: &ChangeLog, word: &str, own_actor: &str) -> Vec<HistoryMatch> {
+    let offsets = index.offsets();
+    let len = word.chars().count();
+    index.search(word).into_iter().map(|start| {
+        let change = offsets.op_at(start).and_then(|op| log.change_for_op(op));
+        let who = match change {
+            Some(change) if change.actor == own_actor => "you".to_string(),
+            Some(change) => change.short_actor(),
+            None => offsets.actor_at(start).unwrap_or("nobody yet").chars().take(change_log::ACTOR_CHARS).collect(),
+        };
+        let context = offsets.slice(start.saturating_sub(CONTEXT_CHARS)..start + len + CONTEXT_CHARS).replace('\n', " ");
+        HistoryMatch { start, context, who, when: change.and_then(|c| c.time_label()) }
+    }).collect()
+}
@@ -0,0 +1,133 @@
+//! Documents saved by an earlier build, kept in `tests/golden/`, and what
+//! they have to load as: guarding against a new automerge-backend, or a
+//! change to `file.rs`, quietly no longer reading what people have saved.
+//!
+//! Each one is made by a script of edits, made the way a tab makes them, and
+//! saved with `file.rs` as File > Save would. Loading one has to give exactly
+//! the text and counter the script left, and the changes in an uncompacted
+//! file have to encode back to the bytes which were read, so a file saved
+//! again by a new build is the same file.
+//!
+//! The files themselves can't be made again the same, every run has new
+//! actor ids and timestamps, which is why they're checked in rather than made
+//! by the test. `BLESS=1 cargo test golden` writes them afresh from the
+//! scripts, for when a new document is added or the format is changed on
+//! purpose. A file which isn't there fails the test, as a guard that's
+//! quietly skipped guards nothing.
+
+use automerge_backend::Change;
+use automerge_frontend::{LocalChange, Path};
+use std::path::{Path as FilePath, PathBuf};
+
+use crate::doc::{deleted, inserted};
+use crate::file;
+use crate::schema::{Schema, COUNTER_FIELD};
+use crate::simulate::Peer;
+
+struct Golden {
+    name: &'static str,
+    text: &'static str,
+    counter: i64,
+    /// Whether the file starts with a snapshot, see `file::compact`
+    compacted: bool,
+    save: fn(&FilePath) -> Result<(), String>,
+}
+
+const GOLDEN: &[Golden] = &[
+    Golden { name: "typing", text: "Hello, w\u{f6}rld \u{1f600}!", counter: 3, compacted: false, save: save_typing },
+    Golden { name: "concurrent", text: "YcX", counter: 3, compacted: false, save: save_concurrent },
+    Golden { name: "compacted", text: "Hello, w\u{f6}rld \u{1f600}! Bye", counter: 3, compacted: true, save: save_compacted },
+];
+
+fn dir() -> PathBuf {
+    FilePath::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("golden")
+}
+
+fn increment() -> LocalChange {
+    LocalChange::increment(Path::root().key(COUNTER_FIELD))
+}
+
+/// A peer with a new document, as a new tab has, and the changes making it
+fn new_document() -> Result<(Peer, Vec<Change>), String> {
+    let mut peer = Peer::default();
+    let changes = peer.edit(Schema::default().initial_changes())?;
+    Ok((peer, changes))
+}
+
+/// Typing a keystroke at a time, deleting a word at once and pasting over
+/// it, with characters of two, three and four bytes
+fn typing() -> Result<(Peer, Vec<Change>), String> {
+    let (mut peer, mut changes) = new_document()?;
+    for (i, c) in "Hello, world!".chars().enumerate() {
+        changes.extend(peer.edit(inserted(i, &c.to_string()))?);
+    }
+    changes.extend(peer.edit(deleted(7, 12))?);
+    changes.extend(peer.edit(inserted(7, "w\u{f6}rld \u{1f600}"))?);
+    for _ in 0..3 {
+        changes.extend(peer.edit(vec![increment()])?);
+    }
+    Ok((peer, changes))
+}
+
+fn save_typing(path: &FilePath) -> Result<(), String> {
+    let (_, changes) = typing()?;
+    file::save(path, &changes).map_err(|e| e.to_string())
+}
+
+/// Two peers editing "abc" at once, both deleting the "a", and both
+/// counting
+fn save_concurrent(path: &FilePath) -> Result<(), String> {
+    let (mut a, mut changes) = new_document()?;
+    changes.extend(a.edit(inserted(0, "abc"))?);
+    let mut b = Peer::default();
+    b.receive(changes.clone())?;
+    let mut from_a = a.edit(deleted(0, 1))?;
+    from_a.extend(a.edit(inserted(2, "X"))?);
+    from_a.extend(a.edit(vec![increment(), increment()])?);
+    let mut from_b = b.edit(deleted(0, 2))?;
+    from_b.extend(b.edit(inserted(0, "Y"))?);
+    from_b.extend(b.edit(vec![increment()])?);
+    a.receive(from_b.clone())?;
+    b.receive(from_a.clone())?;
+    if a.text() != b.text() {
+        return Err(format!("the peers disagree, {:?} and {:?}", a.text(), b.text()));
+    }
+    changes.extend(from_a);
+    changes.extend(from_b);
+    file::save(path, &changes).map_err(|e| e.to_string())
+}
+
+/// `typing` compacted, then typed into and saved again, which appends to the
+/// snapshot
+fn save_compacted(path: &FilePath) -> Result<(), String> {
+    let (mut peer, mut changes) = typing()?;
+    file::compact(path, &changes, None).map_err(|e| e.to_string())?;
+    let end = peer.text().chars().count();
+    changes.extend(peer.edit(inserted(end, " Bye"))?);
+    file::save(path, &changes).map_err(|e| e.to_string())
+}
+
+#[test]
+fn saved_documents_load_as_they_did() {
+    let bless = std::env::var_os("BLESS").is_some();
+    for golden in GOLDEN {
+        let path = dir().join(golden.name).with_extension("automerge");
+        if bless {
+            std::fs::create_dir_all(dir()).unwrap();
+            // Saving a compacted file keeps what's already there
+            let _ = std::fs::remove_file(&path);
+            (golden.save)(&path).unwrap();
+        }
+        let bytes = std::fs::read(&path).unwrap_or_else(|e| {
+            panic!("can't read {} ({}), run with BLESS=1 to write it and check it in", path.display(), e)
+        });
+        let changes = file::decode(&bytes).unwrap();
+        if !golden.compacted {
+            assert!(file::encode(&changes) == bytes, "{} doesn't encode back to the same bytes", golden.name);
+        }
+        let mut peer = Peer::default();
+        peer.receive(changes).unwrap();
+        assert_eq!(peer.text(), golden.text, "the text in {}", golden.name);
+        assert_eq!(peer.counter(), golden.counter, "the counter in {}", golden.name);
+    }
+}
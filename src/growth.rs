@@ -0,0 +1,124 @@
+//! The history growth chart, under the metrics panel. It plots how big the
+//! document's history is, in bytes of encoded changes and in ops, against
+//! time, as each patch arrives.
+//!
+//! Deleting text doesn't make a document any smaller, the delete is one
+//! more op and the deleted characters stay on as tombstones, see `size.rs`.
+//! The chart makes that visible: both lines only ever go up, whatever
+//! happens to the text. Compacting, Tools > Compact, is marked with a
+//! vertical line; it shrinks the file but not the history, so the lines
+//! carry on from where they were.
+//!
+//! Every tab of a document sees the same changes, and a resync or a
+//! reconnection can bring some in again, so they're counted by hash, once
+//! each. Points less than `MERGE_SECS` apart are merged, which keeps typing
+//! from filling the chart with points no one can tell apart.
+
+use std::collections::{HashSet, VecDeque};
+use std::time::Instant;
+use automerge_protocol as amp;
+
+use crate::change_log::ChangeMeta;
+use crate::file::human_size;
+
+/// How many points the chart keeps, the oldest go first
+const MAX_POINTS: usize = 600;
+
+/// Points closer together than this are merged into one
+const MERGE_SECS: f64 = 0.5;
+
+#[derive(Clone, Copy, Debug)]
+struct Point {
+    /// Seconds since the tab was opened
+    secs: f64,
+    bytes: usize,
+    ops: usize,
+}
+
+#[derive(Default)]
+pub struct Growth {
+    started: Option<Instant>,
+    seen: HashSet<amp::ChangeHash>,
+    bytes: usize,
+    ops: usize,
+    points: VecDeque<Point>,
+    /// When the document was compacted, in seconds since the tab was opened
+    compactions: Vec<f64>,
+}
+
+impl Growth {
+    fn now(&mut self) -> f64 {
+        self.started.get_or_insert_with(Instant::now).elapsed().as_secs_f64()
+    }
+
+    /// Add the changes a patch applied
+    pub fn record(&mut self, changes: &[ChangeMeta]) {
+        let mut grew = false;
+        for change in changes {
+            if self.seen.insert(change.hash) {
+                self.bytes += change.bytes;
+                self.ops += change.ops;
+                grew = true;
+            }
+        }
+        if !grew {
+            return;
+        }
+        let point = Point { secs: self.now(), bytes: self.bytes, ops: self.ops };
+        match self.points.back_mut() {
+            Some(last) if point.secs - last.secs < MERGE_SECS && self.points.len() > 1 => *last = point,
+            _ => self.points.push_back(point),
+        }
+        if self.points.len() > MAX_POINTS {
+            self.points.pop_front();
+        }
+    }
+
+    /// The document was compacted just now
+    pub fn compacted(&mut self) {
+        let now = self.now();
+        self.compactions.push(now);
+    }
+
+    /// The size of the history now, for the chart's label
+    pub fn label(&self) -> String {
+        format!("{} in {} ops", human_size(self.bytes as u64), self.ops)
+    }
+
+    /// Plot the bytes in blue and the ops in orange, each scaled to fill the
+    /// height, with compactions as grey lines
+    pub fn draw(&self, cr: &cairo::Context, width: f64, height: f64) {
+        let (first, last) = match (self.points.front(), self.points.back()) {
+            (Some(first), Some(last)) => (first.secs, last.secs),
+            _ => return,
+        };
+        let span = (last - first).max(1.0);
+        let x = |secs: f64| (secs - first) / span * width;
+        cr.set_line_width(1.0);
+        cr.set_source_rgb(0.6, 0.6, 0.6);
+        for secs in self.compactions.iter().filter(|secs| **secs >= first) {
+            cr.move_to(x(*secs).round() + 0.5, 0.0);
+            cr.line_to(x(*secs).round() + 0.5, height);
+            cr.stroke();
+        }
+        let max_bytes = self.points.iter().map(|p| p.bytes).max().unwrap_or(0).max(1) as f64;
+        let max_ops = self.points.iter().map(|p| p.ops).max().unwrap_or(0).max(1) as f64;
+        let lines: [(f64, f64, f64, &dyn Fn(&Point) -> f64); 2] = [
+            (0.21, 0.52, 0.89, &|p: &Point| p.bytes as f64 / max_bytes),
+            (0.96, 0.47, 0.0, &|p: &Point| p.ops as f64 / max_ops),
+        ];
+        cr.set_line_width(1.5);
+        for (r, g, b, value) in lines.iter() {
+            cr.set_source_rgb(*r, *g, *b);
+            for (i, point) in self.points.iter().enumerate() {
+                let y = height - value(point) * (height - 2.0) - 1.0;
+                if i == 0 {
+                    cr.move_to(x(point.secs), y);
+                } else {
+                    cr.line_to(x(point.secs), y);
+                }
+            }
+            cr.stroke();
+        }
+    }
+}
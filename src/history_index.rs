@@ -0,0 +1,398 @@
+//! Indexes over the history of the text, used to answer "who wrote this
+//! character" and "where does this word appear, and who wrote it" without
+//! walking the document each time.
+//!
+//! There are two indexes. The provenance index records, for each character in
+//! the text, the ID of the op which inserted it. This is cheap to maintain so
+//! it is updated from every patch as it arrives: sequence diffs tell us where
+//! elements were inserted and removed and the op IDs of the inserted values.
+//! It's the elements of an `OffsetIndex`, see `offset_index.rs`, which also
+//! says where each element's characters are. Together with the change log it
+//! is what the blame gutter, the suggestion highlight and the text view's
+//! tooltip go by, see `Provenance`.
+//!
+//! The token index maps each word in the text to where it starts, which the
+//! find bar's Who Wrote It list goes by. A word's start is kept as the op of
+//! the element it starts in rather than a char offset, so an edit before it
+//! doesn't move it; where it is now is the offset of that element, which the
+//! provenance index knows. Tokenizing is too much to do on every keystroke
+//! with a long document open, so a patch only notes which elements it
+//! touched, and the words around them are tokenized again once edits have
+//! settled, a chunk at a time in idle callbacks, see `Doc::schedule_indexing`.
+//! Whoever wants the words right now, rather than as of the last idle, can
+//! have the rest done first with `index_all`.
+
+use automerge_protocol as amp;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+use std::rc::Rc;
+use vgtk::lib::gtk::prelude::*;
+use vgtk::lib::gtk::{TextView, TextWindowType};
+
+use crate::change_log::{self, ChangeLog};
+use crate::offset_index::{self, OffsetIndex};
+
+/// How many chars of the text are tokenized in one idle callback
+pub const STEP_CHARS: usize = 16 * 1024;
+
+/// Where a word starts: the op which inserted the element it starts in, and
+/// how many of that element's chars come before it. Elements typed here are
+/// one char, so that's nearly always none.
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct Anchor {
+    op: String,
+    skip: usize,
+}
+
+#[derive(Default)]
+struct TokenIndex {
+    /// Lowercased word -> where each occurrence starts
+    words: HashMap<String, HashSet<Anchor>>,
+    /// The word starting at each anchor
+    at: HashMap<Anchor, String>,
+    /// The elements patches have touched since they were last tokenized,
+    /// counted as the text is now
+    dirty: Vec<Range<usize>>,
+    /// Whether patches have removed elements since the last purge, so some
+    /// anchors may be gone from the text
+    removed: bool,
+}
+
+impl TokenIndex {
+    /// Note the edits of a text diff, moving the dirty ranges along with the
+    /// elements they cover
+    fn note(&mut self, seq: &amp::SeqDiff) {
+        for edit in &seq.edits {
+            match edit {
+                amp::DiffEdit::Insert { index } => {
+                    for range in &mut self.dirty {
+                        if range.start >= *index {
+                            range.start += 1;
+                        }
+                        if range.end > *index {
+                            range.end += 1;
+                        }
+                    }
+                    self.dirty.push(*index..index + 1);
+                }
+                amp::DiffEdit::Remove { index } => {
+                    for range in &mut self.dirty {
+                        if range.start > *index {
+                            range.start -= 1;
+                        }
+                        if range.end > *index {
+                            range.end -= 1;
+                        }
+                    }
+                    // Nothing is left of it, but the words either side of
+                    // where it was may now be one
+                    self.dirty.push(*index..*index);
+                    self.removed = true;
+                }
+            }
+        }
+        for index in seq.props.keys() {
+            self.dirty.push(*index..index + 1);
+        }
+    }
+
+    /// Forget the word at `anchor`, if one starts there
+    fn forget(&mut self, anchor: &Anchor) {
+        if let Some(word) = self.at.remove(anchor) {
+            if let Some(anchors) = self.words.get_mut(&word) {
+                anchors.remove(anchor);
+                if anchors.is_empty() {
+                    self.words.remove(&word);
+                }
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct HistoryIndex {
+    /// The text the patches make, with the op ID which inserted each
+    /// character
+    offsets: OffsetIndex,
+    tokens: TokenIndex,
+}
+
+impl HistoryIndex {
+    /// Update the provenance index from a patch and note what the token
+    /// index has to look at again
+    pub fn apply_patch(&mut self, patch: &amp::Patch) {
+        if let Some(amp::Diff::Seq(seq)) = offset_index::text_diff(patch) {
+            self.tokens.note(seq);
+        }
+        self.offsets.apply_patch(patch);
+    }
+
+    pub fn offsets(&self) -> &OffsetIndex {
+        &self.offsets
+    }
+
+    /// Whether there are words which haven't been tokenized since they were
+    /// edited
+    pub fn needs_indexing(&self) -> bool {
+        !self.tokens.dirty.is_empty() || self.tokens.removed
+    }
+
+    /// Tokenize the next `STEP_CHARS` or so of what's been edited,
+    /// returning whether there's more to do
+    pub fn index_step(&mut self) -> bool {
+        let mut dirty = std::mem::take(&mut self.tokens.dirty);
+        dirty.sort_by_key(|range| range.start);
+        let mut merged: Vec<Range<usize>> = Vec::with_capacity(dirty.len());
+        for range in dirty {
+            match merged.last_mut() {
+                Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+                _ => merged.push(range),
+            }
+        }
+        let mut budget = STEP_CHARS;
+        let mut rest = merged.into_iter();
+        while let Some(range) = rest.next() {
+            let start = self.offsets.element_to_char(range.start);
+            let end = self.offsets.element_to_char(range.end).max(start);
+            let (start, end) = self.word_bounds(start, (start + budget).min(end));
+            self.tokenize(start..end);
+            budget = budget.saturating_sub(end - start);
+            // Past any elements with no chars, which there's nothing to
+            // tokenize in
+            let next = self.offsets.char_to_element(end).max(range.start + 1);
+            if next < range.end {
+                self.tokens.dirty.push(next..range.end);
+            }
+            if budget == 0 {
+                break;
+            }
+        }
+        self.tokens.dirty.extend(rest);
+        if self.tokens.dirty.is_empty() && self.tokens.removed {
+            self.purge();
+        }
+        self.needs_indexing()
+    }
+
+    /// Tokenize everything that's waiting to be
+    pub fn index_all(&mut self) {
+        while self.index_step() {}
+    }
+
+    /// The char offsets at which `word` starts, in order, as of the last
+    /// time it was indexed
+    pub fn search(&self, word: &str) -> Vec<usize> {
+        let anchors = match self.tokens.words.get(&word.to_lowercase()) {
+            Some(anchors) => anchors,
+            None => return Vec::new(),
+        };
+        let mut starts: Vec<usize> = anchors.iter()
+            .filter_map(|anchor| Some(self.offsets.span_of(&anchor.op)?.start + anchor.skip))
+            .collect();
+        starts.sort_unstable();
+        starts
+    }
+
+    /// `start..end` widened to take in the whole of any word it's in the
+    /// middle of
+    fn word_bounds(&self, start: usize, end: usize) -> (usize, usize) {
+        let len = self.offsets.len_chars();
+        let word_char = |offset: usize| self.offsets.char_at(offset).map_or(false, char::is_alphanumeric);
+        let mut start = start.min(len);
+        while start > 0 && word_char(start - 1) {
+            start -= 1;
+        }
+        let mut end = end.min(len);
+        while end < len && word_char(end) {
+            end += 1;
+        }
+        (start, end)
+    }
+
+    /// Forget the words starting in `chars`, which starts and ends between
+    /// words, and find them again
+    fn tokenize(&mut self, chars: Range<usize>) {
+        let anchors = self.anchors(chars.clone());
+        for anchor in anchors.iter().flatten() {
+            self.tokens.forget(anchor);
+        }
+        for (offset, word) in tokenize(&self.offsets.slice(chars)) {
+            if let Some(Some(anchor)) = anchors.get(offset) {
+                self.tokens.words.entry(word.clone()).or_insert_with(HashSet::new).insert(anchor.clone());
+                self.tokens.at.insert(anchor.clone(), word);
+            }
+        }
+    }
+
+    /// The anchor of each char in `chars`. The chars the backend hasn't
+    /// answered for have none.
+    fn anchors(&self, chars: Range<usize>) -> Vec<Option<Anchor>> {
+        let first = self.offsets.char_to_element(chars.start);
+        let mut offset = self.offsets.element_to_char(first);
+        let mut anchors = Vec::with_capacity(chars.len());
+        for (op, len) in self.offsets.element_ops(first) {
+            if offset >= chars.end {
+                break;
+            }
+            for skip in 0..len {
+                if (chars.start..chars.end).contains(&(offset + skip)) {
+                    anchors.push(op.map(|op| Anchor { op: op.to_string(), skip }));
+                }
+            }
+            offset += len;
+        }
+        anchors
+    }
+
+    /// Drop the words whose elements have gone
+    fn purge(&mut self) {
+        let gone: Vec<Anchor> = self.tokens.at.keys()
+            .filter(|anchor| self.offsets.span_of(&anchor.op).is_none())
+            .cloned()
+            .collect();
+        for anchor in &gone {
+            self.tokens.forget(anchor);
+        }
+        self.tokens.removed = false;
+    }
+}
+
+/// Split text into lowercase words along with their char offsets
+fn tokenize(text: &str) -> Vec<(usize, String)> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    let mut start = 0;
+    for (offset, c) in text.chars().enumerate() {
+        if c.is_alphanumeric() {
+            if current.is_empty() {
+                start = offset;
+            }
+            current.extend(c.to_lowercase());
+        } else if !current.is_empty() {
+            tokens.push((start, std::mem::take(&mut current)));
+        }
+    }
+    if !current.is_empty() {
+        tokens.push((start, current));
+    }
+    tokens
+}
+
+/// Says who wrote the char under the pointer, in a text view's tooltip
+#[derive(Clone)]
+pub struct Provenance {
+    index: Rc<RefCell<HistoryIndex>>,
+    log: Rc<RefCell<ChangeLog>>,
+    /// Our own actor, whose changes are "you"
+    own_actor: String,
+}
+
+impl Provenance {
+    pub fn new(index: Rc<RefCell<HistoryIndex>>, log: Rc<RefCell<ChangeLog>>, own_actor: String) -> Provenance {
+        Provenance { index, log, own_actor }
+    }
+
+    /// Show the tooltip on `view`
+    pub fn watch(&self, view: &impl IsA<TextView>) {
+        let provenance = self.clone();
+        view.set_has_tooltip(true);
+        view.connect_query_tooltip(move |view, x, y, keyboard, tooltip| {
+            let iter = if keyboard {
+                view.get_buffer().and_then(|buffer| Some(buffer.get_iter_at_mark(&buffer.get_insert()?)))
+            } else {
+                let (x, y) = view.window_to_buffer_coords(TextWindowType::Widget, x, y);
+                view.get_iter_at_location(x, y)
+            };
+            match iter.and_then(|iter| provenance.describe(iter.get_offset() as usize)) {
+                Some(text) => {
+                    tooltip.set_text(Some(&text));
+                    true
+                }
+                None => false,
+            }
+        });
+    }
+
+    /// Who inserted the char at `offset`, and in which change
+    pub fn describe(&self, offset: usize) -> Option<String> {
+        let index = self.index.borrow();
+        let op = index.offsets().op_at(offset)?;
+        let log = self.log.borrow();
+        let change = match log.change_for_op(op) {
+            Some(change) => change,
+            None => {
+                let actor: String = index.offsets().actor_at(offset)?.chars().take(change_log::ACTOR_CHARS).collect();
+                return Some(format!("Inserted by {}", actor));
+            }
+        };
+        let who = if change.actor == self.own_actor { "you".to_string() } else { change.short_actor() };
+        let mut text = format!("Inserted by {} in change {}", who, change.seq);
+        if let Some(time) = change.time_label() {
+            text.push_str(&format!(", {}", time));
+        }
+        if let Some(message) = &change.message {
+            text.push_str(&format!("\n{}", message));
+        }
+        Some(text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use automerge_backend::Backend;
+    use automerge_demo_core::text::{deleted, initial_changes, inserted};
+    use automerge_frontend::{Frontend, LocalChange};
+
+    use super::HistoryIndex;
+    use crate::backend::BackendHandle;
+
+    /// Make `changes` with `frontend`, and apply them to `backend` and the
+    /// patch to `index`
+    fn change(frontend: &mut Frontend, backend: &mut Backend, index: &mut HistoryIndex, changes: Vec<LocalChange>) {
+        let request = frontend
+            .change(None, |doc| {
+                for change in changes {
+                    doc.add_change(change)?;
+                }
+                Ok(())
+            })
+            .unwrap();
+        let (patch, _) = backend.apply_local_change_and_get(request.unwrap()).unwrap();
+        index.apply_patch(&patch);
+    }
+
+    #[test]
+    fn words_follow_edits_once_indexed() {
+        let mut backend = Backend::init();
+        let mut frontend = Frontend::new();
+        let mut index = HistoryIndex::default();
+        change(&mut frontend, &mut backend, &mut index, initial_changes());
+        change(&mut frontend, &mut backend, &mut index, inserted(0, "the cat sat"));
+        assert!(index.needs_indexing());
+        assert!(!index.index_step());
+        assert_eq!(index.search("cat"), vec![4]);
+        assert_eq!(index.search("SAT"), vec![8]);
+
+        // An edit before a word moves it without it being tokenized again
+        change(&mut frontend, &mut backend, &mut index, inserted(0, "a"));
+        assert_eq!(index.search("cat"), vec![5]);
+        index.index_all();
+        assert_eq!(index.search("athe"), vec![0]);
+        assert!(index.search("the").is_empty());
+
+        // Splitting a word, and removing the start of another, are only
+        // found once they've been indexed
+        change(&mut frontend, &mut backend, &mut index, inserted(7, "."));
+        change(&mut frontend, &mut backend, &mut index, deleted(0, 1));
+        assert_eq!(index.offsets().text(), "the ca.t sat");
+        index.index_all();
+        assert!(!index.needs_indexing());
+        assert!(index.search("cat").is_empty());
+        assert!(index.search("athe").is_empty());
+        assert_eq!(index.search("the"), vec![0]);
+        assert_eq!(index.search("ca"), vec![4]);
+        assert_eq!(index.search("t"), vec![7]);
+        assert_eq!(index.search("sat"), vec![9]);
+    }
+}
@@ -0,0 +1,161 @@
+//! `--http-port <port>`, a read only HTTP API for pulling the live document
+//! out of a running instance.
+//!
+//! - `GET /doc` is the whole state as JSON, in the same form as File > Save
+//!   State as JSON (see `snapshot.rs`)
+//! - `GET /text` is the text as plain text
+//! - `GET /changes` is the change history in the same format as File > Save,
+//!   and `GET /changes?since=<heads>` only the changes which came after the
+//!   heads given, as comma separated hex hashes
+//!
+//! Every response has the document's heads in an `X-Automerge-Heads`
+//! header, so a tool can keep up with the document by polling `/changes`
+//! with the heads it got last time.
+//!
+//! The document served is the one websocket peers sync, the one the
+//! instance started with. Requests are answered by the backend thread, from
+//! the backend of one of the document's tabs; `/doc` and `/text` load its
+//! state into a frontend of their own. Like the metrics server this is as
+//! small as an HTTP server can be, one request per connection, one
+//! connection at a time.
+
+use automerge_frontend::Frontend;
+use automerge_protocol as amp;
+use std::convert::TryFrom;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+
+use crate::backend::{BackendCommand, SHARED_DOCUMENT};
+use crate::{auth, doc, file, snapshot};
+
+/// How long to wait for the backend thread, which might be busy applying a
+/// big change
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    heads: Vec<amp::ChangeHash>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn error(status: &'static str, message: &str) -> Response {
+        Response{status, content_type: "text/plain; charset=utf-8", heads: Vec::new(), body: format!("{}\n", message).into_bytes()}
+    }
+}
+
+/// Start serving the document on `port` in a background thread
+pub fn serve(port: u16, commands: crossbeam::Sender<BackendCommand>) -> io::Result<()> {
+    let listener = TcpListener::bind(("0.0.0.0", port))?;
+    tracing::info!("serving the document over HTTP on port {}", port);
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if let Err(e) = respond(stream, &commands) {
+                        tracing::warn!("HTTP request failed: {}", e);
+                    }
+                }
+                Err(e) => tracing::warn!("HTTP accept failed: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+fn respond(stream: TcpStream, commands: &crossbeam::Sender<BackendCommand>) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Read and ignore the rest of the request headers
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 0 && line != "\r\n" {
+        line.clear();
+    }
+    let mut parts = request_line.split_whitespace();
+    let response = match (parts.next(), parts.next()) {
+        (Some("GET"), Some(target)) => get(target, commands),
+        (Some(_), Some(_)) => Response::error("405 Method Not Allowed", "only GET is supported"),
+        _ => Response::error("400 Bad Request", "that isn't an HTTP request"),
+    };
+    let heads: Vec<String> = response.heads.iter().map(|h| auth::hex(&h.0)).collect();
+    let mut stream = stream;
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nX-Automerge-Heads: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        response.content_type,
+        response.body.len(),
+        heads.join(","),
+    )?;
+    stream.write_all(&response.body)?;
+    stream.flush()
+}
+
+fn get(target: &str, commands: &crossbeam::Sender<BackendCommand>) -> Response {
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (target, None),
+    };
+    match path {
+        "/doc" | "/text" => {
+            let (reply, patch) = crossbeam::channel::bounded(1);
+            let patch = match ask(commands, BackendCommand::GetState{document: SHARED_DOCUMENT, reply}, &patch) {
+                Ok(patch) => patch,
+                Err(response) => return response,
+            };
+            let heads = patch.deps.clone();
+            let mut frontend = Frontend::new();
+            if let Err(e) = frontend.apply_patch(patch) {
+                return Response::error("500 Internal Server Error", &format!("could not load the document: {:?}", e));
+            }
+            if path == "/doc" {
+                let json = serde_json::to_vec_pretty(&snapshot::to_json(&frontend.state())).unwrap();
+                Response{status: "200 OK", content_type: "application/json", heads, body: json}
+            } else {
+                Response{status: "200 OK", content_type: "text/plain; charset=utf-8", heads, body: doc::text_value(&frontend).into_bytes()}
+            }
+        }
+        "/changes" => {
+            let since = match query.map(since).unwrap_or(Ok(Vec::new())) {
+                Ok(since) => since,
+                Err(response) => return response,
+            };
+            let (reply, changes) = crossbeam::channel::bounded(1);
+            match ask(commands, BackendCommand::GetChangesSince{document: SHARED_DOCUMENT, heads: since, reply}, &changes) {
+                Ok((changes, heads)) => Response{status: "200 OK", content_type: "application/octet-stream", heads, body: file::encode(&changes)},
+                Err(response) => response,
+            }
+        }
+        _ => Response::error("404 Not Found", "try /doc, /text or /changes"),
+    }
+}
+
+/// Send `command` to the backend thread and wait for its reply on `answer`
+fn ask<T>(commands: &crossbeam::Sender<BackendCommand>, command: BackendCommand, answer: &crossbeam::Receiver<T>) -> Result<T, Response> {
+    if commands.send(command).is_err() {
+        return Err(Response::error("503 Service Unavailable", "the demo is shutting down"));
+    }
+    answer.recv_timeout(REPLY_TIMEOUT)
+        .map_err(|_| Response::error("503 Service Unavailable", "the document isn't available right now"))
+}
+
+/// The heads in a `since=<hash>,<hash>` query
+fn since(query: &str) -> Result<Vec<amp::ChangeHash>, Response> {
+    let value = query.split('&')
+        .find_map(|pair| pair.strip_prefix("since="))
+        .unwrap_or("")
+        .replace("%2C", ",")
+        .replace("%2c", ",");
+    value.split(',')
+        .filter(|h| !h.is_empty())
+        .map(|h| {
+            auth::unhex(h)
+                .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+                .map(amp::ChangeHash)
+                .ok_or_else(|| Response::error("400 Bad Request", &format!("{} isn't a change hash", h)))
+        })
+        .collect()
+}
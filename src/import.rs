@@ -0,0 +1,113 @@
+//! File > Import Text, loading a plain text file into the text at the cursor.
+//!
+//! The whole file goes in as a single change rather than the change per
+//! keystroke typing it in would make. That is still one insert op per
+//! character though, so a big file is a fair amount of work for the frontend
+//! and then the backend, and a dialog shows how it's going. The work is done
+//! in idle callbacks so the dialog gets redrawn in between: first the ops are
+//! built a chunk at a time, see `core/src/import.rs`, then the change is made
+//! in one go, then we wait for the backend to acknowledge it. `cargo bench`
+//! times a 1MB import.
+
+use automerge_demo_core::import::TextImport;
+use std::cell::RefCell;
+use std::path::Path;
+use std::rc::Rc;
+use vgtk::lib::glib;
+use vgtk::lib::gtk::prelude::*;
+use vgtk::lib::gtk::{Label, Orientation, ProgressBar, Window, WindowType};
+
+use crate::doc::Doc;
+
+/// How often we check whether the backend has caught up
+const WAIT_POLL_MS: u32 = 100;
+
+struct Import {
+    doc: Rc<RefCell<Doc>>,
+    import: TextImport,
+    /// Whether there are ops still to build
+    building: bool,
+    message: String,
+    window: Window,
+    label: Label,
+    progress: ProgressBar,
+}
+
+/// Import the file at `path` into `doc`
+pub fn start(doc: Rc<RefCell<Doc>>, path: &Path) {
+    if doc.borrow().read_only() {
+        return;
+    }
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) => {
+            tracing::error!("Could not import {}: {}", path.display(), e);
+            return;
+        }
+    };
+    let name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+    let window = Window::new(WindowType::Toplevel);
+    window.set_title("Importing");
+    window.set_modal(true);
+    window.set_deletable(false);
+    window.set_default_size(360, -1);
+    window.set_border_width(12);
+    let content = vgtk::lib::gtk::Box::new(Orientation::Vertical, 6);
+    let label = Label::new(Some(&format!("Reading {}", name)));
+    label.set_xalign(0.0);
+    let progress = ProgressBar::new();
+    content.add(&label);
+    content.add(&progress);
+    window.add(&content);
+    window.show_all();
+
+    let offset = doc.borrow().cursor_offset();
+    let import = TextImport::new(&text, offset);
+    let mut import = Import {
+        doc,
+        building: !import.is_empty(),
+        import,
+        message: format!("Import {}", name),
+        window,
+        label,
+        progress,
+    };
+    glib::idle_add_local(move || glib::Continue(import.step()));
+}
+
+impl Import {
+    /// Do the next bit of work, returning whether there's more to do in the
+    /// next idle callback
+    fn step(&mut self) -> bool {
+        if self.building {
+            self.building = self.import.step();
+            let (built, len) = (self.import.built(), self.import.len());
+            self.label.set_text(&format!("Building {} of {} characters", built, len));
+            self.progress.set_fraction(built as f64 / len as f64);
+            if !self.building {
+                // Let the dialog say what's happening before we block on it
+                self.label.set_text("Making the change");
+            }
+            return true;
+        }
+        let changes = self.import.take_changes();
+        let imported = self.doc.borrow_mut().import(changes, self.message.clone());
+        if !imported {
+            self.window.close();
+            return false;
+        }
+        self.label.set_text("Waiting for the backend");
+        let doc = self.doc.clone();
+        let window = self.window.clone();
+        let progress = self.progress.clone();
+        glib::timeout_add_local(WAIT_POLL_MS, move || {
+            if doc.borrow().pending_changes() == 0 {
+                window.close();
+                return glib::Continue(false);
+            }
+            progress.pulse();
+            glib::Continue(true)
+        });
+        false
+    }
+}
@@ -0,0 +1,90 @@
+//! `automerge-demo inspect <file>`, what's in a document File > Save wrote,
+//! without opening it in a window: for chasing down why a file won't load,
+//! or loads as something other than expected.
+//!
+//! The changes are loaded into a backend of their own, the same way opening
+//! the file would, and the state the resulting patch builds in a frontend is
+//! printed as JSON, in the same form as File > Save State as JSON (see
+//! `snapshot.rs`), along with the heads and who made the changes. With
+//! `--changes` each change is listed too, in the order the file has them,
+//! which is an order they can be applied in. An encrypted file needs its
+//! passphrase, given with `--passphrase` as there's no window to ask in.
+
+use automerge_backend::Backend;
+use automerge_frontend::Frontend;
+use std::collections::BTreeMap;
+use std::io;
+
+use crate::backend::BackendHandle;
+use crate::change_log::ChangeMeta;
+use crate::cli::InspectArgs;
+use crate::{auth, crypt, file, snapshot};
+
+/// What an actor has done, over all its changes
+#[derive(Default)]
+struct ActorSummary {
+    changes: usize,
+    ops: usize,
+    last_seq: u64,
+}
+
+pub fn run(args: &InspectArgs) -> io::Result<()> {
+    let bytes = std::fs::read(&args.file)?;
+    let changes = if crypt::is_encrypted(&bytes) {
+        let passphrase = args.passphrase.as_deref()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::PermissionDenied, "the file is encrypted, give its --passphrase"))?;
+        let key = crypt::Key::for_file(passphrase, &bytes)?;
+        file::decode(&crypt::decrypt(&key, &bytes)?)?
+    } else {
+        file::decode(&bytes)?
+    };
+    let metas: Vec<ChangeMeta> = changes.iter().map(ChangeMeta::new).collect();
+    let mut backend = Backend::init();
+    // Not through `BackendHandle`, which takes changes which don't apply to
+    // be a bug, a file like that is what this is for
+    let patch = Backend::apply_changes(&mut backend, changes)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("the changes don't apply: {}", e)))?;
+    let mut frontend = Frontend::new();
+    frontend.apply_patch(patch)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("the changes don't make a document: {:?}", e)))?;
+
+    println!("{}: {} changes, {}", args.file.display(), metas.len(), file::human_size(bytes.len() as u64));
+    println!();
+    println!("heads:");
+    for head in BackendHandle::get_heads(&mut backend) {
+        println!("  {}", auth::hex(&head.0));
+    }
+    println!();
+    let mut actors: BTreeMap<&str, ActorSummary> = BTreeMap::new();
+    for meta in &metas {
+        let actor = actors.entry(&meta.actor).or_default();
+        actor.changes += 1;
+        actor.ops += meta.ops;
+        actor.last_seq = actor.last_seq.max(meta.seq);
+    }
+    println!("actors:");
+    for (actor, summary) in &actors {
+        println!("  {}  {} changes up to seq {}, {} ops", actor, summary.changes, summary.last_seq, summary.ops);
+    }
+    println!();
+    println!("value:");
+    println!("{}", serde_json::to_string_pretty(&snapshot::to_json(&frontend.state())).unwrap());
+    if args.changes {
+        println!();
+        println!("changes:");
+        for meta in &metas {
+            println!(
+                "  {}  {} seq {:<4} {:>5} ops {:>7}  {}  {}",
+                &auth::hex(&meta.hash.0)[..12],
+                meta.short_actor(),
+                meta.seq,
+                meta.ops,
+                file::human_size(meta.bytes as u64),
+                meta.time_label().unwrap_or_else(|| "-".to_string()),
+                meta.message.as_deref().unwrap_or(""),
+            );
+        }
+    }
+    Ok(())
+}
+
@@ -0,0 +1,68 @@
+//! Checking that what this demo produces can be read by the JS automerge,
+//! and the other way round.
+//!
+//! Both implementations build the same document, `interop/fixtures/
+//! document.json`, which is in the snapshot format from `snapshot.rs`.
+//! `--interop-save <dir>` builds it from `<dir>/document.json` with the
+//! frontend and backend the demo uses and saves the changes to
+//! `<dir>/rust.changes`. `interop/interop.js` loads those into the JS
+//! automerge and checks it gets the fixture back, then builds the fixture
+//! itself and saves `<dir>/js.changes`, which `--interop-load <dir>` loads and
+//! checks in turn. `tests/interop.rs` runs all three steps. Changes are saved
+//! in the same length prefixed binary format as saved documents.
+
+use automerge_backend::Backend;
+use automerge_frontend::{Frontend, LocalChange, Path as DocPath};
+use std::io;
+use std::path::Path;
+
+use crate::{file, snapshot};
+
+const FIXTURE: &str = "document.json";
+const RUST_CHANGES: &str = "rust.changes";
+const JS_CHANGES: &str = "js.changes";
+
+/// Build the fixture in `dir` and save the changes making it
+pub fn save(dir: &Path) -> io::Result<()> {
+    let root = snapshot::load(&dir.join(FIXTURE))?;
+    let mut frontend = Frontend::new();
+    let mut backend = Backend::init();
+    let cr = frontend
+        .change(Some("Load the fixture".to_string()), |doc| {
+            for (key, value) in &root {
+                doc.add_change(LocalChange::set(DocPath::root().key(key.clone()), value.clone()))?;
+            }
+            Ok(())
+        })
+        .map_err(|e| invalid(format!("could not build the fixture: {:?}", e)))?;
+    if let Some(cr) = cr {
+        let patch = backend.apply_local_change(cr).map_err(|e| invalid(format!("{:?}", e)))?;
+        frontend.apply_patch(patch).map_err(|e| invalid(format!("{:?}", e)))?;
+    }
+    let changes: Vec<_> = backend.get_changes(&[]).into_iter().cloned().collect();
+    file::save(&dir.join(RUST_CHANGES), &changes)
+}
+
+/// Load the changes the JS automerge saved in `dir` and check they make the
+/// fixture
+pub fn load(dir: &Path) -> io::Result<()> {
+    let changes = file::load(&dir.join(JS_CHANGES))?;
+    let mut backend = Backend::init();
+    let mut frontend = Frontend::new();
+    let patch = backend.apply_changes(changes).map_err(|e| invalid(format!("{:?}", e)))?;
+    frontend.apply_patch(patch).map_err(|e| invalid(format!("{:?}", e)))?;
+    let loaded = snapshot::to_json(&frontend.state());
+    let expected: serde_json::Value = serde_json::from_slice(&std::fs::read(dir.join(FIXTURE))?)?;
+    if loaded == expected {
+        Ok(())
+    } else {
+        Err(invalid(format!(
+            "the JS changes don't make the fixture, they make\n{}",
+            serde_json::to_string_pretty(&loaded)?
+        )))
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
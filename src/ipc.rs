@@ -0,0 +1,323 @@
+//! Running each backend in its own process.
+//!
+//! In a real deployment the backend usually lives somewhere other than the
+//! GUI thread - a web worker, a daemon, a separate process. This module lets
+//! the demo mirror that. When started with `--backend-process` the backend
+//! thread doesn't own a `Backend` directly, instead it owns a `BackendProcess`
+//! for each document, which spawns a copy of this executable with
+//! `--backend-worker <socket>` and talks to it over a Unix domain socket.
+//!
+//! Messages are length prefixed JSON. Change requests and patches are sent
+//! as their protocol types, changes are sent in their binary encoding. If
+//! the worker dies or the socket breaks, the `BackendProcess` restarts the
+//! worker and replays the changes it has seen so far before retrying the
+//! request which failed. Those are the changes from peers and the ones our
+//! own requests made, which the worker sends back with each local change's
+//! patch, so a change applied just before a crash isn't lost. A replay the
+//! new worker refuses counts as another failure, rather than carrying on
+//! with a backend missing part of the history.
+
+use automerge_backend::{Backend, Change};
+use automerge_protocol as amp;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::io::{self, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::process::{Child, Command};
+use std::time::Duration;
+
+use crate::backend::BackendHandle;
+
+/// How many times we try to connect to a freshly spawned worker before
+/// giving up
+const CONNECT_ATTEMPTS: usize = 50;
+const CONNECT_INTERVAL: Duration = Duration::from_millis(20);
+/// How many times we restart a worker for a single request before giving up
+const MAX_RESTARTS: usize = 3;
+
+#[derive(Serialize, Deserialize, Debug)]
+enum WorkerRequest {
+    ApplyLocalChange(amp::Request),
+    ApplyChanges(Vec<Vec<u8>>),
+    GetHeads,
+    /// Every change since the given heads, which is all of them if there
+    /// are no heads
+    GetChangesSince(Vec<amp::ChangeHash>),
+    GetPatch,
+    Shutdown,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+enum WorkerResponse {
+    Patch(amp::Patch),
+    /// The patch for a local change, and the change it made
+    LocalChange(amp::Patch, Vec<Vec<u8>>),
+    Changes(Vec<Vec<u8>>),
+    Heads(Vec<amp::ChangeHash>),
+    Error(String),
+}
+
+/// Write a single length prefixed message
+pub fn write_frame<W: Write, T: Serialize>(w: &mut W, msg: &T) -> io::Result<()> {
+    let bytes = serde_json::to_vec(msg)?;
+    w.write_all(&(bytes.len() as u32).to_be_bytes())?;
+    w.write_all(&bytes)?;
+    w.flush()
+}
+
+/// Read a single length prefixed message
+pub fn read_frame<R: Read, T: DeserializeOwned>(r: &mut R) -> io::Result<T> {
+    let mut len = [0u8; 4];
+    r.read_exact(&mut len)?;
+    let mut buf = vec![0u8; u32::from_be_bytes(len) as usize];
+    r.read_exact(&mut buf)?;
+    Ok(serde_json::from_slice(&buf)?)
+}
+
+pub fn encode_changes(changes: &[&Change]) -> Vec<Vec<u8>> {
+    changes.iter().map(|c| c.raw_bytes().to_vec()).collect()
+}
+
+/// The changes `changes` encode, or why one of them can't be decoded
+pub fn decode_changes(changes: Vec<Vec<u8>>) -> Result<Vec<Change>, String> {
+    changes
+        .into_iter()
+        .map(|bytes| Change::from_bytes(bytes).map_err(|e| format!("undecodable change: {}", e)))
+        .collect()
+}
+
+/// The entry point for the child process. Binds the socket, accepts a single
+/// connection from the GUI process and serves requests until the GUI hangs
+/// up or tells us to shut down.
+pub fn run_worker(socket_path: &str) -> io::Result<()> {
+    let _ = std::fs::remove_file(socket_path);
+    let listener = UnixListener::bind(socket_path)?;
+    let (mut stream, _) = listener.accept()?;
+    let mut backend = Backend::init();
+    loop {
+        let request: WorkerRequest = match read_frame(&mut stream) {
+            Ok(r) => r,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        };
+        let response = match request {
+            WorkerRequest::ApplyLocalChange(cr) => {
+                let heads = backend.get_heads();
+                match backend.apply_local_change(cr) {
+                    Ok(patch) => WorkerResponse::LocalChange(patch, encode_changes(&backend.get_changes(&heads))),
+                    Err(e) => WorkerResponse::Error(e.to_string()),
+                }
+            }
+            WorkerRequest::ApplyChanges(changes) => match decode_changes(changes) {
+                Ok(changes) => match backend.apply_changes(changes) {
+                    Ok(patch) => WorkerResponse::Patch(patch),
+                    Err(e) => WorkerResponse::Error(e.to_string()),
+                },
+                Err(e) => WorkerResponse::Error(e),
+            },
+            WorkerRequest::GetHeads => WorkerResponse::Heads(backend.get_heads()),
+            WorkerRequest::GetChangesSince(heads) => {
+                WorkerResponse::Changes(encode_changes(&backend.get_changes(&heads)))
+            }
+            WorkerRequest::GetPatch => match backend.get_patch() {
+                Ok(patch) => WorkerResponse::Patch(patch),
+                Err(e) => WorkerResponse::Error(e.to_string()),
+            },
+            WorkerRequest::Shutdown => break,
+        };
+        write_frame(&mut stream, &response)?;
+    }
+    let _ = std::fs::remove_file(socket_path);
+    Ok(())
+}
+
+/// The GUI side handle to a backend running in a child process
+pub struct BackendProcess {
+    name: String,
+    socket_path: PathBuf,
+    child: Child,
+    stream: UnixStream,
+    /// Every change this backend has applied, ours and our peers', used to
+    /// restore the state of a restarted worker
+    history: Vec<Vec<u8>>,
+}
+
+impl BackendProcess {
+    pub fn spawn(name: &str) -> io::Result<BackendProcess> {
+        let socket_path = std::env::temp_dir().join(format!(
+            "automerge-demo-{}-{}.sock",
+            std::process::id(),
+            name
+        ));
+        let (child, stream) = start_worker(&socket_path)?;
+        Ok(BackendProcess {
+            name: name.to_string(),
+            socket_path,
+            child,
+            stream,
+            history: Vec::new(),
+        })
+    }
+
+    /// Kill the current worker (if it's still alive), start a new one and
+    /// bring it back up to date
+    fn restart(&mut self) -> io::Result<()> {
+        tracing::warn!("restarting backend worker {}", self.name);
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let (child, stream) = start_worker(&self.socket_path)?;
+        self.child = child;
+        self.stream = stream;
+        if !self.history.is_empty() {
+            write_frame(&mut self.stream, &WorkerRequest::ApplyChanges(self.history.clone()))?;
+            match read_frame(&mut self.stream)? {
+                WorkerResponse::Patch(_) => {}
+                WorkerResponse::Error(e) => {
+                    return Err(io::Error::new(io::ErrorKind::Other, format!("replaying the history failed: {}", e)))
+                }
+                other => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unexpected reply to the replay: {:?}", other)))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// After `error`, restart the worker until one has the whole history
+    /// again, counting each attempt in `restarts`
+    fn recover(&mut self, restarts: &mut usize, mut error: io::Error) {
+        loop {
+            if *restarts >= MAX_RESTARTS {
+                panic!("backend worker {} is unrecoverable: {}", self.name, error);
+            }
+            *restarts += 1;
+            match self.restart() {
+                Ok(()) => return,
+                Err(e) => {
+                    tracing::error!("could not restart backend worker {}: {}", self.name, e);
+                    error = e;
+                }
+            }
+        }
+    }
+
+    fn request(&mut self, request: WorkerRequest) -> WorkerResponse {
+        let mut restarts = 0;
+        loop {
+            let result = write_frame(&mut self.stream, &request)
+                .and_then(|_| read_frame(&mut self.stream));
+            match result {
+                Ok(response) => return response,
+                Err(e) => {
+                    tracing::warn!("backend worker {} failed: {}", self.name, e);
+                    self.recover(&mut restarts, e);
+                }
+            }
+        }
+    }
+
+    /// Decode changes the worker sent, treating any it garbled like the
+    /// worker failing, and asking again
+    fn decoded(&mut self, changes: Vec<Vec<u8>>, restarts: &mut usize) -> Option<Vec<Change>> {
+        match decode_changes(changes) {
+            Ok(changes) => Some(changes),
+            Err(e) => {
+                tracing::warn!("backend worker {} sent an {}", self.name, e);
+                self.recover(restarts, io::Error::new(io::ErrorKind::InvalidData, e));
+                None
+            }
+        }
+    }
+
+    fn expect_patch(&mut self, request: WorkerRequest) -> Result<amp::Patch, String> {
+        match self.request(request) {
+            WorkerResponse::Patch(p) => Ok(p),
+            WorkerResponse::Error(e) => Err(e),
+            other => Err(format!("unexpected response from worker {}: {:?}", self.name, other)),
+        }
+    }
+}
+
+impl BackendHandle for BackendProcess {
+    fn apply_local_change(&mut self, request: amp::Request) -> Result<amp::Patch, String> {
+        self.apply_local_change_and_get(request).map(|(patch, _)| patch)
+    }
+
+    fn apply_local_change_and_get(&mut self, request: amp::Request) -> Result<(amp::Patch, Vec<Change>), String> {
+        let mut restarts = 0;
+        loop {
+            match self.request(WorkerRequest::ApplyLocalChange(request.clone())) {
+                WorkerResponse::LocalChange(patch, encoded) => {
+                    let changes = match self.decoded(encoded.clone(), &mut restarts) {
+                        Some(changes) => changes,
+                        None => continue,
+                    };
+                    self.history.extend(encoded);
+                    return Ok((patch, changes));
+                }
+                WorkerResponse::Error(e) => return Err(e),
+                other => panic!("unexpected response from worker {}: {:?}", self.name, other),
+            }
+        }
+    }
+
+    fn apply_changes(&mut self, changes: Vec<Change>) -> Result<amp::Patch, String> {
+        let encoded = encode_changes(&changes.iter().collect::<Vec<_>>());
+        let patch = self.expect_patch(WorkerRequest::ApplyChanges(encoded.clone()))?;
+        // Only what the worker took, so a restart's replay doesn't fail
+        self.history.extend(encoded);
+        Ok(patch)
+    }
+
+    fn get_heads(&mut self) -> Vec<amp::ChangeHash> {
+        match self.request(WorkerRequest::GetHeads) {
+            WorkerResponse::Heads(heads) => heads,
+            other => panic!("unexpected response from worker {}: {:?}", self.name, other),
+        }
+    }
+
+    fn get_changes_since(&mut self, heads: &[amp::ChangeHash]) -> Vec<Change> {
+        let mut restarts = 0;
+        loop {
+            match self.request(WorkerRequest::GetChangesSince(heads.to_vec())) {
+                WorkerResponse::Changes(changes) => {
+                    if let Some(changes) = self.decoded(changes, &mut restarts) {
+                        return changes;
+                    }
+                }
+                other => panic!("unexpected response from worker {}: {:?}", self.name, other),
+            }
+        }
+    }
+
+    fn get_patch(&mut self) -> Result<amp::Patch, String> {
+        self.expect_patch(WorkerRequest::GetPatch)
+    }
+}
+
+impl Drop for BackendProcess {
+    fn drop(&mut self) {
+        let _ = write_frame(&mut self.stream, &WorkerRequest::Shutdown);
+        let _ = self.child.wait();
+        let _ = std::fs::remove_file(&self.socket_path);
+    }
+}
+
+fn start_worker(socket_path: &PathBuf) -> io::Result<(Child, UnixStream)> {
+    let _ = std::fs::remove_file(socket_path);
+    let mut child = Command::new(std::env::current_exe()?)
+        .arg("--backend-worker")
+        .arg(socket_path)
+        .spawn()?;
+    for _ in 0..CONNECT_ATTEMPTS {
+        match UnixStream::connect(socket_path) {
+            Ok(stream) => return Ok((child, stream)),
+            Err(_) => std::thread::sleep(CONNECT_INTERVAL),
+        }
+    }
+    let _ = child.kill();
+    Err(io::Error::new(
+        io::ErrorKind::TimedOut,
+        "backend worker did not open its socket",
+    ))
+}
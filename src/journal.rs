@@ -0,0 +1,258 @@
+//! A write-ahead journal of everything the backends are asked to do, so
+//! that a crash doesn't lose what was typed since the last save.
+//!
+//! Before the backend thread applies a change request, or changes from
+//! another replica or instance, it appends them to the journal, one JSON
+//! entry per line, under the peer whose backend they're for. A peer's
+//! backend starts empty, so the journal says everything it needs to be
+//! rebuilt: replaying a peer's entries in order into a fresh backend
+//! produces exactly the same backend again, requests and all.
+//!
+//! Quitting normally deletes the journal. If the demo starts and finds one,
+//! the last run didn't quit normally: it replays the journal, opens each
+//! document in it again in a tab without a file, with the same id, and says
+//! so. The journal is then kept next to the new one as `journal.recovered`
+//! in case anything went wrong. A torn last line, the entry being written
+//! when the process died, is skipped.
+//!
+//! Once the journal gets bigger than `MAX_LEN` it's rotated: a new one is
+//! written with a checkpoint of every backend's whole history and replaces
+//! the old one, which is far smaller than all the requests that got there.
+//!
+//! `--journal-fsync` says how hard to try to get entries onto the disk:
+//! `always` syncs after every entry, `never` leaves it to the OS, and a
+//! number of milliseconds, the default being `DEFAULT_FSYNC_MS`, syncs at
+//! most that often. `--no-journal` turns the journal off.
+
+use automerge_backend::{Backend, Change};
+use automerge_protocol as amp;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::backend::BackendHandle;
+use crate::file;
+use crate::peer::{DocumentId, PeerId};
+
+/// How big the journal gets before it's rotated
+const MAX_LEN: u64 = 16 * 1024 * 1024;
+pub const DEFAULT_FSYNC_MS: u64 = 1000;
+
+#[derive(Clone, Copy, Debug)]
+pub enum Fsync {
+    Always,
+    Never,
+    Every(Duration),
+}
+
+impl std::str::FromStr for Fsync {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Fsync, String> {
+        match s {
+            "always" => Ok(Fsync::Always),
+            "never" => Ok(Fsync::Never),
+            ms => ms.parse()
+                .map(|ms| Fsync::Every(Duration::from_millis(ms)))
+                .map_err(|_| format!("expected always, never or a number of milliseconds, not {}", ms)),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+enum Entry {
+    /// A backend for `peer`, a replica of document `id`, was created
+    Start{peer: usize, id: DocumentId},
+    /// A change request to be applied to the backend
+    Request{peer: usize, request: amp::Request},
+    /// Changes to be applied to the backend, base64 encoded
+    Changes{peer: usize, changes: Vec<String>},
+    /// The backend was dropped, its tab closed
+    Closed{peer: usize},
+}
+
+/// Where the journal is kept
+pub fn default_path() -> Option<PathBuf> {
+    file::state_path("journal")
+}
+
+pub struct Journal {
+    path: PathBuf,
+    file: File,
+    len: u64,
+    fsync: Fsync,
+    last_sync: Instant,
+}
+
+impl Journal {
+    /// Start a new, empty journal at `path`
+    pub fn create(path: &Path, fsync: Fsync) -> io::Result<Journal> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).write(true).truncate(true).open(path)?;
+        Ok(Journal{path: path.to_path_buf(), file, len: 0, fsync, last_sync: Instant::now()})
+    }
+
+    fn append(&mut self, entry: &Entry) {
+        let mut line = serde_json::to_vec(entry).unwrap();
+        line.push(b'\n');
+        if let Err(e) = self.file.write_all(&line) {
+            tracing::error!("could not write to the journal: {}", e);
+            return;
+        }
+        self.len += line.len() as u64;
+        let sync = match self.fsync {
+            Fsync::Always => true,
+            Fsync::Never => false,
+            Fsync::Every(interval) => self.last_sync.elapsed() >= interval,
+        };
+        if sync {
+            if let Err(e) = self.file.sync_data() {
+                tracing::error!("could not sync the journal: {}", e);
+            }
+            self.last_sync = Instant::now();
+        }
+    }
+
+    pub fn start(&mut self, peer: PeerId, id: DocumentId) {
+        self.append(&Entry::Start{peer: peer.0, id});
+    }
+
+    pub fn request(&mut self, peer: PeerId, request: &amp::Request) {
+        self.append(&Entry::Request{peer: peer.0, request: request.clone()});
+    }
+
+    pub fn changes(&mut self, peer: PeerId, changes: &[Change]) {
+        if !changes.is_empty() {
+            self.append(&Entry::Changes{peer: peer.0, changes: changes.iter().map(|c| base64::encode(c.raw_bytes())).collect()});
+        }
+    }
+
+    pub fn closed(&mut self, peer: PeerId) {
+        self.append(&Entry::Closed{peer: peer.0});
+    }
+
+    pub fn needs_rotation(&self) -> bool {
+        self.len > MAX_LEN
+    }
+
+    /// Replace the journal with one starting from `checkpoint`, the id and
+    /// whole history of every backend
+    pub fn rotate(&mut self, checkpoint: Vec<(PeerId, DocumentId, Vec<Change>)>) {
+        let tmp = file::temp_path(&self.path);
+        let rotated = Journal::create(&tmp, Fsync::Never).and_then(|mut journal| {
+            for (peer, id, changes) in &checkpoint {
+                journal.start(*peer, *id);
+                journal.changes(*peer, changes);
+            }
+            journal.file.sync_all()?;
+            std::fs::rename(&tmp, &self.path)?;
+            Ok(journal)
+        });
+        match rotated {
+            Ok(journal) => {
+                tracing::info!(before = self.len, after = journal.len, "rotated the journal");
+                self.file = journal.file;
+                self.len = journal.len;
+            }
+            Err(e) => tracing::error!("could not rotate the journal: {}", e),
+        }
+    }
+
+    /// We're quitting cleanly, so there's nothing to recover
+    pub fn finish(self) {
+        drop(self.file);
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            tracing::warn!("could not remove the journal: {}", e);
+        }
+        let _ = std::fs::remove_file(self.path.with_extension("pid"));
+    }
+}
+
+/// Replay the journal left at `path` by a run which didn't quit cleanly,
+/// returning each document in it which was still open, and keep it as
+/// `journal.recovered`. Nothing if there's no journal.
+pub fn recover(path: &Path) -> io::Result<Vec<(DocumentId, Vec<Change>)>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut backends: BTreeMap<usize, (DocumentId, Backend)> = BTreeMap::new();
+    let mut skipped = 0;
+    for line in BufReader::new(file).lines() {
+        let entry: Entry = match serde_json::from_str(&line?) {
+            Ok(entry) => entry,
+            Err(_) => {
+                skipped += 1;
+                continue;
+            }
+        };
+        match entry {
+            Entry::Start{peer, id} => {
+                backends.insert(peer, (id, Backend::init()));
+            }
+            Entry::Request{peer, request} => {
+                if let Some((_, backend)) = backends.get_mut(&peer) {
+                    if BackendHandle::apply_local_change(backend, request).is_err() {
+                        skipped += 1;
+                    }
+                }
+            }
+            Entry::Changes{peer, changes} => {
+                let changes: Vec<Change> = changes.iter()
+                    .filter_map(|c| base64::decode(c).ok())
+                    .filter_map(|bytes| Change::from_bytes(bytes).ok())
+                    .collect();
+                if let Some((_, backend)) = backends.get_mut(&peer) {
+                    if Backend::apply_changes(backend, changes).is_err() {
+                        skipped += 1;
+                    }
+                }
+            }
+            Entry::Closed{peer} => {
+                backends.remove(&peer);
+            }
+        }
+    }
+    if skipped > 0 {
+        tracing::warn!(skipped, "some of the journal couldn't be replayed");
+    }
+    // Replicas of a document have the same changes, but one might be a
+    // change or two behind, so take all of them
+    let mut documents: Vec<(DocumentId, Vec<Change>)> = Vec::new();
+    for (_, (id, mut backend)) in backends {
+        let changes = BackendHandle::get_changes(&mut backend);
+        match documents.iter_mut().find(|(d, _)| *d == id) {
+            Some((_, existing)) => {
+                let have: HashSet<amp::ChangeHash> = existing.iter().map(|c| c.hash).collect();
+                existing.extend(changes.into_iter().filter(|c| !have.contains(&c.hash)));
+            }
+            None => documents.push((id, changes)),
+        }
+    }
+    documents.retain(|(_, changes)| !changes.is_empty());
+    std::fs::rename(path, path.with_extension("recovered"))?;
+    Ok(documents)
+}
+
+/// Whether another instance is running with the journal at `path`, going
+/// by the pid it left next to it. If not, ours is left there instead.
+pub fn claim(path: &Path) -> io::Result<bool> {
+    let pid_path = path.with_extension("pid");
+    if let Ok(pid) = std::fs::read_to_string(&pid_path) {
+        let pid = pid.trim();
+        if !pid.is_empty() && pid != std::process::id().to_string() && Path::new("/proc").join(pid).exists() {
+            return Ok(false);
+        }
+    }
+    if let Some(dir) = pid_path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(pid_path, std::process::id().to_string())?;
+    Ok(true)
+}
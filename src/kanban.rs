@@ -0,0 +1,171 @@
+//! A kanban board stored in the document at `root.kanban`, a list of columns
+//! each containing a list of cards
+//!
+//! ```text
+//! [{ "title": "To do", "cards": [{ "title": "Write docs" }, ...] }, ...]
+//! ```
+//!
+//! Dragging a card from one column to another is a delete from the source
+//! list followed by an insert into the destination list, because automerge
+//! has no move operation. This has some quirks which the board makes easy to
+//! see: if two windows concurrently move the same card to different columns
+//! both inserts succeed and the card is duplicated, and an edit to a card
+//! concurrent with it being moved is applied to the deleted original and
+//! lost.
+
+use automerge_frontend::{Frontend, LocalChange, Path, Value};
+use automerge_protocol as amp;
+use maplit::hashmap;
+use vgtk::lib::gtk::{TargetEntry, TargetFlags};
+
+/// The drag and drop target used for cards
+pub const CARD_TARGET: &str = "automerge-demo/kanban-card";
+
+const INITIAL_COLUMNS: [&str; 3] = ["To do", "Doing", "Done"];
+
+#[derive(Clone, Debug)]
+pub struct Column {
+    pub title: String,
+    pub cards: Vec<String>,
+}
+
+#[derive(Clone, Debug)]
+pub enum KanbanAction {
+    AddCard { column: usize, title: String },
+    MoveCard { from_column: usize, index: usize, to_column: usize },
+    DeleteCard { column: usize, index: usize },
+}
+
+impl KanbanAction {
+    /// The message for the change which makes this action
+    fn message(&self) -> String {
+        match self {
+            KanbanAction::AddCard { title, .. } => format!("Add card \"{}\"", title),
+            KanbanAction::MoveCard { from_column, index, to_column } => {
+                format!("Move card {} from column {} to {}", index + 1, from_column + 1, to_column + 1)
+            }
+            KanbanAction::DeleteCard { column, index } => format!("Delete card {} from column {}", index + 1, column + 1),
+        }
+    }
+}
+
+fn path() -> Path {
+    Path::root().key("kanban")
+}
+
+fn cards_path(column: usize) -> Path {
+    path().index(column).key("cards")
+}
+
+fn str_value(s: &str) -> Value {
+    Value::Primitive(amp::Value::Str(s.to_string()))
+}
+
+fn card_value(title: &str) -> Value {
+    Value::Map(hashmap! {"title".to_string() => str_value(title)}, amp::MapType::Map)
+}
+
+/// The value to initialize `root.kanban` with
+pub fn initial_value() -> Value {
+    Value::Sequence(
+        INITIAL_COLUMNS
+            .iter()
+            .map(|title| {
+                Value::Map(
+                    hashmap! {
+                        "title".to_string() => str_value(title),
+                        "cards".to_string() => Value::Sequence(Vec::new(), amp::SequenceType::List),
+                    },
+                    amp::MapType::Map,
+                )
+            })
+            .collect(),
+        amp::SequenceType::List,
+    )
+}
+
+fn title_of(value: &Value) -> String {
+    match value {
+        Value::Map(props, _) => match props.get("title") {
+            Some(Value::Primitive(amp::Value::Str(s))) => s.clone(),
+            _ => String::new(),
+        },
+        _ => String::new(),
+    }
+}
+
+/// Read the board out of the frontend
+pub fn columns(frontend: &Frontend) -> Vec<Column> {
+    match frontend.get_value(&path()) {
+        Some(Value::Sequence(columns, _)) => columns
+            .iter()
+            .map(|column| Column {
+                title: title_of(column),
+                cards: match column {
+                    Value::Map(props, _) => match props.get("cards") {
+                        Some(Value::Sequence(cards, _)) => cards.iter().map(title_of).collect(),
+                        _ => Vec::new(),
+                    },
+                    _ => Vec::new(),
+                },
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// The drag and drop targets for cards, used for both sources and
+/// destinations
+pub fn drag_targets() -> Vec<TargetEntry> {
+    vec![TargetEntry::new(CARD_TARGET, TargetFlags::SAME_APP, 0)]
+}
+
+/// Encode the card being dragged as drag and drop selection data
+pub fn encode_drag(column: usize, index: usize) -> String {
+    format!("{}:{}", column, index)
+}
+
+pub fn decode_drag(data: &str) -> Option<(usize, usize)> {
+    let mut parts = data.splitn(2, ':');
+    let column = parts.next()?.parse().ok()?;
+    let index = parts.next()?.parse().ok()?;
+    Some((column, index))
+}
+
+/// Apply a kanban action to the frontend, returning the change request to
+/// send to the backend
+pub fn apply(frontend: &mut Frontend, action: KanbanAction) -> Option<amp::Request> {
+    let columns = columns(frontend);
+    let message = action.message();
+    let changes = match action {
+        KanbanAction::AddCard { column, title } => match columns.get(column) {
+            Some(c) if !title.trim().is_empty() => {
+                vec![LocalChange::insert(cards_path(column).index(c.cards.len()), card_value(&title))]
+            }
+            _ => return None,
+        },
+        KanbanAction::MoveCard { from_column, index, to_column } => {
+            let title = columns.get(from_column)?.cards.get(index)?;
+            let destination = columns.get(to_column)?;
+            if from_column == to_column {
+                return None;
+            }
+            vec![
+                LocalChange::delete(cards_path(from_column).index(index)),
+                LocalChange::insert(cards_path(to_column).index(destination.cards.len()), card_value(title)),
+            ]
+        }
+        KanbanAction::DeleteCard { column, index } => {
+            columns.get(column)?.cards.get(index)?;
+            vec![LocalChange::delete(cards_path(column).index(index))]
+        }
+    };
+    frontend
+        .change(Some(message), |doc| {
+            for change in &changes {
+                doc.add_change(change.clone())?;
+            }
+            Ok(())
+        })
+        .unwrap()
+}
@@ -1,332 +1,2391 @@
-//! This is a simple demo app to start testing the automerge-rs library. In
-//! order to understand the following you should be familiar with automerge
-//! and in particular with the reason for the backend/frontend split. 
+//! This is a demo app for testing the automerge-rs library. In order to
+//! understand the following you should be familiar with automerge and in
+//! particular with the reason for the backend/frontend split.
 //!
-//! In this example I'm interested in seeing how hard the automerge-rs API is
-//! to use for GUI applications doing real time text editing. I am not
-//! interested in understanding how network and storage will be integrated, as
-//! such this application starts two windows, each one of which has its own
-//! instance of the frontend, and communicates via crossbeam channels with
-//! its own instance of the backend. Each window immediately applies changes on
-//! its frontend, then sends the resulting change request to a 
-//! crossbeam::Sender<automerge_protocol::Sender> channel. A separate thread
-//! pulls change requests out of the other end of those channels, applies them
-//! to each of the backends, then sends the corresponding patches back to the
-//! frontend via a vgtk scope.
+//! It started out as a way to see how hard the automerge-rs API is to use
+//! for GUI applications doing real time text editing: a window with two tabs
+//! editing the same document, each with its own frontend. Each tab
+//! immediately applies changes on its frontend, then sends the resulting
+//! change request down a crossbeam channel. The backend thread, see
+//! `backend.rs`, pulls change requests out of the other end of those
+//! channels, applies them to the document's backend, see
+//! `core/src/engine.rs`, then sends the corresponding patches back to the
+//! frontends over a glib channel, see `bridge.rs`. More tabs, each with a
+//! document of its own, can be opened from the File menu.
+//!
+//! The backend thread is also where the network and storage come in. Every
+//! build syncs with other instances over websockets, see `ws.rs` and
+//! `transport.rs`, or through a relay, see `relay.rs`, and saves to files,
+//! see `file.rs` and `journal.rs`. The heavier parts are cargo features, see
+//! "Features" in the README: `net` for WebRTC calls and mDNS discovery,
+//! `libp2p` for gossipsub sync, `storage` for the sled and SQLite stores,
+//! `metrics` for the Prometheus endpoint, `scripting` for the Rhai console
+//! and `sourceview` for syntax highlighting.
 
 #![recursion_limit = "512"]
 use vgtk::ext::*;
 use vgtk::lib::gio::{ApplicationFlags, prelude::ApplicationExtManual};
+use vgtk::lib::glib;
 use vgtk::lib::gtk::*;
-use vgtk::lib::gtk::prelude::TextBufferExtManual;
-use vgtk::lib::glib::{SignalHandlerId, ObjectExt};
-use vgtk::{gtk, start, Component, UpdateAction, VNode, Callback};
-use automerge_frontend::{Frontend, LocalChange, Path, Value};
-use automerge_backend::Backend;
+use vgtk::{gtk, start, Component, UpdateAction, VNode};
+use clap::Parser;
+use automerge_backend::{Backend, Change};
 use automerge_protocol as amp;
 use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+
+mod attach;
+mod auth;
+mod backend;
+mod bench;
+mod blame;
+mod bridge;
+mod change_log;
+mod chaos;
+mod chat;
+mod checklist;
+mod cli;
+mod clipboard;
+mod compare_view;
+mod compose;
+mod convergence;
+mod crypt;
+#[cfg(feature = "storage")]
+mod databases;
+mod dbus;
+mod desktop;
+mod diff;
+mod discovery;
+mod doc;
+mod doc_view;
+mod event_log;
+mod export;
+mod file;
+mod find;
+#[cfg(test)]
+mod golden;
+mod growth;
+mod history_index;
+mod http;
+mod import;
+mod inspect;
+mod interop;
+mod journal;
+mod ipc;
+mod kanban;
+mod markdown;
+mod marks;
+mod metrics;
+mod notifications;
+mod offset_index;
+#[cfg(feature = "libp2p")]
+mod p2p;
+mod patch_log;
+mod peer;
+mod pipeline;
+mod preferences_view;
+mod presence;
+#[cfg(feature = "metrics")]
+mod prometheus;
+mod relay;
+mod render;
+mod schema;
+#[cfg(feature = "scripting")]
+mod script;
+#[cfg(feature = "scripting")]
+mod script_view;
+mod session;
+mod settings;
+mod shortcuts;
+mod signing;
+mod simulate;
+mod size;
+mod snapshot;
+mod spellcheck;
+mod splice;
+mod state;
+mod stats;
+mod stats_view;
+mod storage;
+mod stress;
+mod subscriptions;
+mod suggestion;
+mod sync_filter;
+mod syntax;
+mod table;
+mod telemetry;
+mod text_cache;
+mod text_patch;
+mod theme;
+mod title;
+mod tls;
+mod transport;
+mod undo;
+#[cfg(feature = "unified")]
+mod unified;
+mod watch;
+#[cfg(feature = "net")]
+mod webrtc;
+mod workspace;
+mod ws;
+
+use backend::{BackendCommand, BackendConfig, HistoryFor, PeerStart, SHARED_DOCUMENT};
+use change_log::ChangeMeta;
+use chaos::{Chaos, ChaosConfig};
+use doc::Doc;
+use compare_view::CompareView;
+use stats_view::StatsView;
+use preferences_view::PreferencesView;
+#[cfg(feature = "scripting")]
+use script_view::ScriptView;
+use doc_view::DocView;
+use peer::{DocRegistry, DocumentId, PatchEnvelope, PeerId};
+use storage::Storage;
+use transport::Transport;
+
+#[derive(Default)]
+pub struct Model {
+    docs: DocRegistry,
+    /// The peer whose document each peer is a replica of, tabs of the same
+    /// document are kept in sync with each other
+    documents: BTreeMap<PeerId, PeerId>,
+    /// The index of the tab being shown
+    current: usize,
+    next_peer: usize,
+    peers: Vec<discovery::Peer>,
+    /// The state of every connection we've made to another instance
+    connections: BTreeMap<SocketAddr, ws::ConnectionStatus>,
+    /// The latest clients which failed to authenticate with our server, and
+    /// why
+    rejected: Vec<(SocketAddr, String)>,
+    /// What we authenticate with when connecting to other instances
+    auth: Arc<auth::Auth>,
+    presence: presence::PresenceMap,
+    /// libp2p peers syncing the shared document, keyed by peer id
+    network_presence: presence::PresenceMap,
+    /// Tabs in other instances, keyed by actor id, see `presence.rs`
+    remote_presence: presence::PresenceMap,
+    /// The last presence state sent for each of our tabs, and when
+    sent_presence: HashMap<String, (presence::PresenceState, std::time::Instant)>,
+    presence_seq: u64,
+    /// The id of the document the libp2p node is syncing
+    p2p_document: Option<String>,
+    /// The sending end of the presence channel, given to each new doc
+    presence_sx: Option<crossbeam::Sender<presence::PresenceEvent>>,
+    /// The receiving end of the presence channel the docs send heartbeats on
+    presence_rx: Option<crossbeam::Receiver<presence::PresenceEvent>>,
+    /// Used to hand new websocket connections to the backend thread
+    ws_events: Option<crossbeam::Sender<ws::WsEvent>>,
+    /// Requests for the backend thread which aren't change requests
+    commands: Option<crossbeam::Sender<BackendCommand>>,
+    metrics: Option<Arc<metrics::Metrics>>,
+    /// The signatures of changes, with `--sign-key`
+    signatures: Option<Arc<signing::Signatures>>,
+    /// The preferences from `settings.toml`
+    settings: settings::Settings,
+    /// Whether the Preferences window is open
+    preferences: bool,
+    /// Puts renders off until the next frame, once the window is up
+    renders: Option<render::Scheduler>,
+    /// Runs the script console's scripts, and the console, once it has been
+    /// opened
+    #[cfg(feature = "scripting")]
+    scripts: Option<script::Runner>,
+    #[cfg(feature = "scripting")]
+    script: Option<script::Console>,
+    /// Whether the script console is open
+    #[cfg(feature = "scripting")]
+    script_console: bool,
+    /// When the tabs were last autosaved, and the heads of each one's
+    /// document then
+    last_autosave: Option<std::time::Instant>,
+    autosaved: BTreeMap<PeerId, Vec<String>>,
+    /// The typing rate for `--stress` mode, and the typist in each tab
+    stress: Option<f64>,
+    typists: BTreeMap<PeerId, glib::SourceId>,
+    coalesce: Option<Duration>,
+    /// The fields new documents are created with, from `--schema`
+    schema: Arc<schema::Schema>,
+    recorder: Option<session::Recorder>,
+    /// Whether to save every document which has a file on the way out, from
+    /// `--save-on-exit`
+    save_on_exit: bool,
+    /// The key for each encrypted file, once we've had its passphrase
+    keys: HashMap<PathBuf, crypt::Key>,
+    /// Watches the tabs' files for other programs changing them
+    watcher: Option<watch::FileWatcher>,
+    exited: bool,
+    /// The toast showing and the notification center's recent events, see
+    /// `notifications.rs`
+    notifications: notifications::Notifications,
+    /// Tells the desktop about remote edits while the window isn't focused
+    desktop: desktop::DesktopNotifier,
+    /// The history shown in the compare window, if it's open
+    compare: Option<Rc<Vec<Change>>>,
+    /// What the statistics window shows, if it's open
+    stats: Option<(Rc<Vec<stats::ActorStats>>, Rc<size::SizeReport>)>,
+    /// Our connection to the session bus with `--dbus`, to send signals on
+    dbus: Option<dbus::Handle>,
+    /// Whether to save the workspace on the way out
+    keep_workspace: bool,
+    /// The window's size, as last allocated
+    window_size: Option<(i32, i32)>,
+}
 
-/// A wrapper around the state of the frontend, this is passed to DocView as a
-/// property. 
-struct Doc {
-    frontend: Rc<RefCell<Frontend>>,
-    buffer: TextBuffer,
-    /// We need these two signal handlers to block the signals when updating
-    /// the text based on diffs received from the backend
-    insert_text_sigid: SignalHandlerId,
-    del_sig_id: SignalHandlerId,
-    /// This is the channel we use to send new changes to the backend
-    sx: crossbeam::Sender<amp::Request>,
+
+#[derive(Clone, Debug)]
+pub enum Message {
+    Exit,
+    /// For signal handlers which don't need to tell the model anything
+    Noop,
+    /// A frame is due after something asked for a render, see `render.rs`
+    Frame,
+    /// One of the backend thread's messages, which the bridge is told has
+    /// been handled before it hands over the next, see `bridge.rs`
+    Bridged(Box<Message>, bridge::Processed),
+    /// Fired once the backend thread has started and we can create docs
+    Initialized{
+        ws_events: crossbeam::Sender<ws::WsEvent>,
+        commands: crossbeam::Sender<BackendCommand>,
+        /// The file we were started with `--open`
+        opened: Option<PathBuf>,
+        /// The event log we were started with `--replay`
+        replay: Option<PathBuf>,
+        /// Where to record input to with `--record-session`
+        record_session: Option<PathBuf>,
+        metrics: Arc<metrics::Metrics>,
+        signatures: Option<Arc<signing::Signatures>>,
+        /// The typing rate for `--stress` mode
+        stress: Option<f64>,
+        /// How long to batch up keystrokes for with `--coalesce-ms`
+        coalesce: Option<Duration>,
+        /// The schema we were started with `--schema`, or the default one
+        schema: Arc<schema::Schema>,
+        save_on_exit: bool,
+        /// The server to connect to from `--connect`, and how to trust it
+        /// if it's over TLS
+        connect: Option<(SocketAddr, Option<tls::Trust>)>,
+        auth: Arc<auth::Auth>,
+        dbus: Option<dbus::Handle>,
+        /// The documents in `--storage`, to open again
+        stored: Vec<(DocumentId, Vec<Change>)>,
+        /// The documents recovered from the journal after a crash, which
+        /// are opened instead of anything else
+        recovered: Vec<(DocumentId, Vec<Change>)>,
+        watcher: Option<watch::FileWatcher>,
+        /// The workspace to restore, if we're starting without a document
+        workspace: Option<workspace::Workspace>,
+        keep_workspace: bool,
+        settings: settings::Settings,
+        renders: render::Scheduler,
+        #[cfg(feature = "scripting")]
+        scripts: script::Runner,
+    },
+    /// Pushed into the application scope by the backend thread for each new
+    /// patch
+    Patch(PatchEnvelope),
+    /// Patches which arrived within a frame of each other, in the order
+    /// they were sent, see `bridge.rs`
+    Patches(Vec<PatchEnvelope>),
+    /// The whole state of a backend, for its doc to resync from after they
+    /// stopped agreeing
+    Resync(PatchEnvelope),
+    /// The window has been resized
+    WindowResized(i32, i32),
+    /// Another instance has started syncing a document we don't have with
+    /// us
+    RemoteDocument(DocumentId),
+    /// A method call from a script over D-Bus
+    Control(dbus::Call),
+    /// Hide the toast
+    DismissToast,
+    /// The notification center was opened, or its Clear button clicked
+    ReadNotifications,
+    ClearNotifications,
+    /// The backend thread merged a batch of at least `NOTIFY_MERGED` changes
+    /// from another instance, the server we connected to if it's one
+    RemoteChangesMerged{server: Option<SocketAddr>, count: usize},
+    /// The backend thread's reply to `BackendCommand::GetHistory`
+    History(HistoryFor, Vec<Change>),
+    /// Another instance was found on the local network
+    PeerDiscovered(discovery::Peer),
+    /// A previously discovered instance went away, identified by name
+    PeerLost(String),
+    /// The user asked to sync with a discovered peer
+    ConnectPeer(SocketAddr),
+    /// The connection to a server we connected to has changed state
+    ConnectionStatus(SocketAddr, ws::ConnectionStatus),
+    /// Ask the user whether to trust a server's certificate and send the
+    /// answer back to the connecting thread
+    VerifyPeer{server: SocketAddr, fingerprint: String, reply: crossbeam::Sender<bool>},
+    /// A client failed to authenticate with our server
+    PeerRejected(SocketAddr, String),
+    /// The libp2p node has joined the topic for this document
+    P2pAnnounced(String),
+    /// A libp2p peer joined, left or sent something
+    NetworkPeer{id: String, present: bool},
+    /// A tab in another instance told us what it's doing
+    RemotePresence(presence::PresenceState),
+    /// Make a WebRTC offer for another instance to answer
+    StartCall,
+    /// Ask for another instance's WebRTC offer and answer it
+    AnswerCall,
+    /// Our offer or answer is ready to be shown, see `ws::WsEvent::Signal`
+    CallSignal{description: String, reply: Option<crossbeam::Sender<String>>},
+    /// A tab changed its display name or color
+    IdentityChanged(presence::Identity),
+    /// Sent periodically so we can process heartbeats and notice idle peers
+    PresenceTick,
+    /// Sent every second to take a metrics sample
+    MetricsTick,
+    /// The next input from the session being played with `--play-session`
+    Play(session::SessionEvent),
+    /// The user switched to the `n`th tab
+    SwitchTab(usize),
+    /// Open a tab with a new, empty document
+    NewDocument,
+    /// Open a saved document in a new tab
+    Open,
+    CloseTab,
+    Save,
+    SaveAs,
+    /// Write the current tab's history out as a JSON event log
+    ExportHistory,
+    /// Encrypt the current document's file from now on
+    Encrypt,
+    /// Compact the current document's file, and its store
+    Compact,
+    /// The backend thread compacted a document, and this is how it went
+    Compacted(PeerId, String),
+    /// Compare the heads of every replica, see `convergence.rs`
+    VerifyConvergence,
+    /// The backend thread's reply to `BackendCommand::GetHeads`, the
+    /// document and heads of each peer's backend
+    Heads(Vec<(PeerId, PeerId, Vec<amp::ChangeHash>)>),
+    /// Another program, or we, wrote to this file of one of the tabs
+    FileChanged(PathBuf),
+    /// The backend thread merged `count` new changes from `path`
+    FileMerged{path: PathBuf, count: usize},
+    /// Insert a plain text file at the cursor in the current tab
+    ImportText,
+    /// Write the current tab's document out as Markdown, HTML or plain text
+    Export(export::Format),
+    /// Dump the current tab's whole document state as JSON
+    SaveSnapshot,
+    /// Open a tab with a new document initialized from a JSON snapshot
+    NewFromSnapshot,
+    Undo,
+    Redo,
+    /// Add one to, or take one from, the current tab's counter
+    IncrementCounter,
+    DecrementCounter,
+    /// Start sending the current tab's edits to a branch, see
+    /// `suggestion.rs`
+    Suggest,
+    /// Accept or reject the suggestions in a tab
+    ResolveSuggestions(PeerId, bool),
+    /// The backend thread merged a tab's suggestions, this many changes
+    SuggestionsAccepted(usize),
+    /// The backend thread dropped a tab's suggestions, this is the state
+    /// without them
+    SuggestionsRejected(PatchEnvelope),
+    /// Show or hide the find bar in the current tab
+    Find,
+    TogglePatchLog,
+    ToggleUndoHistory,
+    /// Open the compare window for the current tab's history
+    Compare,
+    CloseCompare,
+    /// Open the statistics window for the current tab's history
+    Statistics,
+    CloseStatistics,
+    /// Show the current tab's text in two views of the same buffer
+    ToggleSplit,
+    /// Number the lines of the current tab's text
+    ToggleLineNumbers,
+    /// Show who last changed each line of the current tab's text
+    ToggleBlame,
+    /// Show the current tab's text rendered as Markdown
+    TogglePreview,
+    /// Underline misspelled words in the current tab's text
+    ToggleSpellcheck,
+    ToggleDarkMode,
+    /// Open the Preferences window
+    Preferences,
+    ClosePreferences,
+    /// A setting was changed in the Preferences window, these are all of
+    /// them now
+    SettingsChanged(settings::Settings),
+    /// Open the script console
+    ScriptConsole,
+    CloseScriptConsole,
+    /// Run the console's script on the current tab
+    RunScript,
+    StopScript,
+    ClearScriptOutput,
+    /// Something the running script wants done
+    #[cfg(feature = "scripting")]
+    Script(script::Call),
 }
 
+impl Component for Model {
+    type Message = Message;
+    type Properties = ();
+
+    fn update(&mut self, msg: Self::Message) -> UpdateAction<Self> {
+        let frame = matches!(msg, Message::Frame);
+        match self.handle(msg) {
+            // Rendered on the next frame, along with whatever else asks
+            // before then
+            UpdateAction::Render if !frame && self.renders.as_ref().map_or(false, render::Scheduler::request) => UpdateAction::None,
+            action => action,
+        }
+    }
+
+    fn view(&self) -> VNode<Model> {
+        let current = self.current_doc().map(|(_, d)| d);
+        let can_undo = current.as_ref().map(|d| d.borrow().can_undo()).unwrap_or(false);
+        let can_redo = current.as_ref().map(|d| d.borrow().can_redo()).unwrap_or(false);
+        let can_edit = current.as_ref().map(|d| !d.borrow().read_only()).unwrap_or(false);
+        let can_count = can_edit && current.as_ref().map(|d| d.borrow().has_counter()).unwrap_or(false);
+        let can_spellcheck = current.as_ref().map(|d| d.borrow().spellcheck_available()).unwrap_or(false);
+        let can_suggest = current.as_ref().map(|d| !d.borrow().suggesting()).unwrap_or(false) && can_edit;
+        // An attached window has no backends of its own to open documents
+        // in or ask for the history
+        let local = !attach::attached();
+        let has_backend = local && current.is_some();
+        // Otherwise GTK would hand us over to the instance we're attaching to
+        let flags = if local { ApplicationFlags::empty() } else { ApplicationFlags::NON_UNIQUE };
+        let title = match &current {
+            Some(doc) => self.tab_label(&doc.borrow()),
+            None => "Initializing".to_string(),
+        };
+        let (width, height) = self.window_size.unwrap_or((1000, 600));
+        gtk! {
+            <Application::new_unwrap(Some("com.example.automerge-demo"), flags)>
+                <ApplicationWindow title=title.clone() default_width=width default_height=height on destroy=|_| Message::Exit
+                    on size_allocate=|w, _| {
+                        let (width, height) = w.get_size();
+                        Message::WindowResized(width, height)
+                    }
+                    on realize=|w| {
+                        shortcuts::install(w);
+                        desktop::install(w);
+                        Message::Noop
+                    }>
+                    <SimpleAction::new("new", None) enabled=local on activate=|_, _| Message::NewDocument />
+                    <SimpleAction::new("open", None) enabled=local on activate=|_, _| Message::Open />
+                    <SimpleAction::new("save", None) enabled=has_backend on activate=|_, _| Message::Save />
+                    <SimpleAction::new("save-as", None) enabled=has_backend on activate=|_, _| Message::SaveAs />
+                    <SimpleAction::new("import-text", None) enabled=can_edit on activate=|_, _| Message::ImportText />
+                    <SimpleAction::new("export-markdown", None) enabled=current.is_some() on activate=|_, _| Message::Export(export::Format::Markdown) />
+                    <SimpleAction::new("export-html", None) enabled=current.is_some() on activate=|_, _| Message::Export(export::Format::Html) />
+                    <SimpleAction::new("export-text", None) enabled=current.is_some() on activate=|_, _| Message::Export(export::Format::PlainText) />
+                    <SimpleAction::new("save-snapshot", None) enabled=current.is_some() on activate=|_, _| Message::SaveSnapshot />
+                    <SimpleAction::new("new-from-snapshot", None) enabled=local on activate=|_, _| Message::NewFromSnapshot />
+                    <SimpleAction::new("export-history", None) enabled=has_backend on activate=|_, _| Message::ExportHistory />
+                    <SimpleAction::new("encrypt", None) enabled=has_backend on activate=|_, _| Message::Encrypt />
+                    <SimpleAction::new("compact", None) enabled=has_backend on activate=|_, _| Message::Compact />
+                    <SimpleAction::new("verify", None) enabled=has_backend on activate=|_, _| Message::VerifyConvergence />
+                    <SimpleAction::new("start-call", None) enabled={cfg!(feature = "net") && local && self.ws_events.is_some()} on activate=|_, _| Message::StartCall />
+                    <SimpleAction::new("answer-call", None) enabled={cfg!(feature = "net") && local && self.ws_events.is_some()} on activate=|_, _| Message::AnswerCall />
+                    <SimpleAction::new("close-tab", None) enabled={self.docs.len() > 1} on activate=|_, _| Message::CloseTab />
+                    <SimpleAction::new("quit", None) enabled=true on activate=|_, _| Message::Exit />
+                    <SimpleAction::new("undo", None) enabled=can_undo on activate=|_, _| Message::Undo />
+                    <SimpleAction::new("redo", None) enabled=can_redo on activate=|_, _| Message::Redo />
+                    <SimpleAction::new("increment", None) enabled=can_count on activate=|_, _| Message::IncrementCounter />
+                    <SimpleAction::new("decrement", None) enabled=can_count on activate=|_, _| Message::DecrementCounter />
+                    <SimpleAction::new("suggest", None) enabled={has_backend && can_suggest} on activate=|_, _| Message::Suggest />
+                    <SimpleAction::new("find", None) enabled=current.is_some() on activate=|_, _| Message::Find />
+                    <SimpleAction::new("dark-mode", None) enabled=true on activate=|_, _| Message::ToggleDarkMode />
+                    <SimpleAction::new("preferences", None) enabled=true on activate=|_, _| Message::Preferences />
+                    <SimpleAction::new("patch-log", None) enabled=current.is_some() on activate=|_, _| Message::TogglePatchLog />
+                    <SimpleAction::new("undo-history", None) enabled=current.is_some() on activate=|_, _| Message::ToggleUndoHistory />
+                    <SimpleAction::new("compare", None) enabled=has_backend on activate=|_, _| Message::Compare />
+                    <SimpleAction::new("statistics", None) enabled=has_backend on activate=|_, _| Message::Statistics />
+                    <SimpleAction::new("script-console", None) enabled=cfg!(feature = "scripting") on activate=|_, _| Message::ScriptConsole />
+                    <SimpleAction::new("split", None) enabled=current.is_some() on activate=|_, _| Message::ToggleSplit />
+                    <SimpleAction::new("blame", None) enabled=current.is_some() on activate=|_, _| Message::ToggleBlame />
+                    <SimpleAction::new("spellcheck", None) enabled=can_spellcheck on activate=|_, _| Message::ToggleSpellcheck />
+                    <SimpleAction::new("line-numbers", None) enabled={cfg!(feature = "sourceview") && current.is_some()} on activate=|_, _| Message::ToggleLineNumbers />
+                    <SimpleAction::new("preview", None) enabled=current.is_some() on activate=|_, _| Message::TogglePreview />
+                    <HeaderBar title=title show_close_button=true>
+                        {self.menus_view()}
+                        <Button image="tab-new-symbolic" tooltip_text="New Document" sensitive=local on clicked=|_| Message::NewDocument />
+                        {self.notifications_view()}
+                    </HeaderBar>
+                    <Overlay>
+                    <Notebook scrollable=true page=self.current as i32 on switch_page=|_, _, page| Message::SwitchTab(page as usize)>
+                        {
+                            self.tabs().into_iter().map(|(peer_id, label, doc)| gtk!{
+                                <Box Notebook::tab_label=label orientation=Orientation::Vertical>
+                                    <@DocView doc=doc peers=self.peers.clone() connections=self.connections.clone() rejected=self.rejected.clone() p2p_document=self.p2p_document.clone() presence=self.presence_for(peer_id) metrics=self.metrics.clone() signatures=self.signatures.clone()
+                                        on connect=|addr| Message::ConnectPeer(addr) on identity=|i| Message::IdentityChanged(i)
+                                        on resolve=|accept| Message::ResolveSuggestions(peer_id, accept) />
+                                </Box>
+                            })
+                        }
+                    </Notebook>
+                    {self.toast_view()}
+                    </Overlay>
+                </ApplicationWindow>
+                {
+                    self.compare.iter().map(|history| gtk!{
+                        <@CompareView history=history.clone() on close=|_| Message::CloseCompare />
+                    })
+                }
+                {
+                    self.stats.iter().map(|(stats, size)| gtk!{
+                        <@StatsView stats=stats.clone() size=size.clone() names=self.names() on close=|_| Message::CloseStatistics />
+                    })
+                }
+                {
+                    self.preferences.then(|| gtk!{
+                        <@PreferencesView settings=self.settings.clone()
+                            on change=|settings| Message::SettingsChanged(settings) on close=|_| Message::ClosePreferences />
+                    }).into_iter()
+                }
+                {self.script_window().into_iter()}
+            </Application>
+        }
+    }
+}
 
-impl Doc {
-    fn new(sx: crossbeam::Sender<amp::Request>) -> Doc {
-        let mut frontend = Frontend::new();
-        // Initialize the state of the frontend to
-        // {
-        //     "counts": Counter(0),
-        //     "text": ""
-        // }
-        let cr = frontend.change(None, |doc| {
-            doc.add_change(LocalChange::set(
-                Path::root().key("counts"),
-                Value::Primitive(amp::Value::Counter(0)),
-            ))?;
-            doc.add_change(LocalChange::set(
-                Path::root().key("text"),
-                Value::Sequence(Vec::new(), amp::SequenceType::Text),
-            ))?;
-            Ok(())
-        }).unwrap().unwrap();
-        let sx_clone = sx.clone();
-        let sx_clone_2 = sx.clone();
-        // Send the initialization change request to the backend
-        sx.try_send(cr).unwrap();
-
-
-        let frontend_rf = Rc::new(RefCell::new(frontend));
-        let buffer = TextBuffer::new::<TextTagTable>(None);
-        let frontend_clone = frontend_rf.clone();
-
-        // Wire up the insert text signal handler
-        let sig_id = buffer.connect_insert_text(move |_, iter, i| {
-            let pos = iter.get_offset();
-            // Add the change to the frontend
-            let cr = frontend_clone.borrow_mut().change(None, |doc| {
-                doc.add_change(LocalChange::insert(
-                    Path::root().key("text").index(pos as usize),
-                    Value::Primitive(i.into())
-                ))?;
-                Ok(())
-            }).unwrap();
-
-            // Send the change request to the backend
-            if let Some(r) = cr {
-                sx_clone.send(r).unwrap();
+impl Model {
+    /// Everything `update` does, but for putting renders off until the
+    /// next frame
+    fn handle(&mut self, msg: Message) -> UpdateAction<Model> {
+        match msg {
+            Message::Exit => {
+                // Closing the window after quitting sends this again
+                if !self.exited {
+                    self.exited = true;
+                    self.shut_down();
+                    vgtk::quit();
+                }
+                UpdateAction::None
             }
-        });
+            Message::Noop => UpdateAction::None,
+            Message::Bridged(message, processed) => {
+                let action = self.handle(*message);
+                processed.done();
+                action
+            }
+            Message::Frame => UpdateAction::Render,
+            Message::Initialized{ws_events, commands, opened, replay, record_session, metrics, signatures, stress, coalesce, schema, save_on_exit, connect, auth, dbus, stored, recovered, watcher, workspace, keep_workspace, settings, renders, #[cfg(feature = "scripting")] scripts} => {
+                let (presence_sx, presence_rx) = crossbeam::channel::unbounded();
+                self.presence_sx = Some(presence_sx);
+                self.presence_rx = Some(presence_rx);
+                self.ws_events = Some(ws_events);
+                self.commands = Some(commands);
+                self.metrics = Some(metrics);
+                self.signatures = signatures;
+                self.stress = stress;
+                self.coalesce = coalesce;
+                self.schema = schema;
+                self.save_on_exit = save_on_exit;
+                self.auth = auth;
+                self.dbus = dbus;
+                self.keep_workspace = keep_workspace;
+                self.watcher = watcher;
+                self.settings = settings;
+                self.renders = Some(renders);
+                #[cfg(feature = "scripting")]
+                {
+                    self.scripts = Some(scripts);
+                }
+                self.apply_dark_mode();
+                theme::set_font(self.settings.font.as_deref());
+                self.recorder = record_session.and_then(|path| match session::Recorder::create(&path) {
+                    Ok(recorder) => Some(recorder),
+                    Err(e) => {
+                        tracing::error!("Could not record the session to {}: {}", path.display(), e);
+                        None
+                    }
+                });
+                // Our one doc gets its state from the instance we attached
+                // to
+                if attach::attached() {
+                    self.add_doc(PeerStart::Empty, false, None);
+                    self.current = 0;
+                    return UpdateAction::Render;
+                }
+                let recovering = recovered.len();
+                let mut stored = if recovering > 0 { recovered } else { stored }.into_iter();
+                let workspace = workspace.filter(|_| recovering == 0);
+                if !workspace.map(|w| self.restore_workspace(w)).unwrap_or(false) {
+                    // Start with two tabs editing the same document
+                    let first = replay.and_then(|path| self.replay_document(path))
+                        .or_else(|| opened.and_then(|path| self.open_document(path)))
+                        .or_else(|| stored.next().map(|(id, changes)| {
+                            self.add_doc_as(PeerStart::Open(changes), false, None, Some(id))
+                        }));
+                    let (first, initialize) = match first {
+                        Some(first) => (first, false),
+                        None => (self.add_doc(PeerStart::Empty, true, None), true),
+                    };
+                    let path = self.docs.get(first).and_then(|d| d.borrow().path.clone());
+                    self.add_doc(PeerStart::ReplicaOf(first), initialize, path);
+                    self.current = 0;
+                }
+                // The rest of the stored documents get a tab each
+                for (id, changes) in stored {
+                    self.add_doc_as(PeerStart::Open(changes), false, None, Some(id));
+                }
+                if recovering > 0 {
+                    self.notifications.push(format!(
+                        "The demo didn't quit cleanly last time. {} document{} recovered from the journal, save them to keep them.",
+                        recovering,
+                        if recovering == 1 { " was" } else { "s were" },
+                    ));
+                }
+                if let Some((addr, trust)) = connect {
+                    self.connect(addr, trust);
+                }
+                UpdateAction::Render
+            },
+            Message::Patch(envelope) => self.patches_arrived(vec![envelope]),
+            Message::Patches(envelopes) => self.patches_arrived(envelopes),
+            Message::Resync(envelope) => {
+                let position = self.docs.position(envelope.peer_id);
+                if let (Some(doc), Some(position)) = (self.docs.get(envelope.peer_id), position) {
+                    doc.borrow_mut().resync(envelope.patch);
+                    self.notifications.push(format!(
+                        "Tab {} got out of step with its backend and has been reloaded. Anything typed in the last moment may be lost.",
+                        position + 1
+                    ));
+                }
+                UpdateAction::Render
+            },
+            Message::WindowResized(width, height) => {
+                self.window_size = Some((width, height));
+                UpdateAction::None
+            },
+            Message::RemoteDocument(id) => {
+                let peer_id = self.add_doc(PeerStart::Remote(id), false, None);
+                if let Some(position) = self.docs.position(peer_id) {
+                    self.notifications.push(format!("Another instance shared a document with us, it's in tab {}.", position + 1));
+                }
+                UpdateAction::Render
+            },
+            Message::Control(call) => {
+                self.control(call);
+                UpdateAction::Render
+            },
+            Message::Compact => {
+                if let (Some(commands), Some((peer_id, doc))) = (&self.commands, self.current_doc()) {
+                    let path = doc.borrow().path.clone();
+                    let key = path.as_ref().and_then(|p| self.keys.get(p)).cloned();
+                    commands.send(BackendCommand::Compact{peer_id, path, key}).unwrap();
+                }
+                UpdateAction::None
+            },
+            Message::FileChanged(path) => {
+                let peer_id = self.docs.iter()
+                    .find(|(_, d)| d.borrow().path.as_ref().map(|p| watch::absolute(p) == path).unwrap_or(false))
+                    .map(|(peer_id, _)| peer_id);
+                if let Some(peer_id) = peer_id {
+                    match self.read_file(&path).and_then(|bytes| file::decode(&bytes)) {
+                        Ok(changes) => {
+                            if let Some(commands) = &self.commands {
+                                commands.send(BackendCommand::Merge{peer_id, path, changes}).unwrap();
+                            }
+                        }
+                        Err(e) => tracing::debug!("Could not load {} after it changed, it may not be finished: {}", path.display(), e),
+                    }
+                }
+                UpdateAction::None
+            },
+            Message::FileMerged{path, count} => {
+                let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+                self.notifications.push(format!("Merged {} changes another program made to {}.", count, name));
+                UpdateAction::Render
+            },
+            Message::Compacted(peer_id, report) => {
+                if let Some(doc) = self.docs.get(peer_id) {
+                    doc.borrow().growth().borrow_mut().compacted();
+                }
+                self.notifications.push(report);
+                UpdateAction::Render
+            },
+            Message::VerifyConvergence => {
+                if let Some(commands) = &self.commands {
+                    commands.send(BackendCommand::GetHeads).unwrap();
+                }
+                UpdateAction::None
+            },
+            Message::Heads(backends) => {
+                let replicas: Vec<convergence::Replica> = backends.into_iter()
+                    .filter_map(|(peer_id, document, backend)| {
+                        let doc = self.docs.get(peer_id)?.borrow();
+                        let n = self.docs.position(peer_id)? + 1;
+                        Some(convergence::Replica {
+                            tab: format!("Tab {} ({})", n, doc.title()),
+                            document,
+                            backend,
+                            frontend: doc.heads().to_vec(),
+                        })
+                    })
+                    .collect();
+                let (converged, report) = convergence::verify(&replicas);
+                if converged {
+                    tracing::info!("every replica has converged");
+                } else {
+                    tracing::warn!("replicas haven't converged:\n{}", report);
+                }
+                self.notifications.push(report);
+                UpdateAction::Render
+            },
+            Message::DismissToast => {
+                self.notifications.dismiss();
+                UpdateAction::Render
+            },
+            Message::ReadNotifications => {
+                self.notifications.mark_read();
+                UpdateAction::Render
+            },
+            Message::ClearNotifications => {
+                self.notifications.clear();
+                UpdateAction::Render
+            },
+            Message::RemoteChangesMerged{server, count} => {
+                self.notifications.push(match server {
+                    Some(server) => format!("Merged {} remote changes from {}.", count, server),
+                    None => format!("Merged {} remote changes from a client.", count),
+                });
+                UpdateAction::Render
+            },
+            Message::PeerDiscovered(peer) => {
+                self.peers.retain(|p| p.name != peer.name);
+                self.peers.push(peer);
+                UpdateAction::Render
+            },
+            Message::PeerLost(name) => {
+                self.peers.retain(|p| p.name != name);
+                UpdateAction::Render
+            },
+            Message::ConnectPeer(addr) => {
+                let tls = self.peers.iter().any(|p| p.addr == addr && p.tls);
+                if self.connect(addr, if tls { Some(tls::Trust::OnFirstUse) } else { None }) {
+                    UpdateAction::Render
+                } else {
+                    UpdateAction::None
+                }
+            },
+            Message::ConnectionStatus(addr, status) => {
+                let message = match &status {
+                    ws::ConnectionStatus::Connected => Some(format!("Connected to {}.", addr)),
+                    ws::ConnectionStatus::Failed => Some(format!("Gave up connecting to {}.", addr)),
+                    ws::ConnectionStatus::Rejected(reason) => Some(format!("{} didn't accept us: {}", addr, reason)),
+                    _ => None,
+                };
+                if let Some(message) = message.filter(|_| self.connections.get(&addr) != Some(&status)) {
+                    self.notifications.push(message);
+                }
+                self.connections.insert(addr, status);
+                UpdateAction::Render
+            },
+            Message::VerifyPeer{server, fingerprint, reply} => {
+                let _ = reply.send(tls::ask(server, &fingerprint));
+                UpdateAction::None
+            },
+            Message::P2pAnnounced(document) => {
+                self.p2p_document = Some(document);
+                UpdateAction::Render
+            },
+            Message::NetworkPeer{id, present} => {
+                if !present {
+                    self.network_presence.remove(&id);
+                    return UpdateAction::Render;
+                }
+                let new = !self.network_presence.contains_key(&id);
+                let n = self.network_presence.len();
+                let peer = self.network_presence.entry(id.clone()).or_insert_with(|| {
+                    // Peer ids are base58, so this is on a character boundary
+                    let name = format!("libp2p \u{2026}{}", &id[id.len().saturating_sub(6)..]);
+                    presence::Presence::new(presence::Identity{name, ..presence::Identity::new(id.clone(), n)})
+                });
+                peer.last_seen = Some(std::time::Instant::now());
+                if new {
+                    let name = peer.identity.name.clone();
+                    self.notifications.push(format!("{} joined over libp2p.", name));
+                }
+                if new | presence::refresh(&mut self.network_presence) {
+                    UpdateAction::Render
+                } else {
+                    UpdateAction::None
+                }
+            },
+            #[cfg(feature = "net")]
+            Message::StartCall => {
+                if let Some(events) = &self.ws_events {
+                    webrtc::call(events.clone());
+                }
+                UpdateAction::None
+            },
+            #[cfg(feature = "net")]
+            Message::AnswerCall => {
+                if let (Some(events), Some(offer)) = (&self.ws_events, webrtc::ask_offer()) {
+                    webrtc::answer(offer, events.clone());
+                }
+                UpdateAction::None
+            },
+            // The actions are disabled, and there's nothing to signal, without
+            // WebRTC
+            #[cfg(not(feature = "net"))]
+            Message::StartCall | Message::AnswerCall | Message::CallSignal{..} => UpdateAction::None,
+            #[cfg(feature = "net")]
+            Message::CallSignal{description, reply} => {
+                match reply {
+                    Some(reply) => {
+                        // Dropping the reply without an answer cancels the call
+                        if let Some(answer) = webrtc::exchange_offer(&description) {
+                            let _ = reply.send(answer);
+                        }
+                    }
+                    None => webrtc::show_answer(&description),
+                }
+                UpdateAction::None
+            },
+            Message::PeerRejected(client, reason) => {
+                // One line per address, ports change with every attempt
+                self.rejected.retain(|(c, _)| c.ip() != client.ip());
+                self.rejected.insert(0, (client, reason));
+                self.rejected.truncate(MAX_REJECTED);
+                UpdateAction::Render
+            },
+            Message::IdentityChanged(identity) => {
+                if let Some(p) = self.presence.get_mut(&identity.actor_id) {
+                    p.identity = identity;
+                }
+                UpdateAction::Render
+            },
+            Message::RemotePresence(state) => {
+                presence::receive(&mut self.remote_presence, state);
+                presence::refresh(&mut self.remote_presence);
+                UpdateAction::Render
+            },
+            Message::PresenceTick => {
+                let others = presence::refresh(&mut self.network_presence)
+                    | presence::expire(&mut self.remote_presence)
+                    | self.share_presence();
+                match &self.presence_rx {
+                    Some(rx) if presence::update(&mut self.presence, rx) | others => UpdateAction::Render,
+                    _ if others => UpdateAction::Render,
+                    _ => UpdateAction::None,
+                }
+            },
+            Message::MetricsTick => {
+                // A doc whose frontend failed to make a local change won't
+                // necessarily get another patch, so check here too
+                self.request_resyncs();
+                self.autosave();
+                let expired = self.notifications.expire();
+                self.desktop.tick();
+                let depth = self.docs.queue_depth();
+                match &self.metrics {
+                    Some(metrics) => {
+                        metrics.sample(depth);
+                        UpdateAction::Render
+                    }
+                    None if expired => UpdateAction::Render,
+                    None => UpdateAction::None,
+                }
+            },
+            Message::Play(event) => {
+                match self.docs.get(PeerId(event.tab)) {
+                    Some(doc) => {
+                        doc.borrow_mut().play(&event.input);
+                        UpdateAction::Render
+                    }
+                    None => {
+                        tracing::warn!("dropping recorded input for a tab we don't have: {:?}", event);
+                        UpdateAction::None
+                    }
+                }
+            },
+            Message::SwitchTab(n) => {
+                if n == self.current {
+                    return UpdateAction::None;
+                }
+                self.current = n;
+                UpdateAction::Render
+            },
+            Message::NewDocument => {
+                let peer_id = self.add_doc(PeerStart::Empty, true, None);
+                self.show(peer_id);
+                UpdateAction::Render
+            },
+            Message::Open => {
+                if let Some(path) = file::choose(FileChooserAction::Open) {
+                    if let Some(peer_id) = self.open_document(path) {
+                        self.show(peer_id);
+                    }
+                }
+                UpdateAction::Render
+            },
+            Message::CloseTab => {
+                if let Some((peer_id, _)) = self.current_doc() {
+                    self.close_doc(peer_id);
+                }
+                UpdateAction::Render
+            },
+            Message::Save => {
+                let path = self.current_doc().and_then(|(_, d)| d.borrow().path.clone());
+                match path {
+                    Some(path) => {
+                        self.save(path);
+                        UpdateAction::None
+                    }
+                    None => self.update(Message::SaveAs),
+                }
+            },
+            Message::SaveAs => {
+                if let Some(path) = file::choose(FileChooserAction::Save) {
+                    if let Some((_, doc)) = self.current_doc() {
+                        // Saved somewhere else, it stays encrypted
+                        let old = doc.borrow_mut().path.replace(path.clone());
+                        if let Some(key) = old.and_then(|old| self.keys.get(&old).cloned()) {
+                            self.keys.insert(path.clone(), key);
+                        }
+                    }
+                    self.watch_files();
+                    self.save(path);
+                }
+                UpdateAction::None
+            },
+            Message::ImportText => {
+                if let (Some(path), Some((_, doc))) = (file::choose(FileChooserAction::Open), self.current_doc()) {
+                    import::start(doc, &path);
+                }
+                UpdateAction::None
+            },
+            Message::Export(format) => {
+                if let (Some(mut path), Some((_, doc))) = (file::choose(FileChooserAction::Save), self.current_doc()) {
+                    if path.extension().is_none() {
+                        path.set_extension(format.extension());
+                    }
+                    match std::fs::write(&path, doc.borrow().export(format)) {
+                        Ok(()) => tracing::info!("Exported to {}", path.display()),
+                        Err(e) => tracing::error!("Could not export to {}: {}", path.display(), e),
+                    }
+                }
+                UpdateAction::None
+            },
+            Message::SaveSnapshot => {
+                if let (Some(path), Some((_, doc))) = (file::choose(FileChooserAction::Save), self.current_doc()) {
+                    match doc.borrow().save_snapshot(&path) {
+                        Ok(()) => tracing::info!("Saved a snapshot to {}", path.display()),
+                        Err(e) => tracing::error!("Could not save a snapshot to {}: {}", path.display(), e),
+                    }
+                }
+                UpdateAction::None
+            },
+            Message::NewFromSnapshot => {
+                let path = match file::choose(FileChooserAction::Open) {
+                    Some(path) => path,
+                    None => return UpdateAction::None,
+                };
+                match snapshot::load(&path) {
+                    Ok(root) => {
+                        let peer_id = self.add_doc(PeerStart::Empty, false, None);
+                        if let Some(doc) = self.docs.get(peer_id) {
+                            doc.borrow_mut().load_snapshot(root);
+                        }
+                        self.show(peer_id);
+                    }
+                    Err(e) => tracing::error!("Could not load a snapshot from {}: {}", path.display(), e),
+                }
+                UpdateAction::Render
+            },
+            Message::ExportHistory => {
+                if let (Some(path), Some(commands), Some((peer_id, doc))) =
+                    (file::choose(FileChooserAction::Save), &self.commands, self.current_doc())
+                {
+                    // An encrypted document's log is encrypted too
+                    let key = doc.borrow().path.as_ref().and_then(|p| self.keys.get(p)).cloned();
+                    commands.send(BackendCommand::ExportHistory{peer_id, path, key}).unwrap();
+                }
+                UpdateAction::None
+            },
+            Message::Encrypt => {
+                let path = match self.current_doc().and_then(|(_, d)| d.borrow().path.clone()) {
+                    Some(path) => Some(path),
+                    None => file::choose(FileChooserAction::Save),
+                };
+                if let Some(path) = path {
+                    if let Some(passphrase) = crypt::ask_passphrase(&path, false, true) {
+                        if let Some((_, doc)) = self.current_doc() {
+                            doc.borrow_mut().path = Some(path.clone());
+                        }
+                        self.keys.insert(path.clone(), crypt::Key::new(&passphrase));
+                        self.save(path);
+                        self.watch_files();
+                    }
+                }
+                UpdateAction::Render
+            },
+            Message::Undo => {
+                self.current_doc().map(|(_, d)| d.borrow_mut().undo());
+                UpdateAction::Render
+            },
+            Message::Redo => {
+                self.current_doc().map(|(_, d)| d.borrow_mut().redo());
+                UpdateAction::Render
+            },
+            Message::IncrementCounter => {
+                self.current_doc().map(|(_, d)| d.borrow_mut().inc_counter());
+                UpdateAction::Render
+            },
+            Message::DecrementCounter => {
+                self.current_doc().map(|(_, d)| d.borrow_mut().dec_counter());
+                UpdateAction::Render
+            },
+            Message::Suggest => {
+                if let (Some(commands), Some((peer_id, doc))) = (&self.commands, self.current_doc()) {
+                    let mut doc = doc.borrow_mut();
+                    if !doc.suggesting() && !doc.read_only() {
+                        doc.start_suggesting();
+                        commands.send(BackendCommand::Suggest{peer_id}).unwrap();
+                    }
+                }
+                UpdateAction::Render
+            },
+            Message::ResolveSuggestions(peer_id, accept) => {
+                if let (Some(commands), Some(doc)) = (&self.commands, self.docs.get(peer_id)) {
+                    let mut doc = doc.borrow_mut();
+                    if doc.suggesting() {
+                        doc.stop_suggesting(accept);
+                        commands.send(BackendCommand::ResolveSuggestions{peer_id, accept}).unwrap();
+                    }
+                }
+                UpdateAction::Render
+            },
+            Message::SuggestionsAccepted(count) => {
+                self.notifications.push(format!("Accepted {} suggested changes.", count));
+                UpdateAction::Render
+            },
+            Message::SuggestionsRejected(envelope) => {
+                if let Some(doc) = self.docs.get(envelope.peer_id) {
+                    doc.borrow_mut().resync(envelope.patch);
+                }
+                UpdateAction::Render
+            },
+            Message::Find => {
+                self.current_doc().map(|(_, d)| d.borrow_mut().toggle_find());
+                UpdateAction::Render
+            },
+            Message::TogglePatchLog => {
+                if let Some((_, doc)) = self.current_doc() {
+                    let mut doc = doc.borrow_mut();
+                    doc.show_patch_log = !doc.show_patch_log;
+                }
+                UpdateAction::Render
+            },
+            Message::ToggleUndoHistory => {
+                if let Some((_, doc)) = self.current_doc() {
+                    let mut doc = doc.borrow_mut();
+                    doc.show_undo_history = !doc.show_undo_history;
+                }
+                UpdateAction::Render
+            },
+            Message::Compare => {
+                if let (Some(commands), Some((peer_id, _))) = (&self.commands, self.current_doc()) {
+                    commands.send(BackendCommand::GetHistory{peer_id, purpose: HistoryFor::Compare}).unwrap();
+                }
+                UpdateAction::None
+            },
+            Message::Statistics => {
+                if let (Some(commands), Some((peer_id, _))) = (&self.commands, self.current_doc()) {
+                    commands.send(BackendCommand::GetHistory{peer_id, purpose: HistoryFor::Statistics}).unwrap();
+                }
+                UpdateAction::None
+            },
+            Message::History(HistoryFor::Compare, changes) => {
+                self.compare = Some(Rc::new(changes));
+                UpdateAction::Render
+            },
+            Message::History(HistoryFor::Statistics, changes) => {
+                let frontend_bytes = self.current_doc().map_or(0, |(_, doc)| doc.borrow().frontend_size());
+                let size = size::compute(&changes, frontend_bytes);
+                self.stats = Some((Rc::new(stats::compute(&changes)), Rc::new(size)));
+                UpdateAction::Render
+            },
+            Message::CloseCompare => {
+                self.compare = None;
+                UpdateAction::Render
+            },
+            Message::CloseStatistics => {
+                self.stats = None;
+                UpdateAction::Render
+            },
+            Message::ToggleSplit => {
+                if let Some((_, doc)) = self.current_doc() {
+                    let mut doc = doc.borrow_mut();
+                    doc.split = !doc.split;
+                }
+                UpdateAction::Render
+            },
+            Message::ToggleLineNumbers => {
+                if let Some((_, doc)) = self.current_doc() {
+                    let mut doc = doc.borrow_mut();
+                    doc.show_line_numbers = !doc.show_line_numbers;
+                }
+                UpdateAction::Render
+            },
+            Message::TogglePreview => {
+                if let Some((_, doc)) = self.current_doc() {
+                    doc.borrow_mut().toggle_preview();
+                }
+                UpdateAction::Render
+            },
+            Message::ToggleSpellcheck => {
+                if let Some((_, doc)) = self.current_doc() {
+                    doc.borrow_mut().toggle_spellcheck();
+                }
+                UpdateAction::Render
+            },
+            Message::ToggleBlame => {
+                if let Some((_, doc)) = self.current_doc() {
+                    doc.borrow_mut().toggle_blame();
+                }
+                UpdateAction::Render
+            },
+            Message::ToggleDarkMode => {
+                self.settings.dark_mode = !self.settings.dark_mode;
+                self.save_settings();
+                self.apply_dark_mode();
+                UpdateAction::Render
+            },
+            Message::Preferences => {
+                self.preferences = true;
+                UpdateAction::Render
+            },
+            Message::ClosePreferences => {
+                self.preferences = false;
+                UpdateAction::Render
+            },
+            #[cfg(feature = "scripting")]
+            Message::ScriptConsole => {
+                self.script.get_or_insert_with(script::Console::default);
+                self.script_console = true;
+                UpdateAction::Render
+            },
+            #[cfg(feature = "scripting")]
+            Message::CloseScriptConsole => {
+                // The script, and one which is running, carry on
+                self.script_console = false;
+                UpdateAction::Render
+            },
+            #[cfg(feature = "scripting")]
+            Message::RunScript => {
+                let tab = self.current_doc().map_or(0, |(peer_id, _)| peer_id.0);
+                if let (Some(console), Some(scripts)) = (&mut self.script, &self.scripts) {
+                    console.run(tab, scripts);
+                }
+                UpdateAction::Render
+            },
+            #[cfg(feature = "scripting")]
+            Message::StopScript => {
+                if let Some(console) = &self.script {
+                    console.stop();
+                }
+                UpdateAction::None
+            },
+            #[cfg(feature = "scripting")]
+            Message::ClearScriptOutput => {
+                if let Some(console) = &self.script {
+                    console.clear();
+                }
+                UpdateAction::None
+            },
+            #[cfg(feature = "scripting")]
+            Message::Script(call) => self.script_call(call),
+            // The action is disabled without the console
+            #[cfg(not(feature = "scripting"))]
+            Message::ScriptConsole | Message::CloseScriptConsole | Message::RunScript | Message::StopScript | Message::ClearScriptOutput => UpdateAction::None,
+            Message::SettingsChanged(settings) => {
+                let old = std::mem::replace(&mut self.settings, settings);
+                if old.dark_mode != self.settings.dark_mode {
+                    self.apply_dark_mode();
+                }
+                if old.font != self.settings.font {
+                    theme::set_font(self.settings.font.as_deref());
+                }
+                if old.coalesce_ms != self.settings.coalesce_ms {
+                    self.coalesce = self.settings.coalesce();
+                    for (_, doc) in self.docs.iter() {
+                        doc.borrow().set_coalesce(self.coalesce);
+                    }
+                }
+                if old.display_name != self.settings.display_name {
+                    self.rename_tabs();
+                }
+                self.save_settings();
+                UpdateAction::Render
+            },
+        }
+    }
+
+    /// Ask GTK for the dark theme or the light one, as the settings say, and
+    /// recolor the docs to match
+    fn apply_dark_mode(&self) {
+        let dark = self.settings.dark_mode;
+        if let Some(gtk_settings) = Settings::get_default() {
+            gtk_settings.set_property_gtk_application_prefer_dark_theme(dark);
+        }
+        for (_, doc) in self.docs.iter() {
+            doc.borrow_mut().set_dark(dark);
+        }
+    }
+
+    fn save_settings(&self) {
+        if let Err(e) = self.settings.save() {
+            tracing::error!("Could not save the settings: {}", e);
+        }
+    }
+
+    /// Our name in the `n`th tab, which is showing the doc for `peer_id`
+    fn tab_name(&self, n: usize, peer_id: PeerId) -> String {
+        self.settings.tab_name(n).unwrap_or_else(|| format!("Doc {}", peer_id.0 + 1))
+    }
 
-        let second_frontend_clone = frontend_rf.clone();
-
-        // Wire up the delete text handler
-        let del_sig_id = buffer.connect_delete_range(move |_, start, end| {
-            // For each deleted character, add the change to the frontend
-            // and send the change request to the backend
-            for i in start.get_offset()..end.get_offset() {
-                let cr = second_frontend_clone.borrow_mut().change(None, |doc| {
-                    doc.add_change(LocalChange::delete(
-                        Path::root().key("text").index((i) as usize)
-                    ))?;
-                    Ok(())
-                }).unwrap();
-                if let Some(r) = cr {
-                    sx_clone_2.send(r).unwrap();
+    /// Call us what the display name in the settings says in every tab,
+    /// or what we're called by default if there isn't one any more
+    fn rename_tabs(&mut self) {
+        let names: Vec<(String, String)> = self.docs.iter().enumerate()
+            .map(|(n, (peer_id, doc))| (doc.borrow().actor_id(), self.tab_name(n, peer_id)))
+            .collect();
+        for (actor_id, name) in names {
+            if let Some(p) = self.presence.get_mut(&actor_id) {
+                p.identity.name = name;
+            }
+        }
+    }
+
+    /// The script console, if it's open
+    #[cfg(feature = "scripting")]
+    fn script_window(&self) -> Option<VNode<Model>> {
+        self.script.as_ref().filter(|_| self.script_console).map(|console| gtk!{
+            <@ScriptView source=Some(console.source.clone()) output=Some(console.output.clone()) running=console.running()
+                on run=|_| Message::RunScript on stop=|_| Message::StopScript
+                on clear=|_| Message::ClearScriptOutput on close=|_| Message::CloseScriptConsole />
+        })
+    }
+
+    #[cfg(not(feature = "scripting"))]
+    fn script_window(&self) -> Option<VNode<Model>> {
+        None
+    }
+
+    /// Do what the running script asks, see `script.rs`
+    #[cfg(feature = "scripting")]
+    fn script_call(&mut self, call: script::Call) -> UpdateAction<Model> {
+        match call {
+            script::Call::Input{tab, input, reply} => {
+                let doc = self.docs.get(PeerId(tab));
+                if let Some(doc) = &doc {
+                    doc.borrow_mut().play(&input);
                 }
+                let _ = reply.send(doc.is_some());
+                UpdateAction::Render
+            }
+            script::Call::Splice{tab, splices, reply} => {
+                let result = match self.docs.get(PeerId(tab)) {
+                    Some(doc) => {
+                        let message = format!("Edit {} places from a script", splices.len());
+                        doc.borrow_mut().splice(splices, message).map(|_| ())
+                    }
+                    None => Err(format!("there's no Doc {}", tab + 1)),
+                };
+                let _ = reply.send(result);
+                UpdateAction::Render
+            }
+            script::Call::Text{tab, reply} => {
+                let _ = reply.send(self.docs.get(PeerId(tab)).map(|doc| doc.borrow().text()));
+                UpdateAction::None
+            }
+            script::Call::Print(line) => {
+                if let Some(console) = &self.script {
+                    console.print(&line);
+                }
+                UpdateAction::None
+            }
+            script::Call::Finished(result) => {
+                if let Some(console) = &mut self.script {
+                    console.finished(result);
+                }
+                UpdateAction::Render
+            }
+        }
+    }
+
+    /// Save every tab with a file whose document has changed since the last
+    /// autosave, if the settings say to and it's been long enough
+    fn autosave(&mut self) {
+        let docs: Vec<PeerId> = self.docs.iter().map(|(peer_id, _)| peer_id).collect();
+        self.autosaved.retain(|peer_id, _| docs.contains(peer_id));
+        // What a tab opened with doesn't need saving
+        for (peer_id, doc) in self.docs.iter() {
+            self.autosaved.entry(peer_id).or_insert_with(|| doc.borrow().heads().to_vec());
+        }
+        let interval = match self.settings.autosave_secs.filter(|secs| *secs > 0) {
+            Some(secs) => Duration::from_secs(secs),
+            None => return,
+        };
+        let now = std::time::Instant::now();
+        if self.last_autosave.map_or(false, |last| now.duration_since(last) < interval) {
+            return;
+        }
+        let commands = match (&self.commands, self.last_autosave.replace(now)) {
+            (Some(commands), Some(_)) => commands,
+            _ => return,
+        };
+        for (peer_id, doc) in self.docs.iter() {
+            let doc = doc.borrow();
+            let path = match &doc.path {
+                Some(path) if self.autosaved.get(&peer_id).map(|h| h.as_slice()) != Some(doc.heads()) => path.clone(),
+                _ => continue,
             };
-        });
+            tracing::debug!("autosaving {}", path.display());
+            let key = self.keys.get(&path).cloned();
+            commands.send(BackendCommand::Save{peer_id, path, key}).unwrap();
+            self.autosaved.insert(peer_id, doc.heads().to_vec());
+        }
+    }
+
+    /// Start syncing with another instance's server, over TLS with `trust`.
+    /// Returns false if there's already a connection, or one being retried.
+    fn connect(&mut self, addr: SocketAddr, trust: Option<tls::Trust>) -> bool {
+        if self.connections.get(&addr).map(|s| s.is_active()).unwrap_or(false) {
+            return false;
+        }
+        match &self.ws_events {
+            Some(events) => {
+                self.connections.insert(addr, ws::ConnectionStatus::Connecting);
+                ws::connect(addr, trust, self.auth.clone(), events.clone());
+                true
+            }
+            None => false,
+        }
+    }
 
-        Doc{
-            frontend: frontend_rf,
-            buffer,
-            insert_text_sigid: sig_id,
-            del_sig_id,
-            sx,
+    /// Ask the backend for the state of any doc which has stopped agreeing
+    /// with it, see `Doc::resync`
+    fn request_resyncs(&self) {
+        let commands = match &self.commands {
+            Some(commands) => commands,
+            None => return,
+        };
+        for (peer_id, doc) in self.docs.iter() {
+            if doc.borrow_mut().wants_resync() {
+                tracing::warn!("{} is out of step with its backend, resyncing", peer_id);
+                commands.send(BackendCommand::Resync{peer_id}).unwrap();
+            }
         }
     }
 
-    /// Apply the patch and update the text buffer if necessary
-    fn apply_patch(&mut self, patch: Option<amp::Patch>) {
-        if let Some(patch) = patch {
-            self.frontend.borrow_mut().apply_patch(patch.clone()).unwrap();
-            // We don't need to update the text buffer if it's a patch from ourselves
-            if patch.actor == Some(self.frontend.borrow().actor_id.to_string()) {
-                return
-            };
-            // We have to block these signals otherwise the handlers will fire
-            // as we update the text, which will cause a loop
-            self.buffer.block_signal(&self.insert_text_sigid);
-            self.buffer.block_signal(&self.del_sig_id);
-            let text = match self.frontend.borrow().get_value(&Path::root().key("text")) {
-                Some(Value::Sequence(vals, amp::SequenceType::Text)) => {
-                    vals.iter().map(|v| match v {
-                        Value::Primitive(amp::Value::Str(s)) => s.to_string(),
-                        _ => "".to_string(),
-                    })
-                    .collect::<Vec<String>>()
-                    .join("")
-                },
-                _ => "".to_string()
+    /// Send everything still batched up in the docs to the backend thread
+    /// and wait for it to apply it all, and save if `--save-on-exit` was
+    /// given. The docs are still around while we wait, so nothing they sent
+    /// can be dropped along with them.
+    fn shut_down(&self) {
+        let commands = match &self.commands {
+            Some(commands) => commands,
+            None => return,
+        };
+        for (_, doc) in self.docs.iter() {
+            doc.borrow().flush();
+        }
+        let save = if self.save_on_exit {
+            self.docs.iter()
+                .filter_map(|(peer_id, doc)| {
+                    let path = doc.borrow().path.clone()?;
+                    let key = self.keys.get(&path).cloned();
+                    Some((peer_id, path, key))
+                })
+                .collect()
+        } else {
+            Vec::new()
+        };
+        if self.keep_workspace {
+            self.save_workspace();
+        }
+        let (done, done_rx) = crossbeam::channel::bounded(1);
+        commands.send(BackendCommand::Shutdown{save, done}).unwrap();
+        if done_rx.recv_timeout(SHUTDOWN_TIMEOUT).is_err() {
+            tracing::warn!("The backend didn't finish shutting down in time, the last changes may be lost");
+        }
+    }
+
+    fn save_workspace(&self) {
+        let tabs = self.docs.iter()
+            .map(|(_, doc)| {
+                let doc = doc.borrow();
+                workspace::Tab{path: doc.path.clone(), id: doc.id, cursor: doc.cursor_offset()}
+            })
+            .collect();
+        let workspace = workspace::Workspace{window_size: self.window_size, current: self.current, tabs};
+        if let Err(e) = workspace::save(&workspace) {
+            tracing::warn!("Could not save the workspace: {}", e);
+        }
+    }
+
+    /// Open the tabs saved in `workspace` again, returning whether any of
+    /// them could be
+    fn restore_workspace(&mut self, workspace: workspace::Workspace) -> bool {
+        let mut restored: Vec<(DocumentId, PeerId)> = Vec::new();
+        let mut current = 0;
+        for (index, tab) in workspace.tabs.into_iter().enumerate() {
+            let peer_id = match restored.iter().find(|(id, _)| *id == tab.id) {
+                Some((_, first)) => {
+                    let first = *first;
+                    self.add_doc(PeerStart::ReplicaOf(first), false, tab.path)
+                }
+                None => {
+                    let path = match tab.path {
+                        Some(path) => path,
+                        None => continue,
+                    };
+                    let changes = match self.read_file(&path).and_then(|bytes| file::decode(&bytes)) {
+                        Ok(changes) => changes,
+                        Err(e) => {
+                            tracing::error!("Could not reopen {}: {}", path.display(), e);
+                            continue;
+                        }
+                    };
+                    let peer_id = self.add_doc_as(PeerStart::Open(changes), false, Some(path), Some(tab.id));
+                    restored.push((tab.id, peer_id));
+                    peer_id
+                }
             };
-            self.buffer.set_text(text.as_str());
-            self.buffer.unblock_signal(&self.insert_text_sigid);
-            self.buffer.unblock_signal(&self.del_sig_id);
+            if index == workspace.current {
+                current = self.docs.position(peer_id).unwrap_or(0);
+            }
+            if let Some(doc) = self.docs.get(peer_id) {
+                doc.borrow_mut().restore_cursor(tab.cursor);
+            }
+        }
+        if let Some((width, height)) = workspace.window_size {
+            self.window_size = Some((width, height));
+            if let Some(window) = vgtk::current_window() {
+                window.resize(width, height);
+            }
         }
+        self.current = current;
+        !restored.is_empty()
     }
 
-    /// Get the value of the counter
-    fn counter_value(&self) -> i64 {
-        match self.frontend.borrow_mut().state() {
-            Value::Map(vals, _) => vals.get("counts").and_then(|c| match c {
-                Value::Primitive(amp::Value::Counter(i)) => Some(*i),
-                _ => None
-            }),
-            _ => None,
-        }.unwrap_or(0)
+    /// Create a doc and the backend behind it, returning its peer id
+    fn add_doc(&mut self, start: PeerStart, initialize: bool, path: Option<PathBuf>) -> PeerId {
+        self.add_doc_as(start, initialize, path, None)
     }
 
-    /// Increment the counter value locally and send the corresponding 
-    /// change to the backend
-    fn inc_counter(&mut self) -> () {
-        let cr = self.frontend.borrow_mut().change(None, |doc| {
-            doc.add_change(LocalChange::increment(
-                Path::root().key("counts")
-            ))?;
-            Ok(())
-        }).unwrap();
-        if let Some(cr) = cr {
-            self.sx.send(cr).unwrap();
+    /// `add_doc`, for the document with `id` if it's given rather than a
+    /// new one
+    fn add_doc_as(&mut self, start: PeerStart, initialize: bool, path: Option<PathBuf>, id: Option<DocumentId>) -> PeerId {
+        let peer_id = PeerId(self.next_peer);
+        self.next_peer += 1;
+        let (sx, rx) = crossbeam::channel::unbounded();
+        let document = match &start {
+            PeerStart::ReplicaOf(other) => self.documents.get(other).copied().unwrap_or(*other),
+            _ => peer_id,
+        };
+        let id = match &start {
+            _ if id.is_some() => id.unwrap(),
+            PeerStart::ReplicaOf(other) => self.docs.get(*other).map(|d| d.borrow().id).unwrap_or_else(DocumentId::random),
+            PeerStart::Remote(id) => *id,
+            PeerStart::Empty | PeerStart::Open(_) => DocumentId::random(),
+        };
+        // The backend has to know about the peer before the doc sends its
+        // first change request
+        self.commands.as_ref().unwrap().send(BackendCommand::AddPeer{peer_id, id, requests: rx, start}).unwrap();
+        let presence_sx = self.presence_sx.clone().unwrap();
+        let metrics = self.metrics.clone().unwrap();
+        let mut doc = Doc::new(sx, presence_sx, metrics, initialize, self.coalesce, self.schema.clone());
+        doc.set_dark(self.settings.dark_mode);
+        doc.path = path;
+        doc.id = id;
+        if let Some(recorder) = &self.recorder {
+            doc.set_recorder(recorder.for_tab(peer_id.0));
         }
+        let name = self.tab_name(self.docs.len(), peer_id);
+        let identity = presence::Identity{name, ..presence::Identity::new(doc.actor_id(), peer_id.0)};
+        self.presence.insert(identity.actor_id.clone(), presence::Presence::new(identity));
+        if let Some(rate) = self.stress {
+            let typist = stress::start(doc.buffer.clone(), rate, peer_id.0 as u64 + 1);
+            self.typists.insert(peer_id, typist);
+        }
+        self.docs.insert(peer_id, doc);
+        self.documents.insert(peer_id, document);
+        self.watch_files();
+        peer_id
     }
-}
 
-#[derive(Default)]
-struct DocView {
-    doc: Option<Rc<RefCell<Doc>>>,
-    on_exit: Callback<()>,
-}
+    /// Watch the file of every tab which has one
+    fn watch_files(&self) {
+        if let Some(watcher) = &self.watcher {
+            watcher.set_files(self.docs.iter().filter_map(|(_, doc)| doc.borrow().path.clone()));
+        }
+    }
 
-#[derive(Debug, Clone)]
-enum DocMessage {
-    Inc,
-    Exit,
-}
+    /// Carry out a method call from D-Bus and answer it
+    fn control(&mut self, call: dbus::Call) {
+        use dbus::Call;
+        let tab = match &call {
+            Call::InsertText{tab, ..} | Call::DeleteText{tab, ..} | Call::GetText{tab, ..}
+            | Call::IncrementCounter{tab, ..} | Call::GetCounter{tab, ..} | Call::GetHeads{tab, ..} => *tab,
+        };
+        let doc = self.docs.nth(tab as usize).map(|(_, doc)| doc.clone()).ok_or_else(|| format!("there's no tab {}", tab));
+        let editable = doc.clone().and_then(|doc| if doc.borrow().read_only() {
+            Err(format!("tab {} is read only", tab))
+        } else {
+            Ok(doc)
+        });
+        // Nobody waiting for the answer any more is fine
+        match call {
+            Call::InsertText{offset, text, reply, ..} => {
+                let _ = reply.send(editable.map(|doc| {
+                    doc.borrow_mut().play(&session::Input::Insert{offset: offset as usize, text})
+                }));
+            }
+            Call::DeleteText{start, end, reply, ..} => {
+                let _ = reply.send(editable.map(|doc| {
+                    doc.borrow_mut().play(&session::Input::Delete{start: start as usize, end: end as usize})
+                }));
+            }
+            Call::GetText{reply, ..} => {
+                let _ = reply.send(doc.map(|doc| doc.borrow().text()));
+            }
+            Call::IncrementCounter{reply, ..} => {
+                let _ = reply.send(editable.and_then(|doc| {
+                    let mut doc = doc.borrow_mut();
+                    if !doc.has_counter() {
+                        return Err("this document has no counter".to_string());
+                    }
+                    doc.inc_counter();
+                    Ok(doc.counter_value())
+                }));
+            }
+            Call::GetCounter{reply, ..} => {
+                let _ = reply.send(doc.map(|doc| doc.borrow().counter_value()));
+            }
+            Call::GetHeads{reply, ..} => {
+                let _ = reply.send(doc.map(|doc| doc.borrow().heads_hash()));
+            }
+        }
+    }
 
-#[derive(Clone, Default)]
-struct DocViewProperties {
-    doc: Option<Rc<RefCell<Doc>>>,
-    on_exit: Callback<()>
-}
+    /// The contents of `path`, decrypted if it's encrypted, with the key
+    /// we have for it or by asking for its passphrase until we get the
+    /// right one or are told to give up
+    fn read_file(&mut self, path: &std::path::Path) -> std::io::Result<Vec<u8>> {
+        let bytes = std::fs::read(path)?;
+        if !crypt::is_encrypted(&bytes) {
+            return Ok(bytes);
+        }
+        if let Some(Ok(plain)) = self.keys.get(path).map(|key| crypt::decrypt(key, &bytes)) {
+            return Ok(plain);
+        }
+        let mut retry = false;
+        loop {
+            let passphrase = crypt::ask_passphrase(path, retry, false)
+                .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::PermissionDenied, "it's encrypted and no passphrase was given"))?;
+            let key = crypt::Key::for_file(&passphrase, &bytes)?;
+            match crypt::decrypt(&key, &bytes) {
+                Ok(plain) => {
+                    self.keys.insert(path.to_path_buf(), key);
+                    return Ok(plain);
+                }
+                Err(_) => retry = true,
+            }
+        }
+    }
 
-impl Component for DocView {
-    type Message = DocMessage;
-    type Properties = DocViewProperties;
-    fn view(&self) -> VNode<Self> {
-        match &self.doc {
-            // We're waiting for the outer component to give us a doc
-            None => gtk!{
-                <Window title="Doc 1" border_width=20 default_width=1000 default_height=500 on destroy=|_| DocMessage::Exit>
-                    <HeaderBar title="inc" show_close_button=true />
-                    <Box orientation=Orientation::Vertical valign=Align::Center halign=Align::Center vexpand=true>
-                        <Label label="Initializing" />
-                    </Box>
-                </Window>
-            },
-            Some(doc) => gtk!{
-                <Window title="Doc 1" border_width=20 default_width=1000 default_height=500 on destroy=|_| DocMessage::Exit>
-                    <HeaderBar title="inc" show_close_button=true />
-                    <Box orientation=Orientation::Vertical valign=Align::Center halign=Align::Center vexpand=true>
-                        <Label label="Counter" />
-                        <Box spacing=30 halign=Align::Center valign=Align::Center orientation=Orientation::Horizontal Box::expand=false>
-                            <Label label=doc.borrow().counter_value().to_string() />
-                            <Button label="inc!" image="list-add" Box::expand=false always_show_image=true on clicked=|_| DocMessage::Inc />
-                        </Box>
-                        <Label label="Text" />
-                        <TextView buffer=Some(doc.borrow().buffer.clone()) />
-                    </Box>
-                </Window>
+    /// Load a saved document into a new doc
+    fn open_document(&mut self, path: PathBuf) -> Option<PeerId> {
+        match self.read_file(&path).and_then(|bytes| file::decode(&bytes)) {
+            Ok(changes) => Some(self.add_doc(PeerStart::Open(changes), false, Some(path))),
+            Err(e) => {
+                tracing::error!("Could not open {}: {}", path.display(), e);
+                None
             }
         }
     }
 
-    fn change(&mut self, properties: Self::Properties) -> UpdateAction<Self> {
-        self.doc = properties.doc;
-        UpdateAction::Render
+    /// Reconstruct a document from an exported event log into a new doc. It
+    /// has no path, it isn't a saved document.
+    fn replay_document(&mut self, path: PathBuf) -> Option<PeerId> {
+        match self.read_file(&path).and_then(|bytes| event_log::decode(&bytes)) {
+            Ok(changes) => {
+                tracing::info!("Replaying {} changes from {}", changes.len(), path.display());
+                Some(self.add_doc(PeerStart::Open(changes), false, None))
+            }
+            Err(e) => {
+                tracing::error!("Could not replay {}: {}", path.display(), e);
+                None
+            }
+        }
     }
 
-    fn update(&mut self, msg: Self::Message) -> UpdateAction<Self> {
-        match msg {
-            DocMessage::Inc => {
-                self.doc.as_mut().map(|d| d.borrow_mut().inc_counter());
-                UpdateAction::Render
-            },
-            DocMessage::Exit => {
-                self.on_exit.send(());
-                UpdateAction::None
+    /// Close a tab. Dropping the doc closes its channel to the backend
+    /// thread, which then drops the backend.
+    fn close_doc(&mut self, peer_id: PeerId) {
+        if let Some(doc) = self.docs.remove(peer_id) {
+            self.presence.remove(&doc.borrow().actor_id());
+            self.sent_presence.remove(&doc.borrow().actor_id());
+        }
+        if let Some(typist) = self.typists.remove(&peer_id) {
+            glib::source_remove(typist);
+        }
+        self.documents.remove(&peer_id);
+        self.current = self.current.min(self.docs.len().saturating_sub(1));
+        self.watch_files();
+    }
+
+    fn show(&mut self, peer_id: PeerId) {
+        if let Some(n) = self.docs.position(peer_id) {
+            self.current = n;
+        }
+    }
+
+    fn current_doc(&self) -> Option<(PeerId, Rc<RefCell<Doc>>)> {
+        self.docs.nth(self.current).map(|(id, doc)| (id, doc.clone()))
+    }
+
+    fn save(&self, path: PathBuf) {
+        if let (Some(commands), Some((peer_id, _))) = (&self.commands, self.current_doc()) {
+            let key = self.keys.get(&path).cloned();
+            commands.send(BackendCommand::Save{peer_id, path, key}).unwrap();
+        }
+    }
+
+    /// The document title and our name in it
+    fn tab_label(&self, doc: &Doc) -> String {
+        match self.presence.get(&doc.actor_id()) {
+            Some(p) => format!("{} \u{2014} {}", doc.title(), p.identity.name),
+            None => doc.title(),
+        }
+    }
+
+    /// The peer id, label and doc for each tab. Until the docs are
+    /// initialized we show a single tab waiting for one.
+    fn tabs(&self) -> Vec<(PeerId, String, Option<Rc<RefCell<Doc>>>)> {
+        if self.docs.is_empty() {
+            vec![(PeerId(0), "Initializing".to_string(), None)]
+        } else {
+            self.docs.iter()
+                .map(|(id, doc)| (id, self.tab_label(&doc.borrow()), Some(doc.clone())))
+                .collect()
+        }
+    }
+
+    /// The presence of everyone editing the same document as `peer_id`
+    fn presence_for(&self, peer_id: PeerId) -> presence::PresenceMap {
+        let document = self.documents.get(&peer_id);
+        let actors: Vec<String> = self.documents.iter()
+            .filter(|(_, d)| Some(*d) == document)
+            .filter_map(|(id, _)| self.docs.get(*id))
+            .map(|doc| doc.borrow().actor_id())
+            .collect();
+        // libp2p peers are syncing the shared document with us
+        let shared = document.is_some() && document == self.documents.get(&SHARED_DOCUMENT);
+        let network = self.network_presence.iter().filter(|_| shared);
+        let id = self.docs.get(peer_id).map(|doc| doc.borrow().id);
+        let remote = self.remote_presence.iter()
+            .filter(|(_, p)| match p.state.as_ref().and_then(|s| s.document) {
+                Some(document) => Some(document) == id,
+                None => shared,
+            });
+        self.presence.iter()
+            .filter(|(actor, _)| actors.contains(actor))
+            .chain(network)
+            .chain(remote)
+            .map(|(actor, p)| (actor.clone(), p.clone()))
+            .collect()
+    }
+
+    /// The name of every actor we know, ours and those of the other
+    /// instances' tabs, by actor ID
+    /// Count the changes in the patch made by other instances for the
+    /// desktop notification
+    /// Apply patches from the backend thread, each run of them for the same
+    /// doc in one go, rendering at most once for all of them
+    fn patches_arrived(&mut self, envelopes: Vec<PatchEnvelope>) -> UpdateAction<Model> {
+        let mut render = false;
+        let mut envelopes = envelopes.into_iter().peekable();
+        while let Some(first) = envelopes.next() {
+            let peer_id = first.peer_id;
+            let mut run = vec![first];
+            while let Some(next) = envelopes.next_if(|e| e.peer_id == peer_id) {
+                run.push(next);
+            }
+            if !desktop::window_active() {
+                for envelope in &run {
+                    self.tally_remote_edits(envelope);
+                }
+            }
+            // One signal for each run, from where the last of it left the doc
+            let actor = run.last().and_then(|e| e.patch.actor.clone());
+            render |= self.docs.route(peer_id, run);
+            if let (Some(dbus), Some(position), Some(doc)) = (&self.dbus, self.docs.position(peer_id), self.docs.get(peer_id)) {
+                dbus.patch_applied(position, actor, doc.borrow().heads_hash());
+            }
+        }
+        self.request_resyncs();
+        if render {
+            UpdateAction::Render
+        } else {
+            UpdateAction::None
+        }
+    }
+
+    fn tally_remote_edits(&mut self, envelope: &PatchEnvelope) {
+        let remote: Vec<&ChangeMeta> = envelope.changes.iter()
+            .filter(|change| !self.presence.contains_key(&change.actor))
+            .collect();
+        if remote.is_empty() {
+            return;
+        }
+        let names = self.names();
+        let mut authors: Vec<String> = Vec::new();
+        for change in &remote {
+            let name = names.get(&change.actor).cloned()
+                .unwrap_or_else(|| change.actor.chars().take(change_log::ACTOR_CHARS).collect());
+            if !authors.contains(&name) {
+                authors.push(name);
+            }
+        }
+        let hashes: Vec<amp::ChangeHash> = remote.iter().map(|change| change.hash).collect();
+        let chars = text_patch::edits(&envelope.patch).map_or(0, |edits| edits.chars_changed());
+        self.desktop.edited(&hashes, authors, chars);
+    }
+
+    fn names(&self) -> BTreeMap<String, String> {
+        self.presence.iter()
+            .chain(self.remote_presence.iter())
+            .map(|(actor, p)| (actor.clone(), p.identity.name.clone()))
+            .collect()
+    }
+
+    /// Bring each tab's presence state up to date, and send the ones which
+    /// have changed, or which haven't been sent for a while, to the other
+    /// instances. Returns whether any of them changed.
+    fn share_presence(&mut self) -> bool {
+        let shared = self.documents.get(&SHARED_DOCUMENT).copied();
+        let mut changed = false;
+        for (peer_id, doc) in self.docs.iter() {
+            let doc = doc.borrow();
+            let actor_id = doc.actor_id();
+            let identity = match self.presence.get(&actor_id) {
+                Some(p) => p.identity.clone(),
+                None => continue,
+            };
+            let activity = doc.activity();
+            let state = presence::PresenceState {
+                actor_id: actor_id.clone(),
+                name: identity.name,
+                color: identity.color,
+                document: if shared.is_some() && self.documents.get(&peer_id).copied() == shared { None } else { Some(doc.id) },
+                seq: self.presence_seq + 1,
+                cursor: activity.cursor,
+                selection: activity.selection,
+                typing: activity.typing,
+                viewport: activity.viewport,
+            };
+            let due = match self.sent_presence.get(&actor_id) {
+                Some((sent, at)) => state.differs_from(sent) || at.elapsed() >= presence::KEEPALIVE,
+                None => true,
+            };
+            if !due {
+                continue;
             }
+            if let Some(p) = self.presence.get_mut(&actor_id) {
+                changed |= p.state.as_ref().map(|s| s.typing) != Some(state.typing);
+                p.state = Some(state.clone());
+            }
+            self.presence_seq += 1;
+            if let Some(commands) = &self.commands {
+                let _ = commands.send(BackendCommand::Presence(state.clone()));
+            }
+            self.sent_presence.insert(actor_id, (state, std::time::Instant::now()));
+        }
+        changed
+    }
+
+    /// The File, Edit, View and Tools menus. The items activate the window
+    /// actions declared in `view`.
+    /// The latest notification, floating over the top of the tabs until it
+    /// times out or is closed
+    fn toast_view(&self) -> VNode<Model> {
+        let toast = self.notifications.toast();
+        gtk!{
+            <Revealer halign=Align::Center valign=Align::Start transition_type=RevealerTransitionType::SlideDown reveal_child=toast.is_some()>
+                <Box orientation=Orientation::Horizontal spacing=12 on realize=|b| {
+                    // Adwaita's look for an in-app notification
+                    b.get_style_context().add_class("app-notification");
+                    Message::Noop
+                }>
+                    <Label label=toast.unwrap_or_default().to_string() line_wrap=true max_width_chars=80 />
+                    <Button image="window-close-symbolic" relief=ReliefStyle::None tooltip_text="Dismiss" on clicked=|_| Message::DismissToast />
+                </Box>
+            </Revealer>
+        }
+    }
+
+    /// The bell listing the recent notifications, with how many arrived
+    /// since it was last opened
+    fn notifications_view(&self) -> VNode<Model> {
+        let unread = self.notifications.unread();
+        let label = if unread > 0 { unread.to_string() } else { String::new() };
+        let recent: Vec<notifications::Notification> = self.notifications.recent().cloned().collect();
+        let empty = recent.is_empty();
+        gtk!{
+            <MenuButton HeaderBar::pack_type=PackType::End image="preferences-system-notifications-symbolic" label=label always_show_image=true
+                tooltip_text="Notifications" on clicked=|_| Message::ReadNotifications>
+                <Popover>
+                    <Box orientation=Orientation::Vertical spacing=6 border_width=10>
+                        <Label label="Nothing has happened yet" visible=empty sensitive=false />
+                        <ScrolledWindow hscrollbar_policy=PolicyType::Never propagate_natural_height=true max_content_height=400 visible={!empty}>
+                            <Box orientation=Orientation::Vertical spacing=6>
+                                {
+                                    recent.into_iter().map(|n| gtk!{
+                                        <Box orientation=Orientation::Horizontal spacing=12>
+                                            <Label label=n.time sensitive=false valign=Align::Start />
+                                            <Label label=n.message xalign=0.0 line_wrap=true max_width_chars=60 />
+                                        </Box>
+                                    })
+                                }
+                            </Box>
+                        </ScrolledWindow>
+                        <Button label="Clear" halign=Align::End sensitive={!empty} on clicked=|_| Message::ClearNotifications />
+                    </Box>
+                </Popover>
+            </MenuButton>
+        }
+    }
+
+    fn menus_view(&self) -> VNode<Model> {
+        let file_menu = vgtk::menu()
+            .section(
+                vgtk::menu()
+                    .item("New Document", "win.new")
+                    .item("New Document from JSON\u{2026}", "win.new-from-snapshot")
+                    .item("Open\u{2026}", "win.open"),
+            )
+            .section(
+                vgtk::menu()
+                    .item("Save", "win.save")
+                    .item("Save As\u{2026}", "win.save-as")
+                    .item("Encrypt with Passphrase\u{2026}", "win.encrypt")
+                    .item("Save State as JSON\u{2026}", "win.save-snapshot"),
+            )
+            .section(vgtk::menu().item("Import Text\u{2026}", "win.import-text").item("Export History\u{2026}", "win.export-history"))
+            .section(
+                vgtk::menu()
+                    .item("Export as Markdown\u{2026}", "win.export-markdown")
+                    .item("Export as HTML\u{2026}", "win.export-html")
+                    .item("Export as Plain Text\u{2026}", "win.export-text"),
+            )
+            .section(
+                vgtk::menu()
+                    .item("Start WebRTC Call\u{2026}", "win.start-call")
+                    .item("Answer WebRTC Call\u{2026}", "win.answer-call"),
+            )
+            .section(vgtk::menu().item("Close Tab", "win.close-tab").item("Quit", "win.quit"))
+            .build();
+        let edit_menu = vgtk::menu()
+            .section(vgtk::menu().item("Undo", "win.undo").item("Redo", "win.redo"))
+            .section(vgtk::menu().item("Suggest Changes", "win.suggest"))
+            .section(vgtk::menu().item("Find", "win.find"))
+            .section(vgtk::menu().item("Preferences\u{2026}", "win.preferences"))
+            .build();
+        let view_menu = vgtk::menu()
+            .item("Dark Mode", "win.dark-mode")
+            .item("Patch Log", "win.patch-log")
+            .item("Undo History", "win.undo-history")
+            .item("Split", "win.split")
+            .item("Line Numbers", "win.line-numbers")
+            .item("Blame", "win.blame")
+            .item("Check Spelling", "win.spellcheck")
+            .item("Markdown Preview", "win.preview")
+            .item("Compare\u{2026}", "win.compare")
+            .item("Statistics\u{2026}", "win.statistics")
+            .item("Keyboard Shortcuts", "win.show-help-overlay")
+            .build();
+        let tools_menu = vgtk::menu()
+            .item("Compact", "win.compact")
+            .item("Verify Convergence", "win.verify")
+            .item("Script Console", "win.script-console")
+            .build();
+        gtk!{
+            <Box orientation=Orientation::Horizontal spacing=2>
+                <MenuButton label="File" menu_model=Some(&file_menu) />
+                <MenuButton label="Edit" menu_model=Some(&edit_menu) />
+                <MenuButton label="View" menu_model=Some(&view_menu) />
+                <MenuButton label="Tools" menu_model=Some(&tools_menu) />
+            </Box>
         }
     }
 }
 
-#[derive(Default)]
-struct Model {
-    doc1: Option<Rc<RefCell<Doc>>>,
-    doc2: Option<Rc<RefCell<Doc>>>,
+/// How long quitting waits for the backend thread to apply and save the
+/// last changes before giving up on it
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How many rejected clients the peers popover lists
+const MAX_REJECTED: usize = 10;
+
+
+/// Recover whatever the last run left in the journal and start a new one,
+/// unless another instance is using it
+fn open_journal(fsync: journal::Fsync) -> (Option<journal::Journal>, Vec<(DocumentId, Vec<Change>)>) {
+    let path = match journal::default_path() {
+        Some(path) => path,
+        None => return (None, Vec::new()),
+    };
+    match journal::claim(&path) {
+        Ok(true) => {}
+        Ok(false) => {
+            tracing::warn!("another instance is using the journal in {}, not keeping one", path.display());
+            return (None, Vec::new());
+        }
+        Err(e) => {
+            tracing::warn!("not keeping a journal, {} isn't writable: {}", path.display(), e);
+            return (None, Vec::new());
+        }
+    }
+    let recovered = journal::recover(&path).unwrap_or_else(|e| {
+        tracing::error!("could not recover the journal in {}: {}", path.display(), e);
+        Vec::new()
+    });
+    if !recovered.is_empty() {
+        tracing::warn!(documents = recovered.len(), "recovered documents from the journal after an unclean exit");
+    }
+    match journal::Journal::create(&path, fsync) {
+        Ok(journal) => (Some(journal), recovered),
+        Err(e) => {
+            tracing::warn!("could not start a journal in {}: {}", path.display(), e);
+            (None, recovered)
+        }
+    }
 }
 
+/// Every document in the store, skipping any which can't be loaded
+fn load_stored(storage: &mut dyn Storage) -> Vec<(DocumentId, Vec<Change>)> {
+    let documents = storage.documents().unwrap_or_else(|e| {
+        eprintln!("could not list the documents in the store: {}", e);
+        std::process::exit(1);
+    });
+    documents.into_iter()
+        .filter_map(|id| match storage.load_document(id) {
+            Ok(changes) if !changes.is_empty() => Some((id, changes)),
+            Ok(_) => None,
+            Err(e) => {
+                tracing::error!("Could not load document {} from the store: {}", id, e);
+                None
+            }
+        })
+        .collect()
+}
 
-#[derive(Clone, Debug)]
-enum Message {
-    Exit,
-    /// Fired once the backend thread has started and we have senders to give
-    /// to our docs
-    Initialized{
-        sx1: crossbeam::Sender<amp::Request>,
-        sx2: crossbeam::Sender<amp::Request>,
-    },
-    /// Pushed into the application scope by the backend thread when new
-    /// patches are received
-    Patch {
-        doc1: Option<amp::Patch>,
-        doc2: Option<amp::Patch>,
+/// Read the server given to `--connect`, `wss://<address>` for TLS, trusted
+/// with `pin` if there is one, or `ws://<address>` or just the address
+/// without
+fn parse_connect(url: &str, pin: Option<String>) -> Result<(SocketAddr, Option<tls::Trust>), String> {
+    use std::net::ToSocketAddrs;
+    let (addr, tls) = if let Some(addr) = url.strip_prefix("wss://") {
+        (addr, true)
+    } else {
+        (url.strip_prefix("ws://").unwrap_or(url), false)
+    };
+    let addr = addr.trim_end_matches('/');
+    let addr = addr.to_socket_addrs()
+        .map_err(|e| e.to_string())?
+        .next()
+        .ok_or_else(|| "the address didn't resolve".to_string())?;
+    let trust = if tls { Some(pin.map(tls::Trust::Pinned).unwrap_or(tls::Trust::OnFirstUse)) } else { None };
+    Ok((addr, trust))
+}
+
+/// The certificate and key for a server, if there are both
+fn server_tls(args: &cli::TlsArgs) -> Option<Arc<rustls::ServerConfig>> {
+    match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => Some(tls::server_config(cert, key).unwrap_or_else(|e| {
+            eprintln!("could not load the TLS certificate: {}", e);
+            std::process::exit(1);
+        })),
+        _ => None,
     }
 }
 
-impl Component for Model {
-    type Message = Message;
-    type Properties = ();
+fn load_auth(args: &cli::AuthArgs) -> auth::Auth {
+    auth::Auth {
+        passphrase: args.passphrase.clone(),
+        key: args.auth_key.as_ref().map(|path| auth::load_key(path).unwrap_or_else(|e| {
+            eprintln!("could not read the key {}: {}", path.display(), e);
+            std::process::exit(1);
+        })),
+        authorized: args.authorized_keys.as_ref()
+            .map(|path| auth::load_authorized(path).unwrap_or_else(|e| {
+                eprintln!("could not read the authorized keys {}: {}", path.display(), e);
+                std::process::exit(1);
+            }))
+            .unwrap_or_default(),
+    }
+}
 
-    fn update(&mut self, msg: Self::Message) -> UpdateAction<Self> {
-        match msg {
-            Message::Exit => {
-                vgtk::quit();
-                UpdateAction::None
-            }
-            Message::Initialized{sx1, sx2} => {
-                self.doc1 = Some(Rc::new(RefCell::new(Doc::new(sx1))));
-                self.doc2 = Some(Rc::new(RefCell::new(Doc::new(sx2))));
-                UpdateAction::Render
-            },
-            Message::Patch{doc1: patch1, doc2: patch2} => {
-                self.doc1.as_mut().map(|d| d.borrow_mut().apply_patch(patch1));
-                self.doc2.as_mut().map(|d| d.borrow_mut().apply_patch(patch2));
-                UpdateAction::Render
-            },
+fn load_sync_filters(filters: &[String]) -> sync_filter::SyncFilters {
+    let mut sync_filters = sync_filter::SyncFilters::default();
+    for filter in filters {
+        if let Err(e) = sync_filters.add(filter) {
+            eprintln!("--sync-filter: {}", e);
+            std::process::exit(1);
         }
     }
+    sync_filters
+}
 
-    fn view(&self) -> VNode<Model> {
-        gtk! {
-            <Application::new_unwrap(Some("com.example.automerge-demo"), ApplicationFlags::empty())>
-                <@DocView doc=self.doc1.clone() on exit=|_| Message::Exit />
-                <@DocView doc=self.doc2.clone() on exit=|_| Message::Exit />
-            </Application>
+fn main() {
+    let cli = cli::Cli::parse();
+    let _trace_guard = telemetry::init(cli.trace_file.clone());
+
+    // We've been spawned by another instance of the demo to host a backend
+    if let Some(socket_path) = &cli.backend_worker {
+        ipc::run_worker(socket_path).unwrap();
+        return;
+    }
+    // The Rust side of the interop checks with the JS automerge
+    let interop = cli.interop_save.as_deref().map(interop::save)
+        .or_else(|| cli.interop_load.as_deref().map(interop::load));
+    if let Some(result) = interop {
+        if let Err(e) = result {
+            eprintln!("interop check failed: {}", e);
+            std::process::exit(1);
         }
+        return;
+    }
+    match cli.command.unwrap_or(cli::Command::Gui(cli.gui)) {
+        cli::Command::Gui(args) => gui(args),
+        // The document on its own, without a window
+        cli::Command::Serve(args) => {
+            if let Err(e) = file::load(&args.file) {
+                eprintln!("could not load {}: {}", args.file.display(), e);
+                std::process::exit(1);
+            }
+            let auth = Arc::new(load_auth(&args.auth));
+            let (commands_sx, commands_rx) = crossbeam::channel::unbounded();
+            if let Some(port) = args.http_port {
+                http::serve(port, commands_sx).unwrap();
+            }
+            let filters = load_sync_filters(&args.sync_filter);
+            if let Err(e) = relay::run(args.port, server_tls(&args.tls), auth, Some(args.file), filters, commands_rx) {
+                eprintln!("serving the document failed: {}", e);
+                std::process::exit(1);
+            }
+        }
+        // A headless relay server rather than the GUI
+        cli::Command::Relay(args) => {
+            let auth = Arc::new(load_auth(&args.auth));
+            let filters = load_sync_filters(&args.sync_filter);
+            if let Err(e) = relay::run(args.port, server_tls(&args.tls), auth, args.store, filters, crossbeam::channel::never()) {
+                eprintln!("the relay failed: {}", e);
+                std::process::exit(1);
+            }
+        }
+        cli::Command::Inspect(args) => {
+            if let Err(e) = inspect::run(&args) {
+                eprintln!("could not inspect {}: {}", args.file.display(), e);
+                std::process::exit(1);
+            }
+        }
+        cli::Command::Simulate(args) => {
+            #[cfg(not(feature = "unified"))]
+            {
+                if args.unified {
+                    eprintln!("--unified needs the demo to be built with `--features unified`");
+                    std::process::exit(1);
+                }
+            }
+            match simulate::run(&args) {
+                Ok(texts) if texts.windows(2).all(|pair| pair[0] == pair[1]) => {
+                    println!("{} peers made {} edits each and converged on {} characters", args.peers, args.edits, texts[0].chars().count());
+                }
+                Ok(texts) => {
+                    eprintln!("the peers diverged:");
+                    for (i, text) in texts.iter().enumerate() {
+                        eprintln!("  peer {}: {} characters", i + 1, text.chars().count());
+                    }
+                    std::process::exit(1);
+                }
+                Err(e) => {
+                    eprintln!("the simulation failed: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        cli::Command::Bench(args) => {
+            #[cfg(not(feature = "unified"))]
+            {
+                if args.unified {
+                    eprintln!("--unified needs the demo to be built with `--features unified`");
+                    std::process::exit(1);
+                }
+            }
+            if let Err(e) = bench::run(&args) {
+                eprintln!("the benchmark failed: {}", e);
+                std::process::exit(1);
+            }
+        }
+        // Compact a saved document and go
+        cli::Command::Compact{file: path} => {
+            let result = file::load(&path).and_then(|changes| file::compact(&path, &changes, None));
+            match result {
+                Ok((before, after)) => println!("compacted {} from {} to {}", path.display(), file::human_size(before), file::human_size(after)),
+                Err(e) => {
+                    eprintln!("could not compact {}: {}", path.display(), e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        cli::Command::GenerateKey{file: path} => match auth::generate_key(&path) {
+            Ok(public_key) => println!("{}", public_key),
+            Err(e) => {
+                eprintln!("could not write the key to {}: {}", path.display(), e);
+                std::process::exit(1);
+            }
+        },
     }
 }
 
-fn main() {
-    pretty_env_logger::init();
+/// The window, and everything behind it
+fn gui(args: cli::GuiArgs) {
+    let cli::GuiArgs {
+        backend_process, backend_per_tab, serve_ws: ws_port, listen_tls: tls_port, listen, tls: tls_args, auth: auth_args, sign_key, sync_filter, control_socket,
+        attach: attaching, connect, pin, libp2p: use_libp2p,
+        #[cfg(feature = "libp2p")] libp2p_port,
+        #[cfg(feature = "libp2p")] libp2p_document,
+        #[cfg(feature = "libp2p")] libp2p_peer,
+        metrics_port, http_port, open: opened, replay, record_session, play_session, play_speed, stress,
+        chaos, chaos_delay_ms, chaos_pause_ms, chaos_seed, save_on_exit, coalesce_ms, dbus: use_dbus, no_workspace, no_journal, journal_fsync, storage, schema, gtk_args,
+    } = args;
+    let tls_config = tls_port.and_then(|_| server_tls(&tls_args));
+    let auth = load_auth(&auth_args);
+    let signatures = sign_key.map(|path| {
+        let key = auth::load_key(&path).unwrap_or_else(|e| {
+            eprintln!("could not read the key {}: {}", path.display(), e);
+            std::process::exit(1);
+        });
+        let signatures = signing::Signatures::new(key, auth.authorized.clone());
+        tracing::info!("signing changes with {}", signatures.public_key());
+        Arc::new(signatures)
+    });
+    let sync_filters = load_sync_filters(&sync_filter);
+    let auth = Arc::new(auth);
+    let control_socket = control_socket.unwrap_or_else(attach::default_socket_path);
+    // Another window on a running instance's backend, rather than backends
+    // of our own
+    let attached = attaching.then(|| attach::connect(&control_socket).unwrap_or_else(|e| {
+        eprintln!("could not attach to {}: {}", control_socket.display(), e);
+        std::process::exit(1);
+    }));
+    let connect = connect.map(|url| parse_connect(&url, pin.clone()).unwrap_or_else(|e| {
+        eprintln!("could not connect to {}: {}", url, e);
+        std::process::exit(1);
+    }));
+    if pin.is_some() && !matches!(connect, Some((_, Some(_)))) {
+        eprintln!("--pin needs --connect wss://<address>");
+        std::process::exit(1);
+    }
+    #[cfg(feature = "libp2p")]
+    let p2p_options = p2p::Options {
+        port: libp2p_port,
+        document: libp2p_document,
+        bootstrap: libp2p_peer.iter().map(|addr| addr.parse().expect("--libp2p-peer expects a multiaddr")).collect(),
+    };
+    #[cfg(not(feature = "libp2p"))]
+    {
+        if use_libp2p {
+            eprintln!("--libp2p needs the demo to be built with `--features libp2p`");
+            std::process::exit(1);
+        }
+    }
+    #[cfg(not(feature = "metrics"))]
+    {
+        if metrics_port.is_some() {
+            eprintln!("--metrics-port needs the demo to be built with `--features metrics`");
+            std::process::exit(1);
+        }
+    }
+    let session = play_session.map(|path| session::load(&path).expect("could not read the session recording"));
+    let coalesce = coalesce_ms.map(Duration::from_millis);
+    let journal_fsync = journal_fsync.unwrap_or(journal::Fsync::Every(Duration::from_millis(journal::DEFAULT_FSYNC_MS)));
+    let storage = storage.map(|spec| storage::open(&spec).unwrap_or_else(|e| {
+        eprintln!("could not open the store {}: {}", spec, e);
+        std::process::exit(1);
+    }));
+    let schema = schema
+        .map(|path| schema::Schema::load(&path).unwrap_or_else(|e| {
+            eprintln!("could not read the schema {}: {}", path.display(), e);
+            std::process::exit(1);
+        }))
+        .unwrap_or_default();
+
+    if attached.is_some() {
+        let local_only = [
+            (backend_process, "--backend-process"),
+            (ws_port.is_some() || tls_port.is_some(), "a server"),
+            (connect.is_some(), "--connect"),
+            (use_libp2p, "--libp2p"),
+            (http_port.is_some(), "--http-port"),
+            (storage.is_some(), "--storage"),
+            (opened.is_some() || replay.is_some(), "opening a document"),
+        ];
+        if let Some((_, what)) = local_only.iter().find(|(used, _)| *used) {
+            eprintln!("--attach can't be used with {}, the instance being attached to does that", what);
+            std::process::exit(1);
+        }
+        attach::set_attached();
+    }
+    // The settings fill in for the flags which weren't given. An attached
+    // window's servers would be the ones the instance it's attached to
+    // already has on those ports.
+    let settings = settings::load();
+    let coalesce = coalesce.or_else(|| settings.coalesce());
+    let (ws_port, http_port, metrics_port) = if attached.is_some() {
+        (ws_port, http_port, metrics_port)
+    } else {
+        (
+            ws_port.or(settings.serve_port.filter(|_| tls_port.is_none())),
+            http_port.or(settings.http_port),
+            metrics_port.or(settings.metrics_port),
+        )
+    };
+
+    let keep_workspace = !no_workspace && attached.is_none();
+    let (journal, recovered) = if no_journal || attached.is_some() {
+        (None, Vec::new())
+    } else {
+        open_journal(journal_fsync)
+    };
+    let mut storage = storage;
+    // The store takes the place of the workspace as where the documents
+    // come from
+    let stored = match (&mut storage, opened.is_none() && replay.is_none()) {
+        (Some(storage), true) => load_stored(storage.as_mut()),
+        _ => Vec::new(),
+    };
+    let workspace = if keep_workspace && storage.is_none() && opened.is_none() && replay.is_none() {
+        workspace::load()
+    } else {
+        None
+    };
+
     let (app, scope) = start::<Model>();
-    let args: Vec<String> = std::env::args().collect();
-    let (sx1, rx1) = crossbeam::channel::unbounded();
-    let (sx2, rx2) = crossbeam::channel::unbounded();
     let (closesx, closerx) = crossbeam::channel::unbounded::<()>();
     let scope_clone = scope.clone();
 
+    let (ws_sx, ws_rx) = crossbeam::channel::unbounded();
+    // We can only be discovered if there's a server for peers on the
+    // network to connect to
+    let _discovery = ws_port.or(tls_port).and_then(|port| {
+        let tls = tls_config.is_some();
+        ws::listen(listen, port, tls_config, auth.clone(), ws_sx.clone()).unwrap();
+        (!listen.is_loopback()).then(|| discovery::start(port, tls, scope.clone()).unwrap())
+    });
+    #[cfg(feature = "libp2p")]
+    {
+        if use_libp2p {
+            p2p::start(p2p_options, ws_sx.clone());
+        }
+    }
+
+    let dbus = use_dbus.then(|| dbus::start(scope.clone())).and_then(|result| result.map_err(|e| {
+        tracing::error!("could not serve {} on the session bus: {}", dbus::NAME, e);
+    }).ok());
+    let watcher = watch::start(scope.clone()).map_err(|e| {
+        tracing::warn!("other programs' changes to open files won't be noticed: {}", e);
+    }).ok();
+    let (commands_sx, commands_rx) = crossbeam::channel::unbounded();
+    if attached.is_none() {
+        if let Err(e) = attach::listen(&control_socket, commands_sx.clone()) {
+            tracing::warn!("other windows can't attach at {}: {}", control_socket.display(), e);
+        }
+    }
+    let metrics = Arc::new(metrics::Metrics::default());
+    #[cfg(feature = "metrics")]
+    {
+        if let Some(port) = metrics_port {
+            prometheus::serve(port, metrics.clone()).unwrap();
+        }
+    }
+    // Only the settings can get here with a port
+    #[cfg(not(feature = "metrics"))]
+    {
+        if let Some(port) = metrics_port {
+            tracing::warn!("not serving metrics on port {}, that needs the demo to be built with `--features metrics`", port);
+        }
+    }
+    if let Some(port) = http_port {
+        http::serve(port, commands_sx.clone()).unwrap();
+    }
+    scope_clone.send_message(Message::Initialized{ws_events: ws_sx, commands: commands_sx, opened, replay, record_session, metrics: metrics.clone(), signatures: signatures.clone(), stress, coalesce, schema: Arc::new(schema), save_on_exit, connect, auth, dbus, stored, recovered, watcher, workspace, keep_workspace, settings, renders: render::Scheduler::new(scope.clone()), #[cfg(feature = "scripting")] scripts: script::Runner::new(scope.clone())});
+
+    let transports: Vec<Box<dyn Transport>> = vec![Box::new(transport::Channels::new(ws_rx, sync_filters))];
+    let chaos = chaos.then(|| Chaos::new(ChaosConfig{
+        seed: chaos_seed,
+        max_delay: Duration::from_millis(chaos_delay_ms),
+        max_pause: Duration::from_millis(chaos_pause_ms),
+    }));
+    // What chaos holds back goes between replicas, which there aren't with
+    // an engine for each document
+    let backend_per_tab = backend_per_tab || chaos.is_some();
+    let config = BackendConfig{storage, journal, signatures, transports, metrics, chaos, backend_per_tab};
+    let (ui, bridge) = bridge::channel(scope);
     let backend_thread = std::thread::spawn(move || {
-        let mut backend1 = Backend::init();
-        let mut backend2 = Backend::init();
-        loop {
-            crossbeam::select!{
-                recv(rx1) -> msg => {
-                    let patch1 = backend1.apply_local_change(msg.unwrap()).unwrap();
-                    let patch2 = backend2.apply_changes(backend1.get_changes(&[]).iter().copied().cloned().collect()).unwrap();
-                    scope.try_send(Message::Patch{doc1: Some(patch1), doc2: Some(patch2)}).unwrap();
-                }
-                recv(rx2) -> msg => {
-                    let patch2 = backend2.apply_local_change(msg.unwrap()).unwrap();
-                    let patch1 = backend1.apply_changes(backend2.get_changes(&[]).iter().copied().cloned().collect()).unwrap();
-                    scope.try_send(Message::Patch{doc1: Some(patch1), doc2: Some(patch2)}).unwrap();
-                }
-                recv(closerx) -> _ => return
-            }
-        };
+        if let Some(stream) = attached {
+            attach::run_client(stream, ui, commands_rx, closerx);
+        } else if backend_process {
+            let new_backend = |peer_id: PeerId, id: DocumentId| ipc::BackendProcess::spawn(&format!("{}-peer{}", id, peer_id.0)).unwrap();
+            backend::run_backends(new_backend, closerx, ui, commands_rx, config);
+        } else {
+            backend::run_backends(|_, _| Backend::init(), closerx, ui, commands_rx, config);
+        }
+    });
+
+    // Drive presence updates, this stops when the application has gone away
+    let presence_scope = scope_clone.clone();
+    std::thread::spawn(move || loop {
+        std::thread::sleep(presence::TICK_INTERVAL);
+        if presence_scope.try_send(Message::PresenceTick).is_err() {
+            return;
+        }
+    });
+    if let Some(events) = session {
+        let play_scope = scope_clone.clone();
+        std::thread::spawn(move || {
+            session::play(events, play_speed, |event| play_scope.try_send(Message::Play(event)).is_ok())
+        });
+    }
+    let metrics_scope = scope_clone.clone();
+    std::thread::spawn(move || loop {
+        std::thread::sleep(metrics::SAMPLE_INTERVAL);
+        if metrics_scope.try_send(Message::MetricsTick).is_err() {
+            return;
+        }
     });
-    scope_clone.send_message(Message::Initialized{sx1, sx2});
 
-    app.run(&args);
-    closesx.send(()).unwrap();
+    // GTK rejects options it doesn't know, so it only gets what came after `--`
+    let program = std::env::args().next().unwrap_or_default();
+    app.run(&std::iter::once(program).chain(gtk_args).collect::<Vec<_>>());
+    // The backend thread has normally stopped already, after the UI told it
+    // to shut down, but it may be waiting for the UI to take a message
+    bridge.close();
+    let _ = closesx.send(());
     backend_thread.join().unwrap();
 }
@@ -0,0 +1,135 @@
+//! The Markdown preview, View > Markdown Preview, which shows the text
+//! rendered as Markdown next to the text itself.
+//!
+//! pulldown-cmark parses the text and I turn the events into Pango markup
+//! for a label, which covers everything the preview needs without pulling in
+//! a web view. The preview is updated on every patch, but incrementally: the
+//! text is split into blocks at blank lines and each block is only parsed
+//! and rendered again if its source has changed, so typing in one paragraph
+//! of a long document only re-renders that paragraph.
+
+use pulldown_cmark::{Event, Options, Parser, Tag};
+use std::collections::HashMap;
+use vgtk::lib::glib;
+
+#[derive(Default)]
+pub struct MarkdownPreview {
+    /// The markup for each block of the text as of the last update, by its
+    /// source
+    blocks: HashMap<String, String>,
+    markup: String,
+}
+
+impl MarkdownPreview {
+    /// Render `text`, reusing the markup for every block which hasn't changed
+    pub fn update(&mut self, text: &str) {
+        let mut blocks = HashMap::new();
+        let mut markup = Vec::new();
+        for block in split_blocks(text) {
+            let rendered = match self.blocks.remove(block) {
+                Some(rendered) => rendered,
+                None => render(block),
+            };
+            markup.push(rendered.clone());
+            blocks.insert(block.to_string(), rendered);
+        }
+        self.blocks = blocks;
+        self.markup = markup.join("\n\n");
+    }
+
+    /// The Pango markup for the whole text
+    pub fn markup(&self) -> &str {
+        &self.markup
+    }
+}
+
+/// Split the text into blocks separated by blank lines, keeping fenced code
+/// blocks whole even if they contain blank lines
+fn split_blocks(text: &str) -> Vec<&str> {
+    let mut blocks = Vec::new();
+    let mut start = None;
+    let mut in_fence = false;
+    let mut offset = 0;
+    while offset < text.len() {
+        let end = text[offset..].find('\n').map(|i| offset + i + 1).unwrap_or(text.len());
+        let trimmed = text[offset..end].trim();
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            in_fence = !in_fence;
+        }
+        if trimmed.is_empty() && !in_fence {
+            if let Some(s) = start.take() {
+                blocks.push(&text[s..offset]);
+            }
+        } else if start.is_none() {
+            start = Some(offset);
+        }
+        offset = end;
+    }
+    if let Some(s) = start {
+        blocks.push(&text[s..]);
+    }
+    blocks
+}
+
+/// Render one block of Markdown as Pango markup
+fn render(source: &str) -> String {
+    let mut markup = String::new();
+    // The next number of each ordered list we're in, `None` for bullets
+    let mut lists: Vec<Option<u64>> = Vec::new();
+    for event in Parser::new_ext(source, Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TASKLISTS) {
+        match event {
+            Event::Start(tag) => match tag {
+                Tag::Heading(level) => {
+                    let size = match level {
+                        1 => "xx-large",
+                        2 => "x-large",
+                        3 => "large",
+                        _ => "medium",
+                    };
+                    markup.push_str(&format!("<span size=\"{}\" weight=\"bold\">", size));
+                }
+                Tag::BlockQuote => markup.push_str("<span foreground=\"#777777\"><i>"),
+                Tag::CodeBlock(_) => markup.push_str("<tt>"),
+                Tag::List(first) => lists.push(first),
+                Tag::Item => {
+                    markup.push_str(&"    ".repeat(lists.len().saturating_sub(1)));
+                    match lists.last_mut() {
+                        Some(Some(n)) => {
+                            markup.push_str(&format!("{}. ", n));
+                            *n += 1;
+                        }
+                        _ => markup.push_str("\u{2022} "),
+                    }
+                }
+                Tag::Emphasis => markup.push_str("<i>"),
+                Tag::Strong => markup.push_str("<b>"),
+                Tag::Strikethrough => markup.push_str("<s>"),
+                Tag::Link(..) | Tag::Image(..) => markup.push_str("<span foreground=\"#3465a4\"><u>"),
+                _ => {}
+            },
+            Event::End(tag) => match tag {
+                Tag::Heading(_) => markup.push_str("</span>\n"),
+                Tag::Paragraph => markup.push('\n'),
+                Tag::BlockQuote => markup.push_str("</i></span>"),
+                Tag::CodeBlock(_) => markup.push_str("</tt>"),
+                Tag::List(_) => {
+                    lists.pop();
+                }
+                Tag::Item => markup.push('\n'),
+                Tag::Emphasis => markup.push_str("</i>"),
+                Tag::Strong => markup.push_str("</b>"),
+                Tag::Strikethrough => markup.push_str("</s>"),
+                Tag::Link(..) | Tag::Image(..) => markup.push_str("</u></span>"),
+                _ => {}
+            },
+            Event::Text(text) | Event::Html(text) => markup.push_str(&glib::markup_escape_text(&text)),
+            Event::Code(code) => markup.push_str(&format!("<tt>{}</tt>", glib::markup_escape_text(&code))),
+            Event::SoftBreak => markup.push(' '),
+            Event::HardBreak => markup.push('\n'),
+            Event::Rule => markup.push_str("\u{2015}\u{2015}\u{2015}\u{2015}\u{2015}\u{2015}\n"),
+            Event::TaskListMarker(done) => markup.push_str(if done { "\u{2611} " } else { "\u{2610} " }),
+            Event::FootnoteReference(name) => markup.push_str(&format!("[{}]", glib::markup_escape_text(&name))),
+        }
+    }
+    markup.trim_end().to_string()
+}
@@ -0,0 +1,215 @@
+//! Rich text formatting stored in the CRDT.
+//!
+//! Automerge doesn't (yet) have a notion of formatting, so marks are stored
+//! in a list alongside the text at `root.marks`. Each mark is a map
+//!
+//! ```text
+//! { "type": "bold", "start": "<op id>", "end": "<op id>" }
+//! ```
+//!
+//! where `start` and `end` are the IDs of the ops which inserted the first and
+//! last characters of the span. Anchoring to op IDs rather than offsets means
+//! that when concurrent edits insert or delete text before or inside the span
+//! the mark still covers the same characters - we just look up where those
+//! characters currently are using the provenance index, see
+//! `offset_index.rs`. If one of the anchor characters is deleted the mark no
+//! longer resolves and isn't rendered.
+
+use automerge_frontend::{Frontend, LocalChange, Path, Value};
+use automerge_protocol as amp;
+use maplit::hashmap;
+use vgtk::lib::glib::translate::ToGlib;
+use vgtk::lib::gtk::prelude::*;
+use vgtk::lib::gtk::{TextBuffer, TextTag};
+
+use crate::offset_index::OffsetIndex;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MarkType {
+    Bold,
+    Italic,
+    Underline,
+}
+
+impl MarkType {
+    pub const ALL: [MarkType; 3] = [MarkType::Bold, MarkType::Italic, MarkType::Underline];
+
+    /// The name used both in the document and for the `TextTag`
+    pub fn name(self) -> &'static str {
+        match self {
+            MarkType::Bold => "bold",
+            MarkType::Italic => "italic",
+            MarkType::Underline => "underline",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<MarkType> {
+        MarkType::ALL.iter().copied().find(|m| m.name() == name)
+    }
+}
+
+/// A mark as stored in the document
+#[derive(Clone, Debug)]
+pub struct Mark {
+    /// Where this mark is in the `root.marks` list
+    pub list_index: usize,
+    pub mark_type: MarkType,
+    pub start: String,
+    pub end: String,
+}
+
+impl Mark {
+    /// The char range (end exclusive) this mark currently covers, if both its
+    /// anchors still exist
+    pub fn resolve(&self, offsets: &OffsetIndex) -> Option<(usize, usize)> {
+        let start = offsets.span_of(&self.start)?.start;
+        let end = offsets.span_of(&self.end)?.end;
+        if start < end {
+            Some((start, end))
+        } else {
+            None
+        }
+    }
+}
+
+/// The value to initialize `root.marks` with
+pub fn initial_value() -> Value {
+    Value::Sequence(Vec::new(), amp::SequenceType::List)
+}
+
+/// Create a tag for each mark type in the buffer's tag table
+pub fn create_tags(buffer: &TextBuffer) {
+    let table = buffer.get_tag_table().unwrap();
+    for mark_type in MarkType::ALL.iter() {
+        let tag = TextTag::new(Some(mark_type.name()));
+        match mark_type {
+            MarkType::Bold => tag.set_property_weight(pango::Weight::Bold.to_glib()),
+            MarkType::Italic => tag.set_property_style(pango::Style::Italic),
+            MarkType::Underline => tag.set_property_underline(pango::Underline::Single),
+        }
+        table.add(&tag);
+    }
+}
+
+/// Read the list of marks out of the frontend, along with the length of the
+/// list (which may include entries we don't understand)
+pub fn marks(frontend: &Frontend) -> (Vec<Mark>, usize) {
+    match frontend.get_value(&Path::root().key("marks")) {
+        Some(Value::Sequence(vals, _)) => (
+            vals.iter()
+                .enumerate()
+                .filter_map(|(i, v)| parse_mark(i, v))
+                .collect(),
+            vals.len(),
+        ),
+        _ => (Vec::new(), 0),
+    }
+}
+
+fn parse_mark(list_index: usize, value: &Value) -> Option<Mark> {
+    let props = match value {
+        Value::Map(props, _) => props,
+        _ => return None,
+    };
+    let string = |key: &str| match props.get(key) {
+        Some(Value::Primitive(amp::Value::Str(s))) => Some(s.clone()),
+        _ => None,
+    };
+    Some(Mark {
+        list_index,
+        mark_type: MarkType::from_name(&string("type")?)?,
+        start: string("start")?,
+        end: string("end")?,
+    })
+}
+
+/// Remove every mark tag from the buffer and apply the tags for the marks
+/// currently in the document
+pub fn render(buffer: &TextBuffer, marks: &[Mark], offsets: &OffsetIndex) {
+    let (start, end) = buffer.get_bounds();
+    for mark_type in MarkType::ALL.iter() {
+        buffer.remove_tag_by_name(mark_type.name(), &start, &end);
+    }
+    for mark in marks {
+        if let Some((from, to)) = mark.resolve(offsets) {
+            let from = buffer.get_iter_at_offset(from as i32);
+            let to = buffer.get_iter_at_offset(to as i32);
+            buffer.apply_tag_by_name(mark.mark_type.name(), &from, &to);
+        }
+    }
+}
+
+/// Toggle `mark_type` over the chars `start..end`. If marks of this type
+/// already cover the whole range they are removed, otherwise a new mark is
+/// added. Returns `None` if there's nothing to do, which includes the case
+/// where the characters haven't been acknowledged by the backend yet and so
+/// we don't know their op IDs.
+pub fn toggle(
+    frontend: &mut Frontend,
+    offsets: &OffsetIndex,
+    mark_type: MarkType,
+    start: usize,
+    end: usize,
+) -> Option<amp::Request> {
+    if start >= end {
+        return None;
+    }
+    let (existing, len) = marks(frontend);
+    let covering: Vec<usize> = existing
+        .iter()
+        .filter(|m| m.mark_type == mark_type)
+        .filter(|m| match m.resolve(offsets) {
+            Some((from, to)) => from <= start && to >= end,
+            None => false,
+        })
+        .map(|m| m.list_index)
+        .collect();
+    if !covering.is_empty() {
+        return frontend
+            .change(Some(format!("Remove {}", mark_type.name())), |doc| {
+                // Delete from the back so earlier indices stay valid
+                for i in covering.iter().rev() {
+                    doc.add_change(LocalChange::delete(Path::root().key("marks").index(*i)))?;
+                }
+                Ok(())
+            })
+            .unwrap();
+    }
+    let start_op = offsets.op_at(start)?.to_string();
+    let end_op = offsets.op_at(end - 1)?.to_string();
+    frontend
+        .change(Some(format!("Make text {}", mark_type.name())), |doc| {
+            doc.add_change(LocalChange::insert(Path::root().key("marks").index(len), value(mark_type, &start_op, &end_op)))?;
+            Ok(())
+        })
+        .unwrap()
+}
+
+/// Add marks of each type from the char inserted by one op to the char
+/// inserted by another, as pasting does, see `clipboard.rs`
+pub fn add(frontend: &mut Frontend, anchored: &[(MarkType, String, String)]) -> Option<amp::Request> {
+    if anchored.is_empty() {
+        return None;
+    }
+    let (_, len) = marks(frontend);
+    frontend
+        .change(Some("Paste formatting".to_string()), |doc| {
+            for (i, (mark_type, start_op, end_op)) in anchored.iter().enumerate() {
+                doc.add_change(LocalChange::insert(Path::root().key("marks").index(len + i), value(*mark_type, start_op, end_op)))?;
+            }
+            Ok(())
+        })
+        .unwrap()
+}
+
+/// A mark as it's stored in `root.marks`
+fn value(mark_type: MarkType, start_op: &str, end_op: &str) -> Value {
+    Value::Map(
+        hashmap! {
+            "type".to_string() => Value::Primitive(amp::Value::Str(mark_type.name().to_string())),
+            "start".to_string() => Value::Primitive(amp::Value::Str(start_op.to_string())),
+            "end".to_string() => Value::Primitive(amp::Value::Str(end_op.to_string())),
+        },
+        amp::MapType::Map,
+    )
+}
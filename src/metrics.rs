@@ -0,0 +1,238 @@
+//! Counters for the change pipeline, shown in the metrics panel.
+//!
+//! Every change request a frontend sends and every patch acknowledging one
+//! is counted here, along with the round trip time between the two. Once a
+//! second the model takes a sample - requests per second, the mean round
+//! trip over that second and how many requests are waiting in the channels
+//! to the backend - and the panel draws the last minute of samples as
+//! sparklines.
+//!
+//! The backend thread reports here too. Each time it wakes up it notes how
+//! many messages are still waiting in its channels, change requests from
+//! every doc and attached frontend, websocket events and commands, and how
+//! long it takes to handle the one it woke up for. The sample turns that
+//! into the deepest the backend's queue got over the second, the mean and
+//! longest time a message took, and the share of the second the backend
+//! spent busy, which the panel shows as gauges. Under `--stress` a backend
+//! which can't keep up shows as the busy gauge pinned at the top and the
+//! queue growing, rather than having to be worked out from the round trip.
+//!
+//! The metrics are shared between every window and the Prometheus endpoint
+//! (see `prometheus.rs`), so they live behind a mutex in an `Arc`.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How often we take a sample
+pub const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+/// How many samples we keep for the sparklines
+const MAX_SAMPLES: usize = 60;
+
+/// Upper bounds of the round trip histogram buckets, in milliseconds
+pub const ROUND_TRIP_BUCKETS_MS: [f64; 10] = [1.0, 2.0, 5.0, 10.0, 20.0, 50.0, 100.0, 200.0, 500.0, 1000.0];
+
+/// The backend queue depth at which its gauge is full
+pub const BACKEND_QUEUE_GAUGE_MAX: usize = 100;
+/// The mean time per backend message at which its gauge is full
+pub const BACKEND_MS_GAUGE_MAX: f64 = 20.0;
+
+#[derive(Clone, Debug, Default)]
+pub struct Sample {
+    pub requests_per_sec: f64,
+    /// Mean round trip, zero if nothing was acknowledged
+    pub round_trip_ms: f64,
+    pub queue_depth: usize,
+    /// The most messages waiting for the backend at once
+    pub backend_queue: usize,
+    /// Mean time the backend took to handle a message, zero if it had none
+    pub backend_ms: f64,
+    /// The longest it took
+    pub backend_max_ms: f64,
+    /// The share of the time the backend spent handling messages, 0 to 1
+    pub backend_busy: f64,
+}
+
+/// A cumulative histogram of round trip times
+#[derive(Clone, Debug, Default)]
+pub struct Histogram {
+    /// The number of observations at or below each of `ROUND_TRIP_BUCKETS_MS`
+    pub buckets: [u64; 10],
+    pub sum_ms: f64,
+    pub count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, ms: f64) {
+        for (bound, count) in ROUND_TRIP_BUCKETS_MS.iter().zip(self.buckets.iter_mut()) {
+            if ms <= *bound {
+                *count += 1;
+            }
+        }
+        self.sum_ms += ms;
+        self.count += 1;
+    }
+}
+
+#[derive(Default)]
+struct Inner {
+    requests_total: u64,
+    patches_total: u64,
+    round_trip: Histogram,
+    /// `requests_total` and `round_trip` as of the last sample, so we can
+    /// work out what happened since
+    last_requests: u64,
+    last_round_trip: Histogram,
+    last_sample_at: Option<Instant>,
+    queue_depth: usize,
+    backend_events: u64,
+    backend_busy_ms: f64,
+    /// `backend_events` and `backend_busy_ms` as of the last sample
+    last_backend_events: u64,
+    last_backend_busy_ms: f64,
+    /// The backend's queue depth when it last woke up, and the deepest and
+    /// slowest since the last sample
+    backend_queue: usize,
+    backend_queue_peak: usize,
+    backend_max_ms: f64,
+    samples: VecDeque<Sample>,
+}
+
+/// A copy of the cumulative counters
+#[derive(Clone, Debug, Default)]
+pub struct Totals {
+    pub requests: u64,
+    pub patches: u64,
+    pub round_trip: Histogram,
+    pub queue_depth: usize,
+    pub backend_events: u64,
+    pub backend_busy_ms: f64,
+    pub backend_queue: usize,
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    inner: Mutex<Inner>,
+}
+
+impl Metrics {
+    pub fn request_sent(&self) {
+        self.inner.lock().unwrap().requests_total += 1;
+    }
+
+    pub fn patch_applied(&self) {
+        self.inner.lock().unwrap().patches_total += 1;
+    }
+
+    pub fn round_trip(&self, latency: Duration) {
+        let ms = latency.as_secs_f64() * 1000.0;
+        self.inner.lock().unwrap().round_trip.observe(ms);
+    }
+
+    /// The backend has woken up with `queue_depth` messages still waiting.
+    /// Handling the message it woke up for is timed until the timer returned
+    /// is dropped.
+    pub fn backend_event(&self, queue_depth: usize) -> BackendTimer<'_> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.backend_queue = queue_depth;
+        inner.backend_queue_peak = inner.backend_queue_peak.max(queue_depth);
+        BackendTimer { metrics: self, started: Instant::now() }
+    }
+
+    fn backend_handled(&self, took: Duration) {
+        let ms = took.as_secs_f64() * 1000.0;
+        let mut inner = self.inner.lock().unwrap();
+        inner.backend_events += 1;
+        inner.backend_busy_ms += ms;
+        inner.backend_max_ms = inner.backend_max_ms.max(ms);
+    }
+
+    /// Record a sample covering everything since the last one
+    pub fn sample(&self, queue_depth: usize) {
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+        let elapsed = inner
+            .last_sample_at
+            .map(|at| now.duration_since(at).as_secs_f64())
+            .unwrap_or(1.0)
+            .max(0.001);
+        let requests = inner.requests_total - inner.last_requests;
+        let round_trips = inner.round_trip.count - inner.last_round_trip.count;
+        let round_trip_ms = if round_trips == 0 {
+            0.0
+        } else {
+            (inner.round_trip.sum_ms - inner.last_round_trip.sum_ms) / round_trips as f64
+        };
+        let backend_events = inner.backend_events - inner.last_backend_events;
+        let backend_busy_ms = inner.backend_busy_ms - inner.last_backend_busy_ms;
+        let backend_ms = if backend_events == 0 { 0.0 } else { backend_busy_ms / backend_events as f64 };
+        if inner.samples.len() == MAX_SAMPLES {
+            inner.samples.pop_front();
+        }
+        inner.samples.push_back(Sample {
+            requests_per_sec: requests as f64 / elapsed,
+            round_trip_ms,
+            queue_depth,
+            // The backend may be waiting with messages queued behind it
+            // right now, not just at some point during the second
+            backend_queue: inner.backend_queue_peak.max(inner.backend_queue),
+            backend_ms,
+            backend_max_ms: inner.backend_max_ms,
+            backend_busy: (backend_busy_ms / 1000.0 / elapsed).min(1.0),
+        });
+        inner.last_backend_events = inner.backend_events;
+        inner.last_backend_busy_ms = inner.backend_busy_ms;
+        inner.backend_queue_peak = 0;
+        inner.backend_max_ms = 0.0;
+        inner.last_requests = inner.requests_total;
+        inner.last_round_trip = inner.round_trip.clone();
+        inner.last_sample_at = Some(now);
+        inner.queue_depth = queue_depth;
+    }
+
+    /// The samples we have, oldest first
+    pub fn samples(&self) -> Vec<Sample> {
+        self.inner.lock().unwrap().samples.iter().cloned().collect()
+    }
+
+    pub fn totals(&self) -> Totals {
+        let inner = self.inner.lock().unwrap();
+        Totals {
+            requests: inner.requests_total,
+            patches: inner.patches_total,
+            round_trip: inner.round_trip.clone(),
+            queue_depth: inner.queue_depth,
+            backend_events: inner.backend_events,
+            backend_busy_ms: inner.backend_busy_ms,
+            backend_queue: inner.backend_queue,
+        }
+    }
+}
+
+/// See `Metrics::backend_event`
+pub struct BackendTimer<'a> {
+    metrics: &'a Metrics,
+    started: Instant,
+}
+
+impl Drop for BackendTimer<'_> {
+    fn drop(&mut self) {
+        self.metrics.backend_handled(self.started.elapsed());
+    }
+}
+
+/// Draw `values` as a row of block characters scaled to the largest value
+pub fn sparkline(values: &[f64]) -> String {
+    const BARS: [char; 8] = ['\u{2581}', '\u{2582}', '\u{2583}', '\u{2584}', '\u{2585}', '\u{2586}', '\u{2587}', '\u{2588}'];
+    let max = values.iter().cloned().fold(0.0, f64::max);
+    values
+        .iter()
+        .map(|v| {
+            if max <= 0.0 {
+                BARS[0]
+            } else {
+                BARS[((v / max) * (BARS.len() - 1) as f64).round() as usize]
+            }
+        })
+        .collect()
+}
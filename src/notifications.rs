@@ -0,0 +1,92 @@
+//! What's been happening, told to the user.
+//!
+//! Each event, a peer connecting, a batch of remote changes being merged, a
+//! tab being reloaded after it got out of step, is shown as a toast floating
+//! over the top of the tabs for a few seconds, then kept in the notification
+//! center, the bell in the header bar, which lists the most recent ones with
+//! the time they happened. So nothing has to be caught while it's showing,
+//! and a toast never pushes the text down or has to be closed by hand.
+//!
+//! Toasts are hidden on the metrics tick rather than with a timer of their
+//! own, which is soon enough for something shown for seconds.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use vgtk::lib::glib;
+
+/// How long a toast is shown for
+pub const TOAST_DURATION: Duration = Duration::from_secs(6);
+
+/// How many notifications the notification center keeps
+const KEEP: usize = 50;
+
+#[derive(Clone, Debug)]
+pub struct Notification {
+    pub message: String,
+    /// The local time it happened, e.g. "14:03:27"
+    pub time: String,
+}
+
+#[derive(Default)]
+pub struct Notifications {
+    /// Newest first
+    recent: VecDeque<Notification>,
+    /// The toast showing, if one is, and when it was shown
+    toast: Option<(String, Instant)>,
+    /// How many have arrived since the notification center was last opened
+    unread: usize,
+}
+
+impl Notifications {
+    /// Show `message` as a toast and keep it in the notification center
+    pub fn push(&mut self, message: String) {
+        tracing::info!("notification: {}", message);
+        let time = glib::DateTime::new_now_local()
+            .format("%H:%M:%S")
+            .map(|t| t.to_string())
+            .unwrap_or_default();
+        self.recent.push_front(Notification { message: message.clone(), time });
+        self.recent.truncate(KEEP);
+        self.unread += 1;
+        self.toast = Some((message, Instant::now()));
+    }
+
+    pub fn toast(&self) -> Option<&str> {
+        self.toast.as_ref().map(|(message, _)| message.as_str())
+    }
+
+    pub fn dismiss(&mut self) {
+        self.toast = None;
+    }
+
+    /// Hide the toast if it's been showing long enough, returning whether it
+    /// was
+    pub fn expire(&mut self) -> bool {
+        match &self.toast {
+            Some((_, shown)) if shown.elapsed() >= TOAST_DURATION => {
+                self.toast = None;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// The notifications kept, newest first
+    pub fn recent(&self) -> impl Iterator<Item = &Notification> {
+        self.recent.iter()
+    }
+
+    pub fn unread(&self) -> usize {
+        self.unread
+    }
+
+    /// The notification center has been opened
+    pub fn mark_read(&mut self) {
+        self.unread = 0;
+    }
+
+    pub fn clear(&mut self) {
+        self.recent.clear();
+        self.unread = 0;
+    }
+}
@@ -0,0 +1,337 @@
+//! Finding the same place in the text however it's counted.
+//!
+//! A place in the text can be a char offset, which is what the buffer's
+//! iters and the presence state use, a byte offset into the text as a
+//! `String`, which is what searching it finds, a grapheme, the thing the
+//! cursor moves over and a user would call a character, or an element of the
+//! automerge text, which is what a patch's edits count and which has the op
+//! ID that authorship and marks go by. Each feature used to work one out from
+//! another itself, marks by scanning the provenance for an op ID, blame and
+//! suggestions by assuming an element is one char, so the `OffsetIndex` does
+//! it for all of them: a rope of the text, which knows bytes, chars and
+//! lines, alongside the elements and how many chars each has.
+//!
+//! Every element typed here is one char, see `text::inserted`, and while
+//! they all are, elements and chars are counted the same. Another
+//! implementation can insert an element with a longer string, or none, and
+//! then finding an element's chars means counting up the elements before it,
+//! which is fine for the odd one.
+//!
+//! There are two of them in each doc. The history index's follows the
+//! patches, the text the backend has, see `history_index.rs`, and the text
+//! cache's follows the buffer, what's shown, see `text_cache.rs`. The two are
+//! the same but for anything typed which the backend hasn't answered yet,
+//! whose elements in the buffer's index have no op.
+
+use automerge_protocol as amp;
+use ropey::Rope;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ops::Range;
+use unicode_segmentation::UnicodeSegmentation;
+
+/// Stands in for an inserted element until we know what it is, and for one
+/// whose value isn't a string
+pub const PLACEHOLDER: &str = "\u{fffd}";
+
+struct Element {
+    /// The op which inserted it, once the backend has said
+    op: Option<String>,
+    chars: usize,
+}
+
+#[derive(Default)]
+pub struct OffsetIndex {
+    rope: Rope,
+    elements: Vec<Element>,
+    /// How many elements aren't one char
+    wide: usize,
+    /// Where each op's element is, worked out the first time one is asked
+    /// for after a change
+    positions: RefCell<Option<HashMap<String, usize>>>,
+}
+
+impl OffsetIndex {
+    /// An index of `text` with one element a char, none of them with an op
+    pub fn from_text(text: &str) -> OffsetIndex {
+        let rope = Rope::from_str(text);
+        let elements = (0..rope.len_chars()).map(|_| Element { op: None, chars: 1 }).collect();
+        OffsetIndex { rope, elements, wide: 0, positions: RefCell::new(None) }
+    }
+
+    fn edited(&mut self) {
+        *self.positions.get_mut() = None;
+    }
+
+    /// Make the edits a patch makes to the text, with the op IDs of the
+    /// elements it inserts. Returns whether it changed the text.
+    pub fn apply_patch(&mut self, patch: &amp::Patch) -> bool {
+        let seq = match text_diff(patch) {
+            Some(amp::Diff::Seq(seq)) => seq,
+            _ => return false,
+        };
+        for edit in &seq.edits {
+            match edit {
+                amp::DiffEdit::Insert { index } => {
+                    let index = (*index).min(self.elements.len());
+                    let at = self.element_to_char(index);
+                    self.rope.insert(at, PLACEHOLDER);
+                    self.elements.insert(index, Element { op: None, chars: 1 });
+                }
+                amp::DiffEdit::Remove { index } if *index < self.elements.len() => {
+                    let span = self.element_span(*index);
+                    self.rope.remove(span);
+                    if self.elements.remove(*index).chars != 1 {
+                        self.wide -= 1;
+                    }
+                }
+                amp::DiffEdit::Remove { .. } => {}
+            }
+        }
+        for (index, values) in &seq.props {
+            // The same value as the provenance has always gone by, the
+            // first op's
+            let (op, value) = match values.iter().next() {
+                Some(value) if *index < self.elements.len() => value,
+                _ => continue,
+            };
+            let text = element_text(value).unwrap_or(PLACEHOLDER);
+            let span = self.element_span(*index);
+            if self.rope.slice(span.clone()) != text {
+                self.rope.remove(span.clone());
+                self.rope.insert(span.start, text);
+            }
+            let chars = text.chars().count();
+            let element = &mut self.elements[*index];
+            match (element.chars == 1, chars == 1) {
+                (true, false) => self.wide += 1,
+                (false, true) => self.wide -= 1,
+                _ => {}
+            }
+            element.op = Some(op.clone());
+            element.chars = chars;
+        }
+        self.edited();
+        true
+    }
+
+    /// Insert `text` at char `at`, each char an element no one has
+    /// answered for yet, as typing into the buffer does
+    pub fn insert(&mut self, at: usize, text: &str) {
+        let at = at.min(self.rope.len_chars());
+        let index = self.char_to_element(at);
+        let chars = text.chars().count();
+        self.rope.insert(at, text);
+        self.elements.splice(index..index, (0..chars).map(|_| Element { op: None, chars: 1 }));
+        self.edited();
+    }
+
+    /// Remove the chars `range`, and whatever elements go with them
+    pub fn remove(&mut self, range: Range<usize>) {
+        let len = self.rope.len_chars();
+        let range = range.start.min(len)..range.end.min(len);
+        if range.start >= range.end {
+            return;
+        }
+        self.rope.remove(range.clone());
+        if self.wide == 0 {
+            self.elements.drain(range);
+        } else {
+            // Shortening the elements the range overlaps, and dropping the
+            // ones it takes all of
+            let mut start = 0;
+            let mut wide = 0;
+            self.elements.retain_mut(|element| {
+                let end = start + element.chars;
+                let overlap = end.min(range.end).saturating_sub(start.max(range.start));
+                let whole = if element.chars == 0 { range.contains(&start) } else { overlap == element.chars };
+                start = end;
+                element.chars -= overlap;
+                if !whole && element.chars != 1 {
+                    wide += 1;
+                }
+                !whole
+            });
+            self.wide = wide;
+        }
+        self.edited();
+    }
+
+    pub fn len_chars(&self) -> usize {
+        self.rope.len_chars()
+    }
+
+    pub fn chars(&self) -> ropey::iter::Chars {
+        self.rope.chars()
+    }
+
+    pub fn text(&self) -> String {
+        self.rope.to_string()
+    }
+
+    /// The char at `offset`, if the text is that long
+    pub fn char_at(&self, offset: usize) -> Option<char> {
+        self.rope.get_char(offset)
+    }
+
+    /// The chars `range` of the text
+    pub fn slice(&self, range: Range<usize>) -> String {
+        let len = self.rope.len_chars();
+        self.rope.slice(range.start.min(len)..range.end.min(len)).to_string()
+    }
+
+    /// The char offset at byte `byte` of the text
+    pub fn byte_to_char(&self, byte: usize) -> usize {
+        self.rope.byte_to_char(byte.min(self.rope.len_bytes()))
+    }
+
+    /// The char offset the element `index` starts at
+    pub fn element_to_char(&self, index: usize) -> usize {
+        if self.wide == 0 {
+            index.min(self.rope.len_chars())
+        } else {
+            self.elements.iter().take(index).map(|element| element.chars).sum()
+        }
+    }
+
+    /// The element the char at `offset` is part of
+    pub fn char_to_element(&self, offset: usize) -> usize {
+        if self.wide == 0 {
+            return offset.min(self.elements.len());
+        }
+        let mut start = 0;
+        for (index, element) in self.elements.iter().enumerate() {
+            if offset < start + element.chars {
+                return index;
+            }
+            start += element.chars;
+        }
+        self.elements.len()
+    }
+
+    /// The chars of element `index`
+    fn element_span(&self, index: usize) -> Range<usize> {
+        let start = self.element_to_char(index);
+        start..start + self.elements.get(index).map_or(0, |element| element.chars)
+    }
+
+    /// The char offset `offset` is in the middle of a grapheme, moved back to
+    /// where the grapheme starts
+    pub fn snap_to_grapheme(&self, offset: usize) -> usize {
+        let offset = offset.min(self.rope.len_chars());
+        // ropey keeps "\r\n" in one line, so no grapheme is in two
+        let line = self.rope.char_to_line(offset);
+        let mut start = self.rope.line_to_char(line);
+        let text = self.rope.line(line).to_string();
+        for grapheme in text.graphemes(true) {
+            let end = start + grapheme.chars().count();
+            if end > offset {
+                break;
+            }
+            start = end;
+        }
+        start
+    }
+
+    /// The op which inserted the char at `offset`
+    pub fn op_at(&self, offset: usize) -> Option<&str> {
+        self.elements.get(self.char_to_element(offset)).and_then(|element| element.op.as_deref())
+    }
+
+    /// The actor which inserted the char at `offset`
+    pub fn actor_at(&self, offset: usize) -> Option<&str> {
+        self.op_at(offset).and_then(|op| op.splitn(2, '@').nth(1))
+    }
+
+    /// The op which inserted each char, in order
+    pub fn ops(&self) -> impl Iterator<Item = Option<&str>> + '_ {
+        self.elements.iter().flat_map(|element| std::iter::repeat(element.op.as_deref()).take(element.chars))
+    }
+
+    /// The op which inserted each element from `index` on, and how many
+    /// chars it has
+    pub fn element_ops(&self, index: usize) -> impl Iterator<Item = (Option<&str>, usize)> + '_ {
+        self.elements[index.min(self.elements.len())..].iter().map(|element| (element.op.as_deref(), element.chars))
+    }
+
+    /// The chars the element inserted by `op` has now, if it's still there
+    pub fn span_of(&self, op: &str) -> Option<Range<usize>> {
+        let mut positions = self.positions.borrow_mut();
+        let positions = positions.get_or_insert_with(|| {
+            self.elements.iter().enumerate().filter_map(|(index, element)| Some((element.op.clone()?, index))).collect()
+        });
+        positions.get(op).map(|index| self.element_span(*index))
+    }
+}
+
+/// Find the diff for the "text" object in a patch, if there is one
+pub fn text_diff(patch: &amp::Patch) -> Option<&amp::Diff> {
+    match &patch.diffs {
+        Some(amp::Diff::Map(root)) => root.props.get("text")?.values().next(),
+        _ => None,
+    }
+}
+
+/// The string an element of the text is set to, if it's a string
+pub fn element_text(value: &amp::Diff) -> Option<&str> {
+    match value {
+        amp::Diff::Value(amp::Value::Str(s)) => Some(s),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use automerge_backend::Backend;
+    use automerge_frontend::{Frontend, LocalChange, Path, Value};
+    use automerge_protocol as amp;
+
+    use super::OffsetIndex;
+    use crate::backend::BackendHandle;
+
+    #[test]
+    fn elements_chars_and_bytes_line_up() {
+        let mut backend = Backend::init();
+        let mut frontend = Frontend::new();
+        let mut index = OffsetIndex::default();
+        let request = frontend
+            .change(None, |doc| {
+                doc.add_change(LocalChange::set(
+                    Path::root().key("text"),
+                    Value::Sequence(
+                        ["é", "ab", "", "c"].iter().map(|s| Value::Primitive(amp::Value::Str(s.to_string()))).collect(),
+                        amp::SequenceType::Text,
+                    ),
+                ))
+            })
+            .unwrap();
+        let (patch, _) = backend.apply_local_change_and_get(request.unwrap()).unwrap();
+        assert!(index.apply_patch(&patch));
+        assert_eq!(index.text(), "éabc");
+        assert_eq!(index.element_to_char(2), 3);
+        assert_eq!(index.element_to_char(3), 3);
+        assert_eq!(index.char_to_element(2), 1);
+        assert_eq!(index.char_to_element(3), 3);
+        assert_eq!(index.byte_to_char(2), 1);
+        let ab = index.op_at(1).unwrap().to_string();
+        assert_eq!(index.op_at(2), Some(ab.as_str()));
+        assert_eq!(index.span_of(&ab), Some(1..3));
+        assert_eq!(index.ops().count(), 4);
+        index.remove(1..2);
+        assert_eq!(index.text(), "ébc");
+        assert_eq!(index.span_of(&ab), Some(1..2));
+        index.insert(1, "xy");
+        assert_eq!(index.span_of(&ab), Some(3..4));
+        assert_eq!(index.op_at(1), None);
+    }
+
+    #[test]
+    fn snapping_to_graphemes() {
+        let index = OffsetIndex::from_text("ae\u{301}\r\nb");
+        assert_eq!(index.snap_to_grapheme(2), 1);
+        assert_eq!(index.snap_to_grapheme(3), 3);
+        assert_eq!(index.snap_to_grapheme(4), 3);
+        assert_eq!(index.snap_to_grapheme(5), 5);
+        assert_eq!(index.snap_to_grapheme(9), 6);
+    }
+}
@@ -0,0 +1,221 @@
+//! Syncing over libp2p, with the `libp2p` cargo feature and `--libp2p`.
+//!
+//! Every instance which is syncing a document subscribes to a gossipsub
+//! topic named after the document, and each change is published on it as a
+//! message in the automerge binary format, the same as a websocket message.
+//! The document is identified by the hash of its first change, which is
+//! logged and shown in the peers popover; another instance started with
+//! `--libp2p-document <id>` joins that document's topic instead of its own.
+//! Its history then merges with the document's just like over a websocket.
+//!
+//! Peers are found with mDNS on the local network, and beyond it with a
+//! Kademlia DHT joined through `--libp2p-peer <multiaddr>`, on which we
+//! provide the topic name so that anyone looking for the document can find
+//! us. Either way a peer we find is added to gossipsub directly.
+//!
+//! Gossipsub only delivers messages published after someone subscribes, so
+//! whenever a peer subscribes to our topic we publish our whole history
+//! again. They'll already have most of it, automerge ignores the duplicates.
+//! To be able to do that this module keeps a copy of every change it has
+//! seen, and it doesn't publish changes it received from the topic, which
+//! everyone else got at the same time we did.
+//!
+//! The node is handed to the backend thread as one more websocket client;
+//! changes arriving on the topic go to the backend like a client's would.
+//! Peers subscribed to the topic are shown in the presence bar of the
+//! tabs syncing the document.
+
+use automerge_backend::Change;
+use libp2p::futures::StreamExt;
+use libp2p::gossipsub::{
+    Gossipsub, GossipsubConfigBuilder, GossipsubEvent, IdentTopic, MessageAuthenticity, ValidationMode,
+};
+use libp2p::kad::record::store::MemoryStore;
+use libp2p::kad::{GetProvidersOk, Kademlia, KademliaEvent, QueryResult};
+use libp2p::multiaddr::Protocol;
+use libp2p::swarm::{NetworkBehaviour, SwarmEvent};
+use libp2p::{identity, mdns, Multiaddr, PeerId, Swarm};
+use std::collections::HashSet;
+use std::time::Duration;
+
+use crate::ws::{self, WsEvent};
+
+/// Bigger than the gossipsub default, so pasting a lot of text at once
+/// still fits in a change
+const MAX_MESSAGE: usize = 1 << 20;
+/// How often to look for other providers of the document on the DHT
+const PROVIDER_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(NetworkBehaviour)]
+struct Behaviour {
+    gossipsub: Gossipsub,
+    kademlia: Kademlia<MemoryStore>,
+    mdns: mdns::tokio::Behaviour,
+}
+
+/// What `--libp2p` was given
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    /// The port to listen on, any if `None`
+    pub port: Option<u16>,
+    /// The document to join, rather than the one we start with
+    pub document: Option<String>,
+    /// Peers to join the DHT through
+    pub bootstrap: Vec<Multiaddr>,
+}
+
+/// Start the node on a thread of its own
+pub fn start(options: Options, events: crossbeam::Sender<WsEvent>) {
+    std::thread::spawn(move || {
+        let runtime = match tokio::runtime::Runtime::new() {
+            Ok(runtime) => runtime,
+            Err(e) => {
+                tracing::error!("could not start the libp2p runtime: {}", e);
+                return;
+            }
+        };
+        if let Err(e) = runtime.block_on(run(options, events)) {
+            tracing::error!("libp2p stopped: {}", e);
+        }
+    });
+}
+
+async fn run(options: Options, events: crossbeam::Sender<WsEvent>) -> Result<(), Box<dyn std::error::Error>> {
+    // The backend sends the history straight away, its first change names
+    // the document
+    let (client, outgoing) = crossbeam::channel::unbounded::<Vec<u8>>();
+    events.send(WsEvent::Connected{client, server: None})?;
+    let first = outgoing.recv()?;
+    let document = match options.document {
+        Some(document) => document,
+        None => document_id(&Change::from_bytes(first.clone()).map_err(|e| format!("{:?}", e))?),
+    };
+    let topic = IdentTopic::new(format!("automerge-demo/{}", document));
+    tracing::info!("syncing document {} over libp2p", document);
+    events.send(WsEvent::Announced{document: document.clone()})?;
+
+    let keypair = identity::Keypair::generate_ed25519();
+    let local = PeerId::from(keypair.public());
+    let transport = libp2p::tokio_development_transport(keypair.clone())?;
+    let config = GossipsubConfigBuilder::default()
+        .max_transmit_size(MAX_MESSAGE)
+        .validation_mode(ValidationMode::Strict)
+        .build()?;
+    let mut gossipsub = Gossipsub::new(MessageAuthenticity::Signed(keypair), config)?;
+    gossipsub.subscribe(&topic)?;
+    let behaviour = Behaviour {
+        gossipsub,
+        kademlia: Kademlia::new(local, MemoryStore::new(local)),
+        mdns: mdns::tokio::Behaviour::new(mdns::Config::default())?,
+    };
+    let mut swarm = Swarm::with_tokio_executor(transport, behaviour, local);
+    swarm.listen_on(format!("/ip4/0.0.0.0/tcp/{}", options.port.unwrap_or(0)).parse()?)?;
+    for addr in options.bootstrap {
+        match addr.iter().last() {
+            Some(Protocol::P2p(hash)) => match PeerId::from_multihash(hash) {
+                Ok(peer) => {
+                    swarm.behaviour_mut().kademlia.add_address(&peer, addr.clone());
+                }
+                Err(_) => tracing::warn!("{} has an invalid peer id", addr),
+            },
+            _ => tracing::warn!("{} doesn't end with /p2p/<peer id>, it can't join the DHT", addr),
+        }
+        swarm.dial(addr)?;
+    }
+    let key = libp2p::kad::record::Key::from(topic.hash().as_str().as_bytes().to_vec());
+    // Fails without any bootstrap peers, in which case only mDNS finds
+    // anyone
+    let _ = swarm.behaviour_mut().kademlia.bootstrap();
+    swarm.behaviour_mut().kademlia.start_providing(key.clone())?;
+
+    // Every change we know of, to publish again to new subscribers, and the
+    // ones we got from the topic, which don't need publishing
+    let mut history = vec![first];
+    let mut received = HashSet::new();
+
+    // The backend's queue is a blocking channel, move it onto an async one
+    let (changes_sx, mut changes) = tokio::sync::mpsc::unbounded_channel();
+    std::thread::spawn(move || {
+        for bytes in outgoing {
+            if changes_sx.send(bytes).is_err() {
+                return;
+            }
+        }
+    });
+    let mut providers = tokio::time::interval(PROVIDER_INTERVAL);
+    loop {
+        tokio::select! {
+            bytes = changes.recv() => {
+                let bytes = match bytes {
+                    Some(bytes) => bytes,
+                    // The backend thread has gone
+                    None => return Ok(()),
+                };
+                if received.contains(&bytes) {
+                    continue;
+                }
+                history.push(bytes.clone());
+                // Fails when nobody is subscribed yet, they'll get it with
+                // the history when they do
+                let _ = swarm.behaviour_mut().gossipsub.publish(topic.clone(), bytes);
+            }
+            _ = providers.tick() => {
+                swarm.behaviour_mut().kademlia.get_providers(key.clone());
+            }
+            event = swarm.select_next_some() => match event {
+                SwarmEvent::NewListenAddr{address, ..} => {
+                    tracing::info!("libp2p listening on {}/p2p/{}", address, local);
+                }
+                SwarmEvent::Behaviour(BehaviourEvent::Mdns(mdns::Event::Discovered(found))) => {
+                    for (peer, addr) in found {
+                        swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer);
+                        swarm.behaviour_mut().kademlia.add_address(&peer, addr);
+                    }
+                }
+                SwarmEvent::Behaviour(BehaviourEvent::Mdns(mdns::Event::Expired(gone))) => {
+                    for (peer, _) in gone {
+                        swarm.behaviour_mut().gossipsub.remove_explicit_peer(&peer);
+                    }
+                }
+                SwarmEvent::Behaviour(BehaviourEvent::Kademlia(KademliaEvent::OutboundQueryProgressed{
+                    result: QueryResult::GetProviders(Ok(GetProvidersOk::FoundProviders{providers, ..})),
+                    ..
+                })) => {
+                    for peer in providers.into_iter().filter(|p| *p != local) {
+                        swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer);
+                    }
+                }
+                SwarmEvent::Behaviour(BehaviourEvent::Gossipsub(GossipsubEvent::Subscribed{peer_id, topic: subscribed})) => {
+                    if subscribed == topic.hash() {
+                        events.send(WsEvent::NetworkPeer{id: peer_id.to_string(), present: true})?;
+                        for bytes in &history {
+                            let _ = swarm.behaviour_mut().gossipsub.publish(topic.clone(), bytes.clone());
+                        }
+                    }
+                }
+                SwarmEvent::Behaviour(BehaviourEvent::Gossipsub(GossipsubEvent::Unsubscribed{peer_id, topic: unsubscribed})) => {
+                    if unsubscribed == topic.hash() {
+                        events.send(WsEvent::NetworkPeer{id: peer_id.to_string(), present: false})?;
+                    }
+                }
+                SwarmEvent::Behaviour(BehaviourEvent::Gossipsub(GossipsubEvent::Message{propagation_source, message, ..})) => {
+                    // Anything from a peer counts as it being active
+                    let from = message.source.unwrap_or(propagation_source);
+                    events.send(WsEvent::NetworkPeer{id: from.to_string(), present: true})?;
+                    if received.insert(message.data.clone()) {
+                        history.push(message.data.clone());
+                        if !ws::forward(message.data, None, &events) {
+                            return Ok(());
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// The hex hash of a document's first change
+fn document_id(first: &Change) -> String {
+    first.hash.0.iter().map(|b| format!("{:02x}", b)).collect()
+}
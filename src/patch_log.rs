@@ -0,0 +1,113 @@
+//! A log of the patches a frontend has received, shown from View > Patch Log.
+//!
+//! Watching patches arrive is the quickest way to see how the backend/frontend
+//! split actually behaves: which patches are acknowledgements of our own
+//! changes, which came from the other window and what parts of the document
+//! each one touched. Each entry also shows the message and time of the
+//! change behind the patch.
+//!
+//! If we're checking signatures, see `signing.rs`, an entry is also flagged
+//! if any of its changes isn't signed by a key we trust.
+
+use automerge_protocol as amp;
+use std::collections::VecDeque;
+
+use crate::change_log::ChangeMeta;
+use crate::signing::{Signatures, Status};
+
+/// How many entries we keep before dropping the oldest
+const MAX_ENTRIES: usize = 200;
+
+#[derive(Clone, Debug)]
+pub struct PatchLogEntry {
+    /// The actor whose change this patch is for, `None` for remote patches
+    /// which didn't come with their changes
+    pub actor: Option<String>,
+    pub seq: Option<u64>,
+    /// The top level keys of the document the patch touches
+    pub keys: Vec<String>,
+    /// The newest of the changes the patch applies, and how many others
+    /// came with it
+    pub change: Option<ChangeMeta>,
+    pub other_changes: usize,
+    /// The hashes of all of its changes
+    pub hashes: Vec<amp::ChangeHash>,
+}
+
+impl PatchLogEntry {
+    pub fn new(patch: &amp::Patch, changes: &[ChangeMeta]) -> PatchLogEntry {
+        let mut keys: Vec<String> = match &patch.diffs {
+            Some(amp::Diff::Map(root)) => root.props.keys().cloned().collect(),
+            _ => Vec::new(),
+        };
+        keys.sort();
+        let change = changes.iter().max_by_key(|c| (c.time, c.seq)).cloned();
+        PatchLogEntry {
+            // Remote patches don't say whose change they are for, but the
+            // change itself does
+            actor: patch.actor.clone().or_else(|| change.as_ref().map(|c| c.actor.clone())),
+            seq: patch.seq.or_else(|| change.as_ref().map(|c| c.seq)),
+            keys,
+            change,
+            other_changes: changes.len().saturating_sub(1),
+            hashes: changes.iter().map(|c| c.hash).collect(),
+        }
+    }
+
+    /// A one line description, `own_actor` is the actor of the frontend the
+    /// log belongs to
+    pub fn describe(&self, own_actor: &str, signatures: Option<&Signatures>) -> String {
+        let source = match (&self.actor, self.seq) {
+            (Some(actor), Some(seq)) if actor == own_actor => format!("local #{}", seq),
+            (Some(actor), Some(seq)) => format!("{} #{}", actor, seq),
+            _ => "remote".to_string(),
+        };
+        let mut description = if self.keys.is_empty() {
+            source
+        } else {
+            format!("{}: {}", source, self.keys.join(", "))
+        };
+        if let Some(change) = &self.change {
+            if let Some(message) = &change.message {
+                description.push_str(&format!(" \u{2014} {}", message));
+            }
+            if self.other_changes > 0 {
+                description.push_str(&format!(" (and {} more)", self.other_changes));
+            }
+            if let Some(time) = change.time_label() {
+                description = format!("{} {}", time, description);
+            }
+        }
+        if let Some(signatures) = signatures {
+            let statuses: Vec<Status> = self.hashes.iter().map(|hash| signatures.status(hash)).collect();
+            let unverified = statuses.iter().filter(|s| **s != Status::Verified).count();
+            if let Some(worst) = statuses.into_iter().max().filter(|s| *s != Status::Verified) {
+                if self.hashes.len() > 1 {
+                    description.push_str(&format!(" \u{26a0} {} of {} changes unverified ({})", unverified, self.hashes.len(), worst.label()));
+                } else {
+                    description.push_str(&format!(" \u{26a0} {}", worst.label()));
+                }
+            }
+        }
+        description
+    }
+}
+
+#[derive(Default)]
+pub struct PatchLog {
+    entries: VecDeque<PatchLogEntry>,
+}
+
+impl PatchLog {
+    pub fn push(&mut self, patch: &amp::Patch, changes: &[ChangeMeta]) {
+        if self.entries.len() == MAX_ENTRIES {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(PatchLogEntry::new(patch, changes));
+    }
+
+    /// The entries, newest first
+    pub fn entries(&self) -> impl Iterator<Item = &PatchLogEntry> {
+        self.entries.iter().rev()
+    }
+}
@@ -0,0 +1,97 @@
+//! Routing patches to the documents they belong to.
+//!
+//! Each document the UI shows is backed by its own backend, which I call a
+//! peer here because from automerge's point of view that is what it is. The
+//! backend thread tags every patch it produces with the id of the peer it
+//! came from and the `DocRegistry` in the model hands it on to the right
+//! `Doc`. Nothing here knows how many peers there are, so adding windows,
+//! tabs or network peers is a matter of registering more of them.
+//!
+//! A peer id only means something inside one instance. Each document also
+//! has a `DocumentId`, a UUID made when the document is created or opened
+//! and shared by every tab showing it, which is how instances syncing over
+//! one connection tell each other which document a change is for (see
+//! `ws.rs`). A document another instance sends us is opened in a new tab
+//! under the same id. Saved files don't record it, opening a file makes a
+//! new one.
+
+use automerge_protocol as amp;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+pub use automerge_demo_core::ids::{DocumentId, PeerId};
+
+use crate::change_log::ChangeMeta;
+use crate::doc::Doc;
+
+/// A patch produced by the backend of `peer_id`, along with the changes it
+/// applied
+#[derive(Clone, Debug)]
+pub struct PatchEnvelope {
+    pub peer_id: PeerId,
+    pub patch: amp::Patch,
+    pub changes: Vec<ChangeMeta>,
+}
+
+/// Every document the UI knows about, by the peer backing it
+#[derive(Default)]
+pub struct DocRegistry {
+    docs: BTreeMap<PeerId, Rc<RefCell<Doc>>>,
+}
+
+impl DocRegistry {
+    pub fn insert(&mut self, peer_id: PeerId, doc: Doc) {
+        self.docs.insert(peer_id, Rc::new(RefCell::new(doc)));
+    }
+
+    pub fn remove(&mut self, peer_id: PeerId) -> Option<Rc<RefCell<Doc>>> {
+        self.docs.remove(&peer_id)
+    }
+
+    pub fn get(&self, peer_id: PeerId) -> Option<&Rc<RefCell<Doc>>> {
+        self.docs.get(&peer_id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.docs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.docs.is_empty()
+    }
+
+    /// The `index`th document in peer id order, which is the order of the
+    /// tabs
+    pub fn nth(&self, index: usize) -> Option<(PeerId, &Rc<RefCell<Doc>>)> {
+        self.iter().nth(index)
+    }
+
+    /// Where the document for `peer_id` is in peer id order
+    pub fn position(&self, peer_id: PeerId) -> Option<usize> {
+        self.docs.keys().position(|id| *id == peer_id)
+    }
+
+    /// The documents in peer id order
+    pub fn iter(&self) -> impl Iterator<Item = (PeerId, &Rc<RefCell<Doc>>)> {
+        self.docs.iter().map(|(id, doc)| (*id, doc))
+    }
+
+    /// Apply patches for `peer_id`, which arrived together, to its document
+    /// in one go, returning whether the window needs rendering again to
+    /// show them. That's false if we don't have that document.
+    pub fn route(&self, peer_id: PeerId, envelopes: Vec<PatchEnvelope>) -> bool {
+        match self.docs.get(&peer_id) {
+            Some(doc) => doc.borrow_mut().apply_patches(envelopes.into_iter().map(|e| (e.patch, e.changes)).collect()),
+            None => {
+                tracing::warn!("dropping patches for unknown {}", peer_id);
+                false
+            }
+        }
+    }
+
+    /// The change requests waiting for the backend, across every document
+    pub fn queue_depth(&self) -> usize {
+        self.docs.values().map(|d| d.borrow().queue_depth()).sum()
+    }
+}
@@ -0,0 +1,258 @@
+//! The path from a keystroke to the backend.
+//!
+//! Normally every keystroke is its own change request, which has a fixed
+//! overhead in the frontend, the channel and the backend. With
+//! `--coalesce-ms <ms>` the `Coalescer` instead holds on to the local changes
+//! from keystrokes until typing pauses for that long (or until they've been
+//! held for `MAX_HOLD_FACTOR` times that long, so a fast typist who never
+//! pauses still gets their changes sent) and makes them as a single change.
+//!
+//! The text buffer shows each keystroke immediately as always, the frontend
+//! just catches up when the batch is made. Anything which depends on the
+//! frontend being up to date with the buffer - applying a patch, undo,
+//! replace - has to `flush` first.
+//!
+//! If the frontend can't make the batched changes it has got out of step
+//! with the buffer, or with the backend, and the coalescer marks the doc as
+//! desynchronized rather than panicking so that it can be resynced from the
+//! backend, see `Doc::resync`.
+//!
+//! Every change request is stamped with the time it is sent, and batched
+//! keystrokes get a message saying how much was typed and deleted, so the
+//! patch log and blame gutter can say what each change was and when.
+
+use automerge_frontend::{Frontend, LocalChange};
+use automerge_protocol as amp;
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use vgtk::lib::glib;
+
+use crate::metrics::Metrics;
+use crate::presence;
+
+/// How many times the coalescing window we'll hold changes for at most
+const MAX_HOLD_FACTOR: u32 = 4;
+
+/// Sends change requests to the backend and keeps track of the ones it
+/// hasn't acknowledged yet. Cheap to clone so it can be moved into signal
+/// handlers.
+#[derive(Clone)]
+pub struct ChangeSender {
+    sx: crossbeam::Sender<amp::Request>,
+    /// Tells other windows we're active whenever we make a local change
+    heartbeat: presence::Heartbeat,
+    /// When each unacknowledged request was sent, oldest first. The backend
+    /// handles requests in order so the next patch for our actor is for the
+    /// oldest of these.
+    in_flight: Rc<RefCell<VecDeque<Instant>>>,
+    pub metrics: Arc<Metrics>,
+}
+
+impl ChangeSender {
+    pub fn new(
+        sx: crossbeam::Sender<amp::Request>,
+        heartbeat: presence::Heartbeat,
+        metrics: Arc<Metrics>,
+    ) -> ChangeSender {
+        ChangeSender {
+            sx,
+            heartbeat,
+            in_flight: Rc::new(RefCell::new(VecDeque::new())),
+            metrics,
+        }
+    }
+
+    /// Whether the local user has been typing lately, see `presence.rs`
+    pub fn typing(&self) -> bool {
+        self.heartbeat.typing()
+    }
+
+    pub fn send(&self, mut cr: amp::Request) {
+        let _span = tracing::info_span!("channel_send", seq = cr.seq).entered();
+        cr.time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .ok()
+            .map(|t| t.as_secs() as i64);
+        self.heartbeat.beat();
+        self.in_flight.borrow_mut().push_back(Instant::now());
+        self.metrics.request_sent();
+        self.sx.send(cr).unwrap();
+    }
+
+    /// Record that the backend has acknowledged our oldest request,
+    /// returning how long it took
+    pub fn acknowledged(&self) -> Option<Duration> {
+        let latency = self.in_flight.borrow_mut().pop_front().map(|sent| sent.elapsed());
+        if let Some(latency) = latency {
+            self.metrics.round_trip(latency);
+        }
+        latency
+    }
+
+    pub fn pending(&self) -> usize {
+        self.in_flight.borrow().len()
+    }
+
+    /// Forget about every unacknowledged request, after a resync has
+    /// replaced the frontend which made them
+    pub fn reset(&self) {
+        self.in_flight.borrow_mut().clear();
+    }
+
+    /// How many change requests are waiting in the channel to the backend
+    pub fn queue_depth(&self) -> usize {
+        self.sx.len()
+    }
+}
+
+/// What a keystroke did to the text
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Edit {
+    Insert,
+    Delete,
+}
+
+struct Inner {
+    frontend: Rc<RefCell<Frontend>>,
+    sender: ChangeSender,
+    /// `None` if we aren't coalescing
+    window: Cell<Option<Duration>>,
+    pending: RefCell<Vec<LocalChange>>,
+    /// How many characters the pending changes insert and delete
+    inserted: Cell<usize>,
+    deleted: Cell<usize>,
+    /// When the oldest pending change was queued
+    held_since: Cell<Option<Instant>>,
+    /// The timer which flushes once typing pauses
+    source: RefCell<Option<glib::SourceId>>,
+    /// Set if the frontend couldn't make the changes. Shared with the doc.
+    desynced: Rc<Cell<bool>>,
+}
+
+/// Batches up the local changes from keystrokes. Cheap to clone so it can
+/// be moved into signal handlers.
+#[derive(Clone)]
+pub struct Coalescer {
+    inner: Rc<Inner>,
+}
+
+impl Coalescer {
+    pub fn new(
+        frontend: Rc<RefCell<Frontend>>,
+        sender: ChangeSender,
+        window: Option<Duration>,
+        desynced: Rc<Cell<bool>>,
+    ) -> Coalescer {
+        Coalescer {
+            inner: Rc::new(Inner {
+                frontend,
+                sender,
+                window: Cell::new(window),
+                pending: RefCell::new(Vec::new()),
+                inserted: Cell::new(0),
+                deleted: Cell::new(0),
+                held_since: Cell::new(None),
+                source: RefCell::new(None),
+                desynced,
+            }),
+        }
+    }
+
+    /// Queue the changes for one keystroke, one per character, making them
+    /// straight away if we're not coalescing
+    pub fn push(&self, edit: Edit, changes: Vec<LocalChange>) {
+        let count = match edit {
+            Edit::Insert => &self.inner.inserted,
+            Edit::Delete => &self.inner.deleted,
+        };
+        count.set(count.get() + changes.len());
+        let window = match self.inner.window.get() {
+            Some(window) => window,
+            None => {
+                self.inner.pending.borrow_mut().extend(changes);
+                self.flush();
+                return;
+            }
+        };
+        self.inner.pending.borrow_mut().extend(changes);
+        let held_since = self.inner.held_since.get().unwrap_or_else(Instant::now);
+        self.inner.held_since.set(Some(held_since));
+        if held_since.elapsed() >= window * MAX_HOLD_FACTOR {
+            self.flush();
+            return;
+        }
+        // Restart the pause timer
+        if let Some(source) = self.inner.source.borrow_mut().take() {
+            glib::source_remove(source);
+        }
+        let coalescer = self.clone();
+        let source = glib::timeout_add_local(window.as_millis() as u32, move || {
+            coalescer.inner.source.borrow_mut().take();
+            coalescer.flush();
+            glib::Continue(false)
+        });
+        *self.inner.source.borrow_mut() = Some(source);
+    }
+
+    /// Make every pending change as one change request and send it
+    pub fn flush(&self) {
+        if let Some(source) = self.inner.source.borrow_mut().take() {
+            glib::source_remove(source);
+        }
+        self.inner.held_since.set(None);
+        let changes: Vec<LocalChange> = self.inner.pending.borrow_mut().drain(..).collect();
+        // There's no point making changes to a frontend which is waiting to
+        // be replaced, the resync will reset the buffer anyway
+        if changes.is_empty() || self.inner.desynced.get() {
+            return;
+        }
+        let message = self.message();
+        let result = tracing::info_span!("frontend_change", ops = changes.len()).in_scope(|| {
+            self.inner.frontend.borrow_mut().change(Some(message), |doc| {
+                for change in &changes {
+                    doc.add_change(change.clone())?;
+                }
+                Ok(())
+            })
+        });
+        match result {
+            Ok(Some(cr)) => self.inner.sender.send(cr),
+            Ok(None) => {}
+            Err(e) => {
+                tracing::error!("The frontend couldn't make the changes from the buffer: {:?}", e);
+                self.inner.desynced.set(true);
+            }
+        }
+    }
+
+    /// Change how long keystrokes are batched up for, making whatever has
+    /// been batched up so far first
+    pub fn set_window(&self, window: Option<Duration>) {
+        self.flush();
+        self.inner.window.set(window);
+    }
+
+    /// Drop every pending change without making it
+    pub fn discard(&self) {
+        if let Some(source) = self.inner.source.borrow_mut().take() {
+            glib::source_remove(source);
+        }
+        self.inner.held_since.set(None);
+        self.inner.pending.borrow_mut().clear();
+        self.inner.inserted.set(0);
+        self.inner.deleted.set(0);
+    }
+
+    /// Describe the pending changes, resetting the counts
+    fn message(&self) -> String {
+        let chars = |n| if n == 1 { "1 character".to_string() } else { format!("{} characters", n) };
+        match (self.inner.inserted.replace(0), self.inner.deleted.replace(0)) {
+            (0, deleted) => format!("Delete {}", chars(deleted)),
+            (inserted, 0) => format!("Type {}", chars(inserted)),
+            (inserted, deleted) => format!("Type {} and delete {}", chars(inserted), chars(deleted)),
+        }
+    }
+}
@@ -0,0 +1,187 @@
+//! The Preferences window, opened from Edit > Preferences. A form over the
+//! settings in `settings.rs`, sending the whole of them back up each time one
+//! is changed, so the model can apply it and save them straight away.
+//!
+//! The number fields take effect as they're typed in; something which isn't a
+//! number is ignored until it is one, and an empty field is the default.
+
+use vgtk::ext::*;
+use vgtk::lib::gtk::*;
+use vgtk::{gtk, Callback, Component, UpdateAction, VNode};
+
+use crate::settings;
+
+/// What the font button shows while the theme's font is used
+const THEME_FONT: &str = "Monospace 11";
+
+#[derive(Default)]
+pub struct PreferencesView {
+    settings: settings::Settings,
+    on_change: Callback<settings::Settings>,
+    on_close: Callback<()>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Port {
+    Serve,
+    Http,
+    Metrics,
+}
+
+#[derive(Debug, Clone)]
+pub enum PreferencesMessage {
+    Font(Option<String>),
+    DarkMode(bool),
+    Autosave(String),
+    Coalesce(String),
+    DisplayName(String),
+    Port(Port, String),
+    Close,
+}
+
+#[derive(Clone, Default)]
+pub struct PreferencesViewProperties {
+    settings: settings::Settings,
+    on_change: Callback<settings::Settings>,
+    on_close: Callback<()>,
+}
+
+/// The number in a field, `None` if it's empty, or `Err` if it isn't a
+/// number yet
+fn parse<T: std::str::FromStr>(text: &str) -> Result<Option<T>, ()> {
+    let text = text.trim();
+    if text.is_empty() {
+        Ok(None)
+    } else {
+        text.parse().map(Some).map_err(|_| ())
+    }
+}
+
+fn number_text<T: ToString>(value: Option<T>) -> String {
+    value.map(|v| v.to_string()).unwrap_or_default()
+}
+
+impl PreferencesView {
+    /// A label and the control for it, on row `row` of the grid
+    fn row(&self, row: i32, label: &str, control: VNode<PreferencesView>) -> Vec<VNode<PreferencesView>> {
+        vec![
+            gtk!{ <Label label=label.to_string() xalign=0.0 Grid::left_attach=0 Grid::top_attach=row /> },
+            gtk!{ <Box Grid::left_attach=1 Grid::top_attach=row hexpand=true>{control}</Box> },
+        ]
+    }
+
+    fn port_entry(&self, port: Port, value: Option<u16>) -> VNode<PreferencesView> {
+        gtk!{
+            <Entry text=number_text(value) placeholder_text="Off" width_chars=6 on changed=|entry| {
+                PreferencesMessage::Port(port, entry.get_text().map(|t| t.to_string()).unwrap_or_default())
+            } />
+        }
+    }
+}
+
+impl Component for PreferencesView {
+    type Message = PreferencesMessage;
+    type Properties = PreferencesViewProperties;
+
+    fn create(properties: Self::Properties) -> Self {
+        PreferencesView {
+            settings: properties.settings,
+            on_change: properties.on_change,
+            on_close: properties.on_close,
+        }
+    }
+
+    fn change(&mut self, properties: Self::Properties) -> UpdateAction<Self> {
+        self.on_change = properties.on_change;
+        self.on_close = properties.on_close;
+        // Dark mode can also be switched from the View menu. Nothing else
+        // changes behind our back, and rendering would put the last number
+        // which parsed back in a field being typed in.
+        if properties.settings.dark_mode != self.settings.dark_mode {
+            self.settings.dark_mode = properties.settings.dark_mode;
+            UpdateAction::Render
+        } else {
+            UpdateAction::None
+        }
+    }
+
+    fn update(&mut self, msg: Self::Message) -> UpdateAction<Self> {
+        let settings = &mut self.settings;
+        let parsed = match msg {
+            PreferencesMessage::Font(font) => {
+                settings.font = font;
+                Ok(())
+            }
+            PreferencesMessage::DarkMode(dark) => {
+                settings.dark_mode = dark;
+                Ok(())
+            }
+            PreferencesMessage::Autosave(text) => parse(&text).map(|secs| settings.autosave_secs = secs),
+            PreferencesMessage::Coalesce(text) => parse(&text).map(|ms| settings.coalesce_ms = ms),
+            PreferencesMessage::DisplayName(name) => {
+                settings.display_name = Some(name).filter(|name| !name.trim().is_empty());
+                Ok(())
+            }
+            PreferencesMessage::Port(port, text) => parse(&text).map(|value| match port {
+                Port::Serve => settings.serve_port = value,
+                Port::Http => settings.http_port = value,
+                Port::Metrics => settings.metrics_port = value,
+            }),
+            PreferencesMessage::Close => {
+                self.on_close.send(());
+                return UpdateAction::None;
+            }
+        };
+        if parsed.is_ok() {
+            self.on_change.send(self.settings.clone());
+        }
+        UpdateAction::None
+    }
+
+    fn view(&self) -> VNode<Self> {
+        let settings = &self.settings;
+        let font = settings.font.clone().unwrap_or_else(|| THEME_FONT.to_string());
+        let rows = vec![
+            self.row(0, "Font", gtk!{
+                <Box orientation=Orientation::Horizontal spacing=6>
+                    <FontButton font=font.as_str() on font_set=|button| {
+                        PreferencesMessage::Font(button.get_font().map(|f| f.to_string()))
+                    } />
+                    <Button label="Default" sensitive=settings.font.is_some() on clicked=|_| PreferencesMessage::Font(None) />
+                </Box>
+            }),
+            self.row(1, "Dark theme", gtk!{
+                <CheckButton active=settings.dark_mode on toggled=|button| PreferencesMessage::DarkMode(button.get_active()) />
+            }),
+            self.row(2, "Autosave every (seconds)", gtk!{
+                <Entry text=number_text(settings.autosave_secs) placeholder_text="Never" width_chars=6 on changed=|entry| {
+                    PreferencesMessage::Autosave(entry.get_text().map(|t| t.to_string()).unwrap_or_default())
+                } />
+            }),
+            self.row(3, "Batch keystrokes for (ms)", gtk!{
+                <Entry text=number_text(settings.coalesce_ms) placeholder_text="Off" width_chars=6 on changed=|entry| {
+                    PreferencesMessage::Coalesce(entry.get_text().map(|t| t.to_string()).unwrap_or_default())
+                } />
+            }),
+            self.row(4, "Display name", gtk!{
+                <Entry text=settings.display_name.clone().unwrap_or_default() placeholder_text="Doc 1" on changed=|entry| {
+                    PreferencesMessage::DisplayName(entry.get_text().map(|t| t.to_string()).unwrap_or_default())
+                } />
+            }),
+            self.row(5, "WebSocket server port", self.port_entry(Port::Serve, settings.serve_port)),
+            self.row(6, "HTTP API port", self.port_entry(Port::Http, settings.http_port)),
+            self.row(7, "Metrics port", self.port_entry(Port::Metrics, settings.metrics_port)),
+        ];
+        gtk!{
+            <Window title="Preferences" default_width=420 border_width=12 on destroy=|_| PreferencesMessage::Close>
+                <Box orientation=Orientation::Vertical spacing=12>
+                    <Grid row_spacing=6 column_spacing=12>
+                        {rows.into_iter().flatten()}
+                    </Grid>
+                    <Label label="The ports take effect the next time the demo starts, unless they're given on the command line."
+                        line_wrap=true xalign=0.0 />
+                </Box>
+            </Window>
+        }
+    }
+}
@@ -0,0 +1,256 @@
+//! Who is editing the document. Each frontend has an identity - a display
+//! name and a color - which is ephemeral: it lives in the application model
+//! rather than in the automerge document, so changing your name doesn't add
+//! to the document's history.
+//!
+//! Activity is tracked the same way. Whenever a frontend makes a local change
+//! its `Heartbeat` sends a `PresenceEvent` down the presence channel (at most
+//! once per `HEARTBEAT_INTERVAL`). The model drains the channel on every
+//! presence tick and peers which haven't been seen for `IDLE_AFTER` are shown
+//! as idle.
+//!
+//! Other instances of the demo get the same, and a little more, over the
+//! channel each websocket session has alongside the changes. On every
+//! presence tick each tab's `PresenceState` - who it is, where its cursor
+//! and selection are, whether it's typing and which lines it can see - is
+//! sent to the instances we're connected to if it's changed, or if it
+//! hasn't been sent for `KEEPALIVE`. These messages never go near a backend
+//! so they're never written to the automerge history, and a state which
+//! arrives late is simply replaced by the next one. Anyone we haven't heard
+//! from for `FORGET_AFTER` has gone, and is dropped. The cursors and
+//! selections aren't shown yet, but they're in every `Presence` for the
+//! views which want them.
+
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+use vgtk::lib::glib;
+
+use crate::peer::DocumentId;
+use crate::theme;
+
+/// The minimum time between heartbeats from one frontend
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+/// How long after its last heartbeat a peer is considered idle
+pub const IDLE_AFTER: Duration = Duration::from_secs(10);
+/// How often the model checks for peers going idle
+pub const TICK_INTERVAL: Duration = Duration::from_secs(1);
+/// How long after its last heartbeat a frontend is still typing
+const TYPING_FOR: Duration = Duration::from_secs(3);
+/// How often a tab's state is sent to other instances even if it hasn't
+/// changed
+pub const KEEPALIVE: Duration = Duration::from_secs(5);
+/// How long after its last state another instance's tab is forgotten
+pub const FORGET_AFTER: Duration = Duration::from_secs(30);
+
+/// The colors new identities are given, in order
+const PALETTE: [&str; 6] = ["#e01b24", "#3584e4", "#33d17a", "#f6d32d", "#9141ac", "#ff7800"];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Identity {
+    pub actor_id: String,
+    pub name: String,
+    /// Any color GDK can parse, e.g "#3584e4" or "rgb(53,132,228)"
+    pub color: String,
+}
+
+impl Identity {
+    /// The default identity for the `n`th document
+    pub fn new(actor_id: String, n: usize) -> Identity {
+        Identity {
+            actor_id,
+            name: format!("Doc {}", n + 1),
+            color: PALETTE[n % PALETTE.len()].to_string(),
+        }
+    }
+
+    /// Pango markup showing a colored dot followed by the name, or a grey
+    /// dot and name if the peer is idle. The color is adjusted to be
+    /// legible in the dark theme or the light one, see `theme.rs`.
+    pub fn markup(&self, active: bool, dark: bool) -> String {
+        if active {
+            format!(
+                "<span foreground=\"{}\">\u{25cf}</span> {}",
+                glib::markup_escape_text(&theme::legible(&self.color, dark)),
+                glib::markup_escape_text(&self.name)
+            )
+        } else {
+            format!(
+                "<span foreground=\"grey\">\u{25cb} {}</span>",
+                glib::markup_escape_text(&self.name)
+            )
+        }
+    }
+}
+
+/// What we know about another frontend
+#[derive(Clone, Debug)]
+pub struct Presence {
+    pub identity: Identity,
+    pub last_seen: Option<Instant>,
+    /// Whether we've seen a heartbeat in the last `IDLE_AFTER`, as of the
+    /// last presence tick
+    pub active: bool,
+    /// Where they are and what they're doing, as of the last presence tick
+    pub state: Option<PresenceState>,
+}
+
+impl Presence {
+    pub fn new(identity: Identity) -> Presence {
+        Presence {
+            identity,
+            last_seen: None,
+            active: false,
+            state: None,
+        }
+    }
+
+    /// `Identity::markup`, saying so if they're typing
+    pub fn markup(&self, dark: bool) -> String {
+        let typing = self.state.as_ref().map(|s| s.typing).unwrap_or(false);
+        if typing {
+            format!("{} <i>typing\u{2026}</i>", self.identity.markup(self.active, dark))
+        } else {
+            self.identity.markup(self.active, dark)
+        }
+    }
+}
+
+/// What one tab is doing right now, exchanged with other instances but
+/// never written to the document. Offsets are in characters of the text.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PresenceState {
+    pub actor_id: String,
+    pub name: String,
+    pub color: String,
+    /// The document this is a tab of, `None` for the shared document, whose
+    /// id other instances don't know
+    pub document: Option<DocumentId>,
+    /// Goes up with every state a tab sends, so one which has already been
+    /// seen, or which arrives after a newer one, is ignored
+    pub seq: u64,
+    pub cursor: usize,
+    /// The start and end of the selection, if anything is selected
+    pub selection: Option<(usize, usize)>,
+    pub typing: bool,
+    /// The first and last lines showing
+    pub viewport: Option<(usize, usize)>,
+}
+
+impl PresenceState {
+    /// Whether this says anything `other` doesn't, whatever their `seq`s
+    pub fn differs_from(&self, other: &PresenceState) -> bool {
+        *self != PresenceState{seq: self.seq, ..other.clone()}
+    }
+}
+
+/// Everything but who a tab is and which document it's in, see
+/// `Doc::activity`
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Activity {
+    pub cursor: usize,
+    pub selection: Option<(usize, usize)>,
+    pub typing: bool,
+    pub viewport: Option<(usize, usize)>,
+}
+
+/// Actor ID -> presence for every frontend we know about
+pub type PresenceMap = BTreeMap<String, Presence>;
+
+/// Events sent on the ephemeral presence channel. These are never written
+/// to the document.
+#[derive(Clone, Debug)]
+pub enum PresenceEvent {
+    Heartbeat { actor_id: String, at: Instant },
+}
+
+/// Sends throttled heartbeats for one frontend. Cheap to clone so it can be
+/// moved into signal handlers.
+#[derive(Clone)]
+pub struct Heartbeat {
+    actor_id: String,
+    sx: crossbeam::Sender<PresenceEvent>,
+    last_sent: Rc<Cell<Option<Instant>>>,
+}
+
+impl Heartbeat {
+    pub fn new(actor_id: String, sx: crossbeam::Sender<PresenceEvent>) -> Heartbeat {
+        Heartbeat {
+            actor_id,
+            sx,
+            last_sent: Rc::new(Cell::new(None)),
+        }
+    }
+
+    /// Whether the local user has done anything in the last `TYPING_FOR`
+    pub fn typing(&self) -> bool {
+        self.last_sent.get().map(|last| last.elapsed() < TYPING_FOR).unwrap_or(false)
+    }
+
+    /// Record that the local user did something
+    pub fn beat(&self) {
+        let now = Instant::now();
+        if let Some(last) = self.last_sent.get() {
+            if now.duration_since(last) < HEARTBEAT_INTERVAL {
+                return;
+            }
+        }
+        self.last_sent.set(Some(now));
+        let _ = self.sx.send(PresenceEvent::Heartbeat {
+            actor_id: self.actor_id.clone(),
+            at: now,
+        });
+    }
+}
+
+/// Apply every pending presence event to `presence`, returning whether the
+/// set of active peers changed (and so whether we need to re-render)
+pub fn update(presence: &mut PresenceMap, events: &crossbeam::Receiver<PresenceEvent>) -> bool {
+    for event in events.try_iter() {
+        match event {
+            PresenceEvent::Heartbeat { actor_id, at } => {
+                if let Some(p) = presence.get_mut(&actor_id) {
+                    p.last_seen = Some(at);
+                }
+            }
+        }
+    }
+    refresh(presence)
+}
+
+/// Take in a state another instance sent us, and the identity it gives
+pub fn receive(remote: &mut PresenceMap, state: PresenceState) {
+    let n = remote.len();
+    let peer = remote.entry(state.actor_id.clone())
+        .or_insert_with(|| Presence::new(Identity::new(state.actor_id.clone(), n)));
+    peer.identity.name = state.name.clone();
+    peer.identity.color = state.color.clone();
+    peer.last_seen = Some(Instant::now());
+    peer.state = Some(state);
+}
+
+/// Drop the other instances' tabs we haven't heard from for `FORGET_AFTER`
+/// and refresh the rest, returning whether anything changed
+pub fn expire(remote: &mut PresenceMap) -> bool {
+    let before = remote.len();
+    remote.retain(|_, p| p.last_seen.map(|seen| seen.elapsed() < FORGET_AFTER).unwrap_or(false));
+    refresh(remote) | (remote.len() != before)
+}
+
+/// Mark peers active or idle by when they were last seen, returning whether
+/// any of them changed
+pub fn refresh(presence: &mut PresenceMap) -> bool {
+    let now = Instant::now();
+    let mut changed = false;
+    for p in presence.values_mut() {
+        let active = match p.last_seen {
+            Some(seen) => now.duration_since(seen) < IDLE_AFTER,
+            None => false,
+        };
+        changed |= active != p.active;
+        p.active = active;
+    }
+    changed
+}
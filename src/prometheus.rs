@@ -0,0 +1,82 @@
+//! Exposes the pipeline metrics over HTTP in the Prometheus text format, so
+//! throughput and latency can be graphed while stress testing.
+//!
+//! Started with `--metrics-port <port>`. This is the smallest HTTP server
+//! which will satisfy a Prometheus scraper: every request, whatever its path,
+//! gets the current metrics and the connection is closed.
+
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+
+use crate::metrics::{Metrics, Totals, ROUND_TRIP_BUCKETS_MS};
+
+/// Start serving metrics on `port` in a background thread
+pub fn serve(port: u16, metrics: Arc<Metrics>) -> io::Result<()> {
+    let listener = TcpListener::bind(("0.0.0.0", port))?;
+    tracing::info!("serving metrics on port {}", port);
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if let Err(e) = respond(stream, &metrics) {
+                        tracing::warn!("metrics request failed: {}", e);
+                    }
+                }
+                Err(e) => tracing::warn!("metrics accept failed: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+fn respond(stream: TcpStream, metrics: &Metrics) -> io::Result<()> {
+    // Read and ignore the request headers
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 0 && line != "\r\n" {
+        line.clear();
+    }
+    let body = render(&metrics.totals());
+    let mut stream = stream;
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+/// Format the metrics in the Prometheus text exposition format
+pub fn render(totals: &Totals) -> String {
+    let mut out = String::new();
+    writeln!(out, "# HELP automerge_demo_change_requests_total Change requests sent by frontends.").unwrap();
+    writeln!(out, "# TYPE automerge_demo_change_requests_total counter").unwrap();
+    writeln!(out, "automerge_demo_change_requests_total {}", totals.requests).unwrap();
+    writeln!(out, "# HELP automerge_demo_patches_total Patches applied to frontends.").unwrap();
+    writeln!(out, "# TYPE automerge_demo_patches_total counter").unwrap();
+    writeln!(out, "automerge_demo_patches_total {}", totals.patches).unwrap();
+    writeln!(out, "# HELP automerge_demo_queue_depth Change requests waiting for the backend.").unwrap();
+    writeln!(out, "# TYPE automerge_demo_queue_depth gauge").unwrap();
+    writeln!(out, "automerge_demo_queue_depth {}", totals.queue_depth).unwrap();
+    writeln!(out, "# HELP automerge_demo_backend_queue_depth Messages waiting for the backend thread when it last woke up.").unwrap();
+    writeln!(out, "# TYPE automerge_demo_backend_queue_depth gauge").unwrap();
+    writeln!(out, "automerge_demo_backend_queue_depth {}", totals.backend_queue).unwrap();
+    writeln!(out, "# HELP automerge_demo_backend_messages_total Messages the backend thread has handled.").unwrap();
+    writeln!(out, "# TYPE automerge_demo_backend_messages_total counter").unwrap();
+    writeln!(out, "automerge_demo_backend_messages_total {}", totals.backend_events).unwrap();
+    writeln!(out, "# HELP automerge_demo_backend_busy_milliseconds_total Time the backend thread has spent handling messages.").unwrap();
+    writeln!(out, "# TYPE automerge_demo_backend_busy_milliseconds_total counter").unwrap();
+    writeln!(out, "automerge_demo_backend_busy_milliseconds_total {}", totals.backend_busy_ms).unwrap();
+    writeln!(out, "# HELP automerge_demo_round_trip_milliseconds Time from sending a change request to receiving its patch.").unwrap();
+    writeln!(out, "# TYPE automerge_demo_round_trip_milliseconds histogram").unwrap();
+    for (bound, count) in ROUND_TRIP_BUCKETS_MS.iter().zip(totals.round_trip.buckets.iter()) {
+        writeln!(out, "automerge_demo_round_trip_milliseconds_bucket{{le=\"{}\"}} {}", bound, count).unwrap();
+    }
+    writeln!(out, "automerge_demo_round_trip_milliseconds_bucket{{le=\"+Inf\"}} {}", totals.round_trip.count).unwrap();
+    writeln!(out, "automerge_demo_round_trip_milliseconds_sum {}", totals.round_trip.sum_ms).unwrap();
+    writeln!(out, "automerge_demo_round_trip_milliseconds_count {}", totals.round_trip.count).unwrap();
+    out
+}
@@ -0,0 +1,151 @@
+//! `automerge-demo relay <port>`, a headless server which forwards changes
+//! between everyone connected to it.
+//!
+//! Without a relay every instance which wants to sync with every other one
+//! has to connect to each of them. With one they all connect to the relay,
+//! with `--connect`, and it sends each of them the changes the others make,
+//! so any number of machines can collaborate in a star. The relay speaks the
+//! same websocket protocol as the GUI's server, so JS clients can join too,
+//! and takes the same `--tls-cert`/`--tls-key`, authentication and
+//! `--sync-filter` flags.
+//!
+//! The relay keeps the document in a backend of its own, which is what new
+//! clients are sent as the history and which checks changes make sense
+//! before they're passed on. With `--store <file>` the history is saved
+//! there, in the same format as File > Save, at most once every
+//! `SAVE_INTERVAL`, and loaded again when the relay starts, so the document
+//! outlives everyone disconnecting.
+//!
+//! `automerge-demo serve <file>` is the same loop for a document which is
+//! already saved: the file is the store, and with `--http-port` the HTTP API
+//! (see `http.rs`) reads the document from the relay's backend, as it would
+//! from the shared document's in the GUI.
+
+use automerge_backend::{Backend, Change};
+use crossbeam::channel::{RecvError, RecvTimeoutError};
+use rustls::ServerConfig;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::auth::Auth;
+use crate::backend::{BackendCommand, BackendHandle, SHARED_DOCUMENT};
+use crate::file;
+use crate::sync_filter::SyncFilters;
+use crate::transport::{Channels, Transport};
+use crate::ws::{self, WsEvent};
+
+const SAVE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What the relay was woken up by
+enum Received {
+    Peer(Result<WsEvent, RecvTimeoutError>),
+    Command(Result<BackendCommand, RecvError>),
+}
+
+/// Run the relay until the process is killed, answering the HTTP API's
+/// questions about the document on `commands`
+pub fn run(port: u16, tls: Option<Arc<ServerConfig>>, auth: Arc<Auth>, store: Option<PathBuf>, filters: SyncFilters, commands: crossbeam::Receiver<BackendCommand>) -> io::Result<()> {
+    let mut backend = Backend::init();
+    if let Some(path) = store.as_ref().filter(|p| p.exists()) {
+        let changes = file::load(path)?;
+        tracing::info!("loaded {} changes from {}", changes.len(), path.display());
+        Backend::apply_changes(&mut backend, changes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+    }
+    let (events_sx, events) = crossbeam::channel::unbounded();
+    ws::listen(port, tls, auth, events_sx)?;
+    let mut transport = Channels::new(events, filters);
+    // Everything already stored counts as sent, clients get it as their
+    // history
+    transport.send_changes(None, &BackendHandle::get_changes(&mut backend));
+    let mut unsaved = false;
+    let mut last_save = Instant::now();
+    let mut commands = commands;
+    loop {
+        let received = {
+            let mut select = crossbeam::channel::Select::new();
+            let events_index = select.recv(transport.peer_events());
+            select.recv(&commands);
+            match select.select_timeout(SAVE_INTERVAL) {
+                Ok(op) if op.index() == events_index => {
+                    Received::Peer(op.recv(transport.peer_events()).map_err(|_| RecvTimeoutError::Disconnected))
+                }
+                Ok(op) => Received::Command(op.recv(&commands)),
+                Err(_) => Received::Peer(Err(RecvTimeoutError::Timeout)),
+            }
+        };
+        let event = match received {
+            Received::Peer(event) => event,
+            Received::Command(Ok(command)) => {
+                answer(&mut backend, command);
+                continue;
+            }
+            // Nothing more will be asked
+            Received::Command(Err(_)) => {
+                commands = crossbeam::channel::never();
+                continue;
+            }
+        };
+        match event {
+            Ok(WsEvent::Connected{client, server}) => {
+                transport.add_peer(client, server, &BackendHandle::get_changes(&mut backend))
+            }
+            Ok(WsEvent::Changes{changes, server, document: None}) => {
+                let changes = transport.recv_changes(server, changes);
+                if changes.is_empty() {
+                    continue;
+                }
+                if let Err(e) = Backend::apply_changes(&mut backend, changes.clone()) {
+                    tracing::warn!("not relaying changes which don't apply: {}", e);
+                    continue;
+                }
+                transport.send_changes(None, &changes);
+                unsaved = true;
+            }
+            // The relay only keeps the one document, instances which say
+            // hello to it send it their others too, what their tabs are
+            // doing and the signatures of their changes
+            Ok(WsEvent::Changes{document: Some(_), ..}) | Ok(WsEvent::Documents{..}) | Ok(WsEvent::Presence(_)) | Ok(WsEvent::Signature(..)) => {}
+            Ok(WsEvent::Rejected{client, reason}) => tracing::warn!("rejected {}: {}", client, reason),
+            // Only sent for connections we make, which a relay doesn't
+            Ok(WsEvent::Status(..)) | Ok(WsEvent::Verify{..}) | Ok(WsEvent::Signal{..}) => {}
+            Ok(WsEvent::Announced{..}) | Ok(WsEvent::NetworkPeer{..}) => {}
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+        if let Some(path) = &store {
+            if unsaved && last_save.elapsed() >= SAVE_INTERVAL {
+                save(path, &mut backend);
+                unsaved = false;
+                last_save = Instant::now();
+            }
+        }
+    }
+}
+
+/// The relay's backend is the shared document, and the only one it has
+fn answer(backend: &mut Backend, command: BackendCommand) {
+    match command {
+        BackendCommand::GetState{document: SHARED_DOCUMENT, reply} => match BackendHandle::get_patch(backend) {
+            Ok(patch) => {
+                let _ = reply.send(patch);
+            }
+            Err(e) => tracing::error!("could not get the relay's state: {}", e),
+        },
+        BackendCommand::GetChangesSince{document: SHARED_DOCUMENT, heads, reply} => {
+            let _ = reply.send((BackendHandle::get_changes_since(backend, &heads), BackendHandle::get_heads(backend)));
+        }
+        // Only the HTTP API asks anything, dropping the reply tells it
+        // there's no answer
+        _ => {}
+    }
+}
+
+fn save(path: &Path, backend: &mut Backend) {
+    let changes: Vec<Change> = BackendHandle::get_changes(backend);
+    match file::save(path, &changes) {
+        Ok(()) => tracing::debug!("saved {} changes to {}", changes.len(), path.display()),
+        Err(e) => tracing::error!("could not save to {}: {}", path.display(), e),
+    }
+}
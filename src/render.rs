@@ -0,0 +1,61 @@
+//! Keeping vgtk to one render a frame.
+//!
+//! vgtk renders the whole view again, and diffs it against the widgets,
+//! whenever an update asks it to. Most updates ask, so clicking Increment as
+//! fast as a script can, or `--stress` typing into every tab, was thousands
+//! of renders a second, far more than the screen shows. So `Model::update`
+//! no longer renders straight away: it tells the `Scheduler`, which asks the
+//! window's frame clock to tick, and when it does, `Message::Frame` renders
+//! once for everything that asked since the last frame. Before the window is
+//! up there's no frame clock, and updates render as they always did.
+
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use vgtk::lib::glib;
+use vgtk::lib::gtk::prelude::*;
+
+use crate::{Message, Model};
+
+/// Made at startup, as the model has no scope of its own
+#[derive(Clone)]
+pub struct Scheduler {
+    scope: vgtk::Scope<Model>,
+    /// Whether a tick is on its way to render
+    waiting: Arc<AtomicBool>,
+}
+
+impl fmt::Debug for Scheduler {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Scheduler").field("waiting", &self.waiting.load(Ordering::Relaxed)).finish()
+    }
+}
+
+impl Scheduler {
+    pub fn new(scope: vgtk::Scope<Model>) -> Scheduler {
+        Scheduler { scope, waiting: Arc::new(AtomicBool::new(false)) }
+    }
+
+    /// Ask for a render on the next frame, returning false if there's no
+    /// window to wait for a frame of, and the caller had better render now
+    pub fn request(&self) -> bool {
+        let window = match vgtk::current_window() {
+            Some(window) if window.get_realized() => window,
+            _ => return false,
+        };
+        // Already asked for this frame
+        if self.waiting.swap(true, Ordering::Relaxed) {
+            return true;
+        }
+        let (scope, waiting) = (self.scope.clone(), self.waiting.clone());
+        window.add_tick_callback(move |_, _| {
+            waiting.store(false, Ordering::Relaxed);
+            if let Err(e) = scope.try_send(Message::Frame) {
+                tracing::warn!("Could not render the frame: {}", e);
+            }
+            // Only ticking while there's something to render
+            glib::Continue(false)
+        });
+        true
+    }
+}
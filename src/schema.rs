@@ -0,0 +1,263 @@
+//! The shape of a new document, read from a TOML file with `--schema`.
+//!
+//! Without a schema a new document has a counter at `root.counts` and the
+//! text at `root.text`. A schema replaces those with whatever fields it
+//! declares, each of which becomes a key of the root, is set by the
+//! document's first change and gets a widget in the Fields tab, so a new
+//! document shape can be tried out without touching `Doc::new`:
+//!
+//! ```toml
+//! [[fields]]
+//! name = "text"
+//! kind = "text"
+//!
+//! [[fields]]
+//! name = "likes"
+//! kind = "counter"
+//! initial = 10
+//!
+//! [[fields]]
+//! name = "tags"
+//! kind = "list"
+//! initial = ["draft"]
+//! ```
+//!
+//! The kinds are `counter`, `text`, `string` (a plain string, set as a
+//! whole, so concurrent edits conflict rather than merge) and `list` (a
+//! list of strings). The main editor, blame, find and the preview are all
+//! built on `root.text`, so the schema has to have a text field called
+//! `text`. They're shown on the Text tab rather than the Fields tab, as is a
+//! counter called `counts` if there is one. The keys the rest of the demo
+//! uses, like the checklist's `todos`, can't be declared.
+
+use automerge_frontend::{Frontend, LocalChange, Path, Value};
+use automerge_protocol as amp;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::io;
+
+use crate::state::{Text, Typed};
+
+/// The keys used by the checklist, chat, table, kanban board, marks and title
+const RESERVED: &[&str] = &["title", "marks", "todos", "chat", "table", "table_rows", "table_cols", "kanban"];
+
+pub use automerge_demo_core::text::{COUNTER_FIELD, TEXT_FIELD};
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Schema {
+    pub fields: Vec<Field>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Field {
+    pub name: String,
+    #[serde(flatten)]
+    pub kind: FieldKind,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum FieldKind {
+    Counter {
+        #[serde(default)]
+        initial: i64,
+    },
+    Text {
+        #[serde(default)]
+        initial: String,
+    },
+    String {
+        #[serde(default)]
+        initial: String,
+    },
+    List {
+        #[serde(default)]
+        initial: Vec<String>,
+    },
+}
+
+impl Default for Schema {
+    fn default() -> Schema {
+        Schema {
+            fields: vec![
+                Field { name: COUNTER_FIELD.to_string(), kind: FieldKind::Counter { initial: 0 } },
+                Field { name: TEXT_FIELD.to_string(), kind: FieldKind::Text { initial: String::new() } },
+            ],
+        }
+    }
+}
+
+/// The current value of a field, for its widget
+#[derive(Clone, Debug, PartialEq)]
+pub enum FieldValue {
+    Counter(i64),
+    Text(String),
+    String(String),
+    List(Vec<String>),
+}
+
+/// Something done to a field in the Fields tab
+#[derive(Clone, Debug)]
+pub enum FieldAction {
+    Increment(String),
+    /// Set a text or string field to a new value. Text is changed by the
+    /// fewest inserts and deletes that get it there, so it still merges.
+    Set(String, String),
+    AddItem(String, String),
+    RemoveItem(String, usize),
+}
+
+impl FieldAction {
+    fn message(&self) -> String {
+        match self {
+            FieldAction::Increment(name) => format!("Increment {}", name),
+            FieldAction::Set(name, _) => format!("Edit {}", name),
+            FieldAction::AddItem(name, item) => format!("Add \"{}\" to {}", item, name),
+            FieldAction::RemoveItem(name, i) => format!("Remove item {} from {}", i + 1, name),
+        }
+    }
+}
+
+impl Schema {
+    /// Read and check a schema file
+    pub fn load(path: &std::path::Path) -> io::Result<Schema> {
+        let schema: Schema = toml::from_str(&std::fs::read_to_string(path)?)
+            .map_err(|e| invalid(e.to_string()))?;
+        let mut names = HashSet::new();
+        for field in &schema.fields {
+            if RESERVED.contains(&field.name.as_str()) {
+                return Err(invalid(format!("\"{}\" is used by the demo itself", field.name)));
+            }
+            if !names.insert(field.name.as_str()) {
+                return Err(invalid(format!("\"{}\" is declared twice", field.name)));
+            }
+        }
+        if !schema.fields.iter().any(|f| f.name == TEXT_FIELD && f.is_text()) {
+            return Err(invalid(format!("there has to be a text field called \"{}\"", TEXT_FIELD)));
+        }
+        Ok(schema)
+    }
+
+    /// The changes setting every field to its initial value
+    pub fn initial_changes(&self) -> Vec<LocalChange> {
+        self.fields.iter().map(|f| LocalChange::set(Path::root().key(&f.name), f.initial_value())).collect()
+    }
+
+    /// Whether there's a counter called `counts` for the Text tab
+    pub fn has_counter(&self) -> bool {
+        self.fields.iter().any(|f| f.name == COUNTER_FIELD && matches!(f.kind, FieldKind::Counter { .. }))
+    }
+
+    /// The fields shown in the Fields tab, i.e. everything but the ones on
+    /// the Text tab
+    pub fn extra_fields(&self) -> impl Iterator<Item = &Field> {
+        let has_counter = self.has_counter();
+        self.fields.iter().filter(move |f| f.name != TEXT_FIELD && !(has_counter && f.name == COUNTER_FIELD))
+    }
+
+    /// The current value of every field in the Fields tab
+    pub fn values(&self, frontend: &Frontend) -> Vec<(String, FieldValue)> {
+        self.extra_fields().map(|f| (f.name.clone(), f.value(frontend))).collect()
+    }
+
+    /// Apply a Fields tab action to the frontend, returning the change
+    /// request to send to the backend
+    pub fn apply(&self, frontend: &mut Frontend, action: FieldAction) -> Option<amp::Request> {
+        let name = match &action {
+            FieldAction::Increment(name) | FieldAction::Set(name, _) | FieldAction::AddItem(name, _) | FieldAction::RemoveItem(name, _) => name,
+        };
+        let field = self.fields.iter().find(|f| f.name == *name)?;
+        let path = Path::root().key(&field.name);
+        let changes = match (field.value(frontend), &action) {
+            (FieldValue::Counter(_), FieldAction::Increment(_)) => vec![LocalChange::increment(path)],
+            (FieldValue::Text(old), FieldAction::Set(_, new)) => Text(new.clone()).reconcile(&Text(old), &path),
+            (FieldValue::String(old), FieldAction::Set(_, new)) if old != *new => {
+                vec![LocalChange::set(path, Value::Primitive(amp::Value::Str(new.clone())))]
+            }
+            (FieldValue::List(items), FieldAction::AddItem(_, item)) if !item.trim().is_empty() => {
+                vec![LocalChange::insert(path.index(items.len()), Value::Primitive(amp::Value::Str(item.clone())))]
+            }
+            (FieldValue::List(items), FieldAction::RemoveItem(_, i)) if *i < items.len() => {
+                vec![LocalChange::delete(path.index(*i))]
+            }
+            _ => Vec::new(),
+        };
+        if changes.is_empty() {
+            return None;
+        }
+        let result = frontend.change(Some(action.message()), |doc| {
+            for change in changes {
+                doc.add_change(change)?;
+            }
+            Ok(())
+        });
+        match result {
+            Ok(cr) => cr,
+            // The document was made with a different schema, e.g. it was
+            // opened from a file, and doesn't have this field
+            Err(e) => {
+                tracing::warn!("Could not change {}: {:?}", name, e);
+                None
+            }
+        }
+    }
+}
+
+impl Field {
+    fn is_text(&self) -> bool {
+        matches!(self.kind, FieldKind::Text { .. })
+    }
+
+    fn initial_value(&self) -> Value {
+        match &self.kind {
+            FieldKind::Counter { initial } => Value::Primitive(amp::Value::Counter(*initial)),
+            FieldKind::Text { initial } => Value::Sequence(
+                initial.chars().map(|c| Value::Primitive(amp::Value::Str(c.to_string()))).collect(),
+                amp::SequenceType::Text,
+            ),
+            FieldKind::String { initial } => Value::Primitive(amp::Value::Str(initial.clone())),
+            FieldKind::List { initial } => Value::Sequence(
+                initial.iter().map(|s| Value::Primitive(amp::Value::Str(s.clone()))).collect(),
+                amp::SequenceType::List,
+            ),
+        }
+    }
+
+    /// Read the field out of the frontend. Anything missing or of the
+    /// wrong type reads as empty.
+    fn value(&self, frontend: &Frontend) -> FieldValue {
+        let value = frontend.get_value(&Path::root().key(&self.name));
+        match &self.kind {
+            FieldKind::Counter { .. } => FieldValue::Counter(match value {
+                Some(Value::Primitive(amp::Value::Counter(i))) => i,
+                _ => 0,
+            }),
+            FieldKind::Text { .. } => FieldValue::Text(match &value {
+                Some(Value::Sequence(elems, amp::SequenceType::Text)) => strings(elems).concat(),
+                _ => String::new(),
+            }),
+            FieldKind::String { .. } => FieldValue::String(match value {
+                Some(Value::Primitive(amp::Value::Str(s))) => s,
+                _ => String::new(),
+            }),
+            FieldKind::List { .. } => FieldValue::List(match &value {
+                Some(Value::Sequence(elems, _)) => strings(elems),
+                _ => Vec::new(),
+            }),
+        }
+    }
+}
+
+fn strings(elems: &[Value]) -> Vec<String> {
+    elems
+        .iter()
+        .map(|e| match e {
+            Value::Primitive(amp::Value::Str(s)) => s.clone(),
+            _ => String::new(),
+        })
+        .collect()
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
@@ -0,0 +1,310 @@
+//! The script console, Tools > Script Console, for driving the open tabs
+//! from a Rhai script: the same edits over and over while chasing a bug, or
+//! two tabs typing into the same spot at once to make a conflict happen.
+//!
+//! ```text
+//! insert_text(0, "hello");
+//! let other = tab(2);
+//! other.insert_text(0, "X");   // before the first insert has reached it
+//! sleep(500);
+//! print(get_text());
+//! ```
+//!
+//! Each `Doc` is a tab, numbered as in the tab's default title, so `tab(1)`
+//! and `tab(2)` are Doc 1 and Doc 2, the two every session starts with,
+//! whatever they've been renamed since. The functions which
+//! aren't called on one work on `doc`, the tab the console was run from. A
+//! `Doc` has `insert_text(offset, text)`, `delete_range(start, end)`, in
+//! chars like `--record-session`, `splice(edits)`, which makes every
+//! `[start, end, text]` in `edits` as one change, like a caret at each (see
+//! `splice.rs`), `inc_counter()` and `get_text()`, and `sleep(ms)` waits.
+//!
+//! The script runs in a thread of its own rather than on the main loop, so a
+//! `sleep` doesn't stop the UI, and patches keep arriving while it waits.
+//! Every edit is sent to the UI as a `Message::Script`, which plays it into
+//! the tab's text buffer the way a recorded session is played back (see
+//! `session.rs`), and the script waits for the reply before carrying on. So
+//! a `get_text` always sees the script's own edits, and only gets someone
+//! else's once their patch has arrived.
+//!
+//! Stop sets a flag which Rhai checks as it goes, and a `sleep` wakes up
+//! regularly to check it too.
+
+use rhai::{Array, Dynamic, Engine, EvalAltResult, ImmutableString};
+use std::convert::TryFrom;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use vgtk::lib::gtk::prelude::*;
+use vgtk::lib::gtk::{TextBuffer, TextTagTable};
+
+use crate::session::Input;
+use crate::splice::Splice;
+use crate::{Message, Model};
+
+/// How often a sleeping script checks whether it has been stopped
+const STOP_CHECK: Duration = Duration::from_millis(50);
+
+/// What a running script asks of the UI
+#[derive(Clone, Debug)]
+pub enum Call {
+    /// Play `input` into the tab with peer id `tab`, replying with whether
+    /// there is one
+    Input { tab: usize, input: Input, reply: crossbeam::Sender<bool> },
+    /// Make `splices` to the tab with peer id `tab` as one change, replying
+    /// with why not if it can't
+    Splice { tab: usize, splices: Vec<Splice>, reply: crossbeam::Sender<Result<(), String>> },
+    /// Reply with the text of the tab with peer id `tab`, if there is one
+    Text { tab: usize, reply: crossbeam::Sender<Option<String>> },
+    Print(String),
+    /// The script has finished, with what it evaluated to unless it failed
+    Finished(Result<String, String>),
+}
+
+/// How scripts get back to the UI, made at startup, as the model has no
+/// scope of its own
+#[derive(Clone)]
+pub struct Runner {
+    scope: vgtk::Scope<Model>,
+}
+
+impl fmt::Debug for Runner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Runner").finish()
+    }
+}
+
+impl Runner {
+    pub fn new(scope: vgtk::Scope<Model>) -> Runner {
+        Runner { scope }
+    }
+}
+
+/// The console's script, what it has printed and the script running, if
+/// one is. Kept while the console is closed, so the script is still there
+/// when it's opened again.
+pub struct Console {
+    pub source: sourceview::Buffer,
+    pub output: TextBuffer,
+    stop: Option<Arc<AtomicBool>>,
+}
+
+impl Default for Console {
+    fn default() -> Console {
+        let source = sourceview::Buffer::new::<TextTagTable>(None);
+        // Close enough to Rhai to be worth highlighting
+        crate::syntax::set_language(source.upcast_ref::<TextBuffer>(), Some("rust"));
+        Console {
+            source,
+            output: TextBuffer::new::<TextTagTable>(None),
+            stop: None,
+        }
+    }
+}
+
+impl Console {
+    pub fn running(&self) -> bool {
+        self.stop.is_some()
+    }
+
+    /// Start the script in the source buffer, editing tab `tab` unless it
+    /// says otherwise
+    pub fn run(&mut self, tab: usize, runner: &Runner) {
+        if self.running() {
+            return;
+        }
+        let (start, end) = self.source.get_bounds();
+        let source = self.source.get_text(&start, &end, false).map(|s| s.to_string()).unwrap_or_default();
+        let stop = Arc::new(AtomicBool::new(false));
+        self.stop = Some(stop.clone());
+        self.print(&format!("Running in Doc {}", tab + 1));
+        let scope = runner.scope.clone();
+        std::thread::spawn(move || {
+            let link = Link { scope, stop };
+            let result = run(&source, tab, link.clone()).map_err(|e| {
+                if link.stopped() {
+                    "Stopped".to_string()
+                } else {
+                    e.to_string()
+                }
+            });
+            let _ = link.scope.try_send(Message::Script(Call::Finished(result)));
+        });
+    }
+
+    pub fn stop(&self) {
+        if let Some(stop) = &self.stop {
+            stop.store(true, Ordering::Relaxed);
+        }
+    }
+
+    pub fn finished(&mut self, result: Result<String, String>) {
+        self.stop = None;
+        match result {
+            Ok(value) if value.is_empty() => self.print("Done"),
+            Ok(value) => self.print(&format!("Done: {}", value)),
+            Err(e) => self.print(&format!("Error: {}", e)),
+        }
+    }
+
+    /// Add a line to the output
+    pub fn print(&self, line: &str) {
+        let mut end = self.output.get_end_iter();
+        self.output.insert(&mut end, &format!("{}\n", line));
+    }
+
+    pub fn clear(&self) {
+        self.output.set_text("");
+    }
+}
+
+/// The script's way back to the UI
+#[derive(Clone)]
+struct Link {
+    scope: vgtk::Scope<Model>,
+    stop: Arc<AtomicBool>,
+}
+
+impl fmt::Debug for Link {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Link").field("stop", &self.stop).finish()
+    }
+}
+
+type ScriptResult<T> = Result<T, Box<EvalAltResult>>;
+
+impl Link {
+    fn stopped(&self) -> bool {
+        self.stop.load(Ordering::Relaxed)
+    }
+
+    /// Send the UI `call`, made with the sender of a reply channel, and wait
+    /// for its reply
+    fn ask<T>(&self, call: impl FnOnce(crossbeam::Sender<T>) -> Call) -> ScriptResult<T> {
+        let (reply, replies) = crossbeam::channel::bounded(1);
+        self.scope
+            .try_send(Message::Script(call(reply)))
+            .map_err(|_| "the application has gone away".to_string())?;
+        Ok(replies.recv().map_err(|_| "the application has gone away".to_string())?)
+    }
+}
+
+/// A tab, as the script sees it
+#[derive(Clone, Debug)]
+struct Doc {
+    tab: usize,
+    link: Link,
+}
+
+impl Doc {
+    fn input(&mut self, input: Input) -> ScriptResult<()> {
+        let tab = self.tab;
+        if self.link.ask(|reply| Call::Input { tab, input, reply })? {
+            Ok(())
+        } else {
+            Err(format!("there's no Doc {}", tab + 1).into())
+        }
+    }
+
+    fn insert_text(&mut self, offset: i64, text: ImmutableString) -> ScriptResult<()> {
+        let offset = offset_arg(offset)?;
+        self.input(Input::Insert { offset, text: text.to_string() })
+    }
+
+    fn delete_range(&mut self, start: i64, end: i64) -> ScriptResult<()> {
+        let (start, end) = (offset_arg(start)?, offset_arg(end)?);
+        if end < start {
+            return Err(format!("the range {}..{} ends before it starts", start, end).into());
+        }
+        self.input(Input::Delete { start, end })
+    }
+
+    /// Make every `[start, end, text]` of `edits` at once, each in terms of
+    /// the text as it is before any of them
+    fn splice(&mut self, edits: Array) -> ScriptResult<()> {
+        let splices = edits.into_iter().map(splice_arg).collect::<ScriptResult<Vec<Splice>>>()?;
+        let tab = self.tab;
+        self.link.ask(|reply| Call::Splice { tab, splices, reply })?.map_err(|e| e.into())
+    }
+
+    fn inc_counter(&mut self) -> ScriptResult<()> {
+        self.input(Input::IncrementCounter)
+    }
+
+    fn get_text(&mut self) -> ScriptResult<String> {
+        let tab = self.tab;
+        self.link
+            .ask(|reply| Call::Text { tab, reply })?
+            .ok_or_else(|| format!("there's no Doc {}", tab + 1).into())
+    }
+}
+
+fn offset_arg(offset: i64) -> ScriptResult<usize> {
+    usize::try_from(offset).map_err(|_| format!("offsets can't be negative, got {}", offset).into())
+}
+
+/// A `[start, end, text]` from a script
+fn splice_arg(edit: Dynamic) -> ScriptResult<Splice> {
+    let parts = edit.try_cast::<Array>();
+    let (start, end, text) = match parts.as_deref() {
+        Some([start, end, text]) => (start.as_int(), end.as_int(), text.clone().into_string()),
+        _ => return Err("each edit is [start, end, text]".into()),
+    };
+    match (start, end, text) {
+        (Ok(start), Ok(end), Ok(text)) => Ok(Splice::new(offset_arg(start)?..offset_arg(end)?, &text)),
+        _ => Err("each edit is [start, end, text], two offsets and a string".into()),
+    }
+}
+
+fn sleep(link: &Link, ms: i64) -> ScriptResult<()> {
+    let until = Instant::now() + Duration::from_millis(u64::try_from(ms).unwrap_or(0));
+    while let Some(left) = until.checked_duration_since(Instant::now()).filter(|d| !d.is_zero()) {
+        if link.stopped() {
+            return Err("stopped".into());
+        }
+        std::thread::sleep(left.min(STOP_CHECK));
+    }
+    Ok(())
+}
+
+/// Run `source` to the end, with `doc` as the tab with peer id `tab`
+fn run(source: &str, tab: usize, link: Link) -> ScriptResult<String> {
+    let mut engine = Engine::new();
+    let current = Doc { tab, link: link.clone() };
+    engine.register_type_with_name::<Doc>("Doc");
+    engine.register_fn("insert_text", Doc::insert_text);
+    engine.register_fn("delete_range", Doc::delete_range);
+    engine.register_fn("splice", Doc::splice);
+    engine.register_fn("inc_counter", Doc::inc_counter);
+    engine.register_fn("get_text", Doc::get_text);
+    // The same again, for the tab the console was run from
+    let doc = current.clone();
+    engine.register_fn("insert_text", move |offset: i64, text: ImmutableString| doc.clone().insert_text(offset, text));
+    let doc = current.clone();
+    engine.register_fn("delete_range", move |start: i64, end: i64| doc.clone().delete_range(start, end));
+    let doc = current.clone();
+    engine.register_fn("splice", move |edits: Array| doc.clone().splice(edits));
+    let doc = current.clone();
+    engine.register_fn("inc_counter", move || doc.clone().inc_counter());
+    let doc = current.clone();
+    engine.register_fn("get_text", move || doc.clone().get_text());
+    let linked = link.clone();
+    engine.register_fn("tab", move |n: i64| -> ScriptResult<Doc> {
+        match usize::try_from(n) {
+            Ok(n) if n >= 1 => Ok(Doc { tab: n - 1, link: linked.clone() }),
+            _ => Err(format!("tabs are numbered from 1, got {}", n).into()),
+        }
+    });
+    let sleeping = link.clone();
+    engine.register_fn("sleep", move |ms: i64| sleep(&sleeping, ms));
+    let printing = link.clone();
+    engine.on_print(move |line| {
+        let _ = printing.scope.try_send(Message::Script(Call::Print(line.to_string())));
+    });
+    engine.on_progress(move |_| link.stopped().then(|| Dynamic::UNIT));
+    let mut scope = rhai::Scope::new();
+    scope.push("doc", current);
+    let value: Dynamic = engine.eval_with_scope(&mut scope, source)?;
+    Ok(if value.is::<()>() { String::new() } else { value.to_string() })
+}
@@ -0,0 +1,96 @@
+//! The script console window, opened from Tools > Script Console. The script
+//! and what it prints live in buffers the model keeps, see `script.rs`, so
+//! this is just the two views over them and the buttons.
+
+use vgtk::ext::*;
+use vgtk::lib::gtk::*;
+use vgtk::{gtk, Callback, Component, UpdateAction, VNode};
+use sourceview::View as SourceView;
+use sourceview::prelude::*;
+
+#[derive(Default)]
+pub struct ScriptView {
+    source: Option<sourceview::Buffer>,
+    output: Option<TextBuffer>,
+    running: bool,
+    on_run: Callback<()>,
+    on_stop: Callback<()>,
+    on_clear: Callback<()>,
+    on_close: Callback<()>,
+}
+
+#[derive(Debug, Clone)]
+pub enum ScriptMessage {
+    Run,
+    Stop,
+    Clear,
+    Close,
+}
+
+#[derive(Clone, Default)]
+pub struct ScriptViewProperties {
+    source: Option<sourceview::Buffer>,
+    output: Option<TextBuffer>,
+    /// Whether a script is running, so it can be stopped but not run again
+    running: bool,
+    on_run: Callback<()>,
+    on_stop: Callback<()>,
+    on_clear: Callback<()>,
+    on_close: Callback<()>,
+}
+
+impl Component for ScriptView {
+    type Message = ScriptMessage;
+    type Properties = ScriptViewProperties;
+
+    fn create(properties: Self::Properties) -> Self {
+        ScriptView {
+            source: properties.source,
+            output: properties.output,
+            running: properties.running,
+            on_run: properties.on_run,
+            on_stop: properties.on_stop,
+            on_clear: properties.on_clear,
+            on_close: properties.on_close,
+        }
+    }
+
+    fn change(&mut self, properties: Self::Properties) -> UpdateAction<Self> {
+        *self = ScriptView::create(properties);
+        UpdateAction::Render
+    }
+
+    fn update(&mut self, msg: Self::Message) -> UpdateAction<Self> {
+        match msg {
+            ScriptMessage::Run => self.on_run.send(()),
+            ScriptMessage::Stop => self.on_stop.send(()),
+            ScriptMessage::Clear => self.on_clear.send(()),
+            ScriptMessage::Close => self.on_close.send(()),
+        }
+        UpdateAction::None
+    }
+
+    fn view(&self) -> VNode<Self> {
+        let running = self.running;
+        gtk!{
+            <Window title="Script Console" default_width=600 default_height=500 border_width=12 on destroy=|_| ScriptMessage::Close>
+                <Box orientation=Orientation::Vertical spacing=8>
+                    <Paned orientation=Orientation::Vertical Box::expand=true>
+                        <ScrolledWindow height_request=200>
+                            <SourceView buffer=self.source.clone() monospace=true auto_indent=true show_line_numbers=true />
+                        </ScrolledWindow>
+                        <ScrolledWindow height_request=100>
+                            <TextView buffer=self.output.clone() editable=false cursor_visible=false monospace=true />
+                        </ScrolledWindow>
+                    </Paned>
+                    <Box orientation=Orientation::Horizontal spacing=6>
+                        <Button label="Run" sensitive=!running on clicked=|_| ScriptMessage::Run />
+                        <Button label="Stop" sensitive=running on clicked=|_| ScriptMessage::Stop />
+                        <Button label="Clear Output" on clicked=|_| ScriptMessage::Clear />
+                        <Label label=if running { "Running\u{2026}" } else { "" } Box::pack_type=PackType::End />
+                    </Box>
+                </Box>
+            </Window>
+        }
+    }
+}
@@ -0,0 +1,120 @@
+//! Recording a session's input and playing it back, for reproducible demos
+//! and bug reports.
+//!
+//! With `--record-session <file>` every insert, delete and counter click in
+//! every tab is appended to `file` as it happens, one JSON object per line,
+//! along with how long after startup it happened and which tab it was in.
+//! `--play-session <file>` feeds a recording back in at the same times (or
+//! faster with `--play-speed <factor>`). Each text edit goes back into the
+//! same tab's text buffer at the same offset, so it takes the same path
+//! through the signal handlers, frontend and backend as the original did and
+//! the simulated typists in `stress.rs` are recorded like anyone else.
+//!
+//! Tabs are identified by peer id, which is the order they were opened in,
+//! so the two tabs every session starts with always line up.
+
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, LineWriter, Write};
+use std::path::Path;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// One thing the user did
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "kind")]
+pub enum Input {
+    /// `text` was inserted at char `offset`
+    Insert { offset: usize, text: String },
+    /// The chars from `start` up to `end` were deleted
+    Delete { start: usize, end: usize },
+    IncrementCounter,
+    DecrementCounter,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SessionEvent {
+    /// Milliseconds since the recording started
+    pub at_ms: u64,
+    /// The peer id of the tab it happened in
+    pub tab: usize,
+    pub input: Input,
+}
+
+#[derive(Debug)]
+struct Output {
+    started: Instant,
+    file: LineWriter<File>,
+}
+
+/// Appends events to the recording, each doc gets one for its tab with
+/// `for_tab`
+#[derive(Clone, Debug)]
+pub struct Recorder {
+    output: Rc<RefCell<Output>>,
+    tab: usize,
+}
+
+impl Recorder {
+    pub fn create(path: &Path) -> io::Result<Recorder> {
+        Ok(Recorder {
+            output: Rc::new(RefCell::new(Output {
+                started: Instant::now(),
+                file: LineWriter::new(File::create(path)?),
+            })),
+            tab: 0,
+        })
+    }
+
+    /// A recorder writing to the same recording for the tab with peer id
+    /// `tab`
+    pub fn for_tab(&self, tab: usize) -> Recorder {
+        Recorder {
+            output: self.output.clone(),
+            tab,
+        }
+    }
+
+    pub fn record(&self, input: Input) {
+        let mut output = self.output.borrow_mut();
+        let event = SessionEvent {
+            at_ms: output.started.elapsed().as_millis() as u64,
+            tab: self.tab,
+            input,
+        };
+        let written = serde_json::to_writer(&mut output.file, &event)
+            .map_err(io::Error::from)
+            .and_then(|()| output.file.write_all(b"\n"));
+        if let Err(e) = written {
+            tracing::warn!("Could not record {:?}: {}", event, e);
+        }
+    }
+}
+
+pub fn load(path: &Path) -> io::Result<Vec<SessionEvent>> {
+    let file = BufReader::new(File::open(path)?);
+    let mut events = Vec::new();
+    for line in file.lines() {
+        let line = line?;
+        if !line.trim().is_empty() {
+            events.push(serde_json::from_str(&line)?);
+        }
+    }
+    Ok(events)
+}
+
+/// Hand each event to `send` when it is due, `speed` times as fast as it was
+/// recorded. Blocks until every event has been sent or `send` returns false.
+pub fn play(events: Vec<SessionEvent>, speed: f64, mut send: impl FnMut(SessionEvent) -> bool) {
+    let started = Instant::now();
+    for event in events {
+        let due = Duration::from_secs_f64(event.at_ms as f64 / 1000.0 / speed);
+        if let Some(wait) = due.checked_sub(started.elapsed()) {
+            std::thread::sleep(wait);
+        }
+        if !send(event) {
+            return;
+        }
+    }
+}
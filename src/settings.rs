@@ -0,0 +1,88 @@
+//! The user's preferences, kept between runs in
+//! `~/.config/automerge-demo/settings.toml` and changed in the Preferences
+//! dialog, see `preferences_view.rs`.
+//!
+//! They're read once at startup and written back whenever one of them is
+//! changed from the UI, so there's no save step to forget. Everything but the
+//! ports applies to the running demo as soon as it's changed. The ports are
+//! for the servers the demo starts, which it only does at startup, and the
+//! command line flags for them win.
+//!
+//! A missing file is the defaults, and a file which doesn't parse is
+//! reported and ignored rather than stopping the demo from starting; it's
+//! overwritten the next time something is changed.
+
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::file;
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct Settings {
+    /// Whether to ask GTK for the dark variant of the theme
+    pub dark_mode: bool,
+    /// A Pango font description for the text, "Monospace 12" say, or the
+    /// theme's font
+    pub font: Option<String>,
+    /// How often to save the documents which have a file, in seconds
+    pub autosave_secs: Option<u64>,
+    /// How long to batch up keystrokes for, if `--coalesce-ms` doesn't say
+    pub coalesce_ms: Option<u64>,
+    /// What to call us to everyone else, rather than "Doc 1" and so on
+    pub display_name: Option<String>,
+    /// The ports for `--serve-ws`, `--http-port` and `--metrics-port` when
+    /// they aren't given
+    pub serve_port: Option<u16>,
+    pub http_port: Option<u16>,
+    pub metrics_port: Option<u16>,
+}
+
+fn path() -> Option<PathBuf> {
+    file::config_path("settings.toml")
+}
+
+/// The settings saved last time, or the defaults
+pub fn load() -> Settings {
+    let path = match path() {
+        Some(path) => path,
+        None => return Settings::default(),
+    };
+    let text = match std::fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Settings::default(),
+        Err(e) => {
+            tracing::warn!("Could not read the settings in {}: {}", path.display(), e);
+            return Settings::default();
+        }
+    };
+    toml::from_str(&text).unwrap_or_else(|e| {
+        tracing::warn!("Ignoring the settings in {}: {}", path.display(), e);
+        Settings::default()
+    })
+}
+
+impl Settings {
+    /// How long to batch up keystrokes for, `None` for not at all
+    pub fn coalesce(&self) -> Option<Duration> {
+        self.coalesce_ms.filter(|ms| *ms > 0).map(Duration::from_millis)
+    }
+
+    /// The name for our `n`th tab, if we've been given one. Every tab after
+    /// the first is numbered so they can be told apart.
+    pub fn tab_name(&self, n: usize) -> Option<String> {
+        let name = self.display_name.as_ref().filter(|name| !name.is_empty())?;
+        Some(if n == 0 { name.clone() } else { format!("{} ({})", name, n + 1) })
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        let path = path().ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "there's no config directory"))?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let text = toml::to_string(self).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        std::fs::write(path, text)
+    }
+}
@@ -0,0 +1,74 @@
+//! Keyboard shortcuts, and the window listing them, View > Keyboard
+//! Shortcuts or Ctrl+?.
+//!
+//! Every shortcut is the accelerator of one of the window's actions, the
+//! same actions the menus activate, so a shortcut does nothing when its menu
+//! item is greyed out and there's only one place to say what each does. The
+//! table below is used both to give the actions their accelerators and to
+//! build the shortcuts window, so the two can't disagree.
+//!
+//! Accelerators are handled before the focused widget sees the key, so
+//! Ctrl+Z is our undo, of our own changes, even in the text view. The text
+//! view's own undo would undo remote edits along with ours, which is why
+//! `Doc` turns it off.
+
+use vgtk::lib::glib::markup_escape_text;
+use vgtk::lib::gtk::prelude::*;
+use vgtk::lib::gtk::{ApplicationWindow, Builder, ShortcutsWindow};
+
+/// A section of the shortcuts window: its title, then each shortcut's
+/// action, accelerators, the first of which is the one shown, and title
+const SHORTCUTS: &[(&str, &[(&str, &[&str], &str)])] = &[
+    ("Documents", &[
+        ("win.new", &["<Primary>n"], "New document"),
+        ("win.open", &["<Primary>o"], "Open a document"),
+        ("win.save", &["<Primary>s"], "Save"),
+        ("win.save-as", &["<Primary><Shift>s"], "Save as"),
+        ("win.close-tab", &["<Primary>w"], "Close the tab"),
+        ("win.quit", &["<Primary>q"], "Quit"),
+    ]),
+    ("Editing", &[
+        ("win.undo", &["<Primary>z"], "Undo"),
+        ("win.redo", &["<Primary><Shift>z", "<Primary>y"], "Redo"),
+        ("win.find", &["<Primary>f"], "Find and replace"),
+        ("win.increment", &["<Primary>plus", "<Primary>equal", "<Primary>KP_Add"], "Increment the counter"),
+        ("win.decrement", &["<Primary>minus", "<Primary>KP_Subtract"], "Decrement the counter"),
+    ]),
+    ("View", &[
+        ("win.patch-log", &["<Primary>d"], "Show the patch log"),
+        ("win.preferences", &["<Primary>comma"], "Preferences"),
+        ("win.show-help-overlay", &["<Primary>question"], "Keyboard shortcuts"),
+    ]),
+];
+
+/// Give the window's actions their accelerators, and the window its
+/// shortcuts window
+pub fn install(window: &ApplicationWindow) {
+    if let Some(app) = window.get_application() {
+        for (_, shortcuts) in SHORTCUTS {
+            for (action, accels, _) in shortcuts.iter() {
+                app.set_accels_for_action(action, accels);
+            }
+        }
+    }
+    window.set_help_overlay(Some(&shortcuts_window()));
+}
+
+/// The shortcuts window, made from GtkBuilder XML as it's made of objects
+/// which are only ever put together that way
+fn shortcuts_window() -> ShortcutsWindow {
+    let mut ui = String::from(r#"<interface><object class="GtkShortcutsWindow" id="shortcuts"><property name="modal">1</property><child><object class="GtkShortcutsSection"><property name="section-name">shortcuts</property><property name="visible">1</property>"#);
+    for (section, shortcuts) in SHORTCUTS {
+        ui.push_str(&format!(r#"<child><object class="GtkShortcutsGroup"><property name="title">{}</property><property name="visible">1</property>"#, markup_escape_text(section)));
+        for (_, accels, title) in shortcuts.iter() {
+            ui.push_str(&format!(
+                r#"<child><object class="GtkShortcutsShortcut"><property name="accelerator">{}</property><property name="title">{}</property><property name="visible">1</property></object></child>"#,
+                markup_escape_text(accels[0]),
+                markup_escape_text(title),
+            ));
+        }
+        ui.push_str("</object></child>");
+    }
+    ui.push_str("</object></child></object></interface>");
+    Builder::new_from_string(&ui).get_object("shortcuts").unwrap()
+}
@@ -0,0 +1,157 @@
+//! Signing the changes we make, and checking the signatures on the changes
+//! other instances send us.
+//!
+//! Automerge will apply any change which is well formed, and the actor ID
+//! in a change is just a random ID anyone can put in theirs, so nothing in
+//! the sync protocol says who really made it. With `--sign-key` the backend
+//! thread signs the hash of every change it makes with an Ed25519 key, one
+//! made with `generate-key`, and sends the signature to other instances
+//! along with the change. The hash covers the change's bytes, including the
+//! hashes of the changes it depends on, so a signature can't be moved onto
+//! a different change and a change can't be altered without the signature
+//! failing to verify.
+//!
+//! The signatures can't go in the changes themselves, a change's hash is
+//! computed over everything in it. So they're kept on the side, in a map
+//! from change hash to public key and signature, and travel as their own
+//! messages, see `ws.rs`. Instances which receive one check it and pass it
+//! on, so a change keeps its signature however many instances it goes
+//! through. Only instances which said hello exchange signatures, a JS
+//! client never sees them.
+//!
+//! A signature is trusted if the key which made it is ours or is in the
+//! `--authorized-keys` file. The patch log flags every change which doesn't
+//! have a valid signature by a trusted key. It looks the signatures up when
+//! it's shown, because the history a session starts with arrives before the
+//! hello that lets the signatures for it be sent, so they often come after
+//! the changes. Signatures are only kept in memory, changes loaded from the
+//! store or a file count as unsigned until someone sends their signatures.
+
+use automerge_backend::Change;
+use automerge_protocol as amp;
+use ring::signature::{self, Ed25519KeyPair, KeyPair, UnparsedPublicKey};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::auth;
+
+/// The length of an Ed25519 public key and of a signature made with one
+pub const PUBLIC_KEY_LEN: usize = 32;
+pub const SIGNATURE_LEN: usize = 64;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Signature {
+    pub public_key: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+/// What we know about who made a change
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Status {
+    /// Signed by a key we trust
+    Verified,
+    /// Properly signed, but by a key we don't know
+    Untrusted,
+    /// Nobody has sent us a signature for it
+    Unsigned,
+    /// The signature doesn't match the change
+    Invalid,
+}
+
+impl Status {
+    pub fn label(self) -> &'static str {
+        match self {
+            Status::Verified => "verified",
+            Status::Untrusted => "signed by an unknown key",
+            Status::Unsigned => "unsigned",
+            Status::Invalid => "bad signature",
+        }
+    }
+}
+
+/// Our signing key, the keys we trust and every signature we know of,
+/// shared by the backend thread, which makes and receives them, and the
+/// UI, which shows them
+pub struct Signatures {
+    key: Ed25519KeyPair,
+    trusted: Vec<Vec<u8>>,
+    /// Each signature, and whether it verified when we got it
+    by_change: Mutex<HashMap<amp::ChangeHash, (Signature, bool)>>,
+}
+
+impl Signatures {
+    /// `trusted` is the public keys other than ours whose signatures we
+    /// trust
+    pub fn new(key: Ed25519KeyPair, mut trusted: Vec<Vec<u8>>) -> Signatures {
+        trusted.push(key.public_key().as_ref().to_vec());
+        Signatures {
+            key,
+            trusted,
+            by_change: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Our public key, in hex
+    pub fn public_key(&self) -> String {
+        auth::hex(self.key.public_key().as_ref())
+    }
+
+    /// Sign the changes we've just made, returning the signatures to send
+    pub fn sign(&self, changes: &[Change]) -> Vec<(amp::ChangeHash, Signature)> {
+        let mut by_change = self.by_change.lock().unwrap();
+        changes
+            .iter()
+            .map(|change| {
+                let signature = Signature {
+                    public_key: self.key.public_key().as_ref().to_vec(),
+                    signature: self.key.sign(&change.hash.0).as_ref().to_vec(),
+                };
+                by_change.insert(change.hash, (signature.clone(), true));
+                (change.hash, signature)
+            })
+            .collect()
+    }
+
+    /// Check and keep a signature another instance sent us, returning
+    /// whether it's valid and new, so that it's passed on once and bad ones
+    /// not at all. A valid signature replaces an invalid one, but never the
+    /// other way round.
+    pub fn receive(&self, hash: amp::ChangeHash, signature: Signature) -> bool {
+        let valid = UnparsedPublicKey::new(&signature::ED25519, &signature.public_key)
+            .verify(&hash.0, &signature.signature)
+            .is_ok();
+        if !valid {
+            tracing::warn!("a signature for change {:?} didn't verify", hash);
+        }
+        let mut by_change = self.by_change.lock().unwrap();
+        match by_change.get(&hash) {
+            Some((_, true)) => false,
+            Some((_, false)) if !valid => false,
+            _ => {
+                by_change.insert(hash, (signature, valid));
+                valid
+            }
+        }
+    }
+
+    /// Every valid signature, to send to an instance which has just said
+    /// hello
+    pub fn all(&self) -> Vec<(amp::ChangeHash, Signature)> {
+        self.by_change
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, (_, valid))| *valid)
+            .map(|(hash, (signature, _))| (*hash, signature.clone()))
+            .collect()
+    }
+
+    pub fn status(&self, hash: &amp::ChangeHash) -> Status {
+        match self.by_change.lock().unwrap().get(hash) {
+            None => Status::Unsigned,
+            Some((_, false)) => Status::Invalid,
+            Some((signature, true)) if self.trusted.contains(&signature.public_key) => Status::Verified,
+            Some(_) => Status::Untrusted,
+        }
+    }
+}
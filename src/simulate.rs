@@ -0,0 +1,214 @@
+//! `automerge-demo simulate`, peers editing one document with no window and
+//! no sockets, to see whether they end up with the same text.
+//!
+//! Each peer is a frontend and a backend, as a tab is, and makes its edits
+//! the way a tab does: a change request from the frontend, applied by its
+//! backend, and the patch applied back to the frontend. The changes that
+//! come out are sent to every other peer over a simulated network, where
+//! each one takes a random time up to `--latency-ms` to arrive. So a peer's
+//! changes can overtake each other, and a peer which gets one before the
+//! changes it depends on has to hold on to it until they turn up, as it
+//! would with a slow connection. Time is simulated too, each edit moving it
+//! on a millisecond, so a run is the same every time for the same seed.
+//!
+//! Once everyone has made their edits whatever is still on its way is
+//! delivered, and every peer's text is compared.
+//!
+//! With `--unified` the peers are documents of the unified automerge crate
+//! instead, see `unified.rs`, making the same edits over the same network.
+
+use automerge_backend::{Backend, Change};
+use automerge_frontend::{Frontend, LocalChange, Path, Value};
+use automerge_protocol as amp;
+use std::collections::BTreeMap;
+
+use crate::backend::BackendHandle;
+use crate::cli::SimulateArgs;
+use crate::doc;
+use crate::stress::{Rng, PHRASE};
+#[cfg(feature = "unified")]
+use crate::unified::UnifiedPeer;
+
+/// What the simulation needs of a peer, so that it runs the same on either
+/// automerge
+pub trait Editor {
+    /// What goes over the network
+    type Change: Clone;
+    /// Make one edit, returning the changes for the other peers
+    fn insert(&mut self, index: usize, c: char) -> Result<Vec<Self::Change>, String>;
+    fn delete(&mut self, index: usize) -> Result<Vec<Self::Change>, String>;
+    fn receive(&mut self, changes: Vec<Self::Change>) -> Result<(), String>;
+    fn text(&self) -> String;
+}
+
+/// A frontend and the backend it's in sync with, like a tab
+pub struct Peer {
+    frontend: Frontend,
+    backend: Backend,
+}
+
+impl Default for Peer {
+    /// A peer which has yet to be sent the document
+    fn default() -> Peer {
+        Peer {
+            frontend: Frontend::new(),
+            backend: Backend::init(),
+        }
+    }
+}
+
+impl Peer {
+    /// A peer which makes a document with an empty text, and the change
+    /// making it, for the other peers
+    pub fn creating() -> Result<(Peer, Vec<Change>), String> {
+        let mut peer = Peer::default();
+        let changes = peer.edit(vec![LocalChange::set(
+            Path::root().key("text"),
+            Value::Sequence(Vec::new(), amp::SequenceType::Text),
+        )])?;
+        Ok((peer, changes))
+    }
+
+    /// The change request for `changes`, if they change anything
+    pub fn request(&mut self, changes: Vec<LocalChange>) -> Result<Option<amp::Request>, String> {
+        self.frontend
+            .change(None, |doc| {
+                for change in changes {
+                    doc.add_change(change)?;
+                }
+                Ok(())
+            })
+            .map_err(|e| format!("the frontend rejected the edit: {:?}", e))
+    }
+
+    /// Apply `request` to the backend and the patch to the frontend,
+    /// returning the change it made
+    pub fn apply_request(&mut self, request: amp::Request) -> Result<Vec<Change>, String> {
+        let (patch, changes) = self.backend.apply_local_change_and_get(request)?;
+        self.apply_patch(patch)?;
+        Ok(changes)
+    }
+
+    /// Make `changes` as one change request, like a keystroke, returning the
+    /// change for the other peers
+    pub fn edit(&mut self, changes: Vec<LocalChange>) -> Result<Vec<Change>, String> {
+        match self.request(changes)? {
+            Some(request) => self.apply_request(request),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Apply another peer's changes
+    pub fn receive(&mut self, changes: Vec<Change>) -> Result<(), String> {
+        let patch = BackendHandle::apply_changes(&mut self.backend, changes)?;
+        self.apply_patch(patch)
+    }
+
+    fn apply_patch(&mut self, patch: amp::Patch) -> Result<(), String> {
+        self.frontend.apply_patch(patch).map_err(|e| format!("the frontend rejected a patch: {:?}", e))
+    }
+
+    pub fn text(&self) -> String {
+        doc::text_value(&self.frontend)
+    }
+
+    pub fn counter(&self) -> i64 {
+        doc::counter_value(&self.frontend)
+    }
+}
+
+impl Editor for Peer {
+    type Change = Change;
+
+    fn insert(&mut self, index: usize, c: char) -> Result<Vec<Change>, String> {
+        self.edit(vec![insert_char(index, c)])
+    }
+
+    fn delete(&mut self, index: usize) -> Result<Vec<Change>, String> {
+        self.edit(vec![LocalChange::delete(Path::root().key("text").index(index))])
+    }
+
+    fn receive(&mut self, changes: Vec<Change>) -> Result<(), String> {
+        Peer::receive(self, changes)
+    }
+
+    fn text(&self) -> String {
+        Peer::text(self)
+    }
+}
+
+pub fn insert_char(index: usize, c: char) -> LocalChange {
+    LocalChange::insert(
+        Path::root().key("text").index(index),
+        Value::Primitive(amp::Value::Str(c.to_string())),
+    )
+}
+
+/// Changes on their way, by when they arrive and then the order they were
+/// sent in, to which peer
+type InFlight<C> = BTreeMap<(u64, usize), (usize, Vec<C>)>;
+
+/// Run the simulation, returning each peer's text at the end
+pub fn run(args: &SimulateArgs) -> Result<Vec<String>, String> {
+    if args.peers == 0 {
+        return Err("there have to be some peers".to_string());
+    }
+    #[cfg(feature = "unified")]
+    {
+        if args.unified {
+            let mut first = UnifiedPeer::creating()?;
+            let mut peers: Vec<UnifiedPeer> = (1..args.peers).map(|_| first.fork()).collect();
+            peers.insert(0, first);
+            return simulate(args, peers);
+        }
+    }
+    let (first, history) = Peer::creating()?;
+    let mut peers = vec![first];
+    for _ in 1..args.peers {
+        let mut peer = Peer::default();
+        peer.receive(history.clone())?;
+        peers.push(peer);
+    }
+    simulate(args, peers)
+}
+
+fn simulate<P: Editor>(args: &SimulateArgs, mut peers: Vec<P>) -> Result<Vec<String>, String> {
+    let rng = Rng::new(args.seed);
+    let phrase: Vec<char> = PHRASE.chars().collect();
+    let mut in_flight = InFlight::new();
+    let mut sent = 0;
+    let mut most_in_flight = 0;
+    for now in 0..(args.edits * args.peers) as u64 {
+        deliver(&mut peers, &mut in_flight, Some(now))?;
+        let who = rng.next(peers.len());
+        let len = peers[who].text().chars().count();
+        // Mostly typing, with the odd deletion
+        let changes = if len > 0 && rng.next(5) == 0 {
+            peers[who].delete(rng.next(len))?
+        } else {
+            peers[who].insert(rng.next(len + 1), phrase[now as usize % phrase.len()])?
+        };
+        for to in (0..peers.len()).filter(|to| *to != who) {
+            let arrives = now + rng.next(args.latency_ms as usize + 1) as u64;
+            in_flight.insert((arrives, sent), (to, changes.clone()));
+            sent += 1;
+        }
+        most_in_flight = most_in_flight.max(in_flight.len());
+    }
+    deliver(&mut peers, &mut in_flight, None)?;
+    tracing::info!("sent {} batches of changes, at most {} at once", sent, most_in_flight);
+    Ok(peers.iter().map(P::text).collect())
+}
+
+/// Deliver everything which has arrived by `now`, or everything if there's
+/// no `now`
+fn deliver<P: Editor>(peers: &mut [P], in_flight: &mut InFlight<P::Change>, now: Option<u64>) -> Result<(), String> {
+    let later = match now {
+        Some(now) => in_flight.split_off(&(now + 1, 0)),
+        None => InFlight::new(),
+    };
+    for (_, (to, changes)) in std::mem::replace(in_flight, later) {
+        peers[to].receive(changes)?;
+    }
+    Ok(())
+}
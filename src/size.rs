@@ -0,0 +1,99 @@
+//! How big the document is, and where the bytes go, for the size section of
+//! View > Statistics.
+//!
+//! Automerge keeps every change ever made, so a document's history grows
+//! with every keystroke even when its text doesn't. The report counts the
+//! ops in the history by what they do and how many sequence elements are
+//! deleted but still there as tombstones, since a deleted character is still
+//! in the history and in every backend's op set. It gives the document's size
+//! both as the change log `file.rs` saves and as automerge's compacted
+//! document encoding, Tools > Compact, to show how much of the change log is
+//! the overhead of the changes themselves.
+//!
+//! The frontend's size is an estimate, the size of the values it holds, one
+//! `Value` per character of text and so on. The frontend keeps more than
+//! that, the ids of each element for one, so it's a lower bound.
+
+use automerge_backend::{Backend, Change};
+use automerge_frontend::Value;
+use automerge_protocol as amp;
+use std::collections::BTreeMap;
+use std::mem::size_of;
+
+use crate::file;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SizeReport {
+    pub changes: usize,
+    /// How many ops of each kind there are, most first
+    pub ops: Vec<(String, usize)>,
+    /// Deleted list and text elements
+    pub tombstones: usize,
+    /// The bytes of the change log, as it's saved
+    pub log_bytes: usize,
+    /// The bytes of the compacted document, if it could be made
+    pub compacted_bytes: Option<usize>,
+    /// Roughly how many bytes the frontend's copy takes up
+    pub frontend_bytes: usize,
+}
+
+/// The report for the document with `history`, whose frontend takes up
+/// `frontend_bytes`, see `estimate`
+pub fn compute(history: &[Change], frontend_bytes: usize) -> SizeReport {
+    let mut ops: BTreeMap<String, usize> = BTreeMap::new();
+    let mut tombstones = 0;
+    for change in history {
+        for op in change.decode().operations {
+            if let (amp::OpType::Del, amp::Key::Seq(_)) = (&op.action, &op.key) {
+                tombstones += 1;
+            }
+            *ops.entry(op_kind(&op.action)).or_default() += 1;
+        }
+    }
+    let mut ops: Vec<(String, usize)> = ops.into_iter().collect();
+    ops.sort_by(|(a, m), (b, n)| n.cmp(m).then_with(|| a.cmp(b)));
+    let mut backend = Backend::init();
+    let compacted_bytes = backend.apply_changes(history.to_vec())
+        .and_then(|_| backend.save())
+        .map(|bytes| bytes.len())
+        .map_err(|e| tracing::warn!("could not compact the document to measure it: {}", e))
+        .ok();
+    SizeReport {
+        changes: history.len(),
+        ops,
+        tombstones,
+        log_bytes: file::encode(history).len(),
+        compacted_bytes,
+        frontend_bytes,
+    }
+}
+
+/// What the op does, for people to read. "make text", "set", "delete"...
+fn op_kind(action: &amp::OpType) -> String {
+    // Going by the debug output rather than matching on every kind of op
+    // keeps this working as automerge adds them
+    let debug = format!("{:?}", action);
+    match action {
+        amp::OpType::Make(_) => {
+            let object = debug.split(|c| c == '(' || c == ')').filter(|s| !s.is_empty()).last().unwrap_or("object");
+            format!("make {}", object.to_lowercase())
+        }
+        _ => match debug.split('(').next().unwrap_or_default() {
+            "Del" => "delete".to_string(),
+            "Inc" => "increment".to_string(),
+            kind => kind.to_lowercase(),
+        },
+    }
+}
+
+/// The bytes taken up by `value` and everything in it, leaving out the
+/// allocator's overhead
+pub fn estimate(value: &Value) -> usize {
+    size_of::<Value>()
+        + match value {
+            Value::Map(props, _) => props.iter().map(|(key, value)| size_of::<String>() + key.len() + estimate(value)).sum(),
+            Value::Sequence(values, _) => values.iter().map(estimate).sum(),
+            Value::Primitive(amp::Value::Str(s)) => s.len(),
+            Value::Primitive(_) => 0,
+        }
+}
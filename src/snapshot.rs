@@ -0,0 +1,138 @@
+//! JSON snapshots of the whole document state, File > Save State as JSON
+//! and File > New Document from JSON.
+//!
+//! A snapshot is just the frontend's value tree as JSON: maps and tables
+//! become objects, lists become arrays and strings, numbers, booleans and
+//! null are themselves. Automerge has a few types JSON doesn't, and those are
+//! written as single key objects - `{"$text": "..."}` for text,
+//! `{"$counter": 3}` for counters and `{"$timestamp": 1590000000000}` for
+//! timestamps - so that a snapshot loads back as the same document. That
+//! makes it easy to seed a demo with realistic data written by hand: the
+//! demo's own fields need `"text"` to be `{"$text": ...}` and the table sizes
+//! to be counters, everything else can be plain JSON.
+//!
+//! Loading a snapshot makes a new document with its own history, it doesn't
+//! have any of the history of the document the snapshot was taken from.
+
+use automerge_frontend::{Frontend, Value};
+use automerge_protocol as amp;
+use serde_json::{json, Map, Number};
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+
+const TEXT_KEY: &str = "$text";
+const COUNTER_KEY: &str = "$counter";
+const TIMESTAMP_KEY: &str = "$timestamp";
+
+/// Write the whole state of `frontend` to `path`
+pub fn save(path: &Path, frontend: &mut Frontend) -> io::Result<()> {
+    let json = serde_json::to_vec_pretty(&to_json(&frontend.state()))?;
+    std::fs::write(path, json)
+}
+
+/// Read a snapshot as the values of each key of the document root
+pub fn load(path: &Path) -> io::Result<HashMap<String, Value>> {
+    let json: serde_json::Value = serde_json::from_slice(&std::fs::read(path)?)?;
+    match json {
+        serde_json::Value::Object(root) => root
+            .into_iter()
+            .map(|(key, value)| Ok((key, from_json(value)?)))
+            .collect(),
+        _ => Err(invalid("the snapshot has to be a JSON object".to_string())),
+    }
+}
+
+/// A value as snapshot JSON
+pub fn to_json(value: &Value) -> serde_json::Value {
+    match value {
+        Value::Map(props, _) => serde_json::Value::Object(props.iter().map(|(k, v)| (k.clone(), to_json(v))).collect()),
+        Value::Sequence(elems, amp::SequenceType::Text) => {
+            let text: String = elems
+                .iter()
+                .filter_map(|e| match e {
+                    Value::Primitive(amp::Value::Str(s)) => Some(s.as_str()),
+                    _ => None,
+                })
+                .collect();
+            tagged(TEXT_KEY, json!(text))
+        }
+        Value::Sequence(elems, _) => serde_json::Value::Array(elems.iter().map(to_json).collect()),
+        Value::Primitive(primitive) => match primitive {
+            amp::Value::Str(s) => json!(s),
+            amp::Value::Int(i) => json!(i),
+            amp::Value::Uint(u) => json!(u),
+            amp::Value::F64(f) => Number::from_f64(*f).map(serde_json::Value::Number).unwrap_or(serde_json::Value::Null),
+            amp::Value::Counter(c) => tagged(COUNTER_KEY, json!(c)),
+            amp::Value::Timestamp(t) => tagged(TIMESTAMP_KEY, json!(t)),
+            amp::Value::Boolean(b) => json!(b),
+            // Including null, and anything newer than this demo
+            _ => serde_json::Value::Null,
+        },
+    }
+}
+
+fn from_json(json: serde_json::Value) -> io::Result<Value> {
+    Ok(match json {
+        serde_json::Value::Null => Value::Primitive(amp::Value::Null),
+        serde_json::Value::Bool(b) => Value::Primitive(amp::Value::Boolean(b)),
+        serde_json::Value::Number(n) => Value::Primitive(number(&n)?),
+        serde_json::Value::String(s) => Value::Primitive(amp::Value::Str(s)),
+        serde_json::Value::Array(elems) => Value::Sequence(
+            elems.into_iter().map(from_json).collect::<io::Result<_>>()?,
+            amp::SequenceType::List,
+        ),
+        serde_json::Value::Object(props) => match special(&props) {
+            Some(value) => value?,
+            None => Value::Map(
+                props.into_iter().map(|(k, v)| Ok((k, from_json(v)?))).collect::<io::Result<_>>()?,
+                amp::MapType::Map,
+            ),
+        },
+    })
+}
+
+/// One of the single key objects standing in for a type JSON doesn't have
+fn tagged(key: &str, value: serde_json::Value) -> serde_json::Value {
+    let mut props = Map::new();
+    props.insert(key.to_string(), value);
+    serde_json::Value::Object(props)
+}
+
+/// The value of one of the objects standing in for a type JSON doesn't
+/// have, `None` if it's an ordinary object
+fn special(props: &Map<String, serde_json::Value>) -> Option<io::Result<Value>> {
+    if props.len() != 1 {
+        return None;
+    }
+    let (key, value) = props.iter().next()?;
+    let value = match (key.as_str(), value) {
+        (TEXT_KEY, serde_json::Value::String(s)) => Ok(Value::Sequence(
+            s.chars().map(|c| Value::Primitive(amp::Value::Str(c.to_string()))).collect(),
+            amp::SequenceType::Text,
+        )),
+        (COUNTER_KEY, serde_json::Value::Number(n)) => integer(n).map(|i| Value::Primitive(amp::Value::Counter(i))),
+        (TIMESTAMP_KEY, serde_json::Value::Number(n)) => integer(n).map(|i| Value::Primitive(amp::Value::Timestamp(i))),
+        (TEXT_KEY, _) | (COUNTER_KEY, _) | (TIMESTAMP_KEY, _) => Err(invalid(format!("{} has the wrong type", key))),
+        _ => return None,
+    };
+    Some(value)
+}
+
+fn number(n: &Number) -> io::Result<amp::Value> {
+    if let Some(i) = n.as_i64() {
+        Ok(amp::Value::Int(i))
+    } else if let Some(u) = n.as_u64() {
+        Ok(amp::Value::Uint(u))
+    } else {
+        n.as_f64().map(amp::Value::F64).ok_or_else(|| invalid(format!("{} isn't a number automerge can store", n)))
+    }
+}
+
+fn integer(n: &Number) -> io::Result<i64> {
+    n.as_i64().ok_or_else(|| invalid(format!("{} isn't an integer", n)))
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
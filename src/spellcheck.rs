@@ -0,0 +1,198 @@
+//! Spellchecking the text, View > Check Spelling, with enchant.
+//!
+//! gspell would do this for a text view on its own, but it checks the text
+//! by watching the buffer, and it can't tell a remote patch from a
+//! keystroke, so it would rescan whatever a patch replaced. Instead I tag
+//! misspelled words myself and decide what to look at again. A remote patch
+//! is applied as the edits it makes, see `text_patch.rs`, which say which
+//! offsets changed, and only the words around those get checked again. The
+//! tags everywhere else are left alone, so nothing flashes while someone
+//! else types. Only a patch which replaces the whole text, and a resync,
+//! check everything.
+//!
+//! Local edits are checked once GTK has put them in the buffer: the insert
+//! and delete handlers drop a pair of marks around each edit, which move
+//! with any edits made before the check runs, and the words between them
+//! are checked when the main loop is next idle. The word being typed isn't
+//! flagged until the cursor leaves it, it's only misspelled once it's
+//! finished.
+//!
+//! The dictionary is the one for the language in `$LANG`, falling back to
+//! `en_US`. If enchant doesn't have one spellchecking stays off.
+
+use std::cell::{Cell, RefCell};
+use std::ops::Range;
+use std::rc::Rc;
+use vgtk::lib::glib::{self, ObjectExt};
+use vgtk::lib::gtk::prelude::*;
+use vgtk::lib::gtk::{TextBuffer, TextIter, TextMark, TextTag};
+
+const MISSPELLED_TAG: &str = "misspelled";
+
+const DEFAULT_LANGUAGE: &str = "en_US";
+
+struct Dictionary {
+    // Declared first so that it's dropped before the broker which made it
+    dict: enchant::Dict,
+    _broker: enchant::Broker,
+}
+
+#[derive(Default)]
+struct Inner {
+    dictionary: Option<Dictionary>,
+    enabled: Cell<bool>,
+    /// Local edits waiting to be checked, between pairs of marks
+    pending: RefCell<Vec<(TextMark, TextMark)>>,
+    scheduled: Cell<bool>,
+}
+
+/// The spellchecker for one buffer, shared with its signal handlers
+#[derive(Clone, Default)]
+pub struct Spellcheck {
+    inner: Rc<Inner>,
+}
+
+/// Create the tag for misspelled words in the buffer's tag table
+pub fn create_tag(buffer: &TextBuffer) {
+    let tag = TextTag::new(Some(MISSPELLED_TAG));
+    tag.set_property_underline(pango::Underline::Error);
+    buffer.get_tag_table().unwrap().add(&tag);
+}
+
+impl Spellcheck {
+    /// A spellchecker for `$LANG`, which starts on if there's a dictionary
+    /// for it
+    pub fn new() -> Spellcheck {
+        let language = std::env::var("LANG")
+            .ok()
+            .and_then(|lang| lang.split('.').next().map(String::from))
+            .filter(|lang| !lang.is_empty() && lang != "C" && lang != "POSIX")
+            .unwrap_or_else(|| DEFAULT_LANGUAGE.to_string());
+        let mut broker = enchant::Broker::new();
+        let dictionary = match broker.request_dict(&language) {
+            Ok(dict) => Some(Dictionary { dict, _broker: broker }),
+            Err(e) => {
+                tracing::info!("not checking spelling, there's no {} dictionary: {}", language, e);
+                None
+            }
+        };
+        let enabled = Cell::new(dictionary.is_some());
+        Spellcheck {
+            inner: Rc::new(Inner { dictionary, enabled, ..Inner::default() }),
+        }
+    }
+
+    pub fn available(&self) -> bool {
+        self.inner.dictionary.is_some()
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.inner.enabled.get()
+    }
+
+    /// Turn spellchecking on, checking the whole text, or off, clearing the
+    /// tags
+    pub fn set_enabled(&self, buffer: &TextBuffer, enabled: bool) {
+        self.inner.enabled.set(enabled && self.available());
+        if self.enabled() {
+            self.check_all(buffer);
+        } else {
+            let (start, end) = buffer.get_bounds();
+            buffer.remove_tag_by_name(MISSPELLED_TAG, &start, &end);
+        }
+    }
+
+    /// Check every word, after the whole text has been replaced
+    pub fn check_all(&self, buffer: &TextBuffer) {
+        if self.enabled() {
+            let (start, end) = buffer.get_bounds();
+            self.check(buffer, start, end, None);
+        }
+    }
+
+    /// Check the words around the char ranges a patch touched
+    pub fn check_ranges(&self, buffer: &TextBuffer, ranges: &[Range<usize>]) {
+        if !self.enabled() {
+            return;
+        }
+        for range in ranges {
+            let start = buffer.get_iter_at_offset(range.start as i32);
+            let end = buffer.get_iter_at_offset(range.end as i32);
+            self.check(buffer, start, end, None);
+        }
+    }
+
+    /// Check the words around the text about to be inserted at `iter`, or
+    /// deleted from it, once it's in the buffer. Called from the buffer's
+    /// insert and delete handlers, before the default handler runs.
+    pub fn edited(&self, buffer: &TextBuffer, iter: &TextIter) {
+        if !self.enabled() {
+            return;
+        }
+        // The left gravity mark stays before inserted text and the right
+        // gravity one ends up after it
+        let start = buffer.create_mark(None, iter, true).unwrap();
+        let end = buffer.create_mark(None, iter, false).unwrap();
+        self.inner.pending.borrow_mut().push((start, end));
+        if self.inner.scheduled.replace(true) {
+            return;
+        }
+        let spellcheck = self.clone();
+        let buffer = buffer.downgrade();
+        glib::idle_add_local(move || {
+            spellcheck.inner.scheduled.set(false);
+            let pending = spellcheck.inner.pending.replace(Vec::new());
+            if let Some(buffer) = buffer.upgrade() {
+                let cursor = buffer.get_iter_at_mark(&buffer.get_insert().unwrap()).get_offset();
+                for (start, end) in pending {
+                    let (from, to) = (buffer.get_iter_at_mark(&start), buffer.get_iter_at_mark(&end));
+                    spellcheck.check(&buffer, from, to, Some(cursor));
+                    buffer.delete_mark(&start);
+                    buffer.delete_mark(&end);
+                }
+            }
+            glib::Continue(false)
+        });
+    }
+
+    /// Check the words from `start` to `end`, widened to whole words, and
+    /// tag the misspelled ones. A word ending at `cursor` is still being
+    /// typed and isn't flagged.
+    fn check(&self, buffer: &TextBuffer, mut start: TextIter, mut end: TextIter, cursor: Option<i32>) {
+        let dict = match &self.inner.dictionary {
+            Some(dictionary) if self.enabled() => &dictionary.dict,
+            _ => return,
+        };
+        if !start.starts_word() && (start.inside_word() || start.ends_word()) {
+            start.backward_word_start();
+        }
+        if end.inside_word() && !end.ends_word() {
+            end.forward_word_end();
+        }
+        buffer.remove_tag_by_name(MISSPELLED_TAG, &start, &end);
+        let limit = end.get_offset();
+        let mut word_end = start.clone();
+        loop {
+            let before = word_end.get_offset();
+            // This is false at the end of the buffer even if it's moved to
+            // the end of a word, hence looking at where it's got to instead
+            word_end.forward_word_end();
+            if word_end.get_offset() == before || word_end.get_offset() > limit || !word_end.ends_word() {
+                break;
+            }
+            if cursor == Some(word_end.get_offset()) {
+                continue;
+            }
+            let mut word_start = word_end.clone();
+            word_start.backward_word_start();
+            let word = buffer.get_text(&word_start, &word_end, false).map(|w| w.to_string()).unwrap_or_default();
+            // Numbers and the like aren't words a dictionary knows
+            if word.chars().any(|c| c.is_numeric()) {
+                continue;
+            }
+            if let Ok(false) = dict.check(&word) {
+                buffer.apply_tag_by_name(MISSPELLED_TAG, &word_start, &word_end);
+            }
+        }
+    }
+}
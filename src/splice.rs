@@ -0,0 +1,127 @@
+//! Several edits to the text at once, as if there were a caret at each.
+//!
+//! Replace All, a script editing a column of lines, or anything else which
+//! changes the text in more than one place wants the edits to be one change,
+//! one request to the backend and one step to undo, and wants to say where
+//! each edit goes in the text as it is, not as the edits before it will
+//! leave it. A `Splice` says which chars of the text as it is to replace and
+//! with what, and `arrange` puts a set of them in order, checking none of
+//! them overlap. Then `changes` makes them from the last to the first, so no
+//! edit moves where a later one goes, and `undone` and `carets` do the sums
+//! for where each one ends up, moved along by the ones before it.
+
+use automerge_demo_core::text::{deleted, inserted};
+use automerge_frontend::LocalChange;
+use std::ops::Range;
+
+/// Replace the chars `range` of the text with `text`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Splice {
+    pub range: Range<usize>,
+    pub text: String,
+}
+
+impl Splice {
+    pub fn new(range: Range<usize>, text: &str) -> Splice {
+        Splice { range, text: text.to_string() }
+    }
+}
+
+/// Sort `splices` by where they go in a text of `len` chars, or say why
+/// they can't all be made at once. Ones inserting at the same place stay in
+/// the order they were given.
+pub fn arrange(mut splices: Vec<Splice>, len: usize) -> Result<Vec<Splice>, String> {
+    for splice in &splices {
+        if splice.range.start > splice.range.end || splice.range.end > len {
+            return Err(format!("{}..{} isn't in the text, which is {} chars", splice.range.start, splice.range.end, len));
+        }
+    }
+    splices.sort_by_key(|splice| (splice.range.start, splice.range.end));
+    for pair in splices.windows(2) {
+        if pair[0].range.end > pair[1].range.start {
+            return Err(format!("{}..{} and {}..{} overlap", pair[0].range.start, pair[0].range.end, pair[1].range.start, pair[1].range.end));
+        }
+    }
+    Ok(splices)
+}
+
+/// The changes making arranged `splices`
+pub fn changes(splices: &[Splice]) -> Vec<LocalChange> {
+    splices
+        .iter()
+        .rev()
+        .flat_map(|splice| {
+            let mut changes = deleted(splice.range.start, splice.range.end);
+            changes.extend(inserted(splice.range.start, &splice.text));
+            changes
+        })
+        .collect()
+}
+
+/// Where each of arranged `splices` starts once the ones before it have
+/// been made
+fn moved(splices: &[Splice]) -> impl Iterator<Item = (usize, &Splice)> {
+    let mut shift = 0isize;
+    splices.iter().map(move |splice| {
+        let start = (splice.range.start as isize + shift) as usize;
+        shift += splice.text.chars().count() as isize - splice.range.len() as isize;
+        (start, splice)
+    })
+}
+
+/// The splices undoing arranged `splices`, made to `old`: each one's new
+/// text, where it is once the ones before it have been made, goes back to
+/// what it replaced
+pub fn undone(splices: &[Splice], old: &[char]) -> Vec<Splice> {
+    moved(splices)
+        .map(|(start, splice)| Splice {
+            range: start..start + splice.text.chars().count(),
+            text: old[splice.range.clone()].iter().collect(),
+        })
+        .collect()
+}
+
+/// Where the caret of each of arranged `splices` ends up, after its text
+pub fn carets(splices: &[Splice]) -> Vec<usize> {
+    moved(splices).map(|(start, splice)| start + splice.text.chars().count()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// What making `splices` does to `text`, the way the frontend would
+    fn make(text: &str, splices: &[Splice]) -> String {
+        let mut chars: Vec<char> = text.chars().collect();
+        for splice in splices.iter().rev() {
+            chars.splice(splice.range.clone(), splice.text.chars());
+        }
+        chars.into_iter().collect()
+    }
+
+    #[test]
+    fn splices_go_where_they_were_asked_for_and_undo() {
+        let text = "one two three";
+        let old: Vec<char> = text.chars().collect();
+        let splices = arrange(
+            vec![Splice::new(8..13, "3"), Splice::new(0..3, "uno"), Splice::new(4..7, "deux"), Splice::new(0..0, "> ")],
+            old.len(),
+        )
+        .unwrap();
+        assert_eq!(splices[0], Splice::new(0..0, "> "));
+        let new = make(text, &splices);
+        assert_eq!(new, "> uno deux 3");
+        assert_eq!(carets(&splices), vec![2, 5, 10, 12]);
+        let undo = undone(&splices, &old);
+        assert_eq!(undo[2], Splice::new(6..10, "two"));
+        assert_eq!(make(&new, &undo), text);
+    }
+
+    #[test]
+    fn overlapping_splices_are_refused() {
+        assert!(arrange(vec![Splice::new(2..5, "x"), Splice::new(4..6, "y")], 10).is_err());
+        assert!(arrange(vec![Splice::new(2..5, "x"), Splice::new(3..3, "y")], 10).is_err());
+        assert!(arrange(vec![Splice::new(2..5, "x"), Splice::new(5..5, "y")], 10).is_ok());
+        assert!(arrange(vec![Splice::new(8..11, "x")], 10).is_err());
+    }
+}
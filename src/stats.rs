@@ -0,0 +1,134 @@
+//! Who did what to the document, for View > Statistics.
+//!
+//! The history says which actor made each change but not what the change
+//! did to the text without decoding its ops and working out which object
+//! each belongs to. It's simpler to replay the history one change at a time
+//! into a fresh backend and look at each patch: the sequence diff for the
+//! text says how many characters went in and came out, and the counter's
+//! new value less its old one is how much it was incremented. Every patch
+//! is down to the actor of the change which produced it, which is also a
+//! check on the attribution in blame, which works it out a different way.
+//!
+//! An actor's active times are the runs of its changes with no more than
+//! `SESSION_GAP` between one and the next, by the times the changes were
+//! stamped with.
+
+use automerge_backend::{Backend, Change};
+use automerge_protocol as amp;
+use vgtk::lib::glib;
+
+use crate::{change_log, offset_index, schema};
+
+/// The longest pause between two changes in the same active period, in
+/// seconds
+const SESSION_GAP: i64 = 5 * 60;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ActorStats {
+    pub actor: String,
+    pub changes: usize,
+    pub inserted: usize,
+    pub deleted: usize,
+    pub increments: i64,
+    /// The start and end of each active period, in seconds since the epoch
+    pub active: Vec<(i64, i64)>,
+}
+
+impl ActorStats {
+    fn new(actor: String) -> ActorStats {
+        ActorStats {
+            actor,
+            changes: 0,
+            inserted: 0,
+            deleted: 0,
+            increments: 0,
+            active: Vec::new(),
+        }
+    }
+
+    /// The start of the actor ID, as elsewhere
+    pub fn short_actor(&self) -> String {
+        self.actor.chars().take(change_log::ACTOR_CHARS).collect()
+    }
+
+    fn record_time(&mut self, time: i64) {
+        // Changes from before timestamps were recorded don't say
+        if time == 0 {
+            return;
+        }
+        match self.active.last_mut() {
+            Some((start, end)) if time >= *start && time - *end <= SESSION_GAP => *end = (*end).max(time),
+            _ => self.active.push((time, time)),
+        }
+    }
+
+    /// The active periods for people to read
+    pub fn active_label(&self) -> String {
+        let format = |t: i64, f: &str| {
+            glib::DateTime::new_from_unix_local(t)
+                .format(f)
+                .map(|s| s.to_string())
+                .unwrap_or_default()
+        };
+        if self.active.is_empty() {
+            return "unknown".to_string();
+        }
+        self.active.iter()
+            .map(|(start, end)| format!("{}\u{2013}{}", format(*start, "%Y-%m-%d %H:%M"), format(*end, "%H:%M")))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// Every actor's contributions to the document with `history`, in the order
+/// they first made a change
+pub fn compute(history: &[Change]) -> Vec<ActorStats> {
+    let mut backend = Backend::init();
+    let mut stats: Vec<ActorStats> = Vec::new();
+    let mut counter = 0;
+    for change in history {
+        let actor = change.actor_id().to_string();
+        let time = change.time;
+        let patch = match backend.apply_changes(vec![change.clone()]) {
+            Ok(patch) => patch,
+            Err(e) => {
+                tracing::warn!("statistics stopped at a change which didn't apply: {}", e);
+                break;
+            }
+        };
+        let index = match stats.iter().position(|s| s.actor == actor) {
+            Some(index) => index,
+            None => {
+                stats.push(ActorStats::new(actor));
+                stats.len() - 1
+            }
+        };
+        let actor = &mut stats[index];
+        actor.changes += 1;
+        actor.record_time(time);
+        if let Some(amp::Diff::Seq(seq)) = offset_index::text_diff(&patch) {
+            for edit in &seq.edits {
+                match edit {
+                    amp::DiffEdit::Insert { .. } => actor.inserted += 1,
+                    amp::DiffEdit::Remove { .. } => actor.deleted += 1,
+                }
+            }
+        }
+        if let Some(value) = counter_in_patch(&patch) {
+            actor.increments += value - counter;
+            counter = value;
+        }
+    }
+    stats
+}
+
+/// The counter's value after the patch, if the patch changed it
+fn counter_in_patch(patch: &amp::Patch) -> Option<i64> {
+    match &patch.diffs {
+        Some(amp::Diff::Map(root)) => root.props.get(schema::COUNTER_FIELD)?.values().find_map(|diff| match diff {
+            amp::Diff::Value(amp::Value::Counter(n)) => Some(*n),
+            _ => None,
+        }),
+        _ => None,
+    }
+}
@@ -0,0 +1,187 @@
+//! The statistics window, opened from View > Statistics. A table of what
+//! each actor has contributed to the document, see `stats.rs`, a bar chart
+//! of how many characters each inserted and deleted, and how big the
+//! document has got, see `size.rs`.
+
+use vgtk::ext::*;
+use vgtk::lib::gtk::*;
+use vgtk::lib::glib;
+use vgtk::{gtk, Callback, Component, UpdateAction, VNode};
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+use crate::file::human_size;
+use crate::size::SizeReport;
+use crate::stats::ActorStats;
+
+/// The height of each actor's bars in the chart, and the gap under them
+const BAR_HEIGHT: f64 = 14.0;
+const BAR_GAP: f64 = 10.0;
+/// How much of the chart's width the names get
+const NAME_WIDTH: f64 = 120.0;
+
+#[derive(Default)]
+pub struct StatsView {
+    stats: Rc<Vec<ActorStats>>,
+    size: Rc<SizeReport>,
+    names: BTreeMap<String, String>,
+    on_close: Callback<()>,
+}
+
+#[derive(Debug, Clone)]
+pub enum StatsMessage {
+    Noop,
+    Close,
+}
+
+#[derive(Clone, Default)]
+pub struct StatsViewProperties {
+    /// Every actor's contributions, in the order they first made a change
+    stats: Rc<Vec<ActorStats>>,
+    /// The size of the document and its history
+    size: Rc<SizeReport>,
+    /// The names of the actors we know, by actor ID
+    names: BTreeMap<String, String>,
+    on_close: Callback<()>,
+}
+
+impl StatsView {
+    /// The actor's name if we know it, otherwise the start of its ID
+    fn name(&self, actor: &ActorStats) -> String {
+        self.names.get(&actor.actor).cloned().unwrap_or_else(|| actor.short_actor())
+    }
+
+    fn table_view(&self) -> VNode<StatsView> {
+        let headings = ["Actor", "Changes", "Inserted", "Deleted", "Increments", "Active"];
+        let rows: Vec<[String; 6]> = self.stats.iter().map(|actor| [
+            self.name(actor),
+            actor.changes.to_string(),
+            actor.inserted.to_string(),
+            actor.deleted.to_string(),
+            actor.increments.to_string(),
+            actor.active_label(),
+        ]).collect();
+        let cells = headings.iter().enumerate()
+            .map(|(col, heading)| (0, col, format!("<b>{}</b>", heading)))
+            .chain(rows.into_iter().enumerate().flat_map(|(row, cells)| {
+                cells.to_vec().into_iter().enumerate()
+                    .map(move |(col, cell)| (row + 1, col, glib::markup_escape_text(&cell).to_string()))
+            }))
+            .collect::<Vec<_>>();
+        gtk!{
+            <Grid row_spacing=4 column_spacing=12>
+                {
+                    cells.into_iter().map(|(row, col, markup)| gtk!{
+                        <Label label=markup use_markup=true xalign=0.0 selectable=true
+                            Grid::left_attach=col as i32 Grid::top_attach=row as i32 />
+                    })
+                }
+            </Grid>
+        }
+    }
+
+    /// The size of the document, then its ops by kind
+    fn size_view(&self) -> VNode<StatsView> {
+        let size = &self.size;
+        let bytes = |b: usize| human_size(b as u64);
+        let mut rows = vec![
+            ("Changes".to_string(), size.changes.to_string()),
+            ("Tombstones".to_string(), size.tombstones.to_string()),
+            ("Saved change log".to_string(), bytes(size.log_bytes)),
+            ("Compacted document".to_string(), size.compacted_bytes.map_or_else(|| "unknown".to_string(), bytes)),
+            ("Frontend in memory (at least)".to_string(), bytes(size.frontend_bytes)),
+        ];
+        rows.extend(size.ops.iter().map(|(kind, count)| (format!("Ops: {}", kind), count.to_string())));
+        gtk!{
+            <Grid row_spacing=4 column_spacing=12>
+                {
+                    rows.into_iter().enumerate().flat_map(|(row, (name, value))| vec![
+                        gtk!{ <Label label=name xalign=0.0 Grid::left_attach=0 Grid::top_attach=row as i32 /> },
+                        gtk!{ <Label label=value xalign=1.0 selectable=true Grid::left_attach=1 Grid::top_attach=row as i32 /> },
+                    ])
+                }
+            </Grid>
+        }
+    }
+
+    /// Two bars per actor, characters inserted in green over characters
+    /// deleted in red, on the same scale
+    fn chart_view(&self) -> VNode<StatsView> {
+        let bars: Vec<(String, usize, usize)> = self.stats.iter()
+            .map(|actor| (self.name(actor), actor.inserted, actor.deleted))
+            .collect();
+        let height = (bars.len() as f64 * (2.0 * BAR_HEIGHT + BAR_GAP)) as i32;
+        gtk!{
+            <DrawingArea height_request=height on realize=|area| {
+                let bars = bars.clone();
+                area.connect_draw(move |area, cr| {
+                    let width = area.get_allocated_width() as f64 - NAME_WIDTH;
+                    let most = bars.iter().map(|(_, i, d)| (*i).max(*d)).max().unwrap_or(0).max(1) as f64;
+                    for (n, (name, inserted, deleted)) in bars.iter().enumerate() {
+                        let y = n as f64 * (2.0 * BAR_HEIGHT + BAR_GAP);
+                        cr.set_source_rgb(0.5, 0.5, 0.5);
+                        cr.move_to(0.0, y + BAR_HEIGHT * 1.5);
+                        cr.show_text(name);
+                        cr.set_source_rgb(0.2, 0.82, 0.48);
+                        cr.rectangle(NAME_WIDTH, y, width * *inserted as f64 / most, BAR_HEIGHT);
+                        cr.fill();
+                        cr.set_source_rgb(0.88, 0.11, 0.14);
+                        cr.rectangle(NAME_WIDTH, y + BAR_HEIGHT, width * *deleted as f64 / most, BAR_HEIGHT);
+                        cr.fill();
+                    }
+                    Inhibit(false)
+                });
+                StatsMessage::Noop
+            } />
+        }
+    }
+}
+
+impl Component for StatsView {
+    type Message = StatsMessage;
+    type Properties = StatsViewProperties;
+
+    fn create(properties: Self::Properties) -> Self {
+        StatsView {
+            stats: properties.stats,
+            size: properties.size,
+            names: properties.names,
+            on_close: properties.on_close,
+        }
+    }
+
+    fn change(&mut self, properties: Self::Properties) -> UpdateAction<Self> {
+        self.names = properties.names;
+        self.on_close = properties.on_close;
+        UpdateAction::Render
+    }
+
+    fn update(&mut self, msg: Self::Message) -> UpdateAction<Self> {
+        match msg {
+            StatsMessage::Noop => UpdateAction::None,
+            StatsMessage::Close => {
+                self.on_close.send(());
+                UpdateAction::None
+            }
+        }
+    }
+
+    fn view(&self) -> VNode<Self> {
+        let changes: usize = self.stats.iter().map(|a| a.changes).sum();
+        gtk!{
+            <Window title="Statistics" default_width=700 default_height=400 border_width=12 on destroy=|_| StatsMessage::Close>
+                <Box orientation=Orientation::Vertical spacing=12>
+                    <Label label=format!("{} changes by {} actors", changes, self.stats.len()) xalign=0.0 />
+                    <ScrolledWindow Box::expand=true>
+                        <Box orientation=Orientation::Vertical spacing=12>
+                            {self.table_view()}
+                            {self.chart_view()}
+                            <Label label="<b>Size</b>" use_markup=true xalign=0.0 />
+                            {self.size_view()}
+                        </Box>
+                    </ScrolledWindow>
+                </Box>
+            </Window>
+        }
+    }
+}
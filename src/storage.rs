@@ -0,0 +1,67 @@
+//! `--storage sled:<dir>` or `--storage sqlite:<file>`, keeping documents in
+//! a database rather than in files saved by hand.
+//!
+//! `Storage` is the little an app needs from wherever it keeps automerge
+//! documents: somewhere to append each change as it's made, to load a
+//! document's changes back, to replace them all with the whole history in
+//! one go, and to keep what we know about each peer between runs. The
+//! backend thread appends every change to every document, whether it was
+//! made here or came from another instance, so nothing is lost if the demo
+//! dies. On the way out it saves a snapshot of each document, which throws
+//! away the duplicates appending picks up when a change arrives twice. The
+//! next time the demo starts with the same store it opens every document in
+//! it again, with the same ids, and the first is the one peers sync.
+//!
+//! This version of automerge has no sync protocol, so a peer's "sync state"
+//! is what `ws.rs` keeps for reconnecting, the hashes of every change a
+//! server has sent us. Stored, that survives a restart too, and a server we
+//! come back to is only sent what it hasn't got. It's kept under the shared
+//! document, but covers every document synced over the connection.
+//!
+//! There are two implementations, to show how little there is to it: sled,
+//! an embedded key-value store, and SQLite, see `databases.rs`. They're the
+//! `storage` feature, so without it there's nowhere for `--storage` to open.
+
+use automerge_backend::Change;
+use automerge_protocol as amp;
+use std::io;
+#[cfg(feature = "storage")]
+use std::path::Path;
+
+#[cfg(feature = "storage")]
+use crate::databases::{SledStorage, SqliteStorage};
+use crate::peer::DocumentId;
+
+pub trait Storage: Send {
+    /// Add `changes` to the end of the document's history
+    fn append_changes(&mut self, document: DocumentId, changes: &[Change]) -> io::Result<()>;
+    /// Every change stored for the document, in the order they were stored
+    fn load_document(&mut self, document: DocumentId) -> io::Result<Vec<Change>>;
+    /// Replace everything stored for the document with `changes`, its whole
+    /// history
+    fn save_snapshot(&mut self, document: DocumentId, changes: &[Change]) -> io::Result<()>;
+    /// The hashes of the changes `peer` is known to have, if we've stored
+    /// any for it
+    fn load_sync_state(&mut self, document: DocumentId, peer: &str) -> io::Result<Option<Vec<amp::ChangeHash>>>;
+    fn save_sync_state(&mut self, document: DocumentId, peer: &str, have: &[amp::ChangeHash]) -> io::Result<()>;
+    /// The documents with anything stored, the first one stored first
+    fn documents(&mut self) -> io::Result<Vec<DocumentId>>;
+    /// How many bytes of changes are stored for the document
+    fn size(&mut self, document: DocumentId) -> io::Result<u64>;
+}
+
+/// Open the store described by `--storage`, `sled:<dir>` or
+/// `sqlite:<file>`
+#[cfg(feature = "storage")]
+pub fn open(spec: &str) -> io::Result<Box<dyn Storage>> {
+    match spec.split_once(':') {
+        Some(("sled", path)) => Ok(Box::new(SledStorage::open(Path::new(path))?)),
+        Some(("sqlite", path)) => Ok(Box::new(SqliteStorage::open(Path::new(path))?)),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidInput, "expected sled:<dir> or sqlite:<file>")),
+    }
+}
+
+#[cfg(not(feature = "storage"))]
+pub fn open(_spec: &str) -> io::Result<Box<dyn Storage>> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "the demo has to be built with `--features storage`"))
+}
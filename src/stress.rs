@@ -0,0 +1,68 @@
+//! Simulated typists, started with `--stress <chars-per-sec>`.
+//!
+//! Each tab gets a timer on the GTK main loop which types into its text
+//! buffer at the requested rate. The characters go in through the buffer,
+//! exactly like real keystrokes, so they take the whole per-character path:
+//! signal handler, frontend change, channel, backend, patch back to both
+//! frontends. Typing happens at random positions so the two tabs
+//! constantly make concurrent edits near each other. Watch the metrics panel
+//! (or `--metrics-port`) to see where things start to fall behind.
+
+use std::cell::Cell;
+use std::rc::Rc;
+use vgtk::lib::glib;
+use vgtk::lib::gtk::prelude::*;
+use vgtk::lib::gtk::TextBuffer;
+
+/// How often each typist wakes up. Typists faster than one character per
+/// tick type several characters per tick.
+const TICK_MS: u32 = 10;
+
+pub const PHRASE: &str = "the quick brown fox jumps over the lazy dog ";
+
+/// A tiny xorshift generator, we don't need anything better for picking
+/// where to type
+pub struct Rng(Cell<u64>);
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng(Cell::new(seed.max(1)))
+    }
+
+    /// A number below `bound`
+    pub fn next(&self, bound: usize) -> usize {
+        let mut x = self.0.get();
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.0.set(x);
+        (x % bound.max(1) as u64) as usize
+    }
+}
+
+/// Start typing into `buffer` at `chars_per_sec`. `seed` should differ
+/// between tabs so they don't type in lockstep.
+pub fn start(buffer: TextBuffer, chars_per_sec: f64, seed: u64) -> glib::SourceId {
+    let rng = Rng::new(seed);
+    let per_tick = chars_per_sec * TICK_MS as f64 / 1000.0;
+    // Fractional characters carried over between ticks
+    let owed = Rc::new(Cell::new(0.0));
+    let phrase: Vec<char> = PHRASE.chars().collect();
+    let next_char = Cell::new(0);
+    glib::timeout_add_local(TICK_MS, move || {
+        owed.set(owed.get() + per_tick);
+        while owed.get() >= 1.0 {
+            owed.set(owed.get() - 1.0);
+            // Mostly carry on from the cursor like a person would, but jump
+            // somewhere random every so often
+            if rng.next(20) == 0 {
+                let offset = rng.next(buffer.get_char_count() as usize + 1);
+                buffer.place_cursor(&buffer.get_iter_at_offset(offset as i32));
+            }
+            let c = phrase[next_char.get() % phrase.len()];
+            next_char.set(next_char.get() + 1);
+            buffer.insert_at_cursor(&c.to_string());
+        }
+        glib::Continue(true)
+    })
+}
@@ -0,0 +1,67 @@
+//! Callbacks for when a patch touches part of the document.
+//!
+//! Something interested in part of the document subscribes to the path of
+//! map keys leading to it, like `["counts"]` or `["table", "0"]`, and is
+//! called with the frontend after any patch which changes something at,
+//! under or above that path. Anything which only cares about one part of the
+//! document can then update when that part changes rather than on every
+//! patch.
+//!
+//! The paths a patch touches are read from its diffs. A diff for a map
+//! tells us exactly which keys changed, so I follow those down as far as
+//! they go. Sequences are treated as a whole, a change to any element
+//! touches the path of the sequence itself.
+
+use automerge_frontend::Frontend;
+use automerge_protocol as amp;
+
+pub type Callback = Box<dyn Fn(&Frontend)>;
+
+#[derive(Default)]
+pub struct Subscriptions {
+    subscriptions: Vec<(Vec<String>, Callback)>,
+}
+
+impl Subscriptions {
+    pub fn subscribe(&mut self, path: &[&str], callback: Callback) {
+        self.subscriptions.push((path.iter().map(|k| k.to_string()).collect(), callback));
+    }
+
+    /// Call everyone subscribed to a path in `touched` with the frontend,
+    /// which the patch has already been applied to
+    pub fn notify(&self, touched: &[Vec<String>], frontend: &Frontend) {
+        for (path, callback) in &self.subscriptions {
+            if touched.iter().any(|t| overlaps(t, path)) {
+                callback(frontend);
+            }
+        }
+    }
+}
+
+/// The paths of map keys a patch changes something at
+pub fn touched_paths(patch: &amp::Patch) -> Vec<Vec<String>> {
+    let mut touched = Vec::new();
+    if let Some(diff) = &patch.diffs {
+        walk(diff, &mut Vec::new(), &mut touched);
+    }
+    touched
+}
+
+fn walk(diff: &amp::Diff, prefix: &mut Vec<String>, touched: &mut Vec<Vec<String>>) {
+    if let amp::Diff::Map(map) = diff {
+        for (key, values) in &map.props {
+            prefix.push(key.clone());
+            touched.push(prefix.clone());
+            for value in values.values() {
+                walk(value, prefix, touched);
+            }
+            prefix.pop();
+        }
+    }
+}
+
+/// Whether a change at `touched` affects what's at `path`, i.e. one is a
+/// prefix of the other
+fn overlaps(touched: &[String], path: &[String]) -> bool {
+    touched.iter().zip(path).all(|(a, b)| a == b)
+}
@@ -0,0 +1,68 @@
+//! Suggestion mode, Edit > Suggest Changes: edits which go into a branch of
+//! the document for someone to accept or reject, rather than straight in.
+//!
+//! Automerge makes branching cheap. A fork is just another backend with the
+//! same changes, and merging is applying to one backend the changes the
+//! other has that it doesn't. So when a tab starts suggesting, the backend
+//! thread forks its backend and sends the tab's change requests to the fork
+//! instead, and the tab's frontend follows the fork. Changes from everyone
+//! else still go to both, so the tab keeps up with the document while the
+//! suggestions sit on top of it. Nothing from the fork is saved, journaled
+//! or sent to anyone.
+//!
+//! Accepting merges the fork's own changes into the tab's backend, from
+//! where they go to the store, the other tabs and other instances like any
+//! local edit. The frontend already has them, so it's left as it is.
+//! Rejecting drops the fork and resyncs the frontend from the backend, which
+//! never saw the suggestions. The frontend keeps its actor and so reuses
+//! the seqs of the rejected changes, which is fine as nobody else has them.
+//!
+//! Text the tab has typed since it started suggesting is highlighted: the
+//! characters inserted by our own changes made since then, which the
+//! provenance index and change log know. Deleted text can't be shown, it's
+//! gone from the fork.
+
+use vgtk::lib::gtk::prelude::*;
+use vgtk::lib::gtk::{TextBuffer, TextTag};
+
+use crate::change_log::ChangeLog;
+use crate::offset_index::OffsetIndex;
+
+pub const SUGGESTION_TAG: &str = "suggestion";
+
+/// Create the suggestion highlight tag in the buffer's tag table, which
+/// `theme::apply` colors
+pub fn create_tag(buffer: &TextBuffer) {
+    let tag = TextTag::new(Some(SUGGESTION_TAG));
+    tag.set_property_underline(pango::Underline::Single);
+    buffer.get_tag_table().unwrap().add(&tag);
+}
+
+/// Highlight the characters `own_actor` has inserted in changes after its
+/// change `since`, replacing any previous highlights. `None` clears them.
+pub fn highlight(buffer: &TextBuffer, offsets: &OffsetIndex, log: &ChangeLog, own_actor: &str, since: Option<u64>) {
+    let (start, end) = buffer.get_bounds();
+    buffer.remove_tag_by_name(SUGGESTION_TAG, &start, &end);
+    let since = match since {
+        Some(since) => since,
+        None => return,
+    };
+    let suggested = |offset: usize| {
+        offsets.actor_at(offset) == Some(own_actor)
+            && offsets.op_at(offset).and_then(|op| log.change_for_op(op)).map_or(true, |c| c.seq > since)
+    };
+    let len = buffer.get_char_count().max(0) as usize;
+    let mut run_start = None;
+    for offset in 0..=len {
+        match (run_start, offset < len && suggested(offset)) {
+            (None, true) => run_start = Some(offset),
+            (Some(first), false) => {
+                let start = buffer.get_iter_at_offset(first as i32);
+                let end = buffer.get_iter_at_offset(offset as i32);
+                buffer.apply_tag_by_name(SUGGESTION_TAG, &start, &end);
+                run_start = None;
+            }
+            _ => {}
+        }
+    }
+}
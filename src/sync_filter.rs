@@ -0,0 +1,170 @@
+//! Syncing only part of a document with a peer, `--sync-filter`.
+//!
+//! A filter names the top level keys of the document a peer may see,
+//! `text,title`, or the ones it may not, `!chat`. On its own it applies to
+//! every connection, and `<address>=<keys>` applies it to just the server
+//! at that address, which is how to share the text with one instance but
+//! keep the chat to another. The filters are applied where changes are
+//! routed, in `WsSessions`: a change is only sent to a peer if every op in
+//! it is under a key the peer may see, and a change from a peer is only
+//! applied if it is too.
+//!
+//! Ops on the root object say which key they set, but an op inside the
+//! text or a list only says which object it's in. So `SubtreeIndex` watches
+//! the changes going past for the ops which make objects, and remembers the
+//! top level key each object was made under, which its children inherit.
+//! An op on an object the index hasn't seen made is treated as touching a
+//! key nobody may see, so a filter never lets through something it can't
+//! account for.
+//!
+//! Filtering whole changes runs into how automerge orders them: every
+//! change depends on the heads of the document when it was made, and a
+//! backend won't apply a change until it has all of its dependencies. Once
+//! a change is held back, everything made after it, whatever it touches,
+//! would sit in the other side's queue forever, so the filter holds those
+//! back too rather than sending them for nothing. In practice a filter
+//! works for as long as nobody touches the keys it hides, and the log
+//! says when it has started holding back changes because someone did.
+//! That makes it a way to see why partial replication needs more than
+//! routing, separate documents for separate audiences for instance, rather
+//! than a way to keep secrets.
+
+use automerge_backend::Change;
+use automerge_protocol as amp;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::net::SocketAddr;
+
+/// Which top level keys a peer may see
+#[derive(Clone, Debug, PartialEq)]
+pub enum Filter {
+    Only(BTreeSet<String>),
+    Except(BTreeSet<String>),
+}
+
+impl Filter {
+    /// Parse `key,key`, or `!key,key` for every key but those
+    pub fn parse(spec: &str) -> Result<Filter, String> {
+        let (except, keys) = match spec.strip_prefix('!') {
+            Some(keys) => (true, keys),
+            None => (false, spec),
+        };
+        let keys: BTreeSet<String> = keys.split(',').map(str::trim).filter(|k| !k.is_empty()).map(String::from).collect();
+        if keys.is_empty() {
+            return Err(format!("{} doesn't name any keys", spec));
+        }
+        Ok(if except { Filter::Except(keys) } else { Filter::Only(keys) })
+    }
+
+    fn allows(&self, key: &str) -> bool {
+        match self {
+            Filter::Only(keys) => keys.contains(key),
+            Filter::Except(keys) => !keys.contains(key),
+        }
+    }
+}
+
+/// The filters from every `--sync-filter`
+#[derive(Clone, Debug, Default)]
+pub struct SyncFilters {
+    /// For every peer we don't have a filter of its own for
+    default: Option<Filter>,
+    by_server: HashMap<SocketAddr, Filter>,
+}
+
+impl SyncFilters {
+    /// Add a filter from the command line, `<keys>` or `<address>=<keys>`
+    pub fn add(&mut self, arg: &str) -> Result<(), String> {
+        match arg.split_once('=') {
+            Some((server, spec)) => {
+                let server = server.parse().map_err(|_| format!("{} isn't an address and port", server))?;
+                self.by_server.insert(server, Filter::parse(spec)?);
+            }
+            None => self.default = Some(Filter::parse(arg)?),
+        }
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.default.is_none() && self.by_server.is_empty()
+    }
+
+    /// The filter for the server at `server`, or for a client which
+    /// connected to us if there's no address
+    pub fn for_peer(&self, server: Option<SocketAddr>) -> Option<&Filter> {
+        server.and_then(|s| self.by_server.get(&s)).or_else(|| self.default.as_ref())
+    }
+}
+
+/// The top level keys a change touches
+pub struct Touched {
+    keys: BTreeSet<String>,
+    /// Whether it has ops on objects we don't know the key of
+    unknown: bool,
+}
+
+/// Which top level key each object in the documents is under
+#[derive(Default)]
+pub struct SubtreeIndex {
+    objects: HashMap<amp::ObjectId, String>,
+}
+
+impl SubtreeIndex {
+    /// The keys the change touches, learning the key of each object it
+    /// makes. Changes have to come through in causal order, as they do
+    /// everywhere they're routed.
+    pub fn touched(&mut self, change: &Change) -> Touched {
+        let mut touched = Touched { keys: BTreeSet::new(), unknown: false };
+        let actor = change.actor_id();
+        for (i, op) in change.decode().operations.iter().enumerate() {
+            let key = match (&op.obj, &op.key) {
+                (amp::ObjectId::Root, amp::Key::Map(key)) => Some(key.clone()),
+                (obj, _) => self.objects.get(obj).cloned(),
+            };
+            match key {
+                Some(key) => {
+                    if let amp::OpType::Make(_) = op.action {
+                        let id = amp::OpId(change.start_op + i as u64, actor.clone());
+                        self.objects.insert(amp::ObjectId::Id(id), key.clone());
+                    }
+                    touched.keys.insert(key);
+                }
+                None => touched.unknown = true,
+            }
+        }
+        touched
+    }
+}
+
+/// A filter applied to the changes going one way over one connection
+pub struct Gate {
+    filter: Filter,
+    /// The changes it has held back, which are also why it holds back
+    /// anything depending on them
+    withheld: HashSet<amp::ChangeHash>,
+    /// A name for the peer, for the log
+    peer: String,
+}
+
+impl Gate {
+    pub fn new(filter: Filter, peer: String) -> Gate {
+        Gate { filter, withheld: HashSet::new(), peer }
+    }
+
+    /// Whether the change may go through
+    pub fn admit(&mut self, change: &Change, touched: &Touched) -> bool {
+        let hidden: Vec<&String> = touched.keys.iter().filter(|k| !self.filter.allows(k)).collect();
+        let blocked = change.deps.iter().any(|dep| self.withheld.contains(dep));
+        if hidden.is_empty() && !touched.unknown && !blocked {
+            return true;
+        }
+        if self.withheld.is_empty() {
+            if hidden.is_empty() {
+                tracing::info!("{}: holding back changes to objects the filter can't place", self.peer);
+            } else {
+                tracing::info!("{}: holding back changes from now on, {:?} touched {:?}", self.peer, change.hash, hidden);
+            }
+        }
+        self.withheld.insert(change.hash);
+        false
+    }
+}
@@ -0,0 +1,96 @@
+//! Syntax highlighting, so the demo doubles as a collaborative code editor.
+//!
+//! The text view is a GtkSourceView and the buffer behind it a source
+//! buffer, which highlights the text in the language picked for the tab.
+//! Nothing else about the buffer changes, it's still a text buffer as far
+//! as the CRDT wiring in `Doc` is concerned, with the same signal handlers
+//! turning keystrokes into changes.
+//!
+//! A source buffer works out what to highlight again from the buffer's own
+//! insert and delete notifications, and only rehighlights around them. So
+//! the text is never put in with `set_text`, which would have it highlight
+//! the whole document again: remote patches are replayed as the edits they
+//! make, see `text_patch.rs`, and everything else, a resync or an undo,
+//! replaces just the part of the text which changed.
+//!
+//! The language is a choice made in each tab, rather than something in the
+//! document, as the same text can reasonably be looked at as Rust in one
+//! place and plain text in another.
+//!
+//! GtkSourceView is the `sourceview` feature. Built without it the buffer
+//! is a plain text buffer, there are no languages to pick from and the text
+//! view is a plain text view, see `DocView::editor_view`. This module is the
+//! only place which needs to know which it is.
+
+#[cfg(feature = "sourceview")]
+use sourceview::prelude::*;
+#[cfg(feature = "sourceview")]
+use sourceview::LanguageManager;
+#[cfg(feature = "sourceview")]
+use vgtk::lib::gtk::prelude::*;
+use vgtk::lib::gtk::{TextBuffer, TextTagTable};
+
+/// A buffer for a tab's text
+#[cfg(feature = "sourceview")]
+pub fn buffer() -> TextBuffer {
+    let buffer = sourceview::Buffer::new::<TextTagTable>(None);
+    // Undo is ours, see `undo.rs`, the source buffer's would undo other
+    // people's edits too
+    buffer.set_max_undo_levels(0);
+    buffer.upcast()
+}
+
+#[cfg(not(feature = "sourceview"))]
+pub fn buffer() -> TextBuffer {
+    TextBuffer::new::<TextTagTable>(None)
+}
+
+/// The id and name of every language GtkSourceView knows, by name
+#[cfg(feature = "sourceview")]
+pub fn languages() -> Vec<(String, String)> {
+    let manager = LanguageManager::get_default().unwrap();
+    let mut languages: Vec<(String, String)> = manager
+        .get_language_ids()
+        .into_iter()
+        .filter_map(|id| {
+            let language = manager.get_language(&id)?;
+            let name = language.get_name()?.to_string();
+            Some((id.to_string(), name))
+        })
+        .collect();
+    languages.sort_by(|(_, a), (_, b)| a.to_lowercase().cmp(&b.to_lowercase()));
+    languages
+}
+
+#[cfg(not(feature = "sourceview"))]
+pub fn languages() -> Vec<(String, String)> {
+    Vec::new()
+}
+
+/// The name of the language with id `id`
+#[cfg(feature = "sourceview")]
+pub fn language_name(id: &str) -> Option<String> {
+    LanguageManager::get_default()?.get_language(id)?.get_name().map(|n| n.to_string())
+}
+
+#[cfg(not(feature = "sourceview"))]
+pub fn language_name(_id: &str) -> Option<String> {
+    None
+}
+
+/// Highlight the buffer as language `id`, or not at all
+#[cfg(feature = "sourceview")]
+pub fn set_language(buffer: &TextBuffer, id: Option<&str>) {
+    let buffer = match buffer.downcast_ref::<sourceview::Buffer>() {
+        Some(buffer) => buffer,
+        None => return,
+    };
+    let language = id.and_then(|id| LanguageManager::get_default()?.get_language(id));
+    if id.is_some() && language.is_none() {
+        tracing::warn!("GtkSourceView doesn't know the language {:?}", id);
+    }
+    buffer.set_language(language.as_ref());
+}
+
+#[cfg(not(feature = "sourceview"))]
+pub fn set_language(_buffer: &TextBuffer, _id: Option<&str>) {}
@@ -0,0 +1,138 @@
+//! A small spreadsheet stored as nested maps. The cell at row `r` and column
+//! `c` lives at `root.table[r][c]` (with `r` and `c` as string keys) and the
+//! dimensions of the table are counters at `root.table_rows` and
+//! `root.table_cols`.
+//!
+//! Using counters for the dimensions means that if two windows add a row at
+//! the same time the table grows by two rows rather than one, which is
+//! usually what you want. Edits to different cells never conflict. Concurrent
+//! edits to the same cell conflict and one of them wins, the same one in
+//! every window.
+//!
+//! Row maps are created when a row is added, but two windows adding a row
+//! concurrently both create the map for the same row index, so one of the
+//! rows ends up without a map. We create the missing map the first time a
+//! cell in it is edited, which is itself racy: if two windows do that
+//! concurrently one of the edits is lost. That's a quirk of
+//! modelling a table as nested maps and is worth seeing in action.
+
+use automerge_frontend::{Frontend, LocalChange, Path, Value};
+use automerge_protocol as amp;
+use std::collections::HashMap;
+
+/// The size of a new table
+const INITIAL_SIZE: i64 = 3;
+
+#[derive(Clone, Debug)]
+pub enum TableAction {
+    SetCell { row: usize, col: usize, value: String },
+    AddRow,
+    AddColumn,
+}
+
+impl TableAction {
+    /// The message for the change which makes this action
+    fn message(&self) -> String {
+        match self {
+            TableAction::SetCell { row, col, .. } => format!("Edit cell {},{}", row + 1, col + 1),
+            TableAction::AddRow => "Add a row".to_string(),
+            TableAction::AddColumn => "Add a column".to_string(),
+        }
+    }
+}
+
+/// The contents of the table, as a dense grid of strings
+pub struct Table {
+    pub rows: usize,
+    pub cols: usize,
+    cells: HashMap<(usize, usize), String>,
+}
+
+impl Table {
+    pub fn cell(&self, row: usize, col: usize) -> &str {
+        self.cells.get(&(row, col)).map(|s| s.as_str()).unwrap_or("")
+    }
+}
+
+/// The changes which initialize an empty table
+pub fn initial_changes() -> Vec<LocalChange> {
+    let mut changes = vec![
+        LocalChange::set(Path::root().key("table_rows"), Value::Primitive(amp::Value::Counter(INITIAL_SIZE))),
+        LocalChange::set(Path::root().key("table_cols"), Value::Primitive(amp::Value::Counter(INITIAL_SIZE))),
+    ];
+    let rows = (0..INITIAL_SIZE).map(|r| (r.to_string(), empty_map())).collect();
+    changes.push(LocalChange::set(Path::root().key("table"), Value::Map(rows, amp::MapType::Map)));
+    changes
+}
+
+fn counter(frontend: &Frontend, key: &str) -> usize {
+    match frontend.get_value(&Path::root().key(key)) {
+        Some(Value::Primitive(amp::Value::Counter(i))) => i.max(0) as usize,
+        _ => 0,
+    }
+}
+
+/// Read the table out of the frontend
+pub fn table(frontend: &Frontend) -> Table {
+    let mut cells = HashMap::new();
+    if let Some(Value::Map(rows, _)) = frontend.get_value(&Path::root().key("table")) {
+        for (row, cols) in rows {
+            if let (Ok(row), Value::Map(cols, _)) = (row.parse::<usize>(), cols) {
+                for (col, value) in cols {
+                    if let (Ok(col), Value::Primitive(amp::Value::Str(s))) = (col.parse::<usize>(), value) {
+                        cells.insert((row, col), s);
+                    }
+                }
+            }
+        }
+    }
+    Table {
+        rows: counter(frontend, "table_rows"),
+        cols: counter(frontend, "table_cols"),
+        cells,
+    }
+}
+
+fn row_path(row: usize) -> Path {
+    Path::root().key("table").key(&row.to_string())
+}
+
+fn empty_map() -> Value {
+    Value::Map(HashMap::new(), amp::MapType::Map)
+}
+
+/// Apply a table action to the frontend, returning the change request to
+/// send to the backend
+pub fn apply(frontend: &mut Frontend, action: TableAction) -> Option<amp::Request> {
+    let table = table(frontend);
+    let message = action.message();
+    let changes = match action {
+        TableAction::SetCell { row, col, value } => {
+            if table.cell(row, col) == value {
+                return None;
+            }
+            let value = Value::Primitive(amp::Value::Str(value));
+            match frontend.get_value(&row_path(row)) {
+                Some(Value::Map(_, _)) => vec![LocalChange::set(row_path(row).key(&col.to_string()), value)],
+                _ => {
+                    let mut cols = HashMap::new();
+                    cols.insert(col.to_string(), value);
+                    vec![LocalChange::set(row_path(row), Value::Map(cols, amp::MapType::Map))]
+                }
+            }
+        }
+        TableAction::AddRow => vec![
+            LocalChange::increment(Path::root().key("table_rows")),
+            LocalChange::set(row_path(table.rows), empty_map()),
+        ],
+        TableAction::AddColumn => vec![LocalChange::increment(Path::root().key("table_cols"))],
+    };
+    frontend
+        .change(Some(message), |doc| {
+            for change in &changes {
+                doc.add_change(change.clone())?;
+            }
+            Ok(())
+        })
+        .unwrap()
+}
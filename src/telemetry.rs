@@ -0,0 +1,34 @@
+//! Logging and tracing.
+//!
+//! Everything is instrumented with `tracing`. Log output goes to stderr,
+//! filtered by `RUST_LOG` as before. With `--trace-file <path>` every span
+//! is also written to `path` in the Chrome trace format, which you can load
+//! into `chrome://tracing` or https://ui.perfetto.dev to see where the time
+//! goes between a keystroke and the patch for it arriving back.
+//!
+//! The spans along the way are `keystroke` (the whole signal handler),
+//! `frontend_change`, `channel_send`, `backend_apply` (on the backend
+//! thread) and `apply_patch`. When the patch for one of our own changes
+//! arrives we emit a `change_acknowledged` event recording the latency.
+
+use tracing_chrome::{ChromeLayerBuilder, FlushGuard};
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::EnvFilter;
+
+/// Install the global subscriber. The returned guard must be kept alive
+/// until we exit, dropping it flushes the trace file.
+pub fn init(trace_file: Option<String>) -> Option<FlushGuard> {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("error"));
+    let fmt = tracing_subscriber::fmt::layer().with_filter(filter);
+    match trace_file {
+        Some(path) => {
+            let (chrome, guard) = ChromeLayerBuilder::new().file(path).include_args(true).build();
+            tracing_subscriber::registry().with(fmt).with(chrome).init();
+            Some(guard)
+        }
+        None => {
+            tracing_subscriber::registry().with(fmt).init();
+            None
+        }
+    }
+}
@@ -0,0 +1,137 @@
+//! The text a doc shows, kept as a rope alongside the buffer.
+//!
+//! Everything that looked at the text after a patch, the search, the
+//! preview, the stats and the blame gutter, asked the frontend for it, and
+//! the frontend builds the whole document's value and joins every character
+//! into a `String` each time it's asked, so with a book open a remote
+//! keystroke meant doing that four or five times over. The buffer already
+//! has the text, and changes a keystroke at a time, so the cache follows the
+//! buffer instead: its handlers make every insert and delete the buffer
+//! makes in a rope, including the ones made with the doc's own handlers
+//! blocked, and the text is only joined into a `String` when something asks
+//! for it, once however many ask before it changes again.
+//!
+//! The handlers are connected after the doc's, so an edit the doc stops,
+//! being read only, never reaches the rope either.
+//!
+//! The rope is an `OffsetIndex`'s, see `offset_index.rs`, so what's shown can
+//! be counted in bytes and graphemes as well as chars.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use vgtk::lib::gtk::prelude::*;
+use vgtk::lib::gtk::TextBuffer;
+
+use crate::offset_index::OffsetIndex;
+
+#[derive(Clone)]
+pub struct TextCache {
+    inner: Rc<RefCell<Inner>>,
+}
+
+struct Inner {
+    offsets: OffsetIndex,
+    /// The rope as a string, since it last changed
+    joined: Option<Rc<str>>,
+}
+
+impl Inner {
+    fn edited(&mut self) {
+        self.joined = None;
+    }
+}
+
+impl TextCache {
+    /// Follow `buffer` from what it has now
+    pub fn attach(buffer: &TextBuffer) -> TextCache {
+        let (start, end) = buffer.get_bounds();
+        let text = buffer.get_text(&start, &end, true).map(|t| t.to_string()).unwrap_or_default();
+        let cache = TextCache { inner: Rc::new(RefCell::new(Inner { offsets: OffsetIndex::from_text(&text), joined: None })) };
+        let inserting = cache.inner.clone();
+        buffer.connect_insert_text(move |_, iter, text| {
+            let mut inner = inserting.borrow_mut();
+            inner.offsets.insert(iter.get_offset().max(0) as usize, text);
+            inner.edited();
+        });
+        let deleting = cache.inner.clone();
+        buffer.connect_delete_range(move |_, start, end| {
+            let mut inner = deleting.borrow_mut();
+            let (start, end) = (start.get_offset().max(0) as usize, end.get_offset().max(0) as usize);
+            inner.offsets.remove(start.min(end)..start.max(end));
+            inner.edited();
+        });
+        cache
+    }
+
+    /// The whole text, joined the first time it's asked for after a change
+    pub fn text(&self) -> Rc<str> {
+        let mut inner = self.inner.borrow_mut();
+        if let Some(joined) = &inner.joined {
+            return joined.clone();
+        }
+        let joined: Rc<str> = Rc::from(inner.offsets.text());
+        inner.joined = Some(joined.clone());
+        joined
+    }
+
+    pub fn len_chars(&self) -> usize {
+        self.inner.borrow().offsets.len_chars()
+    }
+
+    /// The char offsets of every non overlapping occurrence of `query`
+    pub fn find(&self, query: &str) -> Vec<usize> {
+        if query.is_empty() {
+            return Vec::new();
+        }
+        let text = self.text();
+        let inner = self.inner.borrow();
+        text.match_indices(query).map(|(byte, _)| inner.offsets.byte_to_char(byte)).collect()
+    }
+
+    /// The start of the grapheme the char at `offset` is part of
+    pub fn snap_to_grapheme(&self, offset: usize) -> usize {
+        self.inner.borrow().offsets.snap_to_grapheme(offset)
+    }
+
+    /// How many whitespace separated words the text has, without joining it
+    pub fn words(&self) -> usize {
+        let inner = self.inner.borrow();
+        let mut words = 0;
+        let mut in_word = false;
+        for c in inner.offsets.chars() {
+            if c.is_whitespace() {
+                in_word = false;
+            } else if !in_word {
+                in_word = true;
+                words += 1;
+            }
+        }
+        words
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use vgtk::lib::gtk::{self, prelude::*, TextBuffer, TextTagTable};
+
+    use super::TextCache;
+
+    #[test]
+    #[ignore = "needs a display, run with `xvfb-run cargo test -- --ignored`"]
+    fn the_cache_follows_the_buffer() {
+        gtk::init().expect("GTK needs a display");
+        let buffer = TextBuffer::new(None::<&TextTagTable>);
+        buffer.set_text("héllo");
+        let cache = TextCache::attach(&buffer);
+        buffer.insert(&mut buffer.get_iter_at_offset(5), " wörld");
+        assert_eq!(&*cache.text(), "héllo wörld");
+        let joined = cache.text();
+        buffer.delete(&mut buffer.get_iter_at_offset(0), &mut buffer.get_iter_at_offset(6));
+        assert_eq!(&*joined, "héllo wörld");
+        assert_eq!(&*cache.text(), "wörld");
+        assert_eq!(cache.len_chars(), 5);
+        assert_eq!(cache.words(), 1);
+        buffer.set_text("añoaño año");
+        assert_eq!(cache.find("año"), vec![0, 3, 7]);
+    }
+}
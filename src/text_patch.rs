@@ -0,0 +1,213 @@
+//! Applying the text part of a remote patch to the text buffer as the edits
+//! it describes, rather than by replacing the whole text.
+//!
+//! Replacing the buffer's text on every remote patch was simple, but it
+//! throws away everything attached to the buffer's text: the cursor jumps to
+//! the end, and every tag has to be applied again from scratch, which for
+//! spelling means checking every word of the document for each character
+//! someone else types. The sequence diff for the text already says exactly
+//! what changed, a list of inserts and removes at indexes into the text as
+//! it is at that point in the list, followed by the values of the inserted
+//! (or updated) elements at their final indexes. So I replay the inserts and
+//! removes on the buffer, with a placeholder for each inserted character,
+//! then fill the placeholders in with those values. The frontend's text is
+//! never needed, joining it is most of the work for a long document. Along
+//! the way I keep track of which offsets in the final text the patch
+//! touched, which is all spellchecking needs to look at again.
+//!
+//! This relies on the edits taking the buffer to the frontend's text, which
+//! they only do while none of our own changes are waiting for the backend.
+//! While some are, the frontend shows them on top of the backend's state,
+//! and what a patch does to what it shows isn't what the patch says. So
+//! then, and if the edits don't add up, or there are more of them than it's
+//! worth replaying, `replace_changed` compares the buffer with the text and
+//! replaces just the part in the middle which differs, which for someone
+//! typing is still only a word or so. `BufferSync` makes that choice for
+//! the doc, for every patch, and remembers when the buffer has to catch up.
+
+use automerge_demo_core::text::changed;
+use automerge_protocol as amp;
+use std::cell::Cell;
+use std::ops::Range;
+use vgtk::lib::gtk::prelude::*;
+use vgtk::lib::gtk::TextBuffer;
+
+use crate::offset_index::{element_text, text_diff, PLACEHOLDER};
+
+/// More edits than this and it's quicker to compare the texts, which is
+/// the case for the patch a document starts with
+const MAX_EDITS: usize = 1000;
+
+enum Edit {
+    Insert(usize),
+    Remove(usize),
+}
+
+/// The edits a patch makes to the text
+pub struct TextEdits {
+    edits: Vec<Edit>,
+    /// The final indexes of the elements the patch sets, and the character
+    /// each is set to, unless it's a conflict or isn't one character
+    set: Vec<(usize, Option<char>)>,
+}
+
+/// The edits the patch makes to the text, if it changes the text at all.
+/// This has to be taken before the patch goes into the frontend.
+pub fn edits(patch: &amp::Patch) -> Option<TextEdits> {
+    match text_diff(patch)? {
+        amp::Diff::Seq(seq) => Some(TextEdits {
+            edits: seq
+                .edits
+                .iter()
+                .map(|edit| match edit {
+                    amp::DiffEdit::Insert { index } => Edit::Insert(*index),
+                    amp::DiffEdit::Remove { index } => Edit::Remove(*index),
+                })
+                .collect(),
+            set: seq.props.iter().map(|(index, values)| (*index, character(values.values()))).collect(),
+        }),
+        // Something which isn't a sequence diff, a new text object say, is
+        // a change to the text we can't replay
+        _ => Some(TextEdits { edits: Vec::new(), set: Vec::new() }),
+    }
+}
+
+/// The character an element of the text is set to, if it has just the one
+/// value and that's one character
+fn character<'a>(mut values: impl ExactSizeIterator<Item = &'a amp::Diff>) -> Option<char> {
+    if values.len() != 1 {
+        return None;
+    }
+    let mut chars = element_text(values.next()?)?.chars();
+    chars.next().filter(|_| chars.next().is_none())
+}
+
+impl TextEdits {
+    /// How many characters the patch inserts or deletes
+    pub fn chars_changed(&self) -> usize {
+        self.edits.len()
+    }
+
+    /// Make the buffer's text the frontend's text after the patch,
+    /// replaying the edits if `replay`, which is only right while none of
+    /// our own changes are waiting for the backend, and replacing what
+    /// changed with `text` otherwise, which is only asked for then. Returns
+    /// the char ranges of the new text which were touched. The buffer's
+    /// signal handlers have to be blocked.
+    pub fn update(&self, buffer: &TextBuffer, text: impl FnOnce() -> Vec<char>, replay: bool) -> Vec<Range<usize>> {
+        let replayed = if replay { self.apply(buffer) } else { None };
+        replayed.unwrap_or_else(|| vec![replace_changed(buffer, &text())])
+    }
+
+    /// Apply the edits to the buffer, returning the char ranges of the new
+    /// text the patch touched, or `None` if they don't fit the buffer, or
+    /// set something we can't show as one character, which leaves the
+    /// buffer to be compared with the text. The buffer's signal handlers
+    /// have to be blocked.
+    pub fn apply(&self, buffer: &TextBuffer) -> Option<Vec<Range<usize>>> {
+        if self.edits.len() > MAX_EDITS || (self.edits.is_empty() && self.set.is_empty()) {
+            return None;
+        }
+        // Where the patch has touched the text, in terms of the text as it
+        // is after the edits so far
+        let mut touched: Vec<usize> = Vec::new();
+        for edit in &self.edits {
+            let len = buffer.get_char_count().max(0) as usize;
+            match *edit {
+                Edit::Insert(index) if index <= len => {
+                    touched.iter_mut().filter(|t| **t >= index).for_each(|t| *t += 1);
+                    touched.push(index);
+                    buffer.insert(&mut buffer.get_iter_at_offset(index as i32), PLACEHOLDER);
+                }
+                Edit::Remove(index) if index < len => {
+                    touched.iter_mut().filter(|t| **t > index).for_each(|t| *t -= 1);
+                    touched.push(index);
+                    buffer.delete(&mut buffer.get_iter_at_offset(index as i32), &mut buffer.get_iter_at_offset(index as i32 + 1));
+                }
+                _ => return None,
+            }
+        }
+        let len = buffer.get_char_count().max(0) as usize;
+        for (index, c) in &self.set {
+            let c = match c {
+                Some(c) if *index < len => c.to_string(),
+                _ => return None,
+            };
+            let mut start = buffer.get_iter_at_offset(*index as i32);
+            let mut end = buffer.get_iter_at_offset(*index as i32 + 1);
+            if buffer.get_text(&start, &end, true).map_or(true, |t| t.as_str() != c) {
+                buffer.delete(&mut start, &mut end);
+                buffer.insert(&mut buffer.get_iter_at_offset(*index as i32), &c);
+            }
+            touched.push(*index);
+        }
+        touched.sort_unstable();
+        touched.dedup();
+        Some(touched.into_iter().map(|t| t.min(len)..(t + 1).min(len)).collect())
+    }
+}
+
+/// Whether the buffer has yet to show a patch, because it arrived while our
+/// own changes were waiting for the backend. The frontend only shows it once
+/// they've all been acknowledged, and the buffer has to catch up then.
+#[derive(Default)]
+pub struct BufferSync {
+    behind: Cell<bool>,
+}
+
+impl BufferSync {
+    /// Bring the buffer up to date with a patch which has just gone into the
+    /// frontend, whose text `text` gives. `edits` are what it does to the
+    /// text, and `acknowledged` says whether it answers one of our own
+    /// requests, with `pending` of them still waiting after it. Our own
+    /// patches don't touch the buffer, which already has what we typed,
+    /// unless they're the last and someone else's arrived in the meantime.
+    /// Returns the char ranges of the text which were touched. The buffer's
+    /// signal handlers have to be blocked.
+    pub fn patched(
+        &self,
+        buffer: &TextBuffer,
+        edits: Option<TextEdits>,
+        acknowledged: bool,
+        pending: usize,
+        text: impl FnOnce() -> Vec<char>,
+    ) -> Vec<Range<usize>> {
+        if acknowledged {
+            if pending == 0 && self.behind.replace(false) {
+                return vec![replace_changed(buffer, &text())];
+            }
+            return Vec::new();
+        }
+        let edits = match edits {
+            Some(edits) => edits,
+            None => return Vec::new(),
+        };
+        if pending > 0 {
+            self.behind.set(true);
+        }
+        edits.update(buffer, text, pending == 0)
+    }
+
+    /// Forget about catching up, after the buffer has been given the whole
+    /// text
+    pub fn reset(&self) {
+        self.behind.set(false);
+    }
+}
+
+/// Make the buffer's text `text` by replacing whatever lies between the
+/// start and the end they have in common, returning the char range of `text`
+/// which was put in. The buffer's signal handlers have to be blocked.
+pub fn replace_changed(buffer: &TextBuffer, text: &[char]) -> Range<usize> {
+    let (start, end) = buffer.get_bounds();
+    let old: Vec<char> = buffer.get_text(&start, &end, true).map(|t| t.chars().collect()).unwrap_or_default();
+    let (removed, put) = changed(&old, text);
+    if !removed.is_empty() {
+        buffer.delete(&mut buffer.get_iter_at_offset(removed.start as i32), &mut buffer.get_iter_at_offset(removed.end as i32));
+    }
+    if !put.is_empty() {
+        let inserted: String = text[put.clone()].iter().collect();
+        buffer.insert(&mut buffer.get_iter_at_offset(put.start as i32), &inserted);
+    }
+    put
+}
@@ -0,0 +1,168 @@
+//! Colors which stay legible whether the theme is light or dark, View > Dark
+//! Mode.
+//!
+//! GTK switches its own colors when it's asked for the dark theme, but not
+//! the ones the demo picks: the highlights in the text and the color each
+//! person is shown in. A pale yellow highlight under the dark theme's pale
+//! text is unreadable, and so is a yellow name on a white background. So
+//! each highlight has a light and a dark version, and a person's color,
+//! which is whatever they chose, is shown lighter or darker as needed while
+//! keeping its hue, so they're still recognisably the same color in either
+//! theme. The color itself isn't changed, other instances are sent what was
+//! chosen and adapt it to their own theme.
+//!
+//! The GtkSourceView style scheme, which colors the syntax highlighting and
+//! the current line, is switched between a light and a dark one too, when
+//! the demo is built with it.
+//!
+//! The font for the text, from the Preferences dialog, is applied in the same
+//! spirit, with a style sheet for every text view rather than a font set on
+//! each one, so the blame gutter keeps lining up with the text.
+
+#[cfg(feature = "sourceview")]
+use sourceview::prelude::*;
+#[cfg(feature = "sourceview")]
+use sourceview::StyleSchemeManager;
+use std::cell::RefCell;
+use vgtk::lib::gdk;
+use vgtk::lib::gtk::prelude::*;
+use vgtk::lib::gtk::{CssProvider, StyleContext, TextBuffer, STYLE_PROVIDER_PRIORITY_APPLICATION};
+
+/// The background of each highlight tag, in the light and the dark theme
+const HIGHLIGHTS: [(&str, &str, &str); 2] = [
+    (crate::find::MATCH_TAG, "#fce94f", "#6b5d00"),
+    (crate::suggestion::SUGGESTION_TAG, "#c8f0c8", "#24502a"),
+];
+
+/// The style schemes for the light and the dark theme
+#[cfg(feature = "sourceview")]
+const LIGHT_SCHEME: &str = "classic";
+#[cfg(feature = "sourceview")]
+const DARK_SCHEME: &str = "oblivion";
+
+/// Text colors need to be at least this light on a dark background, and at
+/// most this light on a light one
+const MIN_LIGHTNESS_ON_DARK: f64 = 0.65;
+const MAX_LIGHTNESS_ON_LIGHT: f64 = 0.45;
+
+/// Color the buffer's highlights and syntax for the theme
+pub fn apply(buffer: &TextBuffer, dark: bool) {
+    let table = buffer.get_tag_table().unwrap();
+    for (name, light_color, dark_color) in HIGHLIGHTS.iter() {
+        if let Some(tag) = table.lookup(name) {
+            tag.set_property_background(Some(if dark { dark_color } else { light_color }));
+        }
+    }
+    #[cfg(feature = "sourceview")]
+    if let Some(buffer) = buffer.downcast_ref::<sourceview::Buffer>() {
+        let scheme = StyleSchemeManager::get_default().and_then(|m| m.get_scheme(if dark { DARK_SCHEME } else { LIGHT_SCHEME }));
+        buffer.set_style_scheme(scheme.as_ref());
+    }
+}
+
+thread_local! {
+    /// The style sheet with the text's font, once one has been set
+    static FONT_CSS: RefCell<Option<CssProvider>> = RefCell::new(None);
+}
+
+/// Show the text in the font a Pango font description like "Monospace 12"
+/// describes, or the theme's font if `None`
+pub fn set_font(font: Option<&str>) {
+    let css = match font {
+        Some(font) => font_css(&pango::FontDescription::from_string(font)),
+        None => String::new(),
+    };
+    FONT_CSS.with(|provider| {
+        let mut provider = provider.borrow_mut();
+        if provider.is_none() {
+            let screen = match gdk::Screen::get_default() {
+                Some(screen) => screen,
+                None => return,
+            };
+            let css = CssProvider::new();
+            StyleContext::add_provider_for_screen(&screen, &css, STYLE_PROVIDER_PRIORITY_APPLICATION);
+            *provider = Some(css);
+        }
+        if let Err(e) = provider.as_ref().unwrap().load_from_data(css.as_bytes()) {
+            tracing::warn!("Could not use the font {:?}: {}", font, e);
+        }
+    });
+}
+
+/// A style sheet rule giving text views the font
+fn font_css(font: &pango::FontDescription) -> String {
+    let mut rules = Vec::new();
+    if let Some(family) = font.get_family() {
+        rules.push(format!("font-family: \"{}\";", family.replace('"', "")));
+    }
+    if font.get_size() > 0 {
+        rules.push(format!("font-size: {}pt;", font.get_size() / pango::SCALE));
+    }
+    match font.get_style() {
+        pango::Style::Italic => rules.push("font-style: italic;".to_string()),
+        pango::Style::Oblique => rules.push("font-style: oblique;".to_string()),
+        _ => {}
+    }
+    let weight = match font.get_weight() {
+        pango::Weight::Thin | pango::Weight::Ultralight | pango::Weight::Light | pango::Weight::Semilight => "300",
+        pango::Weight::Book | pango::Weight::Normal | pango::Weight::Medium => "400",
+        _ => "700",
+    };
+    rules.push(format!("font-weight: {};", weight));
+    format!("textview {{ {} }}", rules.join(" "))
+}
+
+/// `color` as a color for text which can be read in the theme, the same
+/// hue made lighter or darker if it has to be. Colors GDK can't parse are
+/// passed through.
+pub fn legible(color: &str, dark: bool) -> String {
+    let rgba = match color.parse::<gdk::RGBA>() {
+        Ok(rgba) => rgba,
+        Err(_) => return color.to_string(),
+    };
+    let (h, s, l) = to_hsl(rgba.red, rgba.green, rgba.blue);
+    let l = if dark { l.max(MIN_LIGHTNESS_ON_DARK) } else { l.min(MAX_LIGHTNESS_ON_LIGHT) };
+    let (r, g, b) = from_hsl(h, s, l);
+    let byte = |c: f64| (c * 255.0).round() as u8;
+    format!("#{:02x}{:02x}{:02x}", byte(r), byte(g), byte(b))
+}
+
+fn to_hsl(r: f64, g: f64, b: f64) -> (f64, f64, f64) {
+    let max = r.max(g).max(b);
+    let min = r.min(g).min(b);
+    let l = (max + min) / 2.0;
+    if max == min {
+        return (0.0, 0.0, l);
+    }
+    let d = max - min;
+    let s = if l > 0.5 { d / (2.0 - max - min) } else { d / (max + min) };
+    let h = if max == r {
+        (g - b) / d + if g < b { 6.0 } else { 0.0 }
+    } else if max == g {
+        (b - r) / d + 2.0
+    } else {
+        (r - g) / d + 4.0
+    };
+    (h / 6.0, s, l)
+}
+
+fn from_hsl(h: f64, s: f64, l: f64) -> (f64, f64, f64) {
+    if s == 0.0 {
+        return (l, l, l);
+    }
+    let q = if l < 0.5 { l * (1.0 + s) } else { l + s - l * s };
+    let p = 2.0 * l - q;
+    let channel = |t: f64| {
+        let t = if t < 0.0 { t + 1.0 } else if t > 1.0 { t - 1.0 } else { t };
+        if t < 1.0 / 6.0 {
+            p + (q - p) * 6.0 * t
+        } else if t < 0.5 {
+            q
+        } else if t < 2.0 / 3.0 {
+            p + (q - p) * (2.0 / 3.0 - t) * 6.0
+        } else {
+            p
+        }
+    };
+    (channel(h + 1.0 / 3.0), channel(h), channel(h - 1.0 / 3.0))
+}
@@ -0,0 +1,54 @@
+//! The document title, stored at `root.title`.
+//!
+//! If two windows rename the document concurrently automerge picks one of the
+//! values as the winner (the same one everywhere) but keeps the others around
+//! as conflicts. The frontend only gives us the winner, but the patch which
+//! introduced the conflict contains every value, so we pull the losing values
+//! out of the patch and show them next to the title. Setting the title again
+//! resolves the conflict.
+
+use automerge_frontend::{Frontend, LocalChange, Path, Value};
+use automerge_protocol as amp;
+
+pub const DEFAULT_TITLE: &str = "Untitled";
+
+pub fn initial_value() -> Value {
+    Value::Primitive(amp::Value::Str(DEFAULT_TITLE.to_string()))
+}
+
+/// The current (winning) title
+pub fn title(frontend: &Frontend) -> String {
+    match frontend.get_value(&Path::root().key("title")) {
+        Some(Value::Primitive(amp::Value::Str(s))) => s,
+        _ => DEFAULT_TITLE.to_string(),
+    }
+}
+
+pub fn set(frontend: &mut Frontend, title: String) -> Option<amp::Request> {
+    frontend
+        .change(Some(format!("Set the title to \"{}\"", title)), |doc| {
+            doc.add_change(LocalChange::set(
+                Path::root().key("title"),
+                Value::Primitive(amp::Value::Str(title.clone())),
+            ))?;
+            Ok(())
+        })
+        .unwrap()
+}
+
+/// If the patch touches the title, every value the title now has (more than
+/// one if there is a conflict). Returns `None` if the title wasn't changed.
+pub fn values_in_patch(patch: &amp::Patch) -> Option<Vec<String>> {
+    match &patch.diffs {
+        Some(amp::Diff::Map(root)) => root.props.get("title").map(|values| {
+            values
+                .values()
+                .filter_map(|diff| match diff {
+                    amp::Diff::Value(amp::Value::Str(s)) => Some(s.clone()),
+                    _ => None,
+                })
+                .collect()
+        }),
+        _ => None,
+    }
+}
@@ -0,0 +1,207 @@
+//! TLS for the websocket connections between instances, with rustls.
+//!
+//! `--listen-tls <port>` runs the websocket server like `--serve-ws` but
+//! over TLS, with the certificate and key given by `--tls-cert` and
+//! `--tls-key`. Instances running one advertise that over mDNS, so
+//! connecting to them from the peers popover uses TLS too, and
+//! `--connect wss://<addr>` connects to one at startup.
+//!
+//! Instances of the demo don't have certificates signed by anyone we could
+//! check them against, a self-signed one made with openssl is the expected
+//! case, so instead of a chain of trust the certificate itself is what's
+//! trusted, identified by its SHA-256 fingerprint. `--pin <fingerprint>`
+//! says which certificate the `--connect` server has to have. Otherwise it
+//! works like ssh: the first time we connect to a server the user is asked
+//! whether they trust its fingerprint, and if they do it's remembered in
+//! `known_peers` in the config directory. From then on that server is only
+//! trusted with the same certificate, a different one is refused without
+//! asking, as that's what someone in the middle would present.
+//!
+//! The check happens in rustls' certificate verifier, on the thread making
+//! the connection, which asks the UI by sending a `WsEvent::Verify` and
+//! waiting for the answer. The server still has to prove it holds the
+//! certificate's key in the handshake, rustls checks that as normal.
+
+use ring::digest;
+use rustls::{Certificate, ClientConfig, RootCertStore, ServerCertVerified, ServerCertVerifier, ServerConfig, TLSError};
+use std::io::{self, BufReader};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use vgtk::lib::gtk::prelude::*;
+use vgtk::lib::gtk::{ButtonsType, DialogFlags, MessageDialog, MessageType, ResponseType, Window};
+
+use crate::file;
+use crate::ws::WsEvent;
+
+/// The name we ask for in the handshake. The servers don't look at it and
+/// we don't check certificates against it, but rustls needs one.
+pub const SERVER_NAME: &str = "automerge-demo";
+
+/// How to decide whether to trust the server we're connecting to
+#[derive(Clone, Debug, PartialEq)]
+pub enum Trust {
+    /// Only a certificate with this fingerprint
+    Pinned(String),
+    /// Whatever the user accepted the first time, see the module docs
+    OnFirstUse,
+}
+
+/// Load the server's certificate chain and private key from PEM files
+pub fn server_config(cert: &Path, key: &Path) -> io::Result<Arc<ServerConfig>> {
+    let certs = rustls::internal::pemfile::certs(&mut BufReader::new(std::fs::File::open(cert)?))
+        .map_err(|_| invalid(format!("{} isn't a PEM certificate", cert.display())))?;
+    if certs.is_empty() {
+        return Err(invalid(format!("there's no certificate in {}", cert.display())));
+    }
+    let read_keys = |parse: fn(&mut dyn io::BufRead) -> Result<Vec<rustls::PrivateKey>, ()>| -> io::Result<_> {
+        parse(&mut BufReader::new(std::fs::File::open(key)?)).map_err(|_| invalid(format!("{} isn't a PEM key", key.display())))
+    };
+    let mut keys = read_keys(rustls::internal::pemfile::pkcs8_private_keys)?;
+    if keys.is_empty() {
+        keys = read_keys(rustls::internal::pemfile::rsa_private_keys)?;
+    }
+    let key = keys.into_iter().next().ok_or_else(|| invalid(format!("there's no private key in {}", key.display())))?;
+    tracing::info!("serving websockets with the certificate {}", fingerprint(&certs[0]));
+    let mut config = ServerConfig::new(rustls::NoClientAuth::new());
+    config.set_single_cert(certs, key).map_err(|e| invalid(e.to_string()))?;
+    Ok(Arc::new(config))
+}
+
+/// The config for one connection to `server`. The returned flag is set if
+/// the connection fails because the server's certificate wasn't trusted,
+/// which trying again won't fix.
+pub fn client_config(server: SocketAddr, trust: Trust, events: crossbeam::Sender<WsEvent>) -> (Arc<ClientConfig>, Arc<AtomicBool>) {
+    let rejected = Arc::new(AtomicBool::new(false));
+    let mut config = ClientConfig::new();
+    config.dangerous().set_certificate_verifier(Arc::new(Verifier{server, trust, events, rejected: rejected.clone()}));
+    (Arc::new(config), rejected)
+}
+
+/// The SHA-256 fingerprint of a certificate, in the `AB:CD:..` form openssl
+/// prints with `openssl x509 -noout -fingerprint -sha256`
+pub fn fingerprint(cert: &Certificate) -> String {
+    digest::digest(&digest::SHA256, &cert.0)
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// Whether two fingerprints are the same, ignoring case and separators
+fn same_fingerprint(a: &str, b: &str) -> bool {
+    let normalize = |s: &str| s.chars().filter(|c| c.is_ascii_hexdigit()).collect::<String>().to_ascii_uppercase();
+    normalize(a) == normalize(b)
+}
+
+struct Verifier {
+    server: SocketAddr,
+    trust: Trust,
+    events: crossbeam::Sender<WsEvent>,
+    rejected: Arc<AtomicBool>,
+}
+
+impl Verifier {
+    fn trusted(&self, fingerprint: &str) -> bool {
+        match &self.trust {
+            Trust::Pinned(pin) => {
+                let trusted = same_fingerprint(pin, fingerprint);
+                if !trusted {
+                    tracing::warn!("{} presented {}, not the pinned certificate", self.server, fingerprint);
+                }
+                trusted
+            }
+            Trust::OnFirstUse => match known_fingerprint(self.server) {
+                Some(known) => {
+                    let trusted = same_fingerprint(&known, fingerprint);
+                    if !trusted {
+                        tracing::warn!("the certificate of {} has changed from {} to {}, refusing it", self.server, known, fingerprint);
+                    }
+                    trusted
+                }
+                None => {
+                    let (reply, answer) = crossbeam::channel::bounded(1);
+                    let asked = self.events.send(WsEvent::Verify{server: self.server, fingerprint: fingerprint.to_string(), reply});
+                    // No answer means the UI has gone
+                    let trusted = asked.is_ok() && answer.recv().unwrap_or(false);
+                    if trusted {
+                        if let Err(e) = remember(self.server, fingerprint) {
+                            tracing::warn!("could not remember the certificate of {}: {}", self.server, e);
+                        }
+                    }
+                    trusted
+                }
+            },
+        }
+    }
+}
+
+impl ServerCertVerifier for Verifier {
+    fn verify_server_cert(
+        &self,
+        _roots: &RootCertStore,
+        presented_certs: &[Certificate],
+        _dns_name: webpki::DNSNameRef,
+        _ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        let cert = presented_certs.first().ok_or(TLSError::NoCertificatesPresented)?;
+        let fingerprint = fingerprint(cert);
+        if self.trusted(&fingerprint) {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            self.rejected.store(true, Ordering::SeqCst);
+            Err(TLSError::General(format!("the certificate {} isn't trusted", fingerprint)))
+        }
+    }
+}
+
+/// Ask the user whether to trust a server's certificate
+pub fn ask(server: SocketAddr, fingerprint: &str) -> bool {
+    let dialog = MessageDialog::new(
+        None::<&Window>,
+        DialogFlags::MODAL,
+        MessageType::Question,
+        ButtonsType::YesNo,
+        &format!(
+            "The certificate of {} hasn't been seen before. Its SHA-256 fingerprint is\n\n{}\n\nTrust it and connect?",
+            server, fingerprint
+        ),
+    );
+    let response = dialog.run();
+    dialog.destroy();
+    response == ResponseType::Yes
+}
+
+/// Where the fingerprints of servers the user has trusted are kept, one
+/// `<address> <fingerprint>` per line
+fn known_peers_path() -> Option<PathBuf> {
+    file::config_path("known_peers")
+}
+
+fn known_fingerprint(server: SocketAddr) -> Option<String> {
+    let known = std::fs::read_to_string(known_peers_path()?).ok()?;
+    let server = server.to_string();
+    known.lines().find_map(|line| {
+        let mut parts = line.split_whitespace();
+        match (parts.next(), parts.next()) {
+            (Some(addr), Some(fingerprint)) if addr == server => Some(fingerprint.to_string()),
+            _ => None,
+        }
+    })
+}
+
+fn remember(server: SocketAddr, fingerprint: &str) -> io::Result<()> {
+    use std::io::Write;
+    let path = known_peers_path().ok_or_else(|| invalid("there's no config directory".to_string()))?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{} {}", server, fingerprint)
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
@@ -0,0 +1,133 @@
+//! What the backend thread needs from whatever carries changes to and from
+//! other instances and clients.
+//!
+//! The backend thread doesn't know about sockets. It hands every change it
+//! applies to `Transport::send_changes`, lets `Transport::recv_changes`
+//! decide which of the changes a peer sent it should apply, and selects on
+//! `Transport::peer_events` alongside the docs' channels to find out what
+//! the peers are up to. It can have any number of transports; changes go
+//! out over all of them, and changes which come in over one go out over the
+//! others, so a peer on one can edit with a peer on another.
+//!
+//! `Channels` is the one we have. Everything network facing, the websocket
+//! server and the connections we make (`ws.rs`), WebRTC calls (`webrtc.rs`)
+//! and libp2p (`p2p.rs`), is a thread of its own at the other end of a
+//! channel of `WsEvent`s, which tells the backend thread about each peer
+//! along with the channel of bytes to send it, see `ws::WsSessions`. So each
+//! of those is started by its own flag and plugs in without the backend
+//! thread knowing which it is. Something which doesn't fit that, a transport
+//! with its own idea of a session, say, can implement `Transport` directly
+//! and be added to the list the backend thread is started with.
+
+use automerge_backend::Change;
+use automerge_protocol as amp;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+
+use crate::peer::DocumentId;
+use crate::presence::PresenceState;
+use crate::signing::Signature;
+use crate::sync_filter::SyncFilters;
+use crate::ws::{WsEvent, WsSessions};
+
+pub trait Transport: Send {
+    /// Send changes the backend has applied to `document`, or to the shared
+    /// document if it's `None`, to every peer which should have them
+    fn send_changes(&mut self, document: Option<DocumentId>, changes: &[Change]);
+
+    /// Take in changes `server` sent us, or one of the clients which
+    /// connected to us if it's `None`, returning the ones to apply
+    fn recv_changes(&mut self, server: Option<SocketAddr>, changes: Vec<Change>) -> Vec<Change>;
+
+    /// Where peers connecting, sending changes and everything else arrive
+    fn peer_events(&self) -> &crossbeam::Receiver<WsEvent>;
+
+    /// A peer has connected, and is to be sent `history`, the shared
+    /// document's, and the changes to it from now on
+    fn add_peer(&mut self, client: crossbeam::Sender<Vec<u8>>, server: Option<SocketAddr>, history: &[Change]);
+
+    /// A peer has asked for every other document too, `histories` is what
+    /// they have so far
+    fn add_document_peer(&mut self, _client: crossbeam::Sender<Vec<u8>>, _server: Option<SocketAddr>, _histories: &[(DocumentId, Vec<Change>)]) {}
+
+    /// Pass on what a tab is doing, returning false if it's old news
+    fn share_presence(&mut self, _state: &PresenceState) -> bool {
+        true
+    }
+
+    fn share_signatures(&mut self, _signatures: &[(amp::ChangeHash, Signature)]) {}
+
+    /// Whether `server` has sent us anything before, in this run or, once
+    /// `restore_seen` has been told, an earlier one
+    fn knows(&self, _server: SocketAddr) -> bool {
+        false
+    }
+
+    fn restore_seen(&mut self, _server: SocketAddr, _hashes: Vec<amp::ChangeHash>) {}
+
+    /// What each server has sent us, to keep for next time
+    fn seen(&self) -> Vec<(SocketAddr, &HashSet<amp::ChangeHash>)> {
+        Vec::new()
+    }
+}
+
+/// Every peer at the other end of a channel of `WsEvent`s
+pub struct Channels {
+    events: crossbeam::Receiver<WsEvent>,
+    sessions: WsSessions,
+}
+
+impl Channels {
+    /// `events` is what the threads talking to peers send, `filters` what
+    /// each peer may see, from `--sync-filter`
+    pub fn new(events: crossbeam::Receiver<WsEvent>, filters: SyncFilters) -> Channels {
+        Channels { events, sessions: WsSessions::new(filters) }
+    }
+}
+
+impl Transport for Channels {
+    fn send_changes(&mut self, document: Option<DocumentId>, changes: &[Change]) {
+        match document {
+            None => self.sessions.broadcast(changes),
+            Some(id) => self.sessions.broadcast_document(id, changes),
+        }
+    }
+
+    fn recv_changes(&mut self, server: Option<SocketAddr>, changes: Vec<Change>) -> Vec<Change> {
+        self.sessions.received(server, &changes);
+        let admitted = self.sessions.admit(server, changes);
+        self.sessions.new_to_us(admitted)
+    }
+
+    fn peer_events(&self) -> &crossbeam::Receiver<WsEvent> {
+        &self.events
+    }
+
+    fn add_peer(&mut self, client: crossbeam::Sender<Vec<u8>>, server: Option<SocketAddr>, history: &[Change]) {
+        self.sessions.add_client(client, server, history);
+    }
+
+    fn add_document_peer(&mut self, client: crossbeam::Sender<Vec<u8>>, server: Option<SocketAddr>, histories: &[(DocumentId, Vec<Change>)]) {
+        self.sessions.add_document_client(client, server, histories);
+    }
+
+    fn share_presence(&mut self, state: &PresenceState) -> bool {
+        self.sessions.share_presence(state)
+    }
+
+    fn share_signatures(&mut self, signatures: &[(amp::ChangeHash, Signature)]) {
+        self.sessions.share_signatures(signatures);
+    }
+
+    fn knows(&self, server: SocketAddr) -> bool {
+        self.sessions.knows(server)
+    }
+
+    fn restore_seen(&mut self, server: SocketAddr, hashes: Vec<amp::ChangeHash>) {
+        self.sessions.restore_seen(server, hashes);
+    }
+
+    fn seen(&self) -> Vec<(SocketAddr, &HashSet<amp::ChangeHash>)> {
+        self.sessions.seen().collect()
+    }
+}
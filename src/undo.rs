@@ -0,0 +1,219 @@
+//! Undo and redo of local edits.
+//!
+//! Undo in a collaborative document should only undo your own changes, not
+//! whatever somebody else typed in the meantime. We do that by recording, for
+//! every local edit, the changes which reverse it. Undoing makes those
+//! changes as a brand new change, so it syncs to other windows like any
+//! other edit and can itself be undone.
+//!
+//! Entries store plain indexes, so if a remote edit lands between an edit and
+//! its undo the undo applies at the old position. Only edits to the text
+//! (including replacements from the find bar and other splices, see
+//! `splice.rs`) and the title are recorded.
+//!
+//! View > Undo History lists the entries with what each did and when, with
+//! the changes other people made in between, which undo leaves alone.
+//! Undoing several entries at once from there makes one change, with the
+//! reversing changes of each entry in turn from the newest.
+
+use automerge_frontend::{LocalChange, Path, Value};
+use automerge_protocol as amp;
+use std::time::{SystemTime, UNIX_EPOCH};
+use vgtk::lib::glib;
+
+use crate::splice::{self, Splice};
+
+/// How much of the text an edit typed or deleted to show in its label
+const LABEL_CHARS: usize = 24;
+
+/// One undoable local edit
+#[derive(Clone)]
+pub struct UndoEntry {
+    /// The changes which reverse the edit
+    pub undo: Vec<LocalChange>,
+    /// The changes which make the edit again
+    pub redo: Vec<LocalChange>,
+    /// What the edit did, for the undo history
+    pub label: String,
+    /// When it was made, in seconds since the epoch
+    pub time: i64,
+}
+
+impl UndoEntry {
+    fn new(undo: Vec<LocalChange>, redo: Vec<LocalChange>, label: String) -> UndoEntry {
+        UndoEntry {
+            undo,
+            redo,
+            label,
+            time: SystemTime::now().duration_since(UNIX_EPOCH).map(|t| t.as_secs() as i64).unwrap_or(0),
+        }
+    }
+}
+
+/// Text from an edit short enough for a label, on one line
+fn quote(text: &str) -> String {
+    let mut quoted: String = text.chars().take(LABEL_CHARS).map(|c| if c == '\n' { '\u{21b5}' } else { c }).collect();
+    if text.chars().count() > LABEL_CHARS {
+        quoted.push('\u{2026}');
+    }
+    format!("\u{201c}{}\u{201d}", quoted)
+}
+
+#[derive(Default)]
+pub struct UndoStack {
+    undo: Vec<UndoEntry>,
+    redo: Vec<UndoEntry>,
+}
+
+impl UndoStack {
+    /// Record a new local edit, which forgets anything that could be redone
+    pub fn record(&mut self, entry: UndoEntry) {
+        self.undo.push(entry);
+        self.redo.clear();
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+
+    /// The changes to make to undo the most recent edit
+    pub fn undo(&mut self) -> Option<Vec<LocalChange>> {
+        let entry = self.undo.pop()?;
+        let changes = entry.undo.clone();
+        self.redo.push(entry);
+        Some(changes)
+    }
+
+    /// The changes to make to redo the most recently undone edit
+    pub fn redo(&mut self) -> Option<Vec<LocalChange>> {
+        let entry = self.redo.pop()?;
+        let changes = entry.redo.clone();
+        self.undo.push(entry);
+        Some(changes)
+    }
+
+    /// The changes to make to undo the `steps` most recent edits, one
+    /// after the other
+    pub fn undo_steps(&mut self, steps: usize) -> Option<Vec<LocalChange>> {
+        let changes: Vec<LocalChange> = (0..steps).filter_map(|_| self.undo()).flatten().collect();
+        Some(changes).filter(|c| !c.is_empty())
+    }
+
+    /// The changes to make to redo the `steps` most recently undone edits
+    pub fn redo_steps(&mut self, steps: usize) -> Option<Vec<LocalChange>> {
+        let changes: Vec<LocalChange> = (0..steps).filter_map(|_| self.redo()).flatten().collect();
+        Some(changes).filter(|c| !c.is_empty())
+    }
+
+    /// The edits which can be undone, most recent first
+    pub fn undoable(&self) -> impl Iterator<Item = &UndoEntry> {
+        self.undo.iter().rev()
+    }
+
+    /// The edits which can be redone, the next one to redo first
+    pub fn redoable(&self) -> impl Iterator<Item = &UndoEntry> {
+        self.redo.iter().rev()
+    }
+}
+
+/// A row of the undo history
+#[derive(Clone, Debug, PartialEq)]
+pub enum HistoryRow {
+    /// A local edit, undone by undoing `steps` times
+    Undoable{label: String, time: i64, steps: usize},
+    /// A local edit which has been undone, redone by redoing `steps` times
+    Undone{label: String, time: i64, steps: usize},
+    /// Someone else's change, which undo leaves alone
+    Remote{description: String, time: i64},
+}
+
+impl HistoryRow {
+    fn time(&self) -> i64 {
+        match self {
+            HistoryRow::Undoable{time, ..} | HistoryRow::Undone{time, ..} | HistoryRow::Remote{time, ..} => *time,
+        }
+    }
+
+    /// When the change was made, in local time
+    pub fn time_label(&self) -> String {
+        glib::DateTime::new_from_unix_local(self.time())
+            .format("%H:%M:%S")
+            .map(|s| s.to_string())
+            .unwrap_or_default()
+    }
+}
+
+/// The rows of the undo history, newest first: what can be redone, then
+/// what can be undone, with the `remote` changes, a description and the
+/// time of each, made since the oldest of them
+pub fn history(stack: &UndoStack, remote: Vec<(String, i64)>) -> Vec<HistoryRow> {
+    let undone: Vec<HistoryRow> = stack
+        .redoable()
+        .enumerate()
+        .map(|(i, e)| HistoryRow::Undone{label: e.label.clone(), time: e.time, steps: i + 1})
+        .collect();
+    let mut rows: Vec<HistoryRow> = stack
+        .undoable()
+        .enumerate()
+        .map(|(i, e)| HistoryRow::Undoable{label: e.label.clone(), time: e.time, steps: i + 1})
+        .collect();
+    let oldest = stack.undoable().chain(stack.redoable()).map(|e| e.time).min();
+    if let Some(oldest) = oldest {
+        rows.extend(
+            remote
+                .into_iter()
+                .filter(|(_, time)| *time >= oldest)
+                .map(|(description, time)| HistoryRow::Remote{description, time}),
+        );
+    }
+    // Stable, so our own edits stay in order and come before remote
+    // changes made in the same second
+    rows.sort_by_key(|row| std::cmp::Reverse(row.time()));
+    undone.into_iter().rev().chain(rows).collect()
+}
+
+fn text_path(index: usize) -> Path {
+    Path::root().key("text").index(index)
+}
+
+/// The changes inserting `text` at character offset `pos`, one element per
+/// character
+fn insert_chars(pos: usize, text: &str) -> Vec<LocalChange> {
+    text.chars()
+        .enumerate()
+        .map(|(i, c)| LocalChange::insert(text_path(pos + i), Value::Primitive(amp::Value::Str(c.to_string()))))
+        .collect()
+}
+
+fn delete_chars(pos: usize, text: &str) -> Vec<LocalChange> {
+    // Each delete shifts the rest of the text left, so we delete at the same
+    // index over and over
+    text.chars().map(|_| LocalChange::delete(text_path(pos))).collect()
+}
+
+/// `text` was inserted at character offset `pos`
+pub fn text_inserted(pos: usize, text: &str) -> UndoEntry {
+    UndoEntry::new(delete_chars(pos, text), insert_chars(pos, text), format!("Type {}", quote(text)))
+}
+
+/// `text` was deleted from character offset `pos`
+pub fn text_deleted(pos: usize, text: &str) -> UndoEntry {
+    UndoEntry::new(insert_chars(pos, text), delete_chars(pos, text), format!("Delete {}", quote(text)))
+}
+
+/// `splices`, arranged, were made to the text `old` as one edit
+pub fn text_spliced(splices: &[Splice], old: &[char], label: String) -> UndoEntry {
+    UndoEntry::new(splice::changes(&splice::undone(splices, old)), splice::changes(splices), label)
+}
+
+/// The title was changed from `old` to `new`
+pub fn title_changed(old: &str, new: &str) -> UndoEntry {
+    let set = |title: &str| {
+        LocalChange::set(Path::root().key("title"), Value::Primitive(amp::Value::Str(title.to_string())))
+    };
+    UndoEntry::new(vec![set(old)], vec![set(new)], format!("Rename to {}", quote(new)))
+}
@@ -0,0 +1,133 @@
+//! The first step onto the unified `automerge` crate, built with `--features
+//! unified`.
+//!
+//! The demo was written against the split crates: a frontend on the UI
+//! thread, which turns edits into change requests and applies patches, and a
+//! backend on the backend thread, which turns requests into changes and
+//! changes into patches. Showing that split at work is the point of the
+//! demo, so the tabs and the backend thread stay on those crates and keep
+//! their shape. The unified crate does the work of both in one document: an
+//! edit is a transaction on an `AutoCommit`, which is committed as a change,
+//! and peers bring each other up to date through its sync protocol rather
+//! than by sending each other every change.
+//!
+//! Before the rest of the app moves over, the headless modes run on it: a
+//! `UnifiedPeer` is the same one text document a `simulate::Peer` is, so
+//! `simulate --unified` and `bench --unified` do on the unified crate what
+//! they do on the split ones, which is a comparison of both speed and
+//! behaviour. They only type ASCII, so it makes no difference here whether
+//! the crate counts positions in text by character, as the rest of the demo
+//! does, or by byte.
+
+use automerge::sync::{self, SyncDoc};
+use automerge::transaction::Transactable;
+use automerge::{AutoCommit, AutomergeError, Change, ObjId, ObjType, ReadDoc, ScalarValue, ROOT};
+
+use crate::schema::{COUNTER_FIELD, TEXT_FIELD};
+use crate::simulate::Editor;
+
+fn error(e: AutomergeError) -> String {
+    e.to_string()
+}
+
+/// One peer's copy of a document with the demo's text and counter
+pub struct UnifiedPeer {
+    doc: AutoCommit,
+    text: ObjId,
+}
+
+impl UnifiedPeer {
+    /// A peer which makes a new document
+    pub fn creating() -> Result<UnifiedPeer, String> {
+        let mut doc = AutoCommit::new();
+        let text = doc.put_object(ROOT, TEXT_FIELD, ObjType::Text).map_err(error)?;
+        doc.put(ROOT, COUNTER_FIELD, ScalarValue::counter(0)).map_err(error)?;
+        doc.commit();
+        Ok(UnifiedPeer { doc, text })
+    }
+
+    /// Another peer with everything this one has, under an actor of its
+    /// own, as a peer sent the history is
+    pub fn fork(&mut self) -> UnifiedPeer {
+        UnifiedPeer {
+            doc: self.doc.fork(),
+            text: self.text.clone(),
+        }
+    }
+
+    /// A peer with nothing yet, for `sync_with` to bring up to date
+    pub fn empty() -> UnifiedPeer {
+        UnifiedPeer {
+            doc: AutoCommit::new(),
+            text: ROOT,
+        }
+    }
+
+    /// Replace `delete` characters at `index` with `insert` as one
+    /// transaction, returning the change it made
+    pub fn splice(&mut self, index: usize, delete: usize, insert: &str) -> Result<Vec<Change>, String> {
+        self.doc.splice_text(&self.text, index, delete as isize, insert).map_err(error)?;
+        self.doc.commit();
+        Ok(self.doc.get_last_local_change().cloned().into_iter().collect())
+    }
+
+    /// Trade sync messages with `other` until neither has anything the
+    /// other lacks, from no shared state, as two peers which have just
+    /// connected do. Each message goes through its wire encoding. Returns
+    /// how many it took.
+    pub fn sync_with(&mut self, other: &mut UnifiedPeer) -> Result<usize, String> {
+        let (mut ours, mut theirs) = (sync::State::new(), sync::State::new());
+        let mut messages = 0;
+        loop {
+            let to_other = self.doc.sync().generate_sync_message(&mut ours).map(|m| m.encode());
+            let to_us = other.doc.sync().generate_sync_message(&mut theirs).map(|m| m.encode());
+            if to_other.is_none() && to_us.is_none() {
+                break;
+            }
+            if let Some(bytes) = to_other {
+                let message = sync::Message::decode(&bytes).map_err(|e| e.to_string())?;
+                other.doc.sync().receive_sync_message(&mut theirs, message).map_err(error)?;
+                messages += 1;
+            }
+            if let Some(bytes) = to_us {
+                let message = sync::Message::decode(&bytes).map_err(|e| e.to_string())?;
+                self.doc.sync().receive_sync_message(&mut ours, message).map_err(error)?;
+                messages += 1;
+            }
+        }
+        // The text came with the changes, for a peer which started with
+        // nothing
+        self.find_text()?;
+        other.find_text()?;
+        Ok(messages)
+    }
+
+    fn find_text(&mut self) -> Result<(), String> {
+        if let Some((_, text)) = self.doc.get(ROOT, TEXT_FIELD).map_err(error)? {
+            self.text = text;
+        }
+        Ok(())
+    }
+}
+
+impl Editor for UnifiedPeer {
+    type Change = Change;
+
+    fn insert(&mut self, index: usize, c: char) -> Result<Vec<Change>, String> {
+        self.splice(index, 0, &c.to_string())
+    }
+
+    fn delete(&mut self, index: usize) -> Result<Vec<Change>, String> {
+        self.splice(index, 1, "")
+    }
+
+    fn receive(&mut self, changes: Vec<Change>) -> Result<(), String> {
+        // Changes which arrive before the ones they depend on are held in
+        // the document until those turn up
+        self.doc.apply_changes(changes).map_err(error)
+    }
+
+    fn text(&self) -> String {
+        self.doc.text(&self.text).unwrap_or_default()
+    }
+}
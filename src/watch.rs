@@ -0,0 +1,96 @@
+//! Noticing when another program changes a document's file, and merging
+//! what it changed.
+//!
+//! A folder synced by Dropbox or Syncthing makes a crude transport: two
+//! instances on different machines each open the same file there, and
+//! whenever one saves, the other gets the new file. Because a saved file is
+//! the document's whole history, merging it is just applying whatever
+//! changes in it the backend hasn't got yet, which automerge is happy to do
+//! whatever order they turn up in.
+//!
+//! So I watch the directory of every file a tab has open (not the file
+//! itself, saving replaces it with a rename) and tell the UI when one of
+//! them is written. The UI reads it again and the backend thread applies
+//! the changes it doesn't already have to every replica of the document.
+//! Our own saves are noticed too, but everything in them is already there,
+//! so they're ignored. A sync service can be caught halfway through writing
+//! a file, which then doesn't load; the next event, once it's finished,
+//! picks it up.
+
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::HashSet;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::{Message, Model};
+
+#[derive(Clone)]
+pub struct FileWatcher {
+    inner: Arc<Mutex<Inner>>,
+}
+
+struct Inner {
+    watcher: RecommendedWatcher,
+    /// The files we're interested in, shared with the watcher's thread
+    files: Arc<Mutex<HashSet<PathBuf>>>,
+    dirs: HashSet<PathBuf>,
+}
+
+impl fmt::Debug for FileWatcher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FileWatcher").field("files", &self.inner.lock().unwrap().files).finish()
+    }
+}
+
+/// Start watching, sending a `Message::FileChanged` to `scope` whenever a
+/// file passed to `FileWatcher::watch` is written
+pub fn start(scope: vgtk::Scope<Model>) -> notify::Result<FileWatcher> {
+    let files: Arc<Mutex<HashSet<PathBuf>>> = Arc::default();
+    let watched = files.clone();
+    let watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        let event = match event {
+            Ok(event) => event,
+            Err(e) => {
+                tracing::warn!("watching files failed: {}", e);
+                return;
+            }
+        };
+        if !matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+            return;
+        }
+        let changed: Vec<PathBuf> = {
+            let watched = watched.lock().unwrap();
+            event.paths.into_iter().filter(|p| watched.contains(p)).collect()
+        };
+        for path in changed {
+            tracing::debug!("{} changed", path.display());
+            let _ = scope.try_send(Message::FileChanged(path));
+        }
+    })?;
+    Ok(FileWatcher{inner: Arc::new(Mutex::new(Inner{watcher, files, dirs: HashSet::new()}))})
+}
+
+impl FileWatcher {
+    /// Watch exactly `paths` from now on
+    pub fn set_files(&self, paths: impl IntoIterator<Item = PathBuf>) {
+        let mut inner = self.inner.lock().unwrap();
+        let files: HashSet<PathBuf> = paths.into_iter().map(|p| absolute(&p)).collect();
+        let dirs: HashSet<PathBuf> = files.iter().filter_map(|p| p.parent().map(Path::to_path_buf)).collect();
+        for dir in inner.dirs.difference(&dirs).cloned().collect::<Vec<_>>() {
+            let _ = inner.watcher.unwatch(&dir);
+        }
+        for dir in dirs.difference(&inner.dirs).cloned().collect::<Vec<_>>() {
+            if let Err(e) = inner.watcher.watch(&dir, RecursiveMode::NonRecursive) {
+                tracing::warn!("can't watch {} for changes: {}", dir.display(), e);
+            }
+        }
+        inner.dirs = dirs;
+        *inner.files.lock().unwrap() = files;
+    }
+}
+
+/// Events have absolute paths, a path from `--open` might not be
+pub fn absolute(path: &Path) -> PathBuf {
+    std::env::current_dir().map(|dir| dir.join(path)).unwrap_or_else(|_| path.to_path_buf())
+}
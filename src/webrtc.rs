@@ -0,0 +1,225 @@
+//! Syncing with another instance over a WebRTC data channel, for when
+//! neither side can run a server the other can reach, e.g. both are behind
+//! NAT.
+//!
+//! There's no signalling server. File > Start WebRTC Call makes an offer
+//! and shows it in a dialog, to be sent to the other side by whatever means
+//! (chat, email) and pasted into their File > Answer WebRTC Call, which
+//! shows an answer to be sent back and pasted into the first dialog. The
+//! offer and answer are session descriptions as JSON, base64 encoded so
+//! they survive being pasted around as a single line. We wait for ICE
+//! gathering to finish before showing them, so they have every candidate in
+//! them and nothing else has to be exchanged. A public STUN server finds
+//! our address as seen from outside.
+//!
+//! Once the data channel opens it's treated just like a websocket client:
+//! it's handed to the backend thread with `WsEvent::Connected`, sent the
+//! history and then every new change, and each message on it is a change in
+//! the automerge binary format, sent on with `WsEvent::Changes`.
+//!
+//! webrtc-rs is async, so each call gets a thread running a tokio runtime
+//! until the connection closes.
+
+use bytes::Bytes;
+use std::sync::Arc;
+use vgtk::lib::gtk::prelude::*;
+use vgtk::lib::gtk::{Dialog, DialogFlags, Entry, Label, ResponseType, TextView, Window, WrapMode};
+use webrtc::api::APIBuilder;
+use webrtc::data_channel::data_channel_message::DataChannelMessage;
+use webrtc::data_channel::RTCDataChannel;
+use webrtc::ice_transport::ice_server::RTCIceServer;
+use webrtc::peer_connection::configuration::RTCConfiguration;
+use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
+use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+use webrtc::peer_connection::RTCPeerConnection;
+
+use crate::ws::{self, WsEvent};
+
+const STUN_SERVER: &str = "stun:stun.l.google.com:19302";
+const CHANNEL_LABEL: &str = "automerge";
+
+/// Make an offer. It's sent to the UI as a `WsEvent::Signal` with a channel
+/// for the answer.
+pub fn call(events: crossbeam::Sender<WsEvent>) {
+    spawn(None, events);
+}
+
+/// Answer an offer the user pasted in. The answer is sent to the UI as a
+/// `WsEvent::Signal` with nothing to reply on.
+pub fn answer(offer: String, events: crossbeam::Sender<WsEvent>) {
+    spawn(Some(offer), events);
+}
+
+fn spawn(offer: Option<String>, events: crossbeam::Sender<WsEvent>) {
+    std::thread::spawn(move || {
+        let runtime = match tokio::runtime::Runtime::new() {
+            Ok(runtime) => runtime,
+            Err(e) => {
+                tracing::error!("could not start the WebRTC runtime: {}", e);
+                return;
+            }
+        };
+        match runtime.block_on(run(offer, events)) {
+            Ok(()) => tracing::info!("WebRTC call ended"),
+            Err(e) => tracing::warn!("WebRTC call failed: {}", e),
+        }
+    });
+}
+
+async fn run(offer: Option<String>, events: crossbeam::Sender<WsEvent>) -> Result<(), String> {
+    let config = RTCConfiguration {
+        ice_servers: vec![RTCIceServer{urls: vec![STUN_SERVER.to_string()], ..Default::default()}],
+        ..Default::default()
+    };
+    let pc = Arc::new(APIBuilder::new().build().new_peer_connection(config).await.map_err(|e| e.to_string())?);
+    let (closed_sx, mut closed) = tokio::sync::mpsc::channel(1);
+    pc.on_peer_connection_state_change(Box::new(move |state: RTCPeerConnectionState| {
+        tracing::info!("WebRTC connection {}", state);
+        if matches!(state, RTCPeerConnectionState::Disconnected | RTCPeerConnectionState::Failed | RTCPeerConnectionState::Closed) {
+            let _ = closed_sx.try_send(());
+        }
+        Box::pin(async {})
+    }));
+    match offer {
+        None => {
+            let channel = pc.create_data_channel(CHANNEL_LABEL, None).await.map_err(|e| e.to_string())?;
+            bridge(channel, events.clone());
+            let offer = pc.create_offer(None).await.map_err(|e| e.to_string())?;
+            let offer = gather(&pc, offer).await?;
+            let (reply, answer) = crossbeam::channel::bounded(1);
+            events.send(WsEvent::Signal{description: offer, reply: Some(reply)}).map_err(|e| e.to_string())?;
+            // Waiting for the user is blocking, keep it off the runtime's
+            // threads
+            let answer = tokio::task::spawn_blocking(move || answer.recv())
+                .await
+                .map_err(|e| e.to_string())?
+                .map_err(|_| "the call was cancelled".to_string())?;
+            pc.set_remote_description(decode(&answer)?).await.map_err(|e| e.to_string())?;
+        }
+        Some(offer) => {
+            let channel_events = events.clone();
+            pc.on_data_channel(Box::new(move |channel: Arc<RTCDataChannel>| {
+                bridge(channel, channel_events.clone());
+                Box::pin(async {})
+            }));
+            pc.set_remote_description(decode(&offer)?).await.map_err(|e| e.to_string())?;
+            let answer = pc.create_answer(None).await.map_err(|e| e.to_string())?;
+            let answer = gather(&pc, answer).await?;
+            events.send(WsEvent::Signal{description: answer, reply: None}).map_err(|e| e.to_string())?;
+        }
+    }
+    closed.recv().await;
+    pc.close().await.map_err(|e| e.to_string())
+}
+
+/// Set our side's description and wait for every ICE candidate to go in
+/// it, returning it encoded for the user to copy
+async fn gather(pc: &RTCPeerConnection, description: RTCSessionDescription) -> Result<String, String> {
+    let mut complete = pc.gathering_complete_promise().await;
+    pc.set_local_description(description).await.map_err(|e| e.to_string())?;
+    let _ = complete.recv().await;
+    let description = pc.local_description().await.ok_or_else(|| "there's no local description".to_string())?;
+    Ok(base64::encode(serde_json::to_string(&description).unwrap()))
+}
+
+fn decode(pasted: &str) -> Result<RTCSessionDescription, String> {
+    let json = base64::decode(pasted.trim()).map_err(|_| "that isn't an offer or answer".to_string())?;
+    serde_json::from_slice(&json).map_err(|_| "that isn't an offer or answer".to_string())
+}
+
+/// Hand the data channel to the backend thread once it opens, and pass on
+/// the changes which arrive on it
+fn bridge(channel: Arc<RTCDataChannel>, events: crossbeam::Sender<WsEvent>) {
+    let runtime = tokio::runtime::Handle::current();
+    // The channel's handlers are owned by the channel, so they mustn't keep
+    // it alive themselves
+    let weak = Arc::downgrade(&channel);
+    let open_events = events.clone();
+    channel.on_open(Box::new(move || {
+        let (sx, outgoing) = crossbeam::channel::unbounded::<Vec<u8>>();
+        if let (Some(channel), Ok(())) = (weak.upgrade(), open_events.send(WsEvent::Connected{client: sx, server: None})) {
+            tracing::info!("WebRTC data channel open");
+            let runtime = runtime.clone();
+            // The outgoing queue is a blocking channel, so it's drained on
+            // a thread of its own
+            std::thread::spawn(move || {
+                for bytes in outgoing {
+                    if runtime.block_on(channel.send(&Bytes::from(bytes))).is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+        Box::pin(async {})
+    }));
+    channel.on_message(Box::new(move |message: DataChannelMessage| {
+        ws::forward(message.data.to_vec(), None, &events);
+        Box::pin(async {})
+    }));
+}
+
+/// Show our offer and ask for the other side's answer
+pub fn exchange_offer(offer: &str) -> Option<String> {
+    signalling_dialog(
+        "Start WebRTC Call",
+        "Send this offer to the other side, and paste their answer below.",
+        Some(offer),
+        true,
+    )
+}
+
+/// Ask for the offer to answer
+pub fn ask_offer() -> Option<String> {
+    signalling_dialog("Answer WebRTC Call", "Paste the offer from the other side.", None, true)
+}
+
+/// Show our answer to send back
+pub fn show_answer(answer: &str) {
+    signalling_dialog(
+        "Answer WebRTC Call",
+        "Send this answer back to the other side. The call starts once they've pasted it in.",
+        Some(answer),
+        false,
+    );
+}
+
+/// A dialog with `ours` to copy, if there's something, and an entry to
+/// paste the other side's into if `paste`. Returns what was pasted.
+fn signalling_dialog(title: &str, message: &str, ours: Option<&str>, paste: bool) -> Option<String> {
+    let buttons: &[(&str, ResponseType)] = if paste {
+        &[("_Cancel", ResponseType::Cancel), ("_Connect", ResponseType::Ok)]
+    } else {
+        &[("_OK", ResponseType::Ok)]
+    };
+    let dialog = Dialog::with_buttons(Some(title), None::<&Window>, DialogFlags::MODAL, buttons);
+    dialog.set_default_size(500, -1);
+    let content = dialog.get_content_area();
+    content.set_spacing(6);
+    content.set_border_width(10);
+    let label = Label::new(Some(message));
+    label.set_line_wrap(true);
+    content.pack_start(&label, false, false, 0);
+    if let Some(ours) = ours {
+        let view = TextView::new();
+        view.set_editable(false);
+        view.set_wrap_mode(WrapMode::Char);
+        if let Some(buffer) = view.get_buffer() {
+            buffer.set_text(ours);
+        }
+        content.pack_start(&view, true, true, 0);
+    }
+    let entry = Entry::new();
+    entry.set_placeholder_text(Some("Paste here"));
+    if paste {
+        content.pack_start(&entry, false, false, 0);
+    }
+    dialog.show_all();
+    let response = dialog.run();
+    let pasted = entry.get_text().map(|t| t.trim().to_string()).unwrap_or_default();
+    dialog.destroy();
+    if paste && response == ResponseType::Ok && !pasted.is_empty() {
+        Some(pasted)
+    } else {
+        None
+    }
+}
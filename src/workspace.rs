@@ -0,0 +1,63 @@
+//! Remembering which documents were open, and the window, between runs.
+//!
+//! On the way out the demo writes `~/.config/automerge-demo/workspace.json`
+//! with each tab's file, document id and cursor position, which tab was
+//! showing and how big the window was. The next time it starts without
+//! `--open` or `--replay` it opens those files again, tabs which had the
+//! same document as replicas of one another, puts each cursor back and
+//! resizes the window. Keeping the ids means a document restored here is
+//! still recognised as the same document by instances it was syncing with.
+//!
+//! Only tabs with a file can come back, there's nowhere else their changes
+//! are kept. If none of them have one the demo starts as usual.
+//! `--no-workspace` neither restores the workspace nor saves it.
+
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::PathBuf;
+
+use crate::file;
+use crate::peer::DocumentId;
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Workspace {
+    /// The window's width and height
+    pub window_size: Option<(i32, i32)>,
+    /// The index of the tab which was showing
+    pub current: usize,
+    pub tabs: Vec<Tab>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Tab {
+    pub path: Option<PathBuf>,
+    pub id: DocumentId,
+    /// The cursor's char offset in the text. The text isn't in a scrolled
+    /// window, so this is what says where in it the tab was.
+    pub cursor: usize,
+}
+
+fn path() -> Option<PathBuf> {
+    file::config_path("workspace.json")
+}
+
+/// The workspace saved last time, if there is one
+pub fn load() -> Option<Workspace> {
+    let path = path()?;
+    let json = std::fs::read(&path).ok()?;
+    match serde_json::from_slice(&json) {
+        Ok(workspace) => Some(workspace),
+        Err(e) => {
+            tracing::warn!("Ignoring the workspace in {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+pub fn save(workspace: &Workspace) -> io::Result<()> {
+    let path = path().ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "there's no config directory"))?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(path, serde_json::to_vec_pretty(workspace)?)
+}
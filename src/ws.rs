@@ -0,0 +1,695 @@
+//! A WebSocket server which lets other automerge implementations (the obvious
+//! one being the JS implementation running in a browser) join the document
+//! being edited in the GTK windows.
+//!
+//! The protocol is deliberately minimal. Every binary message, in either
+//! direction, is a single change in the automerge binary format. When a client
+//! connects the server sends it every change in the document, after which it
+//! sends each new change as it is applied. Changes received from a client are
+//! applied to both backends and then broadcast to every client (including
+//! the sender, for whom they are duplicates which automerge ignores). On the
+//! JS side this means you just call `Automerge.applyChanges` with whatever
+//! arrives and send whatever `Automerge.getChanges` gives you.
+//!
+//! tungstenite sockets are blocking, so each client gets a thread which
+//! alternates between reading with a short timeout and flushing its outgoing
+//! queue.
+//!
+//! Connections we make to other instances are supervised: if one drops, or
+//! can't be made, we try again after a delay which doubles each time, up to
+//! `MAX_BACKOFF`, and give up after `MAX_ATTEMPTS` tries in a row. The UI is
+//! told whether each server is connected, being reconnected to or given up
+//! on. This version of automerge has no sync protocol to resume a session
+//! with, so instead I keep track of every change each server has sent us,
+//! which it must have, and on reconnecting only send it the rest of the
+//! history. That lasts as long as the process does. Servers still send us
+//! their whole history, they have no way of knowing who we are.
+//!
+//! Either end can use TLS, see `tls.rs`. A socket is then over a `Stream`
+//! wrapping the TCP stream in a rustls session, everything above that is
+//! the same. A server whose certificate we don't trust isn't retried.
+//!
+//! Servers can also require clients to authenticate before a session
+//! starts, see `auth.rs`. The handshake comes straight after the websocket
+//! one, and a rejected client isn't retried either.
+//!
+//! Other instances of the demo can sync every document we have open, not
+//! just the one we started with, over the same connection. An instance
+//! which connects to a server starts the session with a text message,
+//! `HELLO`, and a demo server answers with the same. From then on each side
+//! sends the other the history and new changes of its other documents as
+//! binary messages starting with `DOCUMENT_TAG` and the document's id,
+//! followed by the change. Changes to the shared document are still sent
+//! bare, so a JS client, which never says hello, only ever sees those.
+//!
+//! Instances which said hello also tell each other what their tabs are
+//! doing, see `presence.rs`, as binary messages starting with
+//! `PRESENCE_TAG` followed by the `PresenceState` as JSON. A state we
+//! haven't seen before is passed on to everyone else who said hello, so
+//! instances connected through a third one see each other too.
+//!
+//! They also send each other the signatures of changes, see `signing.rs`,
+//! as binary messages starting with `SIGNATURE_TAG` followed by the
+//! change's hash, the public key and the signature. A change's signature
+//! is sent before the change, so it's there to check when the change
+//! arrives.
+
+use automerge_backend::Change;
+use automerge_protocol as amp;
+use rustls::{ClientSession, ServerConfig, ServerSession, StreamOwned};
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use tungstenite::WebSocket;
+
+use crate::auth::{self, Auth};
+use crate::peer::DocumentId;
+use crate::presence::PresenceState;
+use crate::signing::{self, Signature};
+use crate::sync_filter::{Gate, SubtreeIndex, SyncFilters, Touched};
+use crate::tls;
+
+const READ_TIMEOUT: Duration = Duration::from_millis(50);
+
+/// How long to wait before the first attempt to reconnect, and at most
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// How many attempts in a row may fail before we give up on a server
+const MAX_ATTEMPTS: u32 = 10;
+
+/// What an instance which can sync more than the shared document starts
+/// its sessions with
+pub const HELLO: &str = "automerge-demo/documents";
+/// The first byte of a message for a document other than the shared one.
+/// A change in the binary format starts with its magic bytes, never this.
+const DOCUMENT_TAG: u8 = 0x01;
+/// The first byte of a message saying what another instance's tab is doing
+const PRESENCE_TAG: u8 = 0x02;
+/// The first byte of a message carrying the signature of a change
+const SIGNATURE_TAG: u8 = 0x03;
+
+/// Events sent from the server to the backend thread
+pub enum WsEvent {
+    /// A new client connected, it needs to be sent the existing history.
+    /// `server` is the address of the server if we made the connection.
+    Connected{client: crossbeam::Sender<Vec<u8>>, server: Option<SocketAddr>},
+    /// A client sent us some changes to `document`, or to the shared
+    /// document if there's no id
+    Changes{changes: Vec<Change>, server: Option<SocketAddr>, document: Option<DocumentId>},
+    /// A client said hello, it can be sent every document
+    Documents{client: crossbeam::Sender<Vec<u8>>, server: Option<SocketAddr>},
+    /// The connection to a server we connected to has changed state
+    Status(SocketAddr, ConnectionStatus),
+    /// We're connecting to a server over TLS for the first time, the user
+    /// has to say whether they trust its certificate
+    Verify{server: SocketAddr, fingerprint: String, reply: crossbeam::Sender<bool>},
+    /// A client which connected to us failed to authenticate
+    Rejected{client: SocketAddr, reason: String},
+    /// Our side of a WebRTC call to be shown to the user, along with where
+    /// to send the other side's answer if it's an offer, see `webrtc.rs`
+    Signal{description: String, reply: Option<crossbeam::Sender<String>>},
+    /// The libp2p node is syncing the document with this id, see `p2p.rs`
+    Announced{document: String},
+    /// A libp2p peer joined or left the document's topic, or sent a change
+    NetworkPeer{id: String, present: bool},
+    /// A client told us what one of its tabs is doing
+    Presence(PresenceState),
+    /// A client sent us the signature of a change
+    Signature(amp::ChangeHash, Signature),
+}
+
+/// The stream under a websocket, with or without TLS
+pub enum Stream {
+    Plain(TcpStream),
+    Server(StreamOwned<ServerSession, TcpStream>),
+    Client(StreamOwned<ClientSession, TcpStream>),
+}
+
+impl Stream {
+    fn tcp(&self) -> &TcpStream {
+        match self {
+            Stream::Plain(s) => s,
+            Stream::Server(s) => &s.sock,
+            Stream::Client(s) => &s.sock,
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(s) => s.read(buf),
+            Stream::Server(s) => s.read(buf),
+            Stream::Client(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(s) => s.write(buf),
+            Stream::Server(s) => s.write(buf),
+            Stream::Client(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(s) => s.flush(),
+            Stream::Server(s) => s.flush(),
+            Stream::Client(s) => s.flush(),
+        }
+    }
+}
+
+/// The state of a connection we've made to another instance
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConnectionStatus {
+    /// The first attempt is under way
+    Connecting,
+    Connected,
+    /// The connection dropped or couldn't be made, and we'll try again
+    Reconnecting{attempt: u32, retry_in: Duration},
+    /// Too many attempts failed, connecting again has to be asked for
+    Failed,
+    /// The server didn't accept our passphrase or key
+    Rejected(String),
+}
+
+impl ConnectionStatus {
+    pub fn label(&self) -> String {
+        match self {
+            ConnectionStatus::Connecting => "Connecting\u{2026}".to_string(),
+            ConnectionStatus::Connected => "Connected".to_string(),
+            ConnectionStatus::Reconnecting{attempt, retry_in} => {
+                format!("Reconnecting in {:.1}s (attempt {})", retry_in.as_secs_f64(), attempt)
+            }
+            ConnectionStatus::Failed => "Failed".to_string(),
+            ConnectionStatus::Rejected(reason) => format!("Rejected: {}", reason),
+        }
+    }
+
+    /// Whether the connection is being taken care of, i.e. asking to
+    /// connect again wouldn't do anything
+    pub fn is_active(&self) -> bool {
+        !matches!(self, ConnectionStatus::Failed | ConnectionStatus::Rejected(_))
+    }
+}
+
+/// A connection changes are sent over, and the filter on what it's sent,
+/// see `sync_filter.rs`
+struct Client {
+    sender: crossbeam::Sender<Vec<u8>>,
+    gate: Option<Gate>,
+}
+
+/// The backend thread's view of every connected websocket peer, whether they
+/// connected to us or we connected to them
+#[derive(Default)]
+pub struct WsSessions {
+    clients: Vec<Client>,
+    /// The clients which said hello, who are also sent the other documents
+    document_clients: Vec<Client>,
+    /// The hashes of every change each server we've connected to has sent
+    /// us, so that we know not to send it them again when we reconnect
+    seen: HashMap<SocketAddr, HashSet<amp::ChangeHash>>,
+    /// The hashes of every change we've broadcast or sent as history, all
+    /// of which our backends have
+    broadcast: HashSet<amp::ChangeHash>,
+    /// The `seq` of the latest presence state we've passed on for each
+    /// actor
+    presence: HashMap<String, u64>,
+    /// What each peer may see of the documents, from `--sync-filter`, and
+    /// where everything is in them, which is only kept up if there are any
+    filters: SyncFilters,
+    subtrees: SubtreeIndex,
+    /// The filters on the changes each server sends us. Clients which
+    /// connected to us don't have an address, they share one.
+    incoming: HashMap<Option<SocketAddr>, Gate>,
+}
+
+impl WsSessions {
+    pub fn new(filters: SyncFilters) -> WsSessions {
+        WsSessions {
+            filters,
+            ..Default::default()
+        }
+    }
+
+    fn client(&self, sender: crossbeam::Sender<Vec<u8>>, server: Option<SocketAddr>) -> Client {
+        Client {
+            sender,
+            gate: self.filters.for_peer(server).map(|filter| Gate::new(filter.clone(), format!("to {}", peer_name(server)))),
+        }
+    }
+
+    /// What the change touches, if there are any filters to check it
+    /// against
+    fn touched(&mut self, change: &Change) -> Option<Touched> {
+        if self.filters.is_empty() {
+            None
+        } else {
+            Some(self.subtrees.touched(change))
+        }
+    }
+
+    /// Send the history to a newly connected client and start including it
+    /// in broadcasts. A server we've been connected to before is only sent
+    /// the changes it hasn't shown us it has.
+    pub fn add_client(&mut self, client: crossbeam::Sender<Vec<u8>>, server: Option<SocketAddr>, history: &[Change]) {
+        let mut client = self.client(client, server);
+        self.send_history(&mut client, server, None, history);
+        self.clients.push(client);
+    }
+
+    /// Send the history of every document other than the shared one to a
+    /// client which said hello, and start including it in their broadcasts
+    pub fn add_document_client(
+        &mut self,
+        client: crossbeam::Sender<Vec<u8>>,
+        server: Option<SocketAddr>,
+        histories: &[(DocumentId, Vec<Change>)],
+    ) {
+        let mut client = self.client(client, server);
+        for (document, history) in histories {
+            self.send_history(&mut client, server, Some(*document), history);
+        }
+        self.document_clients.push(client);
+    }
+
+    fn send_history(&mut self, client: &mut Client, server: Option<SocketAddr>, document: Option<DocumentId>, history: &[Change]) {
+        let mut sent = 0;
+        self.broadcast.extend(history.iter().map(|change| change.hash));
+        for change in history {
+            let touched = self.touched(change);
+            let seen = server.and_then(|s| self.seen.get(&s));
+            if seen.map(|seen| seen.contains(&change.hash)).unwrap_or(false) {
+                continue;
+            }
+            if !client.admits(change, touched.as_ref()) {
+                continue;
+            }
+            let _ = client.sender.send(frame(document, change));
+            sent += 1;
+        }
+        if server.map_or(false, |s| self.seen.contains_key(&s)) {
+            tracing::info!("resumed the session with {:?}, sent {} of {} changes", server, sent, history.len());
+        }
+    }
+
+    /// Record the changes a server has sent us
+    pub fn received(&mut self, server: Option<SocketAddr>, changes: &[Change]) {
+        if let Some(server) = server {
+            self.seen.entry(server).or_default().extend(changes.iter().map(|c| c.hash));
+        }
+    }
+
+    /// The changes a peer has sent us which our backends don't have yet, as
+    /// far as we know, so only those are applied and passed on to everyone
+    /// else, and one which comes back round isn't sent out again
+    pub fn new_to_us(&self, changes: Vec<Change>) -> Vec<Change> {
+        changes.into_iter().filter(|change| !self.broadcast.contains(&change.hash)).collect()
+    }
+
+    /// The changes a peer has sent us which its filter lets us apply
+    pub fn admit(&mut self, server: Option<SocketAddr>, changes: Vec<Change>) -> Vec<Change> {
+        let filter = match self.filters.for_peer(server) {
+            Some(filter) => filter.clone(),
+            None => return changes,
+        };
+        let subtrees = &mut self.subtrees;
+        let gate = self.incoming.entry(server).or_insert_with(|| Gate::new(filter, format!("from {}", peer_name(server))));
+        changes
+            .into_iter()
+            .filter(|change| {
+                let touched = subtrees.touched(change);
+                gate.admit(change, &touched)
+            })
+            .collect()
+    }
+
+    /// Whether a server has sent us anything before
+    pub fn knows(&self, server: SocketAddr) -> bool {
+        self.seen.contains_key(&server)
+    }
+
+    /// What each server we've connected to has sent us, to keep for next
+    /// time, see `storage.rs`
+    pub fn seen(&self) -> impl Iterator<Item = (SocketAddr, &HashSet<amp::ChangeHash>)> {
+        self.seen.iter().map(|(server, seen)| (*server, seen))
+    }
+
+    /// Pick up what a server had sent us in an earlier run
+    pub fn restore_seen(&mut self, server: SocketAddr, hashes: Vec<amp::ChangeHash>) {
+        self.seen.entry(server).or_default().extend(hashes);
+    }
+
+    /// Send some new changes to every client, dropping clients which have
+    /// gone away. Changes which have been broadcast before are skipped, so
+    /// when a client sends us back a change we sent it, or two instances or
+    /// a relay are connected to each other both ways, a change goes round
+    /// once rather than forever.
+    pub fn broadcast(&mut self, changes: &[Change]) {
+        for change in changes {
+            if !self.broadcast.insert(change.hash) {
+                continue;
+            }
+            let touched = self.touched(change);
+            send_change(&mut self.clients, change, touched.as_ref(), change.raw_bytes());
+        }
+    }
+
+    /// `broadcast` for a document other than the shared one, which only
+    /// goes to the clients which said hello
+    pub fn broadcast_document(&mut self, document: DocumentId, changes: &[Change]) {
+        for change in changes {
+            if !self.broadcast.insert(change.hash) {
+                continue;
+            }
+            let touched = self.touched(change);
+            send_change(&mut self.document_clients, change, touched.as_ref(), &frame(Some(document), change));
+        }
+    }
+
+    /// Send a presence state to every client which said hello, returning
+    /// false if it's one we've already sent or is older than one we have.
+    /// That's what stops states going round forever between instances
+    /// connected to each other both ways.
+    pub fn share_presence(&mut self, state: &PresenceState) -> bool {
+        match self.presence.get(&state.actor_id) {
+            Some(seq) if *seq >= state.seq => return false,
+            _ => {}
+        }
+        self.presence.insert(state.actor_id.clone(), state.seq);
+        let mut bytes = vec![PRESENCE_TAG];
+        bytes.extend(serde_json::to_vec(state).unwrap());
+        self.document_clients.retain(|c| c.sender.send(bytes.clone()).is_ok());
+        true
+    }
+
+    /// Send signatures to every client which said hello
+    pub fn share_signatures(&mut self, signatures: &[(amp::ChangeHash, Signature)]) {
+        for (hash, signature) in signatures {
+            let bytes = signature_frame(hash, signature);
+            self.document_clients.retain(|c| c.sender.send(bytes.clone()).is_ok());
+        }
+    }
+}
+
+impl Client {
+    fn admits(&mut self, change: &Change, touched: Option<&Touched>) -> bool {
+        match (&mut self.gate, touched) {
+            (Some(gate), Some(touched)) => gate.admit(change, touched),
+            _ => true,
+        }
+    }
+}
+
+/// Send a change, as `bytes`, to each of the clients whose filter lets it
+/// through, dropping clients which have gone away
+fn send_change(clients: &mut Vec<Client>, change: &Change, touched: Option<&Touched>, bytes: &[u8]) {
+    let mut open = Vec::with_capacity(clients.len());
+    for mut client in clients.drain(..) {
+        if !client.admits(change, touched) || client.sender.send(bytes.to_vec()).is_ok() {
+            open.push(client);
+        }
+    }
+    *clients = open;
+}
+
+fn peer_name(server: Option<SocketAddr>) -> String {
+    server.map(|s| s.to_string()).unwrap_or_else(|| "clients".to_string())
+}
+
+/// A change's signature as a message
+pub fn signature_frame(hash: &amp::ChangeHash, signature: &Signature) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(1 + 32 + signing::PUBLIC_KEY_LEN + signing::SIGNATURE_LEN);
+    bytes.push(SIGNATURE_TAG);
+    bytes.extend_from_slice(&hash.0);
+    bytes.extend_from_slice(&signature.public_key);
+    bytes.extend_from_slice(&signature.signature);
+    bytes
+}
+
+/// A change as a message, tagged with its document unless it's for the
+/// shared one
+fn frame(document: Option<DocumentId>, change: &Change) -> Vec<u8> {
+    match document {
+        Some(document) => {
+            let mut bytes = Vec::with_capacity(1 + 16 + change.raw_bytes().len());
+            bytes.push(DOCUMENT_TAG);
+            bytes.extend_from_slice(document.0.as_bytes());
+            bytes.extend_from_slice(change.raw_bytes());
+            bytes
+        }
+        None => change.raw_bytes().to_vec(),
+    }
+}
+
+/// Start listening on `port` of `addr`, events from clients are sent to
+/// `events`. With a TLS config clients have to connect with TLS, and they
+/// have to authenticate if `auth` says so.
+pub fn listen(addr: IpAddr, port: u16, tls: Option<Arc<ServerConfig>>, auth: Arc<Auth>, events: crossbeam::Sender<WsEvent>) -> io::Result<()> {
+    let listener = TcpListener::bind((addr, port))?;
+    tracing::info!("websocket server listening on {}:{}{}", addr, port, if tls.is_some() { " with TLS" } else { "" });
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let events = events.clone();
+                    let auth = auth.clone();
+                    let stream = match &tls {
+                        Some(config) => Stream::Server(StreamOwned::new(ServerSession::new(config), stream)),
+                        None => Stream::Plain(stream),
+                    };
+                    std::thread::spawn(move || accept_client(stream, &auth, events));
+                }
+                Err(e) => tracing::warn!("websocket accept failed: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Connect to another instance's websocket server, reconnecting whenever
+/// the connection drops. Once connected the server is treated like a client
+/// which connected to us: it is sent our history and included in
+/// broadcasts. With `trust` the connection is made with TLS, and if the
+/// server asks we authenticate with `auth`.
+pub fn connect(addr: SocketAddr, trust: Option<tls::Trust>, auth: Arc<Auth>, events: crossbeam::Sender<WsEvent>) {
+    std::thread::spawn(move || {
+        let mut failures = 0;
+        loop {
+            match open(addr, trust.clone(), &auth, &events) {
+                Ok((socket, first)) => {
+                    tracing::info!("connected to websocket server {}", addr);
+                    failures = 0;
+                    if events.send(WsEvent::Status(addr, ConnectionStatus::Connected)).is_err() {
+                        return;
+                    }
+                    if let Some(bytes) = first {
+                        if !forward(bytes, Some(addr), &events) {
+                            return;
+                        }
+                    }
+                    start_session(socket, Some(addr), events.clone());
+                    tracing::info!("disconnected from websocket server {}", addr);
+                }
+                Err(OpenError::Failed(e)) => {
+                    tracing::warn!("could not connect to {}: {}", addr, e);
+                    failures += 1;
+                }
+                Err(OpenError::Untrusted) => {
+                    tracing::warn!("not connecting to {}, its certificate isn't trusted", addr);
+                    let _ = events.send(WsEvent::Status(addr, ConnectionStatus::Failed));
+                    return;
+                }
+                Err(OpenError::Rejected(reason)) => {
+                    tracing::warn!("{} rejected us: {}", addr, reason);
+                    let _ = events.send(WsEvent::Status(addr, ConnectionStatus::Rejected(reason)));
+                    return;
+                }
+            }
+            let status = if failures >= MAX_ATTEMPTS {
+                ConnectionStatus::Failed
+            } else {
+                ConnectionStatus::Reconnecting{attempt: failures + 1, retry_in: backoff(failures)}
+            };
+            // The backend thread has gone, we're shutting down
+            if events.send(WsEvent::Status(addr, status.clone())).is_err() {
+                return;
+            }
+            match status {
+                ConnectionStatus::Reconnecting{retry_in, ..} => std::thread::sleep(retry_in),
+                _ => return,
+            }
+        }
+    });
+}
+
+/// How long to wait after `failures` failed attempts in a row
+fn backoff(failures: u32) -> Duration {
+    (INITIAL_BACKOFF * 2u32.saturating_pow(failures)).min(MAX_BACKOFF)
+}
+
+enum OpenError {
+    /// Worth trying again
+    Failed(String),
+    /// The server's certificate isn't one we trust
+    Untrusted,
+    /// The server didn't accept us
+    Rejected(String),
+}
+
+/// Connect, authenticate if the server asks us to, and return the socket
+/// along with the first change the server sent if it didn't
+fn open(
+    addr: SocketAddr,
+    trust: Option<tls::Trust>,
+    auth: &Auth,
+    events: &crossbeam::Sender<WsEvent>,
+) -> Result<(WebSocket<Stream>, Option<Vec<u8>>), OpenError> {
+    let tcp = TcpStream::connect(addr).map_err(|e| OpenError::Failed(e.to_string()))?;
+    let (stream, url, rejected) = match trust {
+        Some(trust) => {
+            let (config, rejected) = tls::client_config(addr, trust, events.clone());
+            let name = webpki::DNSNameRef::try_from_ascii_str(tls::SERVER_NAME).unwrap();
+            (Stream::Client(StreamOwned::new(ClientSession::new(&config, name), tcp)), format!("wss://{}/", addr), Some(rejected))
+        }
+        None => (Stream::Plain(tcp), format!("ws://{}/", addr), None),
+    };
+    // The TLS handshake happens as part of the websocket one, which is when
+    // the certificate is checked
+    let mut socket = match tungstenite::client(url, stream) {
+        Ok((socket, _)) => socket,
+        Err(_) if rejected.as_ref().map(|r| r.load(Ordering::SeqCst)).unwrap_or(false) => return Err(OpenError::Untrusted),
+        Err(e) => return Err(OpenError::Failed(format!("websocket handshake failed: {}", e))),
+    };
+    socket.get_ref().tcp().set_read_timeout(Some(auth::TIMEOUT)).unwrap();
+    match auth.respond(&mut socket) {
+        auth::Outcome::Accepted(first) => Ok((socket, first)),
+        auth::Outcome::Rejected(reason) => Err(OpenError::Rejected(reason)),
+        auth::Outcome::Failed(e) => Err(OpenError::Failed(format!("authentication failed: {}", e))),
+    }
+}
+
+fn accept_client(stream: Stream, auth: &Auth, events: crossbeam::Sender<WsEvent>) {
+    let peer = stream.tcp().peer_addr().ok();
+    let mut socket = match tungstenite::server::accept(stream) {
+        Ok(s) => s,
+        Err(e) => {
+            tracing::warn!("websocket handshake with {:?} failed: {}", peer, e);
+            return;
+        }
+    };
+    if auth.required() {
+        socket.get_ref().tcp().set_read_timeout(Some(auth::TIMEOUT)).unwrap();
+        if let Err(reason) = auth.challenge(&mut socket) {
+            tracing::warn!("rejected websocket client {:?}: {}", peer, reason);
+            if let Some(client) = peer {
+                let _ = events.send(WsEvent::Rejected{client, reason});
+            }
+            return;
+        }
+    }
+    tracing::info!("websocket client {:?} connected", peer);
+    start_session(socket, None, events);
+    tracing::info!("websocket client {:?} disconnected", peer);
+}
+
+/// Register a connected socket with the backend thread and pump messages
+/// until it closes. `server` is the server's address if we connected to it.
+fn start_session(socket: WebSocket<Stream>, server: Option<SocketAddr>, events: crossbeam::Sender<WsEvent>) {
+    socket.get_ref().tcp().set_read_timeout(Some(READ_TIMEOUT)).unwrap();
+    let (sx, rx) = crossbeam::channel::unbounded();
+    if events.send(WsEvent::Connected{client: sx.clone(), server}).is_err() {
+        return;
+    }
+    run_client(socket, server, sx, rx, events);
+}
+
+fn run_client(
+    mut socket: WebSocket<Stream>,
+    server: Option<SocketAddr>,
+    client: crossbeam::Sender<Vec<u8>>,
+    outgoing: crossbeam::Receiver<Vec<u8>>,
+    events: crossbeam::Sender<WsEvent>,
+) {
+    // Servers we connect to are other instances, which might be able to
+    // sync every document
+    if server.is_some() && socket.write_message(tungstenite::Message::Text(HELLO.to_string())).is_err() {
+        return;
+    }
+    loop {
+        match socket.read_message() {
+            Ok(tungstenite::Message::Binary(bytes)) => {
+                if !forward(bytes, server, &events) {
+                    return;
+                }
+            }
+            Ok(tungstenite::Message::Text(text)) if text == HELLO => {
+                if server.is_none() && socket.write_message(tungstenite::Message::Text(HELLO.to_string())).is_err() {
+                    return;
+                }
+                if events.send(WsEvent::Documents{client: client.clone(), server}).is_err() {
+                    return;
+                }
+            }
+            Ok(tungstenite::Message::Close(_)) => return,
+            Ok(_) => {}
+            Err(tungstenite::Error::Io(ref e))
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {}
+            Err(_) => return,
+        }
+        for bytes in outgoing.try_iter() {
+            if socket.write_message(tungstenite::Message::Binary(bytes)).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Send a change, presence state or signature we've received to the
+/// backend thread, returning false if it's gone
+pub fn forward(mut bytes: Vec<u8>, server: Option<SocketAddr>, events: &crossbeam::Sender<WsEvent>) -> bool {
+    if bytes.first() == Some(&SIGNATURE_TAG) {
+        if bytes.len() != 1 + 32 + signing::PUBLIC_KEY_LEN + signing::SIGNATURE_LEN {
+            tracing::warn!("invalid signature from websocket client: {} bytes", bytes.len());
+            return true;
+        }
+        let hash = amp::ChangeHash(<[u8; 32]>::try_from(&bytes[1..33]).unwrap());
+        let signature = Signature {
+            public_key: bytes[33..33 + signing::PUBLIC_KEY_LEN].to_vec(),
+            signature: bytes[33 + signing::PUBLIC_KEY_LEN..].to_vec(),
+        };
+        return events.send(WsEvent::Signature(hash, signature)).is_ok();
+    }
+    if bytes.first() == Some(&PRESENCE_TAG) {
+        return match serde_json::from_slice(&bytes[1..]) {
+            Ok(state) => events.send(WsEvent::Presence(state)).is_ok(),
+            Err(e) => {
+                tracing::warn!("invalid presence state from websocket client: {}", e);
+                true
+            }
+        };
+    }
+    let document = if bytes.first() == Some(&DOCUMENT_TAG) && bytes.len() > 17 {
+        let id = uuid::Uuid::from_slice(&bytes[1..17]).unwrap();
+        bytes.drain(..17);
+        Some(DocumentId(id))
+    } else {
+        None
+    };
+    match Change::from_bytes(bytes) {
+        Ok(change) => events.send(WsEvent::Changes{changes: vec![change], server, document}).is_ok(),
+        Err(e) => {
+            tracing::warn!("invalid change from websocket client: {:?}", e);
+            true
+        }
+    }
+}
@@ -0,0 +1,34 @@
+//! Round trips the interop fixture through the JS automerge and back, see
+//! `src/interop.rs`. Needs node and the JS side's dependencies, installed
+//! with `npm install` in `interop/`, so it's ignored unless asked for, with
+//! `cargo test --test interop -- --ignored`, and fails if they aren't there
+//! when it is.
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+fn run(command: &mut Command) {
+    let status = command.status().expect("could not run the interop step");
+    assert!(status.success(), "{:?} failed", command);
+}
+
+#[test]
+#[ignore = "needs node, and `npm install` run in `interop/` first"]
+fn round_trip_with_automerge_js() {
+    let interop = Path::new(env!("CARGO_MANIFEST_DIR")).join("interop");
+    assert!(
+        interop.join("node_modules").join("automerge").exists(),
+        "the JS automerge isn't installed, run `npm install` in {} first",
+        interop.display()
+    );
+    let dir: PathBuf = std::env::temp_dir().join(format!("automerge-demo-interop-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::copy(interop.join("fixtures").join("document.json"), dir.join("document.json")).unwrap();
+
+    let demo = env!("CARGO_BIN_EXE_automerge-demo");
+    run(Command::new(demo).arg("--interop-save").arg(&dir));
+    run(Command::new("node").arg(interop.join("interop.js")).arg(&dir));
+    run(Command::new(demo).arg("--interop-load").arg(&dir));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}